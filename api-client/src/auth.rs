@@ -0,0 +1,109 @@
+//! Authorization of mutating local API calls.
+//!
+//! When the gateway has `authorized_keys` configured, calls that have the
+//! gateway sign data or transmit must be signed by the gateway key or one of
+//! the authorized keys. The calls carry the public key, the unix time in
+//! milliseconds and a base64 signature in the request metadata (gRPC) or
+//! headers (REST). The signature covers [`authorization_message`] of the
+//! operation, the request body and the timestamp, and the timestamp must be
+//! within [`MAX_CLOCK_SKEW_MS`] of the gateway clock. To prevent replays the
+//! gateway only accepts a call with a timestamp after the last accepted call
+//! of the same key.
+//!
+//! gRPC operations are the rpc names, like "sign" or "add_gateway", and the
+//! body is the protobuf encoded request. REST operations are the method and
+//! path, like "POST /v1/beacon", and the body is the request body.
+
+use crate::{PublicKey, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::Message;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The b58 encoded public key that signed the call
+pub const KEY_HEADER: &str = "x-helium-key";
+/// The unix time in milliseconds the call was signed at
+pub const TIMESTAMP_HEADER: &str = "x-helium-timestamp";
+/// The base64 encoded signature
+pub const SIGNATURE_HEADER: &str = "x-helium-signature";
+
+/// The maximum difference between the signing time of a call and the gateway
+/// clock
+pub const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+
+/// The timestamp of the last signed call, to keep timestamps increasing for
+/// calls signed within the same millisecond
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// The message that is signed to authorize an operation
+pub fn authorization_message(operation: &str, body: &[u8], timestamp: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(operation.len() + body.len() + 9);
+    message.extend_from_slice(operation.as_bytes());
+    message.push(0);
+    message.extend_from_slice(body);
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message
+}
+
+/// A key that signs mutating calls, like the key of a maker's mobile app.
+pub trait Authorizer: fmt::Debug + Send + Sync {
+    fn public_key(&self) -> PublicKey;
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl Authorizer for helium_crypto::Keypair {
+    fn public_key(&self) -> PublicKey {
+        helium_crypto::Keypair::public_key(self).to_owned()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(helium_crypto::Sign::sign(self, message)?)
+    }
+}
+
+/// Wraps a request with authorization metadata for the given rpc
+pub(crate) fn authorized_request<T: Message>(
+    authorizer: Option<&Arc<dyn Authorizer>>,
+    operation: &str,
+    req: T,
+) -> Result<tonic::Request<T>> {
+    let authorizer = match authorizer {
+        Some(authorizer) => authorizer,
+        None => return Ok(tonic::Request::new(req)),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0);
+    let next = |last: u64| now.max(last + 1);
+    let timestamp = next(
+        LAST_TIMESTAMP
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(next(last))
+            })
+            .expect("timestamp update"),
+    );
+    let signature = authorizer.sign(&authorization_message(
+        operation,
+        &req.encode_to_vec(),
+        timestamp,
+    ))?;
+
+    let mut request = tonic::Request::new(req);
+    let metadata = request.metadata_mut();
+    for (key, value) in [
+        (KEY_HEADER, authorizer.public_key().to_string()),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, STANDARD.encode(signature)),
+    ] {
+        // Keys, numbers and base64 are valid ASCII metadata
+        metadata.insert(key, value.parse().expect("ascii metadata"));
+    }
+    Ok(request)
+}
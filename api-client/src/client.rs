@@ -0,0 +1,147 @@
+use crate::{
+    auth::{authorized_request, Authorizer},
+    Error, Result,
+};
+use beacon::Region;
+use helium_crypto::PublicKey;
+use helium_proto::{
+    blockchain_txn::Txn,
+    services::{
+        local::{AddGatewayReq, Client as LocalClient, PubkeyReq, RegionReq, SignReq},
+        Channel, Endpoint,
+    },
+    BlockchainTxn, BlockchainTxnAddGatewayV1, GatewayStakingMode, Message,
+};
+use std::{sync::Arc, time::Duration};
+
+/// The default port of the local API
+pub const DEFAULT_PORT: u16 = 4467;
+/// The address the local API listens on
+pub const LISTEN_ADDR: &str = "127.0.0.1";
+/// Timeout for connecting to the local API
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The address the local API listens on for the given port
+pub fn listen_addr(port: u16) -> String {
+    format!("{LISTEN_ADDR}:{port}")
+}
+
+/// The uri to connect to the local API on the given port
+pub fn connect_uri(port: u16) -> String {
+    let listen_addr = listen_addr(port);
+    format!("http://{listen_addr}")
+}
+
+/// A client of the local gateway API. Clones share the same connection.
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: LocalClient<Channel>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+}
+
+impl Client {
+    /// Connects to the local API of a gateway on this host listening on the
+    /// given port
+    pub async fn connect(port: u16) -> Result<Self> {
+        Self::connect_to(&connect_uri(port)).await
+    }
+
+    /// Connects to the local API at the given uri, like
+    /// "http://127.0.0.1:4467"
+    pub async fn connect_to(uri: &str) -> Result<Self> {
+        let channel = endpoint(uri)?.connect().await.map_err(Error::connect)?;
+        Ok(Self::from_channel(channel))
+    }
+
+    /// Creates a client for the local API at the given uri which connects on
+    /// first use and reconnects when the gateway restarts. Use this for long
+    /// running applications that may start before the gateway.
+    pub fn connect_lazy(uri: &str) -> Result<Self> {
+        Ok(Self::from_channel(endpoint(uri)?.connect_lazy()))
+    }
+
+    /// Creates a client from an already configured channel
+    pub fn from_channel(channel: Channel) -> Self {
+        Self {
+            client: LocalClient::new(channel),
+            authorizer: None,
+        }
+    }
+
+    /// Signs the calls that have the gateway sign data with the given key.
+    /// Required when the gateway has `authorized_keys` configured, in which
+    /// case the key must be the gateway key or one of the authorized keys.
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Returns the public key of the gateway and its onboarding key
+    pub async fn pubkey(&mut self) -> Result<(PublicKey, PublicKey)> {
+        let response = self.client.pubkey(PubkeyReq {}).await?.into_inner();
+        let public_key = PublicKey::try_from(response.address)?;
+        let onboarding_key = PublicKey::try_from(response.onboarding_address)?;
+        Ok((public_key, onboarding_key))
+    }
+
+    /// Returns the region the gateway currently operates in
+    pub async fn region(&mut self) -> Result<Region> {
+        let response = self.client.region(RegionReq {}).await?;
+        Ok(Region::from_i32(response.into_inner().region)?)
+    }
+
+    /// Signs the given data with the gateway keypair
+    pub async fn sign(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let request = authorized_request(
+            self.authorizer.as_ref(),
+            "sign",
+            SignReq {
+                data: data.to_vec(),
+            },
+        )?;
+        let response = self.client.sign(request).await?;
+        Ok(response.into_inner().signature)
+    }
+
+    /// Returns an add gateway transaction for the given owner and payer,
+    /// signed by the gateway
+    pub async fn add_gateway(
+        &mut self,
+        owner: &PublicKey,
+        payer: &PublicKey,
+        mode: GatewayStakingMode,
+    ) -> Result<BlockchainTxnAddGatewayV1> {
+        let request = authorized_request(
+            self.authorizer.as_ref(),
+            "add_gateway",
+            AddGatewayReq {
+                owner: owner.to_vec(),
+                payer: payer.to_vec(),
+                staking_mode: mode.into(),
+            },
+        )?;
+        let response = self.client.add_gateway(request).await?;
+        let envelope = BlockchainTxn::decode(response.into_inner().add_gateway_txn.as_slice())?;
+        match envelope.txn {
+            Some(Txn::AddGateway(txn)) => Ok(txn),
+            _ => Err(Error::InvalidEnvelope),
+        }
+    }
+}
+
+fn endpoint(uri: &str) -> Result<Endpoint> {
+    Endpoint::from_shared(uri.to_string())
+        .map(|endpoint| endpoint.connect_timeout(CONNECT_TIMEOUT))
+        .map_err(|_| Error::invalid_uri(uri))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uris() {
+        assert_eq!("http://127.0.0.1:4467", connect_uri(DEFAULT_PORT));
+        assert!(Client::connect_lazy("not a uri").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use thiserror::Error;
+
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unable to connect to local server. Check that `helium_gateway` is running.")]
+    Connect(#[source] helium_proto::services::Error),
+    #[error("invalid uri {0}")]
+    InvalidUri(String),
+    #[error("rpc {0:?}")]
+    Rpc(#[from] tonic::Status),
+    #[error("crypto error")]
+    Crypto(#[from] helium_crypto::Error),
+    #[error("region error")]
+    Region(#[from] beacon::Error),
+    #[error("protobuf decode")]
+    Decode(#[from] prost::DecodeError),
+    #[error("unexpected transaction envelope")]
+    InvalidEnvelope,
+}
+
+impl Error {
+    pub fn connect(err: helium_proto::services::Error) -> Self {
+        Self::Connect(err)
+    }
+
+    pub fn invalid_uri(uri: impl ToString) -> Self {
+        Self::InvalidUri(uri.to_string())
+    }
+}
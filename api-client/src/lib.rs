@@ -0,0 +1,30 @@
+//! Client for the local gRPC API of a running Helium gateway, as used by the
+//! `helium_gateway` subcommands. Use it to query the gateway keys and region,
+//! or have the gateway sign data, from applications on the same host without
+//! generating the API protobufs.
+//!
+//! ```no_run
+//! use gateway_api_client::{Client, DEFAULT_PORT};
+//!
+//! # async fn example() -> gateway_api_client::Result {
+//! let mut client = Client::connect(DEFAULT_PORT).await?;
+//! let (public_key, _onboarding_key) = client.pubkey().await?;
+//! let region = client.region().await?;
+//! println!("{public_key} in {region}");
+//! # Ok(())
+//! # }
+//! ```
+
+mod auth;
+mod client;
+mod error;
+
+pub use auth::{
+    authorization_message, Authorizer, KEY_HEADER, MAX_CLOCK_SKEW_MS, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
+pub use beacon::Region;
+pub use client::{connect_uri, listen_addr, Client, CONNECT_TIMEOUT, DEFAULT_PORT, LISTEN_ADDR};
+pub use error::{Error, Result};
+pub use helium_crypto::PublicKey;
+pub use helium_proto::{BlockchainTxnAddGatewayV1, GatewayStakingMode};
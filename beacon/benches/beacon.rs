@@ -0,0 +1,89 @@
+//! Beacon generation and report signing benchmarks.
+//!
+//! Run with `cargo bench -p beacon`. On aarch64 targets compare against a
+//! build with `--features sha2/force-soft` to see the effect of the SHA-256
+//! hardware path.
+
+use beacon::{Beacon, Entropy, Region, RegionParams};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use helium_crypto::{KeyTag, KeyType, Keypair, Network, Sign};
+use helium_proto::{
+    services::poc_lora::LoraBeaconReportReqV1, BlockchainRegionParamV1,
+    BlockchainRegionSpreadingV1, Message, Region as ProtoRegion, RegionSpreading, TaggedSpreading,
+};
+use rand::rngs::OsRng;
+
+fn region_params() -> RegionParams {
+    let spreading = BlockchainRegionSpreadingV1 {
+        tagged_spreading: vec![TaggedSpreading {
+            region_spreading: RegionSpreading::Sf7.into(),
+            max_packet_size: 255,
+        }],
+    };
+    let params = [868_100_000, 868_300_000, 868_500_000]
+        .into_iter()
+        .map(|channel_frequency| BlockchainRegionParamV1 {
+            channel_frequency,
+            bandwidth: 125_000,
+            max_eirp: 160,
+            spreading: Some(spreading.clone()),
+        })
+        .collect();
+    RegionParams {
+        gain: 12.into(),
+        region: Region::from(ProtoRegion::Eu868),
+        params,
+        name: None,
+    }
+}
+
+fn beacon(region_params: &RegionParams) -> Beacon {
+    let remote_entropy = Entropy::from_data(vec![7u8; 32]).expect("remote entropy");
+    let local_entropy = Entropy::from_data(vec![3u8; 4]).expect("local entropy");
+    Beacon::new(remote_entropy, local_entropy, region_params).expect("beacon")
+}
+
+fn bench_beacon(c: &mut Criterion) {
+    let region_params = region_params();
+    c.bench_function("beacon_new", |b| {
+        b.iter(|| beacon(black_box(&region_params)))
+    });
+    c.bench_function("beacon_local_entropy", |b| {
+        b.iter(|| Entropy::local().expect("local entropy"))
+    });
+
+    let beacon = beacon(&region_params);
+    c.bench_function("beacon_report_encode", |b| {
+        b.iter(|| {
+            LoraBeaconReportReqV1::try_from(black_box(beacon.clone()))
+                .expect("beacon report")
+                .encode_to_vec()
+        })
+    });
+}
+
+fn bench_sign(c: &mut Criterion) {
+    let report = LoraBeaconReportReqV1::try_from(beacon(&region_params()))
+        .expect("beacon report")
+        .encode_to_vec();
+    let mut group = c.benchmark_group("beacon_report_sign");
+    for (name, key_type) in [
+        ("ed25519", KeyType::Ed25519),
+        ("ecc_compact", KeyType::EccCompact),
+    ] {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type,
+            },
+            &mut OsRng,
+        );
+        group.bench_function(name, |b| {
+            b.iter(|| keypair.sign(black_box(&report)).expect("signature"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_beacon, bench_sign);
+criterion_main!(benches);
@@ -1,18 +1,25 @@
-use crate::{Entropy, Error, RegionParams, Result};
+use crate::{Entropy, Error, LoraDataRate, RegionParams, Result};
 use byteorder::{ByteOrder, LittleEndian};
-use helium_proto::{services::poc_lora, DataRate};
+use helium_proto::services::poc_lora;
 use rand::{Rng, SeedableRng};
 use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The payload size of version 0 and 1 beacons, and the largest payload size
+/// of later versions
 pub const BEACON_PAYLOAD_SIZE: usize = 51;
+/// The smallest payload size of version 2 beacons. The first two bytes select
+/// the frequency and the rest has to be unlikely to collide.
+pub const MIN_BEACON_PAYLOAD_SIZE: usize = 10;
+/// The most recent beacon version
+pub const BEACON_VERSION: u32 = 2;
 
 #[derive(Debug, Clone, Eq)]
 pub struct Beacon {
     pub data: Vec<u8>,
 
     pub frequency: u64,
-    pub datarate: DataRate,
+    pub datarate: LoraDataRate,
     pub remote_entropy: Entropy,
     pub local_entropy: Entropy,
     pub conducted_power: u32,
@@ -38,22 +45,20 @@ impl Beacon {
     /// Construct a new beacon with a given remote and local entropy. The remote
     /// and local entropy are checked for version equality.
     ///
-    /// Version 0/1 beacons use a Sha256 of the remote and local entropy (data
-    /// and timestamp), which is then used (truncated) as the beacon payload.
-    /// The frequency is derived from the first two bytes of the beacon payload,
-    /// while the data_rate is derived from the packet size (spreading factor)
-    /// and bandwidth as set in the region parameters
+    /// Version 0/1/2 beacons use a Sha256 of the remote and local entropy
+    /// (data and timestamp) to seed the random beacon payload, which has the
+    /// size given by [`Beacon::payload_size`] for the version of the remote
+    /// entropy. The frequency is derived from the first two bytes of the
+    /// beacon payload, while the data_rate is derived from the packet size
+    /// (spreading factor) and bandwidth as set in the region parameters
     pub fn new(
         remote_entropy: Entropy,
         local_entropy: Entropy,
         region_params: &RegionParams,
     ) -> Result<Self> {
         match remote_entropy.version {
-            0 | 1 => {
-                if region_params.params.is_empty() {
-                    return Err(Error::no_region_params());
-                }
-
+            0..=BEACON_VERSION => {
+                let payload_size = Self::payload_size(region_params, remote_entropy.version)?;
                 let seed_data = {
                     let mut hasher = Sha256::new();
                     remote_entropy.digest(&mut hasher);
@@ -68,20 +73,23 @@ impl Beacon {
                 // Make a random generator
                 let mut rng = rand_chacha::ChaCha12Rng::from_seed(seed);
 
-                let data = rand_payload(&mut rng, BEACON_PAYLOAD_SIZE);
+                let data = rand_payload(&mut rng, payload_size);
 
                 // Selet frequency based on the the first two bytes of the
                 // beacon data
                 let freq_seed = LittleEndian::read_u16(&data) as usize;
-                let frequency =
-                    region_params.params[freq_seed % region_params.params.len()].channel_frequency;
+                let frequency = freq_seed
+                    .checked_rem(region_params.params.len())
+                    .and_then(|index| region_params.params.get(index))
+                    .map(|params| params.channel_frequency)
+                    .ok_or_else(Error::no_region_params)?;
                 let datarate = region_params.select_datarate(data.len())?;
                 let conducted_power = region_params.max_conducted_power()?;
 
                 Ok(Self {
                     data,
                     frequency,
-                    datarate: datarate.to_owned(),
+                    datarate,
                     local_entropy,
                     remote_entropy,
                     conducted_power,
@@ -91,6 +99,42 @@ impl Beacon {
         }
     }
 
+    /// The beacon payload size for the given beacon version in the given
+    /// region.
+    ///
+    /// Version 0 and 1 beacons always have a [`BEACON_PAYLOAD_SIZE`] payload.
+    /// Version 2 beacons are smaller in plans that can not send a payload of
+    /// that size at the slowest spreading (DR0), so the beacon fits every
+    /// datarate the plan offers it. Plans that can not fit a
+    /// [`MIN_BEACON_PAYLOAD_SIZE`] payload at DR0 can not beacon.
+    pub fn payload_size(region_params: &RegionParams, version: u32) -> Result<usize> {
+        match version {
+            0 | 1 => Ok(BEACON_PAYLOAD_SIZE),
+            2 => {
+                let size = region_params
+                    .dr0_max_packet_size()?
+                    .min(BEACON_PAYLOAD_SIZE);
+                if size < MIN_BEACON_PAYLOAD_SIZE {
+                    return Err(Error::no_region_spreading_for_size(MIN_BEACON_PAYLOAD_SIZE));
+                }
+                Ok(size)
+            }
+            _ => Err(Error::invalid_version()),
+        }
+    }
+
+    /// Whether a payload of the given size is a beacon of any version in the
+    /// given region. Without region parameters only the version 0 and 1 size
+    /// and the version 2 size range can be checked.
+    fn is_payload_size(len: usize, region_params: &RegionParams) -> bool {
+        if region_params.params.is_empty() {
+            return (MIN_BEACON_PAYLOAD_SIZE..=BEACON_PAYLOAD_SIZE).contains(&len);
+        }
+        (0..=BEACON_VERSION)
+            .filter_map(|version| Self::payload_size(region_params, version).ok())
+            .any(|size| size == len)
+    }
+
     pub fn beacon_id(&self) -> String {
         use base64::Engine;
         base64::engine::general_purpose::STANDARD.encode(&self.data)
@@ -105,6 +149,62 @@ impl Beacon {
     pub fn verify(&self, reported: &Beacon) -> bool {
         self.eq(reported) // && self.conducted_power >= reported.conducted_power
     }
+
+    /// Verifies that a received beacon payload, with the frequency (in Hz)
+    /// and datarate it was received on, could have been transmitted as a
+    /// beacon under the given region parameters. This checks the payload
+    /// length, that the frequency is one of the region's channels and that
+    /// the datarate is the one selected for a packet of the payload length.
+    ///
+    /// This does not check the payload itself since that requires the
+    /// entropy the beacon was constructed with.
+    pub fn verify_received(
+        payload: &[u8],
+        frequency: u64,
+        datarate: &LoraDataRate,
+        region_params: &RegionParams,
+    ) -> Result {
+        if region_params.params.is_empty() {
+            return Err(Error::no_region_params());
+        }
+        // The datarate check below covers whether the length fits the
+        // datarate
+        if !Self::is_payload_size(payload.len(), region_params) {
+            return Err(Error::invalid_beacon_length(payload.len()));
+        }
+        if !region_params.has_channel_frequency(frequency) {
+            return Err(Error::invalid_beacon_frequency(frequency));
+        }
+        let expected = region_params.select_datarate(payload.len())?;
+        if datarate != &expected {
+            return Err(Error::invalid_beacon_datarate(datarate, expected));
+        }
+        Ok(())
+    }
+
+    /// Verifies that a payload of the given length received at the given
+    /// datarate could be a beacon. The length has to be the payload size of
+    /// a beacon version and, when region parameters are known, fit the
+    /// maximum packet size of the region at the datarate.
+    ///
+    /// This is a cheap check on the reception alone, to reject malformed
+    /// receptions before a witness report is built for them.
+    pub fn verify_received_length(
+        len: usize,
+        datarate: &LoraDataRate,
+        region_params: &RegionParams,
+    ) -> Result {
+        if !Self::is_payload_size(len, region_params) {
+            return Err(Error::invalid_beacon_length(len));
+        }
+        if region_params.params.is_empty() {
+            return Ok(());
+        }
+        match region_params.max_packet_size(datarate) {
+            Some(max_packet_size) if len <= max_packet_size => Ok(()),
+            _ => Err(Error::invalid_beacon_length(len)),
+        }
+    }
 }
 
 fn rand_payload<R>(rng: &mut R, size: usize) -> Vec<u8>
@@ -116,9 +216,15 @@ where
         .collect::<Vec<u8>>()
 }
 
+/// Fails for 2.4 GHz beacons since reports can not represent their datarate
+/// yet.
 impl TryFrom<Beacon> for poc_lora::LoraBeaconReportReqV1 {
     type Error = Error;
     fn try_from(v: Beacon) -> Result<Self> {
+        let datarate = v
+            .datarate
+            .to_proto()
+            .ok_or_else(|| Error::unsupported_datarate(v.datarate))?;
         Ok(Self {
             pub_key: vec![],
             local_entropy: v.local_entropy.data,
@@ -126,7 +232,7 @@ impl TryFrom<Beacon> for poc_lora::LoraBeaconReportReqV1 {
             data: v.data,
             frequency: v.frequency,
             channel: 0,
-            datarate: v.datarate as i32,
+            datarate: datarate as i32,
             tmst: 0,
             // This is the initial value. The beacon sender updates this value
             // with the actual conducted power reported by the packet forwarder.
@@ -154,4 +260,149 @@ mod test {
 
         assert_eq!(BEACON_PAYLOAD_SIZE, data.len());
     }
+
+    #[test]
+    fn test_payload_size() {
+        use helium_proto::{
+            BlockchainRegionParamV1, BlockchainRegionSpreadingV1, Region as ProtoRegion,
+            RegionSpreading, TaggedSpreading,
+        };
+        use RegionSpreading::*;
+
+        // Tagged spreadings in region params order
+        let plan = |region: ProtoRegion, spreadings: &[(RegionSpreading, u32)]| RegionParams {
+            gain: 12.into(),
+            region: region.into(),
+            params: vec![BlockchainRegionParamV1 {
+                channel_frequency: 903_900_000,
+                bandwidth: 125_000,
+                max_eirp: 300,
+                spreading: Some(BlockchainRegionSpreadingV1 {
+                    tagged_spreading: spreadings
+                        .iter()
+                        .map(|(spreading, max_packet_size)| TaggedSpreading {
+                            region_spreading: (*spreading).into(),
+                            max_packet_size: *max_packet_size,
+                        })
+                        .collect(),
+                }),
+            }],
+            name: None,
+        };
+        let eu868 = plan(ProtoRegion::Eu868, &[(Sf12, 65), (Sf9, 129), (Sf8, 238)]);
+        let us915 = plan(
+            ProtoRegion::Us915,
+            &[(Sf10, 24), (Sf9, 53), (Sf8, 125), (Sf7, 242)],
+        );
+        let as923 = plan(ProtoRegion::As9231, &[(Sf10, 19), (Sf9, 61), (Sf8, 133)]);
+        let tiny = plan(ProtoRegion::Us915, &[(Sf10, 8), (Sf9, 53)]);
+
+        // Region, version, payload size and the datarate it is sent at
+        let matrix = [
+            (&eu868, 0, 51, "SF12BW125"),
+            (&eu868, 1, 51, "SF12BW125"),
+            (&eu868, 2, 51, "SF12BW125"),
+            (&us915, 1, 51, "SF9BW125"),
+            (&us915, 2, 24, "SF10BW125"),
+            (&as923, 1, 51, "SF9BW125"),
+            (&as923, 2, 19, "SF10BW125"),
+            (&tiny, 1, 51, "SF9BW125"),
+        ];
+        for (region_params, version, size, datarate) in matrix {
+            let payload_size = Beacon::payload_size(region_params, version).expect("size");
+            assert_eq!(size, payload_size, "{} v{version}", region_params.region);
+            let selected = region_params
+                .select_datarate(payload_size)
+                .expect("datarate");
+            assert_eq!(datarate, selected.to_string(), "{}", region_params.region);
+        }
+
+        // DR0 is too small for a version 2 beacon
+        assert!(Beacon::payload_size(&tiny, 2).is_err());
+        assert!(matches!(
+            Beacon::payload_size(&eu868, BEACON_VERSION + 1),
+            Err(Error::InvalidVersion)
+        ));
+        assert!(Beacon::payload_size(&RegionParams::from(eu868.region), 2).is_err());
+
+        // Both version sizes are legal for received beacons
+        assert!(Beacon::is_payload_size(51, &us915));
+        assert!(Beacon::is_payload_size(24, &us915));
+        assert!(!Beacon::is_payload_size(19, &us915));
+    }
+
+    #[test]
+    fn test_verify_received() {
+        use helium_proto::{
+            BlockchainRegionParamV1, BlockchainRegionSpreadingV1, Region as ProtoRegion,
+            RegionSpreading, TaggedSpreading,
+        };
+
+        let channel = |channel_frequency| BlockchainRegionParamV1 {
+            channel_frequency,
+            bandwidth: 125_000,
+            max_eirp: 160,
+            spreading: Some(BlockchainRegionSpreadingV1 {
+                tagged_spreading: vec![
+                    TaggedSpreading {
+                        region_spreading: RegionSpreading::Sf12.into(),
+                        max_packet_size: 25,
+                    },
+                    TaggedSpreading {
+                        region_spreading: RegionSpreading::Sf9.into(),
+                        max_packet_size: 115,
+                    },
+                ],
+            }),
+        };
+        let region = ProtoRegion::Eu868.into();
+        let region_params = RegionParams {
+            gain: 12.into(),
+            region,
+            params: vec![channel(868_100_000), channel(868_300_000)],
+            name: None,
+        };
+        let payload = [0u8; BEACON_PAYLOAD_SIZE];
+        let sf9: LoraDataRate = "SF9BW125".parse().expect("datarate");
+        let verify = |payload: &[u8], frequency, datarate: &str| {
+            let datarate: LoraDataRate = datarate.parse().expect("datarate");
+            Beacon::verify_received(payload, frequency, &datarate, &region_params)
+        };
+
+        assert!(Beacon::verify_received(&payload, 868_300_000, &sf9, &region_params).is_ok());
+        assert!(matches!(
+            verify(&payload[..20], 868_300_000, "SF9BW125"),
+            Err(Error::InvalidBeaconLength(20))
+        ));
+        assert!(matches!(
+            verify(&payload, 868_500_000, "SF9BW125"),
+            Err(Error::InvalidBeaconFrequency(868_500_000))
+        ));
+        assert!(matches!(
+            verify(&payload, 868_100_000, "SF12BW125"),
+            Err(Error::InvalidBeaconDataRate(_, _))
+        ));
+        assert!(matches!(
+            Beacon::verify_received(&payload, 868_100_000, &sf9, &RegionParams::from(region)),
+            Err(Error::NoRegionParams)
+        ));
+
+        let length = |len, datarate: &str, region_params: &RegionParams| {
+            let datarate: LoraDataRate = datarate.parse().expect("datarate");
+            Beacon::verify_received_length(len, &datarate, region_params)
+        };
+        assert!(length(BEACON_PAYLOAD_SIZE, "SF9BW125", &region_params).is_ok());
+        // SF12 only allows 25 byte packets in these region params
+        assert!(matches!(
+            length(BEACON_PAYLOAD_SIZE, "SF12BW125", &region_params),
+            Err(Error::InvalidBeaconLength(BEACON_PAYLOAD_SIZE))
+        ));
+        // No SF10 in these region params
+        assert!(length(BEACON_PAYLOAD_SIZE, "SF10BW125", &region_params).is_err());
+        assert!(length(BEACON_PAYLOAD_SIZE, "SF9BW250", &region_params).is_err());
+        // Only the beacon size is checked without region params
+        let no_params = RegionParams::from(region);
+        assert!(length(BEACON_PAYLOAD_SIZE, "SF12BW125", &no_params).is_ok());
+        assert!(length(52, "SF9BW125", &no_params).is_err());
+    }
 }
@@ -0,0 +1,122 @@
+use crate::{Error, Result};
+use helium_proto::DataRate;
+use std::{fmt, str::FromStr};
+
+/// Sub-GHz LoRa bandwidths in kHz
+const SUB_GHZ_BANDWIDTHS: [u32; 3] = [125, 250, 500];
+/// 2.4 GHz (SX1280) LoRa bandwidths in kHz, truncated like the 2.4 GHz packet
+/// forwarder names them (203.125 kHz is "BW203")
+const ISM2400_BANDWIDTHS: [u32; 4] = [203, 406, 812, 1625];
+
+/// A LoRa datarate for sub-GHz and 2.4 GHz channel plans.
+///
+/// The protobuf `DataRate` only covers sub-GHz datarates. 2.4 GHz datarates
+/// can be used to construct and transmit beacons but can not be reported until
+/// the protobuf definitions include them, see [`LoraDataRate::to_proto`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoraDataRate {
+    spreading: u8,
+    bandwidth: u32,
+}
+
+impl LoraDataRate {
+    /// Construct a datarate from a spreading factor and a bandwidth in kHz.
+    /// The 2.4 GHz bandwidths also allow spreading factors 5 and 6.
+    pub fn new(spreading: u8, bandwidth: u32) -> Result<Self> {
+        let min_spreading = if ISM2400_BANDWIDTHS.contains(&bandwidth) {
+            5
+        } else if SUB_GHZ_BANDWIDTHS.contains(&bandwidth) {
+            7
+        } else {
+            return Err(Error::unsupported_datarate(format!(
+                "SF{spreading}BW{bandwidth}"
+            )));
+        };
+        if !(min_spreading..=12).contains(&spreading) {
+            return Err(Error::unsupported_datarate(format!(
+                "SF{spreading}BW{bandwidth}"
+            )));
+        }
+        Ok(Self {
+            spreading,
+            bandwidth,
+        })
+    }
+
+    pub fn spreading(&self) -> u8 {
+        self.spreading
+    }
+
+    /// The bandwidth in kHz
+    pub fn bandwidth(&self) -> u32 {
+        self.bandwidth
+    }
+
+    /// Whether this is a 2.4 GHz datarate
+    pub fn is_ism2400(&self) -> bool {
+        ISM2400_BANDWIDTHS.contains(&self.bandwidth)
+    }
+
+    /// The protobuf datarate, if there is one
+    pub fn to_proto(&self) -> Option<DataRate> {
+        DataRate::from_str(&self.to_string()).ok()
+    }
+}
+
+impl fmt::Display for LoraDataRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SF{}BW{}", self.spreading, self.bandwidth)
+    }
+}
+
+impl FromStr for LoraDataRate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::unsupported_datarate(s.to_string());
+        let upper = s.to_ascii_uppercase();
+        let (spreading, bandwidth) = upper
+            .strip_prefix("SF")
+            .and_then(|rest| rest.split_once("BW"))
+            .ok_or_else(invalid)?;
+        let spreading = spreading.parse().map_err(|_| invalid())?;
+        let bandwidth = bandwidth.parse().map_err(|_| invalid())?;
+        Self::new(spreading, bandwidth)
+    }
+}
+
+impl TryFrom<DataRate> for LoraDataRate {
+    type Error = Error;
+
+    fn try_from(datarate: DataRate) -> Result<Self> {
+        datarate.as_str_name().parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let datarate: LoraDataRate = "SF12BW125".parse().expect("datarate");
+        assert_eq!(Some(DataRate::Sf12bw125), datarate.to_proto());
+        assert!(!datarate.is_ism2400());
+
+        let datarate: LoraDataRate = "sf5bw812".parse().expect("datarate");
+        assert_eq!("SF5BW812", datarate.to_string());
+        assert!(datarate.is_ism2400());
+        assert_eq!(None, datarate.to_proto());
+
+        assert!("SF5BW125".parse::<LoraDataRate>().is_err());
+        assert!("SF13BW812".parse::<LoraDataRate>().is_err());
+        assert!("SF7BW300".parse::<LoraDataRate>().is_err());
+        assert!("FSK50".parse::<LoraDataRate>().is_err());
+        assert_eq!(
+            Some("SF9BW500".to_string()),
+            LoraDataRate::try_from(DataRate::Sf9bw500)
+                .ok()
+                .map(|datarate| datarate.to_string())
+        );
+    }
+}
@@ -4,7 +4,12 @@ use helium_proto::EntropyReportV1;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fs::File,
+    io::Read,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub const LOCAL_ENTROPY_SIZE: usize = 4;
 
@@ -21,8 +26,13 @@ impl Entropy {
     /// Construct entropy from a local system source. The timestamp and version
     /// of local entropy is always 0.
     pub fn local() -> Result<Self> {
+        Self::local_from(&mut OsRngSource)
+    }
+
+    /// Construct local entropy from the given entropy source.
+    pub fn local_from(source: &mut dyn EntropySource) -> Result<Self> {
         let mut local_entropy = vec![0u8; LOCAL_ENTROPY_SIZE];
-        OsRng.fill_bytes(&mut local_entropy);
+        source.fill(&mut local_entropy)?;
         Ok(Self {
             version: 0,
             timestamp: 0,
@@ -48,6 +58,69 @@ impl Entropy {
     }
 }
 
+/// A source of local entropy
+pub trait EntropySource: Send {
+    /// Fill the given buffer with random bytes
+    fn fill(&mut self, buf: &mut [u8]) -> Result;
+}
+
+/// Entropy from the operating system random number generator
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsRngSource;
+
+impl EntropySource for OsRngSource {
+    fn fill(&mut self, buf: &mut [u8]) -> Result {
+        OsRng.try_fill_bytes(buf).map_err(Error::unhealthy_entropy)
+    }
+}
+
+/// Entropy read from a (hardware) random number generator device like
+/// `/dev/hwrng`
+#[derive(Debug, Clone)]
+pub struct DeviceSource(PathBuf);
+
+impl DeviceSource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self(path.into())
+    }
+}
+
+impl EntropySource for DeviceSource {
+    fn fill(&mut self, buf: &mut [u8]) -> Result {
+        File::open(&self.0)?.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+/// Wraps an entropy source with checks that detect a stuck or broken
+/// generator: output where all bytes are equal, or output that repeats the
+/// previous output, is rejected.
+#[derive(Debug)]
+pub struct HealthChecked<S> {
+    source: S,
+    last: Option<Vec<u8>>,
+}
+
+impl<S: EntropySource> HealthChecked<S> {
+    pub fn new(source: S) -> Self {
+        Self { source, last: None }
+    }
+}
+
+impl<S: EntropySource> EntropySource for HealthChecked<S> {
+    fn fill(&mut self, buf: &mut [u8]) -> Result {
+        self.source.fill(buf)?;
+        if buf.len() > 1 && buf.iter().all(|b| *b == buf[0]) {
+            return Err(Error::unhealthy_entropy("constant output"));
+        }
+        if self.last.as_deref() == Some(&*buf) {
+            return Err(Error::unhealthy_entropy("repeated output"));
+        }
+        self.last = Some(buf.to_vec());
+        Ok(())
+    }
+}
+
 fn default_version() -> u32 {
     0
 }
@@ -126,4 +199,25 @@ mod test {
         assert_eq!(DATA, *ser_entropy.get("data").unwrap());
         assert_eq!(DATA, entropy.to_string());
     }
+
+    #[test]
+    fn test_health_check() {
+        struct Fixed(u8);
+        impl EntropySource for Fixed {
+            fn fill(&mut self, buf: &mut [u8]) -> Result {
+                buf.iter_mut()
+                    .enumerate()
+                    .for_each(|(i, b)| *b = self.0.wrapping_add(i as u8));
+                Ok(())
+            }
+        }
+
+        let mut stuck = HealthChecked::new(Fixed(1));
+        assert!(Entropy::local_from(&mut stuck).is_ok());
+        assert!(Entropy::local_from(&mut stuck).is_err());
+
+        let mut os = HealthChecked::new(OsRngSource);
+        assert!(Entropy::local_from(&mut os).is_ok());
+        assert!(Entropy::local_from(&mut os).is_ok());
+    }
 }
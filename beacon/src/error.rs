@@ -24,6 +24,18 @@ pub enum Error {
     InvalidVersion,
     #[error("no valid datarate found")]
     NoDataRate,
+    #[error("unsupported datarate {0}")]
+    UnsupportedDataRate(String),
+    #[error("invalid beacon payload length {0}")]
+    InvalidBeaconLength(usize),
+    #[error("beacon frequency {0} not in region plan")]
+    InvalidBeaconFrequency(u64),
+    #[error("beacon datarate {0} not allowed, expected {1}")]
+    InvalidBeaconDataRate(String, String),
+    #[error("entropy source io error")]
+    EntropyIo(#[from] std::io::Error),
+    #[error("entropy source unhealthy: {0}")]
+    UnhealthyEntropy(String),
 }
 
 impl Error {
@@ -57,4 +69,24 @@ impl Error {
     pub fn no_data_rate() -> Self {
         Self::NoDataRate
     }
+
+    pub fn unsupported_datarate<T: ToString>(datarate: T) -> Self {
+        Self::UnsupportedDataRate(datarate.to_string())
+    }
+
+    pub fn invalid_beacon_length(len: usize) -> Self {
+        Self::InvalidBeaconLength(len)
+    }
+
+    pub fn invalid_beacon_frequency(frequency: u64) -> Self {
+        Self::InvalidBeaconFrequency(frequency)
+    }
+
+    pub fn invalid_beacon_datarate<T: ToString, E: ToString>(datarate: T, expected: E) -> Self {
+        Self::InvalidBeaconDataRate(datarate.to_string(), expected.to_string())
+    }
+
+    pub fn unhealthy_entropy<T: ToString>(reason: T) -> Self {
+        Self::UnhealthyEntropy(reason.to_string())
+    }
 }
@@ -1,9 +1,11 @@
 mod beacon;
+mod datarate;
 mod entropy;
 mod error;
 mod region;
 
-pub use beacon::Beacon;
-pub use entropy::Entropy;
+pub use beacon::{Beacon, BEACON_PAYLOAD_SIZE, BEACON_VERSION, MIN_BEACON_PAYLOAD_SIZE};
+pub use datarate::LoraDataRate;
+pub use entropy::{DeviceSource, Entropy, EntropySource, HealthChecked, OsRngSource};
 pub use error::{Error, Result};
 pub use region::{Region, RegionParams};
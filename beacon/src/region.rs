@@ -1,13 +1,17 @@
-use crate::{Error, Result};
+use crate::{Error, LoraDataRate, Result};
 use helium_proto::{
     services::iot_config::GatewayRegionParamsResV1, BlockchainRegionParamV1,
-    BlockchainRegionParamsV1, DataRate, GatewayRegionParamsRespV1,
-    GatewayRegionParamsStreamedRespV1, Message, Region as ProtoRegion, RegionSpreading,
+    BlockchainRegionParamsV1, GatewayRegionParamsRespV1, GatewayRegionParamsStreamedRespV1,
+    Message, Region as ProtoRegion, RegionSpreading,
 };
 use rust_decimal::prelude::{Decimal, ToPrimitive};
 use serde::{de, Deserialize, Deserializer};
 use std::{fmt, str::FromStr};
 
+/// The 2.4 GHz ISM band (in Hz)
+const ISM2400_MIN_FREQUENCY: u64 = 2_400_000_000;
+const ISM2400_MAX_FREQUENCY: u64 = 2_500_000_000;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Region(ProtoRegion);
 
@@ -82,6 +86,10 @@ pub struct RegionParams {
     pub gain: Decimal,
     pub region: Region,
     pub params: Vec<BlockchainRegionParamV1>,
+    /// The name of a custom region, like for a private network, which uses
+    /// its own channels under the identity of `region`. None for the
+    /// standard regions.
+    pub name: Option<String>,
 }
 
 impl AsRef<[BlockchainRegionParamV1]> for RegionParams {
@@ -92,7 +100,10 @@ impl AsRef<[BlockchainRegionParamV1]> for RegionParams {
 
 impl PartialEq for RegionParams {
     fn eq(&self, other: &Self) -> bool {
-        self.gain.eq(&other.gain) && self.region.eq(&other.region) && self.params.eq(&other.params)
+        self.gain.eq(&other.gain)
+            && self.region.eq(&other.region)
+            && self.params.eq(&other.params)
+            && self.name.eq(&other.name)
     }
 }
 
@@ -108,6 +119,7 @@ impl TryFrom<GatewayRegionParamsStreamedRespV1> for RegionParams {
             gain: Decimal::new(value.gain as i64, 1),
             params,
             region,
+            name: None,
         })
     }
 }
@@ -124,6 +136,7 @@ impl TryFrom<GatewayRegionParamsRespV1> for RegionParams {
             gain: Decimal::new(value.gain as i64, 1),
             params,
             region,
+            name: None,
         })
     }
 }
@@ -140,6 +153,7 @@ impl TryFrom<GatewayRegionParamsResV1> for RegionParams {
             gain: Decimal::new(value.gain as i64, 1),
             params,
             region,
+            name: None,
         })
     }
 }
@@ -150,6 +164,7 @@ impl From<Region> for RegionParams {
             region,
             gain: 0.into(),
             params: vec![],
+            name: None,
         }
     }
 }
@@ -162,6 +177,7 @@ impl RegionParams {
             region,
             gain,
             params,
+            name: None,
         })
     }
 }
@@ -235,24 +251,96 @@ impl RegionParams {
     }
 
     /// Convenience function to select a spreading and bandwidth for a given
-    /// packet size and convert to a DataRate
-    pub fn select_datarate(&self, packet_size: usize) -> Result<DataRate> {
+    /// packet size and convert to a datarate. 2.4 GHz channel plans result
+    /// in 2.4 GHz datarates.
+    pub fn select_datarate(&self, packet_size: usize) -> Result<LoraDataRate> {
         let spreading = self.select_spreading(packet_size)?.as_str_name();
         let bandwidth = self.bandwidth()? / 1000; // in khz
 
-        DataRate::from_str(&format!("{spreading}BW{bandwidth}")).map_err(|_| Error::no_data_rate())
+        format!("{spreading}BW{bandwidth}")
+            .parse()
+            .map_err(|_| Error::no_data_rate())
+    }
+
+    /// The maximum packet size the region parameters allow at the given
+    /// datarate. None when the region parameters have no spreading for the
+    /// datarate.
+    pub fn max_packet_size(&self, datarate: &LoraDataRate) -> Option<usize> {
+        let params = self.params.first()?;
+        if params.bandwidth / 1000 != datarate.bandwidth() {
+            return None;
+        }
+        params
+            .spreading
+            .as_ref()?
+            .tagged_spreading
+            .iter()
+            .find(|tagged_spreading| {
+                spreading_factor(tagged_spreading.region_spreading) == Some(datarate.spreading())
+            })
+            .map(|tagged_spreading| tagged_spreading.max_packet_size as usize)
+    }
+
+    /// The maximum packet size at the slowest spreading of the region
+    /// parameters (DR0 in most plans). Packets of this size can be sent at
+    /// every spreading the plan allows for them.
+    pub fn dr0_max_packet_size(&self) -> Result<usize> {
+        self.params
+            .first()
+            .ok_or_else(Error::no_region_params)?
+            .spreading
+            .as_ref()
+            .ok_or_else(Error::no_region_spreading)?
+            .tagged_spreading
+            .iter()
+            .filter_map(|tagged_spreading| {
+                spreading_factor(tagged_spreading.region_spreading)
+                    .map(|spreading| (spreading, tagged_spreading.max_packet_size as usize))
+            })
+            .max_by_key(|(spreading, _)| *spreading)
+            .map(|(_, max_packet_size)| max_packet_size)
+            .ok_or_else(Error::no_region_spreading)
+    }
+
+    /// Whether the given frequency (in Hz) is one of the channel
+    /// frequencies of the region parameters
+    pub fn has_channel_frequency(&self, frequency: u64) -> bool {
+        self.params
+            .iter()
+            .any(|params| params.channel_frequency == frequency)
+    }
+
+    /// Whether these are parameters for a 2.4 GHz channel plan
+    pub fn is_ism2400(&self) -> bool {
+        !self.params.is_empty()
+            && self.params.iter().all(|params| {
+                (ISM2400_MIN_FREQUENCY..ISM2400_MAX_FREQUENCY).contains(&params.channel_frequency)
+            })
     }
 }
 
+/// The spreading factor of a protobuf region spreading, like 12 for SF12
+fn spreading_factor(region_spreading: i32) -> Option<u8> {
+    RegionSpreading::from_i32(region_spreading)?
+        .as_str_name()
+        .strip_prefix("SF")?
+        .parse()
+        .ok()
+}
+
 impl std::fmt::Display for RegionParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.region.fmt(f)
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.region),
+            None => self.region.fmt(f),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use helium_proto::DataRate;
 
     const EU868_PARAMS: &[u8] = &[
         10, 35, 8, 224, 202, 187, 157, 3, 16, 200, 208, 7, 24, 161, 1, 34, 20, 10, 4, 8, 6, 16, 65,
@@ -274,18 +362,12 @@ mod test {
     fn test_select_datarate() {
         let region = ProtoRegion::Eu868.into();
         let params = RegionParams::from_bytes(region, 12, EU868_PARAMS).expect("region params");
-        assert_eq!(
-            DataRate::Sf12bw125,
-            params.select_datarate(30).expect("datarate")
-        );
-        assert_eq!(
-            DataRate::Sf9bw125,
-            params.select_datarate(90).expect("datarate")
-        );
-        assert_eq!(
-            DataRate::Sf8bw125,
-            params.select_datarate(130).expect("datarate")
-        );
+        let select = |size| params.select_datarate(size).expect("datarate").to_proto();
+        assert_eq!(Some(DataRate::Sf12bw125), select(30));
+        assert_eq!(Some(DataRate::Sf9bw125), select(90));
+        assert_eq!(Some(DataRate::Sf8bw125), select(130));
         assert!(params.select_datarate(300).is_err());
+        assert!(!params.is_ism2400());
+        assert_eq!(65, params.dr0_max_packet_size().expect("dr0 size"));
     }
 }
@@ -0,0 +1,15 @@
+//! LoRaWAN frame decoding in both directions
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lorawan::{Direction, PHYPayload};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    for direction in [Direction::Uplink, Direction::Downlink] {
+        if let Ok(payload) = PHYPayload::read(direction, &mut Cursor::new(data)) {
+            let mut encoded = vec![];
+            let _ = payload.write(&mut encoded);
+        }
+    }
+});
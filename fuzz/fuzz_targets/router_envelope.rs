@@ -0,0 +1,27 @@
+//! Downlinks as received from the packet router, checked and converted the
+//! way the packet router service does before they are transmitted
+#![no_main]
+
+use gateway_rs::{
+    service::quarantine::{self, Action},
+    Packet,
+};
+use helium_proto::{services::router::PacketRouterPacketDownV1, Message};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut downlink) = PacketRouterPacketDownV1::decode(data) else {
+        return;
+    };
+    let findings = quarantine::check_downlink(&mut downlink);
+    if findings
+        .iter()
+        .any(|finding| finding.action == Action::Dropped)
+    {
+        return;
+    }
+    if let Ok(packet) = Packet::try_from(downlink) {
+        let _ = packet.to_rx1_pull_resp(27);
+        let _ = packet.to_rx2_pull_resp(27);
+    }
+});
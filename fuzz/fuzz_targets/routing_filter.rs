@@ -0,0 +1,18 @@
+//! EUI and DevAddr routing filters as received from validators
+#![no_main]
+
+use gateway_rs::router::{DevAddrFilter, EuiFilter};
+use helium_proto::Eui;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(filter) = EuiFilter::from_bin(data) {
+        let _ = filter.contains(&Eui {
+            deveui: 0,
+            appeui: 0,
+        });
+    }
+    if let Ok(filter) = DevAddrFilter::from_bin(data) {
+        let _ = filter.contains(&0);
+    }
+});
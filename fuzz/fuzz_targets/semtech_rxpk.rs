@@ -0,0 +1,17 @@
+//! Received packets as sent by the packet forwarder in a push data message
+#![no_main]
+
+use gateway_rs::Packet;
+use libfuzzer_sys::fuzz_target;
+use semtech_udp::push_data;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(rxpk) = serde_json::from_slice::<push_data::RxPk>(data) else {
+        return;
+    };
+    if let Ok(packet) = Packet::try_from(rxpk) {
+        let _ = packet.is_potential_beacon();
+        let _ = packet.is_join_request();
+        let _ = packet.dc_payload();
+    }
+});
@@ -111,7 +111,8 @@ fn netid_type(devaddr: u32) -> u8 {
     netid_shift_prefix(first, 7)
 }
 
-fn parse_netid(devaddr: u32) -> u32 {
+/// The NetID a devaddr was allocated from
+pub fn parse_netid(devaddr: u32) -> u32 {
     fn get_netid(devaddr: u32, prefix_len: u8, nwkidbits: u32) -> u32 {
         (devaddr << (prefix_len - 1)) >> (31 - nwkidbits)
     }
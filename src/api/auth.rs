@@ -0,0 +1,228 @@
+use crate::{clock::unix_now_millis, PublicKey, Settings};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gateway_api_client::{
+    authorization_message, Authorizer, KEY_HEADER, MAX_CLOCK_SKEW_MS, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
+use helium_crypto::Verify;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+/// Verifies the authorization of mutating local API calls against the
+/// configured `authorized_keys`. See the `gateway-api-client` crate for the
+/// signing scheme.
+///
+/// A signed call can not be replayed: its timestamp must be later than the
+/// last accepted call of the same key.
+#[derive(Debug, Clone)]
+pub struct ApiAuth {
+    /// The gateway key and the authorized keys. Empty when no keys are
+    /// authorized, in which case calls are not checked.
+    keys: Vec<PublicKey>,
+    /// The timestamp of the last accepted call per (binary) key
+    accepted: Arc<Mutex<HashMap<Vec<u8>, u64>>>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("missing {0}")]
+    Missing(&'static str),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("timestamp outside of the allowed clock skew")]
+    Expired,
+    #[error("timestamp not after the last accepted call")]
+    Replayed,
+    #[error("key {0} is not authorized")]
+    Unauthorized(PublicKey),
+    #[error("invalid signature")]
+    Signature,
+}
+
+impl AuthError {
+    /// Whether the caller is known but not allowed, as opposed to the call
+    /// not being (validly) signed
+    pub fn is_forbidden(&self) -> bool {
+        matches!(self, Self::Unauthorized(_))
+    }
+}
+
+impl ApiAuth {
+    pub fn new(settings: &Settings) -> Self {
+        let keys = if settings.authorized_keys.is_empty() {
+            vec![]
+        } else {
+            let mut keys = vec![settings.keypair.public_key().to_owned()];
+            keys.extend(settings.authorized_keys.iter().cloned());
+            keys
+        };
+        Self {
+            keys,
+            accepted: Arc::default(),
+        }
+    }
+
+    /// Verifies that an operation with the given body was signed by an
+    /// authorized key. `header` looks up the authorization headers of the
+    /// call. Returns the key that signed the call, or `None` when no keys
+    /// are authorized.
+    pub fn verify<'a>(
+        &self,
+        header: impl Fn(&str) -> Option<&'a str>,
+        operation: &str,
+        body: &[u8],
+    ) -> Result<Option<PublicKey>, AuthError> {
+        let now = unix_now_millis();
+        self.verify_at(header, operation, body, now)
+    }
+
+    fn verify_at<'a>(
+        &self,
+        header: impl Fn(&str) -> Option<&'a str>,
+        operation: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<Option<PublicKey>, AuthError> {
+        if self.keys.is_empty() {
+            return Ok(None);
+        }
+        let header = |name: &'static str| header(name).ok_or(AuthError::Missing(name));
+        let key: PublicKey = header(KEY_HEADER)?
+            .parse()
+            .map_err(|_| AuthError::Invalid(KEY_HEADER))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| AuthError::Invalid(TIMESTAMP_HEADER))?;
+        let signature = STANDARD
+            .decode(header(SIGNATURE_HEADER)?)
+            .map_err(|_| AuthError::Invalid(SIGNATURE_HEADER))?;
+
+        if !self.keys.contains(&key) {
+            return Err(AuthError::Unauthorized(key));
+        }
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW_MS {
+            return Err(AuthError::Expired);
+        }
+        key.verify(
+            &authorization_message(operation, body, timestamp),
+            &signature,
+        )
+        .map_err(|_| AuthError::Signature)?;
+
+        let mut accepted = self.accepted.lock().expect("accepted timestamps");
+        let last = accepted.entry(key.to_vec()).or_default();
+        if timestamp <= *last {
+            return Err(AuthError::Replayed);
+        }
+        *last = timestamp;
+        Ok(Some(key))
+    }
+}
+
+impl Authorizer for crate::Keypair {
+    fn public_key(&self) -> PublicKey {
+        (**self).public_key().to_owned()
+    }
+
+    fn sign(&self, message: &[u8]) -> gateway_api_client::Result<Vec<u8>> {
+        Ok(helium_crypto::Sign::sign(self, message)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Base64;
+    use rand::rngs::OsRng;
+    use std::collections::HashMap;
+
+    fn keypair() -> helium_crypto::Keypair {
+        helium_crypto::Keypair::generate(
+            helium_crypto::KeyTag {
+                network: helium_crypto::Network::MainNet,
+                key_type: helium_crypto::KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+    }
+
+    fn headers(
+        keypair: &helium_crypto::Keypair,
+        operation: &str,
+        timestamp: u64,
+    ) -> HashMap<&'static str, String> {
+        let signature = Authorizer::sign(
+            keypair,
+            &authorization_message(operation, b"body", timestamp),
+        )
+        .expect("signature");
+        HashMap::from([
+            (KEY_HEADER, keypair.public_key().to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (SIGNATURE_HEADER, signature.to_b64()),
+        ])
+    }
+
+    #[test]
+    fn verify() {
+        let gateway = keypair();
+        let app = keypair();
+        let auth = ApiAuth {
+            keys: vec![gateway.public_key().to_owned(), app.public_key().to_owned()],
+            accepted: Arc::default(),
+        };
+        let now = 1_700_000_000_000;
+        let verify = |headers: &HashMap<&'static str, String>, operation, now| {
+            auth.verify_at(
+                |name| headers.get(name).map(String::as_str),
+                operation,
+                b"body",
+                now,
+            )
+        };
+
+        let signed = headers(&app, "sign", now);
+        assert_eq!(
+            Ok(Some(app.public_key().to_owned())),
+            verify(&signed, "sign", now)
+        );
+        assert!(verify(&headers(&gateway, "sign", now), "sign", now).is_ok());
+        // The same call again, and an older call of the same key
+        assert_eq!(Err(AuthError::Replayed), verify(&signed, "sign", now));
+        assert_eq!(
+            Err(AuthError::Replayed),
+            verify(&headers(&app, "sign", now - 1), "sign", now)
+        );
+        assert!(verify(&headers(&app, "sign", now + 1), "sign", now).is_ok());
+        // Signed for another operation
+        assert_eq!(
+            Err(AuthError::Signature),
+            verify(&headers(&app, "sign", now + 2), "add_gateway", now)
+        );
+        assert_eq!(
+            Err(AuthError::Expired),
+            verify(
+                &headers(&app, "sign", now + 2),
+                "sign",
+                now + MAX_CLOCK_SKEW_MS + 3
+            )
+        );
+        let stranger = keypair();
+        assert!(verify(&headers(&stranger, "sign", now), "sign", now)
+            .is_err_and(|err| err.is_forbidden()));
+        assert_eq!(
+            Err(AuthError::Missing(KEY_HEADER)),
+            verify(&HashMap::new(), "sign", now)
+        );
+
+        // Nothing is checked without authorized keys
+        let open = ApiAuth {
+            keys: vec![],
+            accepted: Arc::default(),
+        };
+        assert_eq!(Ok(None), open.verify_at(|_| None, "sign", b"body", now));
+    }
+}
@@ -1,34 +1,38 @@
-use super::{connect_uri, AddGatewayReq, GatewayStakingMode, PubkeyReq, RegionReq};
-use crate::{error::Error, settings::StakingMode, PublicKey, Region, Result, TxnEnvelope};
-use helium_proto::{services::local::Client, BlockchainTxnAddGatewayV1};
-use std::convert::TryFrom;
-use tonic::transport::{Channel, Endpoint};
+use crate::{
+    error::{DecodeError, Error},
+    settings::StakingMode,
+    Keypair, PublicKey, Region, Result,
+};
+use gateway_api_client::{Client, Error as ClientError, GatewayStakingMode};
+use helium_proto::BlockchainTxnAddGatewayV1;
+use std::sync::Arc;
 
+/// The local API client used by the CLI. See the `gateway-api-client` crate
+/// for the client itself.
 pub struct LocalClient {
-    client: Client<Channel>,
+    client: Client,
 }
 
 impl LocalClient {
     pub async fn new(port: u16) -> Result<Self> {
-        let uri = connect_uri(port);
-        let endpoint = Endpoint::from_shared(uri).unwrap();
-        let client = Client::connect(endpoint)
-            .await
-            .map_err(Error::local_client_connect)?;
+        let client = Client::connect(port).await?;
         Ok(Self { client })
     }
 
-    pub async fn pubkey(&mut self) -> Result<(PublicKey, PublicKey)> {
-        let response = self.client.pubkey(PubkeyReq {}).await?.into_inner();
+    /// Signs mutating calls with the given keypair, as required when the
+    /// gateway has `authorized_keys` configured
+    pub fn with_keypair(self, keypair: Arc<Keypair>) -> Self {
+        Self {
+            client: self.client.with_authorizer(keypair),
+        }
+    }
 
-        let public_key = PublicKey::try_from(response.address)?;
-        let onboarding_key = PublicKey::try_from(response.onboarding_address)?;
-        Ok((public_key, onboarding_key))
+    pub async fn pubkey(&mut self) -> Result<(PublicKey, PublicKey)> {
+        Ok(self.client.pubkey().await?)
     }
 
     pub async fn region(&mut self) -> Result<Region> {
-        let response = self.client.region(RegionReq {}).await?;
-        Ok(Region::from_i32(response.into_inner().region)?)
+        Ok(self.client.region().await?)
     }
 
     pub async fn add_gateway(
@@ -37,16 +41,24 @@ impl LocalClient {
         payer: &PublicKey,
         mode: &StakingMode,
     ) -> Result<BlockchainTxnAddGatewayV1> {
-        let response = self
+        let txn = self
             .client
-            .add_gateway(AddGatewayReq {
-                owner: owner.to_vec(),
-                payer: payer.to_vec(),
-                staking_mode: GatewayStakingMode::from(mode).into(),
-            })
+            .add_gateway(owner, payer, GatewayStakingMode::from(mode))
             .await?;
-        let encoded = response.into_inner().add_gateway_txn;
-        let txn = BlockchainTxnAddGatewayV1::from_envelope_vec(&encoded)?;
         Ok(txn)
     }
 }
+
+impl From<ClientError> for Error {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::Connect(err) => Error::local_client_connect(err),
+            ClientError::InvalidUri(uri) => Error::custom(format!("invalid uri {uri}")),
+            ClientError::Rpc(status) => status.into(),
+            ClientError::Crypto(err) => err.into(),
+            ClientError::Region(err) => err.into(),
+            ClientError::Decode(err) => err.into(),
+            ClientError::InvalidEnvelope => DecodeError::invalid_envelope(),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! A diagnostics bundle for support tickets.
+//!
+//! The running gateway collects everything support usually asks for into a
+//! single tar.gz, produced entirely on the device:
+//!
+//! * `info.json` - keys, name, version, region and the startup, RF and key
+//!   health records
+//! * `logs.json` - the buffered log lines
+//! * `errors.json` - the buffered warning and error log lines
+//! * `stats.json` - uplink, downlink, channel, service and packet forwarder
+//!   statistics and the current metric values
+//! * `settings.json` - the effective settings with secrets redacted
+//! * `region.json` - the region parameters
+
+use crate::{
+    clock::unix_now,
+    gateway::rf_health::RfHealth,
+    keypair::health::KeyHealth,
+    logging::{self, buffer::LogFilter},
+    metrics,
+    server::{startup::StartupRecord, Handles},
+    settings::ConfigEntry,
+    RegionParams, Result,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// Settings keys whose last part contains one of these have their value
+/// redacted
+const SECRET_KEYS: &[&str] = &["password", "secret", "token", "credential"];
+const REDACTED: &str = "<redacted>";
+
+/// Builds the bundle from the gateway info given by the API, the effective
+/// settings, the current region parameters and the statistics of the running
+/// tasks
+pub fn bundle(
+    info: Value,
+    settings: Vec<ConfigEntry>,
+    region_params: &RegionParams,
+    data_dir: &Path,
+    handles: &Handles,
+) -> Result<Vec<u8>> {
+    let (logs, _) = logging::buffer::subscribe();
+    let warnings = LogFilter {
+        level: Some("warn".to_string()),
+        module: None,
+    };
+    let errors: Vec<_> = logs.iter().filter(|line| warnings.matches(line)).collect();
+    let info = json!({
+        "gateway": info,
+        "startup": StartupRecord::load(data_dir).ok().flatten(),
+        "rf_health": RfHealth::load(data_dir).ok().flatten(),
+        "key_health": KeyHealth::load(data_dir).ok().flatten(),
+    });
+    let stats = json!({
+        "uplinks": handles.uplink_stats.snapshot(),
+        "downlinks": handles.downlink_stats.snapshot(),
+        "channels": handles.channel_mask.snapshot(),
+        "services": handles.service_stats.snapshot(),
+        "forwarder_conformance": handles.forwarder_conformance.snapshot(),
+        "forwarder_acks": handles.forwarder_acks.snapshot(),
+        "forwarder_stats": handles.forwarder_stats.snapshot(),
+        "forwarder_watchdog": handles.watchdog.snapshot(),
+        "crc_failed": handles.crc_failed.snapshot(),
+        "geofence": handles.geofence.snapshot(),
+        "quarantine": handles.quarantine.snapshot(),
+        "metrics": metrics::values(),
+    });
+    let region = json!({
+        "region": region_params.region.to_string(),
+        "gain": region_params.gain,
+        "params": region_params
+            .params
+            .iter()
+            .map(|param| json!({
+                "channel_frequency": param.channel_frequency,
+                "bandwidth": param.bandwidth,
+                "max_eirp": param.max_eirp,
+            }))
+            .collect::<Vec<_>>(),
+    });
+
+    let mtime = unix_now();
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, value) in [
+        ("info.json", info),
+        ("logs.json", json!(logs)),
+        ("errors.json", json!(errors)),
+        ("stats.json", stats),
+        ("settings.json", json!(redact(settings))),
+        ("region.json", region),
+    ] {
+        let data = serde_json::to_vec_pretty(&value)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, name, data.as_slice())?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// Replaces the values, and built-in defaults, of secret settings
+fn redact(mut settings: Vec<ConfigEntry>) -> Vec<ConfigEntry> {
+    for entry in &mut settings {
+        let name = entry.key.rsplit('.').next().unwrap_or_default();
+        if SECRET_KEYS.iter().any(|secret| name.contains(secret)) {
+            entry.value = json!(REDACTED);
+            entry.default = entry.default.as_ref().map(|_| json!(REDACTED));
+        }
+    }
+    settings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::ConfigSource;
+
+    #[test]
+    fn redacts() {
+        let entry = |key: &str, value: &str| ConfigEntry {
+            key: key.to_string(),
+            value: json!(value),
+            source: ConfigSource::File,
+            file: None,
+            default: None,
+        };
+        let settings = redact(vec![
+            entry("mqtt.password", "hunter2"),
+            entry("mqtt.username", "gateway"),
+            entry("fleet.auth_token", "abc"),
+        ]);
+        assert_eq!(json!(REDACTED), settings[0].value);
+        assert_eq!(json!("gateway"), settings[1].value);
+        assert_eq!(json!(REDACTED), settings[2].value);
+    }
+}
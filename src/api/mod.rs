@@ -1,22 +1,22 @@
+#[cfg(any(feature = "api", feature = "rest"))]
+mod auth;
 mod client;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+#[cfg(feature = "rest")]
+mod rest;
+#[cfg(feature = "api")]
 mod server;
 
-const LISTEN_ADDR: &str = "127.0.0.1";
-
 pub use client::LocalClient;
+pub use gateway_api_client::{connect_uri, listen_addr};
 pub use helium_proto::{
     services::local::{
         AddGatewayReq, AddGatewayRes, PubkeyReq, PubkeyRes, RegionReq, RegionRes, SignReq, SignRes,
     },
     GatewayStakingMode,
 };
+#[cfg(feature = "rest")]
+pub use rest::RestServer;
+#[cfg(feature = "api")]
 pub use server::LocalServer;
-
-pub fn listen_addr(port: u16) -> String {
-    format!("{LISTEN_ADDR}:{port}")
-}
-
-pub fn connect_uri(port: u16) -> String {
-    let listen_addr = listen_addr(port);
-    format!("http://{listen_addr}")
-}
@@ -0,0 +1,667 @@
+use super::auth::ApiAuth;
+#[cfg(feature = "diagnostics")]
+use super::diagnostics;
+#[cfg(feature = "fault-injection")]
+use crate::service::faults;
+use crate::{
+    beaconer,
+    event_bus::{Event, EventBus},
+    gateway::{
+        crc_failed::CrcFailedHandle,
+        join_filter::{JoinFilterConfig, JoinFilterHandle},
+    },
+    logging::{self, buffer::LogFilter},
+    metrics::snapshots::SnapshotRing,
+    queues, region_watcher,
+    server::{handoff, Handles},
+    settings::{self, ConfigEntry, ConfigSource},
+    Error, Keypair, PublicKey, Result, Settings,
+};
+use angry_purple_tiger::AnimalName;
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{info, o, Logger};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::sync::broadcast;
+
+/// The largest accepted request body. The JSON bodies of the API are a few
+/// hundred bytes.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A REST/JSON front for the local API, for web UIs and scripts that can not
+/// use the gRPC API.
+///
+/// * `GET /v1/info` - keys, name, firmware version and region
+/// * `GET /v1/region` - the current region, and the name of a custom region
+/// * `GET /v1/region/history` - what changed in the most recent region
+///   params updates
+/// * `GET /v1/config` - the effective configuration with the source of each
+///   value, with passwords and trusted keys redacted
+/// * `GET /v1/beacon` - the beacon schedule and the outcome of recent beacon
+///   attempts
+/// * `POST /v1/beacon` - transmit an unscheduled beacon
+/// * `POST /v1/beacon/test` - transmit a test beacon at reduced power that is
+///   not reported, to validate the transmit chain
+/// * `GET /v1/poc/receipts` - the most recent submitted PoC reports and
+///   whether the ingest service accepted them
+/// * `GET /v1/downlinks` - a stream of newline delimited JSON downlink results
+/// * `GET /v1/uplinks/stats` - uplink disposition counts and the most recent
+///   forwarding decisions with their reason codes
+/// * `GET /v1/uplinks/forward_filter` - uplinks dropped by the NetID and
+///   DevAddr forward filter per rule
+/// * `GET /v1/uplinks/crc_failed` - CRC-failed frame counts per channel and
+///   the recent CRC-failed frames, when `crc_failed.keep` is set
+/// * `GET /v1/uplinks/crc_failed/stream` - a stream of newline delimited JSON
+///   CRC-failed frames, when `crc_failed.stream` is set
+/// * `GET /v1/channels/stats` - frame counts, CRC error rates and noise
+///   floors per channel and the channels masked for beacons
+/// * `GET /v1/services/stats` - request counts and latency histograms per
+///   upstream service and rpc
+/// * `GET /v1/data_usage` - the live bytes exchanged with upstream services
+///   per month and whether the monthly cap is exceeded
+/// * `GET /v1/forwarder/conformance` - packet forwarder protocol warnings per
+///   field and issue with the last example of each
+/// * `GET /v1/forwarder/acks` - PUSH_ACK percentages and streaks of stat
+///   intervals without acks per packet forwarder
+/// * `GET /v1/forwarder/stats` - received, forwarded and transmitted packet
+///   counts per packet forwarder, in total and over recent stat intervals
+/// * `GET /v1/forwarder/watchdog` - whether the packet forwarder is lost and
+///   when it was last heard from
+/// * `GET /v1/geofence` - the gateway position, its source and whether it is
+///   outside the territory of its region
+/// * `GET /v1/queues` - the internal queue sizes
+/// * `POST /v1/queues` - change the router conduit capacity or policy with a
+///   JSON body like `{"conduit": 200}`, from the next router connection
+/// * `GET /v1/joins/filter` - whether the join filter is enabled and its
+///   JoinEUI and DevEUI lists
+/// * `POST /v1/joins/filter` - replace the join filter with a JSON body like
+///   `{"enabled": true, "deny_join_euis": ["70B3D57ED0000000"]}`, fields left
+///   out are cleared, until the next restart
+/// * `GET /v1/metrics/snapshots` - the stored periodic metrics snapshots,
+///   with the metric names listed once
+/// * `GET /v1/quarantine` - recent router and config messages with unknown
+///   or defaulted critical fields and what was done about them
+/// * `GET /v1/diagnostics` - a tar.gz with logs, info, stats, the settings
+///   with secrets redacted and the region parameters for support tickets,
+///   with the `diagnostics` feature
+/// * `GET /v1/faults` - the injected service faults, with the
+///   `fault-injection` feature
+/// * `POST /v1/faults` - set the faults of a service with a JSON body like
+///   `{"service": "packet_router", "drop_percent": 20, "latency_ms": 500,
+///   "corrupt_percent": 5}`, with the `fault-injection` feature
+/// * `GET /v1/logs` - recent log lines as newline delimited JSON, filtered by
+///   the `level` and `module` query parameters. With `follow=true` new lines
+///   are streamed as they are logged.
+///
+/// With `authorized_keys` configured, `POST` requests must be signed by the
+/// gateway key or an authorized key, see the `gateway-api-client` crate.
+/// `POST` bodies over `MAX_BODY_SIZE` are rejected with 413.
+pub struct RestServer {
+    listen: Option<String>,
+    state: Arc<RestState>,
+}
+
+struct RestState {
+    keypair: Arc<Keypair>,
+    onboarding_key: PublicKey,
+    region_watch: region_watcher::MessageReceiver,
+    beacons: beaconer::MessageSender,
+    join_filter: JoinFilterHandle,
+    config: Vec<ConfigEntry>,
+    auth: ApiAuth,
+    snapshots: SnapshotRing,
+    #[cfg(feature = "diagnostics")]
+    data_dir: std::path::PathBuf,
+    events: EventBus,
+    handles: Handles,
+}
+
+impl RestServer {
+    pub fn new(
+        region_watch: region_watcher::MessageReceiver,
+        beacons: beaconer::MessageSender,
+        join_filter: JoinFilterHandle,
+        events: EventBus,
+        handles: &Handles,
+        settings: &Settings,
+    ) -> Self {
+        // Loaded at startup so the configuration reflects what is running
+        // rather than later edits of the settings file
+        let config = Settings::provenance(&settings.config_path)
+            .unwrap_or_default()
+            .into_iter()
+            .map(ConfigEntry::redacted)
+            .collect();
+        Self {
+            listen: settings.rest.listen.clone(),
+            state: Arc::new(RestState {
+                keypair: settings.keypair.clone(),
+                onboarding_key: settings.onboarding_key(),
+                region_watch,
+                beacons,
+                join_filter,
+                config,
+                auth: ApiAuth::new(settings),
+                snapshots: SnapshotRing::new(settings),
+                #[cfg(feature = "diagnostics")]
+                data_dir: settings.data_dir.clone(),
+                events,
+                handles: handles.clone(),
+            }),
+        }
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let listen = match self.listen {
+            Some(listen) => listen,
+            None => return Ok(()),
+        };
+        let addr: SocketAddr = listen.parse()?;
+        let logger = logger.new(o!("module" => "rest", "listen" => addr));
+        info!(logger, "starting");
+
+        let state = self.state;
+        let stream_shutdown = shutdown.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = state.clone();
+            let shutdown = stream_shutdown.clone();
+            let service =
+                service_fn(move |req| handle_request(state.clone(), shutdown.clone(), req));
+            async move { Ok::<_, Infallible>(service) }
+        });
+        Server::from_tcp(handoff::tcp_listener("rest", addr)?)
+            .map_err(Error::from)?
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown.clone())
+            .await
+            .map_err(Error::from)
+    }
+}
+
+async fn handle_request(
+    state: Arc<RestState>,
+    shutdown: triggered::Listener,
+    mut req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if let Err(response) = authorize(&state, &mut req).await {
+        return Ok(response);
+    }
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/v1/info") => json_response(StatusCode::OK, info(&state)),
+        (&Method::GET, "/v1/region") => {
+            let region_params = state.region_watch.borrow();
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "region": region_params.region.to_string(),
+                    "name": region_params.name,
+                }),
+            )
+        }
+        (&Method::GET, "/v1/queues") => {
+            json_response(StatusCode::OK, json!(state.handles.queues.snapshot()))
+        }
+        (&Method::POST, "/v1/queues") => {
+            let update = match read_body(&mut req).await {
+                Ok(body) => {
+                    serde_json::from_slice::<queues::QueueUpdate>(&body).map_err(Error::from)
+                }
+                Err(response) => return Ok(response),
+            };
+            match update.and_then(|update| state.handles.queues.update(&update)) {
+                Ok(sizes) => json_response(StatusCode::OK, json!(sizes)),
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        (&Method::GET, "/v1/joins/filter") => {
+            json_response(StatusCode::OK, json!(state.join_filter.config()))
+        }
+        (&Method::POST, "/v1/joins/filter") => {
+            let config = match read_body(&mut req).await {
+                Ok(body) => serde_json::from_slice::<JoinFilterConfig>(&body).map_err(Error::from),
+                Err(response) => return Ok(response),
+            };
+            match config.and_then(|config| state.join_filter.update(config)) {
+                Ok(config) => json_response(StatusCode::OK, json!(config)),
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        #[cfg(feature = "fault-injection")]
+        (&Method::GET, "/v1/faults") => json_response(StatusCode::OK, json!(faults::snapshot())),
+        #[cfg(feature = "fault-injection")]
+        (&Method::POST, "/v1/faults") => {
+            let update = match read_body(&mut req).await {
+                Ok(body) => {
+                    serde_json::from_slice::<faults::FaultUpdate>(&body).map_err(Error::from)
+                }
+                Err(response) => return Ok(response),
+            };
+            match update.and_then(faults::set) {
+                Ok(faults) => json_response(StatusCode::OK, json!(faults)),
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        (&Method::GET, "/v1/region/history") => json_response(
+            StatusCode::OK,
+            json!(state.handles.region_history.snapshot()),
+        ),
+        (&Method::GET, "/v1/config") => {
+            json_response(StatusCode::OK, json!(running_config(&state)))
+        }
+        (&Method::GET, "/v1/beacon") => match state.beacons.beacon_status().await {
+            Ok(status) => json_response(StatusCode::OK, json!(status)),
+            Err(err) => error_response(StatusCode::SERVICE_UNAVAILABLE, &err),
+        },
+        (&Method::POST, "/v1/beacon") => match state.beacons.transmit_beacon().await {
+            Ok(beacon_id) => json_response(StatusCode::OK, json!({ "beacon_id": beacon_id })),
+            Err(err) => error_response(StatusCode::SERVICE_UNAVAILABLE, &err),
+        },
+        (&Method::POST, "/v1/beacon/test") => match state.beacons.transmit_test_beacon().await {
+            Ok(beacon) => json_response(StatusCode::OK, json!(beacon)),
+            Err(err) => error_response(StatusCode::SERVICE_UNAVAILABLE, &err),
+        },
+        (&Method::GET, "/v1/poc/receipts") => {
+            json_response(StatusCode::OK, json!(state.handles.receipts.snapshot()))
+        }
+        (&Method::GET, "/v1/downlinks") => downlink_stream(&state.events, shutdown),
+        (&Method::GET, "/v1/downlinks/stats") => json_response(
+            StatusCode::OK,
+            json!(state.handles.downlink_stats.snapshot()),
+        ),
+        (&Method::GET, "/v1/uplinks/stats") => {
+            json_response(StatusCode::OK, json!(state.handles.uplink_stats.snapshot()))
+        }
+        (&Method::GET, "/v1/uplinks/forward_filter") => json_response(
+            StatusCode::OK,
+            json!(state.handles.forward_filter.snapshot()),
+        ),
+        (&Method::GET, "/v1/uplinks/crc_failed") => {
+            json_response(StatusCode::OK, json!(state.handles.crc_failed.snapshot()))
+        }
+        (&Method::GET, "/v1/uplinks/crc_failed/stream") => {
+            crc_failed_stream(&state.handles.crc_failed, shutdown)
+        }
+        (&Method::GET, "/v1/channels/stats") => {
+            json_response(StatusCode::OK, json!(state.handles.channel_mask.snapshot()))
+        }
+        (&Method::GET, "/v1/services/stats") => json_response(
+            StatusCode::OK,
+            json!(state.handles.service_stats.snapshot()),
+        ),
+        (&Method::GET, "/v1/data_usage") => {
+            json_response(StatusCode::OK, json!(state.handles.data_usage.snapshot()))
+        }
+        (&Method::GET, "/v1/forwarder/conformance") => json_response(
+            StatusCode::OK,
+            json!(state.handles.forwarder_conformance.snapshot()),
+        ),
+        (&Method::GET, "/v1/forwarder/acks") => json_response(
+            StatusCode::OK,
+            json!(state.handles.forwarder_acks.snapshot()),
+        ),
+        (&Method::GET, "/v1/forwarder/watchdog") => {
+            json_response(StatusCode::OK, json!(state.handles.watchdog.snapshot()))
+        }
+        (&Method::GET, "/v1/forwarder/stats") => json_response(
+            StatusCode::OK,
+            json!(state.handles.forwarder_stats.snapshot()),
+        ),
+        (&Method::GET, "/v1/geofence") => {
+            json_response(StatusCode::OK, json!(state.handles.geofence.snapshot()))
+        }
+        (&Method::GET, "/v1/metrics/snapshots") => match state.snapshots.bundle() {
+            Ok(bundle) => json_response(StatusCode::OK, json!(bundle)),
+            Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err),
+        },
+        (&Method::GET, "/v1/quarantine") => {
+            json_response(StatusCode::OK, json!(state.handles.quarantine.snapshot()))
+        }
+        #[cfg(feature = "diagnostics")]
+        (&Method::GET, "/v1/diagnostics") => {
+            let region_params = state.region_watch.borrow().clone();
+            match diagnostics::bundle(
+                info(&state),
+                running_config(&state),
+                &region_params,
+                &state.data_dir,
+                &state.handles,
+            ) {
+                Ok(bundle) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/gzip")
+                    .header(
+                        "content-disposition",
+                        "attachment; filename=\"diagnostics.tar.gz\"",
+                    )
+                    .body(Body::from(bundle))
+                    .expect("diagnostics response"),
+                Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err),
+            }
+        }
+        (&Method::GET, "/v1/logs") => {
+            match serde_urlencoded::from_str::<LogsQuery>(req.uri().query().unwrap_or_default()) {
+                Ok(query) => log_stream(query, shutdown),
+                Err(err) => {
+                    json_response(StatusCode::BAD_REQUEST, json!({ "error": err.to_string() }))
+                }
+            }
+        }
+        (_, path) => match allowed_methods(path) {
+            Some(methods) => {
+                let mut response = json_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    json!({ "error": "method not allowed" }),
+                );
+                response.headers_mut().insert(
+                    hyper::header::ALLOW,
+                    methods.join(", ").parse().expect("allow header"),
+                );
+                response
+            }
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        },
+    };
+    Ok(response)
+}
+
+/// The paths of the API with the methods they support, for the response to
+/// a request with another method
+const ROUTES: &[(&str, &[&str])] = &[
+    ("/v1/info", &["GET"]),
+    ("/v1/region", &["GET"]),
+    ("/v1/region/history", &["GET"]),
+    ("/v1/queues", &["GET", "POST"]),
+    ("/v1/joins/filter", &["GET", "POST"]),
+    ("/v1/config", &["GET"]),
+    ("/v1/beacon", &["GET", "POST"]),
+    ("/v1/beacon/test", &["POST"]),
+    ("/v1/poc/receipts", &["GET"]),
+    ("/v1/downlinks", &["GET"]),
+    ("/v1/downlinks/stats", &["GET"]),
+    ("/v1/uplinks/stats", &["GET"]),
+    ("/v1/uplinks/forward_filter", &["GET"]),
+    ("/v1/uplinks/crc_failed", &["GET"]),
+    ("/v1/uplinks/crc_failed/stream", &["GET"]),
+    ("/v1/channels/stats", &["GET"]),
+    ("/v1/services/stats", &["GET"]),
+    ("/v1/data_usage", &["GET"]),
+    ("/v1/forwarder/conformance", &["GET"]),
+    ("/v1/forwarder/acks", &["GET"]),
+    ("/v1/forwarder/watchdog", &["GET"]),
+    ("/v1/forwarder/stats", &["GET"]),
+    ("/v1/geofence", &["GET"]),
+    ("/v1/metrics/snapshots", &["GET"]),
+    ("/v1/quarantine", &["GET"]),
+    ("/v1/logs", &["GET"]),
+];
+
+#[cfg(feature = "fault-injection")]
+const FAULT_ROUTES: &[(&str, &[&str])] = &[("/v1/faults", &["GET", "POST"])];
+#[cfg(not(feature = "fault-injection"))]
+const FAULT_ROUTES: &[(&str, &[&str])] = &[];
+
+#[cfg(feature = "diagnostics")]
+const DIAGNOSTICS_ROUTES: &[(&str, &[&str])] = &[("/v1/diagnostics", &["GET"])];
+#[cfg(not(feature = "diagnostics"))]
+const DIAGNOSTICS_ROUTES: &[(&str, &[&str])] = &[];
+
+/// The methods supported by the given path, None for an unknown path
+fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    ROUTES
+        .iter()
+        .chain(FAULT_ROUTES)
+        .chain(DIAGNOSTICS_ROUTES)
+        .find(|(route, _)| *route == path)
+        .map(|(_, methods)| *methods)
+}
+
+/// Checks that mutating (POST) requests are signed by an authorized key when
+/// `authorized_keys` are configured. The request body is read to verify the
+/// signature and put back for the handler.
+async fn authorize(
+    state: &RestState,
+    req: &mut Request<Body>,
+) -> std::result::Result<(), Response<Body>> {
+    if req.method() != Method::POST {
+        return Ok(());
+    }
+    let body = read_body(req).await?;
+    let operation = format!("{} {}", req.method(), req.uri().path());
+    let headers = req.headers();
+    let verified = state.auth.verify(
+        |name| headers.get(name).and_then(|value| value.to_str().ok()),
+        &operation,
+        &body,
+    );
+    *req.body_mut() = Body::from(body);
+    verified.map(|_| ()).map_err(|err| {
+        let status = if err.is_forbidden() {
+            StatusCode::FORBIDDEN
+        } else {
+            StatusCode::UNAUTHORIZED
+        };
+        json_response(status, json!({ "error": err.to_string() }))
+    })
+}
+
+/// Reads the request body, rejecting bodies over `MAX_BODY_SIZE` without
+/// reading the rest
+async fn read_body(req: &mut Request<Body>) -> std::result::Result<Bytes, Response<Body>> {
+    let too_large = || {
+        json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            json!({ "error": format!("request body over {MAX_BODY_SIZE} bytes") }),
+        )
+    };
+    let body = req.body_mut();
+    if body.size_hint().lower() > MAX_BODY_SIZE as u64 {
+        return Err(too_large());
+    }
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            json_response(StatusCode::BAD_REQUEST, json!({ "error": err.to_string() }))
+        })?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// The keys, name, firmware version and region of the gateway
+fn info(state: &RestState) -> serde_json::Value {
+    let public_key = state.keypair.public_key().to_string();
+    let name = public_key
+        .parse::<AnimalName>()
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    json!({
+        "key": public_key,
+        "onboarding": state.onboarding_key.to_string(),
+        "name": name,
+        "fw": settings::version().to_string(),
+        "region": state.region_watch.borrow().region.to_string(),
+        "milestones": state.handles.milestones.snapshot(),
+    })
+}
+
+/// The startup configuration with values that were updated at runtime, like
+/// the region received from the config service.
+fn running_config(state: &RestState) -> Vec<ConfigEntry> {
+    let mut config = state.config.clone();
+    let region = json!(state.region_watch.borrow().region.to_string());
+    hot_reload(&mut config, "region", region);
+    if let serde_json::Value::Object(join_filter) = json!(state.join_filter.config()) {
+        for (field, value) in join_filter {
+            hot_reload(&mut config, &format!("join_filter.{field}"), value);
+        }
+    }
+    config
+}
+
+/// Marks a configuration value as updated at runtime when it differs from
+/// the startup value
+fn hot_reload(config: &mut Vec<ConfigEntry>, key: &str, value: serde_json::Value) {
+    match config.iter_mut().find(|entry| entry.key == key) {
+        Some(entry) if entry.value == value => (),
+        Some(entry) => {
+            entry.value = value;
+            entry.source = ConfigSource::HotReload;
+        }
+        None => config.push(ConfigEntry {
+            key: key.to_string(),
+            value,
+            source: ConfigSource::HotReload,
+            file: None,
+            default: None,
+        }),
+    }
+}
+
+/// Streams downlink results from the event bus until the client disconnects or
+/// the server shuts down.
+fn downlink_stream(events: &EventBus, shutdown: triggered::Listener) -> Response<Body> {
+    let mut events = events.subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.clone() => return,
+                event = events.recv() => event,
+            };
+            let report = match event {
+                Ok(Event::DownlinkResult(report)) => report,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let mut line = json!(report).to_string();
+            line.push('\n');
+            if sender.send_data(line.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/x-ndjson")
+        .body(body)
+        .expect("stream response")
+}
+
+/// Streams the CRC-failed frames received until the client disconnects or the
+/// server shuts down
+fn crc_failed_stream(
+    crc_failed: &CrcFailedHandle,
+    shutdown: triggered::Listener,
+) -> Response<Body> {
+    let Some(mut frames) = crc_failed.subscribe() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "crc_failed.stream is not enabled" }),
+        );
+    };
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                _ = shutdown.clone() => return,
+                frame = frames.recv() => match frame {
+                    Ok(frame) => frame,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            let mut line = json!(frame).to_string();
+            line.push('\n');
+            if sender.send_data(line.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/x-ndjson")
+        .body(body)
+        .expect("stream response")
+}
+
+#[derive(Debug, Deserialize)]
+struct LogsQuery {
+    level: Option<String>,
+    module: Option<String>,
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Streams the buffered log lines matching the query and, when following, the
+/// lines logged after them until the client disconnects or the server shuts
+/// down.
+fn log_stream(query: LogsQuery, shutdown: triggered::Listener) -> Response<Body> {
+    let filter = LogFilter {
+        level: query.level,
+        module: query.module,
+    };
+    let (recent, mut lines) = logging::buffer::subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for line in recent.iter().filter(|line| filter.matches(line)) {
+            let mut line = json!(line).to_string();
+            line.push('\n');
+            if sender.send_data(line.into()).await.is_err() {
+                return;
+            }
+        }
+        if !query.follow {
+            return;
+        }
+        loop {
+            let line = tokio::select! {
+                _ = shutdown.clone() => return,
+                line = lines.recv() => match line {
+                    Ok(line) if filter.matches(&line) => line,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            };
+            let mut line = json!(line).to_string();
+            line.push('\n');
+            if sender.send_data(line.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/x-ndjson")
+        .body(body)
+        .expect("stream response")
+}
+
+/// An error response with the error message and its stable code
+fn error_response(status: StatusCode, err: &Error) -> Response<Body> {
+    let code = err.code();
+    json_response(
+        status,
+        json!({
+            "error": err.to_string(),
+            "code": code.as_str(),
+            "code_number": code.number(),
+        }),
+    )
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("json response")
+}
@@ -1,10 +1,10 @@
 use super::{
-    listen_addr, AddGatewayReq, AddGatewayRes, PubkeyReq, PubkeyRes, RegionReq, RegionRes, SignReq,
-    SignRes,
+    auth::ApiAuth, listen_addr, AddGatewayReq, AddGatewayRes, PubkeyReq, PubkeyRes, RegionReq,
+    RegionRes, SignReq, SignRes,
 };
 use crate::{
-    region_watcher, settings::StakingMode, Error, Keypair, PublicKey, Result, Settings,
-    TxnEnvelope, TxnFee, TxnFeeConfig,
+    region_watcher, server::handoff, settings::StakingMode, Error, Keypair, PublicKey, Result,
+    Settings, TxnEnvelope, TxnFee, TxnFeeConfig,
 };
 use futures::TryFutureExt;
 use helium_crypto::Sign;
@@ -12,6 +12,7 @@ use helium_proto::services::local::{Api, Server};
 use helium_proto::{BlockchainTxnAddGatewayV1, Message};
 use slog::{info, o, Logger};
 use std::sync::Arc;
+use tokio::net::TcpListener;
 use tonic::{self, transport::Server as TransportServer, Request, Response, Status};
 
 pub type ApiResult<T> = std::result::Result<Response<T>, Status>;
@@ -21,6 +22,7 @@ pub struct LocalServer {
     keypair: Arc<Keypair>,
     onboarding_key: PublicKey,
     listen_port: u16,
+    auth: ApiAuth,
 }
 
 impl LocalServer {
@@ -29,6 +31,7 @@ impl LocalServer {
             keypair: settings.keypair.clone(),
             onboarding_key: settings.onboarding_key(),
             listen_port: settings.api,
+            auth: ApiAuth::new(settings),
             region_watch,
         })
     }
@@ -37,12 +40,43 @@ impl LocalServer {
         let addr = listen_addr(self.listen_port).parse().unwrap();
         let logger = logger.new(o!("module" => "api", "listen" => addr));
         info!(logger, "starting");
+        // The listener is bound here rather than by the transport so it can be
+        // handed to a new process on upgrades
+        let listener = TcpListener::from_std(handoff::tcp_listener("api", addr)?)?;
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
         TransportServer::builder()
             .add_service(Server::new(self))
-            .serve_with_shutdown(addr, shutdown.clone())
+            .serve_with_incoming_shutdown(Box::pin(incoming), shutdown.clone())
             .map_err(Error::from)
             .await
     }
+
+    /// Checks that a mutating call is signed by an authorized key when
+    /// `authorized_keys` are configured
+    fn authorize<T: Message>(
+        &self,
+        operation: &str,
+        request: &Request<T>,
+    ) -> std::result::Result<(), Status> {
+        let metadata = request.metadata();
+        self.auth
+            .verify(
+                |name| metadata.get(name).and_then(|value| value.to_str().ok()),
+                operation,
+                &request.get_ref().encode_to_vec(),
+            )
+            .map(|_| ())
+            .map_err(|err| {
+                if err.is_forbidden() {
+                    Status::permission_denied(err.to_string())
+                } else {
+                    Status::unauthenticated(err.to_string())
+                }
+            })
+    }
 }
 
 #[tonic::async_trait]
@@ -63,6 +97,7 @@ impl Api for LocalServer {
     }
 
     async fn sign(&self, request: Request<SignReq>) -> ApiResult<SignRes> {
+        self.authorize("sign", &request)?;
         let data = request.into_inner().data;
         let signature = self
             .keypair
@@ -73,6 +108,7 @@ impl Api for LocalServer {
     }
 
     async fn add_gateway(&self, request: Request<AddGatewayReq>) -> ApiResult<AddGatewayRes> {
+        self.authorize("add_gateway", &request)?;
         let request = request.into_inner();
         let _ = PublicKey::from_bytes(&request.owner)
             .map_err(|_err| Status::invalid_argument("Invalid owner address"))?;
@@ -0,0 +1,230 @@
+//! Adaptive beacon channel mask.
+//!
+//! Received frames are counted per channel with moving averages of their CRC
+//! error rate and noise floor. When enabled, channels of the region channel
+//! plan with a high error rate or a noise floor well above the other channels
+//! are masked for a while and beacons avoid them.
+//!
+//! The beacon frequency is derived from the beacon payload, which the oracle
+//! recomputes from the reported entropy. A beacon on a masked channel is
+//! therefore not moved to another channel but skipped until the next beacon
+//! interval. Making it again with other local entropy would let a gateway
+//! grind its beacons.
+
+use crate::{clock::unix_now, metrics, settings::ChannelMaskSettings};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// The weight of a new frame in the moving averages
+const EWMA_WEIGHT: f64 = 0.05;
+
+/// The number of frames a channel needs before it can be masked
+const MIN_FRAMES: u64 = 20;
+
+/// Gauge per channel frequency which is 1 while the channel is masked
+pub const MASKED_METRIC: &str = "beacon_channel_masked";
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChannelStats {
+    pub frames: u64,
+    pub crc_errors: u64,
+    /// Moving average of the share of frames with a CRC error
+    pub error_rate: f64,
+    /// Moving average of the noise floor (RSSI - SNR) in dBm
+    pub noise_floor: Option<f64>,
+    /// Unix time (seconds) the channel is masked until
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub masked_until: Option<u64>,
+}
+
+impl ChannelStats {
+    fn is_masked(&self, now: u64) -> bool {
+        self.masked_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Channel statistics by channel frequency in Hz
+#[derive(Debug, Default)]
+pub struct ChannelMask {
+    channels: BTreeMap<u64, ChannelStats>,
+}
+
+fn ewma(average: f64, value: f64) -> f64 {
+    average + EWMA_WEIGHT * (value - average)
+}
+
+impl ChannelMask {
+    pub fn record(&mut self, frequency: u64, crc_ok: bool, noise_floor: f64) {
+        let stats = self.channels.entry(frequency).or_default();
+        stats.frames += 1;
+        if !crc_ok {
+            stats.crc_errors += 1;
+        }
+        stats.error_rate = ewma(stats.error_rate, if crc_ok { 0.0 } else { 1.0 });
+        stats.noise_floor = Some(
+            stats
+                .noise_floor
+                .map_or(noise_floor, |average| ewma(average, noise_floor)),
+        );
+    }
+
+    /// Expires masks and masks the worst channels of the given channel plan
+    /// at unix time `now`. At most `max_masked` channels and never more than
+    /// half of the plan are masked. Returns the masked channels of the plan.
+    pub fn update(&mut self, plan: &[u64], settings: &ChannelMaskSettings, now: u64) -> Vec<u64> {
+        for stats in self.channels.values_mut() {
+            if !stats.is_masked(now) {
+                stats.masked_until = None;
+            }
+        }
+
+        let mut noise_floors: Vec<f64> = plan
+            .iter()
+            .filter_map(|frequency| self.channels.get(frequency))
+            .filter(|stats| stats.frames >= MIN_FRAMES)
+            .filter_map(|stats| stats.noise_floor)
+            .collect();
+        noise_floors.sort_by(f64::total_cmp);
+        let median_noise_floor = noise_floors.get(noise_floors.len() / 2).copied();
+
+        let mut candidates: Vec<(u64, f64)> = plan
+            .iter()
+            .filter_map(|frequency| {
+                let stats = self.channels.get(frequency)?;
+                if stats.frames < MIN_FRAMES || stats.is_masked(now) {
+                    return None;
+                }
+                let erroring = stats.error_rate * 100.0 >= settings.error_percent as f64;
+                let noisy = match (stats.noise_floor, median_noise_floor) {
+                    (Some(noise_floor), Some(median)) => {
+                        noise_floor - median >= settings.noise_margin as f64
+                    }
+                    _ => false,
+                };
+                (erroring || noisy).then_some((*frequency, stats.error_rate))
+            })
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let limit = settings.max_masked.min(plan.len() / 2);
+        let masked = self.masked(plan, now).len();
+        for (frequency, _) in candidates.into_iter().take(limit.saturating_sub(masked)) {
+            if let Some(stats) = self.channels.get_mut(&frequency) {
+                stats.masked_until = Some(now + settings.hold);
+            }
+        }
+        self.masked(plan, now)
+    }
+
+    fn masked(&self, plan: &[u64], now: u64) -> Vec<u64> {
+        plan.iter()
+            .filter(|frequency| {
+                self.channels
+                    .get(frequency)
+                    .is_some_and(|stats| stats.is_masked(now))
+            })
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelMaskSnapshot {
+    /// Statistics by channel frequency in Hz
+    pub channels: BTreeMap<u64, ChannelStats>,
+    /// The currently masked channel frequencies
+    pub masked: Vec<u64>,
+}
+
+/// The channel statistics shared by the gateway, which records received
+/// frames, and the beaconer, which masks channels. Clones share the same mask.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelMaskHandle(Arc<Mutex<ChannelMask>>);
+
+impl ChannelMaskHandle {
+    fn with_mask<T>(&self, f: impl FnOnce(&mut ChannelMask) -> T) -> T {
+        f(&mut self.0.lock().expect("channel mask lock"))
+    }
+
+    /// Records a received frame on the given frequency (MHz)
+    pub fn record(&self, frequency: f64, crc_ok: bool, rssi: f64, snr: f64) {
+        let frequency = (frequency * 1_000_000.0).round() as u64;
+        self.with_mask(|mask| mask.record(frequency, crc_ok, rssi - snr))
+    }
+
+    /// Updates the mask for a channel plan (Hz) and returns the masked
+    /// channels
+    pub fn update(&self, plan: &[u64], settings: &ChannelMaskSettings) -> Vec<u64> {
+        let masked = self.with_mask(|mask| mask.update(plan, settings, unix_now()));
+        for frequency in plan {
+            metrics::set(
+                &metrics::labeled(MASKED_METRIC, &[("frequency", &frequency.to_string())]),
+                if masked.contains(frequency) { 1.0 } else { 0.0 },
+            );
+        }
+        masked
+    }
+
+    pub fn snapshot(&self) -> ChannelMaskSnapshot {
+        let now = unix_now();
+        self.with_mask(|mask| {
+            let masked = mask
+                .channels
+                .iter()
+                .filter(|(_, stats)| stats.is_masked(now))
+                .map(|(frequency, _)| *frequency)
+                .collect();
+            ChannelMaskSnapshot {
+                channels: mask.channels.clone(),
+                masked,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mask() {
+        let settings = ChannelMaskSettings {
+            enabled: true,
+            error_percent: 50,
+            noise_margin: 10,
+            max_masked: 2,
+            hold: 60,
+        };
+        let plan = [867_100_000, 867_300_000, 867_500_000, 867_700_000];
+        let mut mask = ChannelMask::default();
+        for _ in 0..100 {
+            mask.record(plan[0], false, -100.0);
+            mask.record(plan[1], true, -100.0);
+            mask.record(plan[2], true, -80.0);
+        }
+        // Too few frames on the last channel to judge it
+        for _ in 0..10 {
+            mask.record(plan[3], false, -100.0);
+        }
+        assert_eq!(vec![plan[0], plan[2]], mask.update(&plan, &settings, 1000));
+        // Masks are kept until they expire
+        assert_eq!(vec![plan[0], plan[2]], mask.update(&plan, &settings, 1059));
+        for _ in 0..100 {
+            mask.record(plan[0], true, -100.0);
+            mask.record(plan[2], true, -100.0);
+        }
+        assert!(mask.update(&plan, &settings, 1060).is_empty());
+
+        // Never more than half of the plan
+        let mut mask = ChannelMask::default();
+        for _ in 0..100 {
+            mask.record(plan[0], false, -100.0);
+            mask.record(plan[1], false, -100.0);
+            mask.record(plan[2], true, -100.0);
+        }
+        assert_eq!(vec![plan[0]], mask.update(&plan[..3], &settings, 0));
+    }
+}
@@ -0,0 +1,417 @@
+//! Witness deduplication across co-located gateways.
+//!
+//! Gateways in a cluster announce the beacons they are about to witness to
+//! their configured peers over UDP. After a short hold-off each gateway decides
+//! based on the configured policy whether its own witness report is redundant
+//! with one of a peer and should be suppressed. Witness processing is not held
+//! up by the hold-off; the report waits for its deadline while later beacons
+//! are processed.
+//!
+//! Announcements carry the time the beacon was received and are signed with
+//! the gateway keypair. Announcements that are not signed by one of the
+//! configured peer keys are ignored, so a host on the network can not
+//! suppress our witnesses.
+
+use crate::{Base64, Keypair, PublicKey, Result, Settings};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_crypto::{Sign, Verify};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::mpsc, time::Instant};
+use xxhash_rust::xxh64::xxh64;
+
+/// How long peer announcements are remembered
+const ANNOUNCEMENT_TTL: Duration = Duration::from_secs(60);
+const ANNOUNCE_QUEUE_SIZE: usize = 10;
+const MAX_DATAGRAM_SIZE: usize = 1024;
+/// Receive times closer than this are considered the same with the "first"
+/// policy, co-located gateways hear a beacon at the same moment
+const FIRST_TOLERANCE_NANOS: u64 = 100_000_000;
+
+/// How to decide which gateway in a cluster reports a beacon
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterPolicy {
+    /// The gateway that received the beacon first reports it. Gateways that
+    /// received it at the same time leave it to the lowest gateway key.
+    #[default]
+    First,
+    /// The gateway that received the beacon with the strongest signal reports
+    /// it
+    Strongest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Announcement {
+    /// Hash of the beacon payload
+    beacon: u64,
+    /// Received signal strength in tenths of dBm
+    signal: i32,
+    /// Time the beacon was received in nanoseconds since the unix epoch
+    observed: u64,
+    /// The announcing gateway
+    gateway: String,
+    /// Base64 signature of the announcing gateway over the other fields
+    #[serde(default)]
+    signature: String,
+}
+
+impl Announcement {
+    fn message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(20 + self.gateway.len());
+        message.extend(self.beacon.to_be_bytes());
+        message.extend(self.signal.to_be_bytes());
+        message.extend(self.observed.to_be_bytes());
+        message.extend(self.gateway.as_bytes());
+        message
+    }
+
+    async fn sign(mut self, keypair: Arc<Keypair>) -> Result<Self> {
+        let message = self.message();
+        let signature = tokio::task::spawn_blocking(move || keypair.sign(&message)).await??;
+        self.signature = signature.to_b64();
+        Ok(self)
+    }
+
+    /// Whether the announcement is signed by one of the given peers
+    fn verify(&self, peers: &[PublicKey]) -> bool {
+        let Some(peer) = peers.iter().find(|peer| peer.to_string() == self.gateway) else {
+            return false;
+        };
+        STANDARD
+            .decode(&self.signature)
+            .is_ok_and(|signature| peer.verify(&self.message(), &signature).is_ok())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PeerWitness {
+    gateway: String,
+    signal: i32,
+    observed: u64,
+    received: Instant,
+}
+
+type PeerWitnesses = Arc<Mutex<HashMap<u64, Vec<PeerWitness>>>>;
+
+/// Receives peer announcements and sends our own to all peers
+pub struct Cluster {
+    listen: String,
+    peers: Vec<String>,
+    peer_keys: Vec<String>,
+    keypair: Arc<Keypair>,
+    gateway: String,
+    enabled: Arc<AtomicBool>,
+    witnesses: PeerWitnesses,
+    announcements: mpsc::Receiver<Announcement>,
+}
+
+/// Used by witness processing to announce beacons and check for redundant
+/// witnesses.
+#[derive(Debug, Clone)]
+pub struct ClusterHandle {
+    policy: ClusterPolicy,
+    holdoff: Duration,
+    gateway: String,
+    enabled: Arc<AtomicBool>,
+    witnesses: PeerWitnesses,
+    announcements: mpsc::Sender<Announcement>,
+}
+
+impl Cluster {
+    /// Returns the cluster and a handle to it when a cluster listen address is
+    /// configured.
+    pub fn new(settings: &Settings) -> Option<(Self, ClusterHandle)> {
+        let listen = settings.cluster.listen.clone()?;
+        let gateway = settings.keypair.public_key().to_string();
+        let enabled = Arc::new(AtomicBool::new(false));
+        let witnesses = PeerWitnesses::default();
+        let (tx, rx) = mpsc::channel(ANNOUNCE_QUEUE_SIZE);
+        let cluster = Self {
+            listen,
+            peers: settings.cluster.peers.clone(),
+            peer_keys: settings.cluster.peer_keys.clone(),
+            keypair: settings.keypair.clone(),
+            gateway: gateway.clone(),
+            enabled: enabled.clone(),
+            witnesses: witnesses.clone(),
+            announcements: rx,
+        };
+        let handle = ClusterHandle {
+            policy: settings.cluster.policy,
+            holdoff: Duration::from_millis(settings.cluster.holdoff),
+            gateway,
+            enabled,
+            witnesses,
+            announcements: tx,
+        };
+        Some((cluster, handle))
+    }
+
+    async fn bind(&self) -> Result<(UdpSocket, Vec<SocketAddr>, Vec<PublicKey>)> {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| peer.parse().map_err(crate::Error::from))
+            .collect::<Result<_>>()?;
+        let peer_keys = self
+            .peer_keys
+            .iter()
+            .map(|key| key.parse().map_err(crate::Error::from))
+            .collect::<Result<_>>()?;
+        let socket = UdpSocket::bind(&self.listen).await?;
+        Ok((socket, peers, peer_keys))
+    }
+
+    /// Runs the cluster until shutdown. A cluster that can not be started is
+    /// disabled rather than stopping the gateway, witnesses are then reported
+    /// without waiting for peers.
+    pub async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("task" => "cluster"));
+        let (socket, peers, peer_keys) = match self.bind().await {
+            Ok(bound) => bound,
+            Err(err) => {
+                warn!(logger, "clustering disabled: {err:?}"; "listen" => &self.listen);
+                return Ok(());
+            }
+        };
+        if peer_keys.is_empty() {
+            warn!(
+                logger,
+                "no cluster peer keys, all peer announcements are ignored"
+            );
+        }
+        info!(logger, "starting";
+            "listen" => &self.listen,
+            "peers" => peers.len(),
+            "peer_keys" => peer_keys.len());
+        self.enabled.store(true, Ordering::Relaxed);
+
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => return Ok(()),
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => match serde_json::from_slice::<Announcement>(&buf[..len]) {
+                        Ok(announcement) if announcement.gateway == self.gateway => (),
+                        Ok(announcement) if announcement.verify(&peer_keys) => {
+                            debug!(logger, "peer announcement {announcement:?}"; "peer" => from);
+                            self.record(announcement);
+                        }
+                        Ok(_) => debug!(logger, "ignoring unverified announcement"; "peer" => from),
+                        Err(err) => debug!(logger, "ignoring invalid announcement: {err:?}"; "peer" => from),
+                    },
+                    Err(err) => warn!(logger, "cluster receive error: {err:?}"),
+                },
+                announcement = self.announcements.recv() => if let Some(announcement) = announcement {
+                    let data = match announcement.sign(self.keypair.clone()).await {
+                        Ok(signed) => serde_json::to_vec(&signed)?,
+                        Err(err) => {
+                            warn!(logger, "failed to sign announcement: {err:?}");
+                            continue;
+                        }
+                    };
+                    for peer in &peers {
+                        if let Err(err) = socket.send_to(&data, peer).await {
+                            debug!(logger, "failed to announce to peer: {err:?}"; "peer" => peer);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn record(&self, announcement: Announcement) {
+        let mut witnesses = self.witnesses.lock().expect("cluster lock");
+        let now = Instant::now();
+        witnesses.retain(|_, peers| {
+            peers.retain(|peer| now.duration_since(peer.received) < ANNOUNCEMENT_TTL);
+            !peers.is_empty()
+        });
+        witnesses
+            .entry(announcement.beacon)
+            .or_default()
+            .push(PeerWitness {
+                gateway: announcement.gateway,
+                signal: announcement.signal,
+                observed: announcement.observed,
+                received: now,
+            });
+    }
+}
+
+impl ClusterHandle {
+    /// Announces a witness of the given beacon payload, received at
+    /// `observed` nanoseconds since the unix epoch, to the cluster peers.
+    /// Returns the deadline to check for a redundant witness with
+    /// [`should_suppress`](Self::should_suppress), or None when the cluster
+    /// is not running and the witness can be reported right away.
+    pub fn announce(&self, beacon: &[u8], signal: i32, observed: u64) -> Option<Instant> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let _ = self.announcements.try_send(Announcement {
+            beacon: xxh64(beacon, 0),
+            signal,
+            observed,
+            gateway: self.gateway.clone(),
+            signature: String::new(),
+        });
+        Some(Instant::now() + self.holdoff)
+    }
+
+    /// Returns whether our own announced witness report should be suppressed
+    /// because a peer is reporting the same beacon.
+    pub fn should_suppress(&self, beacon: &[u8], signal: i32, observed: u64) -> bool {
+        let witnesses = self.witnesses.lock().expect("cluster lock");
+        let peers = witnesses
+            .get(&xxh64(beacon, 0))
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        suppress(self.policy, &self.gateway, signal, observed, peers)
+    }
+}
+
+/// Decides whether our witness is redundant. Ties are broken by gateway key
+/// so exactly one gateway of a cluster reports.
+fn suppress(
+    policy: ClusterPolicy,
+    gateway: &str,
+    signal: i32,
+    observed: u64,
+    peers: &[PeerWitness],
+) -> bool {
+    peers.iter().any(|peer| match policy {
+        ClusterPolicy::First if peer.observed.abs_diff(observed) <= FIRST_TOLERANCE_NANOS => {
+            peer.gateway.as_str() < gateway
+        }
+        ClusterPolicy::First => peer.observed < observed,
+        ClusterPolicy::Strongest => {
+            peer.signal > signal || (peer.signal == signal && peer.gateway.as_str() < gateway)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    const OBSERVED: u64 = 1_700_000_000_000_000_000;
+
+    fn peer(gateway: &str, signal: i32, observed: u64) -> PeerWitness {
+        PeerWitness {
+            gateway: gateway.to_string(),
+            signal,
+            observed,
+            received: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let earlier = OBSERVED - 500_000_000;
+        let later = OBSERVED + 500_000_000;
+        let same = OBSERVED + 10_000_000;
+
+        assert!(!suppress(ClusterPolicy::First, "b", -800, OBSERVED, &[]));
+        assert!(suppress(
+            ClusterPolicy::First,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("c", -900, earlier)]
+        ));
+        assert!(!suppress(
+            ClusterPolicy::First,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("a", -700, later)]
+        ));
+        // Beacons heard at the same time go to the lowest gateway key
+        assert!(suppress(
+            ClusterPolicy::First,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("a", -800, same)]
+        ));
+        assert!(!suppress(
+            ClusterPolicy::First,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("c", -800, same)]
+        ));
+
+        assert!(suppress(
+            ClusterPolicy::Strongest,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("c", -700, later)]
+        ));
+        assert!(!suppress(
+            ClusterPolicy::Strongest,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("a", -900, earlier)]
+        ));
+        // Equal signal goes to the lowest gateway key
+        assert!(suppress(
+            ClusterPolicy::Strongest,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("a", -800, later)]
+        ));
+        assert!(!suppress(
+            ClusterPolicy::Strongest,
+            "b",
+            -800,
+            OBSERVED,
+            &[peer("c", -800, later)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn signed_announcements() {
+        let keypair: Keypair = helium_crypto::Keypair::generate(
+            helium_crypto::KeyTag {
+                network: helium_crypto::Network::MainNet,
+                key_type: helium_crypto::KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+        .into();
+        let public_key = keypair.public_key().to_owned();
+        let announcement = Announcement {
+            beacon: 1,
+            signal: -800,
+            observed: OBSERVED,
+            gateway: public_key.to_string(),
+            signature: String::new(),
+        };
+        assert!(!announcement.verify(&[public_key.clone()]));
+        let signed = announcement.sign(Arc::new(keypair)).await.expect("signed");
+        assert!(signed.verify(&[public_key.clone()]));
+        assert!(!signed.verify(&[]));
+
+        let forged = Announcement {
+            signal: -700,
+            ..signed
+        };
+        assert!(!forged.verify(&[public_key]));
+    }
+}
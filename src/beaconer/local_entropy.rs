@@ -0,0 +1,96 @@
+//! Local entropy sources for beacons.
+//!
+//! Local beacon entropy is read from the operating system RNG, a hardware RNG
+//! device or sampled from the RF noise in received packets. Every source is
+//! health checked so a stuck generator fails beacon construction instead of
+//! producing predictable beacons.
+
+use crate::settings::{LocalEntropySource, Settings};
+use beacon::{DeviceSource, EntropySource, HealthChecked, OsRngSource};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+/// The minimum number of packets mixed into the RF pool between draws
+const MIN_RF_SAMPLES: u32 = 8;
+
+#[derive(Debug, Default)]
+struct RfPool {
+    hasher: Sha256,
+    samples: u32,
+}
+
+/// The RF entropy pool the gateway mixes received packets into. Clones share
+/// the same pool.
+#[derive(Debug, Clone, Default)]
+pub struct RfPoolHandle(Arc<Mutex<RfPool>>);
+
+impl RfPoolHandle {
+    /// Mixes the noisy low bits of a received packet's signal readings and
+    /// concentrator timestamp into the pool.
+    pub fn mix_rf_sample(&self, rssi: i32, snr: f32, tmst: u32) {
+        let mut pool = self.0.lock().expect("rf entropy lock");
+        pool.hasher.update(rssi.to_le_bytes());
+        pool.hasher.update(snr.to_le_bytes());
+        pool.hasher.update(tmst.to_le_bytes());
+        pool.samples += 1;
+    }
+}
+
+/// Entropy drawn from the RF noise pool. Fails when too few packets were
+/// received since the previous draw.
+#[derive(Debug, Clone)]
+pub struct RfNoiseSource(RfPoolHandle);
+
+impl EntropySource for RfNoiseSource {
+    fn fill(&mut self, buf: &mut [u8]) -> beacon::Result {
+        let mut pool = self.0 .0.lock().expect("rf entropy lock");
+        if pool.samples < MIN_RF_SAMPLES {
+            return Err(beacon::Error::unhealthy_entropy(format!(
+                "insufficient rf samples: {}",
+                pool.samples
+            )));
+        }
+        let digest = std::mem::take(&mut pool.hasher).finalize();
+        // Chain the pool so the next draw depends on all earlier samples
+        pool.hasher.update(digest);
+        pool.samples = 0;
+        for (chunk, counter) in buf.chunks_mut(digest.len()).zip(0u32..) {
+            let block = Sha256::new()
+                .chain_update(digest)
+                .chain_update(counter.to_le_bytes())
+                .finalize();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the health checked local entropy source configured in settings,
+/// the RF source draws from the given pool
+pub fn source(settings: &Settings, rf_pool: &RfPoolHandle) -> Box<dyn EntropySource> {
+    match settings.poc.entropy_source {
+        LocalEntropySource::Os => Box::new(HealthChecked::new(OsRngSource)),
+        LocalEntropySource::Hwrng => Box::new(HealthChecked::new(DeviceSource::new(
+            settings.poc.entropy_device.clone(),
+        ))),
+        LocalEntropySource::Rf => Box::new(HealthChecked::new(RfNoiseSource(rf_pool.clone()))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rf_pool() {
+        let pool = RfPoolHandle::default();
+        let mut source = HealthChecked::new(RfNoiseSource(pool.clone()));
+        let mut buf = [0u8; 40];
+        assert!(source.fill(&mut buf).is_err());
+        for i in 0..MIN_RF_SAMPLES {
+            pool.mix_rf_sample(-100 - i as i32, 5.5, 1000 * i);
+        }
+        assert!(source.fill(&mut buf).is_ok());
+        assert!(source.fill(&mut buf).is_err());
+    }
+}
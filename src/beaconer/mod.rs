@@ -0,0 +1,1154 @@
+//! This module provides proof-of-coverage (PoC) beaconing support.
+
+use crate::{
+    clock::unix_now,
+    error::RegionError,
+    gateway::{self, BeaconResp},
+    impl_msg_sign, logging, metrics, region_watcher,
+    server::{milestones::Milestone, Handles},
+    service::{entropy::EntropyService, poc::PocIotService, until_shutdown},
+    settings::{AntennaSettings, ChannelMaskSettings, IngestUris, Settings},
+    status_file, sync, Base64, Error, Keypair, MsgSign, Packet, RegionParams, Result,
+};
+use futures::TryFutureExt;
+use helium_proto::services::poc_lora;
+use http::Uri;
+use rand::{rngs::OsRng, Rng};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::{Deserialize, Serialize};
+use slog::{self, info, warn, Level, Logger};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    sync::watch,
+    time::{self, Duration, Instant},
+};
+
+pub mod channel_mask;
+pub mod cluster;
+pub mod local_entropy;
+pub mod receipts;
+pub mod report_store;
+
+use cluster::{Cluster, ClusterHandle};
+use receipts::ReportKind;
+use report_store::{Report, ReportStore};
+
+/// To prevent a thundering herd of hotspots all beaconing at the same time, we
+/// add a randomized jitter value of up to `BEACON_INTERVAL_JITTER_PERCENTAGE`
+/// to the configured beacon interval. This jitter factor is one time only, and
+/// will only change when this process or task restarts.
+const BEACON_INTERVAL_JITTER_PERCENTAGE: u64 = 10;
+
+/// Gauge which is 1 when the last beacon attempt was transmitted and reported
+/// successfully and 0 when it failed
+pub const BEACON_OK_METRIC: &str = "beacon_ok";
+
+/// Gauge which is 1 when the last transmitted beacon was aligned to a GPS
+/// second boundary and 0 when it was sent immediately
+pub const BEACON_ALIGNED_METRIC: &str = "beacon_aligned";
+
+/// Histogram of the seconds from receiving a beacon to its witness report,
+/// labeled with the stage: "signed" once the report is signed and ready to
+/// submit, "submitted" once the ingest service accepted it
+pub const WITNESS_LATENCY_METRIC: &str = "witness_latency_seconds";
+
+/// Counter of witness reports that exceeded the witness latency budget
+pub const WITNESS_LATE_METRIC: &str = "witness_late_total";
+
+/// Counter of received beacons that were not witnessed because they could not
+/// have been a beacon, labeled with the reason: "length", "frequency",
+/// "datarate" or "invalid"
+pub const WITNESS_REJECTED_METRIC: &str = "witness_rejected_total";
+
+/// Conducted power (dBm) of test beacons, or the region maximum when lower.
+/// Packet forwarders transmit at the nearest power of their tx gain table and
+/// report the power used.
+const TEST_BEACON_CONDUCTED_POWER: u32 = 2;
+
+impl_msg_sign!(poc_lora::LoraBeaconReportReqV1, signature);
+impl_msg_sign!(poc_lora::LoraWitnessReportReqV1, signature);
+
+/// The difference (in dBi) between the installed and asserted antenna gain
+/// above which a warning is logged
+const MAX_ANTENNA_GAIN_DEVIATION: Decimal = Decimal::from_parts(5, 0, 0, false, 1);
+
+/// The number of received beacons that can be queued for witness processing
+const WITNESS_QUEUE_SIZE: usize = 10;
+
+const BEACON_STATUS_FILE: &str = "beacon.json";
+
+/// The beacon schedule and the outcome of recent beacon attempts
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeaconStatus {
+    /// Unix time (seconds) of the next scheduled beacon attempt
+    pub next_beacon: Option<u64>,
+    /// Unix time (seconds) of the last successful beacon
+    pub last_beacon: Option<u64>,
+    /// Id of the last successful beacon
+    pub last_beacon_id: Option<String>,
+    /// The error of the last failed beacon attempt
+    pub last_error: Option<String>,
+    /// The number of beacon attempts that failed since the last success
+    pub consecutive_failures: u32,
+    /// The id the ingest service accepted the report of the last successful
+    /// beacon with. None when the report was stored for deferred submission
+    /// or the ingest service returned no id.
+    #[serde(default)]
+    pub last_receipt: Option<String>,
+    /// Whether the last transmitted beacon was aligned to a GPS second
+    /// boundary
+    #[serde(default)]
+    pub last_beacon_aligned: bool,
+}
+
+/// The part of the beacon status that is stored in the data directory to
+/// survive a restart
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredBeacon {
+    last_beacon: Option<u64>,
+    last_beacon_id: Option<String>,
+    #[serde(default)]
+    last_receipt: Option<String>,
+}
+
+impl From<&BeaconStatus> for StoredBeacon {
+    fn from(status: &BeaconStatus) -> Self {
+        Self {
+            last_beacon: status.last_beacon,
+            last_beacon_id: status.last_beacon_id.clone(),
+            last_receipt: status.last_receipt.clone(),
+        }
+    }
+}
+
+impl BeaconStatus {
+    /// Load the last successful beacon stored by the gateway service. Returns
+    /// None when the service has not stored a beacon yet.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        let stored: Option<StoredBeacon> = status_file::load(data_dir, BEACON_STATUS_FILE)?;
+        Ok(stored.map(|stored| Self {
+            last_beacon: stored.last_beacon,
+            last_beacon_id: stored.last_beacon_id,
+            last_receipt: stored.last_receipt,
+            ..Default::default()
+        }))
+    }
+
+    /// Stores the last successful beacon. The schedule and failures are not
+    /// stored since they start over with the service.
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, BEACON_STATUS_FILE, &StoredBeacon::from(self))
+    }
+
+    fn record(&mut self, result: std::result::Result<&str, String>, now: u64) {
+        match result {
+            Ok(beacon_id) => {
+                self.last_beacon = Some(now);
+                self.last_beacon_id = Some(beacon_id.to_string());
+                self.last_receipt = None;
+                self.consecutive_failures = 0;
+            }
+            Err(err) => {
+                self.last_error = Some(err);
+                self.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
+/// Message types that can be sent to `Beaconer`'s inbox.
+#[derive(Debug)]
+pub enum Message {
+    ReceivedBeacon(Packet),
+    TransmitBeacon(BeaconRequest),
+    Status(sync::ResponseSender<BeaconStatus>),
+}
+
+/// A request for an unscheduled beacon
+#[derive(Debug)]
+pub enum BeaconRequest {
+    /// A beacon that is reported like a scheduled beacon. Responds with the id
+    /// of the transmitted beacon.
+    Beacon(sync::ResponseSender<Result<String>>),
+    /// A test beacon, see [`TestBeacon`]
+    Test(sync::ResponseSender<Result<TestBeacon>>),
+}
+
+/// A beacon transmitted to validate the transmit chain, for example by an
+/// installer. Test beacons are transmitted at reduced power, made from local
+/// entropy only and never reported, so they do not affect proof-of-coverage.
+#[derive(Debug, Clone, Serialize)]
+pub struct TestBeacon {
+    pub beacon_id: String,
+    /// Frequency in Hz
+    pub frequency: u64,
+    pub datarate: String,
+    /// The conducted power (dBm) the packet forwarder transmitted at
+    pub power: i32,
+    pub tmst: u32,
+}
+
+pub type MessageSender = sync::MessageSender<Message>;
+pub type MessageReceiver = sync::MessageReceiver<Message>;
+
+pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
+    sync::message_channel(size)
+}
+
+impl MessageSender {
+    pub async fn received_beacon(&self, packet: Packet) {
+        self.send(Message::ReceivedBeacon(packet)).await
+    }
+
+    /// Constructs and transmits a beacon outside of the beacon schedule
+    pub async fn transmit_beacon(&self) -> Result<String> {
+        self.request(|tx| Message::TransmitBeacon(BeaconRequest::Beacon(tx)))
+            .await?
+    }
+
+    /// Transmits a test beacon, see [`TestBeacon`]
+    pub async fn transmit_test_beacon(&self) -> Result<TestBeacon> {
+        self.request(|tx| Message::TransmitBeacon(BeaconRequest::Test(tx)))
+            .await?
+    }
+
+    /// Returns the beacon schedule and the outcome of recent beacon attempts
+    pub async fn beacon_status(&self) -> Result<BeaconStatus> {
+        self.request(Message::Status).await
+    }
+}
+
+/// Runs beacon construction/transmission and witness report processing as
+/// independent tasks so that a slow witness submission can not delay a
+/// scheduled beacon.
+pub struct Beaconer {
+    /// Our receive queue.
+    messages: MessageReceiver,
+    /// Received beacons for the witness task
+    witness_queue: sync::MessageSender<Packet>,
+    /// Beacon requests for the beacon task
+    beacon_requests: sync::MessageSender<BeaconRequest>,
+    /// Beacon status maintained by the beacon task
+    status: watch::Receiver<BeaconStatus>,
+    beacons: BeaconTask,
+    witnesses: WitnessTask,
+    /// Witness deduplication with co-located gateways, when configured
+    cluster: Option<Cluster>,
+    /// Stored reports to submit, in deferred mode
+    deferred: Option<ReportStore>,
+}
+
+/// Schedules, transmits and reports beacons
+struct BeaconTask {
+    /// keypair to sign reports with
+    keypair: Arc<Keypair>,
+    /// gateway packet transmit message queue
+    transmit: gateway::MessageSender,
+    /// Region change queue
+    region_watch: region_watcher::MessageReceiver,
+    /// Unscheduled beacon requests
+    requests: sync::MessageReceiver<BeaconRequest>,
+    /// Beacon interval
+    interval: Duration,
+    // Time next beacon attempt is o be made
+    next_beacon_time: Instant,
+    /// The payload of the last beacon that was transmitted
+    last_beacon: watch::Sender<Option<Vec<u8>>>,
+    /// Use for channel plan and FR parameters
+    region_params: RegionParams,
+    /// Ingest URLs for reports, selected by the current region
+    ingest_uris: IngestUris,
+    entropy_uri: Uri,
+    /// Health checked source of local beacon entropy
+    entropy_source: Box<dyn beacon::EntropySource>,
+    /// Adaptive channel mask settings, when enabled
+    channel_mask: Option<ChannelMaskSettings>,
+    /// Installed antenna metadata logged with beacon reports
+    antenna: AntennaSettings,
+    /// Beacon schedule and outcomes, also stored in the data directory
+    status: watch::Sender<BeaconStatus>,
+    data_dir: PathBuf,
+    /// Report store in deferred mode
+    deferred: Option<ReportStore>,
+    handles: Handles,
+}
+
+/// A signed witness report announced to the cluster peers
+struct HeldWitness {
+    /// When to decide whether a peer reports the beacon
+    deadline: Instant,
+    report: poc_lora::LoraWitnessReportReqV1,
+    /// When the beacon was received
+    received: std::time::Instant,
+}
+
+/// Signs and submits witness reports for received beacons
+struct WitnessTask {
+    /// keypair to sign reports with
+    keypair: Arc<Keypair>,
+    /// Received beacons to witness
+    messages: sync::MessageReceiver<Packet>,
+    /// The payload of the last beacon transmitted by the beacon task
+    last_beacon: watch::Receiver<Option<Vec<u8>>>,
+    /// Cluster to check for redundant witnesses with
+    cluster: Option<ClusterHandle>,
+    /// Signed witness reports waiting for the cluster hold-off, oldest first
+    held: VecDeque<HeldWitness>,
+    /// Used to verify received beacons against the channel plan
+    region_watch: region_watcher::MessageReceiver,
+    /// Ingest URLs for reports, selected by the current region
+    ingest_uris: IngestUris,
+    /// Report store in deferred mode
+    deferred: Option<ReportStore>,
+    /// Latency budget from receiving a beacon to submitting its witness
+    /// report, if enforced
+    max_latency: Option<Duration>,
+    /// Whether reports over the latency budget are dropped rather than only
+    /// flagged
+    drop_late: bool,
+    handles: Handles,
+}
+
+impl Beaconer {
+    pub fn new(
+        settings: &Settings,
+        messages: MessageReceiver,
+        region_watch: region_watcher::MessageReceiver,
+        transmit: gateway::MessageSender,
+        handles: &Handles,
+    ) -> Self {
+        let interval = Duration::from_secs(settings.poc.interval);
+        let ingest_uris = settings.poc.ingest_uris();
+        let entropy_uri = settings.poc.entropy_uri.clone();
+        let keypair = settings.keypair.clone();
+        let region_params = region_watcher::current_value(&region_watch);
+        let (last_beacon_tx, last_beacon_rx) = watch::channel(None);
+        let (witness_tx, witness_rx) = sync::message_channel(WITNESS_QUEUE_SIZE);
+        let (request_tx, request_rx) = sync::message_channel(1);
+        let (status_tx, status_rx) = watch::channel(
+            BeaconStatus::load(&settings.data_dir)
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+        );
+        let (cluster, cluster_handle) = Cluster::new(settings).unzip();
+        let deferred = ReportStore::new(settings, region_watch.clone(), handles);
+
+        let beacons = BeaconTask {
+            keypair: keypair.clone(),
+            transmit,
+            region_watch: region_watch.clone(),
+            requests: request_rx,
+            interval,
+            last_beacon: last_beacon_tx,
+            // Set a beacon at least an interval out... arrival of region_params
+            // will recalculate this time and no arrival of region_params will
+            // cause the beacon to not occur
+            next_beacon_time: Instant::now() + interval,
+            region_params,
+            ingest_uris: ingest_uris.clone(),
+            entropy_uri,
+            entropy_source: local_entropy::source(settings, &handles.rf_pool),
+            channel_mask: settings
+                .channel_mask
+                .enabled
+                .then(|| settings.channel_mask.clone()),
+            antenna: settings.antenna,
+            status: status_tx,
+            data_dir: settings.data_dir.clone(),
+            deferred: deferred.clone(),
+            handles: handles.clone(),
+        };
+        let witnesses = WitnessTask {
+            keypair,
+            messages: witness_rx,
+            last_beacon: last_beacon_rx,
+            cluster: cluster_handle,
+            held: VecDeque::new(),
+            region_watch,
+            ingest_uris,
+            deferred: deferred.clone(),
+            max_latency: (settings.poc.witness_max_latency > 0)
+                .then(|| Duration::from_millis(settings.poc.witness_max_latency)),
+            drop_late: settings.poc.witness_late_drop,
+            handles: handles.clone(),
+        };
+        Self {
+            messages,
+            witness_queue: witness_tx,
+            beacon_requests: request_tx,
+            status: status_rx,
+            beacons,
+            witnesses,
+            cluster,
+            deferred,
+        }
+    }
+
+    pub async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(slog::o!("module" => "beacon"));
+        info!(logger, "starting";
+            "beacon_interval" => self.beacons.interval.as_secs(),
+            "deferred" => self.deferred.is_some());
+
+        tokio::try_join!(
+            Self::dispatch(
+                &mut self.messages,
+                &self.witness_queue,
+                &self.beacon_requests,
+                &self.status,
+                shutdown,
+                &logger
+            ),
+            self.beacons.run(shutdown, &logger),
+            self.witnesses
+                .run(shutdown, &logger.new(slog::o!("task" => "witness"))),
+            Self::run_cluster(self.cluster.as_mut(), shutdown, &logger),
+            Self::run_deferred(self.deferred.as_ref(), shutdown, &logger),
+        )
+        .map(|_| ())
+    }
+
+    async fn run_cluster(
+        cluster: Option<&mut Cluster>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
+        match cluster {
+            Some(cluster) => cluster.run(shutdown, logger).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn run_deferred(
+        deferred: Option<&ReportStore>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
+        match deferred {
+            Some(deferred) => deferred.run(shutdown, logger).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Hands inbox messages to the beacon and witness tasks. Received beacons
+    /// are dropped rather than waited on when the witness queue is full so the
+    /// gateway is never held up by a slow witness submission.
+    async fn dispatch(
+        messages: &mut MessageReceiver,
+        witness_queue: &sync::MessageSender<Packet>,
+        beacon_requests: &sync::MessageSender<BeaconRequest>,
+        status: &watch::Receiver<BeaconStatus>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => return Ok(()),
+                message = messages.recv() => match message {
+                    Some(Message::ReceivedBeacon(packet)) => {
+                        if witness_queue.try_send(packet).is_err() {
+                            warn!(logger, "witness queue full, dropping received beacon");
+                        }
+                    }
+                    Some(Message::TransmitBeacon(request)) => beacon_requests.send(request).await,
+                    Some(Message::Status(response)) => response.send(status.borrow().clone(), logger),
+                    None => {
+                        warn!(logger, "ignoring closed message channel");
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl BeaconTask {
+    async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if let Some(gain) = self.antenna.gain.and_then(|gain| gain.to_f64()) {
+            metrics::set("antenna_gain_dbi", gain);
+        }
+        if let Some(elevation) = self.antenna.elevation {
+            metrics::set("antenna_elevation_m", elevation as f64);
+        }
+        self.schedule(self.next_beacon_time, logger);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = time::sleep_until(self.next_beacon_time) => {
+                    until_shutdown(shutdown, self.handle_beacon_tick(logger)).await;
+                },
+                request = self.requests.recv() => match request {
+                    Some(BeaconRequest::Beacon(response)) => {
+                        if let Some(result) = until_shutdown(shutdown, self.handle_beacon_request(logger)).await {
+                            response.send(result, logger)
+                        }
+                    }
+                    Some(BeaconRequest::Test(response)) => {
+                        if let Some(result) = until_shutdown(shutdown, self.handle_test_beacon(logger)).await {
+                            response.send(result, logger)
+                        }
+                    }
+                    None => (),
+                },
+                region_change = self.region_watch.changed() => match region_change {
+                    Ok(()) => {
+                        // Recalculate beacon time based on if this was the
+                        // first time region params have arrived. Do the first
+                        // time check below before region params are assigned
+                        self.schedule(
+                            Beaconer::mk_next_beacon_time(self.interval, self.region_params.params.is_empty()),
+                            logger,
+                        );
+                        self.region_params = region_watcher::current_value(&self.region_watch);
+                        info!(logger, "updated region";
+                            "region" => RegionParams::to_string(&self.region_params),
+                            "ism2400" => self.region_params.is_ism2400(),
+                            "ingest_uri" => self.ingest_uris.for_region(&self.region_params.region).to_string());
+                    },
+                    Err(_) => warn!(logger, "region watch disconnected"),
+                }
+
+
+            }
+        }
+    }
+
+    async fn mk_beacon(&mut self) -> Result<beacon::Beacon> {
+        if self.region_params.params.is_empty() {
+            return Err(RegionError::no_region_params());
+        }
+
+        let mut entropy_service = EntropyService::new(self.entropy_uri.clone(), &self.handles);
+        let remote_entropy = entropy_service.get_entropy().await?;
+        let local_entropy = beacon::Entropy::local_from(self.entropy_source.as_mut())?;
+
+        let beacon = beacon::Beacon::new(remote_entropy, local_entropy, &self.region_params)?;
+        check_reportable(&beacon)?;
+        if let Some(settings) = &self.channel_mask {
+            let plan: Vec<u64> = self
+                .region_params
+                .params
+                .iter()
+                .map(|params| params.channel_frequency)
+                .collect();
+            // The frequency follows from the entropy. Making the beacon again
+            // with other local entropy would be grinding it, so a beacon on a
+            // masked channel is skipped instead.
+            if self
+                .handles
+                .channel_mask
+                .update(&plan, settings)
+                .contains(&beacon.frequency)
+            {
+                return Err(RegionError::masked_channel(beacon.frequency));
+            }
+        }
+        Ok(beacon)
+    }
+
+    /// Sends a gateway-to-gateway packet.
+    ///
+    /// See [`gateway::MessageSender::transmit_beacon`]
+    ///
+    /// Returns an error only when the beacon could not be transmitted. Report
+    /// failures are logged.
+    async fn send_beacon(&mut self, beacon: beacon::Beacon, logger: &Logger) -> Result {
+        let beacon_id = beacon.beacon_id();
+        info!(logger, "transmitting beacon"; "beacon" => &beacon_id);
+
+        let (powe, tmst) = match self.transmit.transmit_beacon(beacon.clone()).await {
+            Ok(BeaconResp {
+                powe,
+                tmst,
+                aligned,
+            }) => {
+                metrics::set(BEACON_ALIGNED_METRIC, if aligned { 1.0 } else { 0.0 });
+                self.update_status(|status| status.last_beacon_aligned = aligned, logger);
+                (powe, tmst)
+            }
+            Err(err) => {
+                warn!(logger, "failed to transmit beacon {err:?}"; "code" => err.code());
+                self.record_attempt(Err(format!("transmit failed: {err}")), logger);
+                return Err(err);
+            }
+        };
+
+        self.last_beacon.send_replace(Some(beacon.data.clone()));
+
+        let report = match self.mk_beacon_report(beacon, powe, tmst).await {
+            Ok(report) => report,
+            Err(err) => {
+                warn!(logger, "failed to construct beacon report {err:?}";
+                    "beacon" => &beacon_id, "code" => err.code());
+                self.record_attempt(Err(format!("report failed: {err}")), logger);
+                return Ok(());
+            }
+        };
+        self.log_antenna(logger, &beacon_id);
+        if let Some(deferred) = &self.deferred {
+            let stored = deferred
+                .push(&Report::Beacon(report))
+                .inspect_err(|err| {
+                    warn!(logger, "failed to store poc beacon report: {err:?}";
+                        "beacon" => &beacon_id, "code" => err.code())
+                })
+                .inspect(|_| info!(logger, "poc beacon report stored"; "beacon" => &beacon_id))
+                .map(|_| beacon_id.as_str())
+                .map_err(|err| format!("report store failed: {err}"));
+            self.record_attempt(stored, logger);
+            return Ok(());
+        }
+        let ingest_uri = self.ingest_uris.for_region(&self.region_params.region);
+        let submitted = PocIotService::new(ingest_uri.clone(), &self.handles)
+            .submit_beacon(report)
+            .inspect_err(|err| {
+                info!(logger, "failed to submit poc beacon report: {err:?}";
+                "beacon" => &beacon_id, "code" => err.code())
+            })
+            .inspect_ok(|id| {
+                info!(logger, "poc beacon report submitted"; "beacon" => &beacon_id, "receipt" => id);
+                self.handles
+                    .receipts
+                    .record(logger, ReportKind::Beacon, &beacon_id, id.clone());
+            })
+            .await
+            .map_err(|err| format!("report submission failed: {err}"));
+        let receipt = submitted.as_ref().ok().filter(|id| !id.is_empty()).cloned();
+        self.record_attempt(submitted.map(|_| beacon_id.as_str()), logger);
+        if receipt.is_some() {
+            self.update_status(|status| status.last_receipt = receipt, logger);
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of a beacon attempt in the beacon status and the
+    /// beacon ok metric
+    fn record_attempt(&self, result: std::result::Result<&str, String>, logger: &Logger) {
+        metrics::set(BEACON_OK_METRIC, if result.is_ok() { 1.0 } else { 0.0 });
+        self.update_status(|status| status.record(result, unix_now()), logger);
+    }
+
+    /// Sets the time of the next scheduled beacon attempt
+    fn schedule(&mut self, next_beacon_time: Instant, logger: &Logger) {
+        self.next_beacon_time = next_beacon_time;
+        let next_beacon = unix_now()
+            + next_beacon_time
+                .saturating_duration_since(Instant::now())
+                .as_secs();
+        self.update_status(|status| status.next_beacon = Some(next_beacon), logger);
+    }
+
+    /// Updates the beacon status and stores it when the last successful
+    /// beacon changed
+    fn update_status(&self, f: impl FnOnce(&mut BeaconStatus), logger: &Logger) {
+        let stored = StoredBeacon::from(&*self.status.borrow());
+        self.status.send_modify(f);
+        let status = self.status.borrow().clone();
+        if StoredBeacon::from(&status) == stored {
+            return;
+        }
+        if let Err(err) = status.save(&self.data_dir) {
+            warn!(logger, "failed to store beacon status: {err:?}");
+        }
+    }
+
+    /// Logs the installed antenna metadata with a beacon report. The report
+    /// proto has no fields for it so it is only logged (and exposed as
+    /// metrics) for audits.
+    fn log_antenna(&self, logger: &Logger, beacon_id: &str) {
+        let asserted_gain = self.region_params.gain;
+        info!(logger, "beacon antenna";
+            "beacon" => beacon_id,
+            "asserted_gain" => asserted_gain.to_string(),
+            "gain" => self.antenna.gain.map(|gain| gain.to_string()),
+            "elevation" => self.antenna.elevation);
+        if let Some(gain) = self.antenna.gain {
+            if (gain - asserted_gain).abs() > MAX_ANTENNA_GAIN_DEVIATION {
+                warn!(logger, "installed antenna gain differs from asserted gain";
+                    "asserted_gain" => asserted_gain.to_string(),
+                    "gain" => gain.to_string());
+            }
+        }
+    }
+
+    async fn mk_beacon_report(
+        &self,
+        beacon: beacon::Beacon,
+        conducted_power: i32,
+        tmst: u32,
+    ) -> Result<poc_lora::LoraBeaconReportReqV1> {
+        let mut report = poc_lora::LoraBeaconReportReqV1::try_from(beacon)?;
+        report.tx_power = conducted_power;
+        report.tmst = tmst;
+        report.pub_key = self.keypair.public_key().to_vec();
+        report.signature = report.sign(self.keypair.clone()).await?;
+        Ok(report)
+    }
+
+    async fn handle_beacon_tick(&mut self, logger: &Logger) {
+        match self.mk_beacon().await {
+            Ok(beacon) => {
+                let _ = self.send_beacon(beacon, logger).await;
+                // On success just use the normal behavior for selecting a next
+                // beacon time. Can't be the first time since we have region
+                // parameters to construct a beacon
+                self.schedule(Beaconer::mk_next_beacon_time(self.interval, false), logger);
+            }
+            Err(Error::Region(RegionError::MaskedChannel(frequency))) => {
+                info!(logger, "skipping beacon on masked channel"; "frequency" => frequency);
+                self.schedule(Beaconer::mk_next_beacon_time(self.interval, false), logger);
+            }
+            Err(err) => {
+                warn!(logger, "failed to construct beacon: {err:?}"; "code" => err.code());
+                self.record_attempt(Err(format!("construction failed: {err}")), logger);
+                // On failure to construct a beacon at all, select a shortened
+                // "first time" next beacon time
+                self.schedule(Beaconer::mk_next_beacon_time(self.interval, true), logger);
+            }
+        };
+    }
+
+    /// Transmits an unscheduled beacon. The beacon schedule is not affected.
+    async fn handle_beacon_request(&mut self, logger: &Logger) -> Result<String> {
+        let beacon = self.mk_beacon().await.inspect_err(|err| {
+            warn!(logger, "failed to construct requested beacon: {err:?}"; "code" => err.code());
+            self.record_attempt(Err(format!("construction failed: {err}")), logger);
+        })?;
+        let beacon_id = beacon.beacon_id();
+        self.send_beacon(beacon, logger).await?;
+        Ok(beacon_id)
+    }
+
+    /// Transmits a test beacon. The beacon schedule and status are not
+    /// affected and no report is made.
+    async fn handle_test_beacon(&mut self, logger: &Logger) -> Result<TestBeacon> {
+        if self.region_params.params.is_empty() {
+            return Err(RegionError::no_region_params());
+        }
+        // Test beacons are never reported so remote entropy is not needed
+        let entropy = beacon::Entropy::local_from(self.entropy_source.as_mut())?;
+        let mut beacon = beacon::Beacon::new(entropy.clone(), entropy, &self.region_params)?;
+        beacon.conducted_power = beacon.conducted_power.min(TEST_BEACON_CONDUCTED_POWER);
+        let beacon_id = beacon.beacon_id();
+        info!(logger, "transmitting test beacon";
+            "beacon" => &beacon_id,
+            "power" => beacon.conducted_power);
+
+        let resp = self
+            .transmit
+            .transmit_beacon(beacon.clone())
+            .await
+            .inspect_err(
+                |err| warn!(logger, "failed to transmit test beacon {err:?}"; "code" => err.code()),
+            )?;
+        // Keeps the witness task from witnessing its own test beacon
+        self.last_beacon.send_replace(Some(beacon.data.clone()));
+        Ok(TestBeacon {
+            beacon_id,
+            frequency: beacon.frequency,
+            datarate: beacon.datarate.to_string(),
+            power: resp.powe,
+            tmst: resp.tmst,
+        })
+    }
+}
+
+impl WitnessTask {
+    async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                packet = self.messages.recv() => if let Some(packet) = packet {
+                    until_shutdown(shutdown, self.handle_received_beacon(packet, logger)).await;
+                },
+                _ = time::sleep_until(self.held.front().map_or_else(Instant::now, |held| held.deadline)), if !self.held.is_empty() => {
+                    if let Some(held) = self.held.pop_front() {
+                        until_shutdown(shutdown, self.handle_held_witness(held, logger)).await;
+                    }
+                },
+            }
+        }
+    }
+
+    async fn mk_witness_report(&self, packet: Packet) -> Result<poc_lora::LoraWitnessReportReqV1> {
+        let region_params = region_watcher::current_value(&self.region_watch);
+        let mut report = witness_report(packet, &region_params)?;
+        report.pub_key = self.keypair.public_key().to_vec();
+        report.signature = report.sign(self.keypair.clone()).await?;
+        Ok(report)
+    }
+
+    async fn handle_received_beacon(&mut self, packet: Packet, logger: &Logger) {
+        if let Some(suppressed) = logging::sampling::sample(Level::Info, "witness") {
+            info!(logger, "received possible PoC payload: {packet:?}";
+                "suppressed" => suppressed);
+        }
+
+        if self.last_beacon.borrow().as_ref() == Some(&packet.payload) {
+            info!(logger, "ignoring last self beacon witness");
+            return;
+        }
+
+        let received = packet.received().instant;
+        let report = match self.mk_witness_report(packet).await {
+            Ok(report) => report,
+            Err(err) => {
+                reject_witness(logger, &err);
+                return;
+            }
+        };
+        let latency = received.elapsed();
+        metrics::observe(
+            &metrics::labeled(WITNESS_LATENCY_METRIC, &[("stage", "signed")]),
+            latency.as_secs_f64(),
+        );
+
+        // Wait for cluster peers to announce the same beacon without holding
+        // up the witnesses of other beacons
+        if let Some(deadline) = self
+            .cluster
+            .as_ref()
+            .and_then(|cluster| cluster.announce(&report.data, report.signal, report.timestamp))
+        {
+            self.held.push_back(HeldWitness {
+                deadline,
+                report,
+                received,
+            });
+            return;
+        }
+        self.report_witness(report, received, logger).await
+    }
+
+    async fn handle_held_witness(&mut self, held: HeldWitness, logger: &Logger) {
+        let HeldWitness {
+            report, received, ..
+        } = held;
+        if let Some(cluster) = &self.cluster {
+            if cluster.should_suppress(&report.data, report.signal, report.timestamp) {
+                info!(logger, "suppressing witness reported by cluster peer";
+                    "beacon" => report.data.to_b64());
+                return;
+            }
+        }
+        self.report_witness(report, received, logger).await
+    }
+
+    async fn report_witness(
+        &mut self,
+        report: poc_lora::LoraWitnessReportReqV1,
+        received: std::time::Instant,
+        logger: &Logger,
+    ) {
+        if let Some(deferred) = &self.deferred {
+            let beacon = report.data.to_b64();
+            match deferred.push(&Report::Witness(report)) {
+                Ok(()) => info!(logger, "poc witness report stored"; "beacon" => beacon),
+                Err(err) => warn!(logger, "failed to store poc witness report: {err:?}"),
+            }
+            return;
+        }
+
+        // Deferred reports are expected to be late and are bounded by the
+        // deferred max age instead
+        let latency = received.elapsed();
+        if let Some(max_latency) = self.max_latency.filter(|max| latency > *max) {
+            metrics::increment(WITNESS_LATE_METRIC);
+            warn!(logger, "witness report over latency budget";
+                "beacon" => report.data.to_b64(),
+                "latency_ms" => latency.as_millis() as u64,
+                "max_latency_ms" => max_latency.as_millis() as u64,
+                "dropped" => self.drop_late);
+            if self.drop_late {
+                return;
+            }
+        }
+
+        let region = self.region_watch.borrow().region;
+        let _ = PocIotService::new(self.ingest_uris.for_region(&region).clone(), &self.handles)
+            .submit_witness(report.clone())
+            .inspect_err(|err| info!(logger, "failed to submit poc witness report: {err:?}"; "beacon" => report.data.to_b64()))
+            .inspect_ok(|id| {
+                metrics::observe(
+                    &metrics::labeled(WITNESS_LATENCY_METRIC, &[("stage", "submitted")]),
+                    received.elapsed().as_secs_f64(),
+                );
+                let beacon = report.data.to_b64();
+                info!(logger, "poc witness report submitted"; "beacon" => &beacon, "receipt" => id);
+                self.handles
+                    .receipts
+                    .record(logger, ReportKind::Witness, &beacon, id.clone());
+                self.handles
+                    .milestones
+                    .reach(logger, Milestone::WitnessReported);
+            })
+            .await;
+    }
+}
+
+impl Beaconer {
+    /// Construct a beacon time based on the interval and whether this is the
+    /// "first time" to beacon. The first beacon time is closed by in time
+    fn mk_next_beacon_time(interval: Duration, first_params: bool) -> Instant {
+        let now = Instant::now();
+        if first_params {
+            let max_jitter = (interval.as_secs() * BEACON_INTERVAL_JITTER_PERCENTAGE) / 100;
+            let jitter = OsRng.gen_range(0..=max_jitter);
+            now + Duration::from_secs(jitter)
+        } else {
+            now + interval
+        }
+    }
+}
+
+/// Checks that a beacon can be reported before it is transmitted. Beacon
+/// reports only carry the datarates of the report proto, which has none of
+/// the 2.4 GHz datarates.
+fn check_reportable(beacon: &beacon::Beacon) -> Result {
+    match beacon.datarate.to_proto() {
+        Some(_) => Ok(()),
+        None => Err(beacon::Error::unsupported_datarate(beacon.datarate).into()),
+    }
+}
+
+/// Builds the unsigned witness report for a received beacon. Receptions that
+/// can not be a beacon in the region are rejected before the report is built,
+/// and the report is verified once region params are known.
+fn witness_report(
+    packet: Packet,
+    region_params: &RegionParams,
+) -> Result<poc_lora::LoraWitnessReportReqV1> {
+    // The beacon is the proprietary frame payload, after the one byte MHDR
+    let datarate = packet.datarate.parse::<beacon::LoraDataRate>()?;
+    beacon::Beacon::verify_received_length(
+        packet.payload.len().saturating_sub(1),
+        &datarate,
+        region_params,
+    )?;
+    let report = poc_lora::LoraWitnessReportReqV1::try_from(packet)?;
+    // Received beacons can only be verified once region params are known
+    if !region_params.params.is_empty() {
+        beacon::Beacon::verify_received(&report.data, report.frequency, &datarate, region_params)?;
+    }
+    Ok(report)
+}
+
+/// The reason label of a rejected witness report
+fn reject_reason(err: &Error) -> &'static str {
+    match err {
+        Error::Beacon(beacon::Error::InvalidBeaconLength(_)) => "length",
+        Error::Beacon(beacon::Error::InvalidBeaconFrequency(_)) => "frequency",
+        Error::Beacon(
+            beacon::Error::InvalidBeaconDataRate(..) | beacon::Error::UnsupportedDataRate(_),
+        ) => "datarate",
+        _ => "invalid",
+    }
+}
+
+/// Counts and logs a received beacon that is not witnessed
+fn reject_witness(logger: &Logger, err: &Error) {
+    let reason = reject_reason(err);
+    metrics::increment(&metrics::labeled(
+        WITNESS_REJECTED_METRIC,
+        &[("reason", reason)],
+    ));
+    warn!(logger, "ignoring invalid witness report: {err:?}"; "reason" => reason);
+}
+
+#[test]
+fn test_beacon_status() {
+    let mut status = BeaconStatus::default();
+    status.record(Err("transmit failed: duty cycle".to_string()), 10);
+    status.record(Err("construction failed: entropy".to_string()), 20);
+    assert_eq!(2, status.consecutive_failures);
+    assert_eq!(None, status.last_beacon);
+    assert_eq!(
+        Some("construction failed: entropy"),
+        status.last_error.as_deref()
+    );
+
+    status.record(Ok("beacon"), 30);
+    assert_eq!(0, status.consecutive_failures);
+    assert_eq!(Some(30), status.last_beacon);
+    assert_eq!(Some("beacon"), status.last_beacon_id.as_deref());
+    // The last error is kept for diagnosis after a recovery
+    assert!(status.last_error.is_some());
+}
+
+#[test]
+fn test_beacon_status_store() {
+    let data_dir = std::env::temp_dir().join(format!("beacon_status_{}", std::process::id()));
+    let mut status = BeaconStatus {
+        next_beacon: Some(100),
+        ..Default::default()
+    };
+    status.record(Ok("beacon"), 30);
+    status.record(Err("transmit failed: duty cycle".to_string()), 40);
+    status.save(&data_dir).expect("saved status");
+
+    // Only the last successful beacon survives a restart
+    let loaded = BeaconStatus::load(&data_dir).expect("loaded status");
+    let _ = std::fs::remove_dir_all(&data_dir);
+    assert_eq!(
+        Some(BeaconStatus {
+            last_beacon: Some(30),
+            last_beacon_id: Some("beacon".to_string()),
+            ..Default::default()
+        }),
+        loaded
+    );
+}
+
+#[test]
+fn test_beacon_roundtrip() {
+    use lorawan::PHYPayload;
+
+    let phy_payload_a = PHYPayload::proprietary(b"poc_beacon_data");
+    let payload: Vec<u8> = phy_payload_a.clone().try_into().expect("beacon packet");
+    let phy_payload_b = PHYPayload::read(lorawan::Direction::Uplink, &mut &payload[..]).unwrap();
+    assert_eq!(phy_payload_a, phy_payload_b);
+}
+
+#[test]
+fn test_witness_golden_reports() {
+    use crate::packet::ReceivedAt;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use helium_proto::{
+        BlockchainRegionParamV1, BlockchainRegionSpreadingV1, DataRate as ProtoDataRate,
+        Message as ProtoMessage, Region as ProtoRegion, RegionSpreading, TaggedSpreading,
+    };
+    use semtech_udp::push_data;
+    use std::{fs, str::FromStr, time::UNIX_EPOCH};
+    use RegionSpreading::*;
+
+    /// A recorded packet forwarder uplink with the witness report expected
+    /// for it, without public key and signature, or the reason it is rejected
+    #[derive(Deserialize)]
+    struct Golden {
+        rxpk: push_data::RxPk,
+        /// Unix time (nanoseconds) the uplink was received
+        received: u64,
+        region: String,
+        /// Whether the region params are not known yet
+        #[serde(default)]
+        unverified: bool,
+        report: Option<GoldenReport>,
+        rejected: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct GoldenReport {
+        data: String,
+        timestamp: u64,
+        tmst: u32,
+        signal: i32,
+        snr: i32,
+        frequency: u64,
+        datarate: String,
+    }
+
+    let plan = |region: ProtoRegion, channels: &[u64], spreadings: &[(RegionSpreading, u32)]| {
+        RegionParams {
+            gain: 12.into(),
+            region: region.into(),
+            params: channels
+                .iter()
+                .map(|channel_frequency| BlockchainRegionParamV1 {
+                    channel_frequency: *channel_frequency,
+                    bandwidth: 125_000,
+                    max_eirp: 160,
+                    spreading: Some(BlockchainRegionSpreadingV1 {
+                        tagged_spreading: spreadings
+                            .iter()
+                            .map(|(spreading, max_packet_size)| TaggedSpreading {
+                                region_spreading: (*spreading).into(),
+                                max_packet_size: *max_packet_size,
+                            })
+                            .collect(),
+                    }),
+                })
+                .collect(),
+            name: None,
+        }
+    };
+    let region_params = |region: &str| match region {
+        "EU868" => plan(
+            ProtoRegion::Eu868,
+            &[868_100_000, 868_300_000, 868_500_000],
+            &[(Sf12, 65), (Sf11, 129), (Sf10, 129), (Sf9, 129), (Sf8, 238)],
+        ),
+        "US915" => plan(
+            ProtoRegion::Us915,
+            &(0..8)
+                .map(|n| 903_900_000 + n * 200_000)
+                .collect::<Vec<_>>(),
+            &[(Sf10, 24), (Sf9, 53), (Sf8, 125), (Sf7, 242)],
+        ),
+        other => panic!("no region params for {other}"),
+    };
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/golden/witness");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .expect("golden dir")
+        .map(|entry| entry.expect("golden entry").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let golden: Golden =
+            serde_json::from_slice(&fs::read(&path).expect("golden file")).expect("golden json");
+        let received = ReceivedAt {
+            instant: std::time::Instant::now(),
+            wall: UNIX_EPOCH + Duration::from_nanos(golden.received),
+        };
+        let packet = Packet::try_from(golden.rxpk)
+            .expect("golden packet")
+            .with_received(received);
+        let mut region_params = region_params(&golden.region);
+        if golden.unverified {
+            region_params.params.clear();
+        }
+
+        match (witness_report(packet, &region_params), golden.report) {
+            (Ok(report), Some(expected)) => {
+                let expected = poc_lora::LoraWitnessReportReqV1 {
+                    pub_key: vec![],
+                    data: STANDARD.decode(&expected.data).expect("golden data"),
+                    timestamp: expected.timestamp,
+                    tmst: expected.tmst,
+                    signal: expected.signal,
+                    snr: expected.snr,
+                    frequency: expected.frequency,
+                    datarate: ProtoDataRate::from_str(&expected.datarate)
+                        .expect("golden datarate")
+                        .into(),
+                    signature: vec![],
+                };
+                assert_eq!(
+                    expected.encode_to_vec(),
+                    report.encode_to_vec(),
+                    "{name}: {report:?}"
+                );
+            }
+            (Err(err), None) => assert_eq!(
+                golden.rejected.as_deref(),
+                Some(reject_reason(&err)),
+                "{name}: {err:?}"
+            ),
+            (result, _) => panic!("{name}: unexpected {result:?}"),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Receipts of submitted PoC reports.
+//!
+//! When rewards are missing it matters whether a report never left the
+//! gateway, was submitted, or was taken in by the ingest service. The ingest
+//! service answers a submitted beacon or witness report with an id when it
+//! accepts it. The most recent submissions are kept in the data directory
+//! with that id, so `info receipts` and `GET /v1/poc/receipts` can tell a
+//! report that was only submitted (no id) from one that was accepted. Reports
+//! are identified by the base64 beacon data, the same id as in the beacon
+//! status and the logs.
+
+use crate::{clock::unix_now, status_file, Result};
+use serde::{Deserialize, Serialize};
+use slog::{debug, Logger};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const RECEIPTS_FILE: &str = "receipts.json";
+/// The number of receipts kept
+const MAX_RECEIPTS: usize = 100;
+
+/// The kind of a submitted report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportKind {
+    Beacon,
+    Witness,
+}
+
+/// The receipt of a submitted report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub kind: ReportKind,
+    /// The base64 data of the beacon the report is about
+    pub beacon: String,
+    /// Unix time (seconds) the report was submitted
+    pub submitted: u64,
+    /// The id the ingest service accepted the report with, None when it did
+    /// not return one
+    pub id: Option<String>,
+    /// Whether the ingest service acknowledged the report with an id
+    pub accepted: bool,
+}
+
+impl Receipt {
+    pub fn new(kind: ReportKind, beacon: String, id: String, submitted: u64) -> Self {
+        let id = (!id.is_empty()).then_some(id);
+        Self {
+            kind,
+            beacon,
+            submitted,
+            accepted: id.is_some(),
+            id,
+        }
+    }
+}
+
+/// The most recent receipts, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipts(VecDeque<Receipt>);
+
+impl Receipts {
+    /// Load the stored receipts. Returns None when no report was submitted
+    /// yet.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, RECEIPTS_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, RECEIPTS_FILE, self)
+    }
+
+    fn push(&mut self, receipt: Receipt) {
+        if self.0.len() == MAX_RECEIPTS {
+            self.0.pop_front();
+        }
+        self.0.push_back(receipt);
+    }
+
+    /// The receipt of the last report about the given beacon
+    pub fn find(&self, kind: ReportKind, beacon: &str) -> Option<&Receipt> {
+        self.0
+            .iter()
+            .rev()
+            .find(|receipt| receipt.kind == kind && receipt.beacon == beacon)
+    }
+}
+
+#[derive(Debug)]
+struct Store {
+    data_dir: PathBuf,
+    receipts: Receipts,
+}
+
+/// Keeps the receipts of submitted reports. Receipts are only recorded once
+/// started.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiptsHandle(Arc<Mutex<Option<Store>>>);
+
+impl ReceiptsHandle {
+    /// Starts keeping receipts with the ones stored before
+    pub fn start(&self, data_dir: &Path) -> Result {
+        let receipts = Receipts::load(data_dir)?.unwrap_or_default();
+        *self.0.lock().expect("receipts lock") = Some(Store {
+            data_dir: data_dir.to_path_buf(),
+            receipts,
+        });
+        Ok(())
+    }
+
+    /// Records the response of the ingest service to a submitted report
+    pub fn record(&self, logger: &Logger, kind: ReportKind, beacon: &str, id: String) {
+        let submitted = unix_now();
+        let mut store = self.0.lock().expect("receipts lock");
+        let Some(store) = store.as_mut() else {
+            return;
+        };
+        store
+            .receipts
+            .push(Receipt::new(kind, beacon.to_string(), id, submitted));
+        if let Err(err) = store.receipts.save(&store.data_dir) {
+            debug!(logger, "failed to store report receipts: {err:?}");
+        }
+    }
+
+    /// Returns the current receipts
+    pub fn snapshot(&self) -> Receipts {
+        self.0
+            .lock()
+            .expect("receipts lock")
+            .as_ref()
+            .map(|store| store.receipts.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn receipts() {
+        let mut receipts = Receipts::default();
+        for n in 0..=MAX_RECEIPTS {
+            receipts.push(Receipt::new(
+                ReportKind::Witness,
+                format!("beacon{n}"),
+                String::new(),
+                n as u64,
+            ));
+        }
+        assert_eq!(MAX_RECEIPTS, receipts.0.len());
+        assert!(receipts.find(ReportKind::Witness, "beacon0").is_none());
+        let submitted = receipts
+            .find(ReportKind::Witness, "beacon1")
+            .expect("receipt");
+        assert!(!submitted.accepted);
+        assert_eq!(None, submitted.id);
+
+        receipts.push(Receipt::new(
+            ReportKind::Witness,
+            "beacon1".to_string(),
+            "id1".to_string(),
+            200,
+        ));
+        let accepted = receipts
+            .find(ReportKind::Witness, "beacon1")
+            .expect("receipt");
+        assert!(accepted.accepted);
+        assert_eq!(Some("id1"), accepted.id.as_deref());
+        assert!(receipts.find(ReportKind::Beacon, "beacon1").is_none());
+    }
+}
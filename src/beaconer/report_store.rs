@@ -0,0 +1,374 @@
+//! Deferred submission of PoC reports.
+//!
+//! Gateways with intermittent backhaul, like solar powered sites that sync
+//! once a day, can not submit beacon and witness reports when they are made.
+//! In deferred mode reports are still signed right away, so they carry the
+//! time they were observed, but are stored in the data directory and submitted
+//! in bulk whenever the ingest service can be reached.
+//!
+//! Reports are submitted oldest first and removed as soon as they are
+//! accepted so they are never replayed. Reports older than the configured
+//! maximum age, or rejected by the ingest service as invalid, are discarded
+//! since submitting them again can not succeed. Reports that fail to submit
+//! for any other reason, like an unreachable or overloaded ingest service,
+//! are kept and submitted again on the next attempt.
+
+use crate::{
+    beaconer::receipts::ReportKind,
+    clock::unix_time,
+    error::{DecodeError, ServiceError},
+    metrics, region_watcher,
+    server::{milestones::Milestone, Handles},
+    service::{poc::PocIotService, until_shutdown},
+    settings::IngestUris,
+    Base64, Error, Result, Settings,
+};
+use helium_proto::{
+    services::poc_lora::{LoraBeaconReportReqV1, LoraWitnessReportReqV1},
+    Message,
+};
+use slog::{info, o, warn, Logger};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time;
+use tonic::Code;
+use xxhash_rust::xxh64::xxh64;
+
+const REPORTS_DIR: &str = "reports";
+const REPORT_EXTENSION: &str = "pb";
+
+/// Gauge of the number of stored deferred reports
+pub const STORED_METRIC: &str = "poc_reports_deferred";
+/// Counter of deferred reports accepted by the ingest service
+pub const SUBMITTED_METRIC: &str = "poc_reports_deferred_submitted_total";
+/// Counter of discarded deferred reports labeled with the `reason`
+pub const DISCARDED_METRIC: &str = "poc_reports_deferred_discarded_total";
+
+/// A signed report waiting for submission
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    Beacon(LoraBeaconReportReqV1),
+    Witness(LoraWitnessReportReqV1),
+}
+
+impl Report {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Beacon(_) => "beacon",
+            Self::Witness(_) => "witness",
+        }
+    }
+
+    fn receipt_kind(&self) -> ReportKind {
+        match self {
+            Self::Beacon(_) => ReportKind::Beacon,
+            Self::Witness(_) => ReportKind::Witness,
+        }
+    }
+
+    /// The base64 data of the beacon the report is about
+    fn beacon(&self) -> String {
+        match self {
+            Self::Beacon(report) => report.data.to_b64(),
+            Self::Witness(report) => report.data.to_b64(),
+        }
+    }
+
+    /// Time the report was made in nanoseconds since the unix epoch
+    fn timestamp(&self) -> u64 {
+        match self {
+            Self::Beacon(report) => report.timestamp,
+            Self::Witness(report) => report.timestamp,
+        }
+    }
+
+    fn signature(&self) -> &[u8] {
+        match self {
+            Self::Beacon(report) => &report.signature,
+            Self::Witness(report) => &report.signature,
+        }
+    }
+
+    /// The file name of a stored report. File names sort by report time and
+    /// storing the same report twice results in the same name.
+    fn file_name(&self) -> String {
+        format!(
+            "{:020}-{}-{:016x}.{REPORT_EXTENSION}",
+            self.timestamp(),
+            self.kind(),
+            xxh64(self.signature(), 0)
+        )
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Beacon(report) => report.encode_to_vec(),
+            Self::Witness(report) => report.encode_to_vec(),
+        }
+    }
+
+    fn decode(file_name: &str, data: &[u8]) -> Result<Self> {
+        match file_name.split('-').nth(1) {
+            Some("beacon") => Ok(Self::Beacon(LoraBeaconReportReqV1::decode(data)?)),
+            Some("witness") => Ok(Self::Witness(LoraWitnessReportReqV1::decode(data)?)),
+            _ => Err(DecodeError::unknown_report_kind(file_name)),
+        }
+    }
+
+    fn is_expired(&self, now: Duration, max_age: Duration) -> bool {
+        now.saturating_sub(Duration::from_nanos(self.timestamp())) > max_age
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportStore {
+    dir: PathBuf,
+    ingest_uris: IngestUris,
+    /// Reports are submitted to the ingest URL of the current region
+    region_watch: region_watcher::MessageReceiver,
+    max_age: Duration,
+    max_reports: usize,
+    interval: Duration,
+    handles: Handles,
+}
+
+impl ReportStore {
+    /// Returns the report store when deferred submission is enabled
+    pub fn new(
+        settings: &Settings,
+        region_watch: region_watcher::MessageReceiver,
+        handles: &Handles,
+    ) -> Option<Self> {
+        settings.poc.deferred.then(|| Self {
+            dir: settings.data_dir.join(REPORTS_DIR),
+            ingest_uris: settings.poc.ingest_uris(),
+            region_watch,
+            max_age: Duration::from_secs(settings.poc.deferred_max_age),
+            max_reports: settings.poc.deferred_max_reports,
+            interval: Duration::from_secs(settings.poc.deferred_interval),
+            handles: handles.clone(),
+        })
+    }
+
+    /// Stores a signed report, discarding the oldest stored reports when
+    /// there are more than the maximum number of reports
+    pub fn push(&self, report: &Report) -> Result {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(report.file_name()), report.encode())?;
+        let pending = self.pending()?;
+        let overflow = pending.len().saturating_sub(self.max_reports);
+        for path in &pending[..overflow] {
+            discard(path, "overflow");
+        }
+        metrics::set(STORED_METRIC, (pending.len() - overflow) as f64);
+        Ok(())
+    }
+
+    /// Paths of the stored reports, oldest first
+    pub fn pending(&self) -> Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == REPORT_EXTENSION))
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Submits stored reports until they are all submitted or the ingest
+    /// service can not be reached. Returns the number of submitted reports.
+    pub async fn flush(&self, logger: &Logger) -> Result<usize> {
+        let region = self.region_watch.borrow().region;
+        let mut service =
+            PocIotService::new(self.ingest_uris.for_region(&region).clone(), &self.handles);
+        let mut submitted = 0;
+        let mut result = Ok(());
+        for path in self.pending()? {
+            let report = match load(&path) {
+                Ok(report) => report,
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "discarding unreadable report {}: {err:?}",
+                        path.display()
+                    );
+                    discard(&path, "invalid");
+                    continue;
+                }
+            };
+            if report.is_expired(unix_time(), self.max_age) {
+                info!(logger, "discarding expired {} report", report.kind();
+                    "timestamp" => report.timestamp());
+                discard(&path, "expired");
+                continue;
+            }
+            let submit = match &report {
+                Report::Beacon(report) => service.submit_beacon(report.clone()).await,
+                Report::Witness(report) => service.submit_witness(report.clone()).await,
+            };
+            match submit {
+                Ok(id) => {
+                    let _ = fs::remove_file(&path);
+                    metrics::increment(SUBMITTED_METRIC);
+                    self.handles.receipts.record(
+                        logger,
+                        report.receipt_kind(),
+                        &report.beacon(),
+                        id,
+                    );
+                    if matches!(report, Report::Witness(_)) {
+                        self.handles
+                            .milestones
+                            .reach(logger, Milestone::WitnessReported);
+                    }
+                    submitted += 1;
+                }
+                Err(err) if is_rejected(&err) => {
+                    warn!(logger, "discarding rejected {} report: {err:?}", report.kind();
+                        "timestamp" => report.timestamp());
+                    discard(&path, "rejected");
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        if let Ok(pending) = self.pending() {
+            metrics::set(STORED_METRIC, pending.len() as f64);
+        }
+        if submitted > 0 {
+            info!(logger, "submitted deferred reports"; "submitted" => submitted);
+        }
+        result.map(|_| submitted)
+    }
+
+    pub async fn run(&self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("task" => "deferred"));
+        let pending = self.pending().unwrap_or_else(|err| {
+            warn!(logger, "failed to read deferred reports: {err:?}");
+            vec![]
+        });
+        info!(logger, "starting";
+            "interval" => self.interval.as_secs(),
+            "max_age" => self.max_age.as_secs(),
+            "pending" => pending.len());
+        let mut timer = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = timer.tick() => {
+                    let flush = until_shutdown(shutdown, self.flush(&logger)).await;
+                    if let Some(Err(err)) = flush {
+                        info!(logger, "deferred reports not submitted: {err:?}"; "code" => err.code());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Report> {
+    let data = fs::read(path)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    Report::decode(&file_name, &data)
+}
+
+/// Whether the ingest service refused the report itself, as opposed to a
+/// failure to deliver it which is worth retrying
+fn is_rejected(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Service(ServiceError::Rpc(status)) if matches!(
+            status.code(),
+            Code::InvalidArgument | Code::PermissionDenied | Code::FailedPrecondition
+        )
+    )
+}
+
+fn discard(path: &Path, reason: &str) {
+    let _ = fs::remove_file(path);
+    metrics::increment(&metrics::labeled(DISCARDED_METRIC, &[("reason", reason)]));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Region, RegionParams};
+    use http::Uri;
+    use tokio::sync::watch;
+
+    fn witness(timestamp: u64) -> Report {
+        Report::Witness(LoraWitnessReportReqV1 {
+            timestamp,
+            data: vec![1, 2, 3],
+            signature: timestamp.to_le_bytes().to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn store() {
+        let dir = std::env::temp_dir().join(format!("report_store_{}", std::process::id()));
+        let store = ReportStore {
+            dir: dir.clone(),
+            ingest_uris: Uri::from_static("http://localhost:9080").into(),
+            region_watch: watch::channel(RegionParams::from(Region::from(
+                helium_proto::Region::Eu868,
+            )))
+            .1,
+            max_age: Duration::from_secs(3600),
+            max_reports: 2,
+            interval: Duration::from_secs(300),
+            handles: Handles::default(),
+        };
+        assert!(store.pending().expect("pending").is_empty());
+
+        for timestamp in [3_000, 1_000, 2_000] {
+            store.push(&witness(timestamp)).expect("push");
+        }
+        // Storing a report again does not duplicate it
+        store.push(&witness(3_000)).expect("push");
+        let pending = store.pending().expect("pending");
+        assert_eq!(2, pending.len());
+        // The oldest report was discarded
+        assert_eq!(witness(2_000), load(&pending[0]).expect("report"));
+        assert_eq!(witness(3_000), load(&pending[1]).expect("report"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejections() {
+        let rpc = |code| Error::from(ServiceError::Rpc(tonic::Status::new(code, "test")));
+        assert!(is_rejected(&rpc(Code::InvalidArgument)));
+        assert!(is_rejected(&rpc(Code::PermissionDenied)));
+        assert!(!is_rejected(&rpc(Code::Unavailable)));
+        assert!(!is_rejected(&rpc(Code::Internal)));
+        assert!(!is_rejected(&rpc(Code::Unknown)));
+        assert!(!is_rejected(&Error::from(ServiceError::Timeout)));
+    }
+
+    #[test]
+    fn expiry() {
+        let max_age = Duration::from_secs(3600);
+        let now = Duration::from_secs(1_700_000_000);
+        let made = |secs_ago: u64| witness((now - Duration::from_secs(secs_ago)).as_nanos() as u64);
+        assert!(!made(60).is_expired(now, max_age));
+        assert!(made(3601).is_expired(now, max_age));
+        // Reports made with a clock ahead of the current one are kept
+        assert!(!witness((now + max_age).as_nanos() as u64).is_expired(now, max_age));
+    }
+}
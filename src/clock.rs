@@ -0,0 +1,223 @@
+//! Tracks the clocks of the packet forwarder relative to the host.
+//!
+//! The Semtech UDP protocol has no message to set the time of a packet
+//! forwarder, so the gateway can not push its time to the concentrator.
+//! Instead the concentrator counter (`tmst`) rate and the forwarder UTC time
+//! are compared against the host clocks so drift is visible in the logs and
+//! metrics before it shows up as bad witness timing.
+//!
+//! The estimated counter drift corrects the counter values the gateway
+//! computes from host time itself: the counter estimate used to decide
+//! whether an RX1 window can still be reached, and the counter of GPS
+//! aligned beacons, which are scheduled up to minutes after their timing
+//! reference. Estimates beyond `MAX_CORRECTION_PPM` are more likely a
+//! measurement artifact than a real crystal and are not applied.
+
+use crate::metrics;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Gauge with the estimated concentrator counter drift in parts per million
+pub const TMST_DRIFT_METRIC: &str = "forwarder_tmst_drift_ppm";
+/// Gauge with the offset of the forwarder UTC time from the host in seconds
+pub const UTC_OFFSET_METRIC: &str = "forwarder_utc_offset_seconds";
+
+/// The minimum host time between tmst samples to estimate drift over
+const MIN_DRIFT_WINDOW: Duration = Duration::from_secs(60);
+/// A counter that deviates more than this from the host clock between two
+/// samples is assumed to have been reset (forwarder restart)
+const MAX_SAMPLE_DEVIATION_US: i64 = 1_000_000;
+/// The largest drift estimate applied as a correction
+const MAX_CORRECTION_PPM: f64 = 200.0;
+
+/// The host time since the unix epoch, zero for a host clock before it
+pub fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// The host unix time in seconds
+pub fn unix_now() -> u64 {
+    unix_time().as_secs()
+}
+
+/// The host unix time in milliseconds
+pub fn unix_now_millis() -> u64 {
+    unix_time().as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+pub struct ForwarderClock {
+    /// The first tmst sample of the current drift window
+    reference: Option<(u32, Instant)>,
+    /// The last tmst sample
+    last: Option<(u32, Instant)>,
+    /// Counter microseconds elapsed since the reference sample, unwrapped
+    elapsed_us: u64,
+    drift_ppm: Option<f64>,
+    utc_offset: Option<f64>,
+}
+
+impl ForwarderClock {
+    /// Records a concentrator counter value seen at the given host instant.
+    /// Returns the updated drift estimate when one is available.
+    pub fn observe_tmst(&mut self, tmst: u32, now: Instant) -> Option<f64> {
+        let (last_tmst, last_now) = match self.last {
+            Some(last) => last,
+            None => {
+                self.reset(tmst, now);
+                return self.drift_ppm;
+            }
+        };
+        let counter_delta = tmst.wrapping_sub(last_tmst) as i64;
+        let host_delta = now.saturating_duration_since(last_now).as_micros() as i64;
+        if (counter_delta - host_delta).abs() > MAX_SAMPLE_DEVIATION_US {
+            self.reset(tmst, now);
+            return self.drift_ppm;
+        }
+        self.elapsed_us += counter_delta as u64;
+        self.last = Some((tmst, now));
+
+        if let Some((_, reference_now)) = self.reference {
+            let host_elapsed = now.saturating_duration_since(reference_now);
+            if host_elapsed >= MIN_DRIFT_WINDOW {
+                let host_us = host_elapsed.as_micros() as f64;
+                let drift = (self.elapsed_us as f64 - host_us) / host_us * 1_000_000.0;
+                self.drift_ppm = Some(drift);
+                metrics::set(TMST_DRIFT_METRIC, drift);
+            }
+        }
+        self.drift_ppm
+    }
+
+    /// Records a forwarder reported UTC time (ISO 8601) seen at the given
+    /// host system time. Returns the offset of the forwarder clock in seconds.
+    pub fn observe_utc(&mut self, time: &str, now: SystemTime) -> Option<f64> {
+        let forwarder = parse_utc_seconds(time)?;
+        let host = now.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+        let offset = forwarder - host;
+        self.utc_offset = Some(offset);
+        metrics::set(UTC_OFFSET_METRIC, offset);
+        Some(offset)
+    }
+
+    /// Estimates the concentrator counter at the given host instant from the
+    /// last sample. The estimate trails the real counter by the uplink
+    /// latency from the forwarder, so it errs on the side of more time left.
+    pub fn estimate_tmst(&self, now: Instant) -> Option<u32> {
+        let (tmst, sampled) = self.last?;
+        let elapsed = self.counter_micros(now.saturating_duration_since(sampled));
+        Some(tmst.wrapping_add(elapsed as u32))
+    }
+
+    /// Converts a host duration to concentrator counter microseconds,
+    /// corrected by the drift estimate when there is a plausible one
+    pub fn counter_micros(&self, elapsed: Duration) -> u64 {
+        correct_micros(elapsed.as_micros() as u64, self.drift_ppm)
+    }
+
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.drift_ppm
+    }
+
+    pub fn utc_offset(&self) -> Option<f64> {
+        self.utc_offset
+    }
+
+    fn reset(&mut self, tmst: u32, now: Instant) {
+        self.reference = Some((tmst, now));
+        self.last = Some((tmst, now));
+        self.elapsed_us = 0;
+    }
+}
+
+/// Scales a number of microseconds of true time to the counter microseconds
+/// elapsed in that time with the given drift
+pub fn correct_micros(micros: u64, drift_ppm: Option<f64>) -> u64 {
+    match drift_ppm.filter(|drift| drift.abs() <= MAX_CORRECTION_PPM) {
+        Some(drift) => (micros as f64 * (1.0 + drift / 1_000_000.0)).round() as u64,
+        None => micros,
+    }
+}
+
+/// Parses an ISO 8601 UTC time as reported by packet forwarders, for example
+/// "2013-03-31T16:21:17.528002Z", into (fractional) seconds since the unix
+/// epoch.
+pub fn parse_utc_seconds(time: &str) -> Option<f64> {
+    let time = time.trim_end_matches('Z');
+    let (date, time) = time.split_once('T')?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    let mut time_parts = time.splitn(3, ':');
+    let hour: u32 = time_parts.next()?.parse().ok()?;
+    let minute: u32 = time_parts.next()?.parse().ok()?;
+    let seconds: f64 = time_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    Some((days * 86_400 + hour as i64 * 3600 + minute as i64 * 60) as f64 + seconds)
+}
+
+/// Days since the unix epoch for a proleptic Gregorian calendar date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn utc_parse() {
+        assert_eq!(Some(0.0), parse_utc_seconds("1970-01-01T00:00:00Z"));
+        assert_eq!(
+            Some(1_364_746_877.528002),
+            parse_utc_seconds("2013-03-31T16:21:17.528002Z")
+        );
+        assert_eq!(None, parse_utc_seconds("2013-13-31T16:21:17Z"));
+        assert_eq!(None, parse_utc_seconds("garbage"));
+    }
+
+    #[test]
+    fn tmst_drift() {
+        let start = Instant::now();
+        let mut clock = ForwarderClock::default();
+        assert_eq!(None, clock.observe_tmst(u32::MAX - 10, start));
+        // 100 ppm fast over two minutes, across a counter wrap
+        let host = Duration::from_secs(120);
+        let counter = host.as_micros() as u32 + 12_000;
+        let drift = clock
+            .observe_tmst((u32::MAX - 10).wrapping_add(counter), start + host)
+            .expect("drift");
+        assert!((drift - 100.0).abs() < 0.01);
+        // A counter reset restarts the window but keeps the last estimate
+        assert_eq!(Some(drift), clock.observe_tmst(5, start + host * 2));
+        // The estimate runs at the drift of the counter
+        assert_eq!(
+            Some(1_005),
+            clock.estimate_tmst(start + host * 2 + Duration::from_millis(1))
+        );
+        assert_eq!(
+            Some(10_001_005),
+            clock.estimate_tmst(start + host * 2 + Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn drift_correction() {
+        assert_eq!(1_000_100, correct_micros(1_000_000, Some(100.0)));
+        assert_eq!(999_950, correct_micros(1_000_000, Some(-50.0)));
+        assert_eq!(1_000_000, correct_micros(1_000_000, None));
+        // Implausible estimates are not applied
+        assert_eq!(1_000_000, correct_micros(1_000_000, Some(5_000.0)));
+    }
+}
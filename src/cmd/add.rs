@@ -23,7 +23,9 @@ pub struct Cmd {
 
 impl Cmd {
     pub async fn run(&self, settings: Settings) -> Result {
-        let mut client = LocalClient::new(settings.api).await?;
+        let mut client = LocalClient::new(settings.api)
+            .await?
+            .with_keypair(settings.keypair.clone());
 
         let txn = client
             .add_gateway(&self.owner, &self.payer, &self.mode)
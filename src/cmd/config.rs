@@ -0,0 +1,47 @@
+use crate::{cmd::*, settings::ConfigEntry, Result, Settings};
+
+/// Commands on the gateway configuration
+#[derive(Debug, clap::Args)]
+pub struct Cmd {
+    #[command(subcommand)]
+    command: ConfigCmd,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ConfigCmd {
+    Show(Show),
+}
+
+/// Show the effective configuration and where each value came from (default,
+/// profile, file, include or env). Passwords and trusted keys are redacted.
+#[derive(Debug, clap::Args)]
+pub struct Show {
+    /// Only show values that differ from the built-in defaults
+    #[arg(long)]
+    diff_defaults: bool,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        self.command.run(settings).await
+    }
+}
+
+impl ConfigCmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        match self {
+            Self::Show(cmd) => cmd.run(settings).await,
+        }
+    }
+}
+
+impl Show {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let entries: Vec<_> = Settings::provenance(&settings.config_path)?
+            .into_iter()
+            .filter(|entry| !(self.diff_defaults && entry.is_default()))
+            .map(ConfigEntry::redacted)
+            .collect();
+        print_json(&entries)
+    }
+}
@@ -0,0 +1,36 @@
+use crate::{clock::unix_now, cmd::rest_addr, Error, Result, Settings};
+use http::Uri;
+use hyper::Client;
+use std::path::PathBuf;
+
+/// Save a diagnostics bundle of the running gateway.
+///
+/// The bundle is a tar.gz with the buffered logs and errors, gateway info,
+/// statistics, the settings with secrets redacted and the region parameters,
+/// to attach to support tickets. It is produced by the running gateway through
+/// the REST API, so `rest.listen` must be set in the settings.
+#[derive(Debug, clap::Args)]
+pub struct Cmd {
+    /// The file to write the bundle to, defaults to a timestamped file in the
+    /// current directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let uri: Uri = format!("http://{}/v1/diagnostics", rest_addr(&settings)?).parse()?;
+        let resp = Client::new().get(uri.clone()).await?;
+        if !resp.status().is_success() {
+            return Err(Error::custom(format!("GET {uri}: {}", resp.status())));
+        }
+        let bundle = hyper::body::to_bytes(resp.into_body()).await?;
+        let output = self.output.clone().unwrap_or_else(|| {
+            let now = unix_now();
+            PathBuf::from(format!("helium_gateway_diagnostics_{now}.tar.gz"))
+        });
+        std::fs::write(&output, &bundle)?;
+        println!("{}", output.display());
+        Ok(())
+    }
+}
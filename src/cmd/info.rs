@@ -1,15 +1,25 @@
 use crate::{
     api::LocalClient,
+    beaconer::{receipts::Receipts, BeaconStatus},
     cmd::*,
+    gateway::rf_health::RfHealth,
+    keypair::{health::KeyHealth, KeyProbe},
+    server::{boot::BootState, milestones::Milestones, startup::StartupRecord},
+    service::{
+        data_usage::{UsageHistory, UsageSnapshot},
+        modem::ModemStatus,
+    },
     settings::{self, Settings},
     Region, Result,
 };
 use angry_purple_tiger::AnimalName;
 use helium_crypto::PublicKey;
-
 use serde_json::json;
 use std::collections::HashMap;
 use std::fmt;
+#[cfg(feature = "rest")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, clap::ValueEnum, PartialOrd, Ord, Copy, PartialEq, Eq)]
 pub enum InfoKey {
@@ -18,6 +28,17 @@ pub enum InfoKey {
     Onboarding,
     Name,
     Region,
+    Boot,
+    Modem,
+    Usage,
+    Probe,
+    Beacon,
+    KeyHealth,
+    RfHealth,
+    Startup,
+    Listen,
+    Milestones,
+    Receipts,
 }
 
 /// Info command. Retrieve all or a subset of information from the running
@@ -31,7 +52,7 @@ pub struct Cmd {
 
 impl Cmd {
     pub async fn run(&self, settings: Settings) -> Result {
-        let mut info_cache = InfoCache::new(settings.api);
+        let mut info_cache = InfoCache::new(&settings);
         let mut info: HashMap<String, serde_json::Value> = HashMap::new();
         for key in &self.keys {
             info.insert(key.to_string(), key.to_status(&mut info_cache).await?);
@@ -48,20 +69,37 @@ impl fmt::Display for InfoKey {
             Self::Onboarding => "onboarding",
             Self::Name => "name",
             Self::Region => "region",
+            Self::Boot => "boot",
+            Self::Modem => "modem",
+            Self::Usage => "usage",
+            Self::Probe => "probe",
+            Self::Beacon => "beacon",
+            Self::KeyHealth => "key_health",
+            Self::RfHealth => "rf_health",
+            Self::Startup => "startup",
+            Self::Listen => "listen",
+            Self::Milestones => "milestones",
+            Self::Receipts => "receipts",
         };
         f.write_str(s)
     }
 }
 struct InfoCache {
     port: u16,
+    #[cfg(feature = "rest")]
+    rest_addr: Option<SocketAddr>,
+    data_dir: PathBuf,
     public_keys: Option<(PublicKey, PublicKey)>,
     region: Option<Region>,
 }
 
 impl InfoCache {
-    fn new(port: u16) -> Self {
+    fn new(settings: &Settings) -> Self {
         Self {
-            port,
+            port: settings.api,
+            #[cfg(feature = "rest")]
+            rest_addr: rest_addr(settings).ok(),
+            data_dir: settings.data_dir.clone(),
             public_keys: None,
             region: None,
         }
@@ -96,6 +134,44 @@ impl InfoCache {
         self.region = Some(region);
         Ok(region)
     }
+
+    /// The live usage counters from the REST API when it is enabled, the
+    /// last saved totals otherwise
+    async fn usage(&self) -> Result<UsageSnapshot> {
+        #[cfg(feature = "rest")]
+        if let Some(addr) = self.rest_addr {
+            return rest_get(addr, "/v1/data_usage").await;
+        }
+        Ok(UsageSnapshot::new(
+            UsageHistory::load(&self.data_dir)?,
+            None,
+        ))
+    }
+
+    /// The live beacon status from the REST API when it is enabled, the
+    /// stored last beacon otherwise
+    async fn beacon(&self) -> Result<Option<BeaconStatus>> {
+        #[cfg(feature = "rest")]
+        if let Some(addr) = self.rest_addr {
+            return rest_get(addr, "/v1/beacon").await.map(Some);
+        }
+        BeaconStatus::load(&self.data_dir)
+    }
+}
+
+#[cfg(feature = "rest")]
+async fn rest_get<T: serde::de::DeserializeOwned>(addr: SocketAddr, path: &str) -> Result<T> {
+    use crate::Error;
+    use http::Uri;
+    use hyper::Client;
+
+    let uri: Uri = format!("http://{addr}{path}").parse()?;
+    let resp = Client::new().get(uri.clone()).await?;
+    if !resp.status().is_success() {
+        return Err(Error::custom(format!("GET {uri}: {}", resp.status())));
+    }
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
 }
 
 impl InfoKey {
@@ -119,6 +195,26 @@ impl InfoKey {
             Self::Region => {
                 json!(cache.region().await?.to_string())
             }
+            Self::Boot => {
+                let boot_state = BootState::load(&cache.data_dir)?;
+                json!({
+                    "boot_count": boot_state.boot_count,
+                    "crash_count": boot_state.crash_count,
+                    "last_crash": boot_state.last_crash,
+                })
+            }
+            Self::Modem => json!(ModemStatus::load(&cache.data_dir)?),
+            Self::Probe => json!(KeyProbe::load(&cache.data_dir)?),
+            Self::Beacon => json!(cache.beacon().await?),
+            Self::KeyHealth => json!(KeyHealth::load(&cache.data_dir)?),
+            Self::RfHealth => json!(RfHealth::load(&cache.data_dir)?),
+            Self::Startup => json!(StartupRecord::load(&cache.data_dir)?),
+            Self::Listen => {
+                json!(StartupRecord::load(&cache.data_dir)?.map(|record| record.listen))
+            }
+            Self::Milestones => json!(Milestones::load(&cache.data_dir)?),
+            Self::Receipts => json!(Receipts::load(&cache.data_dir)?),
+            Self::Usage => json!(cache.usage().await?),
         };
         Ok(v)
     }
@@ -0,0 +1,82 @@
+use crate::{
+    cmd::rest_addr,
+    logging::buffer::{LogFilter, LogLine},
+    Error, Result, Settings,
+};
+use http::Uri;
+use hyper::{body::HttpBody, Client};
+
+/// Show the recent log lines of the running gateway.
+///
+/// The log is read through the REST API, so `rest.listen` must be set in the
+/// settings. Only lines at or above the configured log level are available.
+#[derive(Debug, clap::Args)]
+pub struct Cmd {
+    /// Keep printing log lines as they are logged
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Only show lines of a module, like "router" or "beacon"
+    #[arg(long)]
+    module: Option<String>,
+
+    /// Only show lines at or above a level, like "warn" or "debug"
+    #[arg(long)]
+    level: Option<String>,
+
+    /// Print the log lines as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+impl Cmd {
+    pub async fn run(&self, shutdown: &triggered::Listener, settings: Settings) -> Result {
+        let uri = self.logs_uri(&settings)?;
+        let resp = Client::new().get(uri.clone()).await?;
+        if !resp.status().is_success() {
+            return Err(Error::custom(format!("GET {uri}: {}", resp.status())));
+        }
+        let mut body = resp.into_body();
+        let mut pending: Vec<u8> = vec![];
+        loop {
+            let chunk = tokio::select! {
+                _ = shutdown.clone() => return Ok(()),
+                chunk = body.data() => match chunk {
+                    Some(chunk) => chunk?,
+                    None => return Ok(()),
+                },
+            };
+            pending.extend_from_slice(&chunk);
+            while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                self.print_line(&line[..end])?;
+            }
+        }
+    }
+
+    fn logs_uri(&self, settings: &Settings) -> Result<Uri> {
+        let addr = rest_addr(settings)?;
+        let filter = LogFilter {
+            level: self.level.clone(),
+            module: self.module.clone(),
+        };
+        let mut query = serde_urlencoded::to_string(filter).map_err(Error::custom)?;
+        if self.follow {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str("follow=true");
+        }
+        Ok(format!("http://{addr}/v1/logs?{query}").parse()?)
+    }
+
+    fn print_line(&self, line: &[u8]) -> Result {
+        if self.json {
+            println!("{}", String::from_utf8_lossy(line));
+            return Ok(());
+        }
+        let line: LogLine = serde_json::from_slice(line)?;
+        println!("{line}");
+        Ok(())
+    }
+}
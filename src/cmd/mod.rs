@@ -1,11 +1,42 @@
 pub mod add;
+pub mod config;
+#[cfg(feature = "rest")]
+pub mod diagnostics;
 pub mod info;
 pub mod key;
+#[cfg(feature = "rest")]
+pub mod log;
+pub mod selftest;
 pub mod server;
+#[cfg(feature = "rest")]
+pub mod service_stats;
 
 use crate::Result;
+#[cfg(feature = "rest")]
+use crate::{Error, Settings};
+#[cfg(feature = "rest")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub(crate) fn print_json<T: ?Sized + serde::Serialize>(value: &T) -> Result {
     println!("{}", serde_json::to_string_pretty(value)?);
     Ok(())
 }
+
+/// The address the REST API of the running gateway is reached at
+#[cfg(feature = "rest")]
+pub(crate) fn rest_addr(settings: &Settings) -> Result<SocketAddr> {
+    let listen = settings
+        .rest
+        .listen
+        .as_ref()
+        .ok_or_else(|| Error::custom("the REST API is not enabled, set rest.listen"))?;
+    let mut addr: SocketAddr = listen.parse()?;
+    // A wildcard listen address is reached on the loopback interface
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(addr)
+}
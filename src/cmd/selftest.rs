@@ -0,0 +1,223 @@
+use crate::{
+    cmd::*,
+    gateway::{self, listen},
+    server::Handles,
+    service::{
+        config::{ConfigService, RequestNonces},
+        outbound, CONNECT_TIMEOUT,
+    },
+    Error, RegionParams, Result, Settings,
+};
+use helium_crypto::{Sign, Verify};
+use helium_proto::services::Endpoint;
+use lorawan::PHYPayload;
+use semtech_udp::{
+    pull_resp::Time,
+    server_runtime::{Error as SemtechError, Event, UdpRuntime},
+    tx_ack::Error as TxAckErr,
+};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Transmit power (dBm) used for the RF loopback test frame
+const SELFTEST_TX_POWER: u64 = 2;
+/// The start of the RF loopback test frame payload
+const SELFTEST_FRAME_MARKER: &[u8] = b"gateway selftest";
+
+/// Run a self test of the gateway and print a pass/fail report.
+///
+/// Checks keypair signing, the config service and router connectivity and,
+/// with `--rf`, transmits a low power test frame and verifies it is received
+/// back on another RF chain. The RF test needs the packet forwarder to be
+/// connected to this command, so the gateway service must not be running.
+///
+/// The router check only connects to the router, it does not register.
+#[derive(Debug, clap::Args)]
+pub struct Cmd {
+    /// Run the RF loopback test
+    #[arg(long)]
+    rf: bool,
+
+    /// Seconds to wait for the packet forwarder and the loopback frame
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    result: CheckResult,
+    detail: String,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CheckResult {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl Check {
+    fn from_result(name: &'static str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self {
+                name,
+                result: CheckResult::Pass,
+                detail,
+            },
+            Err(err) => Self {
+                name,
+                result: CheckResult::Fail,
+                detail: err.to_string(),
+            },
+        }
+    }
+
+    fn skip(name: &'static str, detail: &str) -> Self {
+        Self {
+            name,
+            result: CheckResult::Skip,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let mut checks = vec![Check::from_result("signing", check_signing(&settings))];
+
+        let region_params = fetch_region_params(&settings).await;
+        let rf_params = region_params.as_ref().ok().cloned();
+        checks.push(Check::from_result(
+            "config",
+            region_params.map(|params| format!("{} region parameters", params.params.len())),
+        ));
+        checks.push(Check::from_result(
+            "router_connect",
+            check_router_connect(&settings).await,
+        ));
+        checks.push(match (self.rf, rf_params) {
+            (false, _) => Check::skip("rf_loopback", "not requested"),
+            (true, None) => Check::skip("rf_loopback", "no region parameters"),
+            (true, Some(params)) => Check::from_result(
+                "rf_loopback",
+                check_rf_loopback(&settings, &params, Duration::from_secs(self.timeout)).await,
+            ),
+        });
+
+        let passed = checks.iter().all(|check| check.result != CheckResult::Fail);
+        print_json(&serde_json::json!({
+            "result": if passed { "pass" } else { "fail" },
+            "checks": checks,
+        }))?;
+        if passed {
+            Ok(())
+        } else {
+            Err(Error::custom("selftest failed"))
+        }
+    }
+}
+
+fn check_signing(settings: &Settings) -> Result<String> {
+    let msg = b"helium gateway selftest";
+    let signature = settings.keypair.sign(msg)?;
+    let public_key = settings.keypair.public_key();
+    public_key.verify(msg, &signature)?;
+    Ok(public_key.to_string())
+}
+
+async fn fetch_region_params(settings: &Settings) -> Result<RegionParams> {
+    // Nothing reads the request statistics of the selftest
+    let mut service = ConfigService::new(&settings.config, &Handles::default());
+    // The gateway service may be running and own the nonce file
+    let mut nonces = RequestNonces::read_only(&settings.data_dir);
+    time::timeout(
+        CONNECT_TIMEOUT,
+        service.region_params(settings.region, settings.keypair.clone(), &mut nonces),
+    )
+    .await
+    .map_err(|_| Error::custom("config service timeout"))?
+}
+
+/// Checks that a connection to the router can be made. This does not register
+/// with the router.
+async fn check_router_connect(settings: &Settings) -> Result<String> {
+    Endpoint::from(settings.router.uri.clone())
+        .connect_timeout(CONNECT_TIMEOUT)
+        .connect_with_connector(outbound::connector())
+        .await
+        .map_err(|err| Error::custom(format!("router connect failed: {err}")))?;
+    Ok(settings.router.uri.to_string())
+}
+
+/// Transmits a low power test frame and waits for it to be received back
+async fn check_rf_loopback(
+    settings: &Settings,
+    region_params: &RegionParams,
+    timeout: Duration,
+) -> Result<String> {
+    // The forwarder sends to the first listen address
+    let listen = listen::resolve(settings)?[0];
+    let mut runtime = UdpRuntime::new(listen).await.map_err(|err| {
+        Error::custom(format!(
+            "failed to listen on {listen} (is the gateway service running?): {err}"
+        ))
+    })?;
+    let deadline = Instant::now() + timeout;
+
+    let mac = loop {
+        match time::timeout_at(deadline, runtime.recv()).await {
+            Ok(Event::NewClient((mac, _addr))) => break mac,
+            Ok(_) => continue,
+            Err(_) => return Err(Error::custom("no packet forwarder connected")),
+        }
+    };
+
+    // A marked frame with a random tag, which no witness mistakes for a
+    // beacon and which tells our frame apart from ones of other gateways
+    let mut frame = SELFTEST_FRAME_MARKER.to_vec();
+    frame.extend(rand::random::<[u8; 8]>());
+    let frequency = region_params
+        .params
+        .first()
+        .map(|params| params.channel_frequency)
+        .ok_or_else(|| Error::custom("no region channels"))?;
+    let datarate = region_params.select_datarate(frame.len())?;
+    let payload: Vec<u8> = PHYPayload::proprietary(frame.as_slice()).try_into()?;
+    let txpk = gateway::frame_to_pull_resp(
+        &frame,
+        frequency,
+        &datarate,
+        SELFTEST_TX_POWER,
+        Time::immediate(),
+    )?;
+    match runtime
+        .prepare_downlink(txpk, mac)
+        .dispatch(Some(gateway::DOWNLINK_TIMEOUT))
+        .await
+    {
+        Ok(_) | Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => (),
+        Err(err) => return Err(Error::custom(format!("transmit failed: {err:?}"))),
+    }
+
+    loop {
+        match time::timeout_at(deadline, runtime.recv()).await {
+            Ok(Event::PacketReceived(rxpk, _)) if rxpk.get_data() == payload.as_slice() => {
+                return Ok(format!(
+                    "received at {} MHz, rssi {}",
+                    rxpk.get_frequency(),
+                    rxpk.get_signal_rssi()
+                        .unwrap_or_else(|| rxpk.get_channel_rssi())
+                ))
+            }
+            Ok(_) => continue,
+            Err(_) => {
+                return Err(Error::custom(
+                    "test frame not received (the concentrator may not support loopback)",
+                ))
+            }
+        }
+    }
+}
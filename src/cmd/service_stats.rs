@@ -0,0 +1,24 @@
+use crate::{cmd::*, Error, Result, Settings};
+use http::Uri;
+use hyper::Client;
+
+/// Show request counts and latency histograms per upstream service of the
+/// running gateway.
+///
+/// The stats are read through the REST API, so `rest.listen` must be set in
+/// the settings.
+#[derive(Debug, clap::Args)]
+pub struct Cmd {}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings) -> Result {
+        let uri: Uri = format!("http://{}/v1/services/stats", rest_addr(&settings)?).parse()?;
+        let resp = Client::new().get(uri.clone()).await?;
+        if !resp.status().is_success() {
+            return Err(Error::custom(format!("GET {uri}: {}", resp.status())));
+        }
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        let stats: serde_json::Value = serde_json::from_slice(&body)?;
+        print_json(&stats)
+    }
+}
@@ -0,0 +1,172 @@
+//! Router-only mode for embedding the gateway as a library.
+//!
+//! Vendors with their own radio drivers can reuse the Helium uplink path
+//! without the Semtech UDP packet forwarder listener. [`RouterOnly`] runs only
+//! the region watcher and the packet router, and uplinks and downlinks pass
+//! through the in-process channels of a [`RouterHandle`]:
+//!
+//! ```no_run
+//! use gateway_rs::{embedded::RouterOnly, Packet, Settings};
+//! use std::{path::Path, time::Instant};
+//!
+//! # async fn example(uplink: helium_proto::Packet) -> gateway_rs::Result {
+//! let settings = Settings::new(Path::new("settings.toml"))?;
+//! let logger = slog::Logger::root(slog::Discard, slog::o!());
+//! let (_trigger, shutdown) = triggered::trigger();
+//! let (router, mut handle) = RouterOnly::builder(&settings).build()?;
+//! let radio = async {
+//!     handle.uplink(Packet::from(uplink), Instant::now()).await;
+//!     while let Some(_downlink) = handle.downlink().await {
+//!         // Transmit the downlink with the radio driver
+//!     }
+//!     Ok::<_, gateway_rs::Error>(())
+//! };
+//! tokio::try_join!(router.run(&shutdown, &logger), radio)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The radio driver is responsible for what the UDP listener does otherwise,
+//! like dropping frames with a CRC error and transmitting in the downlink
+//! windows. Beacons, witnesses and the local API are not run in this mode.
+
+use crate::{
+    event_bus::EventBus, gateway, packet_router, region_watcher, server::Handles, sync, Packet,
+    RegionParams, Result, Settings,
+};
+use slog::Logger;
+use std::time::Instant;
+
+#[cfg(not(feature = "validator"))]
+type Router = packet_router::PacketRouter;
+#[cfg(feature = "validator")]
+type Router = crate::router::Dispatcher;
+
+/// The default number of uplinks that can wait for the router
+const UPLINK_QUEUE_SIZE: usize = 20;
+/// The default number of downlinks that can wait for the radio driver
+const DOWNLINK_QUEUE_SIZE: usize = 10;
+
+pub struct RouterOnlyBuilder<'a> {
+    settings: &'a Settings,
+    uplink_queue: usize,
+    downlink_queue: usize,
+}
+
+impl<'a> RouterOnlyBuilder<'a> {
+    /// The number of uplinks that can wait for the router before
+    /// [`RouterHandle::uplink`] waits. Default 20
+    pub fn uplink_queue(mut self, size: usize) -> Self {
+        self.uplink_queue = size;
+        self
+    }
+
+    /// The number of downlinks that can wait for the radio driver before the
+    /// router waits. Default 10
+    pub fn downlink_queue(mut self, size: usize) -> Self {
+        self.downlink_queue = size;
+        self
+    }
+
+    pub fn build(self) -> Result<(RouterOnly, RouterHandle)> {
+        let (uplinks, router_rx) = sync::message_channel(self.uplink_queue);
+        let (downlink_tx, downlinks) = sync::message_channel(self.downlink_queue);
+        // Nothing subscribes to the events or reads the statistics of the
+        // embedded router
+        let handles = Handles::new(self.settings)?;
+        let events = EventBus::new(handles.queues.snapshot().event_bus);
+        let mut region_watcher =
+            region_watcher::RegionWatcher::new(self.settings, events.clone(), &handles)?;
+        let region_watch = region_watcher.watcher();
+        #[cfg(not(feature = "validator"))]
+        let router = Router::new(
+            self.settings,
+            router_rx,
+            region_watch.clone(),
+            downlink_tx,
+            &handles,
+        )?;
+        #[cfg(feature = "validator")]
+        let router = Router::new(
+            self.settings,
+            router_rx,
+            region_watch.clone(),
+            downlink_tx,
+            events,
+            &handles,
+        );
+        Ok((
+            RouterOnly {
+                region_watcher,
+                router,
+            },
+            RouterHandle {
+                uplinks,
+                downlinks,
+                region_watch,
+            },
+        ))
+    }
+}
+
+/// The region watcher and packet router without the packet forwarder
+/// listener
+pub struct RouterOnly {
+    region_watcher: region_watcher::RegionWatcher,
+    router: Router,
+}
+
+impl RouterOnly {
+    pub fn builder(settings: &Settings) -> RouterOnlyBuilder<'_> {
+        RouterOnlyBuilder {
+            settings,
+            uplink_queue: UPLINK_QUEUE_SIZE,
+            downlink_queue: DOWNLINK_QUEUE_SIZE,
+        }
+    }
+
+    pub async fn run(mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        tokio::try_join!(
+            self.region_watcher.run(shutdown, logger),
+            self.router.run(shutdown, logger),
+        )
+        .map(|_| ())
+    }
+}
+
+/// Hands uplinks to and takes downlinks from a [`RouterOnly`]
+pub struct RouterHandle {
+    uplinks: packet_router::MessageSender,
+    downlinks: gateway::MessageReceiver,
+    region_watch: region_watcher::MessageReceiver,
+}
+
+impl RouterHandle {
+    /// Hands an uplink received by the radio at the given time to the router
+    pub async fn uplink(&self, packet: Packet, received: Instant) {
+        self.uplinks.uplink(packet, received).await
+    }
+
+    /// Waits for the next downlink to transmit. Returns None when the router
+    /// stopped.
+    pub async fn downlink(&mut self) -> Option<Packet> {
+        loop {
+            match self.downlinks.recv().await? {
+                gateway::Message::Downlink(packet) => return Some(packet),
+                // Only the beaconer, which is not run, transmits beacons
+                gateway::Message::TransmitBeacon(..) => continue,
+            }
+        }
+    }
+
+    /// The region params the router currently operates with
+    pub fn region_params(&self) -> RegionParams {
+        region_watcher::current_value(&self.region_watch)
+    }
+
+    /// A sender of uplinks to the router, for radio drivers that receive on
+    /// another task than the one taking downlinks
+    pub fn uplink_sender(&self) -> packet_router::MessageSender {
+        self.uplinks.clone()
+    }
+}
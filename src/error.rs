@@ -29,12 +29,25 @@ pub enum Error {
     Region(#[from] RegionError),
     #[error("system time")]
     SystemTime(#[from] std::time::SystemTimeError),
+    #[cfg(any(feature = "rest", feature = "metrics", feature = "https"))]
+    #[error("http error")]
+    Http(#[from] hyper::Error),
+    #[error("{0}")]
+    PortInUse(crate::server::port_check::PortConflict),
+    #[error("task failed")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("update error: {0}")]
+    Update(#[from] UpdateError),
+    #[error("handoff error: {0}")]
+    Handoff(#[from] HandoffError),
 }
 
 #[derive(Error, Debug)]
 pub enum EncodeError {
     #[error("protobuf encode")]
     Prost(#[from] prost::EncodeError),
+    #[error("invalid {0} metadata")]
+    Metadata(&'static str),
 }
 
 #[derive(Error, Debug)]
@@ -67,6 +80,12 @@ pub enum DecodeError {
     NotBeacon,
     #[error("invalid beacon datarate: {0}")]
     InvalidBeaconDataRate(String),
+    #[error("invalid routing filter: {0}")]
+    InvalidFilter(&'static str),
+    #[error("quarantined message: {0}")]
+    Quarantined(String),
+    #[error("unknown report kind: {0}")]
+    UnknownReportKind(String),
 }
 
 #[derive(Error, Debug)]
@@ -79,18 +98,53 @@ pub enum ServiceError {
     Stream,
     #[error("channel closed")]
     Channel,
+    #[error("timeout")]
+    Timeout,
     #[error("no service")]
     NoService,
     #[error("age {age}s > {max_age}s")]
     Check { age: u64, max_age: u64 },
     #[error("Unable to connect to local server. Check that `helium_gateway` is running.")]
     LocalClientConnect(helium_proto::services::Error),
+    #[error("response for request {echo}, expected {nonce}")]
+    RequestMismatch { nonce: u64, echo: String },
 }
 
 #[derive(Debug, Error)]
 pub enum RegionError {
     #[error("no region params found or active")]
     NoRegionParams,
+    #[error("invalid region params: {0}")]
+    InvalidRegionParams(String),
+    #[error("channel {0} is masked")]
+    MaskedChannel(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("GET {uri}: {status}")]
+    HttpStatus {
+        uri: http::Uri,
+        status: http::StatusCode,
+    },
+    #[error("GET {uri}: larger than {max_size} bytes")]
+    TooLarge { uri: http::Uri, max_size: usize },
+    #[error("digest mismatch: expected {expected}, got {digest}")]
+    DigestMismatch { expected: String, digest: String },
+    #[error("update command failed: {0}")]
+    CommandFailed(String),
+    #[error("failed to execute update: {0}")]
+    Exec(std::io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum HandoffError {
+    #[error("header lists {expected} sockets, received {received}")]
+    SocketCount { expected: usize, received: usize },
+    #[error("truncated header")]
+    TruncatedHeader,
+    #[error("unexpected acknowledgement")]
+    Acknowledgement,
 }
 
 macro_rules! from_err {
@@ -157,12 +211,284 @@ impl DecodeError {
     pub fn not_beacon() -> Error {
         Error::Decode(DecodeError::NotBeacon)
     }
+
+    pub fn invalid_filter(msg: &'static str) -> Error {
+        Error::Decode(DecodeError::InvalidFilter(msg))
+    }
+
+    pub fn quarantined<T: ToString>(msg: T) -> Error {
+        Error::Decode(DecodeError::Quarantined(msg.to_string()))
+    }
+
+    pub fn unknown_report_kind<T: ToString>(kind: T) -> Error {
+        Error::Decode(DecodeError::UnknownReportKind(kind.to_string()))
+    }
+}
+
+impl EncodeError {
+    pub fn metadata(key: &'static str) -> Error {
+        Error::Encode(EncodeError::Metadata(key))
+    }
 }
 
 impl RegionError {
     pub fn no_region_params() -> Error {
         Error::Region(RegionError::NoRegionParams)
     }
+
+    pub fn invalid_region_params<T: ToString>(msg: T) -> Error {
+        Error::Region(RegionError::InvalidRegionParams(msg.to_string()))
+    }
+
+    pub fn masked_channel(frequency: u64) -> Error {
+        Error::Region(RegionError::MaskedChannel(frequency))
+    }
+}
+
+impl UpdateError {
+    pub fn http_status(uri: http::Uri, status: http::StatusCode) -> Error {
+        Error::Update(UpdateError::HttpStatus { uri, status })
+    }
+
+    pub fn too_large(uri: http::Uri, max_size: usize) -> Error {
+        Error::Update(UpdateError::TooLarge { uri, max_size })
+    }
+
+    pub fn digest_mismatch(expected: String, digest: String) -> Error {
+        Error::Update(UpdateError::DigestMismatch { expected, digest })
+    }
+
+    pub fn command_failed<T: ToString>(msg: T) -> Error {
+        Error::Update(UpdateError::CommandFailed(msg.to_string()))
+    }
+
+    pub fn exec(err: std::io::Error) -> Error {
+        Error::Update(UpdateError::Exec(err))
+    }
+}
+
+impl HandoffError {
+    pub fn socket_count(expected: usize, received: usize) -> Error {
+        Error::Handoff(HandoffError::SocketCount { expected, received })
+    }
+
+    pub fn truncated_header() -> Error {
+        Error::Handoff(HandoffError::TruncatedHeader)
+    }
+
+    pub fn acknowledgement() -> Error {
+        Error::Handoff(HandoffError::Acknowledgement)
+    }
+}
+
+/// Stable error codes for alerting and API clients. The number and name of a
+/// code never change meaning; new codes are only ever added.
+///
+/// Codes are grouped by range: 1xx general, 2xx encoding, 3xx decoding, 4xx
+/// services, 5xx packet forwarder and gateway, 6xx beacons, 7xx region and
+/// system, including self-updates and handoffs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Config = 100,
+    Custom = 101,
+    Io = 102,
+    Crypto = 103,
+    PortInUse = 104,
+    Task = 105,
+    Encode = 200,
+    InvalidMetadata = 201,
+    InvalidUri = 300,
+    InvalidKeypairUri = 301,
+    InvalidJson = 302,
+    InvalidBase64 = 303,
+    InvalidAddress = 304,
+    InvalidProtobuf = 305,
+    InvalidLorawan = 306,
+    InvalidDatarate = 307,
+    InvalidCrc = 308,
+    InvalidEnvelope = 309,
+    NoRx1Window = 310,
+    NoDataRate = 311,
+    NotBeacon = 312,
+    InvalidBeaconDataRate = 313,
+    InvalidFilter = 314,
+    Quarantined = 315,
+    UnknownReportKind = 316,
+    Service = 400,
+    Rpc = 401,
+    RpcTransient = 402,
+    StreamClosed = 403,
+    ChannelClosed = 404,
+    Timeout = 405,
+    NoService = 406,
+    ServiceCheck = 407,
+    LocalConnect = 408,
+    RequestMismatch = 409,
+    Semtech = 500,
+    Gateway = 501,
+    Beacon = 600,
+    NoRegionParams = 700,
+    SystemTime = 701,
+    Http = 702,
+    InvalidRegionParams = 703,
+    MaskedChannel = 704,
+    UpdateHttpStatus = 705,
+    UpdateTooLarge = 706,
+    UpdateDigestMismatch = 707,
+    UpdateCommandFailed = 708,
+    UpdateExec = 709,
+    HandoffSocketCount = 710,
+    HandoffTruncatedHeader = 711,
+    HandoffAcknowledgement = 712,
+}
+
+impl ErrorCode {
+    pub fn number(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Config => "config",
+            Self::Custom => "custom",
+            Self::Io => "io",
+            Self::Crypto => "crypto",
+            Self::PortInUse => "port_in_use",
+            Self::Task => "task",
+            Self::Encode => "encode",
+            Self::InvalidMetadata => "invalid_metadata",
+            Self::InvalidUri => "invalid_uri",
+            Self::InvalidKeypairUri => "invalid_keypair_uri",
+            Self::InvalidJson => "invalid_json",
+            Self::InvalidBase64 => "invalid_base64",
+            Self::InvalidAddress => "invalid_address",
+            Self::InvalidProtobuf => "invalid_protobuf",
+            Self::InvalidLorawan => "invalid_lorawan",
+            Self::InvalidDatarate => "invalid_datarate",
+            Self::InvalidCrc => "invalid_crc",
+            Self::InvalidEnvelope => "invalid_envelope",
+            Self::NoRx1Window => "no_rx1_window",
+            Self::NoDataRate => "no_data_rate",
+            Self::NotBeacon => "not_beacon",
+            Self::InvalidBeaconDataRate => "invalid_beacon_datarate",
+            Self::InvalidFilter => "invalid_filter",
+            Self::Quarantined => "quarantined",
+            Self::UnknownReportKind => "unknown_report_kind",
+            Self::Service => "service",
+            Self::Rpc => "rpc",
+            Self::RpcTransient => "rpc_transient",
+            Self::StreamClosed => "stream_closed",
+            Self::ChannelClosed => "channel_closed",
+            Self::Timeout => "timeout",
+            Self::NoService => "no_service",
+            Self::ServiceCheck => "service_check",
+            Self::LocalConnect => "local_connect",
+            Self::RequestMismatch => "request_mismatch",
+            Self::Semtech => "semtech",
+            Self::Gateway => "gateway",
+            Self::Beacon => "beacon",
+            Self::NoRegionParams => "no_region_params",
+            Self::SystemTime => "system_time",
+            Self::Http => "http",
+            Self::InvalidRegionParams => "invalid_region_params",
+            Self::MaskedChannel => "masked_channel",
+            Self::UpdateHttpStatus => "update_http_status",
+            Self::UpdateTooLarge => "update_too_large",
+            Self::UpdateDigestMismatch => "update_digest_mismatch",
+            Self::UpdateCommandFailed => "update_command_failed",
+            Self::UpdateExec => "update_exec",
+            Self::HandoffSocketCount => "handoff_socket_count",
+            Self::HandoffTruncatedHeader => "handoff_truncated_header",
+            Self::HandoffAcknowledgement => "handoff_acknowledgement",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "E{} {}", self.number(), self.as_str())
+    }
+}
+
+impl slog::Value for ErrorCode {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_str(key, self.as_str())
+    }
+}
+
+impl Error {
+    /// The stable code for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Config(_) => ErrorCode::Config,
+            Self::Custom(_) => ErrorCode::Custom,
+            Self::IO(_) => ErrorCode::Io,
+            Self::CryptoError(_) => ErrorCode::Crypto,
+            Self::Encode(EncodeError::Prost(_)) => ErrorCode::Encode,
+            Self::Encode(EncodeError::Metadata(_)) => ErrorCode::InvalidMetadata,
+            Self::Decode(err) => match err {
+                DecodeError::Uri(_) => ErrorCode::InvalidUri,
+                DecodeError::KeypairUri(_) => ErrorCode::InvalidKeypairUri,
+                DecodeError::Json(_) => ErrorCode::InvalidJson,
+                DecodeError::Base64(_) => ErrorCode::InvalidBase64,
+                DecodeError::Addr(_) => ErrorCode::InvalidAddress,
+                DecodeError::Prost(_) => ErrorCode::InvalidProtobuf,
+                DecodeError::LoraWan(_) => ErrorCode::InvalidLorawan,
+                DecodeError::Semtech(_) => ErrorCode::InvalidDatarate,
+                DecodeError::InvalidCrc => ErrorCode::InvalidCrc,
+                DecodeError::InvalidEnvelope => ErrorCode::InvalidEnvelope,
+                DecodeError::NoRx1Window => ErrorCode::NoRx1Window,
+                DecodeError::NoDataRate => ErrorCode::NoDataRate,
+                DecodeError::NotBeacon => ErrorCode::NotBeacon,
+                DecodeError::InvalidBeaconDataRate(_) => ErrorCode::InvalidBeaconDataRate,
+                DecodeError::InvalidFilter(_) => ErrorCode::InvalidFilter,
+                DecodeError::Quarantined(_) => ErrorCode::Quarantined,
+                DecodeError::UnknownReportKind(_) => ErrorCode::UnknownReportKind,
+            },
+            Self::Service(err) => match err {
+                ServiceError::Service(_) => ErrorCode::Service,
+                ServiceError::Rpc(status) if crate::service::is_transient(status) => {
+                    ErrorCode::RpcTransient
+                }
+                ServiceError::Rpc(_) => ErrorCode::Rpc,
+                ServiceError::Stream => ErrorCode::StreamClosed,
+                ServiceError::Channel => ErrorCode::ChannelClosed,
+                ServiceError::Timeout => ErrorCode::Timeout,
+                ServiceError::NoService => ErrorCode::NoService,
+                ServiceError::Check { .. } => ErrorCode::ServiceCheck,
+                ServiceError::LocalClientConnect(_) => ErrorCode::LocalConnect,
+                ServiceError::RequestMismatch { .. } => ErrorCode::RequestMismatch,
+            },
+            Self::Semtech(_) => ErrorCode::Semtech,
+            Self::Gateway(_) => ErrorCode::Gateway,
+            Self::Beacon(_) => ErrorCode::Beacon,
+            Self::Region(RegionError::NoRegionParams) => ErrorCode::NoRegionParams,
+            Self::SystemTime(_) => ErrorCode::SystemTime,
+            #[cfg(any(feature = "rest", feature = "metrics", feature = "https"))]
+            Self::Http(_) => ErrorCode::Http,
+            Self::Region(RegionError::InvalidRegionParams(_)) => ErrorCode::InvalidRegionParams,
+            Self::Region(RegionError::MaskedChannel(_)) => ErrorCode::MaskedChannel,
+            Self::PortInUse(_) => ErrorCode::PortInUse,
+            Self::Task(_) => ErrorCode::Task,
+            Self::Update(err) => match err {
+                UpdateError::HttpStatus { .. } => ErrorCode::UpdateHttpStatus,
+                UpdateError::TooLarge { .. } => ErrorCode::UpdateTooLarge,
+                UpdateError::DigestMismatch { .. } => ErrorCode::UpdateDigestMismatch,
+                UpdateError::CommandFailed(_) => ErrorCode::UpdateCommandFailed,
+                UpdateError::Exec(_) => ErrorCode::UpdateExec,
+            },
+            Self::Handoff(err) => match err {
+                HandoffError::SocketCount { .. } => ErrorCode::HandoffSocketCount,
+                HandoffError::TruncatedHeader => ErrorCode::HandoffTruncatedHeader,
+                HandoffError::Acknowledgement => ErrorCode::HandoffAcknowledgement,
+            },
+        }
+    }
 }
 
 impl Error {
@@ -176,6 +502,10 @@ impl Error {
         Error::Service(ServiceError::Channel)
     }
 
+    pub fn timeout() -> Error {
+        Error::Service(ServiceError::Timeout)
+    }
+
     pub fn no_service() -> Error {
         Error::Service(ServiceError::NoService)
     }
@@ -187,4 +517,45 @@ impl Error {
     pub fn local_client_connect(e: helium_proto::services::Error) -> Error {
         Error::Service(ServiceError::LocalClientConnect(e))
     }
+
+    pub fn port_in_use(conflict: crate::server::port_check::PortConflict) -> Error {
+        Error::PortInUse(conflict)
+    }
+
+    pub fn request_mismatch(nonce: u64, echo: String) -> Error {
+        Error::Service(ServiceError::RequestMismatch { nonce, echo })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(308, DecodeError::invalid_crc().code().number());
+        assert_eq!("timeout", Error::timeout().code().as_str());
+        assert_eq!(
+            ErrorCode::RpcTransient,
+            Error::from(tonic::Status::unavailable("down")).code()
+        );
+        assert_eq!(
+            ErrorCode::Rpc,
+            Error::from(tonic::Status::not_found("missing")).code()
+        );
+        assert_eq!(
+            "E700 no_region_params",
+            RegionError::no_region_params().code().to_string()
+        );
+        assert_eq!(
+            "E707 update_digest_mismatch",
+            UpdateError::digest_mismatch("00".to_string(), "ff".to_string())
+                .code()
+                .to_string()
+        );
+        assert_eq!(
+            ErrorCode::HandoffSocketCount,
+            HandoffError::socket_count(2, 1).code()
+        );
+    }
 }
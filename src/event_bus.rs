@@ -0,0 +1,145 @@
+//! A broadcast bus for gateway events.
+//!
+//! Modules publish what happens to them (uplinks received, downlinks
+//! requested, region changes) and any number of consumers can subscribe to
+//! these events. The bus is created in `server::run` and handed to the tasks
+//! that publish or subscribe like the other channels. Request/response style
+//! messaging between modules still uses the point-to-point channels in
+//! [`crate::sync`].
+//!
+//! The bus is lossy for slow consumers: a subscriber that falls more than
+//! `queues.event_bus` events behind will see a `Lagged` receive error and
+//! skip ahead.
+
+use crate::{
+    gateway::forwarder_acks::AckLoss, server::milestones::MilestoneReached,
+    service::region_history::RegionDiff, Packet, RegionParams,
+};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An uplink was received from the packet forwarder and handed to the
+    /// router.
+    UplinkReceived(Packet),
+    /// A downlink was requested by the router.
+    DownlinkRequested(Packet),
+    /// New region parameters were received.
+    RegionChanged(RegionParams),
+    /// What changed in the region parameters, published before the
+    /// corresponding `RegionChanged`.
+    RegionDiff(RegionDiff),
+    /// The packet forwarder's result of transmitting a downlink in a receive
+    /// window.
+    DownlinkResult(DownlinkReport),
+    /// A router exceeded its error budget and was taken out of rotation.
+    RouterBlacklisted(RouterBlacklist),
+    /// The cooldown of the router with the given uri ended.
+    RouterRestored(String),
+    /// A packet forwarder reported that none of its datagrams were
+    /// acknowledged for several stat intervals.
+    ForwarderAckLoss(AckLoss),
+    /// A success was reached for the first time since boot.
+    MilestoneReached(MilestoneReached),
+}
+
+/// A router that was taken out of rotation in multi-router mode.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouterBlacklist {
+    pub uri: String,
+    /// The error rate over the window, between 0 and 1
+    pub error_rate: f64,
+    /// Seconds the router is out of rotation
+    pub cooldown: u64,
+}
+
+/// Metadata and the transmit result of a router initiated downlink in a
+/// single receive window.
+#[derive(Debug, Clone, Serialize)]
+pub struct DownlinkReport {
+    /// The receive window, "rx1" or "rx2"
+    pub window: &'static str,
+    /// Frequency in MHz
+    pub frequency: f64,
+    pub datarate: String,
+    /// Transmit power in dBm
+    pub tx_power: u64,
+    /// Concentrator timestamp the downlink was scheduled for
+    pub tmst: u32,
+    pub payload_size: usize,
+    /// "sent" or the packet forwarder error, for example "too_late"
+    pub result: String,
+    /// Time from dispatch until the packet forwarder acknowledged, in
+    /// milliseconds
+    pub ack_ms: u64,
+}
+
+pub type Receiver = broadcast::Receiver<Event>;
+
+/// The publishing and subscribing end of the bus. Clones share the same bus.
+#[derive(Debug, Clone)]
+pub struct EventBus(broadcast::Sender<Event>);
+
+impl EventBus {
+    /// Creates a bus a subscriber can fall `size` events behind on
+    pub fn new(size: usize) -> Self {
+        Self(broadcast::channel(size).0)
+    }
+
+    /// Publishes an event to all current subscribers. Events published while
+    /// there are no subscribers are dropped.
+    pub fn publish(&self, event: Event) {
+        if self.0.receiver_count() > 0 {
+            let _ = self.0.send(event);
+        }
+    }
+
+    /// Subscribes to all events published from now on.
+    pub fn subscribe(&self) -> Receiver {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use broadcast::error::TryRecvError;
+
+    fn restored(uri: &str) -> Event {
+        Event::RouterRestored(uri.to_string())
+    }
+
+    fn restored_uri(event: std::result::Result<Event, TryRecvError>) -> String {
+        match event {
+            Ok(Event::RouterRestored(uri)) => uri,
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+
+    #[test]
+    fn publish_subscribe() {
+        let bus = EventBus::new(4);
+        // Nobody hears events published before subscribing
+        bus.publish(restored("before"));
+        let mut first = bus.subscribe();
+        let mut second = bus.clone().subscribe();
+        bus.clone().publish(restored("after"));
+        assert_eq!("after", restored_uri(first.try_recv()));
+        assert_eq!("after", restored_uri(second.try_recv()));
+        assert!(matches!(first.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn lagged() {
+        let bus = EventBus::new(2);
+        let mut events = bus.subscribe();
+        for uri in ["1", "2", "3"] {
+            bus.publish(restored(uri));
+        }
+        // A subscriber that fell behind skips ahead to the oldest kept event
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Lagged(1))));
+        assert_eq!("2", restored_uri(events.try_recv()));
+        assert_eq!("3", restored_uri(events.try_recv()));
+    }
+}
@@ -0,0 +1,143 @@
+//! The Semtech UDP packet forwarders allowed to send to the gateway.
+//!
+//! On shared hosts anyone who can reach the UDP port can point a packet
+//! forwarder at it. With `forwarder.allowed_euis` set, connects, uplinks and
+//! stats of forwarders with other gateway EUIs are dropped. Rejected messages
+//! are counted by kind and logged at most once per `LOG_INTERVAL` per EUI with
+//! the number of messages rejected in between.
+
+use crate::{metrics, Error, Result};
+use semtech_udp::MacAddress;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Counter of messages of forwarders not on the allowlist labeled with `kind`
+pub const REJECTED_METRIC: &str = "forwarder_rejected_total";
+
+/// The minimum time between log lines of rejected messages per EUI
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// The maximum number of rejected EUIs tracked for log throttling
+const MAX_REJECTED: usize = 256;
+
+#[derive(Debug)]
+struct Rejected {
+    logged: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    /// The allowed EUIs, all are allowed when None
+    euis: Option<HashSet<MacAddress>>,
+    rejected: HashMap<MacAddress, Rejected>,
+}
+
+impl Allowlist {
+    pub fn new(euis: &[String]) -> Result<Self> {
+        let euis = if euis.is_empty() {
+            None
+        } else {
+            Some(
+                euis.iter()
+                    .map(|eui| parse_eui(eui))
+                    .collect::<Result<_>>()?,
+            )
+        };
+        Ok(Self {
+            euis,
+            rejected: HashMap::new(),
+        })
+    }
+
+    pub fn allows(&self, mac: MacAddress) -> bool {
+        match &self.euis {
+            Some(euis) => euis.contains(&mac),
+            None => true,
+        }
+    }
+
+    /// Counts a message of the given kind ("client", "uplink" or "stat") of a
+    /// forwarder that is not allowed. Returns the number of messages of the
+    /// forwarder suppressed since the last log line when this one should be
+    /// logged.
+    pub fn reject(&mut self, mac: MacAddress, kind: &str, now: Instant) -> Option<u64> {
+        metrics::increment(&metrics::labeled(REJECTED_METRIC, &[("kind", kind)]));
+        if let Some(rejected) = self.rejected.get_mut(&mac) {
+            if now.saturating_duration_since(rejected.logged) < LOG_INTERVAL {
+                rejected.suppressed += 1;
+                return None;
+            }
+            rejected.logged = now;
+            return Some(std::mem::take(&mut rejected.suppressed));
+        }
+        if self.rejected.len() >= MAX_REJECTED {
+            self.rejected.clear();
+        }
+        self.rejected.insert(
+            mac,
+            Rejected {
+                logged: now,
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+}
+
+/// Parses a gateway EUI given as 16 hex digits, optionally separated by ':'
+/// or '-'
+fn parse_eui(eui: &str) -> Result<MacAddress> {
+    let digits: String = eui.chars().filter(|c| *c != ':' && *c != '-').collect();
+    let value = (digits.len() == 16)
+        .then(|| u64::from_str_radix(&digits, 16).ok())
+        .flatten()
+        .ok_or_else(|| Error::custom(format!("invalid forwarder eui: {eui}")))?;
+    Ok(MacAddress::new(&value.to_be_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allowlist() {
+        let allowed = MacAddress::new(&[0xaa, 0x55, 0x5a, 0, 0, 0, 0, 1]);
+        let other = MacAddress::new(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(Allowlist::new(&[]).unwrap().allows(other));
+
+        let mut allowlist = Allowlist::new(&[
+            "AA555A0000000001".to_string(),
+            "01-02-03-04-05-06-07-09".to_string(),
+        ])
+        .unwrap();
+        assert!(allowlist.allows(allowed));
+        assert!(!allowlist.allows(other));
+        assert!(Allowlist::new(&["AA555A".to_string()]).is_err());
+        assert!(Allowlist::new(&["AA555A000000000G".to_string()]).is_err());
+
+        let start = Instant::now();
+        assert_eq!(Some(0), allowlist.reject(other, "uplink", start));
+        assert_eq!(
+            None,
+            allowlist.reject(other, "uplink", start + Duration::from_secs(1))
+        );
+        assert_eq!(
+            None,
+            allowlist.reject(other, "stat", start + Duration::from_secs(2))
+        );
+        assert_eq!(
+            Some(2),
+            allowlist.reject(other, "uplink", start + LOG_INTERVAL)
+        );
+        assert_eq!(
+            None,
+            allowlist.reject(
+                other,
+                "uplink",
+                start + LOG_INTERVAL + Duration::from_secs(1)
+            )
+        );
+    }
+}
@@ -0,0 +1,981 @@
+//! Semtech Basics Station LNS protocol listener.
+//!
+//! Concentrators that only ship a Basics Station forwarder connect to the
+//! gateway as their LNS (LoRaWAN network server) over websockets instead of
+//! using the Semtech UDP protocol. A station first asks the router-info
+//! endpoint for its muxs (data plane) endpoint and connects there. Once it
+//! reports its version it is sent a router config with the channel plan of the
+//! region params and starts sending uplinks, which are handed to the gateway
+//! like packet forwarder uplinks. Downlinks go to the connected station instead
+//! of the UDP packet forwarder. When the region params change the station is
+//! disconnected to pick up the new channel plan on reconnect.
+//!
+//! Stations transmit downlinks with inverted polarity and pick the transmit
+//! power themselves, so beacons can not be transmitted through a station.
+//! Received beacons are witnessed as usual.
+
+use crate::{
+    gateway::{GatewayError, DOWNLINK_TIMEOUT},
+    packet, region_watcher,
+    server::handoff,
+    Error, Packet, RegionParams, Result,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use beacon::LoraDataRate;
+use futures::{SinkExt, StreamExt};
+use helium_proto::Region as ProtoRegion;
+use http::header::HOST;
+use semtech_udp::push_data;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        Error as WsError, Message as WsMessage,
+    },
+    WebSocketStream,
+};
+
+/// The receive delay (in microseconds) downlinks are scheduled with. Stations
+/// transmit class A downlinks this long after the given xtime.
+const RX_DELAY_US: i64 = 1_000_000;
+/// The maximum offset of a channel from the center frequency of its radio
+const MAX_IF: u64 = 400_000;
+/// The number of multi-SF channels of an SX1301 concentrator
+const MAX_CHANNELS: usize = 8;
+/// The number of multi-SF channels received by one radio
+const RADIO_CHANNELS: usize = 4;
+const UPLINK_QUEUE: usize = 20;
+const SEND_QUEUE: usize = 20;
+/// Wait before accepting again after a failed accept, like when out of file
+/// descriptors
+const ACCEPT_RETRY_WAIT: Duration = Duration::from_secs(1);
+/// The device EUI of downlinks, which stations only use for logging
+const NO_EUI: &str = "00-00-00-00-00-00-00-00";
+
+/// A spreading factor, bandwidth in kHz and whether the datarate is downlink
+/// only. A spreading factor of 0 is FSK, -1 an undefined datarate.
+type DataRateEntry = (i8, u32, bool);
+
+const UNDEFINED: DataRateEntry = (-1, 0, false);
+
+/// DR0 to DR7 of most plans
+const EU_DATARATES: [DataRateEntry; 16] = [
+    (12, 125, false),
+    (11, 125, false),
+    (10, 125, false),
+    (9, 125, false),
+    (8, 125, false),
+    (7, 125, false),
+    (7, 250, false),
+    (0, 0, false),
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+];
+
+const US_DATARATES: [DataRateEntry; 16] = [
+    (10, 125, false),
+    (9, 125, false),
+    (8, 125, false),
+    (7, 125, false),
+    (8, 500, false),
+    UNDEFINED,
+    UNDEFINED,
+    UNDEFINED,
+    (12, 500, true),
+    (11, 500, true),
+    (10, 500, true),
+    (9, 500, true),
+    (8, 500, true),
+    (7, 500, true),
+    UNDEFINED,
+    UNDEFINED,
+];
+
+const AU_DATARATES: [DataRateEntry; 16] = [
+    (12, 125, false),
+    (11, 125, false),
+    (10, 125, false),
+    (9, 125, false),
+    (8, 125, false),
+    (7, 125, false),
+    (8, 500, false),
+    UNDEFINED,
+    (12, 500, true),
+    (11, 500, true),
+    (10, 500, true),
+    (9, 500, true),
+    (8, 500, true),
+    (7, 500, true),
+    UNDEFINED,
+    UNDEFINED,
+];
+
+/// The channel plan sent to stations for a region
+#[derive(Debug)]
+struct Plan {
+    /// The prefix of the protobuf region names the plan is for
+    prefix: &'static str,
+    /// The region name known to stations
+    region: &'static str,
+    /// The transmit frequency range in Hz
+    freq_range: (u64, u64),
+    datarates: &'static [DataRateEntry; 16],
+}
+
+const PLANS: &[Plan] = &[
+    Plan {
+        prefix: "US915",
+        region: "US902",
+        freq_range: (902_000_000, 928_000_000),
+        datarates: &US_DATARATES,
+    },
+    Plan {
+        prefix: "AU915",
+        region: "AU915",
+        freq_range: (915_000_000, 928_000_000),
+        datarates: &AU_DATARATES,
+    },
+    Plan {
+        prefix: "AS923",
+        region: "AS923-1",
+        freq_range: (915_000_000, 928_000_000),
+        datarates: &EU_DATARATES,
+    },
+    Plan {
+        prefix: "KR920",
+        region: "KR920",
+        freq_range: (920_900_000, 923_300_000),
+        datarates: &EU_DATARATES,
+    },
+    Plan {
+        prefix: "IN865",
+        region: "IN865",
+        freq_range: (865_000_000, 867_000_000),
+        datarates: &EU_DATARATES,
+    },
+    Plan {
+        prefix: "EU868",
+        region: "EU863",
+        freq_range: (863_000_000, 870_000_000),
+        datarates: &EU_DATARATES,
+    },
+];
+
+impl Plan {
+    fn for_region(region_params: &RegionParams) -> Option<&'static Self> {
+        let name = ProtoRegion::from(region_params.region).as_str_name();
+        PLANS.iter().find(|plan| name.starts_with(plan.prefix))
+    }
+
+    /// The LoRa datarate of a DR index
+    fn datarate(&self, dr: usize) -> Option<LoraDataRate> {
+        let (spreading, bandwidth, _) = *self.datarates.get(dr)?;
+        LoraDataRate::new(u8::try_from(spreading).ok()?, bandwidth).ok()
+    }
+
+    /// The DR index of a downlink datarate, like "SF12BW500". Downlink only
+    /// datarates come last in the plans and are preferred.
+    fn downlink_dr(&self, datarate: &str) -> Option<usize> {
+        let datarate: LoraDataRate = datarate.parse().ok()?;
+        self.datarates
+            .iter()
+            .rposition(|(spreading, bandwidth, _)| {
+                *spreading == datarate.spreading() as i8 && *bandwidth == datarate.bandwidth()
+            })
+    }
+
+    fn router_config(&self, region_params: &RegionParams) -> Result<Value> {
+        let datarates: Vec<Value> = self
+            .datarates
+            .iter()
+            .map(|(spreading, bandwidth, downlink_only)| {
+                json!([spreading, bandwidth, *downlink_only as u8])
+            })
+            .collect();
+        Ok(json!({
+            "msgtype": "router_config",
+            "NetID": null,
+            "JoinEui": null,
+            "region": self.region,
+            "hwspec": "sx1301/1",
+            "freq_range": [self.freq_range.0, self.freq_range.1],
+            "DRs": datarates,
+            "sx1301_conf": sx1301_conf(region_params)?,
+            // Like with the UDP packet forwarder the router is in charge of
+            // the airtime
+            "nocca": true,
+            "nodc": true,
+            "nodwell": true,
+            "MuxTime": mux_time(),
+        }))
+    }
+}
+
+/// The SX1301 concentrator config for the 125 kHz channels of the region
+/// params. Each radio is centered on up to four channels.
+fn sx1301_conf(region_params: &RegionParams) -> Result<Value> {
+    let mut channels: Vec<u64> = region_params
+        .params
+        .iter()
+        .filter(|params| params.bandwidth == 125_000)
+        .map(|params| params.channel_frequency)
+        .collect();
+    channels.sort_unstable();
+    channels.dedup();
+    channels.truncate(MAX_CHANNELS);
+    if channels.is_empty() {
+        return Err(Error::custom("no 125 kHz channels in region params"));
+    }
+
+    let mut conf = serde_json::Map::new();
+    for (radio, group) in channels.chunks(RADIO_CHANNELS).enumerate() {
+        let (first, last) = (group[0], group[group.len() - 1]);
+        let center = (first + last) / 2;
+        if last - center > MAX_IF {
+            return Err(Error::custom(format!(
+                "channels {first} to {last} do not fit one radio"
+            )));
+        }
+        conf.insert(
+            format!("radio_{radio}"),
+            json!({"enable": true, "freq": center}),
+        );
+        for (n, frequency) in group.iter().enumerate() {
+            conf.insert(
+                format!("chan_multiSF_{}", radio * RADIO_CHANNELS + n),
+                json!({"enable": true, "radio": radio, "if": *frequency as i64 - center as i64}),
+            );
+        }
+    }
+    if channels.len() <= RADIO_CHANNELS {
+        conf.insert(
+            "radio_1".to_string(),
+            json!({"enable": false, "freq": channels[0]}),
+        );
+    }
+    conf.insert("chan_Lora_std".to_string(), json!({"enable": false}));
+    conf.insert("chan_FSK".to_string(), json!({"enable": false}));
+    Ok(Value::Array(vec![Value::Object(conf)]))
+}
+
+/// Messages received from stations. Other messages, like time syncs, are
+/// ignored.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "msgtype", rename_all = "lowercase")]
+enum Received {
+    Version {
+        station: String,
+        #[serde(default)]
+        model: Option<String>,
+    },
+    Updf(DataFrame),
+    Jreq(JoinRequest),
+    Propdf(ProprietaryFrame),
+    Dntxed {
+        diid: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The reception of an uplink
+#[derive(Debug, Deserialize)]
+struct Radio {
+    #[serde(rename = "DR")]
+    dr: usize,
+    /// The frequency in Hz
+    #[serde(rename = "Freq")]
+    freq: u64,
+    upinfo: UpInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpInfo {
+    /// The antenna the uplink was received on
+    #[serde(default)]
+    rctx: i64,
+    /// The extended concentrator time (in microseconds) of the uplink
+    xtime: i64,
+    rssi: f32,
+    snr: f32,
+}
+
+impl Radio {
+    /// The packet forwarder uplink of a frame received with this reception
+    fn rxpk(&self, frame: &[u8], plan: &Plan) -> Option<push_data::RxPk> {
+        let datarate = plan.datarate(self.dr)?;
+        let rxpk = json!({
+            // The lower 32 bits of the xtime counter are the concentrator
+            // counter
+            "tmst": self.upinfo.xtime as u32,
+            "chan": 0,
+            "rfch": self.upinfo.rctx,
+            "freq": self.freq as f64 / 1e6,
+            "stat": 1,
+            "modu": "LORA",
+            "datr": datarate.to_string(),
+            "codr": "4/5",
+            "rssi": self.upinfo.rssi.round() as i32,
+            "lsnr": self.upinfo.snr,
+            "size": frame.len(),
+            "data": STANDARD.encode(frame),
+        });
+        serde_json::from_value(rxpk).ok()
+    }
+}
+
+/// A data uplink, broken up into its fields by the station
+#[derive(Debug, Deserialize)]
+struct DataFrame {
+    #[serde(rename = "MHdr")]
+    mhdr: u8,
+    #[serde(rename = "DevAddr")]
+    dev_addr: i64,
+    #[serde(rename = "FCtrl")]
+    fctrl: u8,
+    #[serde(rename = "FCnt")]
+    fcnt: u16,
+    #[serde(rename = "FOpts")]
+    fopts: String,
+    /// The port, or -1 when there is none
+    #[serde(rename = "FPort")]
+    fport: i16,
+    #[serde(rename = "FRMPayload")]
+    frm_payload: String,
+    #[serde(rename = "MIC")]
+    mic: i64,
+    #[serde(flatten)]
+    radio: Radio,
+}
+
+impl DataFrame {
+    fn frame(&self) -> Option<Vec<u8>> {
+        let mut frame = vec![self.mhdr];
+        frame.extend_from_slice(&(self.dev_addr as u32).to_le_bytes());
+        frame.push(self.fctrl);
+        frame.extend_from_slice(&self.fcnt.to_le_bytes());
+        frame.extend(from_hex(&self.fopts)?);
+        if self.fport >= 0 {
+            frame.push(self.fport as u8);
+            frame.extend(from_hex(&self.frm_payload)?);
+        }
+        frame.extend_from_slice(&(self.mic as u32).to_le_bytes());
+        Some(frame)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinRequest {
+    #[serde(rename = "MHdr")]
+    mhdr: u8,
+    #[serde(rename = "JoinEui")]
+    join_eui: String,
+    #[serde(rename = "DevEui")]
+    dev_eui: String,
+    #[serde(rename = "DevNonce")]
+    dev_nonce: u16,
+    #[serde(rename = "MIC")]
+    mic: i64,
+    #[serde(flatten)]
+    radio: Radio,
+}
+
+impl JoinRequest {
+    fn frame(&self) -> Option<Vec<u8>> {
+        let mut frame = vec![self.mhdr];
+        frame.extend(eui_le(&self.join_eui)?);
+        frame.extend(eui_le(&self.dev_eui)?);
+        frame.extend_from_slice(&self.dev_nonce.to_le_bytes());
+        frame.extend_from_slice(&(self.mic as u32).to_le_bytes());
+        Some(frame)
+    }
+}
+
+/// A proprietary uplink, like a beacon, with the whole frame as payload
+#[derive(Debug, Deserialize)]
+struct ProprietaryFrame {
+    #[serde(rename = "FRMPayload")]
+    frm_payload: String,
+    #[serde(flatten)]
+    radio: Radio,
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The little endian bytes of an EUI like "00-00-00-00-00-00-00-01"
+fn eui_le(eui: &str) -> Option<[u8; 8]> {
+    let mut eui: [u8; 8] = from_hex(&eui.replace(['-', ':'], ""))?.try_into().ok()?;
+    eui.reverse();
+    Some(eui)
+}
+
+/// The xtime of a concentrator timestamp shortly after the xtime of an uplink.
+/// The concentrator timestamp is the lower 32 bits of the xtime counter.
+fn to_xtime(uplink: i64, tmst: u32) -> i64 {
+    let xtime = (uplink & !0xFFFF_FFFF) | tmst as i64;
+    if xtime < uplink - (1 << 31) {
+        xtime + (1 << 32)
+    } else {
+        xtime
+    }
+}
+
+fn mux_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+/// The connected station
+struct Session {
+    id: u64,
+    messages: mpsc::Sender<String>,
+    /// The channel plan, once the station was sent its router config
+    plan: Option<&'static Plan>,
+    /// The xtime and antenna of the last uplink, which downlinks are
+    /// scheduled relative to
+    last_uplink: Option<(i64, i64)>,
+}
+
+#[derive(Default)]
+struct State {
+    session: Option<Session>,
+    sessions: u64,
+    diids: u64,
+    /// Downlinks waiting for the station to confirm their transmission
+    pending: HashMap<u64, oneshot::Sender<()>>,
+}
+
+type SharedState = Arc<Mutex<State>>;
+
+/// Listens for Basics Station connections and receives their uplinks
+pub struct Listener {
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+    region_watch: region_watcher::MessageReceiver,
+    state: SharedState,
+    uplink_sender: mpsc::Sender<push_data::RxPk>,
+    uplinks: mpsc::Receiver<push_data::RxPk>,
+}
+
+impl Listener {
+    pub fn new(listen: &str, region_watch: region_watcher::MessageReceiver) -> Result<Self> {
+        let addr: SocketAddr = listen.parse()?;
+        // Handed to a new process on upgrades like the API sockets
+        let listener = TcpListener::from_std(handoff::tcp_listener("basics_station", addr)?)?;
+        let (uplink_sender, uplinks) = mpsc::channel(UPLINK_QUEUE);
+        Ok(Self {
+            listener: Some(listener),
+            addr,
+            region_watch,
+            state: SharedState::default(),
+            uplink_sender,
+            uplinks,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Starts accepting station connections until shutdown
+    pub fn start(&mut self, shutdown: &triggered::Listener, logger: &Logger) {
+        let Some(listener) = self.listener.take() else {
+            return;
+        };
+        let connection = Connection {
+            state: self.state.clone(),
+            uplinks: self.uplink_sender.clone(),
+            region_watch: self.region_watch.clone(),
+            shutdown: shutdown.clone(),
+            logger: logger.new(o!("basics_station" => self.addr)),
+        };
+        tokio::spawn(accept(listener, connection));
+    }
+
+    /// Receives the next station uplink
+    pub async fn recv(&mut self) -> Option<push_data::RxPk> {
+        self.uplinks.recv().await
+    }
+
+    pub fn sender(&self) -> Sender {
+        Sender(self.state.clone())
+    }
+}
+
+async fn accept(listener: TcpListener, connection: Connection) {
+    loop {
+        tokio::select! {
+            _ = connection.shutdown.clone() => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let connection = connection.clone();
+                    tokio::spawn(async move {
+                        let logger = connection.logger.clone();
+                        if let Err(err) = connection.serve(stream, peer).await {
+                            debug!(logger, "station connection failed: {err}"; "peer" => peer);
+                        }
+                    });
+                }
+                Err(err) => {
+                    warn!(connection.logger, "failed to accept station connection: {err}");
+                    time::sleep(ACCEPT_RETRY_WAIT).await;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Connection {
+    state: SharedState,
+    uplinks: mpsc::Sender<push_data::RxPk>,
+    region_watch: region_watcher::MessageReceiver,
+    shutdown: triggered::Listener,
+    logger: Logger,
+}
+
+type WsResult = std::result::Result<(), WsError>;
+
+impl Connection {
+    async fn serve(self, stream: TcpStream, peer: SocketAddr) -> WsResult {
+        let local = stream.local_addr().map(|addr| addr.to_string());
+        let mut path = String::new();
+        let mut host = None;
+        let ws = accept_hdr_async(
+            stream,
+            |request: &Request,
+             response: Response|
+             -> std::result::Result<Response, ErrorResponse> {
+                path = request.uri().path().to_string();
+                host = request
+                    .headers()
+                    .get(HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .map(str::to_string);
+                Ok(response)
+            },
+        )
+        .await?;
+        if path.ends_with("router-info") {
+            let host = host.or(local.ok()).unwrap_or_default();
+            return self.router_info(ws, &host).await;
+        }
+        self.muxs(ws, peer).await
+    }
+
+    /// Answers a discovery request with the muxs endpoint of the station
+    async fn router_info(&self, mut ws: WebSocketStream<TcpStream>, host: &str) -> WsResult {
+        while let Some(message) = ws.next().await {
+            let WsMessage::Text(text) = message? else {
+                continue;
+            };
+            let router = serde_json::from_str::<Value>(&text)
+                .ok()
+                .and_then(|request| request.get("router").cloned());
+            let response = match router {
+                Some(router) => {
+                    let id = match &router {
+                        Value::String(id) => id.clone(),
+                        other => other.to_string(),
+                    };
+                    debug!(self.logger, "station discovery"; "router" => &id);
+                    json!({
+                        "router": router,
+                        "muxs": "muxs-::0",
+                        "uri": format!("ws://{host}/router-{id}"),
+                    })
+                }
+                None => json!({"error": "missing router"}),
+            };
+            ws.send(WsMessage::Text(response.to_string())).await?;
+            break;
+        }
+        ws.close(None).await
+    }
+
+    /// Runs the data plane connection of a station
+    async fn muxs(mut self, ws: WebSocketStream<TcpStream>, peer: SocketAddr) -> WsResult {
+        let (messages, mut outgoing) = mpsc::channel(SEND_QUEUE);
+        let session = self.connect(messages);
+        // Only changes after the router config is sent reconnect the station
+        self.region_watch.borrow_and_update();
+        info!(self.logger, "basics station connected"; "peer" => peer);
+        let (mut sink, mut stream) = ws.split();
+        let result = loop {
+            tokio::select! {
+                _ = self.shutdown.clone() => break Ok(()),
+                _ = self.region_watch.changed() => {
+                    info!(self.logger, "region params changed, reconnecting station");
+                    break Ok(());
+                }
+                message = outgoing.recv() => match message {
+                    Some(message) => if let Err(err) = sink.send(WsMessage::Text(message)).await {
+                        break Err(err);
+                    },
+                    // Replaced by a newer connection
+                    None => break Ok(()),
+                },
+                message = stream.next() => match message {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        if let Some(reply) = self.handle_text(session, &text).await {
+                            if let Err(err) = sink.send(reply).await {
+                                break Err(err);
+                            }
+                        }
+                    }
+                    Some(Ok(WsMessage::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => (),
+                    Some(Err(err)) => break Err(err),
+                },
+            }
+        };
+        self.disconnect(session);
+        info!(self.logger, "basics station disconnected"; "peer" => peer);
+        let _ = sink.close().await;
+        result
+    }
+
+    /// Makes a new station connection the connected one
+    fn connect(&self, messages: mpsc::Sender<String>) -> u64 {
+        let mut state = self.state.lock().expect("station state lock");
+        state.sessions += 1;
+        let id = state.sessions;
+        state.session = Some(Session {
+            id,
+            messages,
+            plan: None,
+            last_uplink: None,
+        });
+        id
+    }
+
+    fn disconnect(&self, id: u64) {
+        let mut state = self.state.lock().expect("station state lock");
+        if state
+            .session
+            .as_ref()
+            .is_some_and(|session| session.id == id)
+        {
+            state.session = None;
+        }
+    }
+
+    /// Runs the given function on the session when it is still the connected
+    /// one
+    fn with_session<T>(&self, id: u64, f: impl FnOnce(&mut Session) -> T) -> Option<T> {
+        let mut state = self.state.lock().expect("station state lock");
+        state
+            .session
+            .as_mut()
+            .filter(|session| session.id == id)
+            .map(f)
+    }
+
+    /// Handles a message of a station, returning the reply to send, if any
+    async fn handle_text(&mut self, session: u64, text: &str) -> Option<WsMessage> {
+        let received = match serde_json::from_str::<Received>(text) {
+            Ok(received) => received,
+            Err(err) => {
+                debug!(self.logger, "ignoring station message: {err}");
+                return None;
+            }
+        };
+        match received {
+            Received::Version { station, model } => {
+                return Some(self.router_config(session, &station, model.as_deref()))
+            }
+            Received::Updf(frame) => self.uplink(session, frame.frame(), &frame.radio).await,
+            Received::Jreq(frame) => self.uplink(session, frame.frame(), &frame.radio).await,
+            Received::Propdf(frame) => {
+                self.uplink(session, from_hex(&frame.frm_payload), &frame.radio)
+                    .await
+            }
+            Received::Dntxed { diid } => {
+                let confirmed = self
+                    .state
+                    .lock()
+                    .expect("station state lock")
+                    .pending
+                    .remove(&diid);
+                if let Some(confirmed) = confirmed {
+                    let _ = confirmed.send(());
+                }
+            }
+            Received::Other => (),
+        }
+        None
+    }
+
+    /// The router config for a station that reported its version. Stations
+    /// are disconnected while there are no usable region params.
+    fn router_config(&mut self, session: u64, station: &str, model: Option<&str>) -> WsMessage {
+        let region_params = self.region_watch.borrow_and_update().clone();
+        if region_params.params.is_empty() {
+            warn!(self.logger, "disconnecting station without region params");
+            return WsMessage::Close(None);
+        }
+        let Some(plan) = Plan::for_region(&region_params) else {
+            warn!(self.logger, "no basics station channel plan for region";
+                "region" => region_params.region.to_string());
+            return WsMessage::Close(None);
+        };
+        let config = match plan.router_config(&region_params) {
+            Ok(config) => config,
+            Err(err) => {
+                warn!(
+                    self.logger,
+                    "failed to build station router config: {err:?}"
+                );
+                return WsMessage::Close(None);
+            }
+        };
+        self.with_session(session, |session| session.plan = Some(plan));
+        info!(self.logger, "basics station configured";
+            "station" => station,
+            "model" => model,
+            "region" => plan.region);
+        WsMessage::Text(config.to_string())
+    }
+
+    async fn uplink(&self, session: u64, frame: Option<Vec<u8>>, radio: &Radio) {
+        let plan = self
+            .with_session(session, |session| {
+                session.last_uplink = Some((radio.upinfo.xtime, radio.upinfo.rctx));
+                session.plan
+            })
+            .flatten();
+        let rxpk = plan
+            .zip(frame)
+            .and_then(|(plan, frame)| radio.rxpk(&frame, plan));
+        match rxpk {
+            Some(rxpk) => {
+                let _ = self.uplinks.send(rxpk).await;
+            }
+            None => debug!(self.logger, "ignoring station uplink";
+                "dr" => radio.dr,
+                "freq" => radio.freq),
+        }
+    }
+}
+
+/// Sends downlinks to the connected station
+#[derive(Clone)]
+pub struct Sender(SharedState);
+
+impl Sender {
+    /// Whether a station is connected and configured
+    pub fn is_connected(&self) -> bool {
+        self.0
+            .lock()
+            .expect("station state lock")
+            .session
+            .as_ref()
+            .is_some_and(|session| session.plan.is_some())
+    }
+
+    /// Transmits a downlink through the connected station and waits for the
+    /// station to confirm the transmission. The station falls back to the rx2
+    /// window by itself.
+    pub async fn transmit(&self, downlink: &Packet) -> Result {
+        let (diid, confirmed) = self.send(downlink)?;
+        match time::timeout(DOWNLINK_TIMEOUT, confirmed).await {
+            Ok(Ok(())) => Ok(()),
+            _ => {
+                self.0
+                    .lock()
+                    .expect("station state lock")
+                    .pending
+                    .remove(&diid);
+                Err(Error::timeout())
+            }
+        }
+    }
+
+    fn send(&self, downlink: &Packet) -> Result<(u64, oneshot::Receiver<()>)> {
+        let mut state = self.0.lock().expect("station state lock");
+        let State {
+            session,
+            diids,
+            pending,
+            ..
+        } = &mut *state;
+        let session = session.as_ref().ok_or(GatewayError::NoStation)?;
+        let plan = session.plan.ok_or(GatewayError::NoStation)?;
+        let (xtime, rctx) = session.last_uplink.ok_or(GatewayError::NoStation)?;
+        let rx1_dr = plan
+            .downlink_dr(&downlink.datarate)
+            .ok_or_else(|| GatewayError::StationDataRate(downlink.datarate.clone()))?;
+
+        *diids += 1;
+        let diid = *diids;
+        let mut dnmsg = json!({
+            "msgtype": "dnmsg",
+            "DevEui": NO_EUI,
+            "dC": 0,
+            "diid": diid,
+            "pdu": to_hex(&downlink.payload),
+            "RxDelay": RX_DELAY_US / 1_000_000,
+            "RX1DR": rx1_dr,
+            "RX1Freq": packet::to_hz(downlink.frequency),
+            "xtime": to_xtime(xtime, downlink.timestamp as u32) - RX_DELAY_US,
+            "rctx": rctx,
+            "priority": 0,
+            "MuxTime": mux_time(),
+        });
+        let rx2 = downlink
+            .rx2_window
+            .as_ref()
+            .and_then(|window| Some((plan.downlink_dr(&window.datarate)?, window)));
+        if let Some((rx2_dr, window)) = rx2 {
+            dnmsg["RX2DR"] = rx2_dr.into();
+            dnmsg["RX2Freq"] = packet::to_hz(window.frequency).into();
+        }
+        session
+            .messages
+            .try_send(dnmsg.to_string())
+            .map_err(|_| GatewayError::NoStation)?;
+        let (confirmed, wait) = oneshot::channel();
+        pending.insert(diid, confirmed);
+        Ok((diid, wait))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_proto::BlockchainRegionParamV1;
+
+    #[test]
+    fn frames() {
+        let updf = r#"{"msgtype": "updf", "MHdr": 64, "DevAddr": 16909060, "FCtrl": 0,
+            "FCnt": 42, "FOpts": "", "FPort": 1, "FRMPayload": "0a0b", "MIC": -1,
+            "DR": 5, "Freq": 868100000,
+            "upinfo": {"rctx": 0, "xtime": 68116944405337035, "rssi": -50, "snr": 9.25}}"#;
+        let Ok(Received::Updf(frame)) = serde_json::from_str(updf) else {
+            panic!("updf");
+        };
+        assert_eq!(
+            Some(vec![
+                0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x2a, 0x00, 0x01, 0x0a, 0x0b, 0xff, 0xff, 0xff,
+                0xff
+            ]),
+            frame.frame()
+        );
+        let plan = &PLANS[PLANS.len() - 1];
+        let rxpk = frame
+            .radio
+            .rxpk(&frame.frame().unwrap(), plan)
+            .expect("rxpk");
+        assert_eq!(&(68116944405337035i64 as u32), rxpk.get_timestamp());
+        assert_eq!("SF7BW125", rxpk.get_datarate().to_string());
+
+        let jreq = r#"{"msgtype": "jreq", "MHdr": 0, "JoinEui": "01-02-03-04-05-06-07-08",
+            "DevEui": "11:12:13:14:15:16:17:18", "DevNonce": 258, "MIC": 1,
+            "DR": 0, "Freq": 868100000,
+            "upinfo": {"xtime": 1, "rssi": -120, "snr": -10}}"#;
+        let Ok(Received::Jreq(frame)) = serde_json::from_str(jreq) else {
+            panic!("jreq");
+        };
+        assert_eq!(
+            Some(vec![
+                0x00, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x18, 0x17, 0x16, 0x15, 0x14,
+                0x13, 0x12, 0x11, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00
+            ]),
+            frame.frame()
+        );
+
+        assert!(matches!(
+            serde_json::from_str(r#"{"msgtype": "timesync", "txtime": 1}"#),
+            Ok(Received::Other)
+        ));
+    }
+
+    #[test]
+    fn datarates() {
+        let us915 = &PLANS[0];
+        assert_eq!(Some(12), us915.downlink_dr("SF8BW500"));
+        assert_eq!(Some(0), us915.downlink_dr("SF10BW125"));
+        assert_eq!(None, us915.downlink_dr("SF12BW125"));
+        assert_eq!(
+            Some("SF8BW500".to_string()),
+            us915.datarate(4).map(|dr| dr.to_string())
+        );
+        assert_eq!(None, us915.datarate(5));
+    }
+
+    #[test]
+    fn xtimes() {
+        let session = 0x0011_0000_0000_0000;
+        assert_eq!(
+            session | 2_000_000,
+            to_xtime(session | 1_000_000, 2_000_000)
+        );
+        // Across a wrap of the concentrator counter
+        assert_eq!(
+            session | 0x1_0000_0100,
+            to_xtime(session | 0xFFFF_FF00, 0x100)
+        );
+        // A timestamp slightly before the uplink
+        assert_eq!(session | 500, to_xtime(session | 1_000, 500));
+    }
+
+    #[test]
+    fn channels() {
+        let region_params = RegionParams {
+            gain: 12.into(),
+            region: ProtoRegion::Eu868.into(),
+            params: (0..8)
+                .map(|n| BlockchainRegionParamV1 {
+                    channel_frequency: 867_100_000 + n * 200_000,
+                    bandwidth: 125_000,
+                    max_eirp: 160,
+                    spreading: None,
+                })
+                .collect(),
+            name: None,
+        };
+        let conf = sx1301_conf(&region_params).expect("sx1301 conf");
+        assert_eq!(867_400_000, conf[0]["radio_0"]["freq"]);
+        assert_eq!(868_200_000, conf[0]["radio_1"]["freq"]);
+        assert_eq!(-300_000, conf[0]["chan_multiSF_0"]["if"]);
+        assert_eq!(300_000, conf[0]["chan_multiSF_7"]["if"]);
+        assert_eq!(1, conf[0]["chan_multiSF_7"]["radio"]);
+        assert_eq!(
+            "EU863",
+            Plan::for_region(&region_params).expect("plan").region
+        );
+    }
+}
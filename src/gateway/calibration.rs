@@ -0,0 +1,68 @@
+//! Signal calibration for concentrators that systematically misreport RSSI or
+//! SNR.
+//!
+//! Offsets (in dB) are added to the signal strength and SNR of every received
+//! packet before it is sent upstream as an uplink or used in a witness report.
+//! Offsets come from the `calibration` settings or, when configured and
+//! present, from a JSON calibration file like `{"rssi": -3.5, "snr": 1.0}`
+//! which takes precedence.
+
+use crate::{Packet, Result, Settings};
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct Calibration {
+    /// Offset added to the RSSI in dB
+    #[serde(default)]
+    pub rssi: f32,
+    /// Offset added to the SNR in dB
+    #[serde(default)]
+    pub snr: f32,
+}
+
+impl Calibration {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let configured = Self {
+            rssi: settings.calibration.rssi,
+            snr: settings.calibration.snr,
+        };
+        match &settings.calibration.file {
+            Some(path) => Ok(Self::load(path)?.unwrap_or(configured)),
+            None => Ok(configured),
+        }
+    }
+
+    /// Load a calibration file. Returns None if the file does not exist.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Applies the calibration offsets to the given packet
+    pub fn apply(&self, packet: &mut Packet) {
+        packet.adjust_signal(self.rssi, self.snr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let mut packet = Packet::from(helium_proto::Packet {
+            signal_strength: -100.0,
+            snr: 5.5,
+            ..Default::default()
+        });
+        let calibration: Calibration = serde_json::from_str(r#"{"rssi": -3.5}"#).unwrap();
+        assert_eq!(0.0, calibration.snr);
+        calibration.apply(&mut packet);
+        assert_eq!(-103.5, packet.signal_strength);
+        assert_eq!(5.5, packet.snr);
+    }
+}
@@ -0,0 +1,409 @@
+//! chirpstack-concentratord packet forwarder backend.
+//!
+//! Gateways running chirpstack-concentratord can use it directly over its
+//! ZeroMQ sockets instead of a Semtech UDP packet forwarder bridge. Uplinks
+//! are published on the event socket as "up" events with a protobuf encoded
+//! uplink frame and are handed to the gateway like packet forwarder uplinks.
+//! Downlinks and beacons are sent to the command socket as "down" commands,
+//! which concentratord answers with a transmit acknowledgement.
+//!
+//! Downlinks are built as packet forwarder transmit packets and converted to
+//! concentratord downlink items. The rx1 and rx2 windows of a downlink are
+//! sent as separate items of one downlink and concentratord transmits the
+//! first item it can schedule. A concentrator timestamp is sent as the uplink
+//! context concentratord expects for delayed transmissions, without a delay.
+
+use crate::{
+    error::DecodeError,
+    gateway::{GatewayError, DOWNLINK_TIMEOUT},
+    settings::ForwarderSettings,
+    Error, Result,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use beacon::LoraDataRate;
+use bytes::Bytes;
+use chirpstack_api::{
+    gw::{
+        modulation, timing, CodeRate, CrcStatus, DelayTimingInfo, DownlinkFrame, DownlinkFrameItem,
+        DownlinkTxAck, DownlinkTxAckItem, DownlinkTxInfo, ImmediatelyTimingInfo,
+        LoraModulationInfo, Modulation, Timing, TxAckStatus, UplinkFrame,
+    },
+    prost::Message,
+};
+use semtech_udp::{pull_resp, push_data};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, time};
+use zeromq::{ReqSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqError, ZmqMessage};
+
+const UP_EVENT: &str = "up";
+const DOWN_COMMAND: &str = "down";
+const UPLINK_QUEUE: usize = 20;
+/// Wait before reconnecting to the event socket, like while concentratord
+/// restarts
+const RECONNECT_WAIT: Duration = Duration::from_secs(5);
+
+pub struct Concentratord {
+    event_url: String,
+    uplinks: mpsc::Receiver<push_data::RxPk>,
+    /// Taken by the event task when started
+    uplink_tx: Option<mpsc::Sender<push_data::RxPk>>,
+    sender: Sender,
+}
+
+impl Concentratord {
+    pub fn new(settings: &ForwarderSettings) -> Self {
+        let (uplink_tx, uplinks) = mpsc::channel(UPLINK_QUEUE);
+        Self {
+            event_url: settings.event_url.clone(),
+            uplinks,
+            uplink_tx: Some(uplink_tx),
+            sender: Sender {
+                command_url: settings.command_url.as_str().into(),
+                downlink_ids: Arc::new(AtomicU32::new(rand::random())),
+            },
+        }
+    }
+
+    pub fn event_url(&self) -> &str {
+        &self.event_url
+    }
+
+    /// Starts receiving uplinks from the event socket until shutdown
+    pub fn start(&mut self, shutdown: &triggered::Listener, logger: &Logger) {
+        let Some(uplinks) = self.uplink_tx.take() else {
+            return;
+        };
+        let logger = logger.new(o!("backend" => "concentratord"));
+        let event_url = self.event_url.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown => (),
+                _ = subscribe(&event_url, &uplinks, &logger) => (),
+            }
+        });
+    }
+
+    pub async fn recv(&mut self) -> Option<push_data::RxPk> {
+        self.uplinks.recv().await
+    }
+
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+}
+
+/// Receives uplinks from the event socket, reconnecting when it fails
+async fn subscribe(url: &str, uplinks: &mpsc::Sender<push_data::RxPk>, logger: &Logger) {
+    loop {
+        if let Err(err) = receive(url, uplinks, logger).await {
+            warn!(logger, "concentratord events failed: {err}"; "url" => url);
+        }
+        if uplinks.is_closed() {
+            return;
+        }
+        time::sleep(RECONNECT_WAIT).await;
+    }
+}
+
+async fn receive(url: &str, uplinks: &mpsc::Sender<push_data::RxPk>, logger: &Logger) -> Result {
+    let mut socket = SubSocket::new();
+    socket.connect(url).await.map_err(zmq_error)?;
+    socket.subscribe(UP_EVENT).await.map_err(zmq_error)?;
+    info!(logger, "receiving concentratord events"; "url" => url);
+    loop {
+        let message = socket.recv().await.map_err(zmq_error)?;
+        let (Some(event), Some(payload)) = (message.get(0), message.get(1)) else {
+            continue;
+        };
+        if event.as_ref() != UP_EVENT.as_bytes() {
+            continue;
+        }
+        let rxpk = UplinkFrame::decode(payload.as_ref())
+            .ok()
+            .and_then(|frame| rxpk(&frame));
+        match rxpk {
+            Some(rxpk) => {
+                if uplinks.send(rxpk).await.is_err() {
+                    return Ok(());
+                }
+            }
+            None => debug!(logger, "ignoring concentratord uplink"),
+        }
+    }
+}
+
+fn zmq_error(err: ZmqError) -> Error {
+    Error::custom(format!("zeromq: {err}"))
+}
+
+/// The packet forwarder uplink of a concentratord uplink frame. Only LoRa
+/// uplinks with a concentrator timestamp are used.
+fn rxpk(frame: &UplinkFrame) -> Option<push_data::RxPk> {
+    let tx_info = frame.tx_info.as_ref()?;
+    let rx_info = frame.rx_info.as_ref()?;
+    let Some(modulation::Parameters::Lora(lora)) = tx_info.modulation.as_ref()?.parameters.as_ref()
+    else {
+        return None;
+    };
+    let datarate = LoraDataRate::new(
+        u8::try_from(lora.spreading_factor).ok()?,
+        lora.bandwidth / 1000,
+    )
+    .ok()?;
+    // The context of an uplink starts with the concentrator counter
+    let tmst = u32::from_be_bytes(rx_info.context.get(..4)?.try_into().ok()?);
+    let stat = match rx_info.crc_status() {
+        CrcStatus::CrcOk => 1,
+        CrcStatus::BadCrc => -1,
+        CrcStatus::NoCrc => 0,
+    };
+    let rxpk = json!({
+        "tmst": tmst,
+        "chan": rx_info.channel,
+        "rfch": rx_info.rf_chain,
+        "freq": tx_info.frequency as f64 / 1e6,
+        "stat": stat,
+        "modu": "LORA",
+        "datr": datarate.to_string(),
+        "codr": coding_rate(lora.code_rate()),
+        "rssi": rx_info.rssi,
+        "lsnr": rx_info.snr,
+        "size": frame.phy_payload.len(),
+        "data": STANDARD.encode(&frame.phy_payload),
+    });
+    serde_json::from_value(rxpk).ok()
+}
+
+fn coding_rate(code_rate: CodeRate) -> &'static str {
+    match code_rate {
+        CodeRate::Cr46 => "4/6",
+        CodeRate::Cr47 => "4/7",
+        CodeRate::Cr48 => "4/8",
+        _ => "4/5",
+    }
+}
+
+fn code_rate(coding_rate: &str) -> CodeRate {
+    match coding_rate {
+        "4/6" => CodeRate::Cr46,
+        "4/7" => CodeRate::Cr47,
+        "4/8" => CodeRate::Cr48,
+        _ => CodeRate::Cr45,
+    }
+}
+
+/// The fields of a packet forwarder transmit packet concentratord needs
+#[derive(Debug, Deserialize)]
+struct TxPk {
+    #[serde(default)]
+    imme: bool,
+    tmst: Option<u32>,
+    /// The frequency in MHz
+    freq: f64,
+    powe: i32,
+    datr: String,
+    codr: String,
+    #[serde(default)]
+    ipol: bool,
+    data: String,
+}
+
+/// The concentratord downlink item of a packet forwarder transmit packet
+fn downlink_item(txpk: &pull_resp::TxPk) -> Result<DownlinkFrameItem> {
+    let txpk: TxPk = serde_json::from_value(serde_json::to_value(txpk)?)?;
+    let datarate: LoraDataRate = txpk.datr.parse()?;
+    let (timing, context) = match txpk.tmst {
+        Some(tmst) if !txpk.imme => (
+            timing::Parameters::Delay(DelayTimingInfo {
+                delay: Some(Default::default()),
+            }),
+            tmst.to_be_bytes().to_vec(),
+        ),
+        _ => (
+            timing::Parameters::Immediately(ImmediatelyTimingInfo {}),
+            vec![],
+        ),
+    };
+    Ok(DownlinkFrameItem {
+        phy_payload: STANDARD.decode(&txpk.data)?,
+        tx_info: Some(DownlinkTxInfo {
+            frequency: (txpk.freq * 1e6).round() as u32,
+            power: txpk.powe,
+            modulation: Some(Modulation {
+                parameters: Some(modulation::Parameters::Lora(LoraModulationInfo {
+                    bandwidth: datarate.bandwidth() * 1000,
+                    spreading_factor: datarate.spreading() as u32,
+                    code_rate: code_rate(&txpk.codr) as i32,
+                    polarization_inversion: txpk.ipol,
+                    ..Default::default()
+                })),
+            }),
+            timing: Some(Timing {
+                parameters: Some(timing),
+            }),
+            context,
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Sends downlinks to the concentratord command socket
+#[derive(Debug, Clone)]
+pub struct Sender {
+    command_url: Arc<str>,
+    downlink_ids: Arc<AtomicU32>,
+}
+
+impl Sender {
+    /// Transmits the first of the given packets concentratord can schedule
+    /// and returns its index
+    pub async fn transmit(&self, txpks: &[pull_resp::TxPk]) -> Result<usize> {
+        let frame = DownlinkFrame {
+            downlink_id: self.downlink_ids.fetch_add(1, Ordering::Relaxed),
+            items: txpks.iter().map(downlink_item).collect::<Result<_>>()?,
+            ..Default::default()
+        };
+        let reply = self.command(DOWN_COMMAND, frame.encode_to_vec()).await?;
+        let ack = DownlinkTxAck::decode(reply.as_ref())
+            .map_err(|_| DecodeError::prost_decode("concentratord tx ack"))?;
+        if let Some(index) = ack
+            .items
+            .iter()
+            .position(|item| item.status() == TxAckStatus::Ok)
+        {
+            return Ok(index);
+        }
+        // Items after the last one tried are ignored
+        let status = ack
+            .items
+            .iter()
+            .map(DownlinkTxAckItem::status)
+            .filter(|status| *status != TxAckStatus::Ignored)
+            .last()
+            .unwrap_or(TxAckStatus::Ignored);
+        Err(GatewayError::ConcentratordTx(status).into())
+    }
+
+    /// Sends a command and returns the reply. Every command uses its own
+    /// request socket so a command that times out does not block later ones.
+    async fn command(&self, command: &'static str, payload: Vec<u8>) -> Result<Bytes> {
+        let request = async {
+            let mut socket = ReqSocket::new();
+            socket.connect(&self.command_url).await?;
+            let mut message = ZmqMessage::from(command);
+            message.push_back(payload.into());
+            socket.send(message).await?;
+            socket.recv().await
+        };
+        let reply = time::timeout(DOWNLINK_TIMEOUT, request)
+            .await
+            .map_err(|_| Error::timeout())?
+            .map_err(zmq_error)?;
+        reply
+            .into_vec()
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::custom("empty concentratord reply"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chirpstack_api::gw::{UplinkRxInfo, UplinkTxInfo};
+    use semtech_udp::{pull_resp::Time, CodingRate, Modulation as SemtechModulation};
+
+    #[test]
+    fn uplinks() {
+        let frame = UplinkFrame {
+            phy_payload: vec![0x40, 1, 2, 3, 4],
+            tx_info: Some(UplinkTxInfo {
+                frequency: 868_100_000,
+                modulation: Some(Modulation {
+                    parameters: Some(modulation::Parameters::Lora(LoraModulationInfo {
+                        bandwidth: 125_000,
+                        spreading_factor: 9,
+                        code_rate: CodeRate::Cr45 as i32,
+                        ..Default::default()
+                    })),
+                }),
+            }),
+            rx_info: Some(UplinkRxInfo {
+                rssi: -80,
+                snr: 7.5,
+                channel: 2,
+                context: 1_000_000u32.to_be_bytes().to_vec(),
+                crc_status: CrcStatus::CrcOk as i32,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let rxpk = rxpk(&frame).expect("rxpk");
+        assert_eq!(1_000_000, *rxpk.get_timestamp());
+        assert_eq!(868.1, *rxpk.get_frequency());
+        assert_eq!("SF9BW125", rxpk.get_datarate().to_string());
+        assert_eq!(&push_data::CRC::OK, rxpk.get_crc_status());
+        assert_eq!(&frame.phy_payload, rxpk.get_data());
+
+        // Without a concentrator timestamp downlinks can not be scheduled
+        let mut frame = frame;
+        frame.rx_info.as_mut().unwrap().context.clear();
+        assert!(rxpk(&frame).is_none());
+    }
+
+    #[test]
+    fn downlinks() {
+        let txpk = |time| pull_resp::TxPk {
+            time,
+            ipol: true,
+            modu: SemtechModulation::LORA,
+            codr: CodingRate::_4_5,
+            datr: "SF12BW125".parse().expect("datarate"),
+            freq: 869.525,
+            data: pull_resp::PhyData::new(vec![0x60, 1, 2, 3]),
+            powe: 27,
+            rfch: 0,
+            fdev: None,
+            prea: None,
+            ncrc: None,
+        };
+        let item = downlink_item(&txpk(Time::by_tmst(5_000_000))).expect("item");
+        let tx_info = item.tx_info.expect("tx info");
+        assert_eq!(vec![0x60, 1, 2, 3], item.phy_payload);
+        assert_eq!(869_525_000, tx_info.frequency);
+        assert_eq!(27, tx_info.power);
+        assert_eq!(5_000_000u32.to_be_bytes().to_vec(), tx_info.context);
+        assert!(matches!(
+            tx_info.timing.and_then(|timing| timing.parameters),
+            Some(timing::Parameters::Delay(_))
+        ));
+        let Some(modulation::Parameters::Lora(lora)) = tx_info
+            .modulation
+            .and_then(|modulation| modulation.parameters)
+        else {
+            panic!("lora modulation");
+        };
+        assert_eq!(125_000, lora.bandwidth);
+        assert_eq!(12, lora.spreading_factor);
+        assert!(lora.polarization_inversion);
+
+        let item = downlink_item(&txpk(Time::immediate())).expect("item");
+        let tx_info = item.tx_info.expect("tx info");
+        assert!(tx_info.context.is_empty());
+        assert!(matches!(
+            tx_info.timing.and_then(|timing| timing.parameters),
+            Some(timing::Parameters::Immediately(_))
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+//! CRC-failed uplinks kept for diagnostics.
+//!
+//! Frames that fail the CRC check can not be routed and are dropped. A steady
+//! stream of them on one channel points at interference, on all channels at
+//! the antenna or a noisy site. With `crc_failed.keep` set the last
+//! `CAPACITY` CRC-failed frames are kept with their radio metadata and counted
+//! per channel in the `uplink_crc_failed_total` metric, and with
+//! `crc_failed.stream` set they are streamed as they arrive by the local API.
+
+use crate::{clock::unix_now_millis, metrics, settings::CrcFailedSettings};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use semtech_udp::{
+    push_data::{self, CRC},
+    MacAddress,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Counter of CRC-failed frames labeled with the channel `frequency` in Hz
+pub const METRIC: &str = "uplink_crc_failed_total";
+
+/// The number of recent CRC-failed frames kept
+const CAPACITY: usize = 100;
+/// The number of frames a stream subscriber can fall behind
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// A received frame with a CRC error
+#[derive(Debug, Clone, Serialize)]
+pub struct CrcFailedFrame {
+    /// Unix time (milliseconds) the frame was received
+    pub time: u64,
+    /// The gateway EUI of the packet forwarder
+    pub mac: String,
+    /// The channel frequency in Hz
+    pub frequency: u64,
+    pub datarate: String,
+    pub rssi: f64,
+    pub snr: f64,
+    /// The concentrator counter value
+    pub tmst: u32,
+    /// The base64 encoded payload as received
+    pub payload: String,
+}
+
+/// The kept CRC-failed frames for the local API
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CrcFailedStatus {
+    pub keep: bool,
+    pub stream: bool,
+    /// The number of CRC-failed frames by channel frequency in Hz
+    pub channels: BTreeMap<u64, u64>,
+    /// The recent frames, oldest first
+    pub frames: Vec<CrcFailedFrame>,
+}
+
+#[derive(Debug)]
+struct Buffer {
+    keep: bool,
+    stream: bool,
+    channels: BTreeMap<u64, u64>,
+    frames: VecDeque<CrcFailedFrame>,
+    sender: broadcast::Sender<CrcFailedFrame>,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            keep: false,
+            stream: false,
+            channels: BTreeMap::new(),
+            frames: VecDeque::with_capacity(CAPACITY),
+            sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+        }
+    }
+}
+
+impl Buffer {
+    fn push(&mut self, frame: CrcFailedFrame) {
+        *self.channels.entry(frame.frequency).or_default() += 1;
+        if self.frames.len() >= CAPACITY {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.clone());
+        if self.stream {
+            // No subscribers is not an error
+            let _ = self.sender.send(frame);
+        }
+    }
+}
+
+/// Keeps the CRC-failed frames for the local API. Clones share the same
+/// frames.
+#[derive(Debug, Clone, Default)]
+pub struct CrcFailedHandle(Arc<Mutex<Buffer>>);
+
+impl CrcFailedHandle {
+    /// Frames are only kept with `keep` set in the settings
+    pub fn new(settings: &CrcFailedSettings) -> Self {
+        Self(Arc::new(Mutex::new(Buffer {
+            keep: settings.keep,
+            stream: settings.keep && settings.stream,
+            ..Default::default()
+        })))
+    }
+
+    fn with_buffer<T>(&self, f: impl FnOnce(&mut Buffer) -> T) -> T {
+        f(&mut self.0.lock().expect("crc failed lock"))
+    }
+
+    /// Keeps a received frame of the forwarder with the given MAC if it
+    /// failed the CRC check and keeping is enabled
+    pub fn record(&self, mac: MacAddress, rxpk: &push_data::RxPk) {
+        if rxpk.get_crc_status() != &CRC::Fail || !self.with_buffer(|buffer| buffer.keep) {
+            return;
+        }
+        let frame = CrcFailedFrame {
+            time: unix_now_millis(),
+            mac: mac.to_string(),
+            frequency: (*rxpk.get_frequency() * 1_000_000.0).round() as u64,
+            datarate: rxpk.get_datarate().to_string(),
+            rssi: rxpk
+                .get_signal_rssi()
+                .unwrap_or_else(|| rxpk.get_channel_rssi()) as f64,
+            snr: rxpk.get_snr() as f64,
+            tmst: *rxpk.get_timestamp(),
+            payload: STANDARD.encode(rxpk.get_data()),
+        };
+        metrics::increment(&metrics::labeled(
+            METRIC,
+            &[("frequency", &frame.frequency.to_string())],
+        ));
+        self.with_buffer(|buffer| buffer.push(frame))
+    }
+
+    /// Returns the per channel counts and the kept frames
+    pub fn snapshot(&self) -> CrcFailedStatus {
+        self.with_buffer(|buffer| CrcFailedStatus {
+            keep: buffer.keep,
+            stream: buffer.stream,
+            channels: buffer.channels.clone(),
+            frames: buffer.frames.iter().cloned().collect(),
+        })
+    }
+
+    /// Returns a receiver for the CRC-failed frames received from now on,
+    /// None when streaming is not enabled
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<CrcFailedFrame>> {
+        self.with_buffer(|buffer| buffer.stream.then(|| buffer.sender.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(frequency: u64) -> CrcFailedFrame {
+        CrcFailedFrame {
+            time: 0,
+            mac: "aa555a0000000001".to_string(),
+            frequency,
+            datarate: "SF7BW125".to_string(),
+            rssi: -110.0,
+            snr: -12.5,
+            tmst: 0,
+            payload: String::new(),
+        }
+    }
+
+    #[test]
+    fn keeps() {
+        let mut buffer = Buffer {
+            keep: true,
+            stream: true,
+            ..Default::default()
+        };
+        let mut frames = buffer.sender.subscribe();
+        for _ in 0..CAPACITY {
+            buffer.push(frame(868_100_000));
+        }
+        buffer.push(frame(868_300_000));
+        assert_eq!(CAPACITY, buffer.frames.len());
+        assert_eq!(
+            Some(868_300_000),
+            buffer.frames.back().map(|frame| frame.frequency)
+        );
+        assert_eq!(Some(&(CAPACITY as u64)), buffer.channels.get(&868_100_000));
+        assert_eq!(Some(&1), buffer.channels.get(&868_300_000));
+        assert_eq!(868_100_000, frames.try_recv().expect("frame").frequency);
+    }
+}
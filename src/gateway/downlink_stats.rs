@@ -0,0 +1,209 @@
+//! Downlink transmit outcomes.
+//!
+//! Every downlink received from a router is either transmitted in one of its
+//! receive windows or dropped for a gateway side reason. Outcomes are counted
+//! in the metrics registry and the most recent drops are kept with the
+//! forwarder detail so LNS operators can find out why a device missed a
+//! downlink. The packet router envelope has no message for downlink results,
+//! so the reasons are only available locally.
+
+use crate::{
+    clock::unix_now_millis,
+    logging, metrics,
+    server::milestones::{Milestone, MilestonesHandle},
+};
+use serde::Serialize;
+use slog::{info, Level, Logger};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of downlink outcomes labeled with `outcome` and `reason`
+pub const OUTCOME_METRIC: &str = "downlink_outcomes_total";
+
+/// The number of recent dropped downlinks kept for the stats API
+const RECENT_DROPS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Transmitted as requested
+    Sent,
+    /// Transmitted, but the forwarder lowered the transmit power
+    PowerClamped,
+    Dropped(DropReason),
+}
+
+/// Why a downlink was not transmitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// No transmit power is known since there are no region params yet
+    NoTxPower,
+    /// The downlink could not be converted for the packet forwarder
+    Encode,
+    /// The forwarder rejected the downlink as too early or too late for its
+    /// last receive window
+    WindowMissed,
+    /// The forwarder rejected the downlink for another reason, like a
+    /// collision, an unsupported frequency or transmit power
+    ForwarderNack,
+    /// The forwarder did not acknowledge the downlink or is not connected
+    Forwarder,
+    /// The gateway is outside the territory of its region
+    Geofence,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NoTxPower => "no_tx_power",
+            Self::Encode => "encode",
+            Self::WindowMissed => "window_missed",
+            Self::ForwarderNack => "forwarder_nack",
+            Self::Forwarder => "forwarder",
+            Self::Geofence => "geofence",
+        }
+    }
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::PowerClamped => "power_clamped",
+            Self::Dropped(_) => "dropped",
+        }
+    }
+
+    pub fn reason(&self) -> Option<DropReason> {
+        match self {
+            Self::Dropped(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason() {
+            Some(reason) => write!(f, "{}:{}", self.as_str(), reason.as_str()),
+            None => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// A recorded dropped downlink
+#[derive(Debug, Clone, Serialize)]
+pub struct DropRecord {
+    /// Unix time (milliseconds) the drop was recorded
+    pub time: u64,
+    /// The last receive window that was tried, if any
+    pub window: Option<&'static str>,
+    pub reason: &'static str,
+    /// The forwarder or conversion error
+    pub detail: Option<String>,
+}
+
+/// Outcome counts since startup and the most recent dropped downlinks
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DownlinkStats {
+    pub counts: BTreeMap<String, u64>,
+    pub recent_drops: VecDeque<DropRecord>,
+}
+
+/// Records downlink outcomes. Clones share the same stats.
+#[derive(Debug, Clone, Default)]
+pub struct DownlinkStatsHandle {
+    stats: Arc<Mutex<DownlinkStats>>,
+    milestones: MilestonesHandle,
+}
+
+impl DownlinkStatsHandle {
+    /// Stats that reach the first transmitted downlink milestone
+    pub fn new(milestones: MilestonesHandle) -> Self {
+        Self {
+            stats: Default::default(),
+            milestones,
+        }
+    }
+
+    /// Records the final outcome of a downlink. The window is the last
+    /// receive window that was tried and the detail the error that caused a
+    /// drop.
+    pub fn record(
+        &self,
+        logger: &Logger,
+        outcome: Outcome,
+        window: Option<&'static str>,
+        detail: Option<String>,
+    ) {
+        let reason = outcome.reason().map(|reason| reason.as_str());
+        if let Some(reason) = reason {
+            if let Some(suppressed) = logging::sampling::sample(Level::Info, "downlink_drop") {
+                info!(logger, "downlink dropped";
+                    "reason" => reason,
+                    "window" => window,
+                    "detail" => &detail,
+                    "suppressed" => suppressed);
+            }
+        }
+        metrics::increment(&metrics::labeled(
+            OUTCOME_METRIC,
+            &[
+                ("outcome", outcome.as_str()),
+                ("reason", reason.unwrap_or("")),
+            ],
+        ));
+        if reason.is_none() {
+            self.milestones
+                .reach(logger, Milestone::DownlinkTransmitted);
+        }
+
+        let mut stats = self.stats.lock().expect("downlink stats lock");
+        *stats.counts.entry(outcome.to_string()).or_default() += 1;
+        if let Some(reason) = reason {
+            if stats.recent_drops.len() >= RECENT_DROPS {
+                stats.recent_drops.pop_front();
+            }
+            stats.recent_drops.push_back(DropRecord {
+                time: unix_now_millis(),
+                window,
+                reason,
+                detail,
+            });
+        }
+    }
+
+    /// Returns the outcome counts and the most recent dropped downlinks
+    pub fn snapshot(&self) -> DownlinkStats {
+        self.stats.lock().expect("downlink stats lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outcomes() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let stats = DownlinkStatsHandle::default();
+        stats.record(&logger, Outcome::Sent, Some("rx1"), None);
+        stats.record(
+            &logger,
+            Outcome::Dropped(DropReason::WindowMissed),
+            Some("rx2"),
+            Some("TooLate".to_string()),
+        );
+
+        let stats = stats.snapshot();
+        assert_eq!(1, stats.counts["sent"]);
+        assert_eq!(1, stats.counts["dropped:window_missed"]);
+        assert!(stats.recent_drops.iter().any(|record| {
+            record.reason == "window_missed"
+                && record.window == Some("rx2")
+                && record.detail.as_deref() == Some("TooLate")
+        }));
+    }
+}
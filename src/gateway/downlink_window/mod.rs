@@ -0,0 +1,228 @@
+//! RX1 downlink timing tolerance.
+//!
+//! An RX1 downlink has to reach the packet forwarder some time before its
+//! transmit time or the forwarder rejects it as TOO_LATE, by which time the
+//! RX2 window may have passed as well. The gateway estimates the concentrator
+//! counter from recent uplinks and sends downlinks that would not arrive
+//! within the margin straight to RX2. With auto tuning the margin follows the
+//! TOO_LATE rate of dispatched RX1 downlinks: it grows while too many are
+//! late and shrinks back while none are.
+//!
+//! A packet forwarder on another host adds backhaul latency twice: uplinks,
+//! and with them the counter estimate, arrive late and downlinks take as long
+//! to reach the forwarder. With RTT compensation the round trip time to the
+//! forwarder is added to the margin. The UDP runtime answers PULL_DATA itself,
+//! so the round trip is measured from PULL_RESP to TX_ACK of dispatched
+//! downlinks, which cross the same path.
+//!
+//! The window math is exercised against a simulated packet forwarder in the
+//! `sim` test harness.
+
+use crate::{metrics, settings::DownlinkSettings};
+use semtech_udp::tx_ack::Error as TxAckErr;
+use std::time::Duration;
+
+#[cfg(test)]
+mod sim;
+
+/// Gauge with the current RX1 margin in milliseconds
+pub const MARGIN_METRIC: &str = "downlink_rx1_margin_ms";
+/// Counter of RX1 downlinks sent to RX2 because they would have been late
+pub const SKIPPED_METRIC: &str = "downlink_rx1_skipped_total";
+/// Gauge with the moving average packet forwarder round trip time in
+/// milliseconds
+pub const RTT_METRIC: &str = "downlink_forwarder_rtt_ms";
+
+/// The number of RX1 outcomes the TOO_LATE rate is evaluated over
+const TUNE_WINDOW: u32 = 20;
+/// The TOO_LATE rate above which the margin is increased
+const MAX_TOO_LATE_RATE: f64 = 0.05;
+/// The margin adjustment in milliseconds
+const TUNE_STEP_MS: u64 = 10;
+/// The weight of a new round trip time in the moving average
+const RTT_WEIGHT: f64 = 0.2;
+/// Round trip times above this are ignored as forwarder stalls rather than
+/// backhaul latency
+const MAX_RTT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct Rx1Tolerance {
+    margin_ms: u64,
+    min_ms: u64,
+    max_ms: u64,
+    auto_tune: bool,
+    dispatched: u32,
+    too_late: u32,
+    rtt_compensation: bool,
+    /// Moving average packet forwarder round trip time in microseconds
+    rtt_us: Option<f64>,
+}
+
+impl Rx1Tolerance {
+    pub fn new(settings: &DownlinkSettings) -> Self {
+        let min_ms = settings.rx1_margin_min.min(settings.rx1_margin_max);
+        let tolerance = Self {
+            margin_ms: settings.rx1_margin.clamp(min_ms, settings.rx1_margin_max),
+            min_ms,
+            max_ms: settings.rx1_margin_max,
+            auto_tune: settings.auto_tune,
+            dispatched: 0,
+            too_late: 0,
+            rtt_compensation: settings.rtt_compensation,
+            rtt_us: None,
+        };
+        metrics::set(MARGIN_METRIC, tolerance.margin_ms as f64);
+        tolerance
+    }
+
+    pub fn margin(&self) -> Duration {
+        Duration::from_millis(self.margin_ms)
+    }
+
+    /// The round trip time to the packet forwarder added to the margin, zero
+    /// without RTT compensation or measurements
+    pub fn rtt(&self) -> Duration {
+        match self.rtt_us {
+            Some(rtt_us) if self.rtt_compensation => Duration::from_micros(rtt_us as u64),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Whether a downlink to be transmitted at concentrator counter `tx_tmst`
+    /// can still be scheduled when the counter is estimated at `now_tmst`
+    pub fn can_schedule(&self, tx_tmst: u32, now_tmst: u32) -> bool {
+        let remaining_us = tx_tmst.wrapping_sub(now_tmst) as i32 as i64;
+        remaining_us >= (self.margin() + self.rtt()).as_micros() as i64
+    }
+
+    /// Whether to send a downlink straight to its RX2 window. RX1 is only
+    /// skipped when there is an RX2 window to fall back to and the RX1 transmit
+    /// time can not be reached from the estimated concentrator counter.
+    pub fn skip_rx1(&self, rx1_tmst: u32, has_rx2: bool, now_tmst: Option<u32>) -> bool {
+        has_rx2 && now_tmst.is_some_and(|now_tmst| !self.can_schedule(rx1_tmst, now_tmst))
+    }
+
+    /// Records the time from dispatching a downlink to its TX_ACK
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        if rtt > MAX_RTT {
+            return;
+        }
+        let sample = rtt.as_micros() as f64;
+        let rtt_us = self
+            .rtt_us
+            .map_or(sample, |average| average + RTT_WEIGHT * (sample - average));
+        self.rtt_us = Some(rtt_us);
+        metrics::set(RTT_METRIC, rtt_us / 1000.0);
+    }
+
+    /// Records the outcome of a dispatched RX1 downlink. Returns the new
+    /// margin when it was tuned.
+    pub fn record(&mut self, too_late: bool) -> Option<Duration> {
+        if !self.auto_tune {
+            return None;
+        }
+        self.dispatched += 1;
+        if too_late {
+            self.too_late += 1;
+        }
+        if self.dispatched < TUNE_WINDOW {
+            return None;
+        }
+        let rate = self.too_late as f64 / self.dispatched as f64;
+        let margin_ms = if rate > MAX_TOO_LATE_RATE {
+            (self.margin_ms + TUNE_STEP_MS).min(self.max_ms)
+        } else if self.too_late == 0 {
+            self.margin_ms.saturating_sub(TUNE_STEP_MS).max(self.min_ms)
+        } else {
+            self.margin_ms
+        };
+        self.dispatched = 0;
+        self.too_late = 0;
+        if margin_ms == self.margin_ms {
+            return None;
+        }
+        self.margin_ms = margin_ms;
+        metrics::set(MARGIN_METRIC, margin_ms as f64);
+        Some(self.margin())
+    }
+}
+
+/// Whether an RX1 downlink the forwarder rejected with the given error is
+/// retried in the RX2 window
+pub fn retry_in_rx2(err: &TxAckErr) -> bool {
+    matches!(err, TxAckErr::TooEarly | TxAckErr::TooLate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> DownlinkSettings {
+        DownlinkSettings {
+            rx1_margin: 40,
+            auto_tune: true,
+            rx1_margin_min: 30,
+            rx1_margin_max: 50,
+            rtt_compensation: true,
+        }
+    }
+
+    #[test]
+    fn schedule() {
+        let tolerance = Rx1Tolerance::new(&settings());
+        assert!(tolerance.can_schedule(1_000_000, 900_000));
+        assert!(tolerance.can_schedule(1_000_000, 960_000));
+        assert!(!tolerance.can_schedule(1_000_000, 970_000));
+        // Already passed
+        assert!(!tolerance.can_schedule(1_000_000, 1_200_000));
+        // Across a counter wrap
+        assert!(tolerance.can_schedule(50_000, u32::MAX - 50_000));
+    }
+
+    #[test]
+    fn rtt() {
+        let mut tolerance = Rx1Tolerance::new(&settings());
+        tolerance.record_rtt(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), tolerance.rtt());
+        // Stalls are not taken as round trip times
+        tolerance.record_rtt(Duration::from_secs(3));
+        tolerance.record_rtt(Duration::from_millis(50));
+        assert_eq!(Duration::from_millis(90), tolerance.rtt());
+        assert!(tolerance.can_schedule(1_000_000, 870_000));
+        assert!(!tolerance.can_schedule(1_000_000, 900_000));
+
+        let mut uncompensated = Rx1Tolerance::new(&DownlinkSettings {
+            rtt_compensation: false,
+            ..settings()
+        });
+        uncompensated.record_rtt(Duration::from_millis(100));
+        assert_eq!(Duration::ZERO, uncompensated.rtt());
+        assert!(uncompensated.can_schedule(1_000_000, 900_000));
+    }
+
+    #[test]
+    fn tune() {
+        let mut tolerance = Rx1Tolerance::new(&settings());
+        let window = |tolerance: &mut Rx1Tolerance, late: u32| {
+            (0..TUNE_WINDOW)
+                .map(|n| tolerance.record(n < late))
+                .last()
+                .flatten()
+        };
+        assert_eq!(Some(Duration::from_millis(50)), window(&mut tolerance, 5));
+        // Bounded by the configured maximum
+        assert_eq!(None, window(&mut tolerance, 5));
+        // A low but non zero rate keeps the margin
+        assert_eq!(None, window(&mut tolerance, 1));
+        assert_eq!(Some(Duration::from_millis(40)), window(&mut tolerance, 0));
+        assert_eq!(Some(Duration::from_millis(30)), window(&mut tolerance, 0));
+        assert_eq!(None, window(&mut tolerance, 0));
+
+        let mut fixed = Rx1Tolerance::new(&DownlinkSettings {
+            auto_tune: false,
+            ..settings()
+        });
+        assert_eq!(None, window(&mut fixed, 20));
+        assert_eq!(Duration::from_millis(40), fixed.margin());
+    }
+}
@@ -0,0 +1,262 @@
+//! A deterministic downlink simulation for the RX window math.
+//!
+//! Window math bugs otherwise only show with real radios. The simulated packet
+//! forwarder runs a concentrator counter off a simulated host clock, with a
+//! configurable latency in both directions, and accepts or rejects downlinks
+//! by their transmit time like a real forwarder. Router downlinks are
+//! scheduled the way the gateway does: RX1 is skipped when the counter
+//! estimate says it can no longer be reached, and a rejected RX1 downlink is
+//! retried in RX2.
+
+use super::{retry_in_rx2, Rx1Tolerance};
+use crate::{clock::ForwarderClock, settings::DownlinkSettings, Packet};
+use helium_proto::packet::PacketType;
+use semtech_udp::{pull_resp::TxPk, tx_ack::Error as TxAckErr};
+use std::time::{Duration, Instant};
+
+/// The minimum time a downlink has to reach the simulated forwarder before
+/// its transmit time
+const MIN_LEAD_US: i64 = 30_000;
+/// The maximum time a downlink can reach the simulated forwarder before its
+/// transmit time
+const MAX_ADVANCE_US: i64 = 3_000_000;
+
+/// A packet forwarder with a concentrator counter started at `start`
+struct SimForwarder {
+    start: Instant,
+    /// The counter value at `start`
+    start_tmst: u32,
+    /// Latency from the forwarder to the gateway
+    uplink_latency: Duration,
+    /// Latency from the gateway to the forwarder
+    downlink_latency: Duration,
+}
+
+impl SimForwarder {
+    fn tmst(&self, at: Instant) -> u32 {
+        let elapsed = at.saturating_duration_since(self.start).as_micros() as u32;
+        self.start_tmst.wrapping_add(elapsed)
+    }
+
+    /// The TX_ACK of a downlink sent by the gateway at host instant `sent`
+    fn transmit(&self, txpk: &TxPk, sent: Instant) -> Result<(), TxAckErr> {
+        let arrived = self.tmst(sent + self.downlink_latency);
+        let lead = tx_tmst(txpk).wrapping_sub(arrived) as i32 as i64;
+        if lead < MIN_LEAD_US {
+            Err(TxAckErr::TooLate)
+        } else if lead > MAX_ADVANCE_US {
+            Err(TxAckErr::TooEarly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The window a downlink was transmitted in, with its counter value
+#[derive(Debug, PartialEq, Eq)]
+enum Transmitted {
+    Rx1(u32),
+    Rx2(u32),
+    Missed,
+}
+
+/// The gateway side of the simulation
+struct Sim {
+    forwarder: SimForwarder,
+    clock: ForwarderClock,
+    tolerance: Rx1Tolerance,
+}
+
+impl Sim {
+    fn new(start: Instant, start_tmst: u32, latency: Duration) -> Self {
+        Self {
+            forwarder: SimForwarder {
+                start,
+                start_tmst,
+                uplink_latency: latency,
+                downlink_latency: latency,
+            },
+            clock: ForwarderClock::default(),
+            tolerance: Rx1Tolerance::new(&DownlinkSettings {
+                rx1_margin: 40,
+                auto_tune: false,
+                rx1_margin_min: 40,
+                rx1_margin_max: 40,
+                rtt_compensation: false,
+            }),
+        }
+    }
+
+    /// Receives an uplink at the forwarder at host instant `at` and returns
+    /// its counter value
+    fn uplink(&mut self, at: Instant) -> u32 {
+        let tmst = self.forwarder.tmst(at);
+        self.clock
+            .observe_tmst(tmst, at + self.forwarder.uplink_latency);
+        tmst
+    }
+
+    /// Schedules a router downlink that reaches the gateway at host instant
+    /// `at`
+    fn downlink(&mut self, downlink: &Packet, at: Instant) -> Transmitted {
+        let skip_rx1 = self.tolerance.skip_rx1(
+            downlink.timestamp as u32,
+            downlink.rx2_window.is_some(),
+            self.clock.estimate_tmst(at),
+        );
+        let mut sent = at;
+        if !skip_rx1 {
+            let txpk = downlink.to_rx1_pull_resp(27).expect("rx1 txpk");
+            // The next window can only be tried after the TX_ACK is back
+            sent += self.forwarder.downlink_latency + self.forwarder.uplink_latency;
+            match self.forwarder.transmit(&txpk, at) {
+                Ok(()) => return Transmitted::Rx1(tx_tmst(&txpk)),
+                Err(err) if retry_in_rx2(&err) => (),
+                Err(_) => return Transmitted::Missed,
+            }
+        }
+        match downlink.to_rx2_pull_resp(27).expect("rx2 txpk") {
+            Some(txpk) => match self.forwarder.transmit(&txpk, sent) {
+                Ok(()) => Transmitted::Rx2(tx_tmst(&txpk)),
+                Err(_) => Transmitted::Missed,
+            },
+            None => Transmitted::Missed,
+        }
+    }
+}
+
+/// The transmit counter value of a packet forwarder transmit packet
+fn tx_tmst(txpk: &TxPk) -> u32 {
+    serde_json::to_value(txpk).expect("txpk json")["tmst"]
+        .as_u64()
+        .expect("tmst") as u32
+}
+
+/// A router downlink answering the uplink at `uplink_tmst` after
+/// `rx_delay` seconds, with an RX2 window a second later when `rx2` is set.
+/// Routers add the delay to the 64 bit uplink timestamp, so the rx1 timestamp
+/// can go past 2^32.
+fn router_downlink(uplink_tmst: u32, rx_delay: u64, rx2: bool) -> Packet {
+    let rx1_timestamp = uplink_tmst as u64 + rx_delay * 1_000_000;
+    helium_proto::Packet {
+        oui: 0,
+        r#type: PacketType::Lorawan.into(),
+        payload: vec![0x60, 0x04, 0x03, 0x02, 0x01, 0x00, 0x00, 0x00],
+        timestamp: rx1_timestamp,
+        signal_strength: 0.0,
+        frequency: 868.1,
+        datarate: "SF7BW125".to_string(),
+        snr: 0.0,
+        routing: None,
+        rx2_window: rx2.then(|| helium_proto::Window {
+            timestamp: rx1_timestamp + 1_000_000,
+            frequency: 869.525,
+            datarate: "SF12BW125".to_string(),
+        }),
+    }
+    .into()
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rx1() {
+        let start = Instant::now();
+        let mut sim = Sim::new(start, 10_000_000, ms(5));
+        let uplink = sim.uplink(start + ms(100));
+        assert_eq!(10_100_000, uplink);
+        let downlink = router_downlink(uplink, 1, true);
+        assert_eq!(
+            Transmitted::Rx1(11_100_000),
+            sim.downlink(&downlink, start + ms(400))
+        );
+        // A longer receive delay
+        let downlink = router_downlink(uplink, 5, true);
+        assert_eq!(
+            Transmitted::Rx1(15_100_000),
+            sim.downlink(&downlink, start + ms(3000))
+        );
+    }
+
+    #[test]
+    fn rx2() {
+        let start = Instant::now();
+        let mut sim = Sim::new(start, 0, ms(5));
+        let uplink = sim.uplink(start);
+        // The estimate says RX1 can not be reached anymore
+        let downlink = router_downlink(uplink, 1, true);
+        assert_eq!(
+            Transmitted::Rx2(2_000_000),
+            sim.downlink(&downlink, start + ms(980))
+        );
+        // Without an RX2 window RX1 is tried regardless, and misses
+        let downlink = router_downlink(uplink, 1, false);
+        assert_eq!(
+            Transmitted::Missed,
+            sim.downlink(&downlink, start + ms(980))
+        );
+        // Both windows passed
+        let downlink = router_downlink(uplink, 1, true);
+        assert_eq!(
+            Transmitted::Missed,
+            sim.downlink(&downlink, start + ms(1990))
+        );
+    }
+
+    #[test]
+    fn wraparound() {
+        let start = Instant::now();
+        let mut sim = Sim::new(start, u32::MAX - 499_999, ms(5));
+        let uplink = sim.uplink(start + ms(100));
+        assert_eq!(u32::MAX - 399_999, uplink);
+        // The rx1 timestamp is past 2^32 and wraps
+        let downlink = router_downlink(uplink, 1, true);
+        assert!(downlink.timestamp > u32::MAX as u64);
+        assert_eq!(
+            Transmitted::Rx1(600_000),
+            sim.downlink(&downlink, start + ms(500))
+        );
+        // The counter wraps between the uplink and the late downlink
+        assert_eq!(
+            Transmitted::Rx2(1_600_000),
+            sim.downlink(&downlink, start + ms(1090))
+        );
+    }
+
+    #[test]
+    fn late_arrival() {
+        let start = Instant::now();
+        // The estimate trails the counter by the uplink latency, which the margin
+        // does not cover without RTT compensation
+        let mut sim = Sim::new(start, 0, ms(25));
+        let uplink = sim.uplink(start);
+        let downlink = router_downlink(uplink, 1, true);
+        assert!(!sim.tolerance.skip_rx1(
+            downlink.timestamp as u32,
+            true,
+            sim.clock.estimate_tmst(start + ms(960))
+        ));
+        // So RX1 is dispatched, rejected as too late and retried in RX2
+        assert_eq!(
+            Transmitted::Rx2(2_000_000),
+            sim.downlink(&downlink, start + ms(960))
+        );
+        // In time for RX1 with the latency
+        assert_eq!(
+            Transmitted::Rx1(1_000_000),
+            sim.downlink(&downlink, start + ms(900))
+        );
+        // Too far ahead for the forwarder in RX1 and in RX2
+        let downlink = router_downlink(uplink, 5, true);
+        assert_eq!(
+            Transmitted::Missed,
+            sim.downlink(&downlink, start + ms(100))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Packet forwarder acknowledgement statistics.
+//!
+//! The gateway acknowledges every PUSH_DATA datagram of the UDP packet
+//! forwarder with a PUSH_ACK. When those acks do not reach the forwarder, for
+//! example through a firewall or NAT that only passes one direction, uplinks
+//! still arrive but the forwarder considers the gateway dead, which shows up
+//! as mysteriously missing uplinks once it gives up or restarts. The forwarder
+//! reports the percentage of its upstream datagrams that were acknowledged in
+//! the `ackr` field of its periodic `stat` message. PULL_ACKs are not
+//! reported by the forwarder and a missing PULL_DATA keepalive can not be
+//! told apart from an idle forwarder, so only the PUSH_ACK ratio is tracked.
+//!
+//! Per forwarder the last and mean ack percentage and the streak of stat
+//! intervals without any acknowledged datagram are kept for the stats API and
+//! exported as metrics. When a streak reaches `DEAD_STREAK` intervals a
+//! warning is logged and an event published.
+
+use crate::{
+    clock::unix_now,
+    event_bus::{Event, EventBus},
+    metrics,
+};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Gauge of the last reported PUSH_ACK percentage labeled with `mac`
+pub const ACK_PERCENT_METRIC: &str = "forwarder_push_ack_percent";
+/// Gauge of the number of consecutive stat intervals without acks labeled with
+/// `mac`
+pub const MISSING_STREAK_METRIC: &str = "forwarder_missing_ack_streak";
+
+/// The number of consecutive stat intervals without acks after which the
+/// forwarder is considered to see the gateway as dead. The first stat after a
+/// forwarder start reports no acks since nothing was sent yet.
+pub const DEAD_STREAK: u32 = 3;
+
+/// Acknowledgement statistics of one packet forwarder
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ForwarderAcks {
+    /// The number of stat messages with an ack percentage received
+    pub stats: u64,
+    /// The last reported percentage of acknowledged upstream datagrams
+    pub ack_percent: f64,
+    /// The mean of the reported ack percentages
+    pub mean_ack_percent: f64,
+    /// Consecutive stat intervals in which no datagram was acknowledged
+    pub missing_streak: u32,
+    pub longest_missing_streak: u32,
+    /// Unix time (seconds) of the last stat message
+    pub last_stat: u64,
+}
+
+impl ForwarderAcks {
+    /// Adds a reported ack percentage, returns whether the streak of intervals
+    /// without acks just reached `DEAD_STREAK`
+    fn observe(&mut self, ack_percent: f64, now: u64) -> bool {
+        self.stats += 1;
+        self.mean_ack_percent += (ack_percent - self.mean_ack_percent) / self.stats as f64;
+        self.ack_percent = ack_percent;
+        self.last_stat = now;
+        if ack_percent > 0.0 {
+            self.missing_streak = 0;
+            return false;
+        }
+        self.missing_streak += 1;
+        self.longest_missing_streak = self.longest_missing_streak.max(self.missing_streak);
+        self.missing_streak == DEAD_STREAK
+    }
+}
+
+/// A forwarder that did not receive acks for `DEAD_STREAK` stat intervals
+/// while its uplinks still reach the gateway
+#[derive(Debug, Clone, Serialize)]
+pub struct AckLoss {
+    pub mac: String,
+    pub missing_streak: u32,
+}
+
+/// Records the ack statistics of the packet forwarders. Clones share the same
+/// statistics.
+#[derive(Debug, Clone, Default)]
+pub struct ForwarderAcksHandle(Arc<Mutex<BTreeMap<String, ForwarderAcks>>>);
+
+impl ForwarderAcksHandle {
+    /// Records the ack percentage (`ackr`) of a stat message of the forwarder
+    /// with the given MAC
+    pub fn record(&self, logger: &Logger, events: &EventBus, mac: &str, ack_percent: f64) {
+        let now = unix_now();
+        let (dead, acks) = {
+            let mut stats = self.0.lock().expect("forwarder acks lock");
+            let acks = stats.entry(mac.to_string()).or_default();
+            let recovered = acks.missing_streak >= DEAD_STREAK && ack_percent > 0.0;
+            if recovered {
+                info!(logger, "packet forwarder receives acks again"; "mac" => mac);
+            }
+            (acks.observe(ack_percent, now), acks.clone())
+        };
+        let labels = [("mac", mac)];
+        metrics::set(
+            &metrics::labeled(ACK_PERCENT_METRIC, &labels),
+            acks.ack_percent,
+        );
+        metrics::set(
+            &metrics::labeled(MISSING_STREAK_METRIC, &labels),
+            acks.missing_streak as f64,
+        );
+        if dead {
+            warn!(logger, "packet forwarder receives no acks, check the return path to the forwarder";
+                "mac" => mac,
+                "missing_streak" => acks.missing_streak);
+            events.publish(Event::ForwarderAckLoss(AckLoss {
+                mac: mac.to_string(),
+                missing_streak: acks.missing_streak,
+            }));
+        }
+    }
+
+    /// Returns the ack statistics by forwarder MAC
+    pub fn snapshot(&self) -> BTreeMap<String, ForwarderAcks> {
+        self.0.lock().expect("forwarder acks lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streaks() {
+        let mut acks = ForwarderAcks::default();
+        assert!(!acks.observe(0.0, 1));
+        assert!(!acks.observe(100.0, 2));
+        assert_eq!(0, acks.missing_streak);
+        assert!(!acks.observe(0.0, 3));
+        assert!(!acks.observe(0.0, 4));
+        assert!(acks.observe(0.0, 5));
+        // Only raised once per streak
+        assert!(!acks.observe(0.0, 6));
+        assert_eq!(4, acks.longest_missing_streak);
+        assert!(!acks.observe(50.0, 7));
+        assert_eq!(0, acks.missing_streak);
+        assert_eq!(7, acks.stats);
+        assert!((acks.mean_ack_percent - 150.0 / 7.0).abs() < 1e-9);
+        assert_eq!(7, acks.last_stat);
+    }
+}
@@ -0,0 +1,368 @@
+//! Packet forwarder protocol conformance.
+//!
+//! Frames the UDP packet forwarder protocol parser rejects are inspected field
+//! by field, so a buggy third party forwarder build shows up as, for example,
+//! `rxpk.datr: out_of_range` instead of a generic parse error. Warnings are
+//! counted per field and issue in the metrics registry, and the last example
+//! of each is kept for the stats API.
+
+use crate::{logging, metrics};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use slog::{warn, Level, Logger};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of conformance warnings labeled with `field` and `issue`
+pub const WARNING_METRIC: &str = "forwarder_conformance_warnings_total";
+
+/// Protocol header: version, token, identifier
+const HEADER_LEN: usize = 4;
+/// Gateway MAC following the header of upstream frames
+const MAC_LEN: usize = 8;
+
+const PUSH_DATA: u8 = 0x00;
+const PULL_DATA: u8 = 0x02;
+const TX_ACK: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    /// The frame ends before the field
+    Truncated,
+    /// A required field is missing
+    Missing,
+    /// The field has the wrong type or can not be parsed
+    Invalid,
+    /// The field parses but its value is not allowed
+    OutOfRange,
+    /// The field does not match another field, like `size` and `data`
+    Mismatch,
+    /// The frame was rejected but no specific issue was found
+    Unknown,
+}
+
+impl Issue {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Truncated => "truncated",
+            Self::Missing => "missing",
+            Self::Invalid => "invalid",
+            Self::OutOfRange => "out_of_range",
+            Self::Mismatch => "mismatch",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// A non-conformant field in a frame received from the packet forwarder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub field: &'static str,
+    pub issue: Issue,
+    /// The offending value, if any
+    pub value: Option<String>,
+}
+
+impl Warning {
+    fn new(field: &'static str, issue: Issue) -> Self {
+        Self {
+            field,
+            issue,
+            value: None,
+        }
+    }
+
+    fn with_value(field: &'static str, issue: Issue, value: impl ToString) -> Self {
+        Self {
+            field,
+            issue,
+            value: Some(value.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.issue.as_str())?;
+        if let Some(value) = &self.value {
+            write!(f, " ({value})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Inspects a frame received from the packet forwarder for protocol
+/// violations
+pub fn check_frame(frame: &[u8]) -> Vec<Warning> {
+    if frame.len() < HEADER_LEN {
+        return vec![Warning::with_value("header", Issue::Truncated, frame.len())];
+    }
+    let mut warnings = vec![];
+    if !matches!(frame[0], 1 | 2) {
+        warnings.push(Warning::with_value("version", Issue::OutOfRange, frame[0]));
+    }
+    let identifier = frame[3];
+    if !matches!(identifier, PUSH_DATA | PULL_DATA | TX_ACK) {
+        warnings.push(Warning::with_value(
+            "identifier",
+            Issue::OutOfRange,
+            identifier,
+        ));
+        return warnings;
+    }
+    if frame.len() < HEADER_LEN + MAC_LEN {
+        warnings.push(Warning::with_value("mac", Issue::Truncated, frame.len()));
+        return warnings;
+    }
+    let body = &frame[HEADER_LEN + MAC_LEN..];
+    match identifier {
+        PUSH_DATA => check_push_data(body, &mut warnings),
+        // An empty acknowledgement is valid for TX_ACK
+        TX_ACK if !body.iter().all(|byte| *byte == 0) => {
+            if serde_json::from_slice::<Value>(trim_nul(body)).is_err() {
+                warnings.push(Warning::new("json", Issue::Invalid));
+            }
+        }
+        _ => (),
+    }
+    warnings
+}
+
+/// Some forwarders terminate the JSON body with NUL bytes
+fn trim_nul(body: &[u8]) -> &[u8] {
+    let end = body
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |pos| pos + 1);
+    &body[..end]
+}
+
+fn check_push_data(body: &[u8], warnings: &mut Vec<Warning>) {
+    let json: Value = match serde_json::from_slice(trim_nul(body)) {
+        Ok(json) => json,
+        Err(err) => {
+            warnings.push(Warning::with_value("json", Issue::Invalid, err));
+            return;
+        }
+    };
+    let Some(object) = json.as_object() else {
+        warnings.push(Warning::new("json", Issue::Invalid));
+        return;
+    };
+    match object.get("rxpk") {
+        None => (),
+        Some(Value::Array(rxpks)) => {
+            for rxpk in rxpks {
+                match rxpk.as_object() {
+                    Some(rxpk) => check_rxpk(rxpk, warnings),
+                    None => warnings.push(Warning::new("rxpk", Issue::Invalid)),
+                }
+            }
+        }
+        Some(_) => warnings.push(Warning::new("rxpk", Issue::Invalid)),
+    }
+    if let Some(stat) = object.get("stat") {
+        if !stat.is_object() {
+            warnings.push(Warning::new("stat", Issue::Invalid));
+        }
+    }
+}
+
+fn check_rxpk(rxpk: &Map<String, Value>, warnings: &mut Vec<Warning>) {
+    let mut check = |field: &'static str, valid: fn(&Value) -> Option<Issue>| match rxpk
+        .get(field.trim_start_matches("rxpk."))
+    {
+        None => warnings.push(Warning::new(field, Issue::Missing)),
+        Some(value) => {
+            if let Some(issue) = valid(value) {
+                warnings.push(Warning::with_value(field, issue, value));
+            }
+        }
+    };
+    check("rxpk.tmst", |value| {
+        value.as_u64().map_or(Some(Issue::Invalid), |tmst| {
+            (tmst > u32::MAX as u64).then_some(Issue::OutOfRange)
+        })
+    });
+    check("rxpk.freq", |value| {
+        value.as_f64().map_or(Some(Issue::Invalid), |freq| {
+            (!(100.0..3000.0).contains(&freq)).then_some(Issue::OutOfRange)
+        })
+    });
+    check("rxpk.rssi", |value| {
+        value.as_i64().map_or(Some(Issue::Invalid), |_| None)
+    });
+    check("rxpk.size", |value| {
+        value.as_u64().map_or(Some(Issue::Invalid), |size| {
+            (size > 255).then_some(Issue::OutOfRange)
+        })
+    });
+    check("rxpk.data", |value| match value.as_str() {
+        Some(data) if STANDARD.decode(data).is_ok() => None,
+        _ => Some(Issue::Invalid),
+    });
+
+    let is_lora = rxpk.get("modu").and_then(Value::as_str) != Some("FSK");
+    if is_lora {
+        check("rxpk.datr", |value| match value.as_str() {
+            Some(datr) => match datr.parse::<beacon::LoraDataRate>() {
+                Ok(_) => None,
+                Err(_) if is_datarate_like(datr) => Some(Issue::OutOfRange),
+                Err(_) => Some(Issue::Invalid),
+            },
+            None => Some(Issue::Invalid),
+        });
+        check("rxpk.codr", |value| match value.as_str() {
+            Some("4/5" | "4/6" | "4/7" | "4/8" | "4/5LI" | "4/6LI" | "4/8LI" | "OFF") => None,
+            Some(_) => Some(Issue::OutOfRange),
+            None => Some(Issue::Invalid),
+        });
+        check("rxpk.lsnr", |value| {
+            value.as_f64().map_or(Some(Issue::Invalid), |_| None)
+        });
+    } else {
+        check("rxpk.datr", |value| {
+            value.as_u64().map_or(Some(Issue::Invalid), |_| None)
+        });
+    }
+
+    let size = rxpk.get("size").and_then(Value::as_u64);
+    let data_len = rxpk
+        .get("data")
+        .and_then(Value::as_str)
+        .and_then(|data| STANDARD.decode(data).ok())
+        .map(|data| data.len() as u64);
+    if let (Some(size), Some(data_len)) = (size, data_len) {
+        if size != data_len {
+            warnings.push(Warning::with_value(
+                "rxpk.size",
+                Issue::Mismatch,
+                format!("size {size}, data {data_len}"),
+            ));
+        }
+    }
+}
+
+/// Whether a datarate string has the SFxBWy form, so a parse failure means an
+/// unsupported spreading factor or bandwidth
+fn is_datarate_like(datr: &str) -> bool {
+    let upper = datr.to_ascii_uppercase();
+    upper
+        .strip_prefix("SF")
+        .and_then(|rest| rest.split_once("BW"))
+        .is_some_and(|(spreading, bandwidth)| {
+            spreading.parse::<u32>().is_ok() && bandwidth.parse::<u32>().is_ok()
+        })
+}
+
+/// Warning counts per field and issue since startup with the last example
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConformanceStats {
+    pub counts: BTreeMap<String, u64>,
+    pub examples: BTreeMap<String, String>,
+}
+
+/// Records the protocol warnings of packet forwarder frames. Clones share the
+/// same statistics.
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceStatsHandle(Arc<Mutex<ConformanceStats>>);
+
+impl ConformanceStatsHandle {
+    /// Inspects a frame the protocol parser rejected and records its
+    /// warnings. The parse error is recorded when no specific issue is found.
+    pub fn record(&self, logger: &Logger, frame: &[u8], parse_error: &str) {
+        let mut warnings = check_frame(frame);
+        if warnings.is_empty() {
+            warnings.push(Warning::with_value("frame", Issue::Unknown, parse_error));
+        }
+        if let Some(suppressed) = logging::sampling::sample(Level::Warning, "forwarder_conformance")
+        {
+            let summary = warnings
+                .iter()
+                .map(Warning::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!(logger, "non-conformant packet forwarder frame: {summary}";
+                "error" => parse_error,
+                "suppressed" => suppressed);
+        }
+
+        let mut stats = self.0.lock().expect("conformance stats lock");
+        for warning in warnings {
+            metrics::increment(&metrics::labeled(
+                WARNING_METRIC,
+                &[("field", warning.field), ("issue", warning.issue.as_str())],
+            ));
+            let key = format!("{}:{}", warning.field, warning.issue.as_str());
+            *stats.counts.entry(key.clone()).or_default() += 1;
+            stats.examples.insert(key, warning.to_string());
+        }
+    }
+
+    /// Returns the warning counts and examples
+    pub fn snapshot(&self) -> ConformanceStats {
+        self.0.lock().expect("conformance stats lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_data(json: &str) -> Vec<u8> {
+        let mut frame = vec![2, 0x12, 0x34, PUSH_DATA];
+        frame.extend_from_slice(&[0xaa; MAC_LEN]);
+        frame.extend_from_slice(json.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn conformance() {
+        let rxpk = r#"{"rxpk":[{"tmst":3512348611,"chan":2,"rfch":0,"freq":866.349812,
+            "stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/6","rssi":-35,"lsnr":5.1,
+            "size":3,"data":"AQID"}]}"#;
+        assert!(check_frame(&push_data(rxpk)).is_empty());
+
+        let issues = |json: &str| {
+            check_frame(&push_data(json))
+                .into_iter()
+                .map(|warning| (warning.field, warning.issue))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![("rxpk.datr", Issue::OutOfRange)],
+            issues(&rxpk.replace("SF7BW125", "SF13BW125"))
+        );
+        assert_eq!(
+            vec![("rxpk.datr", Issue::Invalid)],
+            issues(&rxpk.replace("SF7BW125", "LORA7"))
+        );
+        assert_eq!(
+            vec![("rxpk.tmst", Issue::Missing)],
+            issues(&rxpk.replace("\"tmst\":3512348611,", ""))
+        );
+        assert_eq!(
+            vec![("rxpk.size", Issue::Mismatch)],
+            issues(&rxpk.replace("\"size\":3", "\"size\":4"))
+        );
+        assert_eq!(
+            vec![("json", Issue::Invalid)],
+            issues(&rxpk.replace("]}", "]"))
+        );
+
+        assert_eq!(
+            vec![Warning::with_value("header", Issue::Truncated, 2)],
+            check_frame(&[2, 0x12])
+        );
+        let mut frame = push_data(rxpk);
+        frame[3] = 0x09;
+        assert_eq!(
+            vec![Warning::with_value("identifier", Issue::OutOfRange, 9)],
+            check_frame(&frame)
+        );
+    }
+}
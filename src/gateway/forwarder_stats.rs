@@ -0,0 +1,182 @@
+//! Packet forwarder statistics.
+//!
+//! The UDP packet forwarder sends a `stat` message every stat interval with
+//! the counts of that interval: received packets (`rxnb`), of which with a
+//! valid CRC (`rxok`) and forwarded (`rxfw`), the percentage of acknowledged
+//! upstream datagrams (`ackr`), received downlinks (`dwnb`) and transmitted
+//! packets (`txnb`). Per forwarder the totals since startup, the last interval
+//! and rolling aggregates over the last `ROLLING_STATS` intervals are kept
+//! for the stats API and exported as metrics, so forwarder health is visible
+//! without the forwarder logs.
+
+use crate::{clock::unix_now, metrics};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of packets received by the forwarder labeled with `mac`
+pub const RX_METRIC: &str = "forwarder_rx_packets_total";
+/// Counter of packets with a valid CRC received by the forwarder labeled with
+/// `mac`
+pub const RX_OK_METRIC: &str = "forwarder_rx_ok_packets_total";
+/// Counter of downlinks received by the forwarder labeled with `mac`
+pub const DOWNLINK_METRIC: &str = "forwarder_downlinks_total";
+/// Counter of packets transmitted by the forwarder labeled with `mac`
+pub const TX_METRIC: &str = "forwarder_tx_packets_total";
+/// Gauge of the rolling percentage of received packets with a valid CRC
+/// labeled with `mac`
+pub const RX_OK_PERCENT_METRIC: &str = "forwarder_rx_ok_percent";
+
+/// The number of stat intervals the rolling aggregates are taken over, 10
+/// minutes with the default 30 second stat interval
+const ROLLING_STATS: usize = 20;
+
+/// The counts of a stat message, missing fields count as 0
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counts {
+    pub rxnb: u64,
+    pub rxok: u64,
+    pub rxfw: u64,
+    pub dwnb: u64,
+    pub txnb: u64,
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.rxnb += other.rxnb;
+        self.rxok += other.rxok;
+        self.rxfw += other.rxfw;
+        self.dwnb += other.dwnb;
+        self.txnb += other.txnb;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+struct Interval {
+    #[serde(flatten)]
+    counts: Counts,
+    ackr: Option<f64>,
+}
+
+/// Statistics of one packet forwarder
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ForwarderStats {
+    /// The number of stat messages received
+    pub stats: u64,
+    pub total: Counts,
+    pub last: Counts,
+    /// The counts of the last `ROLLING_STATS` intervals
+    pub rolling: Counts,
+    /// The percentage of received packets with a valid CRC over the rolling
+    /// intervals
+    pub rx_ok_percent: Option<f64>,
+    /// The mean ack percentage over the rolling intervals that reported one
+    pub ack_percent: Option<f64>,
+    /// Unix time (seconds) of the last stat message
+    pub last_stat: u64,
+    #[serde(skip)]
+    window: VecDeque<Interval>,
+}
+
+impl ForwarderStats {
+    fn observe(&mut self, interval: Interval, now: u64) {
+        self.stats += 1;
+        self.total += interval.counts;
+        self.last = interval.counts;
+        self.last_stat = now;
+        if self.window.len() >= ROLLING_STATS {
+            self.window.pop_front();
+        }
+        self.window.push_back(interval);
+
+        self.rolling = Counts::default();
+        for interval in &self.window {
+            self.rolling += interval.counts;
+        }
+        self.rx_ok_percent = (self.rolling.rxnb > 0)
+            .then(|| self.rolling.rxok as f64 * 100.0 / self.rolling.rxnb as f64);
+        let acks: Vec<f64> = self
+            .window
+            .iter()
+            .filter_map(|interval| interval.ackr)
+            .collect();
+        self.ack_percent = (!acks.is_empty()).then(|| acks.iter().sum::<f64>() / acks.len() as f64);
+    }
+}
+
+/// Records the statistics of the packet forwarders. Clones share the same
+/// statistics.
+#[derive(Debug, Clone, Default)]
+pub struct ForwarderStatsHandle(Arc<Mutex<BTreeMap<String, ForwarderStats>>>);
+
+impl ForwarderStatsHandle {
+    /// Records a stat message, as JSON, of the forwarder with the given MAC
+    pub fn record(&self, mac: &str, stat: &serde_json::Value) {
+        let Ok(interval) = serde_json::from_value::<Interval>(stat.clone()) else {
+            return;
+        };
+        let now = unix_now();
+        let rx_ok_percent = {
+            let mut stats = self.0.lock().expect("forwarder stats lock");
+            let stats = stats.entry(mac.to_string()).or_default();
+            stats.observe(interval, now);
+            stats.rx_ok_percent
+        };
+        let labels = [("mac", mac)];
+        let counts = interval.counts;
+        metrics::increment_by(&metrics::labeled(RX_METRIC, &labels), counts.rxnb);
+        metrics::increment_by(&metrics::labeled(RX_OK_METRIC, &labels), counts.rxok);
+        metrics::increment_by(&metrics::labeled(DOWNLINK_METRIC, &labels), counts.dwnb);
+        metrics::increment_by(&metrics::labeled(TX_METRIC, &labels), counts.txnb);
+        if let Some(rx_ok_percent) = rx_ok_percent {
+            metrics::set(
+                &metrics::labeled(RX_OK_PERCENT_METRIC, &labels),
+                rx_ok_percent,
+            );
+        }
+    }
+
+    /// Returns the statistics by forwarder MAC
+    pub fn snapshot(&self) -> BTreeMap<String, ForwarderStats> {
+        self.0.lock().expect("forwarder stats lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rolling() {
+        let mut stats = ForwarderStats::default();
+        let interval = |stat| serde_json::from_value::<Interval>(stat).expect("interval");
+        stats.observe(
+            interval(
+                json!({"time": "2013-03-31 16:21:17 GMT", "rxnb": 4, "rxok": 2,
+                "rxfw": 2, "ackr": 100.0, "dwnb": 1, "txnb": 1}),
+            ),
+            1,
+        );
+        // Fields of forwarders that do not report everything
+        stats.observe(interval(json!({"rxnb": 2, "rxok": 2})), 2);
+        assert_eq!(6, stats.total.rxnb);
+        assert_eq!(Some(4.0 * 100.0 / 6.0), stats.rx_ok_percent);
+        assert_eq!(Some(100.0), stats.ack_percent);
+        assert_eq!(0, stats.last.txnb);
+
+        for n in 0..ROLLING_STATS as u64 {
+            stats.observe(interval(json!({"rxnb": 1, "rxok": 1, "ackr": 50.0})), 3 + n);
+        }
+        // The first intervals rolled out
+        assert_eq!(ROLLING_STATS as u64, stats.rolling.rxnb);
+        assert_eq!(Some(100.0), stats.rx_ok_percent);
+        assert_eq!(Some(50.0), stats.ack_percent);
+        assert_eq!(6 + ROLLING_STATS as u64, stats.total.rxnb);
+        assert_eq!(1, stats.total.txnb);
+    }
+}
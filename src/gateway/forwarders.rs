@@ -0,0 +1,192 @@
+//! Per packet forwarder state of the Semtech UDP backend.
+//!
+//! Gateways with more than one concentrator, like a sub-GHz and a 2.4 GHz
+//! card, run a packet forwarder per card with its own gateway EUI, and all of
+//! them can send to the gateway at once. Concentrator counters differ between
+//! cards, so the GPS timing reference and the forwarder clock are kept per
+//! EUI.
+//!
+//! Router downlinks do not say which uplink they answer. Their rx1 timestamp
+//! however is the concentrator timestamp of the uplink plus a whole number of
+//! seconds of receive delay, so a downlink is sent to the forwarder that
+//! received a matching uplink. Beacons go to the forwarder whose uplinks are
+//! closest to the beacon frequency. Without a match the forwarder that
+//! connected last is used.
+
+use super::GpsReference;
+use crate::clock::ForwarderClock;
+use semtech_udp::MacAddress;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+/// The receive delays, in seconds, a downlink can follow its uplink with
+const RX_DELAYS: RangeInclusive<u32> = 1..=15;
+/// How long uplinks are kept to match downlinks against
+const UPLINK_AGE: Duration = Duration::from_secs(16);
+/// The maximum number of uplinks kept per forwarder
+const MAX_UPLINKS: usize = 256;
+
+/// The state of one packet forwarder
+#[derive(Debug, Default)]
+pub struct Forwarder {
+    /// The address of a connected forwarder
+    pub addr: Option<SocketAddr>,
+    /// The index of the listen socket the forwarder connected to
+    socket: usize,
+    pub gps_reference: Option<GpsReference>,
+    pub clock: ForwarderClock,
+    /// Concentrator timestamps of recent uplinks
+    uplinks: VecDeque<(u32, Instant)>,
+    /// The frequency in MHz of the last uplink
+    frequency: Option<f64>,
+}
+
+impl Forwarder {
+    fn record_uplink(&mut self, tmst: u32, frequency: f64, now: Instant) {
+        while self
+            .uplinks
+            .front()
+            .is_some_and(|(_, received)| now.saturating_duration_since(*received) > UPLINK_AGE)
+            || self.uplinks.len() >= MAX_UPLINKS
+        {
+            self.uplinks.pop_front();
+        }
+        self.uplinks.push_back((tmst, now));
+        self.frequency = Some(frequency);
+    }
+
+    /// Whether a downlink at the given concentrator timestamp answers a recent
+    /// uplink of this forwarder
+    fn answers(&self, tmst: u32) -> bool {
+        self.uplinks.iter().any(|(uplink, _)| {
+            let delay = tmst.wrapping_sub(*uplink);
+            delay % 1_000_000 == 0 && RX_DELAYS.contains(&(delay / 1_000_000))
+        })
+    }
+}
+
+/// The packet forwarders by gateway EUI
+#[derive(Debug, Default)]
+pub struct Forwarders {
+    forwarders: HashMap<MacAddress, Forwarder>,
+    /// The forwarder that connected last
+    last: MacAddress,
+}
+
+impl Forwarders {
+    pub fn connected(&mut self, mac: MacAddress, addr: SocketAddr, socket: usize) {
+        let forwarder = self.get_mut(mac);
+        forwarder.addr = Some(addr);
+        forwarder.socket = socket;
+        self.last = mac;
+    }
+
+    /// The index of the listen socket to reach a forwarder through
+    pub fn socket(&self, mac: MacAddress) -> usize {
+        self.forwarders
+            .get(&mac)
+            .map(|forwarder| forwarder.socket)
+            .unwrap_or(0)
+    }
+
+    pub fn disconnected(&mut self, mac: MacAddress) {
+        if let Some(forwarder) = self.forwarders.get_mut(&mac) {
+            forwarder.addr = None;
+        }
+        if self.last == mac {
+            if let Some((other, _)) = self
+                .forwarders
+                .iter()
+                .find(|(_, forwarder)| forwarder.addr.is_some())
+            {
+                self.last = *other;
+            }
+        }
+    }
+
+    /// The state of a forwarder, created when not known yet
+    pub fn get_mut(&mut self, mac: MacAddress) -> &mut Forwarder {
+        self.forwarders.entry(mac).or_default()
+    }
+
+    /// The number of connected forwarders
+    pub fn connected_count(&self) -> usize {
+        self.forwarders
+            .values()
+            .filter(|forwarder| forwarder.addr.is_some())
+            .count()
+    }
+
+    /// Records an uplink received by a forwarder with its concentrator
+    /// timestamp and frequency in MHz
+    pub fn record_uplink(&mut self, mac: MacAddress, tmst: u32, frequency: f64) {
+        self.get_mut(mac)
+            .record_uplink(tmst, frequency, Instant::now());
+    }
+
+    /// The forwarder to send a downlink with the given rx1 concentrator
+    /// timestamp to
+    pub fn downlink_mac(&self, tmst: u32) -> MacAddress {
+        self.forwarders
+            .iter()
+            .find(|(_, forwarder)| forwarder.answers(tmst))
+            .map_or(self.last, |(mac, _)| *mac)
+    }
+
+    /// The forwarder to send a beacon on the given frequency in MHz through
+    pub fn beacon_mac(&self, frequency: f64) -> MacAddress {
+        self.forwarders
+            .iter()
+            .filter(|(_, forwarder)| forwarder.addr.is_some())
+            .filter_map(|(mac, forwarder)| Some((mac, (forwarder.frequency? - frequency).abs())))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(self.last, |(mac, _)| *mac)
+    }
+
+    /// The state of a forwarder
+    pub fn get(&self, mac: MacAddress) -> Option<&Forwarder> {
+        self.forwarders.get(&mac)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routing() {
+        let sub_ghz = MacAddress::new(&[1, 0, 0, 0, 0, 0, 0, 1]);
+        let ism_2g4 = MacAddress::new(&[2, 0, 0, 0, 0, 0, 0, 2]);
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let mut forwarders = Forwarders::default();
+        forwarders.connected(sub_ghz, addr, 0);
+        forwarders.connected(ism_2g4, addr, 1);
+
+        forwarders.record_uplink(sub_ghz, 1_000_000, 868.1);
+        forwarders.record_uplink(ism_2g4, 3_500_000, 2403.0);
+        // Downlinks follow the uplink they answer, including over a counter
+        // wrap
+        assert_eq!(sub_ghz, forwarders.downlink_mac(2_000_000));
+        assert_eq!(sub_ghz, forwarders.downlink_mac(6_000_000));
+        assert_eq!(ism_2g4, forwarders.downlink_mac(4_500_000));
+        forwarders.record_uplink(sub_ghz, u32::MAX - 499_999, 868.3);
+        assert_eq!(sub_ghz, forwarders.downlink_mac(500_000));
+        // Unmatched downlinks go to the forwarder that connected last
+        assert_eq!(ism_2g4, forwarders.downlink_mac(2_700_000));
+        assert_eq!(ism_2g4, forwarders.downlink_mac(1_000_000 + 16_000_000));
+
+        assert_eq!(sub_ghz, forwarders.beacon_mac(869.525));
+        assert_eq!(1, forwarders.socket(ism_2g4));
+        assert_eq!(0, forwarders.socket(MacAddress::default()));
+        assert_eq!(ism_2g4, forwarders.beacon_mac(2422.0));
+
+        forwarders.disconnected(ism_2g4);
+        assert_eq!(1, forwarders.connected_count());
+        assert_eq!(sub_ghz, forwarders.beacon_mac(2422.0));
+        assert_eq!(sub_ghz, forwarders.downlink_mac(2_700_000));
+    }
+}
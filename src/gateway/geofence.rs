@@ -0,0 +1,266 @@
+//! A guardrail against transmitting in the wrong region.
+//!
+//! A gateway moved abroad with stale settings keeps transmitting beacons and
+//! downlinks on the frequencies of its old region, which is illegal. With the
+//! geofence enabled the position reported by the packet forwarder GPS, or the
+//! operator entered position until there is one, is checked against a coarse
+//! territory of the region. Outside of it the gateway refuses to transmit and
+//! raises an alarm: an error is logged, the `geofence_alarm` metric is set
+//! and the status is available from the local API. Regions without a known
+//! territory are not checked.
+//!
+//! The territories are bounding boxes that err on the side of allowing
+//! transmissions, they catch a move to another continent, not across a
+//! border.
+
+use crate::{metrics, settings::GeofenceSettings, RegionParams};
+use helium_proto::Region as ProtoRegion;
+use serde::Serialize;
+use slog::{error, info, Logger};
+use std::sync::{Arc, Mutex};
+
+/// Gauge which is 1 while the gateway is outside its region territory
+pub const ALARM_METRIC: &str = "geofence_alarm";
+
+/// A bounding box as (min latitude, max latitude, min longitude, max
+/// longitude) in degrees
+type Bounds = (f64, f64, f64, f64);
+
+/// The territories of regions by region name prefix
+const TERRITORIES: &[(&str, &[Bounds])] = &[
+    // The Americas
+    ("US915", &[(-60.0, 75.0, -170.0, -30.0)]),
+    // Oceania and South America
+    (
+        "AU915",
+        &[(-50.0, 0.0, 110.0, 180.0), (-60.0, 15.0, -95.0, -30.0)],
+    ),
+    // Asia and Oceania
+    ("AS923", &[(-50.0, 55.0, 60.0, 180.0)]),
+    // Europe, Africa and the Middle East
+    ("EU868", &[(-40.0, 72.0, -30.0, 65.0)]),
+    ("EU433", &[(-40.0, 72.0, -30.0, 65.0)]),
+    ("CN470", &[(15.0, 55.0, 70.0, 140.0)]),
+    ("CN779", &[(15.0, 55.0, 70.0, 140.0)]),
+    ("KR920", &[(32.0, 40.0, 123.0, 133.0)]),
+    ("IN865", &[(5.0, 38.0, 66.0, 99.0)]),
+    (
+        "RU864",
+        &[(40.0, 82.0, 19.0, 180.0), (60.0, 72.0, -180.0, -168.0)],
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationSource {
+    /// Entered by the operator in the settings
+    Configured,
+    /// Reported by the packet forwarder GPS
+    Gps,
+}
+
+/// The geofence status for the local API
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GeofenceStatus {
+    pub enabled: bool,
+    pub location: Option<Location>,
+    pub source: Option<LocationSource>,
+    pub region: Option<String>,
+    /// Whether transmissions are refused
+    pub outside: bool,
+}
+
+/// The published geofence status. Clones share the same status.
+#[derive(Debug, Clone, Default)]
+pub struct GeofenceHandle(Arc<Mutex<GeofenceStatus>>);
+
+impl GeofenceHandle {
+    /// Returns the current geofence status
+    pub fn snapshot(&self) -> GeofenceStatus {
+        self.0.lock().expect("geofence lock").clone()
+    }
+}
+
+/// Whether the location is in the territory of the region with the given
+/// name, None when the territory of the region is not known
+pub fn in_territory(region: &str, location: Location) -> Option<bool> {
+    let (_, territory) = TERRITORIES
+        .iter()
+        .find(|(prefix, _)| region.starts_with(prefix))?;
+    Some(
+        territory
+            .iter()
+            .any(|(lat_min, lat_max, lon_min, lon_max)| {
+                (*lat_min..=*lat_max).contains(&location.latitude)
+                    && (*lon_min..=*lon_max).contains(&location.longitude)
+            }),
+    )
+}
+
+#[derive(Debug)]
+pub struct Geofence {
+    enabled: bool,
+    configured: Option<Location>,
+    gps: Option<Location>,
+    region: Option<String>,
+    outside: bool,
+    published: GeofenceHandle,
+}
+
+impl Geofence {
+    /// A geofence publishing its status to the given handle
+    pub fn new(settings: &GeofenceSettings, published: GeofenceHandle) -> Self {
+        let configured = settings
+            .latitude
+            .zip(settings.longitude)
+            .map(|(latitude, longitude)| Location {
+                latitude,
+                longitude,
+            });
+        let geofence = Self {
+            enabled: settings.enabled,
+            configured,
+            gps: None,
+            region: None,
+            outside: false,
+            published,
+        };
+        geofence.publish();
+        geofence
+    }
+
+    /// Whether beacons and downlinks must not be transmitted
+    pub fn blocks(&self) -> bool {
+        self.outside
+    }
+
+    pub fn set_region(&mut self, logger: &Logger, region_params: &RegionParams) {
+        let region = ProtoRegion::from(region_params.region).as_str_name();
+        if self.region.as_deref() == Some(region) {
+            return;
+        }
+        self.region = Some(region.to_string());
+        self.evaluate(logger);
+    }
+
+    /// Records a position reported by a packet forwarder GPS. A forwarder
+    /// without a fix reports 0, 0, which is ignored.
+    pub fn set_gps(&mut self, logger: &Logger, latitude: f64, longitude: f64) {
+        if latitude == 0.0 && longitude == 0.0 {
+            return;
+        }
+        let location = Location {
+            latitude,
+            longitude,
+        };
+        if self.gps == Some(location) {
+            return;
+        }
+        self.gps = Some(location);
+        self.evaluate(logger);
+    }
+
+    fn location(&self) -> Option<(Location, LocationSource)> {
+        match (self.gps, self.configured) {
+            (Some(gps), _) => Some((gps, LocationSource::Gps)),
+            (None, Some(configured)) => Some((configured, LocationSource::Configured)),
+            (None, None) => None,
+        }
+    }
+
+    fn evaluate(&mut self, logger: &Logger) {
+        let outside = self.enabled
+            && self
+                .location()
+                .zip(self.region.as_deref())
+                .and_then(|((location, _), region)| in_territory(region, location))
+                == Some(false);
+        if outside != self.outside {
+            let location = self.location();
+            if outside {
+                error!(logger, "gateway is outside the territory of its region, refusing to transmit";
+                    "region" => &self.region,
+                    "latitude" => location.map(|(location, _)| location.latitude),
+                    "longitude" => location.map(|(location, _)| location.longitude),
+                    "source" => location.map(|(_, source)| format!("{source:?}")));
+            } else {
+                info!(logger, "gateway is in the territory of its region, transmitting";
+                    "region" => &self.region);
+            }
+        }
+        self.outside = outside;
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let location = self.location();
+        let status = GeofenceStatus {
+            enabled: self.enabled,
+            location: location.map(|(location, _)| location),
+            source: location.map(|(_, source)| source),
+            region: self.region.clone(),
+            outside: self.outside,
+        };
+        metrics::set(ALARM_METRIC, if self.outside { 1.0 } else { 0.0 });
+        *self.published.0.lock().expect("geofence lock") = status;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AMSTERDAM: Location = Location {
+        latitude: 52.37,
+        longitude: 4.90,
+    };
+    const NEW_YORK: Location = Location {
+        latitude: 40.71,
+        longitude: -74.01,
+    };
+
+    #[test]
+    fn territories() {
+        assert_eq!(Some(true), in_territory("EU868", AMSTERDAM));
+        assert_eq!(Some(false), in_territory("EU868", NEW_YORK));
+        assert_eq!(Some(true), in_territory("US915", NEW_YORK));
+        assert_eq!(Some(false), in_territory("AS923_1", NEW_YORK));
+        assert_eq!(None, in_territory("UNKNOWN", NEW_YORK));
+    }
+
+    #[test]
+    fn blocks() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let published = GeofenceHandle::default();
+        let mut geofence = Geofence::new(
+            &GeofenceSettings {
+                enabled: true,
+                latitude: Some(AMSTERDAM.latitude),
+                longitude: Some(AMSTERDAM.longitude),
+            },
+            published.clone(),
+        );
+        // No region yet
+        assert!(!geofence.blocks());
+        geofence.set_region(
+            &logger,
+            &RegionParams::from(crate::Region::from(ProtoRegion::Eu868)),
+        );
+        assert!(!geofence.blocks());
+        // The GPS position takes precedence over the configured one
+        geofence.set_gps(&logger, NEW_YORK.latitude, NEW_YORK.longitude);
+        assert!(geofence.blocks());
+        assert!(published.snapshot().outside);
+        // No fix
+        geofence.set_gps(&logger, 0.0, 0.0);
+        assert!(geofence.blocks());
+        geofence.set_gps(&logger, AMSTERDAM.latitude, AMSTERDAM.longitude);
+        assert!(!geofence.blocks());
+    }
+}
@@ -0,0 +1,261 @@
+//! Join request filtering by JoinEUI and DevEUI.
+//!
+//! In dense deployments most join requests a gateway hears come from devices
+//! of other networks. With the filter enabled, join requests are matched
+//! against deny and allow lists of JoinEUIs and DevEUIs before they are signed
+//! and forwarded, independent of the structural uplink filter. A join request
+//! matching a deny list is dropped. When an allow list is set, join requests
+//! matching none of its EUIs are dropped as well.
+//!
+//! The filter comes from the `[join_filter]` settings and can be replaced at
+//! runtime through the local API (`POST /v1/joins/filter`), which applies to
+//! the next join request. A filter set at runtime lasts until the next
+//! restart.
+
+use crate::{metrics, settings::JoinFilterSettings, Error, Packet, Result};
+use helium_proto::{routing_information::Data as RoutingData, Eui, RoutingInformation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Counter of dropped join requests labeled with the failed `check`
+pub const DROPPED_METRIC: &str = "join_filter_dropped_total";
+
+pub type MessageReceiver = watch::Receiver<JoinFilter>;
+
+/// Why a join request was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    DeniedJoinEui,
+    DeniedDevEui,
+    JoinEuiNotAllowed,
+    DevEuiNotAllowed,
+}
+
+impl Check {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DeniedJoinEui => "denied_join_eui",
+            Self::DeniedDevEui => "denied_dev_eui",
+            Self::JoinEuiNotAllowed => "join_eui_not_allowed",
+            Self::DevEuiNotAllowed => "dev_eui_not_allowed",
+        }
+    }
+}
+
+/// Whether the filter is enabled and its lists as hex EUIs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JoinFilterConfig {
+    pub enabled: bool,
+    pub allow_join_euis: Vec<String>,
+    pub deny_join_euis: Vec<String>,
+    pub allow_dev_euis: Vec<String>,
+    pub deny_dev_euis: Vec<String>,
+}
+
+impl From<&JoinFilterSettings> for JoinFilterConfig {
+    fn from(settings: &JoinFilterSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            allow_join_euis: settings.allow_join_euis.clone(),
+            deny_join_euis: settings.deny_join_euis.clone(),
+            allow_dev_euis: settings.allow_dev_euis.clone(),
+            deny_dev_euis: settings.deny_dev_euis.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JoinFilter {
+    config: JoinFilterConfig,
+    allow_join_euis: Vec<u64>,
+    deny_join_euis: Vec<u64>,
+    allow_dev_euis: Vec<u64>,
+    deny_dev_euis: Vec<u64>,
+}
+
+fn parse_euis(euis: &[String]) -> Result<Vec<u64>> {
+    euis.iter()
+        .map(|eui| {
+            u64::from_str_radix(eui.trim_start_matches("0x"), 16)
+                .map_err(|_| Error::custom(format!("invalid eui {eui}")))
+        })
+        .collect()
+}
+
+impl JoinFilter {
+    pub fn new(config: JoinFilterConfig) -> Result<Self> {
+        Ok(Self {
+            allow_join_euis: parse_euis(&config.allow_join_euis)?,
+            deny_join_euis: parse_euis(&config.deny_join_euis)?,
+            allow_dev_euis: parse_euis(&config.allow_dev_euis)?,
+            deny_dev_euis: parse_euis(&config.deny_dev_euis)?,
+            config,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Checks the given uplink, returning the failed check if it is a join
+    /// request that should be dropped. Other uplinks always pass, and all
+    /// uplinks pass when the filter is disabled.
+    pub fn check(&self, packet: &Packet) -> std::result::Result<(), Check> {
+        let Some(RoutingInformation {
+            data: Some(RoutingData::Eui(eui)),
+        }) = packet.routing()
+        else {
+            return Ok(());
+        };
+        let result = self.check_eui(eui);
+        if let Err(check) = result {
+            metrics::increment(&metrics::labeled(
+                DROPPED_METRIC,
+                &[("check", check.as_str())],
+            ));
+        }
+        result
+    }
+
+    fn check_eui(&self, eui: &Eui) -> std::result::Result<(), Check> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let allowed = |list: &[u64], eui| list.is_empty() || list.contains(&eui);
+        if self.deny_join_euis.contains(&eui.appeui) {
+            Err(Check::DeniedJoinEui)
+        } else if self.deny_dev_euis.contains(&eui.deveui) {
+            Err(Check::DeniedDevEui)
+        } else if !allowed(&self.allow_join_euis, eui.appeui) {
+            Err(Check::JoinEuiNotAllowed)
+        } else if !allowed(&self.allow_dev_euis, eui.deveui) {
+            Err(Check::DevEuiNotAllowed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Replaces the join filter of a running gateway
+#[derive(Debug, Clone)]
+pub struct JoinFilterHandle(Arc<watch::Sender<JoinFilter>>);
+
+impl JoinFilterHandle {
+    /// Returns the current filter configuration
+    pub fn config(&self) -> JoinFilterConfig {
+        self.0.borrow().config.clone()
+    }
+
+    /// Checks and replaces the filter, returns the new configuration. An
+    /// invalid configuration leaves the current filter in place.
+    pub fn update(&self, config: JoinFilterConfig) -> Result<JoinFilterConfig> {
+        let filter = JoinFilter::new(config.clone())?;
+        self.0.send_replace(filter);
+        Ok(config)
+    }
+}
+
+/// Checks the join filter settings and returns the handle to replace the
+/// filter at runtime and the receiver the gateway checks join requests with
+pub fn channel(settings: &JoinFilterSettings) -> Result<(JoinFilterHandle, MessageReceiver)> {
+    let (tx, rx) = watch::channel(JoinFilter::new(settings.into())?);
+    Ok((JoinFilterHandle(Arc::new(tx)), rx))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eui(appeui: u64, deveui: u64) -> Eui {
+        Eui { deveui, appeui }
+    }
+
+    #[test]
+    fn checks() {
+        let filter = JoinFilter::new(JoinFilterConfig {
+            enabled: true,
+            allow_join_euis: vec!["70B3D57ED0000001".to_string()],
+            deny_dev_euis: vec!["0x0102030405060708".to_string()],
+            ..Default::default()
+        })
+        .expect("filter");
+        assert_eq!(Ok(()), filter.check_eui(&eui(0x70B3D57ED0000001, 1)));
+        assert_eq!(
+            Err(Check::JoinEuiNotAllowed),
+            filter.check_eui(&eui(0x70B3D57ED0000002, 1))
+        );
+        assert_eq!(
+            Err(Check::DeniedDevEui),
+            filter.check_eui(&eui(0x70B3D57ED0000001, 0x0102030405060708))
+        );
+
+        let filter = JoinFilter::new(JoinFilterConfig {
+            enabled: true,
+            deny_join_euis: vec!["70B3D57ED0000002".to_string()],
+            allow_dev_euis: vec!["1".to_string()],
+            ..Default::default()
+        })
+        .expect("filter");
+        assert_eq!(
+            Err(Check::DeniedJoinEui),
+            filter.check_eui(&eui(0x70B3D57ED0000002, 1))
+        );
+        assert_eq!(
+            Err(Check::DevEuiNotAllowed),
+            filter.check_eui(&eui(0x70B3D57ED0000003, 2))
+        );
+        assert_eq!(Ok(()), filter.check_eui(&eui(0x70B3D57ED0000003, 1)));
+
+        // The lists are kept but not applied while disabled
+        let filter = JoinFilter::new(JoinFilterConfig {
+            deny_join_euis: vec!["70B3D57ED0000002".to_string()],
+            ..Default::default()
+        })
+        .expect("filter");
+        assert_eq!(Ok(()), filter.check_eui(&eui(0x70B3D57ED0000002, 1)));
+
+        assert!(JoinFilter::new(JoinFilterConfig {
+            deny_join_euis: vec!["not an eui".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn reload() {
+        let (handle, filter) = channel(&JoinFilterSettings::default()).expect("join filter");
+        let join = eui(0x70B3D57ED0000002, 1);
+        assert_eq!(Ok(()), filter.borrow().check_eui(&join));
+
+        let config = JoinFilterConfig {
+            enabled: true,
+            deny_join_euis: vec!["70B3D57ED0000002".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config, handle.update(config.clone()).expect("updated"));
+        assert_eq!(config, handle.config());
+        assert_eq!(Err(Check::DeniedJoinEui), filter.borrow().check_eui(&join));
+
+        // An invalid update keeps the current filter
+        assert!(handle
+            .update(JoinFilterConfig {
+                enabled: true,
+                allow_dev_euis: vec!["not an eui".to_string()],
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(config, handle.config());
+        assert_eq!(Err(Check::DeniedJoinEui), filter.borrow().check_eui(&join));
+
+        // Disabling at runtime forwards join requests again
+        handle
+            .update(JoinFilterConfig {
+                enabled: false,
+                ..config
+            })
+            .expect("updated");
+        assert_eq!(Ok(()), filter.borrow().check_eui(&join));
+    }
+}
@@ -0,0 +1,127 @@
+//! The addresses the Semtech UDP packet forwarder listener binds.
+//!
+//! The listener binds the `listen` address and any `extra_listen` addresses,
+//! which can be IPv4 or IPv6, like "[::]:1680". With a `listen_interface` the
+//! ports of those addresses are bound on every address of the interface
+//! instead, so the listener follows the interface rather than a fixed
+//! address. Each resulting address gets its own socket. Listing the interface
+//! addresses needs the `listen-interface` feature.
+
+use crate::{Error, Result, Settings};
+#[cfg(feature = "listen-interface")]
+use std::net::IpAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Resolves the addresses to bind the packet forwarder listener to
+pub fn resolve(settings: &Settings) -> Result<Vec<SocketAddr>> {
+    let mut listen = vec![];
+    for addr in std::iter::once(&settings.listen).chain(&settings.extra_listen) {
+        let resolved = addr
+            .to_socket_addrs()
+            .map_err(|err| Error::custom(format!("invalid listen address \"{addr}\": {err}")))?;
+        listen.extend(resolved);
+    }
+    let interface = settings
+        .listen_interface
+        .as_deref()
+        .map(interface_addrs)
+        .transpose()?;
+    let addrs = bind_addrs(&listen, interface.as_deref());
+    if addrs.is_empty() {
+        return Err(Error::custom(
+            "no address to listen on for packet forwarders",
+        ));
+    }
+    Ok(addrs)
+}
+
+/// The listen addresses, or the ports of the listen addresses on the given
+/// interface addresses, without duplicates
+fn bind_addrs(listen: &[SocketAddr], interface: Option<&[SocketAddr]>) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = vec![];
+    for addr in listen {
+        let candidates = match interface {
+            Some(interface) => interface
+                .iter()
+                .map(|interface_addr| {
+                    let mut bind = *interface_addr;
+                    bind.set_port(addr.port());
+                    bind
+                })
+                .collect(),
+            None => vec![*addr],
+        };
+        for candidate in candidates {
+            if !addrs.contains(&candidate) {
+                addrs.push(candidate);
+            }
+        }
+    }
+    addrs
+}
+
+/// The IPv4 and IPv6 addresses of a network interface. IPv6 link local
+/// addresses keep the scope of the interface.
+#[cfg(feature = "listen-interface")]
+fn interface_addrs(interface: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = nix::ifaddrs::getifaddrs()
+        .map_err(|err| Error::custom(format!("failed to list network interfaces: {err}")))?
+        .filter(|ifaddr| ifaddr.interface_name == interface)
+        .filter_map(|ifaddr| {
+            let address = ifaddr.address?;
+            if let Some(addr) = address.as_sockaddr_in() {
+                return Some(SocketAddr::V4((*addr).into()));
+            }
+            address
+                .as_sockaddr_in6()
+                .map(|addr| SocketAddr::V6((*addr).into()))
+        })
+        .filter(|addr| !matches!(addr.ip(), IpAddr::V4(ip) if ip.is_unspecified()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(Error::custom(format!(
+            "listen_interface \"{interface}\" has no addresses"
+        )));
+    }
+    Ok(addrs)
+}
+
+#[cfg(not(feature = "listen-interface"))]
+fn interface_addrs(_interface: &str) -> Result<Vec<SocketAddr>> {
+    Err(Error::custom(
+        "listen_interface requires the listen-interface feature",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bind() {
+        let listen: Vec<SocketAddr> = vec![
+            "127.0.0.1:1680".parse().unwrap(),
+            "[::]:1680".parse().unwrap(),
+            "0.0.0.0:1700".parse().unwrap(),
+        ];
+        assert_eq!(listen, bind_addrs(&listen, None));
+
+        let interface: Vec<SocketAddr> = vec![
+            "192.168.1.2:0".parse().unwrap(),
+            "[fe80::1%2]:0".parse().unwrap(),
+        ];
+        let addrs: Vec<String> = bind_addrs(&listen, Some(&interface))
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "192.168.1.2:1680",
+                "[fe80::1%2]:1680",
+                "192.168.1.2:1700",
+                "[fe80::1%2]:1700"
+            ],
+            addrs
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Mirroring of Semtech UDP traffic to secondary GWMP servers.
+//!
+//! Like gwmp-mux, but without a separate process: the uplinks and stats of
+//! every allowed packet forwarder are also sent, as received, to each server
+//! in `forwarder.mirror`, like a private ChirpStack. Per server and forwarder
+//! EUI a UDP socket acts as that forwarder towards the server, with PULL_DATA
+//! keepalives. Downlinks (PULL_RESP) of a server are handed to the gateway to
+//! transmit through the forwarder, and the forwarder TX_ACK is relayed back to
+//! the server. The Helium router path is not affected.
+
+use crate::metrics;
+use semtech_udp::{pull_resp::TxPk, tx_ack::Error as TxAckErr, MacAddress};
+use serde::Deserialize;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::mpsc, time};
+
+/// Counter of messages sent to mirror servers labeled with `server` and
+/// `kind` ("push_data", "pull_data" or "tx_ack"), and of uplinks and stats
+/// dropped for a full queue with kind "dropped"
+pub const SENT_METRIC: &str = "gwmp_mirror_messages_total";
+/// Counter of downlinks received from mirror servers labeled with `server`
+pub const DOWNLINKS_METRIC: &str = "gwmp_mirror_downlinks_total";
+
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0;
+const PULL_DATA: u8 = 2;
+const PULL_RESP: u8 = 3;
+const TX_ACK: u8 = 5;
+
+/// The interval between PULL_DATA keepalives, and between connect attempts
+/// while a server can not be resolved
+const KEEPALIVE: Duration = Duration::from_secs(10);
+/// Messages queued per server and forwarder
+const CLIENT_CAPACITY: usize = 50;
+/// Downlinks from mirror servers queued for the gateway
+const DOWNLINK_CAPACITY: usize = 20;
+
+/// A downlink of a mirror server for a packet forwarder
+#[derive(Debug)]
+pub struct Downlink {
+    pub mac: MacAddress,
+    pub txpk: TxPk,
+    token: u16,
+    socket: Arc<UdpSocket>,
+}
+
+impl Downlink {
+    /// Relays the forwarder result of the downlink to the mirror server. An
+    /// error without a TX_ACK of the forwarder is not relayed.
+    pub async fn ack(self, logger: &Logger, error: Option<&TxAckErr>) {
+        let frame = tx_ack(self.token, self.mac, error.map(ack_error).as_deref());
+        if let Err(err) = self.socket.send(&frame).await {
+            debug!(logger, "failed to send mirror tx_ack: {err}");
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Mirror {
+    servers: Vec<String>,
+    /// The message queues of the client tasks by server index and forwarder
+    clients: HashMap<(usize, MacAddress), mpsc::Sender<Vec<u8>>>,
+    downlinks: Option<(mpsc::Sender<Downlink>, mpsc::Receiver<Downlink>)>,
+}
+
+impl Mirror {
+    pub fn new(servers: &[String]) -> Self {
+        Self {
+            servers: servers.to_vec(),
+            clients: HashMap::new(),
+            downlinks: (!servers.is_empty()).then(|| mpsc::channel(DOWNLINK_CAPACITY)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.servers.is_empty()
+    }
+
+    /// Mirrors the PUSH_DATA JSON object, like `{"rxpk":[...]}`, of a forwarder
+    /// to all servers
+    pub fn push(&mut self, logger: &Logger, mac: MacAddress, json: &serde_json::Value) {
+        let Some((downlinks, _)) = &self.downlinks else {
+            return;
+        };
+        let frame = push_data(rand::random(), mac, json.to_string().as_bytes());
+        for (index, server) in self.servers.iter().enumerate() {
+            let client = self.clients.entry((index, mac)).or_insert_with(|| {
+                let (sender, receiver) = mpsc::channel(CLIENT_CAPACITY);
+                let client = Client {
+                    server: server.clone(),
+                    mac,
+                    downlinks: downlinks.clone(),
+                    logger: logger.new(o!("mirror" => server.clone(), "mac" => mac.to_string())),
+                };
+                tokio::spawn(client.run(receiver));
+                sender
+            });
+            if client.try_send(frame.clone()).is_err() {
+                metrics::increment(&metrics::labeled(
+                    SENT_METRIC,
+                    &[("server", server), ("kind", "dropped")],
+                ));
+            }
+        }
+    }
+
+    /// Receives the next downlink of a mirror server, pending forever when
+    /// mirroring is disabled
+    pub async fn recv(&mut self) -> Downlink {
+        match &mut self.downlinks {
+            Some((_, receiver)) => match receiver.recv().await {
+                Some(downlink) => downlink,
+                None => std::future::pending().await,
+            },
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Acts as one packet forwarder towards one mirror server
+struct Client {
+    server: String,
+    mac: MacAddress,
+    downlinks: mpsc::Sender<Downlink>,
+    logger: Logger,
+}
+
+impl Client {
+    /// Sends the queued PUSH_DATA frames and keepalives until the mirror is
+    /// dropped
+    async fn run(self, mut frames: mpsc::Receiver<Vec<u8>>) {
+        let mut keepalive = time::interval(KEEPALIVE);
+        let mut socket: Option<Arc<UdpSocket>> = None;
+        let mut connect_failed = false;
+        let mut buf = vec![0u8; 65_535];
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => self.send(socket.as_deref(), &frame, "push_data").await,
+                    None => return,
+                },
+                _ = keepalive.tick() => {
+                    if socket.is_none() {
+                        match connect(&self.server).await {
+                            Ok(connected) => {
+                                info!(self.logger, "mirroring to gwmp server");
+                                socket = Some(Arc::new(connected));
+                                connect_failed = false;
+                            }
+                            Err(err) if !connect_failed => {
+                                warn!(self.logger, "failed to connect to mirror server: {err}");
+                                connect_failed = true;
+                            }
+                            Err(_) => (),
+                        }
+                    }
+                    let frame = pull_data(rand::random(), self.mac);
+                    self.send(socket.as_deref(), &frame, "pull_data").await;
+                },
+                received = recv(socket.as_deref(), &mut buf) => match received {
+                    Ok(len) => self.received(socket.clone(), &buf[..len]),
+                    Err(err) => debug!(self.logger, "mirror server receive error: {err}"),
+                },
+            }
+        }
+    }
+
+    async fn send(&self, socket: Option<&UdpSocket>, frame: &[u8], kind: &str) {
+        let Some(socket) = socket else {
+            return;
+        };
+        match socket.send(frame).await {
+            Ok(_) => metrics::increment(&metrics::labeled(
+                SENT_METRIC,
+                &[("server", &self.server), ("kind", kind)],
+            )),
+            Err(err) => debug!(self.logger, "failed to send mirror {kind}: {err}"),
+        }
+    }
+
+    /// Hands a downlink of the server to the gateway, acks are ignored
+    fn received(&self, socket: Option<Arc<UdpSocket>>, frame: &[u8]) {
+        let (Some(socket), Some((token, txpk))) = (socket, parse_pull_resp(frame)) else {
+            return;
+        };
+        metrics::increment(&metrics::labeled(
+            DOWNLINKS_METRIC,
+            &[("server", &self.server)],
+        ));
+        let downlink = Downlink {
+            mac: self.mac,
+            txpk,
+            token,
+            socket,
+        };
+        if self.downlinks.try_send(downlink).is_err() {
+            warn!(self.logger, "dropping mirror downlink, gateway busy");
+        }
+    }
+}
+
+/// A UDP socket connected to the server "host:port"
+async fn connect(server: &str) -> io::Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(server)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Receives on the socket, pending forever without one
+async fn recv(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<usize> {
+    match socket {
+        Some(socket) => socket.recv(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The 8 byte gateway EUI of a MAC address
+fn eui(mac: MacAddress) -> [u8; 8] {
+    let digits: String = mac
+        .to_string()
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .collect();
+    u64::from_str_radix(&digits, 16)
+        .unwrap_or_default()
+        .to_be_bytes()
+}
+
+fn header(token: u16, identifier: u8) -> Vec<u8> {
+    let [token_hi, token_lo] = token.to_be_bytes();
+    vec![PROTOCOL_VERSION, token_hi, token_lo, identifier]
+}
+
+fn push_data(token: u16, mac: MacAddress, json: &[u8]) -> Vec<u8> {
+    let mut frame = header(token, PUSH_DATA);
+    frame.extend_from_slice(&eui(mac));
+    frame.extend_from_slice(json);
+    frame
+}
+
+fn pull_data(token: u16, mac: MacAddress) -> Vec<u8> {
+    let mut frame = header(token, PULL_DATA);
+    frame.extend_from_slice(&eui(mac));
+    frame
+}
+
+fn tx_ack(token: u16, mac: MacAddress, error: Option<&str>) -> Vec<u8> {
+    let mut frame = header(token, TX_ACK);
+    frame.extend_from_slice(&eui(mac));
+    let json = serde_json::json!({ "txpk_ack": { "error": error.unwrap_or("NONE") } });
+    frame.extend_from_slice(json.to_string().as_bytes());
+    frame
+}
+
+/// The TX_ACK error name of a forwarder error, like "TOO_LATE". A transmit
+/// at adjusted power was sent and is not an error.
+fn ack_error(err: &TxAckErr) -> String {
+    let debug = format!("{err:?}");
+    let name = debug.split('(').next().unwrap_or_default();
+    if name == "AdjustedTransmitPower" {
+        return "NONE".to_string();
+    }
+    let mut error = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            error.push('_');
+        }
+        error.push(c.to_ascii_uppercase());
+    }
+    error
+}
+
+#[derive(Debug, Deserialize)]
+struct PullResp {
+    txpk: TxPk,
+}
+
+/// The token and transmit packet of a PULL_RESP frame
+fn parse_pull_resp(frame: &[u8]) -> Option<(u16, TxPk)> {
+    match frame {
+        [PROTOCOL_VERSION, token_hi, token_lo, PULL_RESP, json @ ..] => {
+            let PullResp { txpk } = serde_json::from_slice(json).ok()?;
+            Some((u16::from_be_bytes([*token_hi, *token_lo]), txpk))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let mac = MacAddress::new(&[0xaa, 0x55, 0x5a, 0, 0, 0, 0, 1]);
+        let frame = push_data(0x1234, mac, br#"{"stat":{}}"#);
+        assert_eq!(
+            &[2, 0x12, 0x34, 0, 0xaa, 0x55, 0x5a, 0, 0, 0, 0, 1],
+            &frame[..12]
+        );
+        assert_eq!(br#"{"stat":{}}"#, &frame[12..]);
+        assert_eq!(12, pull_data(1, mac).len());
+        let ack = tx_ack(7, mac, Some("TOO_LATE"));
+        assert_eq!(&[2, 0, 7, 5], &ack[..4]);
+        assert_eq!(
+            serde_json::json!({"txpk_ack": {"error": "TOO_LATE"}}),
+            serde_json::from_slice::<serde_json::Value>(&ack[12..]).unwrap()
+        );
+        assert_eq!("TOO_LATE", ack_error(&TxAckErr::TooLate));
+        assert_eq!("TOO_EARLY", ack_error(&TxAckErr::TooEarly));
+
+        let mut pull_resp = vec![2, 0xab, 0xcd, 3];
+        pull_resp.extend_from_slice(
+            br#"{"txpk":{"imme":false,"tmst":1000000,"freq":868.1,"rfch":0,"powe":14,
+            "modu":"LORA","datr":"SF7BW125","codr":"4/5","ipol":true,"size":4,
+            "data":"AQIDBA=="}}"#,
+        );
+        let (token, _) = parse_pull_resp(&pull_resp).expect("pull_resp");
+        assert_eq!(0xabcd, token);
+        // Not a PULL_RESP
+        pull_resp[3] = 4;
+        assert!(parse_pull_resp(&pull_resp).is_none());
+    }
+}
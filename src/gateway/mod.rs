@@ -0,0 +1,1399 @@
+use crate::{
+    beaconer, clock,
+    error::DecodeError,
+    event_bus::{DownlinkReport, Event as BusEvent, EventBus},
+    logging, metrics, packet_router, region_watcher,
+    router::ForwardFilter,
+    server::{handoff, Handles},
+    settings::ForwarderBackend,
+    sync, Error, Packet, RegionParams, Result, Settings,
+};
+use beacon::{Beacon, LoraDataRate};
+#[cfg(feature = "concentratord")]
+use chirpstack_api::gw::TxAckStatus;
+use lorawan::PHYPayload;
+use semtech_udp::{
+    pull_resp::{self, Time},
+    push_data::{self, CRC},
+    server_runtime::{Error as SemtechError, Event, UdpRuntime},
+    tx_ack,
+    tx_ack::Error as TxAckErr,
+    CodingRate, MacAddress, Modulation,
+};
+use serde_json::json;
+use slog::{debug, info, o, warn, Level, Logger};
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+mod allowlist;
+#[cfg(feature = "basics-station")]
+pub mod basics_station;
+pub mod calibration;
+#[cfg(feature = "concentratord")]
+pub mod concentratord;
+pub mod crc_failed;
+pub mod downlink_stats;
+pub mod downlink_window;
+pub mod forwarder_acks;
+pub mod forwarder_conformance;
+pub mod forwarder_stats;
+mod forwarders;
+pub mod geofence;
+pub mod join_filter;
+pub mod listen;
+mod mirror;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod rf_health;
+pub mod status_led;
+pub mod tx_power;
+pub mod uplink_filter;
+pub mod uplink_metadata;
+pub mod uplink_stats;
+pub mod watchdog;
+
+use calibration::Calibration;
+use downlink_stats::{DownlinkStatsHandle, DropReason as DownlinkDropReason, Outcome};
+use downlink_window::Rx1Tolerance;
+use geofence::Geofence;
+use tx_power::TxPowerTable;
+use uplink_filter::UplinkFilter;
+use uplink_metadata::MetadataCheck;
+use uplink_stats::{Disposition, DropReason};
+
+pub const DOWNLINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// The forwarder UTC offset (in seconds) above which a clock warning is logged
+const MAX_UTC_OFFSET: f64 = 1.0;
+
+/// Minimum lead time used when scheduling a GPS aligned beacon. The beacon is
+/// placed on the first GPS second boundary at least this far in the future to
+/// give the packet forwarder time to queue it.
+const BEACON_ALIGN_LEAD: Duration = Duration::from_millis(500);
+/// Maximum age of a GPS timing reference before it is considered stale. The
+/// concentrator counter drifts relative to GPS time without a fresh reference.
+const GPS_REFERENCE_MAX_AGE: Duration = Duration::from_secs(300);
+/// Wait between attempts to bind the packet forwarder port while the process
+/// that handed off is releasing it
+const HANDOFF_REBIND_WAIT: Duration = Duration::from_millis(20);
+
+#[derive(Debug)]
+pub struct BeaconResp {
+    pub powe: i32,
+    pub tmst: u32,
+    /// Whether the beacon was scheduled on a GPS second boundary
+    pub aligned: bool,
+}
+
+/// A GPS timing reference derived from a received packet. Packet forwarders
+/// with a valid PPS/GPS lock include the GPS time `tmms` and the GPS derived
+/// UTC `time` in received packets which, together with the concentrator
+/// `tmst` counter, allows scheduling transmissions on GPS second boundaries.
+/// Some forwarders fill `time` from the host clock without a GPS lock, so
+/// only packets with a `tmms` are used.
+#[derive(Debug, Clone, Copy)]
+struct GpsReference {
+    tmst: u32,
+    subsec_us: u32,
+    received: Instant,
+}
+
+impl GpsReference {
+    fn from_rxpk(rxpk: &push_data::RxPk) -> Option<Self> {
+        let subsec_us = rxpk.get_time().as_deref().and_then(gps_subsec_micros)?;
+        // The rxpk accessors do not cover the GPS time
+        serde_json::to_value(rxpk)
+            .ok()?
+            .get("tmms")
+            .and_then(|tmms| tmms.as_u64())?;
+        Some(Self {
+            tmst: *rxpk.get_timestamp(),
+            subsec_us,
+            received: Instant::now(),
+        })
+    }
+
+    /// Returns the concentrator timestamp of the next GPS second boundary that
+    /// is at least `BEACON_ALIGN_LEAD` in the future, corrected by the counter
+    /// drift of the forwarder, or None if this reference is too old to be
+    /// trusted.
+    fn next_second_tmst(&self, drift_ppm: Option<f64>) -> Option<u32> {
+        let age = self.received.elapsed();
+        if age > GPS_REFERENCE_MAX_AGE {
+            return None;
+        }
+        Some(next_second_tmst(
+            self.tmst,
+            self.subsec_us,
+            age + BEACON_ALIGN_LEAD,
+            drift_ppm,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Downlink(Packet),
+    TransmitBeacon(Beacon, sync::ResponseSender<Result<BeaconResp>>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GatewayError {
+    #[error("unknown beacon tx power")]
+    NoBeaconTxPower,
+    #[error("beacon transmit failed")]
+    BeaconTxFailure,
+    #[error("beacons can not be transmitted through a basics station")]
+    StationBeacon,
+    #[error("no basics station connected")]
+    NoStation,
+    #[error("datarate {0} not in the basics station channel plan")]
+    StationDataRate(String),
+    #[cfg(feature = "concentratord")]
+    #[error("concentratord did not transmit: {0:?}")]
+    ConcentratordTx(TxAckStatus),
+    #[error("gateway is outside the territory of its region")]
+    Geofence,
+}
+
+pub type MessageSender = sync::MessageSender<Message>;
+pub type MessageReceiver = sync::MessageReceiver<Message>;
+
+pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
+    sync::message_channel(size)
+}
+
+impl MessageSender {
+    pub async fn downlink(&self, packet: Packet) {
+        self.send(Message::Downlink(packet)).await
+    }
+
+    /// Send a non-inverted (`ipol = false`) beacon packet that is receivable by
+    /// other gateways.
+    ///
+    /// Essentially, this packet looks like a regular uplink packet to other
+    /// gateways until further inspection.
+    pub async fn transmit_beacon(&self, beacon: Beacon) -> Result<BeaconResp> {
+        self.request(move |tx| Message::TransmitBeacon(beacon, tx))
+            .await?
+    }
+}
+
+/// The packet forwarder backend uplinks are received from and downlinks and
+/// beacons are transmitted through
+enum Backend {
+    /// A runtime per listen socket
+    SemtechUdp(Vec<UdpRuntime>),
+    #[cfg(feature = "concentratord")]
+    Concentratord(concentratord::Concentratord),
+}
+
+pub struct Gateway {
+    messages: MessageReceiver,
+    uplinks: packet_router::MessageSender,
+    beacons: beaconer::MessageSender,
+    backend: Backend,
+    /// The listen addresses of the Semtech UDP backend
+    listen_address: String,
+    region_watch: region_watcher::MessageReceiver,
+    region_params: RegionParams,
+    beacon_gps_align: bool,
+    /// The Semtech UDP packet forwarders by gateway EUI. Uplinks from other
+    /// backends are kept under the default EUI.
+    forwarders: forwarders::Forwarders,
+    /// The Semtech UDP packet forwarders allowed to send to the gateway
+    allowlist: allowlist::Allowlist,
+    /// Refuses transmissions outside the region territory
+    geofence: Geofence,
+    /// Detects a Semtech UDP packet forwarder that went silent
+    watchdog: watchdog::Watchdog,
+    /// Mirrors Semtech UDP traffic to secondary GWMP servers
+    mirror: mirror::Mirror,
+    calibration: Calibration,
+    uplink_filter: UplinkFilter,
+    /// JoinEUI and DevEUI filter of join requests, replaceable at runtime
+    join_filter: join_filter::MessageReceiver,
+    forward_filter: ForwardFilter,
+    metadata_check: MetadataCheck,
+    rx1_tolerance: Arc<Mutex<Rx1Tolerance>>,
+    /// Maximum TX power by datarate below the region maximum
+    tx_power: TxPowerTable,
+    /// The Basics Station listener, when enabled. Downlinks go to a connected
+    /// station rather than the UDP packet forwarder.
+    #[cfg(feature = "basics-station")]
+    station: Option<basics_station::Listener>,
+    events: EventBus,
+    handles: Handles,
+}
+
+/// Binds a packet forwarder port. After a handoff the old process releases
+/// the port when it exits, so binding is retried until then.
+async fn bind_udp(listen: SocketAddr) -> Result<UdpRuntime> {
+    let deadline = Instant::now() + handoff::HANDOFF_TIMEOUT;
+    loop {
+        match UdpRuntime::new(listen).await {
+            Err(_) if handoff::is_takeover() && Instant::now() < deadline => {
+                tokio::time::sleep(HANDOFF_REBIND_WAIT).await
+            }
+            result => return Ok(result.map_err(Box::new)?),
+        }
+    }
+}
+
+impl Gateway {
+    pub async fn new(
+        settings: &Settings,
+        messages: MessageReceiver,
+        region_watch: region_watcher::MessageReceiver,
+        uplinks: packet_router::MessageSender,
+        beacons: beaconer::MessageSender,
+        join_filter: join_filter::MessageReceiver,
+        events: EventBus,
+        handles: &Handles,
+    ) -> Result<Self> {
+        let region_params = region_watcher::current_value(&region_watch);
+        #[cfg(not(feature = "basics-station"))]
+        if settings.basics_station.listen.is_some() {
+            return Err(Error::custom(
+                "basics_station.listen requires the basics-station feature",
+            ));
+        }
+        #[cfg(feature = "basics-station")]
+        let station = settings
+            .basics_station
+            .listen
+            .as_deref()
+            .map(|listen| basics_station::Listener::new(listen, region_watch.clone()))
+            .transpose()?;
+        let mut listen_address = String::new();
+        let backend = match settings.forwarder.backend {
+            ForwarderBackend::SemtechUdp => {
+                let listen = listen::resolve(settings)?;
+                let mut runtimes = Vec::with_capacity(listen.len());
+                for addr in &listen {
+                    runtimes.push(bind_udp(*addr).await?);
+                }
+                listen_address = listen
+                    .iter()
+                    .map(SocketAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Backend::SemtechUdp(runtimes)
+            }
+            #[cfg(feature = "concentratord")]
+            ForwarderBackend::Concentratord => {
+                Backend::Concentratord(concentratord::Concentratord::new(&settings.forwarder))
+            }
+            #[cfg(not(feature = "concentratord"))]
+            ForwarderBackend::Concentratord => {
+                return Err(Error::custom(
+                    "forwarder backend \"concentratord\" requires the concentratord feature",
+                ))
+            }
+        };
+        let gateway = Gateway {
+            messages,
+            uplinks,
+            beacons,
+            listen_address,
+            backend,
+            region_watch,
+            region_params,
+            beacon_gps_align: settings.poc.gps_align,
+            forwarders: Default::default(),
+            allowlist: allowlist::Allowlist::new(&settings.forwarder.allowed_euis)?,
+            geofence: Geofence::new(&settings.geofence, handles.geofence.clone()),
+            mirror: mirror::Mirror::new(&settings.forwarder.mirror),
+            watchdog: watchdog::Watchdog::new(
+                &settings.forwarder,
+                settings.forwarder.backend == ForwarderBackend::SemtechUdp,
+                handles.watchdog.clone(),
+            ),
+            calibration: Calibration::new(settings)?,
+            uplink_filter: UplinkFilter::new(&settings.uplink_filter)?,
+            join_filter,
+            forward_filter: ForwardFilter::new(
+                &settings.forward_filter,
+                handles.forward_filter.clone(),
+            )?,
+            metadata_check: MetadataCheck::new(&settings.uplink_metadata),
+            rx1_tolerance: Arc::new(Mutex::new(Rx1Tolerance::new(&settings.downlink))),
+            tx_power: TxPowerTable::new(&settings.tx_power)?,
+            #[cfg(feature = "basics-station")]
+            station,
+            events,
+            handles: handles.clone(),
+        };
+        Ok(gateway)
+    }
+
+    pub async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let logger = logger.new(o!("module" => "gateway"));
+        let listen = match &self.backend {
+            Backend::SemtechUdp(_) => self.listen_address.as_str(),
+            #[cfg(feature = "concentratord")]
+            Backend::Concentratord(concentratord) => concentratord.event_url(),
+        };
+        #[cfg(feature = "basics-station")]
+        let station = self
+            .station
+            .as_ref()
+            .map(|station| station.addr().to_string());
+        #[cfg(not(feature = "basics-station"))]
+        let station: Option<String> = None;
+        info!(logger, "starting";
+            "listen" => listen,
+            "rssi_offset" => self.calibration.rssi,
+            "snr_offset" => self.calibration.snr,
+            "uplink_filter" => self.uplink_filter.is_enabled(),
+            "join_filter" => self.join_filter.borrow().is_enabled(),
+            "forward_filter" => self.forward_filter.is_enabled(),
+            "watchdog" => self.watchdog.is_enabled(),
+            "mirror" => self.mirror.is_enabled(),
+            "basics_station" => station);
+        #[cfg(feature = "basics-station")]
+        if let Some(station) = &mut self.station {
+            station.start(shutdown, &logger);
+        }
+        #[cfg(feature = "concentratord")]
+        if let Backend::Concentratord(concentratord) = &mut self.backend {
+            concentratord.start(shutdown, &logger);
+        }
+        self.geofence.set_region(&logger, &self.region_params);
+        let mut watchdog_check = tokio::time::interval(watchdog::CHECK_INTERVAL);
+        loop {
+            #[cfg(feature = "basics-station")]
+            let station_rx = station_uplink(&mut self.station);
+            #[cfg(not(feature = "basics-station"))]
+            let station_rx = std::future::pending();
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                event = udp_event(&mut self.backend) =>
+                    self.handle_udp_event(&logger, event).await?,
+                rxpk = concentratord_uplink(&mut self.backend) =>
+                    self.handle_received(&logger, rxpk, MacAddress::default()).await,
+                rxpk = station_rx =>
+                    self.handle_received(&logger, rxpk, MacAddress::default()).await,
+                downlink = self.mirror.recv() => self.handle_mirror_downlink(&logger, downlink),
+                message = self.messages.recv() => match message {
+                    Some(message) => self.handle_message(&logger, message).await,
+                    None => {
+                        warn!(logger, "ignoring closed message channel");
+                        continue;
+                    }
+                },
+                region_change = self.region_watch.changed() => match region_change {
+                    Ok(()) => {
+                        self.region_params = region_watcher::current_value(&self.region_watch);
+                        self.geofence.set_region(&logger, &self.region_params);
+                    }
+                    Err(_) => warn!(logger, "region watch disconnected")
+                },
+                _ = watchdog_check.tick() => {
+                    if self.watchdog.check(&logger, Instant::now()) {
+                        self.watchdog.recover(&logger);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles an event of the runtime of the given listen socket
+    async fn handle_udp_event(
+        &mut self,
+        logger: &Logger,
+        (socket, event): (usize, Event),
+    ) -> Result {
+        if let Event::NewClient((mac, _))
+        | Event::UpdateClient((mac, _))
+        | Event::PacketReceived(_, mac)
+        | Event::StatReceived(_, mac) = &event
+        {
+            if self.allowlist.allows(*mac) {
+                self.watchdog.seen(logger, Instant::now());
+            }
+        }
+        match event {
+            Event::UnableToParseUdpFrame(e, buf) => {
+                debug!(
+                    logger,
+                    "ignoring semtech udp parsing error {e}, raw bytes {buf:?}"
+                );
+                self.handles
+                    .forwarder_conformance
+                    .record(logger, &buf, &e.to_string());
+            }
+            Event::NewClient((mac, _)) | Event::UpdateClient((mac, _))
+                if !self.allowed(logger, mac, "client") => {}
+            Event::PacketReceived(_, mac) if !self.allowed(logger, mac, "uplink") => {
+                self.handles.uplink_stats.record(
+                    logger,
+                    Disposition::Dropped(DropReason::UnknownForwarder),
+                    None,
+                );
+            }
+            Event::StatReceived(_, mac) if !self.allowed(logger, mac, "stat") => {}
+            Event::NewClient((mac, addr)) => {
+                self.forwarders.connected(mac, addr, socket);
+                info!(logger, "new packet forwarder client: {mac}, {addr}";
+                    "forwarders" => self.forwarders.connected_count());
+                if let Err(err) = self.handles.startup.concentrator_connected(mac.to_string()) {
+                    warn!(logger, "failed to store startup record: {err:?}");
+                }
+            }
+            Event::UpdateClient((mac, addr)) => {
+                self.forwarders.connected(mac, addr, socket);
+                info!(logger, "mac existed, but IP updated: {mac}, {addr}")
+            }
+            Event::ClientDisconnected((mac, addr)) => {
+                self.forwarders.disconnected(mac);
+                info!(logger, "disconnected packet forwarder: {mac}, {addr}";
+                    "forwarders" => self.forwarders.connected_count());
+            }
+            Event::PacketReceived(rxpk, mac) => {
+                if self.mirror.is_enabled() {
+                    let rxpk = serde_json::to_value(&rxpk).unwrap_or_default();
+                    self.mirror.push(logger, mac, &json!({ "rxpk": [rxpk] }));
+                }
+                self.handle_received(logger, rxpk, mac).await
+            }
+            Event::NoClientWithMac(_packet, mac) => {
+                info!(logger, "ignoring send to client with unknown MAC: {mac}")
+            }
+            Event::StatReceived(stat, mac) => {
+                debug!(logger, "mac: {mac}, stat: {stat:?}");
+                let stat = serde_json::to_value(&stat).unwrap_or_default();
+                self.mirror.push(logger, mac, &json!({ "stat": stat }));
+                self.handles.forwarder_stats.record(&mac.to_string(), &stat);
+                if let Some(ack_percent) = stat.get("ackr").and_then(|ackr| ackr.as_f64()) {
+                    self.handles.forwarder_acks.record(
+                        logger,
+                        &self.events,
+                        &mac.to_string(),
+                        ack_percent,
+                    );
+                }
+                let gps = stat
+                    .get("lati")
+                    .and_then(|lati| lati.as_f64())
+                    .zip(stat.get("long").and_then(|long| long.as_f64()));
+                if let Some((latitude, longitude)) = gps {
+                    self.geofence.set_gps(logger, latitude, longitude);
+                }
+            }
+        };
+        Ok(())
+    }
+
+    /// Transmits a downlink of a mirror server through the packet forwarder it
+    /// is for and relays the result back to the server
+    #[cfg_attr(not(feature = "concentratord"), allow(irrefutable_let_patterns))]
+    fn handle_mirror_downlink(&self, logger: &Logger, downlink: mirror::Downlink) {
+        let Backend::SemtechUdp(runtimes) = &self.backend else {
+            return;
+        };
+        if self.geofence.blocks() {
+            debug!(
+                logger,
+                "dropping mirror downlink outside the region territory"
+            );
+            return;
+        }
+        let mut tx =
+            runtimes[self.forwarders.socket(downlink.mac)].prepare_empty_downlink(downlink.mac);
+        tx.set_packet(downlink.txpk.clone());
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            match tx.dispatch(Some(DOWNLINK_TIMEOUT)).await {
+                Ok(_) => downlink.ack(&logger, None).await,
+                Err(SemtechError::Ack(err)) => downlink.ack(&logger, Some(&err)).await,
+                Err(err) => debug!(logger, "mirror downlink failed: {err:?}"),
+            }
+        });
+    }
+
+    /// Whether the packet forwarder with the given EUI may send to the gateway.
+    /// Messages of other forwarders are logged at a throttled rate.
+    fn allowed(&mut self, logger: &Logger, mac: MacAddress, kind: &str) -> bool {
+        if self.allowlist.allows(mac) {
+            return true;
+        }
+        if let Some(suppressed) = self.allowlist.reject(mac, kind, Instant::now()) {
+            warn!(logger, "dropping {kind} of packet forwarder not in allowed_euis: {mac}";
+                "suppressed" => suppressed);
+        }
+        false
+    }
+
+    /// Handles a packet received by the packet forwarder with the given EUI or
+    /// a station
+    async fn handle_received(&mut self, logger: &Logger, rxpk: push_data::RxPk, mac: MacAddress) {
+        self.forwarders
+            .record_uplink(mac, *rxpk.get_timestamp(), *rxpk.get_frequency());
+        if self.beacon_gps_align {
+            if let Some(reference) = GpsReference::from_rxpk(&rxpk) {
+                self.forwarders.get_mut(mac).gps_reference = Some(reference);
+            }
+        }
+        self.observe_forwarder_clock(logger, &rxpk, mac);
+        self.handles.rf_pool.mix_rf_sample(
+            rxpk.get_signal_rssi()
+                .unwrap_or_else(|| rxpk.get_channel_rssi()),
+            rxpk.get_snr(),
+            *rxpk.get_timestamp(),
+        );
+        self.handle_rxpk(logger, rxpk, mac).await
+    }
+
+    /// The sender of a connected station, if any
+    #[cfg(feature = "basics-station")]
+    fn station(&self) -> Option<basics_station::Sender> {
+        self.station
+            .as_ref()
+            .map(basics_station::Listener::sender)
+            .filter(basics_station::Sender::is_connected)
+    }
+
+    fn observe_forwarder_clock(
+        &mut self,
+        logger: &Logger,
+        rxpk: &push_data::RxPk,
+        mac: MacAddress,
+    ) {
+        let clock = &mut self.forwarders.get_mut(mac).clock;
+        clock.observe_tmst(*rxpk.get_timestamp(), Instant::now());
+
+        let Some(time) = rxpk.get_time().as_deref() else {
+            return;
+        };
+        let was_offset = clock
+            .utc_offset()
+            .is_some_and(|offset| offset.abs() > MAX_UTC_OFFSET);
+        if let Some(offset) = clock.observe_utc(time, SystemTime::now()) {
+            if offset.abs() > MAX_UTC_OFFSET && !was_offset {
+                warn!(logger, "packet forwarder clock offset from host";
+                    "mac" => mac.to_string(),
+                    "offset" => offset,
+                    "tmst_drift_ppm" => clock.drift_ppm());
+            }
+        }
+    }
+
+    async fn handle_rxpk(&mut self, logger: &Logger, rxpk: push_data::RxPk, mac: MacAddress) {
+        self.handles.channel_mask.record(
+            *rxpk.get_frequency(),
+            rxpk.get_crc_status() == &CRC::OK,
+            rxpk.get_signal_rssi()
+                .unwrap_or_else(|| rxpk.get_channel_rssi()) as f64,
+            rxpk.get_snr() as f64,
+        );
+        self.handles.crc_failed.record(mac, &rxpk);
+        let packet = Packet::try_from(rxpk).map(|mut packet| {
+            self.calibration.apply(&mut packet);
+            packet
+        });
+        match packet {
+            Ok(packet) if packet.is_potential_beacon() => {
+                self.beacons.received_beacon(packet).await
+            }
+            Ok(packet) => self.handle_uplink(logger, packet, mac).await,
+            Err(err) => {
+                warn!(logger, "ignoring push_data: {err:?}");
+                let reason = match err {
+                    Error::Decode(DecodeError::InvalidCrc) => DropReason::Crc,
+                    _ => DropReason::Decode,
+                };
+                self.handles
+                    .uplink_stats
+                    .record(logger, Disposition::Dropped(reason), None);
+            }
+        }
+    }
+
+    async fn handle_uplink(&mut self, logger: &Logger, mut packet: Packet, mac: MacAddress) {
+        if let Err(missing) = self.metadata_check.check(&mut packet) {
+            let missing: Vec<_> = missing.iter().map(|field| field.as_str()).collect();
+            debug!(logger, "ignoring uplink with missing metadata {}", packet;
+                "missing" => missing.join(","));
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::Metadata),
+                Some(packet.payload()),
+            );
+            return;
+        }
+        if let Err(check) = self.uplink_filter.check(&packet) {
+            debug!(logger, "ignoring garbage uplink {}", packet; "check" => check.as_str());
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::Garbage),
+                Some(packet.payload()),
+            );
+            return;
+        }
+        if let Err(check) = self.join_filter.borrow().check(&packet) {
+            debug!(logger, "ignoring filtered join request {}", packet;
+                "check" => check.as_str());
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::JoinDenied),
+                Some(packet.payload()),
+            );
+            return;
+        }
+        if let Err(rule) = self.forward_filter.check(&packet) {
+            debug!(logger, "ignoring filtered uplink {}", packet; "rule" => rule);
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::Denied),
+                Some(packet.payload()),
+            );
+            return;
+        }
+        if self.handles.data_usage.over_cap() && !packet.is_join_request() {
+            debug!(logger, "ignoring uplink over data cap {}", packet);
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::DataCap),
+                Some(packet.payload()),
+            );
+            return;
+        }
+        if let Some(suppressed) = logging::sampling::sample(Level::Info, "uplink") {
+            info!(logger, "uplink {} from {}", packet, mac;
+                "suppressed" => suppressed);
+        }
+        self.events
+            .publish(BusEvent::UplinkReceived(packet.clone()));
+        let received = packet.received().instant;
+        self.uplinks.uplink(packet, received).await;
+    }
+
+    async fn handle_message(&mut self, logger: &Logger, message: Message) {
+        match message {
+            Message::Downlink(packet) => self.handle_downlink(logger, packet).await,
+            Message::TransmitBeacon(beacon, tx_resp) => {
+                self.handle_transmit_beacon(logger, beacon, tx_resp).await
+            }
+        }
+    }
+
+    fn max_tx_power(&mut self) -> Result<u32> {
+        Ok(self.region_params.max_conducted_power()?)
+    }
+
+    async fn handle_transmit_beacon(
+        &mut self,
+        logger: &Logger,
+        beacon: Beacon,
+        responder: sync::ResponseSender<Result<BeaconResp>>,
+    ) {
+        #[cfg(feature = "basics-station")]
+        if self.station().is_some() {
+            let err = GatewayError::StationBeacon;
+            warn!(logger, "ignoring transmit: {err}");
+            responder.send(Err(err.into()), logger);
+            return;
+        }
+        if self.geofence.blocks() {
+            let err = GatewayError::Geofence;
+            warn!(logger, "ignoring transmit: {err}");
+            responder.send(Err(err.into()), logger);
+            return;
+        }
+        // Beacons are made at the maximum power of the region but can ask for
+        // less, like test beacons
+        let tx_power = match self.max_tx_power() {
+            Ok(tx_power) => self.tx_power.limit(
+                &beacon.datarate.to_string(),
+                beacon.conducted_power.min(tx_power),
+            ),
+            Err(err) => {
+                warn!(logger, "ignoring transmit: {err}");
+                responder.send(Err(err), logger);
+                return;
+            }
+        };
+
+        // Send the beacon through the forwarder that receives closest to its
+        // frequency. Align it to a GPS second boundary when enabled and a
+        // recent GPS timing reference of that forwarder is available,
+        // otherwise send immediately
+        let mac = match self.backend {
+            Backend::SemtechUdp(_) => self.forwarders.beacon_mac(beacon.frequency as f64 / 1e6),
+            #[cfg(feature = "concentratord")]
+            Backend::Concentratord(_) => MacAddress::default(),
+        };
+        let aligned_tmst = if self.beacon_gps_align {
+            self.forwarders.get(mac).and_then(|forwarder| {
+                forwarder
+                    .gps_reference
+                    .and_then(|reference| reference.next_second_tmst(forwarder.clock.drift_ppm()))
+            })
+        } else {
+            None
+        };
+        let time = aligned_tmst.map_or_else(Time::immediate, Time::by_tmst);
+        let aligned = aligned_tmst.is_some();
+
+        let packet = match beacon_to_pull_resp(&beacon, tx_power as u64, time) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!(logger, "failed to construct beacon pull resp: {err:?}");
+                responder.send(Err(err), logger);
+                return;
+            }
+        };
+
+        let udp_runtime = match &self.backend {
+            Backend::SemtechUdp(runtimes) => &runtimes[self.forwarders.socket(mac)],
+            #[cfg(feature = "concentratord")]
+            Backend::Concentratord(concentratord) => {
+                let tx = ConcentratordBeacon {
+                    sender: concentratord.sender(),
+                    packet,
+                    tx_power,
+                    aligned_tmst,
+                };
+                tx.spawn(logger, beacon, responder);
+                return;
+            }
+        };
+        let beacon_tx = udp_runtime.prepare_downlink(packet, mac);
+
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let beacon_id = beacon.beacon_id();
+            match beacon_tx.dispatch(Some(DOWNLINK_TIMEOUT)).await {
+                Ok(tmst) => {
+                    info!(logger, "beacon transmitted"; 
+                        "beacon" => &beacon_id, 
+                        "power" => tx_power, 
+                        "tmst" => tmst,
+                        "aligned" => aligned);
+                    responder.send(
+                        Ok(BeaconResp {
+                            powe: tx_power as i32,
+                            tmst: tmst.or(aligned_tmst).unwrap_or(0),
+                            aligned,
+                        }),
+                        &logger,
+                    );
+                    tmst
+                }
+                Err(err) => {
+                    if let semtech_udp::server_runtime::Error::Ack(
+                        tx_ack::Error::AdjustedTransmitPower(power_used, tmst),
+                    ) = err
+                    {
+                        match power_used {
+                            None => {
+                                warn!(logger, "packet transmitted with adjusted power, but packet forwarder does not indicate power used.");
+                                responder.send(Err(GatewayError::NoBeaconTxPower.into()), &logger);
+                            }
+                            Some(actual_power) => {
+                                info!(logger, "beacon transmitted with adjusted power output"; "beacon" => &beacon_id, "power" => actual_power, "tmst" => tmst, "aligned" => aligned);
+                                responder.send(
+                                    Ok(BeaconResp {
+                                        powe: actual_power,
+                                        tmst: tmst.or(aligned_tmst).unwrap_or(0),
+                                        aligned,
+                                    }),
+                                    &logger,
+                                );
+                            }
+                        }
+                        tmst
+                    } else {
+                        warn!(logger, "failed to transmit beacon:  {err:?}"; "beacon" => &beacon_id);
+                        responder.send(Err(GatewayError::BeaconTxFailure.into()), &logger);
+                        None
+                    }
+                }
+            }
+        });
+    }
+
+    async fn handle_downlink(&mut self, logger: &Logger, downlink: Packet) {
+        self.events
+            .publish(BusEvent::DownlinkRequested(downlink.clone()));
+        if self.geofence.blocks() {
+            self.handles.downlink_stats.record(
+                logger,
+                Outcome::Dropped(DownlinkDropReason::Geofence),
+                None,
+                Some(GatewayError::Geofence.to_string()),
+            );
+            return;
+        }
+        #[cfg(feature = "basics-station")]
+        if let Some(station) = self.station() {
+            self.handle_station_downlink(logger, station, downlink);
+            return;
+        }
+        let tx_power = match self.max_tx_power() {
+            Ok(tx_power) => tx_power,
+            Err(err) => {
+                warn!(logger, "ignoring transmit: {err}");
+                self.handles.downlink_stats.record(
+                    logger,
+                    Outcome::Dropped(DownlinkDropReason::NoTxPower),
+                    None,
+                    Some(err.to_string()),
+                );
+                return;
+            }
+        };
+
+        // Send the downlink through the forwarder that received the uplink it
+        // answers
+        let downlink_mac = self.forwarders.downlink_mac(downlink.timestamp as u32);
+        let udp_runtime = match &self.backend {
+            Backend::SemtechUdp(runtimes) => &runtimes[self.forwarders.socket(downlink_mac)],
+            #[cfg(feature = "concentratord")]
+            Backend::Concentratord(concentratord) => {
+                let sender = concentratord.sender();
+                self.handle_concentratord_downlink(logger, sender, downlink, tx_power);
+                return;
+            }
+        };
+        let (mut downlink_rx1, mut downlink_rx2) = (
+            // first downlink
+            udp_runtime.prepare_empty_downlink(downlink_mac),
+            // 2nd downlink window if requested by the router response
+            udp_runtime.prepare_empty_downlink(downlink_mac),
+        );
+
+        // Skip an RX1 window that can no longer be reached when there is an RX2
+        // window to fall back to
+        let skip_rx1 = self
+            .rx1_tolerance
+            .lock()
+            .expect("rx1 tolerance lock")
+            .skip_rx1(
+                downlink.timestamp as u32,
+                downlink.rx2_window.is_some(),
+                self.forwarders
+                    .get(downlink_mac)
+                    .and_then(|forwarder| forwarder.clock.estimate_tmst(Instant::now())),
+            );
+
+        let rx1_power = self.tx_power.limit(&downlink.datarate, tx_power);
+        let rx2_power = downlink.rx2_window.as_ref().map_or(tx_power, |window| {
+            self.tx_power.limit(&window.datarate, tx_power)
+        });
+
+        let rx1_tolerance = self.rx1_tolerance.clone();
+        let events = self.events.clone();
+        let stats = self.handles.downlink_stats.clone();
+        let logger = logger.clone();
+
+        tokio::spawn(async move {
+            // Why rx1 was not used, to retry on the rx2 slot if available
+            let rx1_missed = if skip_rx1 {
+                debug!(logger, "rx1 downlink too late, using rx2");
+                metrics::increment(downlink_window::SKIPPED_METRIC);
+                "rx1 skipped".to_string()
+            } else {
+                let txpk = match downlink.to_rx1_pull_resp(rx1_power) {
+                    Ok(txpk) => txpk,
+                    Err(err) => {
+                        stats.record(
+                            &logger,
+                            Outcome::Dropped(DownlinkDropReason::Encode),
+                            Some("rx1"),
+                            Some(format!("{err:?}")),
+                        );
+                        return;
+                    }
+                };
+                if let Some(suppressed) = logging::sampling::sample(Level::Info, "downlink") {
+                    info!(logger, "rx1 downlink {txpk} via {downlink_mac}";
+                        "suppressed" => suppressed);
+                }
+
+                let report = DownlinkReporter::new(
+                    events.clone(),
+                    "rx1",
+                    &downlink,
+                    &txpk,
+                    downlink.timestamp,
+                );
+                downlink_rx1.set_packet(txpk);
+                let dispatched = Instant::now();
+                let result = downlink_rx1.dispatch(Some(DOWNLINK_TIMEOUT)).await;
+                report.publish(&result);
+                if matches!(result, Ok(_) | Err(SemtechError::Ack(_))) {
+                    let too_late = matches!(result, Err(SemtechError::Ack(TxAckErr::TooLate)));
+                    let mut tolerance = rx1_tolerance.lock().expect("rx1 tolerance lock");
+                    tolerance.record_rtt(dispatched.elapsed());
+                    let tuned = tolerance.record(too_late);
+                    if let Some(margin) = tuned {
+                        info!(logger, "rx1 margin tuned"; "margin_ms" => margin.as_millis() as u64);
+                    }
+                }
+                match result {
+                    // On a too early or too late error retry on the rx2 slot if available.
+                    Err(SemtechError::Ack(err)) if downlink_window::retry_in_rx2(&err) => {
+                        format!("{err:?}")
+                    }
+                    result => {
+                        record_downlink_result(&logger, &stats, "rx1", &result);
+                        return;
+                    }
+                }
+            };
+
+            let txpk = match downlink.to_rx2_pull_resp(rx2_power) {
+                Ok(Some(txpk)) => txpk,
+                Ok(None) => {
+                    stats.record(
+                        &logger,
+                        Outcome::Dropped(DownlinkDropReason::WindowMissed),
+                        Some("rx1"),
+                        Some(rx1_missed),
+                    );
+                    return;
+                }
+                Err(err) => {
+                    stats.record(
+                        &logger,
+                        Outcome::Dropped(DownlinkDropReason::Encode),
+                        Some("rx2"),
+                        Some(format!("{err:?}")),
+                    );
+                    return;
+                }
+            };
+            if let Some(suppressed) = logging::sampling::sample(Level::Info, "downlink") {
+                info!(logger, "rx2 downlink {txpk} via {downlink_mac}";
+                    "suppressed" => suppressed);
+            }
+
+            let report = DownlinkReporter::new(
+                events,
+                "rx2",
+                &downlink,
+                &txpk,
+                downlink.rx2_window.as_ref().map_or(0, |w| w.timestamp),
+            );
+            downlink_rx2.set_packet(txpk);
+            let dispatched = Instant::now();
+            let result = downlink_rx2.dispatch(Some(DOWNLINK_TIMEOUT)).await;
+            report.publish(&result);
+            if matches!(result, Ok(_) | Err(SemtechError::Ack(_))) {
+                rx1_tolerance
+                    .lock()
+                    .expect("rx1 tolerance lock")
+                    .record_rtt(dispatched.elapsed());
+            }
+            record_downlink_result(&logger, &stats, "rx2", &result);
+        });
+    }
+
+    /// Transmits a downlink through concentratord, which falls back to the rx2
+    /// window by itself when the rx1 window can not be used
+    #[cfg(feature = "concentratord")]
+    fn handle_concentratord_downlink(
+        &self,
+        logger: &Logger,
+        sender: concentratord::Sender,
+        downlink: Packet,
+        tx_power: u32,
+    ) {
+        let rx1_power = self.tx_power.limit(&downlink.datarate, tx_power);
+        let rx2_power = downlink.rx2_window.as_ref().map_or(tx_power, |window| {
+            self.tx_power.limit(&window.datarate, tx_power)
+        });
+        let encode_failed = |window, err: Error| {
+            self.handles.downlink_stats.record(
+                logger,
+                Outcome::Dropped(DownlinkDropReason::Encode),
+                Some(window),
+                Some(format!("{err:?}")),
+            )
+        };
+        let rx1 = match downlink.to_rx1_pull_resp(rx1_power) {
+            Ok(txpk) => txpk,
+            Err(err) => return encode_failed("rx1", err),
+        };
+        let rx2 = match downlink.to_rx2_pull_resp(rx2_power) {
+            Ok(rx2) => rx2,
+            Err(err) => return encode_failed("rx2", err),
+        };
+        if let Some(suppressed) = logging::sampling::sample(Level::Info, "downlink") {
+            info!(logger, "rx1 downlink {rx1} via concentratord";
+                "suppressed" => suppressed);
+        }
+        let mut reports = vec![DownlinkReporter::new(
+            self.events.clone(),
+            "rx1",
+            &downlink,
+            &rx1,
+            downlink.timestamp,
+        )];
+        let mut txpks = vec![rx1];
+        if let Some(rx2) = rx2 {
+            reports.push(DownlinkReporter::new(
+                self.events.clone(),
+                "rx2",
+                &downlink,
+                &rx2,
+                downlink.rx2_window.as_ref().map_or(0, |w| w.timestamp),
+            ));
+            txpks.push(rx2);
+        }
+
+        let stats = self.handles.downlink_stats.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let result = sender.transmit(&txpks).await;
+            // Report the window that was sent or, when none was, the last one
+            // tried
+            let index = *result.as_ref().unwrap_or(&(reports.len() - 1));
+            let report = reports.swap_remove(index);
+            let window = report.report.window;
+            let (outcome, detail) = match result {
+                Ok(_) => {
+                    report.finish("sent".to_string());
+                    (Outcome::Sent, None)
+                }
+                Err(err) => {
+                    warn!(logger, "ignoring {window} downlink error: {err:?}");
+                    let reason = match err {
+                        #[cfg(feature = "concentratord")]
+                        Error::Gateway(GatewayError::ConcentratordTx(
+                            TxAckStatus::TooEarly | TxAckStatus::TooLate,
+                        )) => DownlinkDropReason::WindowMissed,
+                        #[cfg(feature = "concentratord")]
+                        Error::Gateway(GatewayError::ConcentratordTx(_)) => {
+                            DownlinkDropReason::ForwarderNack
+                        }
+                        _ => DownlinkDropReason::Forwarder,
+                    };
+                    report.finish(err.to_string());
+                    (Outcome::Dropped(reason), Some(err.to_string()))
+                }
+            };
+            stats.record(&logger, outcome, Some(window), detail);
+        });
+    }
+
+    /// Transmits a downlink through a station, which picks the receive window
+    /// and transmit power itself
+    #[cfg(feature = "basics-station")]
+    fn handle_station_downlink(
+        &self,
+        logger: &Logger,
+        station: basics_station::Sender,
+        downlink: Packet,
+    ) {
+        if let Some(suppressed) = logging::sampling::sample(Level::Info, "downlink") {
+            info!(logger, "station downlink {downlink}"; "suppressed" => suppressed);
+        }
+        let stats = self.handles.downlink_stats.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let (outcome, detail) = match station.transmit(&downlink).await {
+                Ok(()) => (Outcome::Sent, None),
+                Err(err) => {
+                    warn!(logger, "ignoring station downlink error: {err:?}");
+                    let reason = match err {
+                        Error::Gateway(GatewayError::StationDataRate(_)) => {
+                            DownlinkDropReason::Encode
+                        }
+                        _ => DownlinkDropReason::Forwarder,
+                    };
+                    (Outcome::Dropped(reason), Some(err.to_string()))
+                }
+            };
+            stats.record(&logger, outcome, None, detail);
+        });
+    }
+}
+
+/// Receives the next event of the Semtech UDP backend, pending forever with
+/// another backend
+async fn udp_event(backend: &mut Backend) -> (usize, Event) {
+    match backend {
+        Backend::SemtechUdp(runtimes) => {
+            let recvs = runtimes.iter_mut().map(|runtime| Box::pin(runtime.recv()));
+            let (event, socket, _) = futures::future::select_all(recvs).await;
+            (socket, event)
+        }
+        #[cfg(feature = "concentratord")]
+        Backend::Concentratord(_) => std::future::pending().await,
+    }
+}
+
+/// Receives the next uplink of the concentratord backend, pending forever with
+/// another backend
+async fn concentratord_uplink(backend: &mut Backend) -> push_data::RxPk {
+    match backend {
+        #[cfg(feature = "concentratord")]
+        Backend::Concentratord(concentratord) => match concentratord.recv().await {
+            Some(rxpk) => rxpk,
+            None => std::future::pending().await,
+        },
+        Backend::SemtechUdp(_) => std::future::pending().await,
+    }
+}
+
+/// A beacon transmitted through concentratord
+#[cfg(feature = "concentratord")]
+struct ConcentratordBeacon {
+    sender: concentratord::Sender,
+    packet: pull_resp::TxPk,
+    tx_power: u32,
+    /// The GPS aligned transmit time, if any
+    aligned_tmst: Option<u32>,
+}
+
+#[cfg(feature = "concentratord")]
+impl ConcentratordBeacon {
+    fn spawn(
+        self,
+        logger: &Logger,
+        beacon: Beacon,
+        responder: sync::ResponseSender<Result<BeaconResp>>,
+    ) {
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let beacon_id = beacon.beacon_id();
+            let aligned = self.aligned_tmst.is_some();
+            match self.sender.transmit(&[self.packet]).await {
+                Ok(_) => {
+                    info!(logger, "beacon transmitted";
+                        "beacon" => &beacon_id,
+                        "power" => self.tx_power,
+                        "tmst" => self.aligned_tmst,
+                        "aligned" => aligned);
+                    responder.send(
+                        Ok(BeaconResp {
+                            powe: self.tx_power as i32,
+                            tmst: self.aligned_tmst.unwrap_or(0),
+                            aligned,
+                        }),
+                        &logger,
+                    );
+                }
+                Err(err) => {
+                    warn!(logger, "failed to transmit beacon:  {err:?}"; "beacon" => &beacon_id);
+                    responder.send(Err(GatewayError::BeaconTxFailure.into()), &logger);
+                }
+            }
+        });
+    }
+}
+
+/// Receives the next uplink of a station, pending forever without a station
+/// listener
+#[cfg(feature = "basics-station")]
+async fn station_uplink(station: &mut Option<basics_station::Listener>) -> push_data::RxPk {
+    match station {
+        Some(station) => match station.recv().await {
+            Some(rxpk) => rxpk,
+            None => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Logs and records the final outcome of a downlink transmitted in the given
+/// receive window
+fn record_downlink_result<T>(
+    logger: &Logger,
+    stats: &DownlinkStatsHandle,
+    window: &'static str,
+    result: &std::result::Result<T, SemtechError>,
+) {
+    let (outcome, detail) = match result {
+        Ok(_) => (Outcome::Sent, None),
+        Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
+            warn!(
+                logger,
+                "{window} downlink sent with adjusted transmit power"
+            );
+            (Outcome::PowerClamped, None)
+        }
+        Err(err) => {
+            warn!(logger, "ignoring {window} downlink error: {err:?}");
+            let reason = match err {
+                SemtechError::Ack(TxAckErr::TooEarly | TxAckErr::TooLate) => {
+                    DownlinkDropReason::WindowMissed
+                }
+                SemtechError::Ack(_) => DownlinkDropReason::ForwarderNack,
+                _ => DownlinkDropReason::Forwarder,
+            };
+            (Outcome::Dropped(reason), Some(format!("{err:?}")))
+        }
+    };
+    stats.record(logger, outcome, Some(window), detail);
+}
+
+/// Mirrors the result of a downlink transmission in one receive window to
+/// event bus subscribers.
+struct DownlinkReporter {
+    events: EventBus,
+    report: DownlinkReport,
+    dispatched: Instant,
+}
+
+impl DownlinkReporter {
+    fn new(
+        events: EventBus,
+        window: &'static str,
+        downlink: &Packet,
+        txpk: &pull_resp::TxPk,
+        tmst: u64,
+    ) -> Self {
+        Self {
+            events,
+            report: DownlinkReport {
+                window,
+                frequency: txpk.freq,
+                datarate: txpk.datr.to_string(),
+                tx_power: txpk.powe,
+                tmst: tmst as u32,
+                payload_size: downlink.payload.len(),
+                result: String::new(),
+                ack_ms: 0,
+            },
+            dispatched: Instant::now(),
+        }
+    }
+
+    fn publish<T>(self, result: &std::result::Result<T, SemtechError>) {
+        let result = match result {
+            Ok(_) => "sent".to_string(),
+            Err(SemtechError::Ack(TxAckErr::TooEarly)) => "too_early".to_string(),
+            Err(SemtechError::Ack(TxAckErr::TooLate)) => "too_late".to_string(),
+            Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
+                "adjusted_power".to_string()
+            }
+            Err(err) => format!("{err:?}"),
+        };
+        self.finish(result)
+    }
+
+    fn finish(mut self, result: String) {
+        self.report.result = result;
+        self.report.ack_ms = self.dispatched.elapsed().as_millis() as u64;
+        self.events.publish(BusEvent::DownlinkResult(self.report));
+    }
+}
+
+pub fn beacon_to_pull_resp(beacon: &Beacon, tx_power: u64, time: Time) -> Result<pull_resp::TxPk> {
+    frame_to_pull_resp(
+        &beacon.data,
+        beacon.frequency,
+        &beacon.datarate,
+        tx_power,
+        time,
+    )
+}
+
+/// Builds a transmit packet for a proprietary LoRa frame with the given
+/// payload, frequency in Hz and datarate
+pub fn frame_to_pull_resp(
+    payload: &[u8],
+    frequency: u64,
+    datarate: &LoraDataRate,
+    tx_power: u64,
+    time: Time,
+) -> Result<pull_resp::TxPk> {
+    let datr = datarate.to_string().parse()?;
+    // convert hz to mhz
+    let freq = frequency as f64 / 1e6;
+    let data: Vec<u8> = PHYPayload::proprietary(payload).try_into()?;
+
+    Ok(pull_resp::TxPk {
+        time,
+        ipol: false,
+        modu: Modulation::LORA,
+        codr: CodingRate::_4_5,
+        datr,
+        freq,
+        data: pull_resp::PhyData::new(data),
+        powe: tx_power,
+        rfch: 0,
+        fdev: None,
+        prea: None,
+        ncrc: None,
+    })
+}
+
+/// Parses the sub-second part of a packet forwarder UTC time string (for
+/// example "2013-03-31T16:21:17.528002Z") into microseconds.
+fn gps_subsec_micros(time: &str) -> Option<u32> {
+    let fraction = time.split_once('.')?.1.trim_end_matches('Z');
+    if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Normalize to exactly 6 digits (microseconds)
+    let micros: String = fraction.chars().chain("000000".chars()).take(6).collect();
+    micros.parse().ok()
+}
+
+/// Computes the concentrator timestamp for the first GPS second boundary that
+/// is at least `lead` after a reference packet received at `tmst` with the
+/// given sub-second GPS offset. The GPS time to the boundary is converted to
+/// counter time with the counter drift. The counter wraps at 2^32
+/// microseconds.
+fn next_second_tmst(tmst: u32, subsec_us: u32, lead: Duration, drift_ppm: Option<f64>) -> u32 {
+    const SECOND_US: u64 = 1_000_000;
+    let to_boundary = SECOND_US - (subsec_us as u64 % SECOND_US);
+    let lead_us = lead.as_micros() as u64;
+    let extra_seconds = lead_us.saturating_sub(to_boundary).div_ceil(SECOND_US);
+    let offset = clock::correct_micros(to_boundary + extra_seconds * SECOND_US, drift_ppm);
+    tmst.wrapping_add(offset as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gps_subsec_micros() {
+        assert_eq!(
+            Some(528002),
+            gps_subsec_micros("2013-03-31T16:21:17.528002Z")
+        );
+        assert_eq!(Some(500000), gps_subsec_micros("2013-03-31T16:21:17.5Z"));
+        assert_eq!(
+            Some(123456),
+            gps_subsec_micros("2013-03-31T16:21:17.123456789Z")
+        );
+        assert_eq!(None, gps_subsec_micros("2013-03-31T16:21:17Z"));
+    }
+
+    #[test]
+    fn test_next_second_tmst() {
+        // Reference at 0.75s into a GPS second, 0.5s lead lands two boundaries out
+        assert_eq!(
+            1_250_000,
+            next_second_tmst(0, 750_000, Duration::from_millis(500), None)
+        );
+        // Enough time to the next boundary
+        assert_eq!(
+            1_000_000,
+            next_second_tmst(500_000, 500_000, Duration::from_millis(100), None)
+        );
+        // Counter wraparound
+        assert_eq!(
+            999_999,
+            next_second_tmst(u32::MAX, 0, Duration::from_millis(100), None)
+        );
+        // A boundary a minute out on a counter running 100 ppm fast
+        assert_eq!(
+            60_256_025,
+            next_second_tmst(0, 750_000, Duration::from_secs(60), Some(100.0))
+        );
+    }
+}
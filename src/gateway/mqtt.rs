@@ -0,0 +1,391 @@
+//! A bridge between the gateway and an MQTT broker, for local automation and
+//! private LNS setups.
+//!
+//! Uplinks the gateway hands to its router are also published to the uplink
+//! topic, and downlinks published to the downlink topic are transmitted like
+//! router downlinks. The Helium router path is not affected. Uplinks and
+//! downlinks are encoded as JSON or as a protobuf `helium_proto::Packet`. The
+//! transmit result of every downlink window is published to the result topic
+//! as JSON. With a `recipient` set, published uplinks and results are
+//! encrypted to that age X25519 recipient, so a third-party broker only sees
+//! ciphertext.
+//!
+//! A JSON uplink looks like
+//!
+//! ```json
+//! {"payload":"QDDaAAEA...","timestamp":3512348611,"frequency":868.1,
+//!  "datarate":"SF7BW125","rssi":-52.0,"snr":9.5}
+//! ```
+//!
+//! where the payload is base64 encoded, the timestamp is the concentrator
+//! timestamp in microseconds and the frequency is in MHz. A JSON downlink has
+//! the same `payload`, `timestamp` (of the rx1 window), `frequency` and
+//! `datarate` fields and an optional `rx2` window with its own `timestamp`,
+//! `frequency` and `datarate`.
+
+use crate::{
+    event_bus::{Event, EventBus},
+    gateway, metrics,
+    settings::{MqttFormat, MqttSettings},
+    Base64, Error, Packet, Result, Settings,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::packet::PacketType;
+use prost::Message;
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Packet as MqttPacket, QoS};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, o, warn, Logger};
+use std::{io::Write, time::Duration};
+use tokio::{sync::broadcast::error::RecvError, time};
+
+/// Gauge which is 1 while the broker is connected
+pub const CONNECTED_METRIC: &str = "mqtt_connected";
+pub const UPLINKS_METRIC: &str = "mqtt_uplinks_total";
+pub const DOWNLINKS_METRIC: &str = "mqtt_downlinks_total";
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait before reconnecting to the broker after a connection error
+const RECONNECT_WAIT: Duration = Duration::from_secs(5);
+/// Requests queued for the broker connection
+const CLIENT_CAPACITY: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct JsonUplink {
+    payload: String,
+    timestamp: u64,
+    frequency: f32,
+    datarate: String,
+    rssi: f32,
+    snr: f32,
+}
+
+impl From<&Packet> for JsonUplink {
+    fn from(packet: &Packet) -> Self {
+        Self {
+            payload: packet.payload().to_b64(),
+            timestamp: packet.timestamp,
+            frequency: packet.frequency,
+            datarate: packet.datarate.clone(),
+            rssi: packet.signal_strength,
+            snr: packet.snr,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonDownlink {
+    payload: String,
+    timestamp: u64,
+    frequency: f32,
+    datarate: String,
+    rx2: Option<JsonWindow>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonWindow {
+    timestamp: u64,
+    frequency: f32,
+    datarate: String,
+}
+
+impl TryFrom<JsonDownlink> for Packet {
+    type Error = Error;
+
+    fn try_from(downlink: JsonDownlink) -> Result<Self> {
+        let payload = STANDARD
+            .decode(&downlink.payload)
+            .map_err(|err| Error::custom(format!("invalid downlink payload: {err}")))?;
+        Ok(helium_proto::Packet {
+            oui: 0,
+            r#type: PacketType::Lorawan.into(),
+            payload,
+            timestamp: downlink.timestamp,
+            signal_strength: 0.0,
+            frequency: downlink.frequency,
+            datarate: downlink.datarate,
+            snr: 0.0,
+            routing: None,
+            rx2_window: downlink.rx2.map(|window| helium_proto::Window {
+                timestamp: window.timestamp,
+                frequency: window.frequency,
+                datarate: window.datarate,
+            }),
+        }
+        .into())
+    }
+}
+
+/// Encrypts published messages to an age X25519 recipient
+struct Encryption(age::x25519::Recipient);
+
+impl Encryption {
+    fn new(recipient: &str) -> Result<Self> {
+        recipient
+            .trim()
+            .parse()
+            .map(Self)
+            .map_err(|err| Error::custom(format!("invalid mqtt recipient: {err}")))
+    }
+
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let encryptor = age::Encryptor::with_recipients(vec![Box::new(self.0.clone())])
+            .ok_or_else(|| Error::custom("no mqtt recipient"))?;
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(payload)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+}
+
+pub struct MqttBridge {
+    /// The broker host and port, when the bridge is enabled
+    broker: Option<(String, u16)>,
+    settings: MqttSettings,
+    /// Encrypts published messages, when a recipient is set
+    encryption: Option<Encryption>,
+    transmit: gateway::MessageSender,
+    events: EventBus,
+}
+
+impl MqttBridge {
+    pub fn new(
+        settings: &Settings,
+        transmit: gateway::MessageSender,
+        events: EventBus,
+    ) -> Result<Self> {
+        let broker = settings
+            .mqtt
+            .broker
+            .as_deref()
+            .map(broker_addr)
+            .transpose()?;
+        let encryption = settings
+            .mqtt
+            .recipient
+            .as_deref()
+            .map(Encryption::new)
+            .transpose()?;
+        Ok(Self {
+            broker,
+            settings: settings.mqtt.clone(),
+            encryption,
+            transmit,
+            events,
+        })
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let Some((host, port)) = &self.broker else {
+            return Ok(());
+        };
+        let logger = logger.new(o!("module" => "mqtt"));
+        info!(logger, "starting";
+            "broker" => format!("{host}:{port}"),
+            "uplink_topic" => &self.settings.uplink_topic,
+            "downlink_topic" => &self.settings.downlink_topic,
+            "encrypted" => self.encryption.is_some());
+
+        let mut options = MqttOptions::new(&self.settings.client_id, host, *port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &self.settings.username {
+            options.set_credentials(
+                username,
+                self.settings.password.as_deref().unwrap_or_default(),
+            );
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, CLIENT_CAPACITY);
+        let mut events = self.events.subscribe();
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    let _ = client.try_disconnect();
+                    metrics::set(CONNECTED_METRIC, 0.0);
+                    return Ok(())
+                },
+                event = events.recv() => match event {
+                    Ok(Event::UplinkReceived(packet)) => self.publish_uplink(&logger, &client, &packet),
+                    Ok(Event::DownlinkResult(report)) => {
+                        match serde_json::to_vec(&report) {
+                            Ok(payload) => {
+                                self.publish(&logger, &client, &self.settings.result_topic, payload);
+                            }
+                            Err(err) => warn!(logger, "failed to encode downlink result: {err}"),
+                        };
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => warn!(logger, "event bus closed"),
+                },
+                mqtt_event = eventloop.poll() => match mqtt_event {
+                    Ok(MqttEvent::Incoming(MqttPacket::ConnAck(_))) => {
+                        info!(logger, "connected");
+                        metrics::set(CONNECTED_METRIC, 1.0);
+                        if let Err(err) = client.try_subscribe(&self.settings.downlink_topic, QoS::AtLeastOnce) {
+                            warn!(logger, "failed to subscribe to downlinks: {err}");
+                        }
+                    }
+                    Ok(MqttEvent::Incoming(MqttPacket::Publish(publish))) => {
+                        if publish.topic == self.settings.downlink_topic {
+                            self.handle_downlink(&logger, &publish.payload).await
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(err) => {
+                        warn!(logger, "broker connection error: {err}";
+                            "retry_secs" => RECONNECT_WAIT.as_secs());
+                        metrics::set(CONNECTED_METRIC, 0.0);
+                        time::sleep(RECONNECT_WAIT).await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn publish_uplink(&self, logger: &Logger, client: &AsyncClient, packet: &Packet) {
+        let payload = match self.settings.format {
+            MqttFormat::Json => match serde_json::to_vec(&JsonUplink::from(packet)) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!(logger, "failed to encode uplink: {err}");
+                    return;
+                }
+            },
+            MqttFormat::Protobuf => packet.encode_to_vec(),
+        };
+        let result = if self.publish(logger, client, &self.settings.uplink_topic, payload) {
+            "published"
+        } else {
+            "dropped"
+        };
+        metrics::increment(&metrics::labeled(UPLINKS_METRIC, &[("result", result)]));
+    }
+
+    /// Queues a message for the broker, encrypted when a recipient is set,
+    /// returns whether it was queued
+    fn publish(
+        &self,
+        logger: &Logger,
+        client: &AsyncClient,
+        topic: &str,
+        payload: Vec<u8>,
+    ) -> bool {
+        let payload = match &self.encryption {
+            Some(encryption) => match encryption.encrypt(&payload) {
+                Ok(encrypted) => encrypted,
+                Err(err) => {
+                    warn!(logger, "failed to encrypt message for {topic}: {err}");
+                    return false;
+                }
+            },
+            None => payload,
+        };
+        match client.try_publish(topic, QoS::AtMostOnce, false, payload) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!(logger, "dropping message for {topic}: {err}");
+                false
+            }
+        }
+    }
+
+    async fn handle_downlink(&self, logger: &Logger, payload: &[u8]) {
+        let packet = match decode_downlink(self.settings.format, payload) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!(logger, "ignoring invalid downlink: {err}");
+                metrics::increment(&metrics::labeled(
+                    DOWNLINKS_METRIC,
+                    &[("result", "invalid")],
+                ));
+                return;
+            }
+        };
+        metrics::increment(&metrics::labeled(DOWNLINKS_METRIC, &[("result", "queued")]));
+        self.transmit.downlink(packet).await
+    }
+}
+
+fn decode_downlink(format: MqttFormat, payload: &[u8]) -> Result<Packet> {
+    match format {
+        MqttFormat::Json => serde_json::from_slice::<JsonDownlink>(payload)?.try_into(),
+        MqttFormat::Protobuf => Ok(helium_proto::Packet::decode(payload)
+            .map_err(|_| Error::custom("invalid downlink protobuf"))?
+            .into()),
+    }
+}
+
+/// Splits a broker address into host and port, the port defaults to 1883
+fn broker_addr(broker: &str) -> Result<(String, u16)> {
+    let broker = broker.strip_prefix("mqtt://").unwrap_or(broker);
+    match broker.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| Error::custom(format!("invalid mqtt broker port: {port}")))?;
+            Ok((host.to_string(), port))
+        }
+        None => Ok((broker.to_string(), DEFAULT_PORT)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn downlinks() {
+        let json = br#"{"payload":"YAQAAAAAAQAB","timestamp":3000000,"frequency":869.525,
+            "datarate":"SF12BW125","rx2":{"timestamp":4000000,"frequency":869.525,"datarate":"SF9BW125"}}"#;
+        let packet = decode_downlink(MqttFormat::Json, json).expect("json downlink");
+        assert_eq!(3_000_000, packet.timestamp);
+        assert_eq!("SF12BW125", packet.datarate);
+        assert_eq!(
+            Some(4_000_000),
+            packet.rx2_window.as_ref().map(|window| window.timestamp)
+        );
+
+        let protobuf = packet.encode_to_vec();
+        let decoded = decode_downlink(MqttFormat::Protobuf, &protobuf).expect("protobuf downlink");
+        assert_eq!(packet.payload, decoded.payload);
+        assert!(decode_downlink(MqttFormat::Json, b"{}").is_err());
+    }
+
+    #[test]
+    fn brokers() {
+        assert_eq!(
+            ("localhost".to_string(), 1883),
+            broker_addr("localhost").unwrap()
+        );
+        assert_eq!(
+            ("10.0.0.2".to_string(), 8883),
+            broker_addr("mqtt://10.0.0.2:8883").unwrap()
+        );
+        assert!(broker_addr("localhost:mqtt").is_err());
+    }
+
+    #[test]
+    fn encryption() {
+        use std::io::Read;
+
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::new(&identity.to_public().to_string()).expect("recipient");
+        let encrypted = encryption.encrypt(b"uplink").expect("encrypt");
+        assert!(!encrypted.windows(6).any(|window| window == b"uplink"));
+
+        let age::Decryptor::Recipients(decryptor) =
+            age::Decryptor::new(encrypted.as_slice()).expect("decryptor")
+        else {
+            panic!("not encrypted to a recipient");
+        };
+        let mut decrypted = vec![];
+        decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .expect("decrypt")
+            .read_to_end(&mut decrypted)
+            .expect("read");
+        assert_eq!(b"uplink".to_vec(), decrypted);
+        assert!(Encryption::new("age1invalid").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! RF sanity alarms.
+//!
+//! A disconnected antenna or a failed LNA does not make the packet forwarder
+//! fail, the gateway just hears (much) less. The monitor watches received
+//! uplinks on the event bus and raises an alarm when a previously active
+//! gateway has not received any uplinks for a while, or when the median RSSI
+//! of recent uplinks collapses below its long term median. Alarms are logged,
+//! exported as the `rf_alarm` metric, kept in the data directory for
+//! `info rf_health` and, with the `rf-webhook` feature, optionally POSTed to a
+//! webhook.
+
+#[cfg(feature = "rf-webhook")]
+use crate::service::{outbound, RPC_TIMEOUT};
+use crate::{
+    clock::unix_now,
+    event_bus::{Event, EventBus},
+    metrics, status_file, Error, Result, Settings,
+};
+use config::ConfigError;
+use http::Uri;
+#[cfg(feature = "rf-webhook")]
+use hyper::{Body, Client, Method, Request};
+#[cfg(feature = "rf-webhook")]
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "rf-webhook")]
+use serde_json::json;
+use slog::{error, info, o, warn, Logger};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::{sync::broadcast::error::RecvError, time};
+
+const RF_HEALTH_FILE: &str = "rf_health.json";
+
+/// Gauge which is 1 while the alarm given by the `alarm` label is raised
+pub const ALARM_METRIC: &str = "rf_alarm";
+/// Gauge of the median RSSI of recent uplinks in dBm
+pub const RSSI_MEDIAN_METRIC: &str = "rf_rssi_median_dbm";
+
+/// The interval alarms are evaluated and the status is stored at
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// The number of recent uplinks the RSSI median is taken over
+const RSSI_WINDOW: usize = 50;
+/// The weight of a new window median in the long term RSSI median
+const BASELINE_WEIGHT: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Alarm {
+    /// No uplinks were received for the configured time
+    Silence,
+    /// The median RSSI of recent uplinks dropped far below the long term median
+    RssiCollapse,
+}
+
+impl Alarm {
+    const ALL: [Self; 2] = [Self::Silence, Self::RssiCollapse];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Silence => "silence",
+            Self::RssiCollapse => "rssi_collapse",
+        }
+    }
+}
+
+/// The RF health as last evaluated by the running gateway
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RfHealth {
+    /// The currently raised alarms
+    pub alarms: Vec<Alarm>,
+    /// The number of uplinks received, across restarts
+    pub uplinks: u64,
+    /// Unix time (seconds) of the last received uplink
+    pub last_uplink: Option<u64>,
+    /// Median RSSI of recent uplinks in dBm
+    pub rssi_median: Option<f64>,
+    /// Long term median RSSI in dBm
+    pub rssi_baseline: Option<f64>,
+}
+
+impl RfHealth {
+    /// Load the last stored RF health. Returns None when the gateway has not
+    /// stored it yet.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, RF_HEALTH_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, RF_HEALTH_FILE, self)
+    }
+}
+
+/// Tracks uplinks and decides which alarms are raised
+#[derive(Debug)]
+struct Detector {
+    silence: Duration,
+    min_uplinks: u64,
+    rssi_drop: f64,
+    uplinks: u64,
+    last_uplink: Option<Instant>,
+    recent: VecDeque<f64>,
+    baseline: Option<f64>,
+}
+
+impl Detector {
+    fn observe(&mut self, rssi: f64, now: Instant) {
+        self.uplinks += 1;
+        self.last_uplink = Some(now);
+        if self.recent.len() >= RSSI_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rssi);
+        // Fold every full window into the long term median, unless it looks
+        // collapsed so the baseline does not follow a failure down
+        if self.recent.len() == RSSI_WINDOW && self.uplinks % RSSI_WINDOW as u64 == 0 {
+            let median = self.median().unwrap_or(rssi);
+            if !self.is_collapsed(median) {
+                self.baseline = Some(match self.baseline {
+                    Some(baseline) => baseline + (median - baseline) * BASELINE_WEIGHT,
+                    None => median,
+                });
+            }
+        }
+    }
+
+    fn median(&self) -> Option<f64> {
+        if self.recent.is_empty() {
+            return None;
+        }
+        let mut sorted = self.recent.iter().copied().collect::<Vec<_>>();
+        sorted.sort_by(f64::total_cmp);
+        Some(sorted[sorted.len() / 2])
+    }
+
+    fn is_collapsed(&self, median: f64) -> bool {
+        self.baseline
+            .is_some_and(|baseline| median < baseline - self.rssi_drop)
+    }
+
+    fn alarms(&self, now: Instant) -> Vec<Alarm> {
+        let silent = self.uplinks >= self.min_uplinks
+            && self
+                .last_uplink
+                .is_some_and(|last| now.saturating_duration_since(last) >= self.silence);
+        let collapsed = self.recent.len() == RSSI_WINDOW
+            && self
+                .median()
+                .is_some_and(|median| self.is_collapsed(median));
+        Alarm::ALL
+            .into_iter()
+            .filter(|alarm| match alarm {
+                Alarm::Silence => silent,
+                Alarm::RssiCollapse => collapsed,
+            })
+            .collect()
+    }
+}
+
+pub struct RfHealthMonitor {
+    detector: Detector,
+    webhook: Option<Uri>,
+    #[cfg(feature = "rf-webhook")]
+    client: Client<HttpsConnector<outbound::Connector>, Body>,
+    #[cfg(feature = "rf-webhook")]
+    gateway: String,
+    data_dir: PathBuf,
+    events: EventBus,
+}
+
+impl RfHealthMonitor {
+    pub fn new(settings: &Settings, events: EventBus) -> Result<Self> {
+        let webhook = settings
+            .rf_health
+            .webhook
+            .as_deref()
+            .map(str::parse::<Uri>)
+            .transpose()?;
+        if webhook.is_some() && cfg!(not(feature = "rf-webhook")) {
+            return Err(Error::from(ConfigError::Message(
+                "rf_health.webhook requires the rf-webhook feature".to_string(),
+            )));
+        }
+        Ok(Self {
+            detector: Detector {
+                silence: Duration::from_secs(settings.rf_health.silence_hours * 3600),
+                min_uplinks: settings.rf_health.min_uplinks,
+                rssi_drop: settings.rf_health.rssi_drop as f64,
+                uplinks: 0,
+                last_uplink: None,
+                recent: VecDeque::with_capacity(RSSI_WINDOW),
+                baseline: None,
+            },
+            webhook,
+            #[cfg(feature = "rf-webhook")]
+            client: Client::builder().build(outbound::https_connector()),
+            #[cfg(feature = "rf-webhook")]
+            gateway: settings.keypair.public_key().to_string(),
+            data_dir: settings.data_dir.clone(),
+            events,
+        })
+    }
+
+    /// Whether alarms are enabled with a non-zero silence time
+    pub fn is_enabled(&self) -> bool {
+        !self.detector.silence.is_zero()
+    }
+
+    pub async fn run(mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let logger = logger.new(o!("module" => "rf_health"));
+        info!(logger, "starting";
+            "silence_hours" => self.detector.silence.as_secs() / 3600,
+            "webhook" => self.webhook.as_ref().map(|uri| uri.to_string()));
+
+        // Restore what was known before a restart, so a gateway that restarts
+        // with a disconnected antenna still raises an alarm
+        let mut alarms = vec![];
+        if let Ok(Some(stored)) = RfHealth::load(&self.data_dir) {
+            self.detector.uplinks = stored.uplinks;
+            self.detector.baseline = stored.rssi_baseline;
+            self.detector.last_uplink = stored.last_uplink.map(|last_uplink| {
+                let ago = unix_now().saturating_sub(last_uplink);
+                Instant::now()
+                    .checked_sub(Duration::from_secs(ago))
+                    .unwrap_or_else(Instant::now)
+            });
+            alarms = stored.alarms;
+        }
+
+        let mut events = self.events.subscribe();
+        let mut timer = time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                event = events.recv() => match event {
+                    Ok(Event::UplinkReceived(packet)) => {
+                        self.detector.observe(packet.signal_strength as f64, Instant::now())
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => warn!(logger, "event bus closed"),
+                },
+                _ = timer.tick() => {
+                    let raised = self.detector.alarms(Instant::now());
+                    for alarm in Alarm::ALL {
+                        match (alarms.contains(&alarm), raised.contains(&alarm)) {
+                            (false, true) => self.notify(alarm, true, &logger).await,
+                            (true, false) => self.notify(alarm, false, &logger).await,
+                            _ => (),
+                        }
+                    }
+                    alarms = raised;
+                    self.store(&alarms, &logger);
+                }
+            }
+        }
+    }
+
+    fn status(&self, alarms: &[Alarm]) -> RfHealth {
+        let now = Instant::now();
+        RfHealth {
+            alarms: alarms.to_vec(),
+            uplinks: self.detector.uplinks,
+            last_uplink: self.detector.last_uplink.map(|last_uplink| {
+                unix_now().saturating_sub(now.saturating_duration_since(last_uplink).as_secs())
+            }),
+            rssi_median: self.detector.median(),
+            rssi_baseline: self.detector.baseline,
+        }
+    }
+
+    fn store(&self, alarms: &[Alarm], logger: &Logger) {
+        let status = self.status(alarms);
+        for alarm in Alarm::ALL {
+            let raised = if alarms.contains(&alarm) { 1.0 } else { 0.0 };
+            metrics::set(
+                &metrics::labeled(ALARM_METRIC, &[("alarm", alarm.as_str())]),
+                raised,
+            );
+        }
+        if let Some(median) = status.rssi_median {
+            metrics::set(RSSI_MEDIAN_METRIC, median);
+        }
+        if let Err(err) = status.save(&self.data_dir) {
+            warn!(logger, "failed to store rf health: {err:?}");
+        }
+    }
+
+    async fn notify(&self, alarm: Alarm, raised: bool, logger: &Logger) {
+        let status = self.status(&[]);
+        if raised {
+            error!(logger, "rf alarm raised, check the antenna connection";
+                "alarm" => alarm.as_str(),
+                "uplinks" => status.uplinks,
+                "last_uplink" => status.last_uplink,
+                "rssi_median" => status.rssi_median,
+                "rssi_baseline" => status.rssi_baseline);
+        } else {
+            info!(logger, "rf alarm cleared"; "alarm" => alarm.as_str());
+        }
+        #[cfg(feature = "rf-webhook")]
+        if let Some(webhook) = &self.webhook {
+            let body = json!({
+                "gateway": self.gateway,
+                "alarm": alarm,
+                "raised": raised,
+                "time": unix_now(),
+                "status": status,
+            });
+            if let Err(err) = self.post(webhook, body.to_string()).await {
+                warn!(logger, "failed to post rf alarm: {err:?}"; "alarm" => alarm.as_str());
+            }
+        }
+    }
+
+    #[cfg(feature = "rf-webhook")]
+    async fn post(&self, uri: &Uri, body: String) -> Result {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| Error::custom(format!("webhook request: {err}")))?;
+        let resp = time::timeout(RPC_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Error::timeout())??;
+        if !resp.status().is_success() {
+            return Err(Error::custom(format!("webhook {uri}: {}", resp.status())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alarms() {
+        let mut detector = Detector {
+            silence: Duration::from_secs(3600),
+            min_uplinks: 10,
+            rssi_drop: 20.0,
+            uplinks: 0,
+            last_uplink: None,
+            recent: VecDeque::new(),
+            baseline: None,
+        };
+        let start = Instant::now();
+        // Not active yet
+        detector.observe(-80.0, start);
+        assert!(detector
+            .alarms(start + Duration::from_secs(7200))
+            .is_empty());
+
+        for _ in 0..(2 * RSSI_WINDOW) {
+            detector.observe(-80.0, start);
+        }
+        assert_eq!(Some(-80.0), detector.baseline);
+        assert!(detector.alarms(start).is_empty());
+        assert_eq!(
+            vec![Alarm::Silence],
+            detector.alarms(start + Duration::from_secs(3600))
+        );
+
+        // A disconnected antenna only hears very close devices
+        for _ in 0..RSSI_WINDOW {
+            detector.observe(-115.0, start);
+        }
+        assert_eq!(vec![Alarm::RssiCollapse], detector.alarms(start));
+        // The baseline did not follow the collapse
+        assert_eq!(Some(-80.0), detector.baseline);
+
+        for _ in 0..RSSI_WINDOW {
+            detector.observe(-85.0, start);
+        }
+        assert!(detector.alarms(start).is_empty());
+    }
+}
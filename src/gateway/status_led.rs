@@ -0,0 +1,209 @@
+use crate::{
+    beaconer::BEACON_OK_METRIC,
+    metrics,
+    server::milestones::{Milestone, MilestonesHandle},
+    service::packet_router::CONNECTED_METRIC,
+    Error, Result, Settings,
+};
+use slog::{info, o, warn, Logger};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The resolution of the blink patterns
+const TICK: Duration = Duration::from_millis(250);
+/// The time the first witness report after boot is shown
+const WITNESSED_DURATION: Duration = Duration::from_secs(60);
+
+/// The state reflected by the status LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedState {
+    /// No router connection. Slow blink
+    Disconnected,
+    /// Connected to a router but the last beacon attempt failed. Fast blink
+    BeaconFailed,
+    /// Connected and healthy. Solid on
+    Connected,
+    /// Connected and the first witness report after boot was just accepted.
+    /// Three short blinks and a pause
+    Witnessed,
+}
+
+impl LedState {
+    /// Derive the current state from the metrics registry and the milestones
+    pub fn current(milestones: &MilestonesHandle) -> Self {
+        match Self::from_gauges(
+            metrics::get(CONNECTED_METRIC),
+            metrics::get(BEACON_OK_METRIC),
+        ) {
+            Self::Connected
+                if milestones.reached_within(Milestone::WitnessReported, WITNESSED_DURATION) =>
+            {
+                Self::Witnessed
+            }
+            state => state,
+        }
+    }
+
+    /// A beacon status that has not been recorded yet (no beacon attempted) is
+    /// not considered a failure.
+    pub fn from_gauges(connected: Option<f64>, beacon_ok: Option<f64>) -> Self {
+        match (connected, beacon_ok) {
+            (Some(c), _) if c < 1.0 => Self::Disconnected,
+            (None, _) => Self::Disconnected,
+            (_, Some(b)) if b < 1.0 => Self::BeaconFailed,
+            _ => Self::Connected,
+        }
+    }
+
+    /// Whether the LED is lit at the given tick count
+    pub fn is_lit(&self, tick: u64) -> bool {
+        match self {
+            // 1s on, 1s off
+            Self::Disconnected => (tick / 4) % 2 == 0,
+            // 250ms on, 250ms off
+            Self::BeaconFailed => tick % 2 == 0,
+            Self::Connected => true,
+            // 3 x 250ms on, 250ms off, then 500ms off
+            Self::Witnessed => tick % 8 < 6 && tick % 2 == 0,
+        }
+    }
+}
+
+/// Drives a status LED (through a sysfs LED or GPIO value file) to reflect the
+/// router connection and beacon state of the gateway.
+pub struct StatusLed {
+    path: Option<PathBuf>,
+    gpio: Option<u32>,
+    active_low: bool,
+    milestones: MilestonesHandle,
+}
+
+impl StatusLed {
+    pub fn new(settings: &Settings, milestones: MilestonesHandle) -> Self {
+        Self {
+            path: settings.led.path.clone(),
+            gpio: settings.led.gpio,
+            active_low: settings.led.active_low,
+            milestones,
+        }
+    }
+
+    /// Whether a led path or gpio is configured
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some() || self.gpio.is_some()
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let path = match (self.path.clone(), self.gpio) {
+            (Some(path), _) => path,
+            // The gateway runs on without the led when the gpio can not be
+            // set up
+            (None, Some(gpio)) => match export_gpio(gpio) {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!(logger, "status led disabled: {err:?}"; "module" => "status_led", "gpio" => gpio);
+                    return Ok(());
+                }
+            },
+            (None, None) => return Ok(()),
+        };
+        let logger = logger.new(o!("module" => "status_led", "path" => path.display().to_string()));
+        info!(logger, "starting");
+
+        let mut timer = tokio::time::interval(TICK);
+        let mut tick: u64 = 0;
+        let mut lit: Option<bool> = None;
+        let mut failed = false;
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    let _ = self.write(&path, false);
+                    return Ok(())
+                },
+                _ = timer.tick() => {
+                    let state = LedState::current(&self.milestones);
+                    let next = state.is_lit(tick);
+                    tick = tick.wrapping_add(1);
+                    if lit == Some(next) {
+                        continue;
+                    }
+                    match self.write(&path, next) {
+                        Ok(()) => {
+                            lit = Some(next);
+                            failed = false;
+                        }
+                        Err(err) => {
+                            // Only warn once per failure streak
+                            if !failed {
+                                warn!(logger, "failed to set led: {err:?}");
+                            }
+                            lit = None;
+                            failed = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, lit: bool) -> Result {
+        let value = if lit != self.active_low { "1" } else { "0" };
+        fs::write(path, value).map_err(Error::from)
+    }
+}
+
+/// Exports the given gpio through sysfs, configures it as an output and
+/// returns the path of its value file.
+fn export_gpio(gpio: u32) -> Result<PathBuf> {
+    let base = PathBuf::from(format!("/sys/class/gpio/gpio{gpio}"));
+    if !base.exists() {
+        fs::write("/sys/class/gpio/export", gpio.to_string())?;
+    }
+    fs::write(base.join("direction"), "out")?;
+    Ok(base.join("value"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn led_state() {
+        assert_eq!(LedState::Disconnected, LedState::from_gauges(None, None));
+        assert_eq!(
+            LedState::Disconnected,
+            LedState::from_gauges(Some(0.0), Some(1.0))
+        );
+        assert_eq!(
+            LedState::BeaconFailed,
+            LedState::from_gauges(Some(1.0), Some(0.0))
+        );
+        assert_eq!(LedState::Connected, LedState::from_gauges(Some(1.0), None));
+        assert_eq!(
+            LedState::Connected,
+            LedState::from_gauges(Some(1.0), Some(1.0))
+        );
+    }
+
+    #[test]
+    fn led_patterns() {
+        let pattern = |state: LedState| (0..8).map(|t| state.is_lit(t)).collect::<Vec<_>>();
+        assert_eq!(
+            vec![true, true, true, true, false, false, false, false],
+            pattern(LedState::Disconnected)
+        );
+        assert_eq!(
+            vec![true, false, true, false, true, false, true, false],
+            pattern(LedState::BeaconFailed)
+        );
+        assert!(pattern(LedState::Connected).into_iter().all(|lit| lit));
+        assert_eq!(
+            vec![true, false, true, false, true, false, false, false],
+            pattern(LedState::Witnessed)
+        );
+    }
+}
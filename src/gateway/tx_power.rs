@@ -0,0 +1,66 @@
+//! Maximum TX power per datarate.
+//!
+//! Some regions and hardware need datarate specific derating below the
+//! maximum power of the region params. The `tx_power` settings table maps
+//! datarates to a maximum conducted power which is applied on top of the
+//! region params to beacons and downlinks.
+
+use crate::{Error, Result};
+use beacon::LoraDataRate;
+use config::ConfigError;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxPowerTable(HashMap<LoraDataRate, u32>);
+
+impl TxPowerTable {
+    /// Constructs a table from the `tx_power` settings, which map datarate
+    /// names like "SF12BW125" to a maximum conducted power in dBm
+    pub fn new(settings: &BTreeMap<String, u32>) -> Result<Self> {
+        settings
+            .iter()
+            .map(|(datarate, power)| {
+                datarate
+                    .parse::<LoraDataRate>()
+                    .map(|datarate| (datarate, *power))
+                    .map_err(|_| {
+                        Error::from(ConfigError::Message(format!(
+                            "invalid tx_power datarate: \"{datarate}\""
+                        )))
+                    })
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// Returns the conducted power to transmit at with the given datarate
+    /// name, given the maximum power of the region params. Datarates that are
+    /// not in the table or can not be parsed use the region maximum.
+    pub fn limit(&self, datarate: &str, region_max: u32) -> u32 {
+        datarate
+            .parse::<LoraDataRate>()
+            .ok()
+            .and_then(|datarate| self.0.get(&datarate))
+            .map_or(region_max, |power| region_max.min(*power))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table() {
+        let table = TxPowerTable::new(&BTreeMap::from([
+            ("SF12BW125".to_string(), 14),
+            ("SF7BW125".to_string(), 30),
+        ]))
+        .expect("table");
+        assert_eq!(14, table.limit("SF12BW125", 27));
+        assert_eq!(27, table.limit("SF7BW125", 27));
+        assert_eq!(27, table.limit("SF9BW125", 27));
+        assert_eq!(27, table.limit("FSK", 27));
+
+        assert!(TxPowerTable::new(&BTreeMap::from([("SF13BW125".to_string(), 14)])).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Structural filtering of received uplinks.
+//!
+//! At high spreading factors concentrators regularly decode RF noise into
+//! frames that pass the CRC check. The LoRaWAN decoder already rejects
+//! impossible lengths and reserved message types; this filter additionally
+//! drops frames that decode but can not have been sent by an end device, like
+//! frames with an unknown major version, reserved header bits, downlink message
+//! types or reserved ports. When join EUIs are configured, join requests
+//! for other join EUIs are dropped as well.
+
+use crate::{metrics, settings::UplinkFilterSettings, Error, Packet, Result};
+use lorawan::{MType, PHYPayloadFrame};
+
+/// Counter of uplinks dropped by the filter labeled with the failed `check`
+pub const GARBAGE_METRIC: &str = "uplink_garbage_total";
+
+/// The largest LoRaWAN PHY payload for any region and datarate
+const MAX_PHY_LEN: usize = 255;
+/// Reserved (RFU) bits of the MHDR
+const MHDR_RFU_MASK: u8 = 0b0001_1100;
+/// Ports above the test port (224) are reserved
+const MAX_FPORT: u8 = 224;
+/// The only defined LoRaWAN major version (R1)
+const MAJOR_R1: u8 = 0;
+
+/// Why a frame was considered garbage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Length,
+    Major,
+    Rfu,
+    MType,
+    FPort,
+    JoinEui,
+}
+
+impl Check {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Length => "length",
+            Self::Major => "major",
+            Self::Rfu => "rfu",
+            Self::MType => "mtype",
+            Self::FPort => "fport",
+            Self::JoinEui => "join_eui",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UplinkFilter {
+    enabled: bool,
+    join_euis: Vec<u64>,
+}
+
+impl UplinkFilter {
+    pub fn new(settings: &UplinkFilterSettings) -> Result<Self> {
+        let join_euis = settings
+            .join_euis
+            .iter()
+            .map(|eui| {
+                u64::from_str_radix(eui.trim_start_matches("0x"), 16)
+                    .map_err(|_| Error::custom(format!("invalid join eui {eui}")))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            enabled: settings.enabled,
+            join_euis,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Checks the given uplink, returning the failed check if it should be
+    /// dropped. Always passes when the filter is disabled.
+    pub fn check(&self, packet: &Packet) -> std::result::Result<(), Check> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let result = self.check_payload(packet.payload());
+        if let Err(check) = result {
+            metrics::increment(&metrics::labeled(
+                GARBAGE_METRIC,
+                &[("check", check.as_str())],
+            ));
+        }
+        result
+    }
+
+    fn check_payload(&self, payload: &[u8]) -> std::result::Result<(), Check> {
+        let mhdr = match payload.first() {
+            Some(mhdr) if payload.len() <= MAX_PHY_LEN => *mhdr,
+            _ => return Err(Check::Length),
+        };
+        if mhdr & 0b11 != MAJOR_R1 {
+            return Err(Check::Major);
+        }
+        if mhdr & MHDR_RFU_MASK != 0 {
+            return Err(Check::Rfu);
+        }
+        let frame =
+            Packet::parse_frame(lorawan::Direction::Uplink, payload).map_err(|_| Check::Length)?;
+        match frame {
+            PHYPayloadFrame::JoinRequest(request)
+                if !self.join_euis.is_empty() && !self.join_euis.contains(&request.app_eui) =>
+            {
+                Err(Check::JoinEui)
+            }
+            PHYPayloadFrame::JoinRequest(_) => Ok(()),
+            PHYPayloadFrame::MACPayload(payload) => {
+                match MType::from(mhdr >> 5) {
+                    MType::UnconfirmedUp | MType::ConfirmedUp => (),
+                    _ => return Err(Check::MType),
+                }
+                if payload.fport.is_some_and(|fport| fport > MAX_FPORT) {
+                    return Err(Check::FPort);
+                }
+                Ok(())
+            }
+            PHYPayloadFrame::JoinAccept(_) => Err(Check::MType),
+            PHYPayloadFrame::Proprietary(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data_up(mhdr: u8, fctrl: u8, fport: u8) -> Vec<u8> {
+        let mut payload = vec![mhdr, 0x04, 0x03, 0x02, 0x01, fctrl, 0x01, 0x00];
+        payload.extend(std::iter::repeat(0xAA).take((fctrl & 0x0f).into()));
+        payload.extend([fport, 0x55, 0xde, 0xad, 0xbe, 0xef]);
+        payload
+    }
+
+    fn join_request(app_eui: u64) -> Vec<u8> {
+        let mut payload = vec![0x00];
+        payload.extend(app_eui.to_le_bytes());
+        payload.extend(0x0102030405060708u64.to_le_bytes());
+        payload.extend([0x01, 0x02, 0xde, 0xad, 0xbe, 0xef]);
+        payload
+    }
+
+    #[test]
+    fn checks() {
+        let filter = UplinkFilter::new(&UplinkFilterSettings {
+            enabled: true,
+            join_euis: vec!["70B3D57ED0000001".to_string()],
+        })
+        .expect("filter");
+        assert_eq!(Ok(()), filter.check_payload(&data_up(0x40, 0x00, 1)));
+        assert_eq!(Ok(()), filter.check_payload(&data_up(0x80, 0x02, 1)));
+        assert_eq!(
+            Err(Check::Major),
+            filter.check_payload(&data_up(0x41, 0, 1))
+        );
+        assert_eq!(Err(Check::Rfu), filter.check_payload(&data_up(0x44, 0, 1)));
+        assert_eq!(
+            Err(Check::MType),
+            filter.check_payload(&data_up(0x60, 0, 1))
+        );
+        assert_eq!(Ok(()), filter.check_payload(&data_up(0x40, 0, 224)));
+        assert_eq!(
+            Err(Check::FPort),
+            filter.check_payload(&data_up(0x40, 0, 230))
+        );
+        assert_eq!(Err(Check::Length), filter.check_payload(&[]));
+        assert_eq!(Err(Check::Length), filter.check_payload(&[0x40; 300]));
+
+        assert_eq!(
+            Ok(()),
+            filter.check_payload(&join_request(0x70B3D57ED0000001))
+        );
+        assert_eq!(
+            Err(Check::JoinEui),
+            filter.check_payload(&join_request(0x70B3D57ED0000002))
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Completeness checks of the radio metadata of received uplinks.
+//!
+//! LNS adaptive data rate works off the RSSI, SNR, frequency and datarate of
+//! the uplinks of a device. Forwarders that omit a field, like a
+//! concentratord frame without signal values where the protobuf defaults of 0
+//! show up, produce uplinks that look valid but silently skew ADR. Every
+//! uplink is checked and missing fields are counted per field in the
+//! `uplink_metadata_missing_total` metric.
+//!
+//! A missing value is forwarded as the sentinel 0, the protobuf default
+//! routers already see for absent fields, so values that are not a number
+//! never reach a router. With `uplink_metadata.drop_incomplete` set,
+//! incomplete uplinks are dropped instead.
+
+use crate::{metrics, settings::UplinkMetadataSettings, Packet};
+use semtech_udp::DataRate;
+use std::str::FromStr;
+
+/// Counter of uplinks with missing metadata labeled with the missing `field`
+pub const MISSING_METRIC: &str = "uplink_metadata_missing_total";
+
+/// A metadata field of an uplink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Rssi,
+    Snr,
+    Frequency,
+    Datarate,
+    Timestamp,
+}
+
+impl Field {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rssi => "rssi",
+            Self::Snr => "snr",
+            Self::Frequency => "frequency",
+            Self::Datarate => "datarate",
+            Self::Timestamp => "timestamp",
+        }
+    }
+}
+
+/// The missing metadata fields of an uplink. An RSSI of exactly 0 dBm is not
+/// a real reception, and an SNR of 0 only counts as missing together with it,
+/// as both are the defaults of absent fields.
+pub fn missing(packet: &helium_proto::Packet) -> Vec<Field> {
+    let rssi_missing = !packet.signal_strength.is_finite() || packet.signal_strength == 0.0;
+    let mut missing = vec![];
+    if rssi_missing {
+        missing.push(Field::Rssi);
+    }
+    if !packet.snr.is_finite() || (rssi_missing && packet.snr == 0.0) {
+        missing.push(Field::Snr);
+    }
+    if !packet.frequency.is_finite() || packet.frequency <= 0.0 {
+        missing.push(Field::Frequency);
+    }
+    if DataRate::from_str(&packet.datarate).is_err() {
+        missing.push(Field::Datarate);
+    }
+    if packet.timestamp == 0 {
+        missing.push(Field::Timestamp);
+    }
+    missing
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataCheck {
+    drop_incomplete: bool,
+}
+
+impl MetadataCheck {
+    pub fn new(settings: &UplinkMetadataSettings) -> Self {
+        Self {
+            drop_incomplete: settings.drop_incomplete,
+        }
+    }
+
+    /// Counts the missing metadata of an uplink and replaces values that are
+    /// not a number with the sentinel 0. Returns the missing fields if the
+    /// uplink should be dropped.
+    pub fn check(&self, packet: &mut Packet) -> std::result::Result<(), Vec<Field>> {
+        let missing = missing(packet);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        for field in &missing {
+            metrics::increment(&metrics::labeled(
+                MISSING_METRIC,
+                &[("field", field.as_str())],
+            ));
+        }
+        if self.drop_incomplete {
+            return Err(missing);
+        }
+        packet.normalize_metadata();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn packet(rssi: f32, snr: f32, frequency: f32, datarate: &str, timestamp: u64) -> Packet {
+        helium_proto::Packet {
+            signal_strength: rssi,
+            snr,
+            frequency,
+            datarate: datarate.to_string(),
+            timestamp,
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn completeness() {
+        assert!(missing(&packet(-80.0, 0.0, 868.1, "SF7BW125", 1)).is_empty());
+        assert_eq!(
+            vec![Field::Rssi, Field::Snr],
+            missing(&packet(0.0, 0.0, 868.1, "SF7BW125", 1))
+        );
+        assert_eq!(
+            vec![
+                Field::Snr,
+                Field::Frequency,
+                Field::Datarate,
+                Field::Timestamp
+            ],
+            missing(&packet(-80.0, f32::NAN, 0.0, "", 0))
+        );
+
+        let mut incomplete = packet(f32::NAN, 5.0, 868.1, "SF7BW125", 1);
+        assert!(MetadataCheck::default().check(&mut incomplete).is_ok());
+        assert_eq!(0.0, incomplete.signal_strength);
+        let check = MetadataCheck {
+            drop_incomplete: true,
+        };
+        assert_eq!(
+            Err(vec![Field::Rssi]),
+            check.check(&mut packet(0.0, 5.0, 868.1, "SF7BW125", 1))
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Uplink forwarding decisions.
+//!
+//! Every uplink received from the packet forwarder is either forwarded to a
+//! router or dropped for a reason. Dispositions are counted in the metrics
+//! registry and the most recent ones are kept with their packet hash so packet
+//! loss inside the gateway can be explained.
+
+use crate::{
+    clock::unix_now_millis,
+    logging, metrics,
+    server::milestones::{Milestone, MilestonesHandle},
+    Base64,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use slog::{debug, Level, Logger};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of uplink dispositions labeled with `disposition` and `reason`
+pub const DISPOSITION_METRIC: &str = "uplink_dispositions_total";
+
+/// The number of recent dispositions kept for the stats API
+const RECENT_DISPOSITIONS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Forwarded,
+    Dropped(DropReason),
+}
+
+/// Why an uplink was not forwarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The packet failed the CRC check
+    Crc,
+    /// The packet is not a valid LoRaWAN frame
+    Decode,
+    /// The packet decoded but failed the structural uplink filter
+    Garbage,
+    /// The monthly data cap was exceeded
+    DataCap,
+    /// No router matched the packet's routing information
+    Filter,
+    /// The router queue was full and the oldest packet was discarded
+    QueueFull,
+    /// The packet was queued for too long
+    Age,
+    /// The packet could not be sent to the router
+    NoConduit,
+    /// The packet could not be encoded or signed for the router
+    Encode,
+    /// Only routers that exceeded their error budget matched the packet
+    Blacklisted,
+    /// The packet forwarder that received the packet is not allowed
+    UnknownForwarder,
+    /// The packet misses radio metadata and incomplete packets are dropped
+    Metadata,
+    /// The packet's NetID or DevAddr is not forwarded by the forward filter
+    Denied,
+    /// The join request's JoinEUI or DevEUI is not forwarded by the join
+    /// filter
+    JoinDenied,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Crc => "crc",
+            Self::Decode => "decode",
+            Self::Garbage => "garbage",
+            Self::DataCap => "data_cap",
+            Self::Filter => "filter",
+            Self::QueueFull => "queue_full",
+            Self::Age => "age",
+            Self::NoConduit => "no_conduit",
+            Self::Encode => "encode",
+            Self::Blacklisted => "blacklisted",
+            Self::UnknownForwarder => "unknown_forwarder",
+            Self::Metadata => "metadata",
+            Self::Denied => "denied",
+            Self::JoinDenied => "join_denied",
+        }
+    }
+}
+
+impl Disposition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Forwarded => "forwarded",
+            Self::Dropped(_) => "dropped",
+        }
+    }
+
+    pub fn reason(&self) -> Option<DropReason> {
+        match self {
+            Self::Forwarded => None,
+            Self::Dropped(reason) => Some(*reason),
+        }
+    }
+}
+
+impl fmt::Display for Disposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason() {
+            Some(reason) => write!(f, "{}:{}", self.as_str(), reason.as_str()),
+            None => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// A recorded uplink disposition
+#[derive(Debug, Clone, Serialize)]
+pub struct UplinkRecord {
+    /// Unix time (milliseconds) the disposition was recorded
+    pub time: u64,
+    /// Base64 sha256 hash of the packet payload, when known
+    pub packet_hash: Option<String>,
+    pub disposition: &'static str,
+    pub reason: Option<&'static str>,
+}
+
+/// Disposition counts since startup and the most recent dispositions
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UplinkStats {
+    pub counts: BTreeMap<String, u64>,
+    pub recent: VecDeque<UplinkRecord>,
+}
+
+/// Records uplink dispositions. Clones share the same stats.
+#[derive(Debug, Clone, Default)]
+pub struct UplinkStatsHandle {
+    stats: Arc<Mutex<UplinkStats>>,
+    milestones: MilestonesHandle,
+}
+
+impl UplinkStatsHandle {
+    /// Stats that reach the first forwarded uplink milestone
+    pub fn new(milestones: MilestonesHandle) -> Self {
+        Self {
+            stats: Default::default(),
+            milestones,
+        }
+    }
+
+    /// Records the disposition of an uplink with the given payload. The
+    /// payload is not known for packets that are only counted, like discarded
+    /// queued packets.
+    pub fn record(&self, logger: &Logger, disposition: Disposition, payload: Option<&[u8]>) {
+        let packet_hash = payload.map(|payload| Sha256::digest(payload).to_vec().to_b64());
+        let reason = disposition.reason().map(|reason| reason.as_str());
+        if let Some(suppressed) = logging::sampling::sample(Level::Debug, "uplink_disposition") {
+            debug!(logger, "uplink {}", disposition.as_str();
+                "reason" => reason,
+                "packet_hash" => &packet_hash,
+                "suppressed" => suppressed);
+        }
+        metrics::increment(&metrics::labeled(
+            DISPOSITION_METRIC,
+            &[
+                ("disposition", disposition.as_str()),
+                ("reason", reason.unwrap_or("")),
+            ],
+        ));
+        if disposition == Disposition::Forwarded {
+            self.milestones.reach(logger, Milestone::UplinkForwarded);
+        }
+
+        let time = unix_now_millis();
+        let mut stats = self.stats.lock().expect("uplink stats lock");
+        *stats.counts.entry(disposition.to_string()).or_default() += 1;
+        if stats.recent.len() >= RECENT_DISPOSITIONS {
+            stats.recent.pop_front();
+        }
+        stats.recent.push_back(UplinkRecord {
+            time,
+            packet_hash,
+            disposition: disposition.as_str(),
+            reason,
+        });
+    }
+
+    /// Records the same disposition for a number of packets with unknown
+    /// payloads
+    pub fn record_count(&self, logger: &Logger, disposition: Disposition, count: usize) {
+        for _ in 0..count {
+            self.record(logger, disposition, None);
+        }
+    }
+
+    /// Returns the disposition counts and the most recent dispositions
+    pub fn snapshot(&self) -> UplinkStats {
+        self.stats.lock().expect("uplink stats lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dispositions() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let stats = UplinkStatsHandle::default();
+        stats.record(&logger, Disposition::Forwarded, Some(b"uplink"));
+        stats.record_count(&logger, Disposition::Dropped(DropReason::Age), 2);
+
+        let stats = stats.snapshot();
+        assert_eq!(1, stats.counts["forwarded"]);
+        assert_eq!(2, stats.counts["dropped:age"]);
+        assert!(stats
+            .recent
+            .iter()
+            .any(|record| { record.disposition == "dropped" && record.reason == Some("age") }));
+        assert!(stats
+            .recent
+            .iter()
+            .any(|record| record.disposition == "forwarded" && record.packet_hash.is_some()));
+    }
+}
@@ -0,0 +1,180 @@
+//! Packet forwarder liveness watchdog.
+//!
+//! A Semtech UDP packet forwarder sends PULL_DATA keepalives and PUSH_DATA
+//! with uplinks and a stat message every stat interval. The runtime only
+//! reports PULL_DATA when a forwarder connects or changes address, so every
+//! connect, uplink and stat counts as a sign of life. When none was seen for
+//! `forwarder.watchdog_timeout` seconds the gateway is in the "forwarder
+//! lost" state: a warning is logged, the `forwarder_lost` metric is set, the
+//! state is available from the local API and the optional
+//! `forwarder.recovery_command` is run, once per loss, to restart the
+//! forwarder or the concentrator.
+
+use crate::{clock::unix_now, metrics, settings::ForwarderSettings};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{process::Command, time};
+
+/// Gauge which is 1 while no packet forwarder is heard from
+pub const LOST_METRIC: &str = "forwarder_lost";
+
+/// The interval the watchdog is checked at
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The time the recovery command may take
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The watchdog state for the local API
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WatchdogStatus {
+    pub enabled: bool,
+    /// Whether no packet forwarder was heard from within the timeout
+    pub lost: bool,
+    /// Unix time (seconds) a packet forwarder was last heard from
+    pub last_seen: Option<u64>,
+    /// Unix time (seconds) the forwarder was declared lost
+    pub lost_since: Option<u64>,
+    /// The number of times the forwarder was lost since startup
+    pub losses: u64,
+}
+
+/// The published watchdog state. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct WatchdogHandle(Arc<Mutex<WatchdogStatus>>);
+
+impl WatchdogHandle {
+    /// Returns the current watchdog state
+    pub fn snapshot(&self) -> WatchdogStatus {
+        self.0.lock().expect("watchdog lock").clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    /// The time without a sign of life after which the forwarder is lost,
+    /// disabled when None
+    timeout: Option<Duration>,
+    recovery_command: Option<String>,
+    /// The last sign of life, or the start of the gateway
+    last_seen: Instant,
+    status: WatchdogStatus,
+    published: WatchdogHandle,
+}
+
+impl Watchdog {
+    /// A watchdog started now, publishing its state to the given handle. It
+    /// is disabled with a zero timeout.
+    pub fn new(settings: &ForwarderSettings, enabled: bool, published: WatchdogHandle) -> Self {
+        let timeout = (enabled && settings.watchdog_timeout > 0)
+            .then(|| Duration::from_secs(settings.watchdog_timeout));
+        let watchdog = Self {
+            timeout,
+            recovery_command: settings.recovery_command.clone(),
+            last_seen: Instant::now(),
+            status: WatchdogStatus {
+                enabled: timeout.is_some(),
+                ..Default::default()
+            },
+            published,
+        };
+        watchdog.publish();
+        watchdog
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.timeout.is_some()
+    }
+
+    /// Records a sign of life of a packet forwarder
+    pub fn seen(&mut self, logger: &Logger, now: Instant) {
+        self.last_seen = now;
+        self.status.last_seen = Some(unix_now());
+        if self.status.lost {
+            info!(logger, "packet forwarder is back");
+            self.status.lost = false;
+            self.status.lost_since = None;
+            self.publish();
+        }
+    }
+
+    /// Checks for a lost forwarder at `now`. Returns true when the forwarder
+    /// was just lost.
+    pub fn check(&mut self, logger: &Logger, now: Instant) -> bool {
+        let Some(timeout) = self.timeout else {
+            return false;
+        };
+        let silence = now.saturating_duration_since(self.last_seen);
+        if self.status.lost || silence < timeout {
+            return false;
+        }
+        warn!(logger, "packet forwarder lost, nothing heard from it";
+            "seconds" => silence.as_secs());
+        self.status.lost = true;
+        self.status.lost_since = Some(unix_now());
+        self.status.losses += 1;
+        self.publish();
+        true
+    }
+
+    /// Runs the recovery command, if any, without waiting for it
+    pub fn recover(&self, logger: &Logger) {
+        let Some(command) = self.recovery_command.clone() else {
+            return;
+        };
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            info!(logger, "running forwarder recovery command"; "command" => &command);
+            match time::timeout(COMMAND_TIMEOUT, Command::new(&command).output()).await {
+                Ok(Ok(output)) if output.status.success() => {
+                    info!(logger, "forwarder recovery command done")
+                }
+                Ok(Ok(output)) => warn!(logger, "forwarder recovery command failed";
+                    "status" => output.status.to_string(),
+                    "stderr" => String::from_utf8_lossy(&output.stderr).trim().to_string()),
+                Ok(Err(err)) => warn!(logger, "failed to run forwarder recovery command: {err:?}"),
+                Err(_) => warn!(logger, "forwarder recovery command timeout"),
+            }
+        });
+    }
+
+    fn publish(&self) {
+        metrics::set(LOST_METRIC, if self.status.lost { 1.0 } else { 0.0 });
+        *self.published.0.lock().expect("watchdog lock") = self.status.clone();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lost() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let settings = ForwarderSettings {
+            watchdog_timeout: 60,
+            ..Default::default()
+        };
+        let published = WatchdogHandle::default();
+        let mut watchdog = Watchdog::new(&settings, true, published.clone());
+        let start = watchdog.last_seen;
+        // A forwarder that never connects is lost as well
+        assert!(!watchdog.check(&logger, start + Duration::from_secs(59)));
+        assert!(watchdog.check(&logger, start + Duration::from_secs(60)));
+        assert!(published.snapshot().lost);
+        // Lost once until it is back
+        assert!(!watchdog.check(&logger, start + Duration::from_secs(120)));
+        assert!(watchdog.status.lost);
+
+        watchdog.seen(&logger, start + Duration::from_secs(121));
+        assert!(!watchdog.status.lost);
+        assert!(!watchdog.check(&logger, start + Duration::from_secs(180)));
+        assert!(watchdog.check(&logger, start + Duration::from_secs(181)));
+        assert_eq!(2, watchdog.status.losses);
+
+        let mut disabled = Watchdog::new(&settings, false, WatchdogHandle::default());
+        assert!(!disabled.check(&logger, start + Duration::from_secs(3600)));
+    }
+}
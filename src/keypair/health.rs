@@ -0,0 +1,190 @@
+//! Periodic keypair health checks.
+//!
+//! A secure element can fail at runtime (bus errors, a brown out or a worn
+//! out device) and would otherwise only be noticed when the next beacon or
+//! witness report fails to sign. The checker signs a random message with the
+//! configured keypair and verifies the signature at a fixed interval, logging
+//! an alarm on failure, and keeps the outcome in the data directory for
+//! `info key_health` and in the `keypair_healthy` metric.
+
+use crate::{clock::unix_now, metrics, status_file, Error, Keypair, Result, Settings};
+use helium_crypto::{Sign, Verify};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, warn, Logger};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+const KEY_HEALTH_FILE: &str = "key_health.json";
+
+/// Gauge which is 1 while the last keypair check succeeded and 0 otherwise
+pub const HEALTHY_METRIC: &str = "keypair_healthy";
+/// Counter of failed keypair checks
+pub const FAILURES_METRIC: &str = "keypair_check_failures_total";
+/// Gauge of the time the last successful sign and verify took in seconds
+pub const SIGN_SECONDS_METRIC: &str = "keypair_sign_seconds";
+
+/// The outcome of the keypair checks since the last server start
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KeyHealth {
+    /// Whether the last check succeeded
+    pub ok: bool,
+    /// Unix time (seconds) of the last check
+    pub last_check: u64,
+    /// Unix time (seconds) of the last successful check
+    pub last_ok: Option<u64>,
+    /// The number of checks that failed since the last successful one
+    pub consecutive_failures: u32,
+    /// The error (debug formatted, to include the secure element error) of
+    /// the last failed check
+    pub last_error: Option<String>,
+    /// Milliseconds the last successful sign and verify took
+    pub sign_ms: Option<u64>,
+}
+
+impl KeyHealth {
+    /// Load the last stored key health. Returns None when no checks were ever
+    /// stored.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, KEY_HEALTH_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, KEY_HEALTH_FILE, self)
+    }
+
+    /// Records the outcome of a check made at the given unix time
+    fn record(&mut self, now: u64, result: &Result<Duration>) {
+        self.last_check = now;
+        match result {
+            Ok(elapsed) => {
+                self.ok = true;
+                self.last_ok = Some(now);
+                self.consecutive_failures = 0;
+                self.last_error = None;
+                self.sign_ms = Some(elapsed.as_millis() as u64);
+            }
+            Err(err) => {
+                self.ok = false;
+                self.consecutive_failures += 1;
+                self.last_error = Some(format!("{err:?}"));
+            }
+        }
+    }
+}
+
+pub struct KeyHealthChecker {
+    keypair: Arc<Keypair>,
+    interval: Duration,
+    data_dir: PathBuf,
+}
+
+impl KeyHealthChecker {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            keypair: settings.keypair.clone(),
+            interval: Duration::from_secs(settings.key_health.interval),
+            data_dir: settings.data_dir.clone(),
+        }
+    }
+
+    /// Whether checks are enabled with a non-zero interval
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let logger = logger.new(o!("module" => "key_health"));
+        info!(logger, "starting"; "interval" => self.interval.as_secs());
+
+        let mut health = KeyHealth::default();
+        let mut timer = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = timer.tick() => {
+                    let result = check(self.keypair.clone()).await;
+                    let failures = health.consecutive_failures;
+                    health.record(unix_now(), &result);
+                    match &result {
+                        Ok(_) if failures > 0 => info!(logger, "keypair recovered";
+                            "failures" => failures),
+                        Ok(_) => (),
+                        Err(err) => error!(logger, "keypair check failed: {err:?}";
+                            "code" => err.code(),
+                            "failures" => health.consecutive_failures),
+                    }
+                    set_metrics(&result);
+                    if let Err(err) = health.save(&self.data_dir) {
+                        warn!(logger, "failed to store key health: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Signs a random message and verifies the signature with the public key,
+/// returning how long it took. Signing may block on the secure element so it
+/// is done off the runtime.
+async fn check(keypair: Arc<Keypair>) -> Result<Duration> {
+    tokio::task::spawn_blocking(move || {
+        let mut msg = [0u8; 32];
+        OsRng.fill_bytes(&mut msg);
+        let started = Instant::now();
+        let signature = keypair.sign(&msg)?;
+        keypair.public_key().verify(&msg, &signature)?;
+        Ok(started.elapsed())
+    })
+    .await
+    .map_err(|_| Error::custom("keypair check task failed"))?
+}
+
+fn set_metrics(result: &Result<Duration>) {
+    match result {
+        Ok(elapsed) => {
+            metrics::set(HEALTHY_METRIC, 1.0);
+            metrics::set(SIGN_SECONDS_METRIC, elapsed.as_secs_f64());
+        }
+        Err(_) => {
+            metrics::set(HEALTHY_METRIC, 0.0);
+            metrics::increment(FAILURES_METRIC);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let mut health = KeyHealth::default();
+        health.record(10, &Err(Error::custom("i2c timeout")));
+        health.record(20, &Err(Error::custom("i2c timeout")));
+        assert!(!health.ok);
+        assert_eq!(2, health.consecutive_failures);
+        assert_eq!(None, health.last_ok);
+        assert!(health
+            .last_error
+            .as_ref()
+            .is_some_and(|error| error.contains("i2c timeout")));
+
+        health.record(30, &Ok(Duration::from_millis(42)));
+        assert!(health.ok);
+        assert_eq!(0, health.consecutive_failures);
+        assert_eq!(Some(30), health.last_ok);
+        assert_eq!(Some(42), health.sign_ms);
+        assert_eq!(None, health.last_error);
+    }
+}
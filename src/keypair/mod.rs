@@ -0,0 +1,556 @@
+use crate::*;
+#[cfg(feature = "ecc608")]
+use helium_crypto::ecc608;
+#[cfg(feature = "tpm")]
+use helium_crypto::tpm;
+use helium_crypto::{KeyTag, KeyType, Network};
+use http::Uri;
+use rand::rngs::OsRng;
+use serde::{de, Deserializer, Serialize};
+use slog::{info, warn, Logger};
+#[cfg(feature = "ecc608")]
+use std::path::Path;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{self, PathBuf},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+use std::{thread, time::Duration};
+
+pub mod health;
+
+/// A keypair and the signing implementation selected for it
+#[derive(Debug)]
+pub struct Keypair(helium_crypto::Keypair, OnceLock<Signer>);
+pub type PublicKey = helium_crypto::PublicKey;
+
+/// Gauge of the time a signature took in the startup benchmark in
+/// microseconds, labeled with the signing `backend`
+pub const SIGN_BENCH_METRIC: &str = "keypair_sign_bench_micros";
+/// The number of signatures made with every backend in the benchmark
+const SIGN_BENCH_ROUNDS: u32 = 64;
+
+/// A signing implementation. Secure element keys and ecdsa file keys only
+/// have the helium-crypto one, ed25519 file keys can also be signed with
+/// ed25519-dalek which uses the AVX2 backend on CPUs that support it and
+/// precomputed tables everywhere else.
+#[derive(Debug)]
+enum Signer {
+    HeliumCrypto,
+    Dalek(Box<ed25519_dalek::SigningKey>),
+}
+
+impl Signer {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::HeliumCrypto => "helium-crypto",
+            Self::Dalek(_) => "ed25519-dalek",
+        }
+    }
+
+    fn sign(&self, keypair: &helium_crypto::Keypair, msg: &[u8]) -> helium_crypto::Result<Vec<u8>> {
+        match self {
+            Self::HeliumCrypto => helium_crypto::Sign::sign(keypair, msg),
+            Self::Dalek(key) => Ok(ed25519_dalek::Signer::sign(key.as_ref(), msg)
+                .to_bytes()
+                .to_vec()),
+        }
+    }
+
+    /// The signers available for a keypair, the helium-crypto one first
+    fn candidates(keypair: &helium_crypto::Keypair) -> Vec<Self> {
+        let mut candidates = vec![Self::HeliumCrypto];
+        if keypair.public_key().key_type() == KeyType::Ed25519 {
+            // The secret of an ed25519 key is the seed followed by the public
+            // key
+            let seed = keypair
+                .secret_to_vec()
+                .get(..ed25519_dalek::SECRET_KEY_LENGTH)
+                .and_then(|seed| ed25519_dalek::SecretKey::try_from(seed).ok());
+            if let Some(seed) = seed {
+                candidates.push(Self::Dalek(Box::new(
+                    ed25519_dalek::SigningKey::from_bytes(&seed),
+                )));
+            }
+        }
+        candidates
+    }
+
+    /// Returns the time one signature takes. Fails when the signer does not
+    /// make the same signature as helium-crypto, which signs deterministically
+    /// for all key types.
+    fn bench(&self, keypair: &helium_crypto::Keypair) -> Result<std::time::Duration> {
+        let msg = [0x5a; 256];
+        if self.sign(keypair, &msg)? != helium_crypto::Sign::sign(keypair, &msg)? {
+            return Err(Error::custom(format!(
+                "{} signature does not match",
+                self.name()
+            )));
+        }
+        let start = Instant::now();
+        for _ in 0..SIGN_BENCH_ROUNDS {
+            self.sign(keypair, &msg)?;
+        }
+        Ok(start.elapsed() / SIGN_BENCH_ROUNDS)
+    }
+}
+
+pub fn load_from_file(path: &str) -> error::Result<Keypair> {
+    let data = fs::read(path)?;
+    Ok(helium_crypto::Keypair::try_from(&data[..])?.into())
+}
+
+pub fn save_to_file(keypair: &Keypair, path: &str) -> io::Result<()> {
+    if let Some(parent) = path::PathBuf::from(path).parent() {
+        fs::create_dir_all(parent)?;
+    };
+    fs::write(path, keypair.0.to_vec())?;
+    Ok(())
+}
+
+const KEY_PROBE_FILE: &str = "key_probe.json";
+/// The default number of attempts to load a secure element key
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+const DEFAULT_PROBE_ATTEMPTS: u32 = 5;
+/// The delay before the second attempt, doubled for every following attempt
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+const PROBE_WARMUP: Duration = Duration::from_millis(500);
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+const MAX_PROBE_DELAY: Duration = Duration::from_secs(4);
+
+/// The result of probing a secure element key at startup
+#[derive(Debug, Clone, Default, Serialize, serde::Deserialize)]
+pub struct KeyProbe {
+    pub uri: String,
+    /// The number of attempts made to load the key
+    pub attempts: u32,
+    pub ok: bool,
+    /// The error of the last failed attempt
+    pub error: Option<String>,
+    /// The i2c buses found when probing an ecc secure element
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buses: Vec<String>,
+}
+
+static KEY_PROBES: Mutex<Vec<KeyProbe>> = Mutex::new(Vec::new());
+/// The data dir probe results are saved in. Only set for the server, other
+/// commands load a secure element key with a single attempt.
+static PROBE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Enables retries when loading a secure element key and saves the results
+/// of every probe in the given data dir
+pub fn enable_probing(data_dir: &path::Path) {
+    *PROBE_DIR.lock().expect("key probe lock") = Some(data_dir.to_path_buf());
+}
+
+impl KeyProbe {
+    /// Returns the probes of all secure element keys loaded by this process
+    pub fn current() -> Vec<Self> {
+        KEY_PROBES.lock().expect("key probe lock").clone()
+    }
+
+    /// Load the probes stored by the last server start
+    pub fn load(data_dir: &path::Path) -> Result<Vec<Self>> {
+        Ok(status_file::load(data_dir, KEY_PROBE_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save(probes: &[Self], data_dir: &path::Path) -> Result {
+        status_file::save(data_dir, KEY_PROBE_FILE, probes)
+    }
+}
+
+/// Attempts to load a secure element key up to the `probe_attempts` uri
+/// argument times when probing is enabled, backing off between attempts.
+/// Secure elements are often not ready right after a cold boot, and failing
+/// immediately results in service restart storms. The bus scan runs in
+/// parallel with the attempts. The probe is saved before returning, so a key
+/// that failed to load is still reported by `info probe`.
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+fn probe<F>(url: &Uri, args: &KeypairArgs, scan: fn() -> Vec<String>, load: F) -> Result<Keypair>
+where
+    F: FnMut() -> Result<Keypair>,
+{
+    let probe_dir = PROBE_DIR.lock().expect("key probe lock").clone();
+    let attempts = match probe_dir {
+        Some(_) => args
+            .get::<u32>("probe_attempts", DEFAULT_PROBE_ATTEMPTS)?
+            .max(1),
+        None => 1,
+    };
+    let (probe, result) = thread::scope(|scope| {
+        let buses = scope.spawn(scan);
+        let (mut probe, result) = retry(attempts, PROBE_WARMUP, load);
+        probe.uri = url.to_string();
+        probe.buses = buses.join().unwrap_or_default();
+        (probe, result)
+    });
+    let mut probes = KEY_PROBES.lock().expect("key probe lock");
+    probes.push(probe);
+    if let Some(data_dir) = probe_dir {
+        // A failure to save must not keep the gateway from starting, the
+        // probes are logged by the server as well
+        let _ = KeyProbe::save(&probes, &data_dir);
+    }
+    result
+}
+
+/// Calls `load` up to `attempts` times, waiting `warmup` before the second
+/// attempt and doubling the wait for every following attempt
+#[cfg(any(feature = "ecc608", feature = "tpm"))]
+fn retry<F>(attempts: u32, warmup: Duration, mut load: F) -> (KeyProbe, Result<Keypair>)
+where
+    F: FnMut() -> Result<Keypair>,
+{
+    let mut probe = KeyProbe::default();
+    let mut delay = warmup;
+    let result = loop {
+        probe.attempts += 1;
+        match load() {
+            Err(err) if probe.attempts < attempts => {
+                probe.error = Some(err.to_string());
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_PROBE_DELAY);
+            }
+            result => break result,
+        }
+    };
+    probe.ok = result.is_ok();
+    if let Err(err) = &result {
+        probe.error = Some(err.to_string());
+    }
+    (probe, result)
+}
+
+/// Lists the i2c buses present in /dev
+#[cfg(feature = "ecc608")]
+fn i2c_buses() -> Vec<String> {
+    let mut buses: Vec<String> = fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with("i2c-"))
+                .collect()
+        })
+        .unwrap_or_default();
+    buses.sort();
+    buses
+}
+
+macro_rules! uri_error {
+    ($format:expr) => {
+        error::DecodeError::keypair_uri(format!($format))
+    };
+    ($format:expr, $( $arg:expr ),+ ) => {
+        error::DecodeError::keypair_uri(format!($format, $( $arg ),+))
+    };
+}
+
+impl From<helium_crypto::Keypair> for Keypair {
+    fn from(v: helium_crypto::Keypair) -> Self {
+        Self(v, OnceLock::new())
+    }
+}
+
+impl Keypair {
+    /// Benchmarks the signing implementations available for the keypair and
+    /// signs with the fastest from now on. Only the first selection applies.
+    pub fn select_signer(&self, logger: &Logger) {
+        let mut fastest: Option<(Signer, std::time::Duration)> = None;
+        for signer in Signer::candidates(&self.0) {
+            match signer.bench(&self.0) {
+                Ok(elapsed) => {
+                    info!(logger, "signing benchmark";
+                        "backend" => signer.name(),
+                        "micros" => elapsed.as_micros() as u64);
+                    crate::metrics::set(
+                        &crate::metrics::labeled(SIGN_BENCH_METRIC, &[("backend", signer.name())]),
+                        elapsed.as_micros() as f64,
+                    );
+                    if fastest.as_ref().map_or(true, |(_, best)| elapsed < *best) {
+                        fastest = Some((signer, elapsed));
+                    }
+                }
+                Err(err) => warn!(logger, "signing backend not usable: {err:?}";
+                    "backend" => signer.name()),
+            }
+        }
+        if let Some((signer, _)) = fastest {
+            info!(logger, "selected signing backend"; "backend" => signer.name());
+            let _ = self.1.set(signer);
+        }
+    }
+
+    /// The name of the signing implementation in use
+    pub fn signer(&self) -> &'static str {
+        self.1
+            .get()
+            .map_or(Signer::HeliumCrypto.name(), Signer::name)
+    }
+}
+
+impl helium_crypto::Sign for Keypair {
+    fn sign(&self, msg: &[u8]) -> helium_crypto::Result<Vec<u8>> {
+        match self.1.get() {
+            Some(signer) => signer.sign(&self.0, msg),
+            None => helium_crypto::Sign::sign(&self.0, msg),
+        }
+    }
+}
+
+impl FromStr for Keypair {
+    type Err = Error;
+    fn from_str(str: &str) -> Result<Self> {
+        let url: Uri = str
+            .parse()
+            .map_err(|err| uri_error!("invalid keypair url \"{str}\": {err:?}"))?;
+        match url.scheme_str() {
+            Some("file") | None => match load_from_file(url.path()) {
+                Ok(k) => Ok(k),
+                Err(Error::IO(io_error)) if io_error.kind() == std::io::ErrorKind::NotFound => {
+                    let args = KeypairArgs::from_uri(&url)?;
+                    let network = args.get::<Network>("network", Network::MainNet)?;
+                    let new_key: Keypair = helium_crypto::Keypair::generate(
+                        KeyTag {
+                            network,
+                            key_type: KeyType::Ed25519,
+                        },
+                        &mut OsRng,
+                    )
+                    .into();
+                    save_to_file(&new_key, url.path()).map_err(|err| {
+                        uri_error!("unable to save key file \"{}\": {err:?}", url.path())
+                    })?;
+                    Ok(new_key)
+                }
+                Err(err) => Err(uri_error!(
+                    "unable to load key file \"{}\": {err:?}",
+                    url.path()
+                )),
+            },
+            #[cfg(feature = "ecc608")]
+            Some("ecc") => {
+                let args = KeypairArgs::from_uri(&url).map_err(error::DecodeError::keypair_uri)?;
+
+                let bus_address = url.port_u16().unwrap_or(96);
+                let slot = args.get::<u8>("slot", 0)?;
+                let network = args.get("network", Network::MainNet)?;
+                let path = url
+                    .host()
+                    .map(|dev| Path::new("/dev").join(dev))
+                    .ok_or_else(|| uri_error!("missing ecc device path"))?;
+                // The ecc library only needs to be initialized once
+                let mut initialized = false;
+                probe(&url, &args, i2c_buses, || {
+                    if !path.exists() {
+                        return Err(uri_error!(
+                            "ecc device \"{}\" not found",
+                            path.to_string_lossy()
+                        ));
+                    }
+                    if !initialized {
+                        ecc608::init(&path.to_string_lossy(), bus_address).map_err(|err| {
+                            uri_error!(
+                                "could not initialize ecc \"{}:{bus_address}\": {err:?}",
+                                path.to_string_lossy()
+                            )
+                        })?;
+                        initialized = true;
+                    }
+                    ecc608::Keypair::from_slot(network, slot)
+                        .map(|keypair| helium_crypto::Keypair::from(keypair).into())
+                        .map_err(|err| {
+                            uri_error!("could not load ecc keypair in slot {slot}: {err:?}")
+                        })
+                })
+            }
+            #[cfg(feature = "tpm")]
+            Some("tpm") => {
+                let args = KeypairArgs::from_uri(&url).map_err(error::DecodeError::keypair_uri)?;
+                let network = args.get("network", Network::MainNet)?;
+                let path = url.path();
+
+                probe(&url, &args, Vec::new, || {
+                    tpm::Keypair::from_key_path(network, path)
+                        .map(|keypair| helium_crypto::Keypair::from(keypair).into())
+                        .map_err(|err| {
+                            uri_error!("could not load tpm keypair on path {path}: {err:?}")
+                        })
+                })
+            }
+            Some(unknown) => Err(uri_error!("unkown keypair scheme: \"{unknown}\"")),
+        }
+    }
+}
+
+impl std::ops::Deref for Keypair {
+    type Target = helium_crypto::Keypair;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+struct KeypairArgs(HashMap<String, String>);
+
+impl KeypairArgs {
+    pub(crate) fn from_uri(url: &Uri) -> Result<Self> {
+        let args = url
+            .query()
+            .map_or_else(
+                || Ok(HashMap::new()),
+                serde_urlencoded::from_str::<HashMap<String, String>>,
+            )
+            .map_err(|err| uri_error!("invalid keypair url \"{url}\": {err:?}"))?;
+        Ok(Self(args))
+    }
+
+    pub fn get<T>(&self, name: &str, default: T) -> Result<T>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Debug,
+    {
+        self.0
+            .get(name)
+            .map(|s| s.parse::<T>())
+            .unwrap_or_else(|| Ok(default))
+            .map_err(|err| uri_error!("invalid uri argument for {name}: {err:?}"))
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Keypair {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct _Visitor;
+
+        impl<'de> de::Visitor<'de> for _Visitor {
+            type Value = Keypair;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("keypair uri")
+            }
+            fn visit_str<E>(self, value: &str) -> std::result::Result<Keypair, E>
+            where
+                E: de::Error,
+            {
+                Keypair::from_str(value).map_err(|err| de::Error::custom(err.to_string()))
+            }
+        }
+
+        deserializer.deserialize_str(_Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "ecc608", feature = "tpm"))]
+    #[test]
+    fn probes() {
+        let generate = || -> Keypair {
+            helium_crypto::Keypair::generate(
+                KeyTag {
+                    network: Network::MainNet,
+                    key_type: KeyType::Ed25519,
+                },
+                &mut OsRng,
+            )
+            .into()
+        };
+        let mut calls = 0;
+        let (probe_result, result) = retry(5, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(uri_error!("not ready"))
+            } else {
+                Ok(generate())
+            }
+        });
+        assert!(result.is_ok());
+        assert!(probe_result.ok);
+        assert_eq!(3, probe_result.attempts);
+
+        let (probe_result, result) = retry(2, Duration::ZERO, || Err(uri_error!("not found")));
+        assert!(result.is_err());
+        assert!(!probe_result.ok);
+        assert_eq!(2, probe_result.attempts);
+        assert!(probe_result.error.expect("error").contains("not found"));
+
+        // Without probing the key is loaded with a single attempt
+        let uri = Uri::from_static("ecc://i2c-1?probe_attempts=2");
+        let args = KeypairArgs::from_uri(&uri).expect("keypair args");
+        let mut calls = 0;
+        let result = probe(&uri, &args, Vec::new, || {
+            calls += 1;
+            Err(uri_error!("not found"))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+
+        // With probing the key is retried and the failed probe is saved
+        let dir = std::env::temp_dir().join(format!("key_probe_{}", std::process::id()));
+        enable_probing(&dir);
+        let mut calls = 0;
+        let result = probe(
+            &uri,
+            &args,
+            || vec!["i2c-1".to_string()],
+            || {
+                calls += 1;
+                Err(uri_error!("not found"))
+            },
+        );
+        *PROBE_DIR.lock().expect("key probe lock") = None;
+        assert!(result.is_err());
+        assert_eq!(2, calls);
+        let saved = KeyProbe::load(&dir).expect("saved probes");
+        let saved = saved.last().expect("saved probe");
+        assert!(!saved.ok);
+        assert_eq!(2, saved.attempts);
+        assert_eq!(vec!["i2c-1".to_string()], saved.buses);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn signers() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let keypair: Keypair = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+        .into();
+        let candidates = Signer::candidates(&keypair);
+        assert_eq!(2, candidates.len());
+        for signer in &candidates {
+            assert!(signer.bench(&keypair).is_ok(), "{}", signer.name());
+        }
+        keypair.select_signer(&logger);
+        let signature = helium_crypto::Sign::sign(&keypair, b"message").expect("signature");
+        assert!(
+            helium_crypto::Verify::verify(keypair.public_key(), b"message", &signature).is_ok()
+        );
+    }
+
+    #[test]
+    fn keypair_args() {
+        let uri = &Uri::from_static("ecc://i2c-1:196?slot=22&network=testnet");
+        let args = KeypairArgs::from_uri(uri).expect("keypair args");
+        assert_eq!(22, args.get::<u8>("slot", 22).expect("slot"));
+        assert_eq!(196, uri.port_u16().expect("uri port"));
+        assert_eq!(
+            Network::TestNet,
+            args.get::<Network>("network", Network::MainNet)
+                .expect("network")
+        );
+    }
+}
@@ -1,18 +1,25 @@
 pub mod beaconer;
+pub mod clock;
 pub mod cmd;
+pub mod embedded;
 pub mod error;
+pub mod event_bus;
 pub mod gateway;
 pub mod keyed_uri;
 pub mod keypair;
+pub mod logging;
 pub mod message_cache;
+pub mod metrics;
 pub mod packet;
 
 pub mod packet_router;
+pub mod queues;
 pub mod region_watcher;
 pub mod router;
 pub mod server;
 pub mod service;
 pub mod settings;
+pub mod status_file;
 pub mod sync;
 
 mod api;
@@ -0,0 +1,205 @@
+//! In-process log buffer.
+//!
+//! Log records that pass the configured log level are copied into a ring of
+//! recent lines and broadcast to live subscribers, so operators can tail the
+//! gateway log through the REST API (`GET /v1/logs`) or the `log` command
+//! without access to syslog or journalctl.
+
+use crate::clock::unix_now_millis;
+use serde::{Deserialize, Serialize};
+use slog::{Key, OwnedKVList, Record, KV};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::Mutex,
+};
+use tokio::sync::broadcast;
+
+/// The number of recent log lines kept
+const CAPACITY: usize = 500;
+/// The number of log lines a live subscriber can fall behind
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// A formatted log record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Unix time (milliseconds) the record was logged
+    pub time: u64,
+    /// The level name, like "INFO" or "DEBUG"
+    pub level: String,
+    /// The "module" value of the logger, if any
+    pub module: Option<String>,
+    pub msg: String,
+    /// All other key values of the record and its logger
+    pub kv: BTreeMap<String, String>,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:03} {:<5} ",
+            self.time / 1000,
+            self.time % 1000,
+            self.level
+        )?;
+        if let Some(module) = &self.module {
+            write!(f, "[{module}] ")?;
+        }
+        f.write_str(&self.msg)?;
+        for (key, value) in &self.kv {
+            write!(f, ", {key}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects log lines by minimum level and module
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogFilter {
+    /// The minimum level name, like "info" or "debug"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, line: &LogLine) -> bool {
+        let level = match self.level.as_deref().map(str::parse::<slog::Level>) {
+            Some(Ok(min_level)) => line
+                .level
+                .parse::<slog::Level>()
+                .map(|level| level.is_at_least(min_level))
+                .unwrap_or(true),
+            _ => true,
+        };
+        let module = match &self.module {
+            Some(module) => line.module.as_ref() == Some(module),
+            None => true,
+        };
+        level && module
+    }
+}
+
+struct Buffer {
+    lines: VecDeque<LogLine>,
+    sender: broadcast::Sender<LogLine>,
+}
+
+static BUFFER: Mutex<Option<Buffer>> = Mutex::new(None);
+
+fn with_buffer<T>(f: impl FnOnce(&mut Buffer) -> T) -> T {
+    let mut buffer = BUFFER.lock().expect("log buffer lock");
+    let buffer = buffer.get_or_insert_with(|| Buffer {
+        lines: VecDeque::with_capacity(CAPACITY),
+        sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+    });
+    f(buffer)
+}
+
+fn push(line: LogLine) {
+    with_buffer(|buffer| {
+        if buffer.lines.len() >= CAPACITY {
+            buffer.lines.pop_front();
+        }
+        buffer.lines.push_back(line.clone());
+        // No subscribers is not an error
+        let _ = buffer.sender.send(line);
+    })
+}
+
+/// Returns the buffered log lines, oldest first, and a receiver for the lines
+/// logged after them. No line is missed or repeated between the two.
+pub fn subscribe() -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+    with_buffer(|buffer| {
+        (
+            buffer.lines.iter().cloned().collect(),
+            buffer.sender.subscribe(),
+        )
+    })
+}
+
+/// A drain that copies log records into the log buffer. Place it behind the
+/// same level filter as the log output.
+pub struct BufferDrain;
+
+impl slog::Drain for BufferDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        let mut kv = KvCollector::default();
+        // Record values take precedence over logger values
+        let _ = values.serialize(record, &mut kv);
+        let _ = record.kv().serialize(record, &mut kv);
+        let mut kv = kv.0;
+        let module = kv.remove("module");
+        push(LogLine {
+            time: unix_now_millis(),
+            level: record.level().as_str().to_string(),
+            module,
+            msg: record.msg().to_string(),
+            kv,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct KvCollector(BTreeMap<String, String>);
+
+impl slog::Serializer for KvCollector {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(level: &str, module: Option<&str>) -> LogLine {
+        LogLine {
+            time: 1_700_000_000_123,
+            level: level.to_string(),
+            module: module.map(str::to_string),
+            msg: "starting".to_string(),
+            kv: BTreeMap::from([("listen".to_string(), "127.0.0.1:1680".to_string())]),
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = LogFilter {
+            level: Some("info".to_string()),
+            module: Some("router".to_string()),
+        };
+        assert!(filter.matches(&line("INFO", Some("router"))));
+        assert!(filter.matches(&line("ERROR", Some("router"))));
+        assert!(!filter.matches(&line("DEBUG", Some("router"))));
+        assert!(!filter.matches(&line("INFO", Some("beacon"))));
+        assert!(!filter.matches(&line("INFO", None)));
+        assert!(LogFilter::default().matches(&line("TRACE", None)));
+        assert_eq!(
+            "1700000000.123 INFO  [router] starting, listen: 127.0.0.1:1680",
+            line("INFO", Some("router")).to_string()
+        );
+    }
+
+    #[test]
+    fn drain() {
+        let logger = slog::Logger::root(BufferDrain, slog::o!("module" => "log_buffer_test"));
+        slog::info!(logger, "buffered"; "key" => 42);
+        let (lines, _receiver) = subscribe();
+        let line = lines
+            .iter()
+            .rev()
+            .find(|line| line.module.as_deref() == Some("log_buffer_test"))
+            .expect("buffered line");
+        assert_eq!("buffered", line.msg);
+        assert_eq!("INFO", line.level);
+        assert_eq!(Some("42"), line.kv.get("key").map(String::as_str));
+    }
+}
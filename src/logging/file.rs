@@ -0,0 +1,167 @@
+//! Size capped log file.
+//!
+//! Hotspots keep their logs on flash and often lack a logrotate config, so a
+//! log file left alone eventually fills the disk. With `log.method = "file"`
+//! the log is written to `log.file`, which is rotated once it grows past
+//! `log.max_size` KiB: the current file becomes `<file>.1` (gzipped to
+//! `<file>.1.gz` when `log.compress` is set), older files move up by one and
+//! only `log.keep` rotated files are kept. Compression needs the
+//! `log-compress` feature.
+//!
+//! Rotation happens on flush, after a complete log line, so lines are never
+//! split over two files.
+
+use crate::{settings::LogSettings, Error, Result};
+#[cfg(feature = "log-compress")]
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+    max_size: u64,
+    keep: usize,
+    compress: bool,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+/// Writes the gzipped contents of a file to another file
+#[cfg(feature = "log-compress")]
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "log-compress"))]
+fn gzip(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl RotatingFile {
+    pub fn new(settings: &LogSettings) -> Result<Self> {
+        if settings.compress && cfg!(not(feature = "log-compress")) {
+            return Err(Error::custom(
+                "log.compress requires the log-compress feature",
+            ));
+        }
+        if let Some(dir) = settings.file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let (file, size) = open(&settings.file)?;
+        Ok(Self {
+            path: settings.file.clone(),
+            file,
+            size,
+            max_size: settings.max_size.saturating_mul(1024),
+            keep: settings.keep,
+            compress: settings.compress,
+        })
+    }
+
+    /// The path of the nth rotated file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{n}"));
+        if self.compress {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+            }
+            if self.compress {
+                gzip(&self.path, &self.rotated(1))?;
+            } else {
+                fs::copy(&self.path, self.rotated(1))?;
+            }
+        }
+        self.file.set_len(0)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_size > 0 && self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotate() {
+        let dir = std::env::temp_dir().join(format!("log_file_{}", std::process::id()));
+        let path = dir.join("gateway.log");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("log dir");
+        let (file, size) = open(&path).expect("log file");
+        let mut log = RotatingFile {
+            path: path.clone(),
+            file,
+            size,
+            max_size: 10,
+            keep: 2,
+            compress: false,
+        };
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).expect("write");
+            log.flush().expect("flush");
+        }
+        assert_eq!("fourth\n", fs::read_to_string(&path).expect("log"));
+        assert_eq!(
+            "third line\n",
+            fs::read_to_string(log.rotated(1)).expect("rotated")
+        );
+        assert_eq!(
+            "second line\n",
+            fs::read_to_string(log.rotated(2)).expect("rotated")
+        );
+        assert!(!log.rotated(3).exists());
+
+        #[cfg(feature = "log-compress")]
+        {
+            log.compress = true;
+            log.write_all(b"fifth line of the log\n").expect("write");
+            log.flush().expect("flush");
+            assert!(log.rotated(1).exists());
+            assert_eq!(0, fs::metadata(&path).expect("log").len());
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,36 @@
+//! Runtime log level.
+//!
+//! The log level starts out as the configured level and can be changed while
+//! the gateway runs, for example over the fleet control channel to collect
+//! debug logs from a gateway in the field without restarting it.
+
+use std::sync::Mutex;
+
+static LEVEL: Mutex<slog::Level> = Mutex::new(slog::Level::Info);
+
+/// Sets the log level and returns the previous level
+pub fn set(level: slog::Level) -> slog::Level {
+    std::mem::replace(&mut *LEVEL.lock().expect("log level lock"), level)
+}
+
+pub fn get() -> slog::Level {
+    *LEVEL.lock().expect("log level lock")
+}
+
+/// Whether a log record passes the current log level. Used as the filter of
+/// the log drains.
+pub fn is_enabled(record: &slog::Record) -> bool {
+    record.level().is_at_least(get())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn level() {
+        let previous = set(slog::Level::Debug);
+        assert_eq!(slog::Level::Debug, get());
+        assert_eq!(slog::Level::Debug, set(previous));
+    }
+}
@@ -0,0 +1,7 @@
+//! Log drains and the runtime log level, and sampling of high-rate log
+//! events.
+
+pub mod buffer;
+pub mod file;
+pub mod level;
+pub mod sampling;
@@ -0,0 +1,148 @@
+//! Samples high-rate log events.
+//!
+//! Event types (like "uplink" or "downlink") can be configured to only log
+//! one in every N occurrences. Each sampled log line carries the number of
+//! events suppressed since the previous one and a periodic summary reports
+//! the suppressed counts for all sampled event types.
+//!
+//! Events logged below the current log level are not logged at all, so they
+//! are neither sampled nor counted as suppressed.
+
+use crate::{logging, Result, Settings};
+use slog::{info, o, Level, Logger};
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+use tokio::time;
+
+#[derive(Debug)]
+struct EventSampler {
+    /// Log one in every `every` events
+    every: u64,
+    /// Position in the current sample window
+    seen: u64,
+    /// Events suppressed since the last logged event
+    pending: u64,
+    /// Events suppressed since the last summary
+    suppressed: u64,
+}
+
+static SAMPLERS: Mutex<BTreeMap<String, EventSampler>> = Mutex::new(BTreeMap::new());
+
+/// Configures the sample rate for the given event types. A rate of 0 or 1
+/// logs every event.
+pub fn configure(rates: &BTreeMap<String, u64>) {
+    let mut samplers = SAMPLERS.lock().expect("log sampling lock");
+    samplers.clear();
+    for (event, every) in rates {
+        if *every > 1 {
+            samplers.insert(
+                event.clone(),
+                EventSampler {
+                    every: *every,
+                    seen: 0,
+                    pending: 0,
+                    suppressed: 0,
+                },
+            );
+        }
+    }
+}
+
+/// Returns whether an occurrence of the given event type, logged at the given
+/// level, should be logged and, if so, how many occurrences were suppressed
+/// since the previous logged one. Event types without a configured sample
+/// rate are always logged when the level is enabled.
+pub fn sample(level: Level, event: &str) -> Option<u64> {
+    if !level.is_at_least(logging::level::get()) {
+        return None;
+    }
+    let mut samplers = SAMPLERS.lock().expect("log sampling lock");
+    let sampler = match samplers.get_mut(event) {
+        Some(sampler) => sampler,
+        None => return Some(0),
+    };
+    let logged = sampler.seen == 0;
+    sampler.seen = (sampler.seen + 1) % sampler.every;
+    if logged {
+        Some(std::mem::take(&mut sampler.pending))
+    } else {
+        sampler.pending += 1;
+        sampler.suppressed += 1;
+        None
+    }
+}
+
+/// Takes the per event type suppressed counts since the last summary
+fn take_suppressed() -> BTreeMap<String, u64> {
+    let mut samplers = SAMPLERS.lock().expect("log sampling lock");
+    samplers
+        .iter_mut()
+        .filter(|(_, sampler)| sampler.suppressed > 0)
+        .map(|(event, sampler)| (event.clone(), std::mem::take(&mut sampler.suppressed)))
+        .collect()
+}
+
+/// Periodically logs a summary of suppressed log events
+pub struct LogSampler {
+    rates: BTreeMap<String, u64>,
+    summary_interval: Duration,
+}
+
+impl LogSampler {
+    pub fn new(settings: &Settings) -> Self {
+        configure(&settings.log.sample);
+        Self {
+            rates: settings.log.sample.clone(),
+            summary_interval: Duration::from_secs(settings.log.sample_summary.max(1)),
+        }
+    }
+
+    /// Whether any event is sampled, and so has suppressed logs to summarize
+    pub fn is_enabled(&self) -> bool {
+        self.rates.values().any(|every| *every > 1)
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let logger = logger.new(o!("module" => "log_sampling"));
+        let mut timer = time::interval_at(
+            time::Instant::now() + self.summary_interval,
+            self.summary_interval,
+        );
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => return Ok(()),
+                _ = timer.tick() => {
+                    let suppressed = take_suppressed();
+                    if !suppressed.is_empty() {
+                        info!(logger, "suppressed log events {suppressed:?}";
+                            "interval" => self.summary_interval.as_secs());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        configure(&BTreeMap::from([("test".to_string(), 3)]));
+        let logged: Vec<Option<u64>> = (0..7).map(|_| sample(Level::Info, "test")).collect();
+        assert_eq!(
+            vec![Some(0), None, None, Some(2), None, None, Some(2)],
+            logged
+        );
+        assert_eq!(Some(&4), take_suppressed().get("test"));
+        assert!(take_suppressed().is_empty());
+        assert_eq!(Some(0), sample(Level::Info, "other"));
+
+        // Events below the log level are not counted
+        assert_eq!(None, sample(Level::Trace, "test"));
+        assert!(take_suppressed().is_empty());
+    }
+}
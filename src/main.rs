@@ -2,6 +2,9 @@ use clap::Parser;
 use gateway_rs::{
     cmd,
     error::Result,
+    logging,
+    server::handoff,
+    service::outbound,
     settings::{LogMethod, Settings},
 };
 use slog::{self, debug, error, o, Drain, Logger};
@@ -37,6 +40,14 @@ pub enum Cmd {
     Info(cmd::info::Cmd),
     Server(cmd::server::Cmd),
     Add(Box<cmd::add::Cmd>),
+    Config(cmd::config::Cmd),
+    Selftest(cmd::selftest::Cmd),
+    #[cfg(feature = "rest")]
+    Log(cmd::log::Cmd),
+    #[cfg(feature = "rest")]
+    Diagnostics(cmd::diagnostics::Cmd),
+    #[cfg(feature = "rest")]
+    ServiceStats(cmd::service_stats::Cmd),
 }
 
 /// An empty timestamp function for when timestamp should not be included in
@@ -46,7 +57,16 @@ fn timestamp_none(_io: &mut dyn io::Write) -> io::Result<()> {
     Ok(())
 }
 
-fn mk_logger(settings: &Settings) -> Logger {
+fn log_timestamp(settings: &Settings) -> fn(&mut dyn io::Write) -> io::Result<()> {
+    if settings.log.timestamp {
+        slog_term::timestamp_local
+    } else {
+        timestamp_none
+    }
+}
+
+fn mk_logger(settings: &Settings) -> Result<Logger> {
+    logging::level::set(settings.log.level.into());
     let async_drain = match settings.log.method {
         LogMethod::Syslog => {
             let drain = slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)
@@ -54,39 +74,59 @@ fn mk_logger(settings: &Settings) -> Logger {
                 .fuse();
             slog_async::Async::new(drain)
                 .build()
-                .filter_level(settings.log.level.into())
+                .filter(logging::level::is_enabled)
                 .fuse()
         }
         LogMethod::Stdio => {
             let decorator = slog_term::PlainDecorator::new(io::stdout());
-            let timestamp = if settings.log.timestamp {
-                slog_term::timestamp_local
-            } else {
-                timestamp_none
-            };
             let drain = slog_term::FullFormat::new(decorator)
-                .use_custom_timestamp(timestamp)
+                .use_custom_timestamp(log_timestamp(settings))
                 .build()
                 .fuse();
             slog_async::Async::new(drain)
                 .build()
-                .filter_level(settings.log.level.into())
+                .filter(logging::level::is_enabled)
+                .fuse()
+        }
+        LogMethod::File => {
+            let file = logging::file::RotatingFile::new(&settings.log)?;
+            let decorator = slog_term::PlainDecorator::new(file);
+            let drain = slog_term::FullFormat::new(decorator)
+                .use_custom_timestamp(log_timestamp(settings))
+                .build()
+                .fuse();
+            slog_async::Async::new(drain)
+                .build()
+                .filter(logging::level::is_enabled)
                 .fuse()
         }
     };
-    slog::Logger::root(async_drain, o!())
+    // Log lines are also kept in process for the log command and REST API
+    let buffer_drain = logging::buffer::BufferDrain
+        .filter(logging::level::is_enabled)
+        .fuse();
+    Ok(slog::Logger::root(
+        slog::Duplicate::new(async_drain, buffer_drain).fuse(),
+        o!(),
+    ))
 }
 
 pub fn main() -> Result {
     let cli = Cli::parse();
-    if cli.daemon {
+    // A process started by a handoff is already detached, and the old process
+    // moves the pid file over once it took over
+    if cli.daemon && std::env::var_os(handoff::HANDOFF_ENV).is_none() {
         daemonize::Daemonize::new()
-            .pid_file(format!("/var/run/{}.pid", env!("CARGO_BIN_NAME")))
+            .pid_file(handoff::PID_FILE)
             .start()
             .expect("daemon start");
     }
 
-    let settings = Settings::new(&cli.config)?;
+    let settings = match cli.cmd {
+        Cmd::Server(_) => Settings::for_server(&cli.config)?,
+        _ => Settings::new(&cli.config)?,
+    };
+    outbound::configure(&settings.outbound)?;
 
     // This `main()` returns a result only for errors we can't easily
     // intercept and log. An example is config file parsing. The
@@ -96,7 +136,7 @@ pub fn main() -> Result {
     // logger, simply calling `exit()` early prevents any error
     // logging from reaching its destination.
     let retcode = {
-        let logger = mk_logger(&settings);
+        let logger = mk_logger(&settings)?;
         // This guard protects the global logger and needs live until
         // the end of this block, despite being used directly.
         let _scope_guard = slog_scope::set_global_logger(logger);
@@ -128,7 +168,7 @@ pub fn main() -> Result {
 
         match res {
             Err(e) => {
-                error!(&run_logger, "{e}");
+                error!(&run_logger, "{e}"; "code" => e.code());
                 1
             }
             _ => 0,
@@ -149,6 +189,14 @@ pub async fn run(
         Cmd::Key(cmd) => cmd.run(settings).await,
         Cmd::Info(cmd) => cmd.run(settings).await,
         Cmd::Add(cmd) => cmd.run(settings).await,
+        Cmd::Config(cmd) => cmd.run(settings).await,
+        Cmd::Selftest(cmd) => cmd.run(settings).await,
+        #[cfg(feature = "rest")]
+        Cmd::Log(cmd) => cmd.run(shutdown_listener, settings).await,
+        #[cfg(feature = "rest")]
+        Cmd::Diagnostics(cmd) => cmd.run(settings).await,
+        #[cfg(feature = "rest")]
+        Cmd::ServiceStats(cmd) => cmd.run(settings).await,
         Cmd::Server(cmd) => cmd.run(shutdown_listener, settings, &logger).await,
     }
 }
@@ -42,10 +42,14 @@ impl<T> MessageCache<T> {
         }
     }
 
-    pub fn push_back(&mut self, message: T, received: Instant) {
+    /// Queues a message. Returns the oldest message when it was discarded to
+    /// stay within the maximum number of messages.
+    pub fn push_back(&mut self, message: T, received: Instant) -> Option<CacheMessage<T>> {
         self.waiting.push_back(CacheMessage { message, received });
         if self.len() > self.max_messages as usize {
-            self.waiting.pop_front();
+            self.waiting.pop_front()
+        } else {
+            None
         }
     }
 
@@ -0,0 +1,249 @@
+//! A minimal process wide metrics registry.
+//!
+//! Modules record counters, gauges and histograms by name (optionally with
+//! labels, see [`labeled`]) and the registry is rendered in the Prometheus text exposition
+//! format by the [`MetricsServer`]. Values are kept behind a mutex rather than
+//! atomics since 64 bit atomics are not available on all supported targets.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    sync::Mutex,
+};
+
+#[cfg(feature = "metrics")]
+pub mod server;
+pub mod snapshots;
+
+#[cfg(feature = "metrics")]
+pub use server::MetricsServer;
+pub use snapshots::MetricsSnapshots;
+
+/// Upper bounds of the histogram buckets, suitable for request latencies in
+/// seconds
+pub const HISTOGRAM_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        /// Observations per bucket, not cumulative
+        buckets: [u64; HISTOGRAM_BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+const EMPTY_HISTOGRAM: Value = Value::Histogram {
+    buckets: [0; HISTOGRAM_BUCKETS.len()],
+    sum: 0.0,
+    count: 0,
+};
+
+static REGISTRY: Mutex<BTreeMap<String, Value>> = Mutex::new(BTreeMap::new());
+
+/// Increment the counter with the given name by one
+pub fn increment(name: &str) {
+    increment_by(name, 1)
+}
+
+/// Increment the counter with the given name by the given value
+pub fn increment_by(name: &str, value: u64) {
+    let mut registry = REGISTRY.lock().expect("metrics registry");
+    match registry.get_mut(name) {
+        Some(Value::Counter(counter)) => *counter = counter.saturating_add(value),
+        _ => {
+            registry.insert(name.to_string(), Value::Counter(value));
+        }
+    }
+}
+
+/// Set the gauge with the given name to the given value
+pub fn set(name: &str, value: f64) {
+    REGISTRY
+        .lock()
+        .expect("metrics registry")
+        .insert(name.to_string(), Value::Gauge(value));
+}
+
+/// Record an observation in the histogram with the given name
+pub fn observe(name: &str, value: f64) {
+    let mut registry = REGISTRY.lock().expect("metrics registry");
+    let entry = registry.entry(name.to_string()).or_insert(EMPTY_HISTOGRAM);
+    if !matches!(entry, Value::Histogram { .. }) {
+        *entry = EMPTY_HISTOGRAM;
+    }
+    if let Value::Histogram {
+        buckets,
+        sum,
+        count,
+    } = entry
+    {
+        if let Some(bucket) = HISTOGRAM_BUCKETS.iter().position(|bound| value <= *bound) {
+            buckets[bucket] += 1;
+        }
+        *sum += value;
+        *count += 1;
+    }
+}
+
+/// Get the current value of the counter or gauge with the given name
+pub fn get(name: &str) -> Option<f64> {
+    REGISTRY
+        .lock()
+        .expect("metrics registry")
+        .get(name)
+        .map(|value| match value {
+            Value::Counter(counter) => *counter as f64,
+            Value::Gauge(gauge) => *gauge,
+            Value::Histogram { count, .. } => *count as f64,
+        })
+}
+
+/// The current values of all counters and gauges. Histograms are included as
+/// their `_count` and `_sum`.
+pub fn values() -> BTreeMap<String, f64> {
+    let registry = REGISTRY.lock().expect("metrics registry");
+    let mut values = BTreeMap::new();
+    for (name, value) in registry.iter() {
+        match value {
+            Value::Counter(counter) => {
+                values.insert(name.clone(), *counter as f64);
+            }
+            Value::Gauge(gauge) => {
+                values.insert(name.clone(), *gauge);
+            }
+            Value::Histogram { sum, count, .. } => {
+                let (base_name, labels) = name
+                    .split_once('{')
+                    .map_or((name.as_str(), String::new()), |(base, labels)| {
+                        (base, format!("{{{labels}"))
+                    });
+                values.insert(format!("{base_name}_count{labels}"), *count as f64);
+                values.insert(format!("{base_name}_sum{labels}"), *sum);
+            }
+        }
+    }
+    values
+}
+
+/// Construct a metric name with the given labels, for example
+/// `labeled("uplinks", &[("reason", "crc")])` returns `uplinks{reason="crc"}`
+pub fn labeled(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", value.replace('"', "\\\"")))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{name}{{{labels}}}")
+}
+
+/// Render all registered metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = REGISTRY.lock().expect("metrics registry");
+    let mut typed = BTreeSet::new();
+    let mut output = String::new();
+    for (name, value) in registry.iter() {
+        let (base_name, labels) = name
+            .split_once('{')
+            .map_or((name.as_str(), ""), |(base, labels)| {
+                (base, labels.trim_end_matches('}'))
+            });
+        let kind = match value {
+            Value::Counter(_) => "counter",
+            Value::Gauge(_) => "gauge",
+            Value::Histogram { .. } => "histogram",
+        };
+        if typed.insert(base_name) {
+            let _ = writeln!(output, "# TYPE {base_name} {kind}");
+        }
+        match value {
+            Value::Counter(counter) => {
+                let _ = writeln!(output, "{name} {counter}");
+            }
+            Value::Gauge(gauge) => {
+                let _ = writeln!(output, "{name} {gauge}");
+            }
+            Value::Histogram {
+                buckets,
+                sum,
+                count,
+            } => {
+                let separator = if labels.is_empty() { "" } else { "," };
+                let mut cumulative = 0;
+                for (bound, observed) in HISTOGRAM_BUCKETS.iter().zip(buckets) {
+                    cumulative += observed;
+                    let _ = writeln!(
+                        output,
+                        "{base_name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+                    );
+                }
+                let _ = writeln!(
+                    output,
+                    "{base_name}_bucket{{{labels}{separator}le=\"+Inf\"}} {count}"
+                );
+                let labels = if labels.is_empty() {
+                    String::new()
+                } else {
+                    format!("{{{labels}}}")
+                };
+                let _ = writeln!(output, "{base_name}_sum{labels} {sum}");
+                let _ = writeln!(output, "{base_name}_count{labels} {count}");
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        increment(&labeled("test_render_total", &[("kind", "a")]));
+        increment_by(&labeled("test_render_total", &[("kind", "b")]), 2);
+        set("test_render_gauge", 1.5);
+
+        let output = render();
+        assert!(output.contains("# TYPE test_render_total counter\n"));
+        assert!(output.contains("test_render_total{kind=\"a\"} 1\n"));
+        assert!(output.contains("test_render_total{kind=\"b\"} 2\n"));
+        assert!(output.contains("# TYPE test_render_gauge gauge\ntest_render_gauge 1.5\n"));
+        assert_eq!(1, output.matches("# TYPE test_render_total").count());
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let name = labeled("test_histogram_seconds", &[("service", "a")]);
+        observe(&name, 0.25);
+        observe(&name, 0.5);
+        observe(&name, 20.0);
+        assert_eq!(Some(3.0), get(&name));
+
+        let output = render();
+        assert!(output.contains("# TYPE test_histogram_seconds histogram\n"));
+        assert!(output.contains("test_histogram_seconds_bucket{service=\"a\",le=\"0.005\"} 0\n"));
+        assert!(output.contains("test_histogram_seconds_bucket{service=\"a\",le=\"0.25\"} 1\n"));
+        assert!(output.contains("test_histogram_seconds_bucket{service=\"a\",le=\"0.5\"} 2\n"));
+        assert!(output.contains("test_histogram_seconds_bucket{service=\"a\",le=\"+Inf\"} 3\n"));
+        assert!(output.contains("test_histogram_seconds_sum{service=\"a\"} 20.75\n"));
+        assert!(output.contains("test_histogram_seconds_count{service=\"a\"} 3\n"));
+
+        let values = values();
+        assert_eq!(
+            Some(&3.0),
+            values.get("test_histogram_seconds_count{service=\"a\"}")
+        );
+        assert_eq!(
+            Some(&20.75),
+            values.get("test_histogram_seconds_sum{service=\"a\"}")
+        );
+    }
+}
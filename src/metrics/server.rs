@@ -0,0 +1,52 @@
+use crate::{metrics, Error, Result, Settings};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use slog::{info, o, Logger};
+use std::{convert::Infallible, net::SocketAddr};
+
+/// Serves the metrics registry in the Prometheus text format on `/metrics`.
+/// The server is only started when a metrics listen address is configured.
+pub struct MetricsServer {
+    listen: Option<String>,
+}
+
+impl MetricsServer {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            listen: settings.metrics.listen.clone(),
+        }
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let listen = match self.listen {
+            Some(listen) => listen,
+            None => return Ok(()),
+        };
+        let addr: SocketAddr = listen.parse()?;
+        let logger = logger.new(o!("module" => "metrics", "listen" => addr));
+        info!(logger, "starting");
+
+        let make_service =
+            make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+        Server::try_bind(&addr)
+            .map_err(Error::from)?
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown.clone())
+            .await
+            .map_err(Error::from)
+    }
+}
+
+async fn handle_request(req: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("metrics response"))
+}
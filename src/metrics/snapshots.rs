@@ -0,0 +1,222 @@
+//! Periodic metrics snapshots kept in a ring file in the data directory.
+//!
+//! Support can then look at how a gateway behaved over the last day even when
+//! nothing scraped the metrics endpoint. Every `metrics.snapshot_interval`
+//! seconds the counters and gauges (and the count and sum of histograms) are
+//! appended to the ring file as a JSON line, and snapshots older than
+//! `metrics.snapshot_retention` seconds are dropped. The REST API serves the
+//! snapshots as a [`SnapshotBundle`].
+
+use crate::{clock::unix_now, metrics, Result, Settings};
+use serde::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::time;
+
+const SNAPSHOTS_FILE: &str = "metrics_snapshots.jsonl";
+
+/// The metric values at a point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Unix time (seconds) the snapshot was taken
+    pub time: u64,
+    pub values: BTreeMap<String, f64>,
+}
+
+/// Snapshots in a columnar layout so metric names are not repeated in every
+/// snapshot
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotBundle {
+    /// Seconds between snapshots
+    pub interval: u64,
+    /// The metric names the snapshot values are in the order of
+    pub names: Vec<String>,
+    pub snapshots: Vec<BundleSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BundleSnapshot {
+    /// Unix time (seconds) the snapshot was taken
+    pub time: u64,
+    /// The value per name, null for metrics that did not exist yet
+    pub values: Vec<Option<f64>>,
+}
+
+impl SnapshotBundle {
+    fn new(interval: u64, snapshots: Vec<Snapshot>) -> Self {
+        let names: Vec<String> = snapshots
+            .iter()
+            .flat_map(|snapshot| snapshot.values.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect();
+        let snapshots = snapshots
+            .into_iter()
+            .map(|snapshot| BundleSnapshot {
+                time: snapshot.time,
+                values: names
+                    .iter()
+                    .map(|name| snapshot.values.get(name).copied())
+                    .collect(),
+            })
+            .collect();
+        Self {
+            interval,
+            names,
+            snapshots,
+        }
+    }
+}
+
+/// The ring file of metrics snapshots
+#[derive(Debug, Clone)]
+pub struct SnapshotRing {
+    path: PathBuf,
+    interval: u64,
+    retention: u64,
+}
+
+impl SnapshotRing {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            path: settings.data_dir.join(SNAPSHOTS_FILE),
+            interval: settings.metrics.snapshot_interval,
+            retention: settings.metrics.snapshot_retention,
+        }
+    }
+
+    /// The stored snapshots, oldest first. Lines that can not be parsed,
+    /// like one cut short by a power loss, are skipped.
+    pub fn load(&self) -> Result<Vec<Snapshot>> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(data
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    pub fn bundle(&self) -> Result<SnapshotBundle> {
+        Ok(SnapshotBundle::new(self.interval, self.load()?))
+    }
+
+    /// Appends a snapshot and drops the snapshots that are past the retention
+    /// period. The file is replaced as a whole so it never holds a partial
+    /// ring.
+    pub fn push(&self, snapshot: Snapshot) -> Result {
+        let mut snapshots = self.load()?;
+        let oldest = snapshot.time.saturating_sub(self.retention);
+        snapshots.retain(|stored| stored.time > oldest && stored.time < snapshot.time);
+        snapshots.push(snapshot);
+        let mut data = String::new();
+        for snapshot in &snapshots {
+            data.push_str(&serde_json::to_string(snapshot)?);
+            data.push('\n');
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Takes a metrics snapshot every snapshot interval
+pub struct MetricsSnapshots {
+    ring: SnapshotRing,
+}
+
+impl MetricsSnapshots {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            ring: SnapshotRing::new(settings),
+        }
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if self.ring.interval == 0 {
+            return Ok(());
+        }
+        let logger = logger.new(o!("module" => "metrics_snapshots"));
+        info!(logger, "starting";
+            "interval" => self.ring.interval,
+            "retention" => self.ring.retention);
+
+        let interval = Duration::from_secs(self.ring.interval);
+        // The first snapshot is taken one interval after startup, an empty
+        // registry is of no use
+        let mut timer = time::interval_at(time::Instant::now() + interval, interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = timer.tick() => {
+                    let snapshot = Snapshot {
+                        time: unix_now(),
+                        values: metrics::values(),
+                    };
+                    if let Err(err) = self.ring.push(snapshot) {
+                        warn!(logger, "failed to store metrics snapshot: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(time: u64, values: &[(&str, f64)]) -> Snapshot {
+        Snapshot {
+            time,
+            values: values
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn ring() {
+        let dir = std::env::temp_dir().join(format!("metrics_snapshots_{}", std::process::id()));
+        let ring = SnapshotRing {
+            path: dir.join(SNAPSHOTS_FILE),
+            interval: 300,
+            retention: 600,
+        };
+        assert_eq!(Vec::<Snapshot>::new(), ring.load().expect("load"));
+
+        ring.push(snapshot(1000, &[("a", 1.0)])).expect("push");
+        ring.push(snapshot(1300, &[("a", 2.0), ("b", 1.0)]))
+            .expect("push");
+        ring.push(snapshot(1600, &[("b", 2.0)])).expect("push");
+        // The first snapshot is past the retention period
+        let snapshots = ring.load().expect("load");
+        assert_eq!(
+            vec![1300, 1600],
+            snapshots.iter().map(|s| s.time).collect::<Vec<_>>()
+        );
+
+        let bundle = ring.bundle().expect("bundle");
+        assert_eq!(vec!["a", "b"], bundle.names);
+        assert_eq!(vec![Some(2.0), Some(1.0)], bundle.snapshots[0].values);
+        assert_eq!(vec![None, Some(2.0)], bundle.snapshots[1].values);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -9,6 +9,10 @@ use helium_proto::{
     DataRate as ProtoDataRate, Eui, RoutingInformation,
 };
 use lorawan::{Direction, PHYPayloadFrame, MHDR};
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal,
+};
 use semtech_udp::{
     pull_resp::{self, PhyData, Time},
     push_data::{self, CRC},
@@ -20,11 +24,35 @@ use std::{
     fmt,
     ops::Deref,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug, Clone)]
-pub struct Packet(helium_proto::Packet);
+pub struct Packet(helium_proto::Packet, ReceivedAt);
+
+/// When a packet was received by the gateway. Time differences, like how long
+/// an uplink was held, are taken from the monotonic instant so they are not
+/// affected when NTP steps the system clock. The wall clock time is only used
+/// in reports.
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedAt {
+    pub instant: Instant,
+    pub wall: SystemTime,
+}
+
+impl ReceivedAt {
+    pub fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// The wall clock receive time in nanoseconds since the unix epoch
+    pub fn unix_nanos(&self) -> Result<u64> {
+        Ok(self.wall.duration_since(UNIX_EPOCH)?.as_nanos() as u64)
+    }
+}
 
 impl Deref for Packet {
     type Target = helium_proto::Packet;
@@ -73,7 +101,7 @@ impl TryFrom<push_data::RxPk> for Packet {
                 rx2_window: None,
                 oui: 0,
             };
-            Ok(Self(packet))
+            Ok(Self(packet, ReceivedAt::now()))
         } else {
             Err(DecodeError::invalid_crc())
         }
@@ -97,13 +125,17 @@ impl TryFrom<PacketRouterPacketDownV1> for Packet {
             datarate: datarate.to_string(),
             snr: 0.0,
             routing: None,
-            rx2_window: pr_down.rx2.map(|window| helium_proto::Window {
-                timestamp: window.timestamp,
-                frequency: window.frequency as f32 / 1_000_000.0,
-                datarate: window.datarate.to_string(),
+            // An rx2 window with an unknown datarate is left out rather than
+            // failing the whole downlink
+            rx2_window: pr_down.rx2.and_then(|window| {
+                Some(helium_proto::Window {
+                    timestamp: window.timestamp,
+                    frequency: window.frequency as f32 / 1_000_000.0,
+                    datarate: helium_proto::DataRate::from_i32(window.datarate)?.to_string(),
+                })
             }),
         };
-        Ok(Self(packet))
+        Ok(Self(packet, ReceivedAt::now()))
     }
 }
 
@@ -118,7 +150,8 @@ impl TryFrom<Packet> for PacketRouterPacketUpV1 {
             datarate: ProtoDataRate::from_str(&value.datarate)? as i32,
             snr: value.snr,
             region: 0,
-            hold_time: 0,
+            // Milliseconds since the uplink was received
+            hold_time: value.1.instant.elapsed().as_millis() as u64,
             gateway: vec![],
             signature: vec![],
         })
@@ -138,10 +171,9 @@ impl TryFrom<Packet> for poc_lora::LoraWitnessReportReqV1 {
             pub_key: vec![],
             data: payload,
             tmst: value.timestamp as u32,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map_err(Error::from)?
-                .as_nanos() as u64,
+            // The time the beacon was received rather than when the report
+            // is made, which can be later when witnesses are queued
+            timestamp: value.1.unix_nanos()?,
             signal: (value.signal_strength * 10.0) as i32,
             snr: (value.snr * 10.0) as i32,
             frequency: to_hz(value.frequency),
@@ -154,7 +186,7 @@ impl TryFrom<Packet> for poc_lora::LoraWitnessReportReqV1 {
 
 impl From<helium_proto::Packet> for Packet {
     fn from(v: helium_proto::Packet) -> Self {
-        Self(v)
+        Self(v, ReceivedAt::now())
     }
 }
 
@@ -167,6 +199,45 @@ impl Packet {
         self.0
     }
 
+    pub fn received(&self) -> ReceivedAt {
+        self.1
+    }
+
+    /// The packet as received at the given time, like when replaying a
+    /// recorded uplink
+    pub fn with_received(self, received: ReceivedAt) -> Self {
+        Self(self.0, received)
+    }
+
+    /// Adds the given offsets (in dB) to the signal strength and SNR
+    pub fn adjust_signal(&mut self, rssi: f32, snr: f32) {
+        self.0.signal_strength += rssi;
+        self.0.snr += snr;
+    }
+
+    /// Replaces signal and frequency values that are not a number with 0
+    pub fn normalize_metadata(&mut self) {
+        for value in [
+            &mut self.0.signal_strength,
+            &mut self.0.snr,
+            &mut self.0.frequency,
+        ] {
+            if !value.is_finite() {
+                *value = 0.0;
+            }
+        }
+    }
+
+    /// Whether this packet is a LoRaWAN join request
+    pub fn is_join_request(&self) -> bool {
+        matches!(
+            self.routing(),
+            Some(RoutingInformation {
+                data: Some(RoutingData::Eui(_))
+            })
+        )
+    }
+
     pub fn payload(&self) -> &[u8] {
         &self.0.payload
     }
@@ -268,6 +339,11 @@ impl Packet {
     }
 }
 
-fn to_hz(mhz: f32) -> u64 {
-    (mhz * 1_000_000f32).trunc() as u64
+/// Converts a frequency in MHz to Hz. The frequency is rounded to the
+/// precision of the float first, multiplying 868.1 as a float by a million
+/// yields 868099968.
+pub fn to_hz(mhz: f32) -> u64 {
+    Decimal::from_f32(mhz)
+        .and_then(|mhz| (mhz * Decimal::from(1_000_000)).trunc().to_u64())
+        .unwrap_or_default()
 }
@@ -1,16 +1,33 @@
 use crate::{
-    gateway,
+    clock::unix_time,
+    gateway::{
+        self,
+        uplink_stats::{Disposition, DropReason, UplinkStatsHandle},
+    },
+    logging,
     message_cache::{CacheMessage, MessageCache},
     region_watcher,
-    service::packet_router::PacketRouterService,
+    server::Handles,
+    service::{
+        packet_router::{PacketRouterService, Received},
+        quarantine::{self, QuarantineHandle},
+        until_shutdown,
+    },
     sync, Base64, Keypair, MsgSign, Packet, RegionParams, Result, Settings,
 };
 use exponential_backoff::Backoff;
 use helium_proto::services::router::{PacketRouterPacketDownV1, PacketRouterPacketUpV1};
-use slog::{debug, info, o, warn, Logger};
+use http::Uri;
+use slog::{debug, info, o, warn, Level, Logger};
 use std::{sync::Arc, time::Instant as StdInstant};
 use tokio::time::{self, Duration, Instant};
 
+pub mod shadow;
+mod spool;
+
+pub use shadow::ShadowRouter;
+use spool::Spool;
+
 const STORE_GC_INTERVAL: Duration = Duration::from_secs(60);
 
 const RECONNECT_BACKOFF_RETRIES: u32 = 20;
@@ -23,19 +40,26 @@ pub enum Message {
         packet: Packet,
         received: StdInstant,
     },
+    /// Reconnect to the router right away instead of waiting for the next
+    /// reconnect time
+    Reconnect,
 }
 
 pub type MessageSender = sync::MessageSender<Message>;
 pub type MessageReceiver = sync::MessageReceiver<Message>;
 
-pub fn message_channel() -> (MessageSender, MessageReceiver) {
-    sync::message_channel(20)
+pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
+    sync::message_channel(size)
 }
 
 impl MessageSender {
     pub async fn uplink(&self, packet: Packet, received: StdInstant) {
         self.send(Message::Uplink { packet, received }).await
     }
+
+    pub async fn reconnect(&self) {
+        self.send(Message::Reconnect).await
+    }
 }
 
 pub struct PacketRouter {
@@ -47,6 +71,11 @@ pub struct PacketRouter {
     region_params: RegionParams,
     keypair: Arc<Keypair>,
     store: MessageCache<Packet>,
+    /// Uplinks that failed to send while the router was unreachable, when
+    /// enabled
+    spool: Option<Spool>,
+    uplink_stats: UplinkStatsHandle,
+    quarantine: QuarantineHandle,
 }
 
 impl PacketRouter {
@@ -55,13 +84,28 @@ impl PacketRouter {
         messages: MessageReceiver,
         region_watch: region_watcher::MessageReceiver,
         transmit: gateway::MessageSender,
-    ) -> Self {
+        handles: &Handles,
+    ) -> Result<Self> {
         let router_settings = &settings.router;
-        let service =
-            PacketRouterService::new(router_settings.uri.clone(), settings.keypair.clone());
+        let fallbacks = router_settings
+            .fallbacks
+            .iter()
+            .map(|uri| uri.parse::<Uri>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let service = PacketRouterService::new(
+            router_settings.uri.clone(),
+            settings.keypair.clone(),
+            handles,
+        )
+        .with_fallbacks(
+            fallbacks,
+            Duration::from_secs(router_settings.failback_interval),
+        )
+        .with_heartbeat(Duration::from_secs(router_settings.heartbeat_interval));
         let store = MessageCache::new(router_settings.queue);
+        let spool = Spool::new(router_settings, &settings.data_dir);
         let region_params = region_watcher::current_value(&region_watch);
-        Self {
+        Ok(Self {
             service,
             region_params,
             region_watch,
@@ -69,16 +113,27 @@ impl PacketRouter {
             transmit,
             messages,
             store,
+            spool,
             reconnect_retry: 0,
-        }
+            uplink_stats: handles.uplink_stats.clone(),
+            quarantine: handles.quarantine.clone(),
+        })
     }
 
     pub async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
         let logger = logger.new(o!(
             "module" => "router",
-            "uri" => self.service.uri.to_string(),
+            "uri" => self.service.uri().to_string(),
         ));
         info!(logger, "starting");
+        if let Some(spool) = &mut self.spool {
+            match spool.refresh() {
+                Ok(spooled) => info!(logger, "spooling uplinks";
+                    "max_age" => spool.max_age().as_secs(),
+                    "spooled" => spooled),
+                Err(err) => warn!(logger, "failed to read uplink spool {err:?}"),
+            }
+        }
 
         let reconnect_backoff = Backoff::new(
             RECONNECT_BACKOFF_RETRIES,
@@ -91,25 +146,46 @@ impl PacketRouter {
         let mut reconnect_sleep = Instant::now() + RECONNECT_BACKOFF_MIN_WAIT;
 
         loop {
+            let failback_at = self.service.failback_at();
+            let heartbeat_at = self.service.heartbeat_at();
             tokio::select! {
                 _ = shutdown.clone() => {
                     info!(logger, "shutting down");
                     return Ok(())
                 },
                 message = self.messages.recv() => match message {
-                    Some(Message::Uplink{packet, received}) =>
-                        self.handle_uplink(&logger, packet, received).await,
+                    Some(Message::Uplink{packet, received}) => {
+                        until_shutdown(shutdown, self.handle_uplink(&logger, packet, received)).await;
+                    }
+                    Some(Message::Reconnect) => {
+                        info!(logger, "reconnect requested");
+                        reconnect_sleep = Instant::now();
+                    }
                     None => warn!(logger, "ignoring closed message channel"),
                 },
                 region_change = self.region_watch.changed() => match region_change {
                     Ok(()) => self.region_params = region_watcher::current_value(&self.region_watch),
                     Err(_) => warn!(logger, "region watch disconnected")
                 },
+                _ = time::sleep_until(failback_at.unwrap_or_else(Instant::now)), if failback_at.is_some() => {
+                    until_shutdown(shutdown, self.handle_failback(&logger)).await;
+                },
+                _ = time::sleep_until(heartbeat_at.unwrap_or_else(Instant::now)), if heartbeat_at.is_some() => {
+                    if let Some(Err(err)) = until_shutdown(shutdown, self.service.heartbeat()).await {
+                        warn!(logger, "router heartbeat failed {err:?}");
+                    }
+                },
                 _ = time::sleep_until(reconnect_sleep) => {
-                    reconnect_sleep = self.handle_reconnect(&logger, &reconnect_backoff).await;
+                    if let Some(next) = until_shutdown(shutdown, self.handle_reconnect(&logger, &reconnect_backoff)).await {
+                        reconnect_sleep = next;
+                    }
                 },
                 downlink = self.service.recv() => match downlink {
-                    Ok(Some(message)) => self.handle_downlink(&logger, message).await,
+                    Ok(Some(Received::Connected)) => {
+                        info!(logger, "connected"; "endpoint" => self.service.uri().to_string());
+                        until_shutdown(shutdown, self.handle_connected(&logger)).await;
+                    }
+                    Ok(Some(Received::Downlink(message))) => self.handle_downlink(&logger, message).await,
                     Ok(None) => warn!(logger, "router disconnected"),
                     Err(err) => warn!(logger, "router error {:?}", err),
                 }
@@ -121,9 +197,8 @@ impl PacketRouter {
         info!(logger, "reconnecting");
         match self.service.reconnect().await {
             Ok(_) => {
-                info!(logger, "reconnected");
-                self.reconnect_retry = RECONNECT_BACKOFF_RETRIES;
-                self.send_waiting_packets(logger).await
+                info!(logger, "reconnected"; "endpoint" => self.service.uri().to_string());
+                self.handle_connected(logger).await
             }
             Err(err) => {
                 warn!(logger, "could not reconnect {err:?}");
@@ -140,12 +215,44 @@ impl PacketRouter {
                 .unwrap_or(RECONNECT_BACKOFF_MAX_WAIT)
     }
 
+    /// Sends the uplinks that waited for the router once it is connected
+    async fn handle_connected(&mut self, logger: &Logger) {
+        self.reconnect_retry = RECONNECT_BACKOFF_RETRIES;
+        self.send_spooled_packets(logger).await;
+        self.send_waiting_packets(logger).await
+    }
+
+    async fn handle_failback(&mut self, logger: &Logger) {
+        match self.service.failback().await {
+            Ok(true) => {
+                info!(logger, "failed back to primary router";
+                    "endpoint" => self.service.uri().to_string());
+                self.send_spooled_packets(logger).await;
+            }
+            Ok(false) => (),
+            Err(err) => warn!(logger, "primary router still unreachable {err:?}"),
+        }
+    }
+
     async fn handle_uplink(&mut self, logger: &Logger, uplink: Packet, received: StdInstant) {
-        self.store.push_back(uplink, received);
+        if let Some(discarded) = self.store.push_back(uplink, received) {
+            self.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::QueueFull),
+                Some(discarded.payload()),
+            );
+        }
         self.send_waiting_packets(logger).await;
     }
 
-    async fn handle_downlink(&mut self, logger: &Logger, message: PacketRouterPacketDownV1) {
+    async fn handle_downlink(&mut self, logger: &Logger, mut message: PacketRouterPacketDownV1) {
+        let findings = quarantine::check_downlink(&mut message);
+        if self
+            .quarantine
+            .quarantine(logger, "router", &message, findings)
+        {
+            return;
+        }
         match Packet::try_from(message) {
             Ok(packet) => self.transmit.downlink(packet).await,
             Err(err) => warn!(logger, "could not convert packet to downlink {:?}", err),
@@ -153,12 +260,101 @@ impl PacketRouter {
     }
 
     async fn send_waiting_packets(&mut self, logger: &Logger) {
+        let mut sent = false;
         while let (removed, Some(packet)) = self.store.pop_front(STORE_GC_INTERVAL) {
             if removed > 0 {
                 info!(logger, "discarded {} queued packets", removed);
+                self.uplink_stats.record_count(
+                    logger,
+                    Disposition::Dropped(DropReason::Age),
+                    removed,
+                );
+            }
+            match self.send_packet(logger, packet).await {
+                Ok(()) => sent = true,
+                Err(err) => warn!(logger, "failed to send uplink {err:?}"; "code" => err.code()),
+            }
+        }
+        // A send reconnects the router as well, spooled uplinks follow the
+        // fresh ones once it is reachable again
+        if sent {
+            self.send_spooled_packets(logger).await;
+        }
+    }
+
+    /// Sends spooled uplinks, oldest first, until they are all sent or the
+    /// router can not be reached
+    async fn send_spooled_packets(&mut self, logger: &Logger) {
+        let Some(spool) = &mut self.spool else {
+            return;
+        };
+        if spool.is_empty() {
+            return;
+        }
+        let pending = match spool.pending() {
+            Ok(pending) => pending,
+            Err(err) => {
+                warn!(logger, "failed to read uplink spool {err:?}");
+                return;
+            }
+        };
+        let now = unix_time();
+        let mut sent = 0;
+        for spooled in pending {
+            if spooled.is_expired(now, spool.max_age()) {
+                spooled.discard("expired");
+                self.uplink_stats
+                    .record(logger, Disposition::Dropped(DropReason::Age), None);
+                continue;
+            }
+            let uplink = match spooled.load() {
+                Ok(uplink) => uplink,
+                Err(err) => {
+                    warn!(logger, "discarding unreadable spooled uplink {err:?}");
+                    spooled.discard("invalid");
+                    continue;
+                }
+            };
+            let payload = uplink.payload.clone();
+            match self.service.send(uplink).await {
+                Ok(()) => {
+                    spooled.remove();
+                    self.uplink_stats
+                        .record(logger, Disposition::Forwarded, Some(&payload));
+                    sent += 1;
+                }
+                Err(err) => {
+                    warn!(logger, "failed to send spooled uplink {err:?}"; "code" => err.code());
+                    break;
+                }
             }
-            if let Err(err) = self.send_packet(logger, packet).await {
-                warn!(logger, "failed to send uplink {err:?}")
+        }
+        if let Err(err) = spool.refresh() {
+            warn!(logger, "failed to read uplink spool {err:?}");
+        }
+        if sent > 0 {
+            info!(logger, "sent spooled uplinks"; "sent" => sent);
+        }
+    }
+
+    /// Spools an uplink that failed to send. Returns false when spooling is
+    /// disabled or failed.
+    fn spool_packet(&mut self, logger: &Logger, uplink: &PacketRouterPacketUpV1) -> bool {
+        let Some(spool) = &mut self.spool else {
+            return false;
+        };
+        match spool.push(uplink) {
+            Ok(discarded) => {
+                self.uplink_stats.record_count(
+                    logger,
+                    Disposition::Dropped(DropReason::QueueFull),
+                    discarded,
+                );
+                true
+            }
+            Err(err) => {
+                warn!(logger, "failed to spool uplink {err:?}");
+                false
             }
         }
     }
@@ -172,10 +368,30 @@ impl PacketRouter {
     }
 
     async fn send_packet(&mut self, logger: &Logger, packet: CacheMessage<Packet>) -> Result {
-        debug!(logger, "sending packet";
-            "packet_hash" => packet.hash().to_b64());
+        if let Some(suppressed) = logging::sampling::sample(Level::Debug, "send") {
+            debug!(logger, "sending packet";
+                "packet_hash" => packet.hash().to_b64(),
+                "suppressed" => suppressed);
+        }
 
-        let uplink = self.mk_uplink(packet).await?;
-        self.service.send(uplink).await
+        let payload = packet.payload().to_vec();
+        let uplink = self.mk_uplink(packet).await.inspect_err(|_| {
+            self.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::Encode),
+                Some(&payload),
+            )
+        })?;
+        // Keep a copy of the signed uplink to spool it when it fails to send
+        let spooled = self.spool.is_some().then(|| uplink.clone());
+        let result = self.service.send(uplink).await;
+        let disposition = match (&result, spooled) {
+            (Ok(()), _) => Disposition::Forwarded,
+            (Err(_), Some(uplink)) if self.spool_packet(logger, &uplink) => return result,
+            (Err(_), _) => Disposition::Dropped(DropReason::NoConduit),
+        };
+        self.uplink_stats
+            .record(logger, disposition, Some(&payload));
+        result
     }
 }
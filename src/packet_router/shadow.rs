@@ -0,0 +1,356 @@
+//! A shadow packet router for validating a new router before cutting over.
+//!
+//! The shadow router is sent a copy of every uplink the gateway hands to its
+//! router, taken from the event bus. Downlinks from the shadow router are never
+//! transmitted. They are matched against the uplinks they answer, like the
+//! downlinks of the primary router, and both are compared in metrics:
+//!
+//! * `router_shadow_uplinks_total{result}`: uplinks sent to the shadow router,
+//!   or that failed to send
+//! * `router_shadow_downlinks_total{target}`: downlinks of the primary and
+//!   shadow router
+//! * `router_shadow_response_seconds{target}`: time from receiving an uplink to
+//!   the downlink answering it
+//! * `router_shadow_answers_total{answered}`: uplinks answered by both routers,
+//!   only one of them or neither
+
+use crate::{
+    event_bus::{Event, EventBus},
+    metrics, region_watcher,
+    server::Handles,
+    service::packet_router::{PacketRouterService, Received},
+    Keypair, MsgSign, Packet, RegionParams, Result, Settings,
+};
+use helium_proto::services::router::{PacketRouterPacketDownV1, PacketRouterPacketUpV1};
+use http::Uri;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::broadcast::error::RecvError, time};
+
+/// Gauge which is 1 while the shadow router conduit is connected
+pub const CONNECTED_METRIC: &str = "router_shadow_connected";
+pub const UPLINKS_METRIC: &str = "router_shadow_uplinks_total";
+pub const DOWNLINKS_METRIC: &str = "router_shadow_downlinks_total";
+pub const RESPONSE_METRIC: &str = "router_shadow_response_seconds";
+pub const ANSWERS_METRIC: &str = "router_shadow_answers_total";
+
+/// Wait before sending to the shadow router again after a failed send
+const RECONNECT_WAIT: Duration = Duration::from_secs(30);
+/// The receive delays, in seconds, a downlink can follow its uplink with
+const RX_DELAYS: std::ops::RangeInclusive<u32> = 1..=15;
+/// How long uplinks are kept to match downlinks against
+const UPLINK_AGE: Duration = Duration::from_secs(16);
+/// The maximum number of uplinks kept to match downlinks against
+const MAX_UPLINKS: usize = 256;
+/// The interval answered uplinks are tallied at
+const TALLY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Primary,
+    Shadow,
+}
+
+impl Target {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Shadow => "shadow",
+        }
+    }
+}
+
+/// Which routers answered an uplink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answered {
+    Both,
+    Primary,
+    Shadow,
+    Neither,
+}
+
+impl Answered {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::Primary => "primary",
+            Self::Shadow => "shadow",
+            Self::Neither => "neither",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Uplink {
+    tmst: u32,
+    received: Instant,
+    primary: bool,
+    shadow: bool,
+}
+
+impl Uplink {
+    fn answered(&self) -> Answered {
+        match (self.primary, self.shadow) {
+            (true, true) => Answered::Both,
+            (true, false) => Answered::Primary,
+            (false, true) => Answered::Shadow,
+            (false, false) => Answered::Neither,
+        }
+    }
+}
+
+/// Matches the downlinks of both routers to the uplinks they answer. Downlinks
+/// do not say which uplink they answer, but their rx1 timestamp is the
+/// concentrator timestamp of the uplink plus a whole number of seconds.
+#[derive(Debug, Default)]
+struct Comparison {
+    uplinks: VecDeque<Uplink>,
+}
+
+impl Comparison {
+    fn uplink(&mut self, tmst: u32, now: Instant) -> Vec<Answered> {
+        let mut answered = self.expire(now);
+        if self.uplinks.len() >= MAX_UPLINKS {
+            answered.extend(self.uplinks.pop_front().map(|uplink| uplink.answered()));
+        }
+        self.uplinks.push_back(Uplink {
+            tmst,
+            received: now,
+            primary: false,
+            shadow: false,
+        });
+        answered
+    }
+
+    /// Marks the uplink the downlink at the given rx1 timestamp answers and
+    /// returns the time since it was received
+    fn downlink(&mut self, target: Target, tmst: u32, now: Instant) -> Option<Duration> {
+        let uplink = self.uplinks.iter_mut().rev().find(|uplink| {
+            let delay = tmst.wrapping_sub(uplink.tmst);
+            delay % 1_000_000 == 0 && RX_DELAYS.contains(&(delay / 1_000_000))
+        })?;
+        match target {
+            Target::Primary => uplink.primary = true,
+            Target::Shadow => uplink.shadow = true,
+        }
+        Some(now.saturating_duration_since(uplink.received))
+    }
+
+    /// Removes the uplinks that can no longer be answered and returns which
+    /// routers answered them
+    fn expire(&mut self, now: Instant) -> Vec<Answered> {
+        let mut answered = vec![];
+        while let Some(uplink) = self
+            .uplinks
+            .front()
+            .filter(|uplink| now.saturating_duration_since(uplink.received) > UPLINK_AGE)
+        {
+            answered.push(uplink.answered());
+            self.uplinks.pop_front();
+        }
+        answered
+    }
+}
+
+pub struct ShadowRouter {
+    service: Option<PacketRouterService>,
+    keypair: Arc<Keypair>,
+    region_watch: region_watcher::MessageReceiver,
+    region_params: RegionParams,
+    comparison: Comparison,
+    /// Sends are skipped until this time after a failed send
+    paused_until: Option<Instant>,
+    events: EventBus,
+}
+
+impl ShadowRouter {
+    pub fn new(
+        settings: &Settings,
+        region_watch: region_watcher::MessageReceiver,
+        events: EventBus,
+        handles: &Handles,
+    ) -> Result<Self> {
+        let service = settings
+            .router
+            .shadow
+            .as_deref()
+            .map(str::parse::<Uri>)
+            .transpose()?
+            .map(|uri| {
+                PacketRouterService::new(uri, settings.keypair.clone(), handles)
+                    .with_connected_metric(CONNECTED_METRIC)
+            });
+        let region_params = region_watcher::current_value(&region_watch);
+        Ok(Self {
+            service,
+            keypair: settings.keypair.clone(),
+            region_watch,
+            region_params,
+            comparison: Comparison::default(),
+            paused_until: None,
+            events,
+        })
+    }
+
+    /// Whether a shadow router is configured
+    pub fn is_enabled(&self) -> bool {
+        self.service.is_some()
+    }
+
+    pub async fn run(mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let Some(mut service) = self.service.take() else {
+            return Ok(());
+        };
+        let logger = logger.new(o!(
+            "module" => "shadow_router",
+            "uri" => service.uri().to_string(),
+        ));
+        info!(logger, "starting");
+
+        let mut events = self.events.subscribe();
+        let mut tally = time::interval(TALLY_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                event = events.recv() => match event {
+                    Ok(Event::UplinkReceived(packet)) => {
+                        let answered = self.comparison.uplink(packet.timestamp as u32, Instant::now());
+                        record_answered(answered);
+                        self.handle_uplink(&logger, &mut service, packet).await;
+                    }
+                    Ok(Event::DownlinkRequested(packet)) => {
+                        self.handle_downlink(Target::Primary, packet.timestamp as u32)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => warn!(logger, "event bus closed"),
+                },
+                region_change = self.region_watch.changed() => match region_change {
+                    Ok(()) => self.region_params = region_watcher::current_value(&self.region_watch),
+                    Err(_) => warn!(logger, "region watch disconnected")
+                },
+                downlink = service.recv() => match downlink {
+                    Ok(Some(Received::Connected)) => info!(logger, "shadow router connected"),
+                    Ok(Some(Received::Downlink(message))) => self.handle_shadow_downlink(&logger, message),
+                    Ok(None) => warn!(logger, "shadow router disconnected"),
+                    Err(err) => warn!(logger, "shadow router error {:?}", err),
+                },
+                _ = tally.tick() => record_answered(self.comparison.expire(Instant::now())),
+            }
+        }
+    }
+
+    async fn handle_uplink(
+        &mut self,
+        logger: &Logger,
+        service: &mut PacketRouterService,
+        packet: Packet,
+    ) {
+        if self
+            .paused_until
+            .is_some_and(|paused_until| Instant::now() < paused_until)
+        {
+            metrics::increment(&metrics::labeled(UPLINKS_METRIC, &[("result", "failed")]));
+            return;
+        }
+        let result = match self.mk_uplink(packet).await {
+            Ok(uplink) => service.send(uplink).await,
+            Err(err) => Err(err),
+        };
+        let label = match result {
+            Ok(()) => {
+                self.paused_until = None;
+                "sent"
+            }
+            Err(err) => {
+                warn!(logger, "failed to send uplink to shadow router {err:?}";
+                    "retry_secs" => RECONNECT_WAIT.as_secs());
+                self.paused_until = Some(Instant::now() + RECONNECT_WAIT);
+                "failed"
+            }
+        };
+        metrics::increment(&metrics::labeled(UPLINKS_METRIC, &[("result", label)]));
+    }
+
+    async fn mk_uplink(&self, packet: Packet) -> Result<PacketRouterPacketUpV1> {
+        let mut uplink: PacketRouterPacketUpV1 = packet.try_into()?;
+        uplink.region = self.region_params.region.into();
+        uplink.gateway = self.keypair.public_key().into();
+        uplink.signature = uplink.sign(self.keypair.clone()).await?;
+        Ok(uplink)
+    }
+
+    /// Records a shadow router downlink, which is never transmitted
+    fn handle_shadow_downlink(&mut self, logger: &Logger, message: PacketRouterPacketDownV1) {
+        let Some(rx1) = message.rx1 else {
+            warn!(logger, "ignoring shadow downlink without rx1 window");
+            return;
+        };
+        debug!(logger, "ignoring shadow downlink"; "tmst" => rx1.timestamp);
+        self.handle_downlink(Target::Shadow, rx1.timestamp as u32);
+    }
+
+    fn handle_downlink(&mut self, target: Target, tmst: u32) {
+        let labels = [("target", target.as_str())];
+        metrics::increment(&metrics::labeled(DOWNLINKS_METRIC, &labels));
+        if let Some(response) = self.comparison.downlink(target, tmst, Instant::now()) {
+            metrics::observe(
+                &metrics::labeled(RESPONSE_METRIC, &labels),
+                response.as_secs_f64(),
+            );
+        }
+    }
+}
+
+fn record_answered(answered: Vec<Answered>) {
+    for answered in answered {
+        metrics::increment(&metrics::labeled(
+            ANSWERS_METRIC,
+            &[("answered", answered.as_str())],
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn comparison() {
+        let start = Instant::now();
+        let mut comparison = Comparison::default();
+        assert!(comparison.uplink(1_000_000, start).is_empty());
+        comparison.uplink(1_500_000, start);
+        comparison.uplink(u32::MAX - 299_999, start);
+
+        let later = start + Duration::from_millis(800);
+        assert_eq!(
+            Some(Duration::from_millis(800)),
+            comparison.downlink(Target::Primary, 2_000_000, later)
+        );
+        assert!(comparison
+            .downlink(Target::Shadow, 2_000_000, later)
+            .is_some());
+        assert!(comparison
+            .downlink(Target::Shadow, 2_500_000, later)
+            .is_some());
+        // Across a counter wrap
+        assert!(comparison
+            .downlink(Target::Primary, 700_000, later)
+            .is_some());
+        assert_eq!(None, comparison.downlink(Target::Shadow, 2_600_000, later));
+        assert_eq!(None, comparison.downlink(Target::Shadow, 17_000_000, later));
+
+        assert!(comparison.expire(later).is_empty());
+        assert_eq!(
+            vec![Answered::Both, Answered::Shadow, Answered::Primary],
+            comparison.expire(start + UPLINK_AGE + Duration::from_secs(1))
+        );
+    }
+}
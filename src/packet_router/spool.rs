@@ -0,0 +1,205 @@
+//! Disk-backed store-and-forward queue for uplinks.
+//!
+//! The in-memory queue of the packet router only covers short hiccups. When
+//! the router can not be reached for hours, like a flapping cellular
+//! backhaul, uplinks that fail to send are signed and spooled to the data
+//! directory instead of dropped, and are sent again oldest first once the
+//! router is reachable.
+//!
+//! Spooled uplinks are removed as soon as they are sent. The oldest uplinks
+//! are discarded when more than `router.spool_max_packets` are spooled, and
+//! uplinks spooled longer than `router.spool_max_age` seconds are discarded
+//! instead of sent since they are of no use to the LNS anymore.
+
+use crate::{clock::unix_time, metrics, settings::RouterSettings, Result};
+use helium_proto::{services::router::PacketRouterPacketUpV1, Message};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use xxhash_rust::xxh64::xxh64;
+
+const SPOOL_DIR: &str = "uplinks";
+const SPOOL_EXTENSION: &str = "pb";
+
+/// Gauge of the number of spooled uplinks
+pub const SPOOLED_METRIC: &str = "router_uplinks_spooled";
+/// Counter of discarded spooled uplinks labeled with the `reason`
+pub const DISCARDED_METRIC: &str = "router_uplinks_spooled_discarded_total";
+
+/// A spooled uplink
+#[derive(Debug)]
+pub struct Spooled {
+    path: PathBuf,
+    /// Time the uplink was spooled in nanoseconds since the unix epoch
+    spooled: u64,
+}
+
+impl Spooled {
+    fn from_path(path: PathBuf) -> Self {
+        let spooled = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('-').next())
+            .and_then(|spooled| spooled.parse().ok())
+            .unwrap_or(0);
+        Self { path, spooled }
+    }
+
+    pub fn load(&self) -> Result<PacketRouterPacketUpV1> {
+        let data = fs::read(&self.path)?;
+        Ok(PacketRouterPacketUpV1::decode(data.as_slice())?)
+    }
+
+    pub fn is_expired(&self, now: Duration, max_age: Duration) -> bool {
+        now.saturating_sub(Duration::from_nanos(self.spooled)) > max_age
+    }
+
+    /// Removes a sent uplink from the spool
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// Removes an uplink that will not be sent from the spool
+    pub fn discard(&self, reason: &str) {
+        discard(&self.path, reason)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+    max_packets: usize,
+    max_age: Duration,
+    /// The number of spooled uplinks, kept up to date by pushes and refreshes
+    spooled: usize,
+}
+
+impl Spool {
+    /// Returns the spool when spooling is enabled
+    pub fn new(settings: &RouterSettings, data_dir: &Path) -> Option<Self> {
+        settings.spool.then(|| Self {
+            dir: data_dir.join(SPOOL_DIR),
+            max_packets: settings.spool_max_packets,
+            max_age: Duration::from_secs(settings.spool_max_age),
+            spooled: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spooled == 0
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Spools a signed uplink, discarding the oldest spooled uplinks when
+    /// there are more than the maximum number of uplinks. Returns the number
+    /// of discarded uplinks.
+    pub fn push(&mut self, uplink: &PacketRouterPacketUpV1) -> Result<usize> {
+        self.push_at(uplink, unix_time())
+    }
+
+    fn push_at(&mut self, uplink: &PacketRouterPacketUpV1, now: Duration) -> Result<usize> {
+        fs::create_dir_all(&self.dir)?;
+        // File names sort by spool time
+        let file_name = format!(
+            "{:020}-{:016x}.{SPOOL_EXTENSION}",
+            now.as_nanos(),
+            xxh64(&uplink.signature, 0)
+        );
+        fs::write(self.dir.join(file_name), uplink.encode_to_vec())?;
+        // The spool is only read when it may have overflowed, pushes are
+        // frequent while the router is unreachable
+        self.spooled += 1;
+        if self.spooled <= self.max_packets {
+            metrics::set(SPOOLED_METRIC, self.spooled as f64);
+            return Ok(0);
+        }
+        let pending = self.pending()?;
+        let overflow = pending.len().saturating_sub(self.max_packets);
+        for spooled in &pending[..overflow] {
+            spooled.discard("overflow");
+        }
+        self.spooled = pending.len() - overflow;
+        metrics::set(SPOOLED_METRIC, self.spooled as f64);
+        Ok(overflow)
+    }
+
+    /// The spooled uplinks, oldest first
+    pub fn pending(&self) -> Result<Vec<Spooled>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == SPOOL_EXTENSION))
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths.into_iter().map(Spooled::from_path).collect())
+    }
+
+    /// Counts the spooled uplinks, returns the number of spooled uplinks
+    pub fn refresh(&mut self) -> Result<usize> {
+        self.spooled = self.pending()?.len();
+        metrics::set(SPOOLED_METRIC, self.spooled as f64);
+        Ok(self.spooled)
+    }
+}
+
+fn discard(path: &Path, reason: &str) {
+    let _ = fs::remove_file(path);
+    metrics::increment(&metrics::labeled(DISCARDED_METRIC, &[("reason", reason)]));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn uplink(signature: u8) -> PacketRouterPacketUpV1 {
+        PacketRouterPacketUpV1 {
+            payload: vec![1, 2, 3],
+            signature: vec![signature],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn spool() {
+        let dir = std::env::temp_dir().join(format!("uplink_spool_{}", std::process::id()));
+        let mut spool = Spool {
+            dir: dir.clone(),
+            max_packets: 2,
+            max_age: Duration::from_secs(3600),
+            spooled: 0,
+        };
+        assert!(spool.pending().expect("pending").is_empty());
+
+        let now = Duration::from_secs(1_700_000_000);
+        for (secs, signature) in [(1, 1), (2, 2), (3, 3)] {
+            spool
+                .push_at(&uplink(signature), now + Duration::from_secs(secs))
+                .expect("push");
+        }
+        let pending = spool.pending().expect("pending");
+        assert_eq!(2, pending.len());
+        // The oldest uplink was discarded
+        assert_eq!(uplink(2), pending[0].load().expect("uplink"));
+        assert_eq!(uplink(3), pending[1].load().expect("uplink"));
+
+        let max_age = spool.max_age();
+        assert!(!pending[0].is_expired(now + max_age, max_age));
+        assert!(pending[0].is_expired(now + max_age + Duration::from_secs(3), max_age));
+
+        assert!(!spool.is_empty());
+        pending[0].remove();
+        pending[1].discard("expired");
+        assert_eq!(0, spool.refresh().expect("spooled"));
+        assert!(spool.is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,165 @@
+//! Internal queue sizes.
+//!
+//! High traffic gateways need bigger queues between the packet forwarder,
+//! the router and the router conduit, tiny devices smaller ones. The sizes
+//! come from the `[queues]` settings and are checked against `BOUNDS`. The
+//! conduit capacity can also be changed at runtime through the local API
+//! (`POST /v1/queues`) and applies from the next router connection, as does
+//! the conduit policy which decides whether a full conduit blocks the router
+//! or drops its oldest uplink. The message queues between the gateway tasks
+//! and the event bus are created at startup and keep their startup size.
+
+use crate::{settings::QueueSettings, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+/// The allowed range of every queue size
+pub const BOUNDS: RangeInclusive<usize> = 1..=4096;
+
+/// What a send to a full router conduit does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConduitPolicy {
+    /// Wait for the router to take an uplink, holding up the router task
+    #[default]
+    Block,
+    /// Drop the oldest queued uplink to make room. Register messages are
+    /// never dropped. The conduit capacity is rounded up to a power of two
+    /// with this policy.
+    DropOldest,
+}
+
+/// The effective queue sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QueueSizes {
+    /// Uplinks queued on the router conduit stream
+    pub conduit: usize,
+    /// Messages queued for the router task
+    pub router: usize,
+    /// Messages queued for the gateway task
+    pub gateway: usize,
+    /// Messages queued for the beaconer task
+    pub beacon: usize,
+    /// Events a slow event bus subscriber can fall behind
+    pub event_bus: usize,
+    /// What a send to a full conduit does
+    pub conduit_policy: ConduitPolicy,
+}
+
+impl From<&QueueSettings> for QueueSizes {
+    fn from(settings: &QueueSettings) -> Self {
+        Self {
+            conduit: settings.conduit,
+            router: settings.router,
+            gateway: settings.gateway,
+            beacon: settings.beacon,
+            event_bus: settings.event_bus,
+            conduit_policy: settings.conduit_policy,
+        }
+    }
+}
+
+impl QueueSizes {
+    fn validate(&self) -> Result {
+        for (name, size) in [
+            ("conduit", self.conduit),
+            ("router", self.router),
+            ("gateway", self.gateway),
+            ("beacon", self.beacon),
+            ("event_bus", self.event_bus),
+        ] {
+            if !BOUNDS.contains(&size) {
+                return Err(Error::custom(format!(
+                    "queues.{name} of {size} is not in {}..={}",
+                    BOUNDS.start(),
+                    BOUNDS.end()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A runtime change of the queue sizes, only the conduit capacity and policy
+/// can be changed
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueUpdate {
+    pub conduit: Option<usize>,
+    pub conduit_policy: Option<ConduitPolicy>,
+}
+
+/// The effective queue sizes, shared with the router conduit and the local
+/// API
+#[derive(Debug, Clone)]
+pub struct QueuesHandle(Arc<Mutex<QueueSizes>>);
+
+impl QueuesHandle {
+    /// Checks and applies the queue settings
+    pub fn new(settings: &QueueSettings) -> Result<Self> {
+        let sizes = QueueSizes::from(settings);
+        sizes.validate()?;
+        Ok(Self(Arc::new(Mutex::new(sizes))))
+    }
+
+    /// Checks and applies a runtime change, returns the new sizes
+    pub fn update(&self, update: &QueueUpdate) -> Result<QueueSizes> {
+        let mut sizes = self.0.lock().expect("queues lock");
+        let mut updated = *sizes;
+        if let Some(conduit) = update.conduit {
+            updated.conduit = conduit;
+        }
+        if let Some(conduit_policy) = update.conduit_policy {
+            updated.conduit_policy = conduit_policy;
+        }
+        updated.validate()?;
+        *sizes = updated;
+        Ok(updated)
+    }
+
+    /// Returns the current queue sizes
+    pub fn snapshot(&self) -> QueueSizes {
+        *self.0.lock().expect("queues lock")
+    }
+}
+
+impl Default for QueuesHandle {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(QueueSizes::from(
+            &QueueSettings::default(),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let settings = QueueSettings::default();
+        assert!(QueueSizes::from(&settings).validate().is_ok());
+        let settings = QueueSettings {
+            router: 0,
+            ..Default::default()
+        };
+        assert!(QueueSizes::from(&settings).validate().is_err());
+        let settings = QueueSettings {
+            conduit: *BOUNDS.end() + 1,
+            ..Default::default()
+        };
+        assert!(QueueSizes::from(&settings).validate().is_err());
+    }
+
+    #[test]
+    fn conduit_policy() {
+        let update: QueueUpdate =
+            serde_json::from_str(r#"{"conduit_policy": "drop_oldest"}"#).expect("update");
+        assert_eq!(Some(ConduitPolicy::DropOldest), update.conduit_policy);
+        assert_eq!(None, update.conduit);
+        assert!(serde_json::from_str::<QueueUpdate>(r#"{"conduit_policy": "oldest"}"#).is_err());
+    }
+}
@@ -1,13 +1,28 @@
-use crate::{settings::Settings, KeyedUri, Keypair, Region, RegionParams, Result};
+use crate::{
+    error::RegionError,
+    event_bus::{Event, EventBus},
+    metrics,
+    server::Handles,
+    service::region_http::HttpRegionSource,
+    settings::{RegionSource, Settings},
+    Error, KeyedUri, Keypair, Region, RegionParams, Result,
+};
 use exponential_backoff::Backoff;
 use slog::{info, o, warn, Logger};
 use std::{sync::Arc, time::Duration};
-use tokio::{sync::watch, time};
+use tokio::{
+    sync::{watch, Notify},
+    time::{self, Instant},
+};
 
 const REGION_BACKOFF_RETRIES: u32 = 10;
 const REGION_BACKOFF_MIN_WAIT: Duration = Duration::from_secs(5);
 const REGION_BACKOFF_MAX_WAIT: Duration = Duration::from_secs(3600); // 60 minutes
 
+/// Counter of fetched region params changes that were superseded or reverted
+/// within the debounce window and never applied
+pub const COALESCED_METRIC: &str = "region_params_coalesced_total";
+
 pub type MessageSender = watch::Sender<RegionParams>;
 pub type MessageReceiver = watch::Receiver<RegionParams>;
 
@@ -18,44 +33,170 @@ where
     receiver.borrow().clone()
 }
 
+/// Requests an immediate region params fetch from a running region watcher
+#[derive(Clone, Debug)]
+pub struct RegionRefresh(Arc<Notify>);
+
+impl RegionRefresh {
+    pub fn refresh(&self) {
+        self.0.notify_one()
+    }
+}
+
 pub struct RegionWatcher {
     keypair: Arc<Keypair>,
     #[cfg(not(feature = "validator"))]
     config_uri: KeyedUri,
+    #[cfg(not(feature = "validator"))]
+    nonces: crate::service::config::RequestNonces,
     default_region: Region,
     request_retry: u32,
     watch: MessageSender,
     #[cfg(feature = "validator")]
     seed_gateways: Vec<KeyedUri>,
+    /// Fetches region params from a JSON document instead of the config
+    /// service when configured
+    http_source: Option<HttpRegionSource>,
+    /// The region params of a custom region, which are used as they are
+    /// instead of fetched
+    custom: Option<RegionParams>,
+    /// The time fetched changes are held back before they are applied
+    debounce: Duration,
+    refresh: RegionRefresh,
+    events: EventBus,
+    handles: Handles,
+}
+
+/// A fetched region params change held back until the debounce window ends
+#[derive(Debug)]
+struct PendingChange {
+    params: RegionParams,
+    apply_at: Instant,
+}
+
+impl PendingChange {
+    /// Holds back fetched region params. A change during the window of an
+    /// earlier change replaces it without extending the window, and a change
+    /// back to the current params drops it. The first params, when there are
+    /// none yet, are applied right away.
+    fn coalesce(
+        pending: Option<Self>,
+        current: &RegionParams,
+        params: RegionParams,
+        debounce: Duration,
+        now: Instant,
+    ) -> Option<Self> {
+        if pending
+            .as_ref()
+            .is_some_and(|pending| pending.params != params)
+        {
+            metrics::increment(COALESCED_METRIC);
+        }
+        if &params == current {
+            return None;
+        }
+        let apply_at = match pending {
+            Some(pending) => pending.apply_at,
+            None if current.params.is_empty() => now,
+            None => now + debounce,
+        };
+        Some(Self { params, apply_at })
+    }
 }
 
 impl RegionWatcher {
-    pub fn new(settings: &Settings) -> Self {
-        let default_params = RegionParams::from(settings.region);
+    pub fn new(settings: &Settings, events: EventBus, handles: &Handles) -> Result<Self> {
+        let custom = match settings.region_params.source {
+            RegionSource::Custom => {
+                let params = settings
+                    .region_params
+                    .custom
+                    .clone()
+                    .ok_or_else(|| {
+                        Error::custom("region_params.custom is required for the custom source")
+                    })?
+                    .region_params()?;
+                validate(&params)?;
+                Some(params)
+            }
+            _ => None,
+        };
+        let default_params = custom
+            .clone()
+            .unwrap_or_else(|| RegionParams::from(settings.region));
         let (watch, _) = watch::channel(default_params);
-        Self {
+        let http_source = match settings.region_params.source {
+            RegionSource::Config | RegionSource::Custom => None,
+            RegionSource::Http => {
+                let url = settings.region_params.url.as_deref().ok_or_else(|| {
+                    Error::custom("region_params.url is required for the http source")
+                })?;
+                Some(HttpRegionSource::new(
+                    url,
+                    settings.region_params.pubkey.clone(),
+                    handles,
+                )?)
+            }
+        };
+        Ok(Self {
             keypair: settings.keypair.clone(),
             #[cfg(not(feature = "validator"))]
             config_uri: settings.config.clone(),
+            #[cfg(not(feature = "validator"))]
+            nonces: crate::service::config::RequestNonces::load(&settings.data_dir),
             // Start retry at 1 to get some jitter in the first request time
             request_retry: 1,
             default_region: settings.region,
             watch,
             #[cfg(feature = "validator")]
             seed_gateways: settings.gateways.clone(),
-        }
+            http_source,
+            custom,
+            debounce: Duration::from_secs(settings.region_params.debounce),
+            refresh: RegionRefresh(Arc::new(Notify::new())),
+            events,
+            handles: handles.clone(),
+        })
     }
 
     pub fn watcher(&mut self) -> watch::Receiver<RegionParams> {
         self.watch.subscribe()
     }
 
+    pub fn refresher(&self) -> RegionRefresh {
+        self.refresh.clone()
+    }
+
+    /// Starts out with the given region params, like the ones handed over by
+    /// the process this one took over from, until params are fetched. A
+    /// custom region is kept.
+    pub fn seed(&mut self, region_params: RegionParams) {
+        if self.custom.is_none() {
+            self.watch.send_replace(region_params);
+        }
+    }
+
     pub async fn run(&mut self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
         let logger = logger.new(o!(
             "module" => "region_watcher",
         ));
+        if let Some(custom) = &self.custom {
+            // Custom region params never change, refreshes are ignored
+            info!(logger, "using custom region";
+                "region" => custom.to_string(),
+                "channels" => custom.params.len(),
+            );
+            shutdown.clone().await;
+            info!(logger, "shutting down");
+            return Ok(());
+        }
+        let source = self
+            .http_source
+            .as_ref()
+            .map_or("config".to_string(), |source| source.uri.to_string());
         info!(logger, "starting";
             "default_region" => self.default_region.to_string(),
+            "source" => source,
         );
 
         let backoff = Backoff::new(
@@ -64,32 +205,122 @@ impl RegionWatcher {
             REGION_BACKOFF_MAX_WAIT,
         );
 
+        // Deadline based so applying a held back change does not delay the
+        // next fetch
+        let mut fetch_at = Instant::now();
+        let mut pending: Option<PendingChange> = None;
+
         loop {
-            let sleep = backoff
-                .next(self.request_retry)
-                .unwrap_or(REGION_BACKOFF_MAX_WAIT);
+            if fetch_at <= Instant::now() {
+                fetch_at = Instant::now()
+                    + backoff
+                        .next(self.request_retry)
+                        .unwrap_or(REGION_BACKOFF_MAX_WAIT);
+            }
+            let apply_at = pending.as_ref().map(|pending| pending.apply_at);
 
             tokio::select! {
                 _ = shutdown.clone() => {
                     info!(logger, "shutting down");
                     return Ok(())
                 },
-                _ = time::sleep(sleep) => match self.check_region(shutdown, &logger).await {
-                    // A successful fetch will set request_retry to RETRIES + 1
-                    // which means a first error can reset it back to 1 to start
-                    // backing of up to RETRIES
-                    Err(_) => self.request_retry = if self.request_retry > REGION_BACKOFF_RETRIES {
+                _ = time::sleep_until(apply_at.unwrap_or_else(Instant::now)), if apply_at.is_some() => {
+                    if let Some(pending) = pending.take() {
+                        self.apply(&logger, pending.params);
+                    }
+                    continue;
+                },
+                _ = time::sleep_until(fetch_at) => (),
+                _ = self.refresh.0.notified() => info!(logger, "region refresh requested"),
+            }
+            fetch_at = Instant::now();
+
+            match self.fetch_valid_region(shutdown, &logger).await {
+                // A successful fetch will set request_retry to RETRIES + 1
+                // which means a first error can reset it back to 1 to start
+                // backing of up to RETRIES
+                Err(_) => {
+                    self.request_retry = if self.request_retry > REGION_BACKOFF_RETRIES {
                         1
                     } else {
                         (self.request_retry + 1).min(REGION_BACKOFF_RETRIES)
-                    },
-                    Ok(None) => (),
-                    Ok(Some(remote_params)) => {
-                        self.request_retry = REGION_BACKOFF_RETRIES + 1;
-                        if remote_params != *self.watch.borrow() {
-                            _ = self.watch.send_replace(remote_params);
-                        };
-                    },
+                    }
+                }
+                Ok(None) => (),
+                Ok(Some(remote_params)) => {
+                    self.request_retry = REGION_BACKOFF_RETRIES + 1;
+                    let current = self.watch.borrow().clone();
+                    pending = PendingChange::coalesce(
+                        pending,
+                        &current,
+                        remote_params,
+                        self.debounce,
+                        Instant::now(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, logger: &Logger, params: RegionParams) {
+        let current = self.watch.borrow().clone();
+        if params == current {
+            return;
+        }
+        let diff = self.handles.region_history.record(&current, &params);
+        info!(logger, "region params changed: {diff}";
+            "region" => params.to_string());
+        self.events.publish(Event::RegionDiff(diff));
+        self.events.publish(Event::RegionChanged(params.clone()));
+        _ = self.watch.send_replace(params);
+    }
+
+    /// Fetches region params and checks that they are usable. Invalid params
+    /// are treated like a failed fetch so the last known good params stay in
+    /// use.
+    async fn fetch_valid_region(
+        &mut self,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<RegionParams>> {
+        let params = self.fetch_region(shutdown, logger).await?;
+        if let Some(params) = &params {
+            validate(params).inspect_err(|err| {
+                warn!(logger, "ignoring invalid region params: {err}";
+                    "code" => err.code(),
+                    "region" => params.region.to_string(),
+                    "kept_region" => self.watch.borrow().region.to_string(),
+                )
+            })?;
+        }
+        Ok(params)
+    }
+
+    async fn fetch_region(
+        &mut self,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<RegionParams>> {
+        let source = match self.http_source.clone() {
+            Some(source) => source,
+            None => return self.check_region(shutdown, logger).await,
+        };
+        tokio::select! {
+            _ = shutdown.clone() => Ok(None),
+            response = source.region_params() => match response {
+                Err(err) => {
+                    warn!(logger, "http region_params error: {err:?}";
+                        "code" => err.code(),
+                        "uri" => source.uri.to_string(),
+                    );
+                    Err(err)
+                }
+                Ok(params) => {
+                    info!(logger, "http region_params fetched";
+                        "uri" => source.uri.to_string(),
+                        "region" => params.region.to_string(),
+                    );
+                    Ok(Some(params))
                 }
             }
         }
@@ -101,15 +332,17 @@ impl RegionWatcher {
         shutdown: &triggered::Listener,
         logger: &Logger,
     ) -> Result<Option<RegionParams>> {
-        let mut service = crate::service::config::ConfigService::new(&self.config_uri);
+        let mut service =
+            crate::service::config::ConfigService::new(&self.config_uri, &self.handles);
         let current_region = self.watch.borrow().region;
         let service_uri = service.uri.clone();
 
         tokio::select! {
             _ = shutdown.clone() => Ok(None),
-            response = service.region_params(current_region, self.keypair.clone()) => match response.map(Some) {
+            response = service.region_params(current_region, self.keypair.clone(), &mut self.nonces) => match response.map(Some) {
                 Err(err) => {
                     warn!(logger, "config region_params error: {err:?}";
+                        "code" => err.code(),
                         "pubkey" => service_uri.pubkey.to_string(),
                         "uri" => service_uri.uri.to_string(),
                         "region" => current_region.to_string(),
@@ -134,7 +367,6 @@ impl RegionWatcher {
         shutdown: &triggered::Listener,
         logger: &Logger,
     ) -> Result<Option<RegionParams>> {
-        use crate::Error;
         use futures::TryFutureExt;
 
         let current_region = self.watch.borrow().region;
@@ -160,6 +392,7 @@ impl RegionWatcher {
                 match response.map(Some) {
                     Err(err) => {
                         warn!(logger, "gateway region_params error: {err:?}";
+                            "code" => err.code(),
                             "pubkey" => service_uri.pubkey.to_string(),
                             "uri" => service_uri.uri.to_string(),
                             "region" => current_region.to_string()
@@ -177,3 +410,145 @@ impl RegionWatcher {
         }
     }
 }
+
+/// Checks that region params can be used to construct beacons and select
+/// transmit power: at least one channel, every channel with a frequency,
+/// bandwidth and spreading, and a beacon datarate and conducted power that can
+/// be derived from them.
+pub fn validate(params: &RegionParams) -> Result {
+    if params.params.is_empty() {
+        return Err(RegionError::no_region_params());
+    }
+    for channel in &params.params {
+        if channel.channel_frequency == 0 {
+            return Err(RegionError::invalid_region_params("zero channel frequency"));
+        }
+        if channel.bandwidth == 0 {
+            return Err(RegionError::invalid_region_params(format!(
+                "zero bandwidth for channel {}",
+                channel.channel_frequency
+            )));
+        }
+        if channel
+            .spreading
+            .as_ref()
+            .map_or(true, |spreading| spreading.tagged_spreading.is_empty())
+        {
+            return Err(RegionError::invalid_region_params(format!(
+                "no spreading for channel {}",
+                channel.channel_frequency
+            )));
+        }
+    }
+    beacon::Beacon::payload_size(&params, beacon::BEACON_VERSION)
+        .and_then(|size| params.select_datarate(size))
+        .map_err(RegionError::invalid_region_params)?;
+    params
+        .max_conducted_power()
+        .map_err(RegionError::invalid_region_params)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_proto::{
+        BlockchainRegionParamV1, BlockchainRegionSpreadingV1, RegionSpreading, TaggedSpreading,
+    };
+
+    #[test]
+    fn coalesce() {
+        let channel = |channel_frequency| BlockchainRegionParamV1 {
+            channel_frequency,
+            bandwidth: 125_000,
+            max_eirp: 160,
+            spreading: None,
+        };
+        let params = |channels: &[u64]| RegionParams {
+            params: channels.iter().copied().map(channel).collect(),
+            ..RegionParams::from(Region::from(helium_proto::Region::Eu868))
+        };
+        let debounce = Duration::from_secs(30);
+        let now = Instant::now();
+
+        // The first params are applied right away
+        let none = params(&[]);
+        let pending = PendingChange::coalesce(None, &none, params(&[868_100_000]), debounce, now)
+            .expect("pending");
+        assert_eq!(now, pending.apply_at);
+
+        let current = params(&[868_100_000]);
+        let pending =
+            PendingChange::coalesce(None, &current, params(&[868_300_000]), debounce, now)
+                .expect("pending");
+        assert_eq!(now + debounce, pending.apply_at);
+        // A later change replaces the pending one within the same window
+        let later = now + Duration::from_secs(10);
+        let pending = PendingChange::coalesce(
+            Some(pending),
+            &current,
+            params(&[868_500_000]),
+            debounce,
+            later,
+        )
+        .expect("pending");
+        assert_eq!(now + debounce, pending.apply_at);
+        assert_eq!(params(&[868_500_000]), pending.params);
+        // A change back to the current params is dropped
+        assert!(
+            PendingChange::coalesce(Some(pending), &current, current.clone(), debounce, later)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn validate_params() {
+        let channel = BlockchainRegionParamV1 {
+            channel_frequency: 868_100_000,
+            bandwidth: 125_000,
+            max_eirp: 160,
+            spreading: Some(BlockchainRegionSpreadingV1 {
+                tagged_spreading: vec![TaggedSpreading {
+                    region_spreading: RegionSpreading::Sf9.into(),
+                    max_packet_size: 115,
+                }],
+            }),
+        };
+        let region = Region::from(helium_proto::Region::Eu868);
+        let params = |params: Vec<BlockchainRegionParamV1>| RegionParams {
+            params,
+            ..RegionParams::from(region)
+        };
+        assert!(validate(&params(vec![channel.clone()])).is_ok());
+        assert!(validate(&params(vec![])).is_err());
+        assert!(validate(&params(vec![
+            channel.clone(),
+            BlockchainRegionParamV1 {
+                spreading: None,
+                ..channel.clone()
+            }
+        ]))
+        .is_err());
+        assert!(validate(&params(vec![BlockchainRegionParamV1 {
+            channel_frequency: 0,
+            ..channel.clone()
+        }]))
+        .is_err());
+        let dr0 = |max_packet_size| BlockchainRegionParamV1 {
+            spreading: Some(BlockchainRegionSpreadingV1 {
+                tagged_spreading: vec![TaggedSpreading {
+                    region_spreading: RegionSpreading::Sf12.into(),
+                    max_packet_size,
+                }],
+            }),
+            ..channel.clone()
+        };
+        // Too small for version 0 and 1 beacons but version 2 beacons fit
+        assert!(validate(&params(vec![dr0(25)])).is_ok());
+        // No spreading for a beacon sized packet
+        assert!(validate(&params(vec![dr0(
+            beacon::MIN_BEACON_PAYLOAD_SIZE as u32 - 1
+        )]))
+        .is_err());
+    }
+}
@@ -1,15 +1,27 @@
 use crate::{
     error::Error,
-    gateway,
+    event_bus::{Event, EventBus, RouterBlacklist},
+    gateway::{
+        self,
+        uplink_stats::{Disposition, DropReason, UplinkStatsHandle},
+    },
+    logging,
     message_cache::{CacheMessage, MessageCache},
     region_watcher,
-    router::StateChannelMessage,
-    service::router::RouterService,
+    router::{health::RouterHealth, StateChannelMessage},
+    server::Handles,
+    service::{
+        quarantine::{self, QuarantineHandle},
+        router::RouterService,
+    },
     Base64, KeyedUri, Keypair, Packet, RegionParams, Result,
 };
 use futures::TryFutureExt;
-use slog::{debug, info, o, warn, Logger};
-use std::{sync::Arc, time::Instant};
+use slog::{debug, info, o, warn, Level, Logger};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use tokio::{sync::mpsc, time::Duration};
 
 pub const STORE_GC_INTERVAL: Duration = Duration::from_secs(60);
@@ -51,9 +63,14 @@ pub struct RouterClient {
     keypair: Arc<Keypair>,
     downlinks: gateway::MessageSender,
     store: MessageCache<Packet>,
+    health: Arc<Mutex<RouterHealth>>,
+    events: EventBus,
+    uplink_stats: UplinkStatsHandle,
+    quarantine: QuarantineHandle,
 }
 
 impl RouterClient {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         oui: u32,
         region_watch: region_watcher::MessageReceiver,
@@ -61,8 +78,11 @@ impl RouterClient {
         downlinks: gateway::MessageSender,
         keypair: Arc<Keypair>,
         max_packets: u16,
+        health: Arc<Mutex<RouterHealth>>,
+        events: EventBus,
+        handles: &Handles,
     ) -> Result<Self> {
-        let router = RouterService::new(uri)?;
+        let router = RouterService::new(uri, handles)?;
         let store = MessageCache::new(max_packets);
         let region_params = region_watcher::current_value(&region_watch);
         Ok(Self {
@@ -73,6 +93,10 @@ impl RouterClient {
             keypair,
             downlinks,
             store,
+            health,
+            events,
+            uplink_stats: handles.uplink_stats.clone(),
+            quarantine: handles.quarantine.clone(),
         })
     }
 
@@ -126,18 +150,37 @@ impl RouterClient {
         uplink: Packet,
         received: Instant,
     ) -> Result {
-        self.store.push_back(uplink, received);
+        if let Some(discarded) = self.store.push_back(uplink, received) {
+            self.uplink_stats.record(
+                logger,
+                Disposition::Dropped(DropReason::QueueFull),
+                Some(discarded.payload()),
+            );
+        }
         self.send_waiting_packets(logger).await
     }
 
-    async fn handle_downlink(&mut self, _logger: &Logger, packet: Packet) {
-        self.downlinks.downlink(packet).await;
+    async fn handle_downlink(&mut self, logger: &Logger, packet: Packet) {
+        let mut packet = packet.to_packet();
+        let findings = quarantine::check_packet(&mut packet);
+        if self
+            .quarantine
+            .quarantine(logger, "router", &packet, findings)
+        {
+            return;
+        }
+        self.downlinks.downlink(Packet::from(packet)).await;
     }
 
     async fn send_waiting_packets(&mut self, logger: &Logger) -> Result {
         while let (removed, Some(packet)) = self.store.pop_front(STORE_GC_INTERVAL) {
             if removed > 0 {
                 info!(logger, "discarded {} queued packets", removed);
+                self.uplink_stats.record_count(
+                    logger,
+                    Disposition::Dropped(DropReason::Age),
+                    removed,
+                );
             }
             if let Some(message) = self.send_packet(logger, packet).await? {
                 match message.to_downlink() {
@@ -155,10 +198,14 @@ impl RouterClient {
         logger: &Logger,
         packet: CacheMessage<Packet>,
     ) -> Result<Option<StateChannelMessage>> {
-        debug!(logger, "sending packet";
-            "packet_hash" => packet.hash().to_b64());
+        if let Some(suppressed) = logging::sampling::sample(Level::Debug, "send") {
+            debug!(logger, "sending packet";
+                "packet_hash" => packet.hash().to_b64(),
+                "suppressed" => suppressed);
+        }
         let hold_time = packet.hold_time().as_millis() as u64;
-        StateChannelMessage::packet(
+        let payload = packet.payload().to_vec();
+        let result = StateChannelMessage::packet(
             packet.into_inner(),
             self.keypair.clone(),
             self.region_params.region,
@@ -166,6 +213,31 @@ impl RouterClient {
         )
         .and_then(|message| self.router.route(message.to_message()))
         .map_ok(StateChannelMessage::from_message)
-        .await
+        .await;
+        let disposition = match &result {
+            Ok(_) => Disposition::Forwarded,
+            Err(_) => Disposition::Dropped(DropReason::NoConduit),
+        };
+        self.uplink_stats
+            .record(logger, disposition, Some(&payload));
+        self.record_health(logger, result.is_ok());
+        result
+    }
+
+    fn record_health(&self, logger: &Logger, ok: bool) {
+        let uri = self.router.uri.uri.to_string();
+        let mut health = self.health.lock().expect("router health lock");
+        if let Some(error_rate) = health.record(&uri, ok, Instant::now()) {
+            let cooldown = health.cooldown().as_secs();
+            warn!(logger, "router exceeded error budget, blacklisting";
+                "error_rate" => error_rate,
+                "cooldown" => cooldown);
+            self.events
+                .publish(Event::RouterBlacklisted(RouterBlacklist {
+                    uri,
+                    error_rate,
+                    cooldown,
+                }));
+        }
     }
 }
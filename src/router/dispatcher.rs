@@ -1,7 +1,17 @@
 use crate::{
-    gateway, packet_router, region_watcher,
-    router::{self, RouterClient, Routing},
-    service::{self, gateway::GatewayService},
+    event_bus::{Event, EventBus},
+    gateway::{
+        self,
+        uplink_stats::{Disposition, DropReason},
+    },
+    metrics, packet_router, region_watcher,
+    router::{
+        self,
+        health::{Availability, RouterHealth},
+        RouterClient, Routing,
+    },
+    server::Handles,
+    service::{self, gateway::GatewayService, packet_router::CONNECTED_METRIC},
     Error, KeyedUri, Keypair, Packet, RegionParams, Result, Settings,
 };
 use exponential_backoff::Backoff;
@@ -14,7 +24,7 @@ use slog_scope;
 use std::{
     collections::HashMap,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 use tokio::{task::JoinHandle, time};
@@ -36,6 +46,9 @@ pub struct Dispatcher {
     gateway_retry: u32,
     routers: HashMap<RouterKey, RouterEntry>,
     default_routers: Option<Vec<KeyedUri>>,
+    health: Arc<Mutex<RouterHealth>>,
+    events: EventBus,
+    handles: Handles,
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -68,12 +81,15 @@ impl Dispatcher {
         messages: MessageReceiver,
         region_watch: region_watcher::MessageReceiver,
         transmit: gateway::MessageSender,
+        events: EventBus,
+        handles: &Handles,
     ) -> Self {
         let seed_gateways = settings.gateways.clone();
         let routers = HashMap::with_capacity(5);
         let default_routers = settings.routers.clone();
         let max_packets = settings.router.queue;
         let region_params = region_watcher::current_value(&region_watch);
+        let health = Arc::new(Mutex::new(RouterHealth::new(&settings.router_health)));
         Self {
             keypair: settings.keypair.clone(),
             messages,
@@ -86,6 +102,9 @@ impl Dispatcher {
             default_routers,
             max_packets,
             gateway_retry: 0,
+            health,
+            events,
+            handles: handles.clone(),
         }
     }
 
@@ -132,8 +151,11 @@ impl Dispatcher {
                     .and_then(|service | Self::setup_routing_stream(service, self.routing_height, &logger))
                      => match gateway {
                         Ok(Some((service, gateway_streams))) => {
-                            self.run_with_gateway(service, gateway_streams,  shutdown.clone(), &logger)
-                                .await?;
+                            metrics::set(CONNECTED_METRIC, 1.0);
+                            let result = self.run_with_gateway(service, gateway_streams,  shutdown.clone(), &logger)
+                                .await;
+                            metrics::set(CONNECTED_METRIC, 0.0);
+                            result?;
                             },
                         Ok(None) =>
                             return Ok(()),
@@ -228,6 +250,8 @@ impl Dispatcher {
                 message = self.messages.recv() => match message {
                     Some(Message::Uplink{packet, received}) =>
                         self.handle_uplink(logger, packet, received).await,
+                    Some(Message::Reconnect) =>
+                        info!(logger, "ignoring reconnect request"),
                     None => warn!(logger, "ignoring closed message channel"),
                 },
             }
@@ -281,6 +305,8 @@ impl Dispatcher {
                 message = self.messages.recv() => match message {
                     Some(Message::Uplink{packet, received}) =>
                         self.handle_uplink(logger, packet, received).await,
+                    Some(Message::Reconnect) =>
+                        info!(logger, "ignoring reconnect request"),
                     None => warn!(logger, "ignoring closed message channel"),
                 },
         }
@@ -288,8 +314,15 @@ impl Dispatcher {
 
     async fn handle_uplink(&self, logger: &Logger, packet: Packet, received: Instant) {
         let mut handled = false;
-        for router_entry in self.routers.values() {
+        // Whether a router matched but was skipped for exceeding its error
+        // budget. The packet is then not sent to the default routers.
+        let mut blacklisted = false;
+        for (router_key, router_entry) in &self.routers {
             if router_entry.routing.matches_routing_info(packet.routing()) {
+                if !self.is_available(logger, router_key) {
+                    blacklisted = true;
+                    continue;
+                }
                 match router_entry.dispatch.uplink(packet.clone(), received).await {
                     Ok(()) => (),
                     Err(err) => warn!(logger, "ignoring router dispatch error: {err:?}"),
@@ -297,16 +330,53 @@ impl Dispatcher {
                 handled = true;
             }
         }
-        if !handled {
+        if !handled && !blacklisted {
             if let Some(default_routers) = &self.default_routers {
                 for (router_key, router_entry) in &self.routers {
                     if default_routers.contains(&router_key.uri) {
+                        if !self.is_available(logger, router_key) {
+                            blacklisted = true;
+                            continue;
+                        }
                         debug!(logger, "sending to default router");
                         let _ = router_entry.dispatch.uplink(packet.clone(), received).await;
+                        handled = true;
                     }
                 }
             }
         }
+        if !handled {
+            let reason = if blacklisted {
+                DropReason::Blacklisted
+            } else {
+                DropReason::Filter
+            };
+            self.handles.uplink_stats.record(
+                logger,
+                Disposition::Dropped(reason),
+                Some(packet.payload()),
+            );
+        }
+    }
+
+    /// Whether the given router is not blacklisted for exceeding its error
+    /// budget
+    fn is_available(&self, logger: &Logger, router_key: &RouterKey) -> bool {
+        let uri = router_key.uri.uri.to_string();
+        let availability = self
+            .health
+            .lock()
+            .expect("router health lock")
+            .check(&uri, Instant::now());
+        match availability {
+            Availability::Available => true,
+            Availability::Blacklisted => false,
+            Availability::Restored => {
+                info!(logger, "router restored"; "uri" => &uri);
+                self.events.publish(Event::RouterRestored(uri));
+                true
+            }
+        }
     }
 
     async fn handle_region_params_update(&mut self, logger: &Logger) {
@@ -419,6 +489,9 @@ impl Dispatcher {
             self.transmit.clone(),
             self.keypair.clone(),
             self.max_packets,
+            self.health.clone(),
+            self.events.clone(),
+            &self.handles,
         )
         .await?;
         let join_handle =
@@ -1,3 +1,4 @@
+use crate::{error::DecodeError, Result};
 use bytes::{Buf, BufMut};
 use helium_proto::Eui;
 use std::{fmt, sync::Arc};
@@ -23,19 +24,27 @@ impl fmt::Debug for EuiFilter {
 }
 
 impl EuiFilter {
-    pub fn from_bin<D: AsRef<[u8]>>(data: D) -> Self {
+    pub fn from_bin<D: AsRef<[u8]>>(data: D) -> Result<Self> {
         let mut buf = data.as_ref();
+        if buf.remaining() < 16 {
+            return Err(DecodeError::invalid_filter("eui filter header"));
+        }
         let seed = buf.get_u64_le();
         let block_length = buf.get_u64_le() as usize;
-        let mut filters: Vec<u16> = Vec::with_capacity(block_length * 3);
-        for _ in 0..block_length * 3 {
+        // An empty filter would index out of its fingerprints on lookup
+        let fingerprint_count = block_length
+            .checked_mul(3)
+            .filter(|count| *count > 0 && count.saturating_mul(2) <= buf.remaining())
+            .ok_or_else(|| DecodeError::invalid_filter("eui filter length"))?;
+        let mut filters: Vec<u16> = Vec::with_capacity(fingerprint_count);
+        for _ in 0..fingerprint_count {
             filters.push(buf.get_u16_le());
         }
-        Self(Arc::new(Xor16 {
+        Ok(Self(Arc::new(Xor16 {
             seed,
             block_length,
             fingerprints: filters.into_boxed_slice(),
-        }))
+        })))
     }
 
     pub fn contains(&self, eui: &Eui) -> bool {
@@ -55,14 +64,18 @@ const BITS_23: u64 = 8388607; // biggest unsigned number in 23 bits
 const BITS_25: u64 = 33554431; // biggest unsigned number in 25 bits
 
 impl DevAddrFilter {
-    pub fn from_bin<D: AsRef<[u8]>>(data: D) -> Self {
+    pub fn from_bin<D: AsRef<[u8]>>(data: D) -> Result<Self> {
+        let data = data.as_ref();
+        if data.len() != 6 {
+            return Err(DecodeError::invalid_filter("devaddr subnet length"));
+        }
         let mut buf = [0u8; 8];
-        buf[2..].copy_from_slice(data.as_ref());
+        buf[2..].copy_from_slice(data);
         let val: u64 = u64::from_be_bytes(buf);
         let mask = (val & BITS_23) as u32;
         let base = ((val >> 23) & BITS_25) as u32;
         let size = ((mask ^ BITS_23 as u32) << 2) + 0b11 + 1;
-        Self { base, size }
+        Ok(Self { base, size })
     }
 
     pub fn contains(&self, devaddr: &u32) -> bool {
@@ -80,7 +93,7 @@ mod tests {
         #[test]
         fn from_bin_1() {
             static MASK: [u8; 6] = [0, 2, 0, 127, 255, 0];
-            let filter = DevAddrFilter::from_bin(MASK);
+            let filter = DevAddrFilter::from_bin(MASK).expect("devaddr filter");
             assert_eq!(1024, filter.base);
             assert_eq!(1024, filter.size);
             assert!(filter.contains(&1024));
@@ -89,11 +102,18 @@ mod tests {
         #[test]
         fn from_bin_2() {
             static MASK: [u8; 6] = [0, 4, 4, 127, 255, 254];
-            let filter = DevAddrFilter::from_bin(MASK);
+            let filter = DevAddrFilter::from_bin(MASK).expect("devaddr filter");
             assert_eq!(2056, filter.base);
             assert_eq!(8, filter.size);
             assert!(filter.contains(&2063));
         }
+
+        #[test]
+        fn from_bin_invalid() {
+            assert!(DevAddrFilter::from_bin([]).is_err());
+            assert!(DevAddrFilter::from_bin([0, 2, 0, 127, 255]).is_err());
+            assert!(DevAddrFilter::from_bin([0, 2, 0, 127, 255, 0, 0]).is_err());
+        }
     }
 
     mod eui {
@@ -107,13 +127,26 @@ mod tests {
                 0, 1, 0, 0, 0, 168, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 24, 236, 22, 0, 0, 0, 0, 1,
                 104, 2, 0,
             ];
-            let filter = EuiFilter::from_bin(EMPTY_BIN);
+            let filter = EuiFilter::from_bin(EMPTY_BIN).expect("eui filter");
             assert!(!filter.contains(&Eui {
                 deveui: 0,
                 appeui: 0,
             }),);
         }
 
+        #[test]
+        // Truncated or empty filters are rejected instead of panicking
+        fn invalid_filter() {
+            assert!(EuiFilter::from_bin([0u8; 15]).is_err());
+            assert!(EuiFilter::from_bin([0u8; 16]).is_err());
+            let mut truncated = [0u8; 20];
+            truncated[8] = 1;
+            assert!(EuiFilter::from_bin(truncated).is_err());
+            let mut huge = [0u8; 16];
+            huge[8..].copy_from_slice(&u64::MAX.to_le_bytes());
+            assert!(EuiFilter::from_bin(huge).is_err());
+        }
+
         #[test]
         //  Test a filter with keys generated in an external (erlang xor16) package.
         fn some_filter() {
@@ -136,7 +169,7 @@ mod tests {
                 0, 0, 0, 223, 21, 0, 0, 198, 225, 145, 206, 0, 0, 99, 63, 0, 0, 217, 218, 224, 20,
                 0, 0, 0, 0, 0, 0, 0, 0,
             ];
-            let filter = EuiFilter::from_bin(SOME_FILTER_BIN);
+            let filter = EuiFilter::from_bin(SOME_FILTER_BIN).expect("eui filter");
             assert!(!filter.contains(&Eui {
                 appeui: 0,
                 deveui: 0,
@@ -0,0 +1,215 @@
+//! Filtering of uplinks by NetID and DevAddr before they are forwarded.
+//!
+//! Gateways shared with a private LoRaWAN network hear that network's devices
+//! too. Their uplinks are of no use to the Helium router and only add to the
+//! data usage of the gateway. Data uplinks are matched against deny and allow
+//! rules by the NetID their DevAddr was allocated from or by inclusive DevAddr
+//! ranges. An uplink matching a deny rule is dropped. When allow rules are
+//! configured, an uplink matching none of them is dropped as well. Join
+//! requests carry no DevAddr and always pass.
+//!
+//! Drops are counted per rule for `GET /v1/uplinks/forward_filter`, with
+//! uplinks that matched no allow rule counted as `not_allowed`.
+
+use crate::{metrics, settings::ForwardFilterSettings, Error, Packet, Result};
+use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of uplinks dropped by the forward filter labeled with the `rule`
+pub const DROPPED_METRIC: &str = "uplink_forward_filter_dropped_total";
+
+/// The label of drops of uplinks that matched no allow rule
+const NOT_ALLOWED: &str = "not_allowed";
+
+/// A NetID or DevAddr range rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    NetId(u32),
+    /// An inclusive range of DevAddrs
+    DevAddrs {
+        start: u32,
+        end: u32,
+    },
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+impl Rule {
+    fn parse_net_id(value: &str) -> Result<Self> {
+        parse_hex(value)
+            // NetIDs are 24 bits
+            .filter(|net_id| *net_id <= 0xFF_FFFF)
+            .map(Self::NetId)
+            .ok_or_else(|| Error::custom(format!("invalid net id {value}")))
+    }
+
+    pub fn matches(&self, devaddr: u32) -> bool {
+        match self {
+            Self::NetId(net_id) => lorawan::subnet::parse_netid(devaddr) == *net_id,
+            Self::DevAddrs { start, end } => (*start..=*end).contains(&devaddr),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    /// Parses a DevAddr range as hex `start-end`, or a single hex DevAddr
+    fn from_str(value: &str) -> Result<Self> {
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        match (parse_hex(start), parse_hex(end)) {
+            (Some(start), Some(end)) if start <= end => Ok(Self::DevAddrs { start, end }),
+            _ => Err(Error::custom(format!("invalid devaddr range {value}"))),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NetId(net_id) => write!(f, "net_id:{net_id:06X}"),
+            Self::DevAddrs { start, end } => write!(f, "devaddr:{start:08X}-{end:08X}"),
+        }
+    }
+}
+
+/// The number of uplinks dropped per rule. Clones share the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardFilterHandle(Arc<Mutex<BTreeMap<String, u64>>>);
+
+impl ForwardFilterHandle {
+    fn record(&self, rule: &str) {
+        *self
+            .0
+            .lock()
+            .expect("forward filter lock")
+            .entry(rule.to_string())
+            .or_default() += 1;
+        metrics::increment(&metrics::labeled(DROPPED_METRIC, &[("rule", rule)]));
+    }
+
+    /// Returns the number of dropped uplinks per rule
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0.lock().expect("forward filter lock").clone()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ForwardFilter {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    dropped: ForwardFilterHandle,
+}
+
+impl ForwardFilter {
+    /// A filter counting its drops with the given handle
+    pub fn new(settings: &ForwardFilterSettings, dropped: ForwardFilterHandle) -> Result<Self> {
+        fn rules(net_ids: &[String], devaddrs: &[String]) -> Result<Vec<Rule>> {
+            net_ids
+                .iter()
+                .map(|net_id| Rule::parse_net_id(net_id))
+                .chain(devaddrs.iter().map(|range| range.parse()))
+                .collect()
+        }
+        Ok(Self {
+            allow: rules(&settings.allow_net_ids, &settings.allow_devaddrs)?,
+            deny: rules(&settings.deny_net_ids, &settings.deny_devaddrs)?,
+            dropped,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    /// Checks the given uplink, returning the label of the rule it was
+    /// dropped by. Drops are counted per rule.
+    pub fn check(&self, packet: &Packet) -> std::result::Result<(), String> {
+        let Some(RoutingInformation {
+            data: Some(RoutingData::Devaddr(devaddr)),
+        }) = packet.routing()
+        else {
+            return Ok(());
+        };
+        let result = self.check_devaddr(*devaddr);
+        if let Err(rule) = &result {
+            self.dropped.record(rule);
+        }
+        result
+    }
+
+    fn check_devaddr(&self, devaddr: u32) -> std::result::Result<(), String> {
+        if let Some(rule) = self.deny.iter().find(|rule| rule.matches(devaddr)) {
+            return Err(rule.to_string());
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(devaddr)) {
+            return Err(NOT_ALLOWED.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            Rule::DevAddrs {
+                start: 0x2600_0000,
+                end: 0x27FF_FFFF
+            },
+            "26000000-27FFFFFF".parse().expect("range")
+        );
+        assert_eq!(
+            Rule::DevAddrs {
+                start: 0x4800_0001,
+                end: 0x4800_0001
+            },
+            "0x48000001".parse().expect("devaddr")
+        );
+        assert!("27FFFFFF-26000000".parse::<Rule>().is_err());
+        assert!("devaddr".parse::<Rule>().is_err());
+        assert!(Rule::parse_net_id("1000000").is_err());
+
+        // A Helium (NetID 00003C) and a TTN (NetID 000013) devaddr
+        let helium = 0x7800_0001;
+        let ttn = 0x2600_0001;
+        let filter = ForwardFilter::new(
+            &ForwardFilterSettings {
+                allow_net_ids: vec!["00003C".to_string()],
+                deny_devaddrs: vec!["78000000-780000FF".to_string()],
+                ..Default::default()
+            },
+            Default::default(),
+        )
+        .expect("filter");
+        assert!(filter.is_enabled());
+        assert_eq!(Ok(()), filter.check_devaddr(0x7800_0100));
+        assert_eq!(
+            Err("devaddr:78000000-780000FF".to_string()),
+            filter.check_devaddr(helium)
+        );
+        assert_eq!(Err(NOT_ALLOWED.to_string()), filter.check_devaddr(ttn));
+
+        let filter = ForwardFilter::new(
+            &ForwardFilterSettings {
+                deny_net_ids: vec!["13".to_string()],
+                ..Default::default()
+            },
+            Default::default(),
+        )
+        .expect("filter");
+        assert_eq!(Ok(()), filter.check_devaddr(helium));
+        assert_eq!(Err("net_id:000013".to_string()), filter.check_devaddr(ttn));
+        assert!(!ForwardFilter::default().is_enabled());
+    }
+}
@@ -0,0 +1,145 @@
+//! Error budgets of the routers in multi-router mode.
+//!
+//! Every router gets the same queue and retries, so a router that keeps
+//! failing uses up send attempts meant for the healthy ones. The outcomes of
+//! the most recent sends are kept per router and a router whose error rate
+//! exceeds the budget is taken out of rotation for a cooldown period. After
+//! the cooldown the router is back on probation with a clean slate.
+
+use crate::{metrics, settings::RouterHealthSettings};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Gauge per router uri which is 1 while the router is blacklisted
+pub const BLACKLISTED_METRIC: &str = "router_blacklisted";
+
+#[derive(Debug, Default)]
+struct RouterStats {
+    /// Whether each of the most recent sends succeeded
+    outcomes: VecDeque<bool>,
+    blacklisted_until: Option<Instant>,
+}
+
+impl RouterStats {
+    fn error_rate(&self) -> f64 {
+        let errors = self.outcomes.iter().filter(|ok| !**ok).count();
+        errors as f64 / self.outcomes.len().max(1) as f64
+    }
+}
+
+/// Whether a router can be sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Available,
+    Blacklisted,
+    /// The cooldown of the router just ended
+    Restored,
+}
+
+#[derive(Debug)]
+pub struct RouterHealth {
+    settings: RouterHealthSettings,
+    routers: HashMap<String, RouterStats>,
+}
+
+impl RouterHealth {
+    pub fn new(settings: &RouterHealthSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            routers: HashMap::new(),
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.settings.cooldown)
+    }
+
+    /// Records the outcome of a send to the router with the given uri.
+    /// Returns the error rate when this blacklisted the router.
+    pub fn record(&mut self, uri: &str, ok: bool, now: Instant) -> Option<f64> {
+        if !self.settings.enabled {
+            return None;
+        }
+        let window = self.settings.window.max(1);
+        let stats = self.routers.entry(uri.to_string()).or_default();
+        if stats.blacklisted_until.is_some() {
+            return None;
+        }
+        if stats.outcomes.len() == window {
+            stats.outcomes.pop_front();
+        }
+        stats.outcomes.push_back(ok);
+        let error_rate = stats.error_rate();
+        if stats.outcomes.len() < window || error_rate * 100.0 < self.settings.error_percent as f64
+        {
+            return None;
+        }
+        stats.blacklisted_until = Some(now + Duration::from_secs(self.settings.cooldown));
+        metrics::set(&blacklisted_metric(uri), 1.0);
+        Some(error_rate)
+    }
+
+    /// Whether the router with the given uri can be sent to
+    pub fn check(&mut self, uri: &str, now: Instant) -> Availability {
+        let Some(stats) = self.routers.get_mut(uri) else {
+            return Availability::Available;
+        };
+        match stats.blacklisted_until {
+            Some(until) if until > now => Availability::Blacklisted,
+            Some(_) => {
+                *stats = RouterStats::default();
+                metrics::set(&blacklisted_metric(uri), 0.0);
+                Availability::Restored
+            }
+            None => Availability::Available,
+        }
+    }
+}
+
+fn blacklisted_metric(uri: &str) -> String {
+    metrics::labeled(BLACKLISTED_METRIC, &[("uri", uri)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blacklist() {
+        let mut health = RouterHealth::new(&RouterHealthSettings {
+            enabled: true,
+            error_percent: 50,
+            window: 4,
+            cooldown: 60,
+        });
+        let now = Instant::now();
+        let uri = "http://router.example.com:8080/";
+        assert_eq!(None, health.record(uri, false, now));
+        assert_eq!(None, health.record(uri, true, now));
+        assert_eq!(None, health.record(uri, false, now));
+        assert_eq!(Availability::Available, health.check(uri, now));
+        // Two of the last four sends failed
+        assert_eq!(Some(0.5), health.record(uri, true, now));
+        assert_eq!(Availability::Blacklisted, health.check(uri, now));
+        assert_eq!(
+            Availability::Available,
+            health.check("http://other.example.com/", now)
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(Availability::Restored, health.check(uri, later));
+        assert_eq!(Availability::Available, health.check(uri, later));
+        // A clean slate after the cooldown
+        assert_eq!(None, health.record(uri, false, later));
+
+        let mut disabled = RouterHealth::new(&RouterHealthSettings {
+            enabled: false,
+            ..health.settings.clone()
+        });
+        for _ in 0..10 {
+            assert_eq!(None, disabled.record(uri, false, now));
+        }
+    }
+}
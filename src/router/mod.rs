@@ -1,11 +1,14 @@
 pub mod client;
 pub mod dispatcher;
 pub mod filter;
+pub mod forward_filter;
+pub mod health;
 pub mod routing;
 pub mod state_channel_message;
 
 pub use client::RouterClient;
 pub use dispatcher::Dispatcher;
 pub use filter::{DevAddrFilter, EuiFilter};
+pub use forward_filter::ForwardFilter;
 pub use routing::Routing;
 pub use state_channel_message::StateChannelMessage;
@@ -35,8 +35,16 @@ impl Routing {
     }
 
     pub fn from_proto(logger: &Logger, r: &helium_proto::Routing) -> Result<Self> {
-        let filters = r.filters.iter().map(EuiFilter::from_bin).collect();
-        let subnets = r.subnets.iter().map(DevAddrFilter::from_bin).collect();
+        let filters = r
+            .filters
+            .iter()
+            .map(EuiFilter::from_bin)
+            .collect::<Result<_>>()?;
+        let subnets = r
+            .subnets
+            .iter()
+            .map(DevAddrFilter::from_bin)
+            .collect::<Result<_>>()?;
         let oui = r.oui;
         let uris = r
             .addresses
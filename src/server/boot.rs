@@ -0,0 +1,86 @@
+//! Tracks gateway (re)boots and crashes across restarts.
+//!
+//! A small state file in the configured data directory records the number of
+//! times the gateway server was started, how many of those starts followed a
+//! crash or unclean shutdown, and the reason for the last crash when known.
+//! Unclean shutdowns (power loss, OOM kills) are detected by a `running` marker
+//! which is only cleared on a clean shutdown.
+
+use crate::{metrics, status_file, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+const BOOT_STATE_FILE: &str = "boot.json";
+const UNCLEAN_SHUTDOWN: &str = "unclean shutdown";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BootState {
+    /// Number of times the gateway server was started
+    #[serde(default)]
+    pub boot_count: u64,
+    /// Number of starts that followed a crash or an unclean shutdown
+    #[serde(default)]
+    pub crash_count: u64,
+    /// The reason for the last detected crash
+    #[serde(default)]
+    pub last_crash: Option<String>,
+    /// A panic reason recorded by the panic hook of the previous run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_crash: Option<String>,
+    /// Set while the gateway server is running
+    #[serde(default)]
+    running: bool,
+}
+
+impl BootState {
+    /// Load the boot state from the given data directory. A missing state file
+    /// results in a default (empty) boot state.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        Ok(status_file::load(data_dir, BOOT_STATE_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, BOOT_STATE_FILE, self)
+    }
+
+    /// Record a server start. This increments the boot counter, detects a
+    /// crash of the previous run and marks the server as running.
+    pub fn record_boot(data_dir: &Path) -> Result<Self> {
+        let mut state = Self::load(data_dir)?;
+        state.boot_count += 1;
+        if state.running {
+            state.crash_count += 1;
+            state.last_crash = state
+                .pending_crash
+                .take()
+                .or_else(|| Some(UNCLEAN_SHUTDOWN.to_string()));
+        }
+        state.running = true;
+        state.save(data_dir)?;
+        metrics::set("gateway_boot_count", state.boot_count as f64);
+        metrics::set("gateway_crash_count", state.crash_count as f64);
+        Ok(state)
+    }
+
+    /// Record a clean server shutdown
+    pub fn record_shutdown(data_dir: &Path) -> Result {
+        let mut state = Self::load(data_dir)?;
+        state.running = false;
+        state.save(data_dir)
+    }
+
+    /// Install a panic hook which records the panic message as the crash
+    /// reason to be picked up on the next boot. The previously installed hook
+    /// is still called.
+    pub fn install_panic_hook(data_dir: &Path) {
+        let data_dir = data_dir.to_path_buf();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Ok(mut state) = Self::load(&data_dir) {
+                state.pending_crash = Some(format!("panic: {info}"));
+                let _ = state.save(&data_dir);
+            }
+            default_hook(info)
+        }));
+    }
+}
@@ -0,0 +1,342 @@
+//! Zero downtime upgrades with a socket handoff.
+//!
+//! With `update.action = "handoff"` the running gateway starts the staged
+//! binary next to itself instead of exec-ing into it. The new process finds
+//! the handoff unix socket of the old one through the [`HANDOFF_ENV`]
+//! environment variable and receives the listening sockets of the local and
+//! REST APIs and the serialized state of the old process, currently the region
+//! params, over it. Once the new process acknowledges, the old one shuts down
+//! like on a signal and the new one binds the packet forwarder port as soon as
+//! it is released.
+//! Uplinks are only lost in that short window instead of during the whole
+//! startup, and the new process routes and beacons with the known region params
+//! right away. Deferred reports are kept in the data directory and need no
+//! handoff.
+//!
+//! The Semtech UDP runtime binds its own socket, so unlike the API sockets the
+//! UDP socket is released and rebound rather than inherited. When the new
+//! process does not take over within [`HANDOFF_TIMEOUT`] it is killed and the
+//! old one keeps running.
+//!
+//! The new process is started as a child of the old one. The handoff is meant
+//! for gateways run in daemon mode, where the pid file is moved over to the new
+//! process, or under supervisors that do not stop all processes of a service
+//! when its main process exits, unlike systemd by default.
+//!
+//! Passing the sockets needs the `handoff` feature. Builds without it can
+//! neither hand over nor take over.
+
+use crate::{error::HandoffError, Error, Region, RegionParams, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::{BlockchainRegionParamsV1, Message};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use std::{
+    fs,
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        io::{AsFd, OwnedFd},
+        net::UnixStream,
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::{net::UnixListener, process::Command, time};
+
+#[cfg(feature = "handoff")]
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+#[cfg(feature = "handoff")]
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+};
+
+/// The environment variable with the handoff socket path of the old process
+pub const HANDOFF_ENV: &str = "HELIUM_GATEWAY_HANDOFF";
+/// The pid file of the gateway in daemon mode
+pub const PID_FILE: &str = "/var/run/helium_gateway.pid";
+/// Time allowed for the new process to take over, and for the old process to
+/// release the packet forwarder port after that
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HANDOFF_SOCKET: &str = "handoff.sock";
+/// The maximum number of sockets handed over
+#[cfg(feature = "handoff")]
+const MAX_SOCKETS: usize = 8;
+const READY: &[u8] = b"ready";
+
+/// Listening sockets to hand to a new process, by name
+static SHARED: Mutex<Vec<(&'static str, OwnedFd)>> = Mutex::new(Vec::new());
+/// Listening sockets inherited from the old process, by name
+static INHERITED: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static TAKEOVER: AtomicBool = AtomicBool::new(false);
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+
+/// The in-memory state handed to the new process
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    region: i32,
+    /// Antenna gain in tenths of a dBi
+    gain: u64,
+    /// Protobuf encoded channel params, base64 encoded
+    params: String,
+}
+
+impl State {
+    /// The state for the given region params. None when there are no channel
+    /// params yet, in which case the new process fetches them itself.
+    pub fn new(region_params: &RegionParams) -> Option<Self> {
+        if region_params.params.is_empty() {
+            return None;
+        }
+        let params = BlockchainRegionParamsV1 {
+            region_params: region_params.params.clone(),
+        };
+        Some(Self {
+            region: region_params.region.into(),
+            gain: (region_params.gain * Decimal::TEN).to_u64()?,
+            params: STANDARD.encode(params.encode_to_vec()),
+        })
+    }
+
+    pub fn region_params(&self) -> Result<RegionParams> {
+        let region = Region::from_i32(self.region)?;
+        let params = STANDARD.decode(&self.params)?;
+        Ok(RegionParams::from_bytes(region, self.gain, &params)?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    /// The names of the sockets passed along with the header, in order
+    sockets: Vec<String>,
+    state: Option<State>,
+}
+
+/// Whether this process took over from an old one that may still be
+/// releasing its ports
+pub fn is_takeover() -> bool {
+    TAKEOVER.load(Ordering::Relaxed)
+}
+
+/// Whether this process handed over to a new one and is shutting down
+pub fn is_handed_off() -> bool {
+    HANDED_OFF.load(Ordering::Relaxed)
+}
+
+/// The state handed over by the old process, if any
+pub fn take_state() -> Option<State> {
+    STATE.lock().expect("handoff state lock").take()
+}
+
+/// Binds a TCP listener, or takes the one inherited under the given name, and
+/// shares it for the next handoff. The listener is non-blocking.
+pub fn tcp_listener(name: &'static str, addr: SocketAddr) -> Result<TcpListener> {
+    let inherited = take(name)
+        .map(TcpListener::from)
+        .filter(|listener| listener.local_addr().ok() == Some(addr));
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => TcpListener::bind(addr)?,
+    };
+    listener.set_nonblocking(true)?;
+    SHARED
+        .lock()
+        .expect("handoff sockets lock")
+        .push((name, listener.as_fd().try_clone_to_owned()?));
+    Ok(listener)
+}
+
+fn take(name: &str) -> Option<OwnedFd> {
+    let mut inherited = INHERITED.lock().expect("handoff sockets lock");
+    let index = inherited.iter().position(|(socket, _)| socket == name)?;
+    Some(inherited.remove(index).1)
+}
+
+/// Takes over from an old process when this process was started by a
+/// handoff. Returns whether it was.
+pub fn take_over(logger: &Logger) -> Result<bool> {
+    let Some(path) = std::env::var_os(HANDOFF_ENV) else {
+        return Ok(false);
+    };
+    // Processes started by this one, like the update command, are not part of
+    // the handoff
+    std::env::remove_var(HANDOFF_ENV);
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    let (header, fds) = receive(&mut stream)?;
+    if header.sockets.len() != fds.len() {
+        return Err(HandoffError::socket_count(header.sockets.len(), fds.len()));
+    }
+    info!(logger, "taking over";
+        "sockets" => header.sockets.join(","),
+        "state" => header.state.is_some());
+    *INHERITED.lock().expect("handoff sockets lock") =
+        header.sockets.into_iter().zip(fds).collect();
+    *STATE.lock().expect("handoff state lock") = header.state;
+    TAKEOVER.store(true, Ordering::Relaxed);
+    stream.write_all(READY)?;
+    Ok(true)
+}
+
+#[cfg(feature = "handoff")]
+fn receive(stream: &mut UnixStream) -> Result<(Header, Vec<OwnedFd>)> {
+    let mut len = [0u8; 4];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_SOCKETS]);
+    let mut fds = vec![];
+    {
+        let mut iov = [IoSliceMut::new(&mut len)];
+        let msg = recvmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::empty(),
+        )
+        .map_err(io::Error::from)?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                for fd in received {
+                    // Received descriptors are not close-on-exec, duplicates
+                    // are
+                    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                    fds.push(fd.try_clone()?);
+                }
+            }
+        }
+        if msg.bytes != len.len() {
+            return Err(HandoffError::truncated_header());
+        }
+    }
+    let mut header = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut header)?;
+    Ok((serde_json::from_slice(&header)?, fds))
+}
+
+/// Hands this gateway over to the given binary. Returns once the new process
+/// took over, the caller then shuts this process down. When the handoff
+/// fails the new process is killed and this process keeps running.
+pub async fn hand_over(
+    binary: &Path,
+    data_dir: &Path,
+    state: Option<State>,
+    logger: &Logger,
+) -> Result {
+    let path = data_dir.join(HANDOFF_SOCKET);
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let mut child = Command::new(binary)
+        .args(std::env::args_os().skip(1))
+        .env(HANDOFF_ENV, &path)
+        .spawn()?;
+    let pid = child.id().unwrap_or_default();
+    info!(logger, "handing off"; "pid" => pid);
+
+    let handed = time::timeout(HANDOFF_TIMEOUT, async {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = stream.into_std()?;
+        tokio::task::spawn_blocking(move || send(&mut stream, state)).await?
+    })
+    .await;
+    let _ = fs::remove_file(&path);
+    match handed {
+        Ok(Ok(())) => {
+            // The clean shutdown is recorded by the new process
+            HANDED_OFF.store(true, Ordering::Relaxed);
+            move_pid_file(pid);
+            info!(logger, "handed off"; "pid" => pid);
+            Ok(())
+        }
+        Ok(Err(err)) => {
+            let _ = child.kill().await;
+            Err(err)
+        }
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(Error::timeout())
+        }
+    }
+}
+
+#[cfg(not(feature = "handoff"))]
+fn receive(_stream: &mut UnixStream) -> Result<(Header, Vec<OwnedFd>)> {
+    Err(Error::custom("socket handoff requires the handoff feature"))
+}
+
+#[cfg(feature = "handoff")]
+fn send(stream: &mut UnixStream, state: Option<State>) -> Result {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    let shared = SHARED.lock().expect("handoff sockets lock");
+    let header = serde_json::to_vec(&Header {
+        sockets: shared.iter().map(|(name, _)| name.to_string()).collect(),
+        state,
+    })?;
+    let fds: Vec<RawFd> = shared.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+    let len = (header.len() as u32).to_be_bytes();
+    let rights = [ControlMessage::ScmRights(&fds)];
+    let cmsgs: &[ControlMessage] = if fds.is_empty() { &[] } else { &rights };
+    sendmsg::<UnixAddr>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&len)],
+        cmsgs,
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(io::Error::from)?;
+    stream.write_all(&header)?;
+
+    let mut ready = [0u8; READY.len()];
+    stream.read_exact(&mut ready)?;
+    if ready != READY {
+        return Err(HandoffError::acknowledgement());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "handoff"))]
+fn send(_stream: &mut UnixStream, _state: Option<State>) -> Result {
+    Err(Error::custom("socket handoff requires the handoff feature"))
+}
+
+/// Points the daemon pid file, if it is this process', at the new process
+fn move_pid_file(pid: u32) {
+    let own = fs::read_to_string(PID_FILE)
+        .map(|contents| contents.trim() == std::process::id().to_string())
+        .unwrap_or(false);
+    if own {
+        let _ = fs::write(PID_FILE, format!("{pid}\n"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_proto::{BlockchainRegionParamV1, Region as ProtoRegion};
+
+    #[test]
+    fn state() {
+        let region_params = RegionParams {
+            gain: Decimal::new(58, 1),
+            params: vec![BlockchainRegionParamV1 {
+                channel_frequency: 868_100_000,
+                bandwidth: 125_000,
+                max_eirp: 160,
+                spreading: None,
+            }],
+            ..RegionParams::from(Region::from(ProtoRegion::Eu868))
+        };
+        let state = State::new(&region_params).expect("state");
+        let json = serde_json::to_vec(&state).expect("json");
+        let state: State = serde_json::from_slice(&json).expect("state");
+        assert_eq!(region_params, state.region_params().expect("region params"));
+
+        assert!(State::new(&RegionParams::from(Region::from(ProtoRegion::Eu868))).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+//! First successes after boot.
+//!
+//! Installers commissioning a gateway need a concrete sign that it works end
+//! to end. The first uplink forwarded to a router, the first downlink
+//! transmitted and the first witness report accepted after every boot are
+//! milestones: each is logged, published on the event bus and kept in the
+//! data directory with the time it was reached for `info milestones`. The
+//! status LED shows the first witness report for a minute.
+
+use crate::{
+    clock::unix_now,
+    event_bus::{Event, EventBus},
+    status_file, Result,
+};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const MILESTONES_FILE: &str = "milestones.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Milestone {
+    UplinkForwarded,
+    DownlinkTransmitted,
+    WitnessReported,
+}
+
+impl Milestone {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UplinkForwarded => "uplink_forwarded",
+            Self::DownlinkTransmitted => "downlink_transmitted",
+            Self::WitnessReported => "witness_reported",
+        }
+    }
+}
+
+/// A milestone that was just reached, published on the event bus
+#[derive(Debug, Clone, Serialize)]
+pub struct MilestoneReached {
+    pub milestone: &'static str,
+    /// Unix time (seconds) the milestone was reached
+    pub time: u64,
+    /// Seconds from the boot to the milestone
+    pub since_boot: u64,
+}
+
+/// The time the milestones were reached since the last boot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Milestones {
+    /// Unix time (seconds) of the boot
+    pub boot: u64,
+    /// Unix time (seconds) the first uplink was forwarded to a router
+    pub uplink_forwarded: Option<u64>,
+    /// Unix time (seconds) the first downlink was transmitted
+    pub downlink_transmitted: Option<u64>,
+    /// Unix time (seconds) the first witness report was accepted
+    pub witness_reported: Option<u64>,
+}
+
+impl Milestones {
+    /// Load the milestones stored by the running server. Returns None when
+    /// the server never started.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, MILESTONES_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, MILESTONES_FILE, self)
+    }
+
+    fn get_mut(&mut self, milestone: Milestone) -> &mut Option<u64> {
+        match milestone {
+            Milestone::UplinkForwarded => &mut self.uplink_forwarded,
+            Milestone::DownlinkTransmitted => &mut self.downlink_transmitted,
+            Milestone::WitnessReported => &mut self.witness_reported,
+        }
+    }
+
+    /// Records a milestone reached at the given unix time. Returns false when
+    /// it was already reached.
+    fn reach(&mut self, milestone: Milestone, now: u64) -> bool {
+        let reached = self.get_mut(milestone);
+        if reached.is_some() {
+            return false;
+        }
+        *reached = Some(now);
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Tracker {
+    data_dir: PathBuf,
+    milestones: Milestones,
+    events: EventBus,
+}
+
+/// Records the milestones of this boot. Milestones are only recorded once
+/// started.
+#[derive(Debug, Clone, Default)]
+pub struct MilestonesHandle(Arc<Mutex<Option<Tracker>>>);
+
+impl MilestonesHandle {
+    /// Starts tracking the milestones of this boot. Reached milestones are
+    /// published on the given bus.
+    pub fn start(&self, data_dir: &Path, events: EventBus) -> Result {
+        let milestones = Milestones {
+            boot: unix_now(),
+            ..Default::default()
+        };
+        milestones.save(data_dir)?;
+        *self.0.lock().expect("milestones lock") = Some(Tracker {
+            data_dir: data_dir.to_path_buf(),
+            milestones,
+            events,
+        });
+        Ok(())
+    }
+
+    /// Records a milestone if this is the first time it is reached since boot
+    pub fn reach(&self, logger: &Logger, milestone: Milestone) {
+        let mut tracker = self.0.lock().expect("milestones lock");
+        let Some(tracker) = tracker.as_mut() else {
+            return;
+        };
+        let now = unix_now();
+        if !tracker.milestones.reach(milestone, now) {
+            return;
+        }
+        let reached = MilestoneReached {
+            milestone: milestone.as_str(),
+            time: now,
+            since_boot: now.saturating_sub(tracker.milestones.boot),
+        };
+        info!(logger, "milestone reached";
+            "milestone" => reached.milestone,
+            "since_boot" => reached.since_boot);
+        if let Err(err) = tracker.milestones.save(&tracker.data_dir) {
+            warn!(logger, "failed to store milestones: {err:?}");
+        }
+        tracker.events.publish(Event::MilestoneReached(reached));
+    }
+
+    /// Whether the milestone was reached less than `duration` ago
+    pub fn reached_within(&self, milestone: Milestone, duration: Duration) -> bool {
+        let mut tracker = self.0.lock().expect("milestones lock");
+        tracker
+            .as_mut()
+            .and_then(|tracker| *tracker.milestones.get_mut(milestone))
+            .is_some_and(|reached| unix_now().saturating_sub(reached) < duration.as_secs())
+    }
+
+    /// Returns the milestones of this boot
+    pub fn snapshot(&self) -> Milestones {
+        self.0
+            .lock()
+            .expect("milestones lock")
+            .as_ref()
+            .map(|tracker| tracker.milestones.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_only() {
+        let mut milestones = Milestones {
+            boot: 1_000,
+            ..Default::default()
+        };
+        assert!(milestones.reach(Milestone::WitnessReported, 1_060));
+        assert!(!milestones.reach(Milestone::WitnessReported, 1_120));
+        assert_eq!(Some(1_060), milestones.witness_reported);
+        assert_eq!(None, milestones.uplink_forwarded);
+        assert!(milestones.reach(Milestone::UplinkForwarded, 1_010));
+        assert_eq!(Some(1_010), milestones.uplink_forwarded);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{
+    beaconer::{
+        self, channel_mask::ChannelMaskHandle, local_entropy::RfPoolHandle,
+        receipts::ReceiptsHandle,
+    },
+    event_bus::EventBus,
+    gateway::{
+        self, crc_failed::CrcFailedHandle, downlink_stats::DownlinkStatsHandle,
+        forwarder_acks::ForwarderAcksHandle, forwarder_conformance::ConformanceStatsHandle,
+        forwarder_stats::ForwarderStatsHandle, geofence::GeofenceHandle,
+        rf_health::RfHealthMonitor, status_led::StatusLed, uplink_stats::UplinkStatsHandle,
+        watchdog::WatchdogHandle,
+    },
+    keypair::{health::KeyHealthChecker, KeyProbe},
+    logging::sampling::LogSampler,
+    packet_router,
+    queues::QueuesHandle,
+    region_watcher,
+    router::forward_filter::ForwardFilterHandle,
+    service::{
+        channel,
+        data_usage::{DataUsage, DataUsageHandle},
+        fleet::FleetAgent,
+        modem::ModemReader,
+        quarantine::QuarantineHandle,
+        region_history::RegionHistoryHandle,
+        stats::ServiceStatsHandle,
+    },
+    settings::{self, Settings},
+    Result,
+};
+use futures::future::try_join_all;
+use slog::{info, warn, Logger};
+use std::{future::Future, pin::Pin, time::Duration};
+
+pub mod boot;
+pub mod handoff;
+pub mod milestones;
+pub mod port_check;
+pub mod startup;
+
+use boot::BootState;
+use milestones::MilestonesHandle;
+use startup::{StartupHandle, StartupRecord};
+
+/// A task the server runs until shutdown
+type Task<'a> = Pin<Box<dyn Future<Output = Result> + 'a>>;
+
+/// The state the server tasks record and the local APIs report. Clones share
+/// the same state.
+#[derive(Debug, Clone)]
+pub struct Handles {
+    pub milestones: MilestonesHandle,
+    pub startup: StartupHandle,
+    pub queues: QueuesHandle,
+    pub uplink_stats: UplinkStatsHandle,
+    pub downlink_stats: DownlinkStatsHandle,
+    pub forwarder_acks: ForwarderAcksHandle,
+    pub forwarder_stats: ForwarderStatsHandle,
+    pub geofence: GeofenceHandle,
+    pub forward_filter: ForwardFilterHandle,
+    pub forwarder_conformance: ConformanceStatsHandle,
+    pub crc_failed: CrcFailedHandle,
+    pub watchdog: WatchdogHandle,
+    pub channel_mask: ChannelMaskHandle,
+    pub rf_pool: RfPoolHandle,
+    pub receipts: ReceiptsHandle,
+    pub quarantine: QuarantineHandle,
+    pub region_history: RegionHistoryHandle,
+    pub data_usage: DataUsageHandle,
+    pub service_stats: ServiceStatsHandle,
+}
+
+impl Handles {
+    /// Checks the queue settings and creates the state of a new run.
+    /// Milestones, receipts and the startup record are not recorded until
+    /// started.
+    pub fn new(settings: &Settings) -> Result<Self> {
+        Ok(Self::with(
+            QueuesHandle::new(&settings.queues)?,
+            CrcFailedHandle::new(&settings.crc_failed),
+        ))
+    }
+
+    fn with(queues: QueuesHandle, crc_failed: CrcFailedHandle) -> Self {
+        let milestones = MilestonesHandle::default();
+        Self {
+            startup: StartupHandle::default(),
+            queues,
+            uplink_stats: UplinkStatsHandle::new(milestones.clone()),
+            downlink_stats: DownlinkStatsHandle::new(milestones.clone()),
+            forwarder_acks: ForwarderAcksHandle::default(),
+            forwarder_stats: ForwarderStatsHandle::default(),
+            geofence: GeofenceHandle::default(),
+            forward_filter: ForwardFilterHandle::default(),
+            forwarder_conformance: ConformanceStatsHandle::default(),
+            crc_failed,
+            watchdog: WatchdogHandle::default(),
+            channel_mask: ChannelMaskHandle::default(),
+            rf_pool: RfPoolHandle::default(),
+            receipts: ReceiptsHandle::default(),
+            quarantine: QuarantineHandle::default(),
+            region_history: RegionHistoryHandle::default(),
+            data_usage: DataUsageHandle::default(),
+            service_stats: ServiceStatsHandle::default(),
+            milestones,
+        }
+    }
+}
+
+impl Default for Handles {
+    fn default() -> Self {
+        Self::with(QueuesHandle::default(), CrcFailedHandle::default())
+    }
+}
+
+pub async fn run(shutdown: &triggered::Listener, settings: &Settings, logger: &Logger) -> Result {
+    let handoff_state = if handoff::take_over(logger)? {
+        // The old process exits cleanly now, and leaves recording that to this
+        // one so it can not overwrite the boot recorded below
+        if let Err(err) = BootState::record_shutdown(&settings.data_dir) {
+            warn!(logger, "failed to record handoff shutdown: {err:?}");
+        }
+        handoff::take_state()
+    } else {
+        None
+    };
+    BootState::install_panic_hook(&settings.data_dir);
+    match BootState::record_boot(&settings.data_dir) {
+        Ok(boot_state) => info!(logger, "boot recorded";
+            "boot_count" => boot_state.boot_count,
+            "crash_count" => boot_state.crash_count,
+            "last_crash" => boot_state.last_crash),
+        Err(err) => warn!(logger, "failed to record boot: {err:?}"),
+    }
+    let handles = Handles::new(settings)?;
+    if let Err(err) = handles.receipts.start(&settings.data_dir) {
+        warn!(logger, "failed to load report receipts: {err:?}");
+    }
+    let key_probes = KeyProbe::current();
+    for probe in &key_probes {
+        info!(logger, "secure element probed";
+            "uri" => &probe.uri,
+            "attempts" => probe.attempts,
+            "ok" => probe.ok,
+            "error" => &probe.error,
+            "buses" => probe.buses.join(","));
+    }
+    settings.keypair.select_signer(logger);
+    let startup = StartupRecord::new(settings, &key_probes);
+    if let Err(err) = handles.startup.record(startup, &settings.data_dir, logger) {
+        warn!(logger, "failed to store startup record: {err:?}");
+    }
+
+    port_check::check(settings)?;
+    let queues = handles.queues.snapshot();
+    let events = EventBus::new(queues.event_bus);
+    if let Err(err) = handles.milestones.start(&settings.data_dir, events.clone()) {
+        warn!(logger, "failed to store milestones: {err:?}");
+    }
+    channel::configure_keepalive((settings.router.keepalive_interval > 0).then(|| {
+        channel::Keepalive {
+            interval: Duration::from_secs(settings.router.keepalive_interval),
+            timeout: Duration::from_secs(settings.router.keepalive_timeout),
+        }
+    }));
+
+    // The tasks stop on shutdown, or when an update restarts the gateway
+    let (stop_trigger, stop) = triggered::trigger();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        let stop_trigger = stop_trigger.clone();
+        async move {
+            shutdown.await;
+            stop_trigger.trigger();
+        }
+    });
+    let shutdown = &stop;
+
+    let (gateway_tx, gateway_rx) = gateway::message_channel(queues.gateway);
+    let (router_tx, router_rx) = packet_router::message_channel(queues.router);
+    let (beacon_tx, beacon_rx) = beaconer::message_channel(queues.beacon);
+
+    let mut region_watcher =
+        region_watcher::RegionWatcher::new(settings, events.clone(), &handles)?;
+    if let Some(state) = handoff_state {
+        match state.region_params() {
+            Ok(region_params) => region_watcher.seed(region_params),
+            Err(err) => warn!(logger, "ignoring handed off region params: {err:?}"),
+        }
+    }
+    let region_rx = region_watcher.watcher();
+    let region_refresh = region_watcher.refresher();
+
+    // Without a beaconer received beacons and beacon requests are dropped
+    #[cfg(feature = "poc")]
+    let mut beaconer = settings.poc.enabled.then(|| {
+        beaconer::Beaconer::new(
+            settings,
+            beacon_rx,
+            region_rx.clone(),
+            gateway_tx.clone(),
+            &handles,
+        )
+    });
+    #[cfg(not(feature = "poc"))]
+    drop(beacon_rx);
+
+    #[cfg(not(feature = "validator"))]
+    let mut router = packet_router::PacketRouter::new(
+        settings,
+        router_rx,
+        region_rx.clone(),
+        gateway_tx.clone(),
+        &handles,
+    )?;
+
+    #[cfg(feature = "validator")]
+    let mut router = crate::router::Dispatcher::new(
+        settings,
+        router_rx,
+        region_rx.clone(),
+        gateway_tx.clone(),
+        events.clone(),
+        &handles,
+    );
+
+    let (join_filter, join_filter_rx) = gateway::join_filter::channel(&settings.join_filter)?;
+    let mut gateway = gateway::Gateway::new(
+        settings,
+        gateway_rx,
+        region_rx.clone(),
+        router_tx.clone(),
+        beacon_tx.clone(),
+        join_filter_rx,
+        events.clone(),
+        &handles,
+    )
+    .await?;
+    // The join filter is only replaced at runtime through the REST API
+    #[cfg(not(feature = "rest"))]
+    drop(join_filter);
+    let fleet = FleetAgent::new(settings, region_rx.clone(), router_tx, region_refresh);
+    let mut tasks: Vec<Task> = vec![
+        Box::pin(region_watcher.run(shutdown, logger)),
+        Box::pin(gateway.run(shutdown, logger)),
+        Box::pin(router.run(shutdown, logger)),
+    ];
+    // Optional subsystems are only run when built with their feature and
+    // enabled in the settings
+    #[cfg(feature = "poc")]
+    if let Some(beaconer) = &mut beaconer {
+        tasks.push(Box::pin(beaconer.run(shutdown, logger)));
+    }
+    let shadow =
+        packet_router::ShadowRouter::new(settings, region_rx.clone(), events.clone(), &handles)?;
+    if shadow.is_enabled() {
+        tasks.push(Box::pin(shadow.run(shutdown, logger)));
+    }
+    let led = StatusLed::new(settings, handles.milestones.clone());
+    if led.is_enabled() {
+        tasks.push(Box::pin(led.run(shutdown, logger)));
+    }
+    let modem = ModemReader::new(settings);
+    if modem.is_enabled() {
+        tasks.push(Box::pin(modem.run(shutdown, logger)));
+    }
+    let key_health = KeyHealthChecker::new(settings);
+    if key_health.is_enabled() {
+        tasks.push(Box::pin(key_health.run(shutdown, logger)));
+    }
+    let rf_health = RfHealthMonitor::new(settings, events.clone())?;
+    if rf_health.is_enabled() {
+        tasks.push(Box::pin(rf_health.run(shutdown, logger)));
+    }
+    let data_usage = DataUsage::new(settings, handles.data_usage.clone());
+    if data_usage.is_enabled() {
+        tasks.push(Box::pin(data_usage.run(shutdown, logger)));
+    }
+    let log_sampler = LogSampler::new(settings);
+    if log_sampler.is_enabled() {
+        tasks.push(Box::pin(log_sampler.run(shutdown, logger)));
+    }
+    if fleet.is_enabled() {
+        tasks.push(Box::pin(fleet.run(shutdown, logger)));
+    }
+    #[cfg(feature = "updater")]
+    let staged = {
+        let updater =
+            crate::service::updater::Updater::new(settings, region_rx.clone(), stop_trigger)?;
+        let staged = updater.staged();
+        if updater.is_enabled() {
+            tasks.push(Box::pin(updater.run(shutdown, logger)));
+        }
+        staged
+    };
+    #[cfg(not(feature = "updater"))]
+    if settings.update.manifest.is_some() {
+        return Err(crate::Error::from(config::ConfigError::Message(
+            "update.manifest requires the updater feature".to_string(),
+        )));
+    }
+    #[cfg(feature = "api")]
+    {
+        let api = crate::api::LocalServer::new(region_rx.clone(), settings)?;
+        tasks.push(Box::pin(api.run(shutdown, logger)));
+    }
+    #[cfg(feature = "rest")]
+    {
+        let rest = crate::api::RestServer::new(
+            region_rx.clone(),
+            beacon_tx,
+            join_filter,
+            events.clone(),
+            &handles,
+            settings,
+        );
+        tasks.push(Box::pin(rest.run(shutdown, logger)));
+    }
+    #[cfg(feature = "mqtt")]
+    tasks.push(Box::pin(
+        gateway::mqtt::MqttBridge::new(settings, gateway_tx.clone(), events.clone())?
+            .run(shutdown, logger),
+    ));
+    #[cfg(feature = "metrics")]
+    {
+        use crate::metrics::{MetricsServer, MetricsSnapshots};
+        tasks.push(Box::pin(MetricsServer::new(settings).run(shutdown, logger)));
+        tasks.push(Box::pin(
+            MetricsSnapshots::new(settings).run(shutdown, logger),
+        ));
+    }
+    info!(logger,
+        "starting server";
+        "version" => settings::version().to_string(),
+        "key" => settings.keypair.public_key().to_string(),
+        "tasks" => tasks.len(),
+    );
+    let result = try_join_all(tasks).await.map(|_| ());
+
+    // The new process records the shutdown of this one after a handoff, so
+    // this one can not overwrite the boot recorded there
+    if handoff::is_handed_off() {
+        return result;
+    }
+    // A task that failed is counted as a crash on the next boot
+    result?;
+    if let Err(err) = BootState::record_shutdown(&settings.data_dir) {
+        warn!(logger, "failed to record shutdown: {err:?}");
+    }
+    #[cfg(feature = "updater")]
+    staged.exec(logger)?;
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! Pre-flight checks that the ports the gateway listens on are free.
+//!
+//! Binding a port another process holds fails with a bare "address in use" IO
+//! error which is easy to misread, for example when a second gateway instance
+//! or another packet forwarder bridge already listens on the Semtech UDP port.
+//! The ports are checked before the server starts and a conflict is reported
+//! with the setting that configures the port and, where /proc allows, the
+//! process holding it.
+
+use crate::{
+    gateway::listen, server::handoff, settings::ForwarderBackend, Error, Result, Settings,
+};
+use std::{
+    fmt, fs, io,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    path::Path,
+};
+
+/// The /proc/net state of a listening TCP socket
+const TCP_LISTEN: &str = "0A";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl Protocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+        }
+    }
+
+    /// The /proc/net socket tables of the protocol
+    fn proc_tables(&self) -> [&'static str; 2] {
+        match self {
+            Self::Udp => ["udp", "udp6"],
+            Self::Tcp => ["tcp", "tcp6"],
+        }
+    }
+}
+
+/// The process holding a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortHolder {
+    pub pid: u32,
+    /// The command name of the process
+    pub name: String,
+}
+
+/// A port the gateway needs that is already in use
+#[derive(Debug)]
+pub struct PortConflict {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+    /// The setting that configures the port
+    pub setting: &'static str,
+    /// The process holding the port, when it could be found
+    pub holder: Option<PortHolder>,
+}
+
+impl fmt::Display for PortConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} port {} ({} = \"{}\") is already in use by ",
+            self.protocol.as_str(),
+            self.addr.port(),
+            self.setting,
+            self.addr
+        )?;
+        match &self.holder {
+            Some(holder) => write!(f, "pid {} ({})", holder.pid, holder.name)?,
+            None => f.write_str("another process")?,
+        }
+        write!(
+            f,
+            ", stop that process or change {} in the settings",
+            self.setting
+        )
+    }
+}
+
+/// Checks that the Semtech UDP ports, when used, the Basics Station port and the
+/// local API port can be bound
+pub fn check(settings: &Settings) -> Result {
+    // After a handoff the old process holds the ports until it exits
+    if handoff::is_takeover() {
+        return Ok(());
+    }
+    // Listen addresses that can not be resolved are reported when the packet
+    // forwarder server binds them
+    if settings.forwarder.backend == ForwarderBackend::SemtechUdp {
+        for addr in listen::resolve(settings).unwrap_or_default() {
+            check_port(Protocol::Udp, addr, "listen")?;
+        }
+    }
+    if let Some(Ok(addr)) = settings
+        .basics_station
+        .listen
+        .as_deref()
+        .map(str::parse::<SocketAddr>)
+    {
+        check_port(Protocol::Tcp, addr, "basics_station.listen")?;
+    }
+    #[cfg(feature = "api")]
+    {
+        let api_addr = crate::api::listen_addr(settings.api).parse()?;
+        check_port(Protocol::Tcp, api_addr, "api")?;
+    }
+    Ok(())
+}
+
+fn check_port(protocol: Protocol, addr: SocketAddr, setting: &'static str) -> Result {
+    let bound = match protocol {
+        Protocol::Udp => UdpSocket::bind(addr).map(drop),
+        Protocol::Tcp => TcpListener::bind(addr).map(drop),
+    };
+    match bound {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            Err(Error::port_in_use(PortConflict {
+                protocol,
+                addr,
+                setting,
+                holder: find_holder(protocol, addr.port()),
+            }))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Finds the process holding a port through /proc. Only processes whose file
+/// descriptors are readable, like those of the same user, can be found.
+fn find_holder(protocol: Protocol, port: u16) -> Option<PortHolder> {
+    let inode = protocol.proc_tables().iter().find_map(|table| {
+        let contents = fs::read_to_string(format!("/proc/net/{table}")).ok()?;
+        socket_inode(&contents, protocol, port)
+    })?;
+    let socket = format!("socket:[{inode}]");
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse().ok()?;
+        let holds = fs::read_dir(entry.path().join("fd"))
+            .ok()?
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).map_or(false, |link| link == Path::new(&socket)));
+        if !holds {
+            return None;
+        }
+        let name = fs::read_to_string(entry.path().join("comm"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        Some(PortHolder { pid, name })
+    })
+}
+
+/// Finds the inode of the socket bound to a local port in a /proc/net socket
+/// table
+fn socket_inode(table: &str, protocol: Protocol, port: u16) -> Option<u64> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_port = fields.get(1)?.rsplit(':').next()?;
+        if u16::from_str_radix(local_port, 16).ok()? != port {
+            return None;
+        }
+        if protocol == Protocol::Tcp && fields.get(3) != Some(&TCP_LISTEN) {
+            return None;
+        }
+        let inode = fields.get(9)?.parse().ok()?;
+        (inode != 0).then_some(inode)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn proc_net_table() {
+        let udp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+            \x20 10: 0100007F:0690 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 31337 2 0000000000000000 0\n";
+        assert_eq!(Some(31337), socket_inode(udp, Protocol::Udp, 1680));
+        assert_eq!(None, socket_inode(udp, Protocol::Udp, 1681));
+
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+            \x20  0: 0100007F:1173 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000     0        0 4242 1 0000000000000000 20 4 30 10 -1\n\
+            \x20  1: 0100007F:1173 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4343 1 0000000000000000 100 0 0 10 0\n";
+        assert_eq!(Some(4343), socket_inode(tcp, Protocol::Tcp, 4467));
+    }
+
+    #[test]
+    fn conflict() {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let addr = socket.local_addr().expect("local addr");
+        let err = check_port(Protocol::Udp, addr, "listen").expect_err("port in use");
+        assert_eq!(ErrorCode::PortInUse, err.code());
+        if let Error::PortInUse(PortConflict {
+            holder: Some(holder),
+            ..
+        }) = &err
+        {
+            assert_eq!(std::process::id(), holder.pid);
+        }
+        drop(socket);
+        assert!(check_port(Protocol::Udp, addr, "listen").is_ok());
+    }
+}
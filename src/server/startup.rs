@@ -0,0 +1,164 @@
+//! A record of the environment the gateway server started in.
+//!
+//! Support needs to know which build runs with which settings on which
+//! hardware for nearly every ticket. On every server start the version,
+//! enabled features, a hash of the effective settings, the key type and
+//! secure element and the packet forwarder listen addresses are logged as a
+//! single structured record and stored in the data directory, where
+//! `info startup` reads it. The EUIs of packet forwarders are added to the
+//! stored record as they connect.
+
+use crate::{
+    clock::unix_now,
+    gateway::listen,
+    keypair::KeyProbe,
+    settings::{self, ForwarderBackend},
+    status_file, Result, Settings,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slog::{info, Logger};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const STARTUP_FILE: &str = "startup.json";
+
+/// The cargo features the binary was built with
+const FEATURES: &[(&str, bool)] = &[
+    ("ecc608", cfg!(feature = "ecc608")),
+    ("tpm", cfg!(feature = "tpm")),
+    ("validator", cfg!(feature = "validator")),
+    ("poc", cfg!(feature = "poc")),
+    ("api", cfg!(feature = "api")),
+    ("rest", cfg!(feature = "rest")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("concentratord", cfg!(feature = "concentratord")),
+    ("basics-station", cfg!(feature = "basics-station")),
+    ("handoff", cfg!(feature = "handoff")),
+    ("listen-interface", cfg!(feature = "listen-interface")),
+    ("log-compress", cfg!(feature = "log-compress")),
+    ("diagnostics", cfg!(feature = "diagnostics")),
+    ("updater", cfg!(feature = "updater")),
+    ("rf-webhook", cfg!(feature = "rf-webhook")),
+    ("region-http", cfg!(feature = "region-http")),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StartupRecord {
+    pub version: String,
+    /// Unix time (seconds) the server started
+    pub started: u64,
+    /// The target architecture and operating system of the binary
+    pub target: String,
+    pub features: Vec<String>,
+    /// A hash of the effective settings, equal for equal settings regardless
+    /// of where the values came from
+    pub settings_hash: Option<String>,
+    pub key_type: String,
+    /// The uri of the secure element the key was loaded from, if any
+    pub secure_element: Option<String>,
+    pub forwarder: ForwarderBackend,
+    /// The resolved packet forwarder listen addresses
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// The EUIs of the packet forwarders that connected since the start
+    #[serde(default)]
+    pub concentrators: Vec<String>,
+}
+
+impl StartupRecord {
+    pub fn new(settings: &Settings, key_probes: &[KeyProbe]) -> Self {
+        Self {
+            version: settings::version().to_string(),
+            started: unix_now(),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| feature.to_string())
+                .collect(),
+            settings_hash: settings_hash(&settings.config_path),
+            key_type: format!("{:?}", settings.keypair.public_key().key_type()),
+            secure_element: key_probes
+                .iter()
+                .find(|probe| probe.ok)
+                .map(|probe| probe.uri.clone()),
+            forwarder: settings.forwarder.backend,
+            listen: match settings.forwarder.backend {
+                ForwarderBackend::SemtechUdp => listen::resolve(settings)
+                    .map(|addrs| addrs.iter().map(ToString::to_string).collect())
+                    .unwrap_or_default(),
+                ForwarderBackend::Concentratord => vec![],
+            },
+            concentrators: vec![],
+        }
+    }
+
+    /// Load the record stored by the last server start
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, STARTUP_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, STARTUP_FILE, self)
+    }
+}
+
+/// The record of this run and the data directory it is stored in, once
+/// recorded. Clones share the same record.
+#[derive(Debug, Clone, Default)]
+pub struct StartupHandle(Arc<Mutex<Option<(PathBuf, StartupRecord)>>>);
+
+impl StartupHandle {
+    /// Logs and stores the given record as the record of this run
+    pub fn record(&self, record: StartupRecord, data_dir: &Path, logger: &Logger) -> Result {
+        info!(logger, "startup";
+            "version" => &record.version,
+            "target" => &record.target,
+            "features" => record.features.join(","),
+            "settings_hash" => &record.settings_hash,
+            "key_type" => &record.key_type,
+            "secure_element" => &record.secure_element,
+            "forwarder" => format!("{:?}", record.forwarder),
+            "listen" => record.listen.join(","));
+        record.save(data_dir)?;
+        *self.0.lock().expect("startup lock") = Some((data_dir.to_path_buf(), record));
+        Ok(())
+    }
+
+    /// Adds a packet forwarder EUI to the stored record of this run
+    pub fn concentrator_connected(&self, eui: String) -> Result {
+        let mut current = self.0.lock().expect("startup lock");
+        let Some((data_dir, record)) = current.as_mut() else {
+            return Ok(());
+        };
+        if record.concentrators.contains(&eui) {
+            return Ok(());
+        }
+        record.concentrators.push(eui);
+        record.save(data_dir)
+    }
+}
+
+/// Hashes the effective settings as dotted keys and values, or None when the
+/// settings file can not be read
+fn settings_hash(config_path: &Path) -> Option<String> {
+    let entries = Settings::provenance(config_path).ok()?;
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        hasher.update(entry.key.as_bytes());
+        hasher.update(b"=");
+        hasher.update(entry.value.to_string().as_bytes());
+        hasher.update(b"\n");
+    }
+    let digest = hasher.finalize();
+    Some(
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect(),
+    )
+}
@@ -0,0 +1,113 @@
+//! A process wide pool of gRPC channels.
+//!
+//! The router, config, entropy and ingest services are often hosted behind
+//! the same host. Services that connect to the same scheme and authority share
+//! one lazily connected channel, and with it one HTTP/2 connection, instead of
+//! every service doing its own connect and TLS handshake. Channels reconnect
+//! by themselves when the connection drops. Connections are bound to the
+//! configured outbound interface or address.
+//!
+//! With keepalive configured, connections with an open stream are pinged so
+//! long lived streams like the router stream fail when the connection is dead
+//! instead of hanging until the next write times out.
+
+use crate::{
+    metrics,
+    service::{outbound, CONNECT_TIMEOUT, RPC_TIMEOUT},
+};
+use helium_proto::services::{Channel, Endpoint};
+use http::Uri;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Gauge of the number of pooled channels
+pub const POOLED_METRIC: &str = "grpc_channels_pooled";
+/// Counter of channel lookups, labeled with the authority and whether a pooled
+/// channel was "reused" or a new one "created"
+pub const LOOKUP_METRIC: &str = "grpc_channel_lookups_total";
+
+static POOL: Mutex<Option<HashMap<String, Channel>>> = Mutex::new(None);
+
+/// HTTP/2 keepalive pings of channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time between pings
+    pub interval: Duration,
+    /// Time to wait for a ping to be answered
+    pub timeout: Duration,
+}
+
+static KEEPALIVE: Mutex<Option<Keepalive>> = Mutex::new(None);
+
+/// Sets the keepalive of channels created from now on, None to not ping
+pub fn configure_keepalive(keepalive: Option<Keepalive>) {
+    *KEEPALIVE.lock().expect("keepalive lock") = keepalive;
+}
+
+/// The pool key of a uri. gRPC requests carry their own path so only the
+/// scheme and authority identify the connection.
+fn pool_key(uri: &Uri) -> String {
+    format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.authority().map_or("", |authority| authority.as_str())
+    )
+}
+
+/// Returns the pooled channel for the scheme and authority of the given uri,
+/// creating a lazily connected one when there is none yet
+pub fn channel(uri: &Uri) -> Channel {
+    let key = pool_key(uri);
+    let mut pool = POOL.lock().expect("channel pool lock");
+    let pool = pool.get_or_insert_with(HashMap::new);
+    let lookup = |result| {
+        metrics::increment(&metrics::labeled(
+            LOOKUP_METRIC,
+            &[("authority", &key), ("result", result)],
+        ))
+    };
+    if let Some(channel) = pool.get(&key) {
+        lookup("reused");
+        return channel.clone();
+    }
+    lookup("created");
+    let mut endpoint = Endpoint::from(uri.clone())
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(RPC_TIMEOUT);
+    if let Some(keepalive) = *KEEPALIVE.lock().expect("keepalive lock") {
+        // Only connections with open streams are pinged
+        endpoint = endpoint
+            .http2_keep_alive_interval(keepalive.interval)
+            .keep_alive_timeout(keepalive.timeout)
+            .keep_alive_while_idle(false);
+    }
+    let channel = endpoint.connect_with_connector_lazy(outbound::connector());
+    pool.insert(key, channel.clone());
+    metrics::set(POOLED_METRIC, pool.len() as f64);
+    channel
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let key = |uri: &'static str| pool_key(&Uri::from_static(uri));
+        assert_eq!(
+            key("http://mainnet-router.helium.io:8080/"),
+            key("http://mainnet-router.helium.io:8080")
+        );
+        assert_eq!(
+            "https://ingest.example.com",
+            key("https://ingest.example.com/some/path")
+        );
+        assert_ne!(
+            key("http://ingest.example.com"),
+            key("https://ingest.example.com")
+        );
+        assert_ne!(
+            key("http://ingest.example.com:9080"),
+            key("http://ingest.example.com:9081")
+        );
+    }
+}
@@ -1,30 +1,144 @@
 use crate::{
+    error::{DecodeError, EncodeError},
     impl_msg_sign,
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
-    KeyedUri, Keypair, MsgSign, Region, RegionParams, Result,
+    server::Handles,
+    service::{
+        channel,
+        data_usage::DataUsageHandle,
+        faults, is_transient,
+        quarantine::{self, QuarantineHandle},
+        retry_wait,
+        stats::ServiceStatsHandle,
+        RPC_RETRIES,
+    },
+    Base64, Error, KeyedUri, Keypair, MsgSign, Region, RegionParams, Result,
+};
+use helium_crypto::Sign;
+use helium_proto::{
+    services::{self, iot_config::GatewayRegionParamsReqV1, Channel},
+    Message,
+};
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use helium_proto::services::{self, iot_config::GatewayRegionParamsReqV1, Channel, Endpoint};
-use std::sync::Arc;
 
 type ConfigClient = services::iot_config::GatewayClient<Channel>;
 
 impl_msg_sign!(GatewayRegionParamsReqV1, signature);
 
+const REQUEST_NONCE_FILE: &str = "config_nonce.json";
+/// Request metadata carrying the request nonce. Doubles as the request id the
+/// service echoes in its response metadata.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const REQUEST_TIMESTAMP_HEADER: &str = "x-request-timestamp";
+/// Signature over the signed request, the nonce and the timestamp
+const REQUEST_SIGNATURE_HEADER: &str = "x-request-signature";
+
 #[derive(Debug, Clone)]
 pub struct ConfigService {
     pub uri: KeyedUri,
     client: ConfigClient,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
+    quarantine: QuarantineHandle,
+}
+
+/// Monotonic request nonces for config service requests, persisted in the data
+/// directory so they keep increasing across restarts and clock adjustments.
+#[derive(Debug)]
+pub struct RequestNonces {
+    /// The nonce file, None when nonces are not stored
+    path: Option<PathBuf>,
+    state: NonceState,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct NonceState {
+    /// The last nonce used
+    last: u64,
+}
+
+impl RequestNonces {
+    /// Load the last used nonce from the given data directory. A missing or
+    /// unreadable nonce file starts nonces from the current time.
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join(REQUEST_NONCE_FILE);
+        let state = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path: Some(path),
+            state,
+        }
+    }
+
+    /// Load the last used nonce from the given data directory without ever
+    /// storing nonces, for commands that run next to the gateway service.
+    pub fn read_only(data_dir: &Path) -> Self {
+        Self {
+            path: None,
+            ..Self::load(data_dir)
+        }
+    }
+
+    /// Returns the next nonce, at least the current time in milliseconds, and
+    /// stores it before it is used.
+    fn next(&mut self, now_millis: u64) -> u64 {
+        self.state.last = now_millis.max(self.state.last + 1);
+        self.save();
+        self.state.last
+    }
+
+    /// Checks the request id echoed in a response against the request nonce.
+    /// The echo is unsigned response metadata, so this only catches a
+    /// response to another request, it is no replay protection. Services
+    /// that do not echo request ids are accepted.
+    fn check_echo(nonce: u64, echo: Option<&str>) -> Result {
+        match echo {
+            Some(echo) if echo != nonce.to_string() => {
+                Err(Error::request_mismatch(nonce, echo.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stores the last nonce. A data directory that can not be written, like
+    /// a read-only filesystem, should not stop region updates, nonces then
+    /// only increase for as long as the process runs.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let write = || -> Result {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, serde_json::to_vec(&self.state)?)?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            warn!(
+                slog_scope::logger(),
+                "failed to store config request nonce: {err:?}"
+            );
+        }
+    }
 }
 
 impl ConfigService {
-    pub fn new(keyed_uri: &KeyedUri) -> Self {
-        let channel = Endpoint::from(keyed_uri.uri.clone())
-            .connect_timeout(CONNECT_TIMEOUT)
-            .timeout(RPC_TIMEOUT)
-            .connect_lazy();
+    pub fn new(keyed_uri: &KeyedUri, handles: &Handles) -> Self {
         Self {
             uri: keyed_uri.clone(),
-            client: ConfigClient::new(channel),
+            client: ConfigClient::new(channel::channel(&keyed_uri.uri)),
+            stats: handles.service_stats.clone(),
+            usage: handles.data_usage.clone(),
+            quarantine: handles.quarantine.clone(),
         }
     }
 
@@ -32,17 +146,111 @@ impl ConfigService {
         &mut self,
         default_region: Region,
         keypair: Arc<Keypair>,
+        nonces: &mut RequestNonces,
     ) -> Result<RegionParams> {
         let mut req = GatewayRegionParamsReqV1 {
             region: default_region.into(),
             address: keypair.public_key().to_vec(),
             signature: vec![],
         };
-        req.signature = req.sign(keypair).await?;
+        req.signature = req.sign(keypair.clone()).await?;
+        let sent = req.encoded_len();
 
-        let resp = self.client.region_params(req).await?.into_inner();
+        // Retries are signed with a fresh nonce so they are not rejected as
+        // replays of a request that did reach the service
+        let mut retry = 0;
+        let (resp, nonce) = loop {
+            let (request, nonce) = signed_request(req.clone(), keypair.clone(), nonces).await?;
+            let mut client = self.client.clone();
+            let result = self
+                .stats
+                .timed("config", "region_params", async {
+                    faults::inject("config").await?;
+                    client.region_params(request).await
+                })
+                .await;
+            match result {
+                Err(status) if retry < RPC_RETRIES && is_transient(&status) => {
+                    retry += 1;
+                    retry_wait(retry).await;
+                }
+                result => break (result?, nonce),
+            }
+        };
+        let echo = resp
+            .metadata()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok());
+        RequestNonces::check_echo(nonce, echo)?;
+        let mut resp = faults::corrupt("config", resp.into_inner())?;
+        self.usage.record("config", sent, resp.encoded_len());
+        let findings = quarantine::check_region_params(resp.region, &mut resp.params);
+        if self
+            .quarantine
+            .quarantine(&slog_scope::logger(), "config", &resp, findings)
+        {
+            return Err(DecodeError::quarantined("region params"));
+        }
         // TODO: re-enable when config service public prod key is established
         // resp.verify(&self.uri.pubkey)?;
         Ok(RegionParams::try_from(resp)?)
     }
 }
+
+/// Wraps a signed request with the next request nonce, the current time and a
+/// signature over both in the request metadata.
+async fn signed_request(
+    req: GatewayRegionParamsReqV1,
+    keypair: Arc<Keypair>,
+    nonces: &mut RequestNonces,
+) -> Result<(tonic::Request<GatewayRegionParamsReqV1>, u64)> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::from)?
+        .as_millis() as u64;
+    let nonce = nonces.next(timestamp);
+    let mut envelope = req.encode_to_vec();
+    envelope.extend_from_slice(&nonce.to_be_bytes());
+    envelope.extend_from_slice(&timestamp.to_be_bytes());
+    let signature = tokio::task::spawn_blocking(move || keypair.sign(&envelope)).await??;
+
+    let mut request = tonic::Request::new(req);
+    let metadata = request.metadata_mut();
+    for (key, value) in [
+        (REQUEST_ID_HEADER, nonce.to_string()),
+        (REQUEST_TIMESTAMP_HEADER, timestamp.to_string()),
+        (REQUEST_SIGNATURE_HEADER, signature.to_b64()),
+    ] {
+        let value = value.parse().map_err(|_| EncodeError::metadata(key))?;
+        metadata.insert(key, value);
+    }
+    Ok((request, nonce))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nonces() {
+        let dir = std::env::temp_dir().join(format!("config_nonce_{}", std::process::id()));
+        let mut nonces = RequestNonces::load(&dir);
+        assert_eq!(1000, nonces.next(1000));
+        // A clock going backwards still gives increasing nonces
+        assert_eq!(1001, nonces.next(500));
+
+        let mut nonces = RequestNonces::load(&dir);
+        assert_eq!(1002, nonces.next(900));
+        assert!(RequestNonces::check_echo(1002, Some("1001")).is_err());
+        assert!(RequestNonces::check_echo(1002, Some("1002")).is_ok());
+        assert!(RequestNonces::check_echo(1002, None).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+
+        // A data directory that can not be written does not fail requests
+        let file = std::env::temp_dir().join(format!("config_nonce_file_{}", std::process::id()));
+        fs::write(&file, b"").expect("file");
+        let mut nonces = RequestNonces::load(&file.join("data"));
+        assert_eq!(2000, nonces.next(2000));
+        let _ = fs::remove_file(file);
+    }
+}
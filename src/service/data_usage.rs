@@ -0,0 +1,304 @@
+//! Accounts for the data exchanged with upstream services.
+//!
+//! Services record the (encoded message) bytes they send and receive per
+//! upstream service. Totals are kept per calendar month (UTC), persisted in
+//! the data directory and can optionally be capped. When a cap is exceeded
+//! and enforced, non-join uplinks are no longer forwarded while PoC traffic
+//! (beacons and witnesses) keeps flowing.
+
+use crate::{metrics, status_file, Result, Settings};
+use serde::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+const DATA_USAGE_FILE: &str = "data_usage.json";
+/// How often the usage totals are written to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// The number of months of history to keep
+const MAX_MONTHS: usize = 12;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServiceUsage {
+    /// Bytes sent to the service
+    pub sent: u64,
+    /// Bytes received from the service
+    pub received: u64,
+}
+
+impl ServiceUsage {
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+}
+
+/// Usage per service for each month, keyed by "YYYY-MM"
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UsageHistory {
+    pub months: BTreeMap<String, BTreeMap<String, ServiceUsage>>,
+}
+
+impl UsageHistory {
+    /// Load the stored usage history. A missing file results in an empty
+    /// history.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        Ok(status_file::load(data_dir, DATA_USAGE_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, DATA_USAGE_FILE, self)
+    }
+
+    /// Total bytes exchanged with all services in the given month
+    pub fn month_total(&self, month: &str) -> u64 {
+        self.months
+            .get(month)
+            .map(|services| services.values().map(ServiceUsage::total).sum())
+            .unwrap_or(0)
+    }
+
+    fn record(&mut self, month: &str, service: &str, sent: u64, received: u64) {
+        let usage = self
+            .months
+            .entry(month.to_string())
+            .or_default()
+            .entry(service.to_string())
+            .or_default();
+        usage.sent += sent;
+        usage.received += received;
+        while self.months.len() > MAX_MONTHS {
+            let oldest = self.months.keys().next().cloned();
+            if let Some(oldest) = oldest {
+                self.months.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Accounting {
+    history: UsageHistory,
+    /// Monthly cap in bytes, when enforced
+    cap: Option<u64>,
+}
+
+/// Accounts the data exchanged with upstream services. Clones share the same
+/// totals.
+#[derive(Debug, Clone, Default)]
+pub struct DataUsageHandle(Arc<Mutex<Accounting>>);
+
+impl DataUsageHandle {
+    fn with_accounting<R>(&self, f: impl FnOnce(&mut Accounting) -> R) -> R {
+        f(&mut self.0.lock().expect("data usage lock"))
+    }
+
+    /// Records bytes sent to and received from the named upstream service
+    pub fn record(&self, service: &str, sent: usize, received: usize) {
+        let month = month_key(SystemTime::now());
+        self.with_accounting(|accounting| {
+            accounting
+                .history
+                .record(&month, service, sent as u64, received as u64)
+        });
+        for (direction, bytes) in [("sent", sent), ("received", received)] {
+            if bytes > 0 {
+                metrics::increment_by(
+                    &metrics::labeled(
+                        "data_usage_bytes",
+                        &[("service", service), ("direction", direction)],
+                    ),
+                    bytes as u64,
+                );
+            }
+        }
+    }
+
+    /// Whether the monthly cap is enforced and has been exceeded
+    pub fn over_cap(&self) -> bool {
+        let month = month_key(SystemTime::now());
+        self.with_accounting(|accounting| {
+            accounting
+                .cap
+                .is_some_and(|cap| accounting.history.month_total(&month) >= cap)
+        })
+    }
+
+    /// Returns a copy of the current in-process usage history
+    pub fn history(&self) -> UsageHistory {
+        self.with_accounting(|accounting| accounting.history.clone())
+    }
+
+    /// Returns the live usage counters of the running gateway
+    pub fn snapshot(&self) -> UsageSnapshot {
+        let (history, cap) =
+            self.with_accounting(|accounting| (accounting.history.clone(), accounting.cap));
+        UsageSnapshot::new(history, cap)
+    }
+}
+
+/// The live usage totals of the current month and the stored history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageSnapshot {
+    pub month: String,
+    pub total: u64,
+    /// Enforced monthly cap in bytes
+    pub cap: Option<u64>,
+    pub over_cap: bool,
+    pub months: BTreeMap<String, BTreeMap<String, ServiceUsage>>,
+}
+
+impl UsageSnapshot {
+    pub fn new(history: UsageHistory, cap: Option<u64>) -> Self {
+        let month = month_key(SystemTime::now());
+        let total = history.month_total(&month);
+        Self {
+            over_cap: cap.is_some_and(|cap| total >= cap),
+            month,
+            total,
+            cap,
+            months: history.months,
+        }
+    }
+}
+
+/// Loads persisted usage on start and periodically saves usage totals to the
+/// data directory.
+pub struct DataUsage {
+    usage: DataUsageHandle,
+    enabled: bool,
+    data_dir: PathBuf,
+    cap: Option<u64>,
+    enforce: bool,
+}
+
+impl DataUsage {
+    pub fn new(settings: &Settings, usage: DataUsageHandle) -> Self {
+        Self {
+            usage,
+            enabled: settings.data_usage.enabled,
+            data_dir: settings.data_dir.clone(),
+            cap: settings.data_usage.monthly_cap_mb.map(|mb| mb * 1_000_000),
+            enforce: settings.data_usage.enforce,
+        }
+    }
+
+    /// Whether usage is stored and capped, with `data_usage.enabled`
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        if !self.enabled {
+            return Ok(());
+        }
+        let logger = logger.new(o!("module" => "data_usage"));
+        match UsageHistory::load(&self.data_dir) {
+            Ok(stored) => self.usage.with_accounting(|accounting| {
+                // Merge anything recorded before the stored history was loaded
+                let recorded = std::mem::replace(&mut accounting.history, stored);
+                for (month, services) in recorded.months {
+                    for (service, usage) in services {
+                        accounting
+                            .history
+                            .record(&month, &service, usage.sent, usage.received);
+                    }
+                }
+            }),
+            Err(err) => warn!(logger, "failed to load data usage: {err:?}"),
+        }
+        self.usage
+            .with_accounting(|accounting| accounting.cap = self.cap.filter(|_| self.enforce));
+        info!(logger, "starting"; "cap" => self.cap, "enforce" => self.enforce);
+
+        let mut timer = time::interval(SAVE_INTERVAL);
+        let mut was_over_cap = false;
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    let _ = self.save(&logger);
+                    return Ok(())
+                },
+                _ = timer.tick() => {
+                    let _ = self.save(&logger);
+                    let month = month_key(SystemTime::now());
+                    let total = self.usage.history().month_total(&month);
+                    let over_cap = self.cap.is_some_and(|cap| total >= cap);
+                    if over_cap && !was_over_cap {
+                        warn!(logger, "monthly data cap exceeded";
+                            "month" => &month,
+                            "total" => total,
+                            "enforced" => self.enforce);
+                    }
+                    was_over_cap = over_cap;
+                }
+            }
+        }
+    }
+
+    fn save(&self, logger: &Logger) -> Result {
+        self.usage
+            .history()
+            .save(&self.data_dir)
+            .inspect_err(|err| warn!(logger, "failed to save data usage: {err:?}"))
+    }
+}
+
+/// The "YYYY-MM" (UTC) month key for the given time
+pub fn month_key(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+    let (year, month) = year_month_from_days(days);
+    format!("{year:04}-{month:02}")
+}
+
+/// The proleptic Gregorian (year, month) for days since the unix epoch
+fn year_month_from_days(days: i64) -> (i64, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn month_keys() {
+        assert_eq!("1970-01", month_key(UNIX_EPOCH));
+        // 2024-02-29T12:00:00Z
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_208_000);
+        assert_eq!("2024-02", month_key(leap_day));
+        // 2023-12-31T23:59:59Z
+        let new_years_eve = UNIX_EPOCH + Duration::from_secs(1_704_067_199);
+        assert_eq!("2023-12", month_key(new_years_eve));
+    }
+
+    #[test]
+    fn history_totals() {
+        let mut history = UsageHistory::default();
+        history.record("2024-01", "poc", 100, 10);
+        history.record("2024-01", "packet_router", 50, 5);
+        history.record("2024-02", "poc", 1, 1);
+        assert_eq!(165, history.month_total("2024-01"));
+        assert_eq!(2, history.month_total("2024-02"));
+        for month in 1..=MAX_MONTHS + 2 {
+            history.record(&format!("2025-{month:02}"), "poc", 1, 0);
+        }
+        assert_eq!(MAX_MONTHS, history.months.len());
+        assert_eq!(0, history.month_total("2024-01"));
+    }
+}
@@ -1,29 +1,52 @@
 use crate::{
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
+    server::Handles,
+    service::{
+        channel, data_usage::DataUsageHandle, faults, retry_transient, stats::ServiceStatsHandle,
+    },
     Result,
 };
 use beacon::Entropy;
-use helium_proto::services::{self, poc_entropy::EntropyReqV1, Channel, Endpoint};
+use helium_proto::services::{self, poc_entropy::EntropyReqV1, Channel};
+use helium_proto::Message;
 use http::Uri;
 
 type EntropyClient = helium_proto::services::poc_entropy::Client<Channel>;
 
 #[derive(Debug)]
-pub struct EntropyService(EntropyClient);
+pub struct EntropyService {
+    client: EntropyClient,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
+}
 
 impl EntropyService {
-    pub fn new(uri: Uri) -> Self {
-        let channel = Endpoint::from(uri)
-            .connect_timeout(CONNECT_TIMEOUT)
-            .timeout(RPC_TIMEOUT)
-            .connect_lazy();
-        let client = services::poc_entropy::Client::new(channel);
-        Self(client)
+    pub fn new(uri: Uri, handles: &Handles) -> Self {
+        let client = services::poc_entropy::Client::new(channel::channel(&uri));
+        Self {
+            client,
+            stats: handles.service_stats.clone(),
+            usage: handles.data_usage.clone(),
+        }
     }
 
     pub async fn get_entropy(&mut self) -> Result<Entropy> {
         let req = EntropyReqV1 {};
-        let resp = self.0.entropy(req).await?;
-        Ok(resp.into_inner().into())
+        let sent = req.encoded_len();
+        let resp = retry_transient(|| {
+            let mut client = self.client.clone();
+            let stats = self.stats.clone();
+            async move {
+                stats
+                    .timed("entropy", "entropy", async {
+                        faults::inject("entropy").await?;
+                        client.entropy(EntropyReqV1 {}).await
+                    })
+                    .await
+            }
+        })
+        .await?
+        .into_inner();
+        self.usage.record("entropy", sent, resp.encoded_len());
+        Ok(resp.into())
     }
 }
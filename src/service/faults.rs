@@ -0,0 +1,184 @@
+//! Fault injection for resilience tests.
+//!
+//! With the `fault-injection` feature the service clients can be made to drop
+//! connections, add latency and corrupt responses, per upstream service, to
+//! test reconnects, retries and uplink spooling against a real router or
+//! ingest service. Faults are set through the local REST API
+//! (`POST /v1/faults`) and are off at startup. Without the feature the hooks
+//! do nothing and are compiled away.
+//!
+//! Services are named as in the service stats: `packet_router`, `poc`,
+//! `config` and `entropy`.
+
+#[cfg(feature = "fault-injection")]
+pub use injection::*;
+
+#[cfg(feature = "fault-injection")]
+mod injection {
+    use crate::{metrics, Error, Result};
+    use helium_proto::Message;
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+    use tonic::Status;
+
+    /// Counter of injected faults labeled with the `service` and the `fault`
+    pub const INJECTED_METRIC: &str = "service_faults_injected_total";
+
+    /// The faults of one upstream service
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct Fault {
+        /// Percentage of requests that fail as if the connection dropped
+        pub drop_percent: u8,
+        /// Milliseconds added to every request
+        pub latency_ms: u64,
+        /// Percentage of responses with a flipped bit
+        pub corrupt_percent: u8,
+    }
+
+    impl Fault {
+        fn is_none(&self) -> bool {
+            *self == Self::default()
+        }
+    }
+
+    /// A change of the faults of a service. A fault without drops, latency
+    /// or corruption removes the faults of the service.
+    #[derive(Debug, Deserialize)]
+    pub struct FaultUpdate {
+        pub service: String,
+        #[serde(flatten)]
+        pub fault: Fault,
+    }
+
+    static FAULTS: Mutex<BTreeMap<String, Fault>> = Mutex::new(BTreeMap::new());
+
+    fn fault(service: &str) -> Option<Fault> {
+        FAULTS.lock().expect("faults lock").get(service).copied()
+    }
+
+    fn chance(percent: u8) -> bool {
+        percent > 0 && rand::thread_rng().gen_range(0..100) < percent
+    }
+
+    fn injected(service: &str, fault: &str) {
+        metrics::increment(&metrics::labeled(
+            INJECTED_METRIC,
+            &[("service", service), ("fault", fault)],
+        ));
+    }
+
+    /// Sets the faults of a service, returns the faults of all services
+    pub fn set(update: FaultUpdate) -> Result<BTreeMap<String, Fault>> {
+        let fault = update.fault;
+        if fault.drop_percent > 100 || fault.corrupt_percent > 100 {
+            return Err(Error::custom("fault percentages must be at most 100"));
+        }
+        let mut faults = FAULTS.lock().expect("faults lock");
+        if fault.is_none() {
+            faults.remove(&update.service);
+        } else {
+            faults.insert(update.service, fault);
+        }
+        Ok(faults.clone())
+    }
+
+    /// Returns the faults of all services
+    pub fn snapshot() -> BTreeMap<String, Fault> {
+        FAULTS.lock().expect("faults lock").clone()
+    }
+
+    /// Delays a request to the service and fails it as a dropped connection
+    /// when faults are set
+    pub async fn inject(service: &str) -> std::result::Result<(), Status> {
+        let Some(fault) = fault(service) else {
+            return Ok(());
+        };
+        if fault.latency_ms > 0 {
+            injected(service, "latency");
+            tokio::time::sleep(Duration::from_millis(fault.latency_ms)).await;
+        }
+        if chance(fault.drop_percent) {
+            injected(service, "drop");
+            return Err(Status::unavailable("injected connection drop"));
+        }
+        Ok(())
+    }
+
+    /// Returns the response of the service, with a flipped bit when faults
+    /// are set. A corrupted response may no longer decode.
+    pub fn corrupt<M: Message + Default>(service: &str, msg: M) -> Result<M> {
+        match fault(service) {
+            Some(fault) if chance(fault.corrupt_percent) => {
+                injected(service, "corrupt");
+                let mut data = msg.encode_to_vec();
+                if data.is_empty() {
+                    return Ok(msg);
+                }
+                let mut rng = rand::thread_rng();
+                let index = rng.gen_range(0..data.len());
+                data[index] ^= 1 << rng.gen_range(0..8);
+                Ok(M::decode(data.as_slice())?)
+            }
+            _ => Ok(msg),
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use helium_proto::services::router::PacketRouterPacketDownV1;
+
+        #[tokio::test]
+        async fn faults() {
+            let update = |fault| FaultUpdate {
+                service: "test".to_string(),
+                fault,
+            };
+            assert!(set(update(Fault {
+                drop_percent: 101,
+                ..Default::default()
+            }))
+            .is_err());
+            set(update(Fault {
+                drop_percent: 100,
+                corrupt_percent: 100,
+                ..Default::default()
+            }))
+            .expect("set");
+            assert!(inject("test").await.is_err());
+            assert!(inject("other").await.is_ok());
+            let downlink = PacketRouterPacketDownV1 {
+                payload: vec![0; 16],
+                ..Default::default()
+            };
+            if let Ok(corrupted) = corrupt("test", downlink.clone()) {
+                assert_ne!(downlink, corrupted);
+            }
+
+            set(update(Fault::default())).expect("clear");
+            assert!(snapshot().is_empty());
+            assert!(inject("test").await.is_ok());
+        }
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+mod disabled {
+    use crate::Result;
+    use tonic::Status;
+
+    #[inline]
+    pub async fn inject(_service: &str) -> std::result::Result<(), Status> {
+        Ok(())
+    }
+
+    #[inline]
+    pub fn corrupt<M>(_service: &str, msg: M) -> Result<M> {
+        Ok(msg)
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+pub use disabled::*;
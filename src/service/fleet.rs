@@ -0,0 +1,263 @@
+//! Outbound fleet control channel.
+//!
+//! When `fleet.uri` is set the gateway dials the fleet manager and opens the
+//! bidirectional `control` stream of the `helium.fleet.fleet_control` gRPC
+//! service. Since the gateway opens the connection, gateways behind NAT can be
+//! managed without exposing a port.
+//!
+//! The first message up is a hello signed with the gateway key, which the
+//! fleet manager uses to authenticate the gateway. The fleet manager then
+//! sends commands down and the gateway answers each command with a result
+//! carrying the command id. Only a restricted set of commands is accepted:
+//!
+//! * `info`: the gateway key, name, version and region as JSON
+//! * `reconnect`: reconnect to the packet router right away
+//! * `log_level`: sets the log level to the argument, like "debug", and
+//!   returns the previous level. Returns the current level without an
+//!   argument.
+//! * `region_refresh`: fetch region params right away
+
+use crate::{
+    impl_msg_sign, logging, packet_router,
+    region_watcher::{self, RegionRefresh},
+    service::{outbound, until_shutdown, CONNECT_TIMEOUT},
+    settings::{self, Settings},
+    Error, Keypair, MsgSign, Result,
+};
+use angry_purple_tiger::AnimalName;
+use helium_proto::services::Endpoint;
+use http::{uri::PathAndQuery, Uri};
+use serde_json::json;
+use slog::{info, o, warn, Logger};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, time};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::ProstCodec;
+
+const CONTROL_PATH: &str = "/helium.fleet.fleet_control/control";
+
+/// The number of results that can be waiting to be sent to the fleet manager
+const RESULT_QUEUE_SIZE: usize = 10;
+
+/// Authenticates the gateway to the fleet manager. The signature covers the
+/// message with an empty signature.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ControlHelloV1 {
+    #[prost(bytes = "vec", tag = "1")]
+    pub gateway: Vec<u8>,
+    /// Unix time in milliseconds
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(string, tag = "3")]
+    pub version: String,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: Vec<u8>,
+}
+
+/// A message from the gateway to the fleet manager
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ControlUpV1 {
+    #[prost(message, optional, tag = "1")]
+    pub hello: Option<ControlHelloV1>,
+    #[prost(message, optional, tag = "2")]
+    pub result: Option<ControlResultV1>,
+}
+
+/// A command from the fleet manager
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ControlDownV1 {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub command: String,
+    #[prost(string, tag = "3")]
+    pub arg: String,
+}
+
+/// The result of a command
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ControlResultV1 {
+    /// The id of the command
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(bool, tag = "2")]
+    pub ok: bool,
+    /// The JSON result of the command, or the error message when the command
+    /// failed
+    #[prost(string, tag = "3")]
+    pub result: String,
+}
+
+impl_msg_sign!(ControlHelloV1, signature);
+
+pub struct FleetAgent {
+    uri: Option<String>,
+    reconnect_interval: Duration,
+    keypair: Arc<Keypair>,
+    region_watch: region_watcher::MessageReceiver,
+    router: packet_router::MessageSender,
+    region_refresh: RegionRefresh,
+}
+
+impl FleetAgent {
+    pub fn new(
+        settings: &Settings,
+        region_watch: region_watcher::MessageReceiver,
+        router: packet_router::MessageSender,
+        region_refresh: RegionRefresh,
+    ) -> Self {
+        Self {
+            uri: settings.fleet.uri.clone(),
+            reconnect_interval: Duration::from_secs(settings.fleet.reconnect_interval),
+            keypair: settings.keypair.clone(),
+            region_watch,
+            router,
+            region_refresh,
+        }
+    }
+
+    /// Whether a fleet management uri is configured
+    pub fn is_enabled(&self) -> bool {
+        self.uri.is_some()
+    }
+
+    pub async fn run(&self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let uri: Uri = match &self.uri {
+            Some(uri) => uri.parse()?,
+            None => return Ok(()),
+        };
+        let logger = logger.new(o!("module" => "fleet", "uri" => uri.to_string()));
+        info!(logger, "starting");
+
+        loop {
+            match until_shutdown(shutdown, self.session(&uri, &logger)).await {
+                None => {
+                    info!(logger, "shutting down");
+                    return Ok(());
+                }
+                Some(Ok(())) => info!(logger, "control channel closed"),
+                Some(Err(err)) => {
+                    warn!(logger, "control channel error: {err:?}"; "code" => err.code())
+                }
+            }
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = time::sleep(self.reconnect_interval) => (),
+            }
+        }
+    }
+
+    async fn session(&self, uri: &Uri, logger: &Logger) -> Result {
+        let channel = Endpoint::from(uri.clone())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .connect_with_connector_lazy(outbound::connector());
+        let mut client = tonic::client::Grpc::new(channel);
+        client.ready().await.map_err(Error::custom)?;
+
+        let (tx, rx) = mpsc::channel(RESULT_QUEUE_SIZE);
+        let hello = ControlUpV1 {
+            hello: Some(self.hello().await?),
+            result: None,
+        };
+        tx.send(hello).await.map_err(Error::custom)?;
+        let mut commands = client
+            .streaming(
+                tonic::Request::new(ReceiverStream::new(rx)),
+                PathAndQuery::from_static(CONTROL_PATH),
+                ProstCodec::<ControlUpV1, ControlDownV1>::default(),
+            )
+            .await?
+            .into_inner();
+        info!(logger, "control channel connected");
+
+        while let Some(command) = commands.message().await? {
+            let result = ControlUpV1 {
+                hello: None,
+                result: Some(self.execute(logger, command).await),
+            };
+            tx.send(result).await.map_err(Error::custom)?;
+        }
+        Ok(())
+    }
+
+    async fn hello(&self) -> Result<ControlHelloV1> {
+        let mut hello = ControlHelloV1 {
+            gateway: self.keypair.public_key().into(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(Error::from)?
+                .as_millis() as u64,
+            version: settings::version().to_string(),
+            signature: vec![],
+        };
+        hello.signature = hello.sign(self.keypair.clone()).await?;
+        Ok(hello)
+    }
+
+    async fn execute(&self, logger: &Logger, command: ControlDownV1) -> ControlResultV1 {
+        info!(logger, "executing command";
+            "id" => command.id,
+            "command" => &command.command,
+            "arg" => &command.arg,
+        );
+        match self.command(&command.command, &command.arg).await {
+            Ok(result) => ControlResultV1 {
+                id: command.id,
+                ok: true,
+                result: result.to_string(),
+            },
+            Err(err) => {
+                warn!(logger, "command failed: {err}";
+                    "id" => command.id,
+                    "command" => &command.command,
+                );
+                ControlResultV1 {
+                    id: command.id,
+                    ok: false,
+                    result: err.to_string(),
+                }
+            }
+        }
+    }
+
+    async fn command(&self, command: &str, arg: &str) -> Result<serde_json::Value> {
+        match command {
+            "info" => {
+                let public_key = self.keypair.public_key().to_string();
+                let name = public_key
+                    .parse::<AnimalName>()
+                    .map(|name| name.to_string())
+                    .unwrap_or_default();
+                let region = region_watcher::current_value(&self.region_watch).region;
+                Ok(json!({
+                    "key": public_key,
+                    "name": name,
+                    "version": settings::version().to_string(),
+                    "region": region.to_string(),
+                }))
+            }
+            "reconnect" => {
+                self.router.reconnect().await;
+                Ok(serde_json::Value::Null)
+            }
+            "log_level" if arg.is_empty() => Ok(json!(logging::level::get().as_str())),
+            "log_level" => {
+                let level = arg
+                    .parse::<slog::Level>()
+                    .map_err(|_| Error::custom(format!("invalid log level {arg}")))?;
+                Ok(json!(logging::level::set(level).as_str()))
+            }
+            "region_refresh" => {
+                self.region_refresh.refresh();
+                Ok(serde_json::Value::Null)
+            }
+            other => Err(Error::custom(format!("unsupported command {other}"))),
+        }
+    }
+}
@@ -1,11 +1,10 @@
 use crate::{
-    impl_msg_sign,
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
-    Error, KeyedUri, Keypair, MsgSign, MsgVerify, PublicKey, Region, RegionParams, Result,
+    impl_msg_sign, service::channel, Error, KeyedUri, Keypair, MsgSign, MsgVerify, PublicKey,
+    Region, RegionParams, Result,
 };
 use helium_proto::{
     gateway_resp_v1,
-    services::{self, Channel, Endpoint},
+    services::{self, Channel},
     BlockchainVarV1, GatewayConfigReqV1, GatewayConfigRespV1, GatewayRegionParamsReqV1,
     GatewayRegionParamsUpdateReqV1, GatewayRespV1, GatewayRoutingReqV1, GatewayScIsActiveReqV1,
     GatewayScIsActiveRespV1, GatewayValidatorsReqV1, GatewayValidatorsRespV1, GatewayVersionReqV1,
@@ -91,13 +90,9 @@ pub struct GatewayService {
 
 impl GatewayService {
     pub fn new(keyed_uri: &KeyedUri) -> Result<Self> {
-        let channel = Endpoint::from(keyed_uri.uri.clone())
-            .connect_timeout(CONNECT_TIMEOUT)
-            .timeout(RPC_TIMEOUT)
-            .connect_lazy();
         Ok(Self {
             uri: keyed_uri.clone(),
-            client: GatewayClient::new(channel),
+            client: GatewayClient::new(channel::channel(&keyed_uri.uri)),
         })
     }
 
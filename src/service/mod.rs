@@ -1,11 +1,95 @@
-use std::time::Duration;
+use rand::Rng;
+use std::{future::Future, time::Duration};
+use tonic::{Code, Status};
 
 pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 pub const RPC_TIMEOUT: Duration = Duration::from_secs(5);
 
+pub mod channel;
 pub mod config;
+pub mod data_usage;
 pub mod entropy;
+pub mod faults;
+pub mod fleet;
 pub mod gateway;
+pub mod modem;
+pub mod outbound;
 pub mod packet_router;
 pub mod poc;
+pub mod quarantine;
+pub mod region_history;
+pub mod region_http;
 pub mod router;
+pub mod stats;
+#[cfg(feature = "updater")]
+pub mod updater;
+
+/// The number of times an idempotent request is retried on a transient status
+pub const RPC_RETRIES: u32 = 3;
+/// The base wait before a retry, doubled for every retry and jittered by up to
+/// the same amount
+const RPC_RETRY_WAIT: Duration = Duration::from_millis(250);
+
+/// Whether a status is likely to be resolved by retrying the same request
+pub fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted
+    )
+}
+
+/// Waits before the given (1 based) retry of a request
+pub async fn retry_wait(retry: u32) {
+    let wait = RPC_RETRY_WAIT * 2u32.saturating_pow(retry.saturating_sub(1));
+    let jitter = rand::thread_rng().gen_range(0..=RPC_RETRY_WAIT.as_millis() as u64);
+    tokio::time::sleep(wait + Duration::from_millis(jitter)).await
+}
+
+/// Runs an idempotent request, retrying it up to [`RPC_RETRIES`] times when it
+/// fails with a transient status. The last status is returned when all
+/// attempts fail.
+pub async fn retry_transient<T, F, Fut>(mut request: F) -> std::result::Result<T, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, Status>>,
+{
+    let mut retry = 0;
+    loop {
+        match request().await {
+            Err(status) if retry < RPC_RETRIES && is_transient(&status) => {
+                retry += 1;
+                retry_wait(retry).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs the given (connect or request) future unless shutdown is triggered
+/// first, in which case the future is dropped and None is returned. Use this
+/// for work done in response to a select branch so a slow connect does not
+/// delay process exit.
+pub async fn until_shutdown<F: Future>(
+    shutdown: &triggered::Listener,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        _ = shutdown.clone() => None,
+        output = future => Some(output),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transient_statuses() {
+        assert!(is_transient(&Status::unavailable("down")));
+        assert!(is_transient(&Status::deadline_exceeded("slow")));
+        assert!(is_transient(&Status::resource_exhausted("busy")));
+        assert!(!is_transient(&Status::invalid_argument("bad")));
+        assert!(!is_transient(&Status::permission_denied("denied")));
+    }
+}
@@ -0,0 +1,284 @@
+//! Reports cellular modem signal quality and data usage.
+//!
+//! Signal quality is read either through ModemManager (`mmcli`) or by sending
+//! `AT+CSQ` directly to a modem AT port. Data usage is read from the kernel
+//! statistics of the modem network interface. The latest readings are exposed
+//! as metrics and stored in the data directory for the `info` command.
+
+use crate::{
+    clock::unix_now, metrics, settings::ModemSource, status_file, Error, Result, Settings,
+};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{process::Command, task, time};
+
+const MODEM_STATUS_FILE: &str = "modem.json";
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The latest modem readings. Values the modem or source does not report are
+/// left empty.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModemStatus {
+    /// Received signal strength in dBm
+    pub rssi: Option<f64>,
+    /// LTE reference signal received power in dBm
+    pub rsrp: Option<f64>,
+    /// LTE reference signal received quality in dB
+    pub rsrq: Option<f64>,
+    /// Signal to noise ratio in dB
+    pub snr: Option<f64>,
+    /// Bytes received on the modem interface since it came up
+    pub rx_bytes: Option<u64>,
+    /// Bytes transmitted on the modem interface since it came up
+    pub tx_bytes: Option<u64>,
+    /// Unix time (seconds) of the readings
+    pub updated: u64,
+}
+
+impl ModemStatus {
+    /// Load the last stored modem status. Returns None when no modem readings
+    /// were ever stored.
+    pub fn load(data_dir: &Path) -> Result<Option<Self>> {
+        status_file::load(data_dir, MODEM_STATUS_FILE)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result {
+        status_file::save(data_dir, MODEM_STATUS_FILE, self)
+    }
+
+    fn set_metrics(&self) {
+        let gauges = [
+            ("modem_rssi_dbm", self.rssi),
+            ("modem_rsrp_dbm", self.rsrp),
+            ("modem_rsrq_db", self.rsrq),
+            ("modem_snr_db", self.snr),
+            ("modem_rx_bytes", self.rx_bytes.map(|v| v as f64)),
+            ("modem_tx_bytes", self.tx_bytes.map(|v| v as f64)),
+        ];
+        for (name, value) in gauges {
+            if let Some(value) = value {
+                metrics::set(name, value);
+            }
+        }
+    }
+}
+
+pub struct ModemReader {
+    source: Option<ModemSource>,
+    modem: u32,
+    device: Option<PathBuf>,
+    interface: Option<String>,
+    interval: Duration,
+    data_dir: PathBuf,
+    /// Set while a blocking AT port read is in progress
+    at_busy: Arc<AtomicBool>,
+}
+
+impl ModemReader {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            source: settings.modem.source,
+            modem: settings.modem.modem,
+            device: settings.modem.device.clone(),
+            interface: settings.modem.interface.clone(),
+            interval: Duration::from_secs(settings.modem.interval),
+            data_dir: settings.data_dir.clone(),
+            at_busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether a modem source is configured
+    pub fn is_enabled(&self) -> bool {
+        self.source.is_some()
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let source = match self.source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        let logger = logger.new(o!("module" => "modem"));
+        info!(logger, "starting"; "source" => format!("{source:?}"));
+        if source == ModemSource::Mmcli {
+            // Signal readings are only refreshed by ModemManager when enabled
+            if let Err(err) = self
+                .mmcli(&["--signal-setup", &self.interval.as_secs().to_string()])
+                .await
+            {
+                warn!(logger, "failed to enable modem signal refresh: {err:?}");
+            }
+        }
+
+        let mut timer = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = timer.tick() => match self.read(source).await {
+                    Ok(status) => {
+                        debug!(logger, "modem status {status:?}");
+                        status.set_metrics();
+                        if let Err(err) = status.save(&self.data_dir) {
+                            warn!(logger, "failed to store modem status: {err:?}");
+                        }
+                    }
+                    Err(err) => warn!(logger, "failed to read modem status: {err:?}"),
+                }
+            }
+        }
+    }
+
+    async fn read(&self, source: ModemSource) -> Result<ModemStatus> {
+        let mut status = match source {
+            ModemSource::Mmcli => parse_mmcli_signal(&self.mmcli(&["--signal-get"]).await?),
+            ModemSource::At => self.read_at().await?,
+        };
+        if let Some(interface) = &self.interface {
+            status.rx_bytes = read_interface_stat(interface, "rx_bytes");
+            status.tx_bytes = read_interface_stat(interface, "tx_bytes");
+        }
+        status.updated = unix_now();
+        Ok(status)
+    }
+
+    async fn mmcli(&self, args: &[&str]) -> Result<String> {
+        let output = time::timeout(
+            READ_TIMEOUT,
+            Command::new("mmcli")
+                .arg("-m")
+                .arg(self.modem.to_string())
+                .args(args)
+                .arg("--output-keyvalue")
+                .output(),
+        )
+        .await
+        .map_err(|_| Error::custom("mmcli timeout"))??;
+        if !output.status.success() {
+            return Err(Error::custom(format!(
+                "mmcli failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    async fn read_at(&self) -> Result<ModemStatus> {
+        let device = self
+            .device
+            .clone()
+            .ok_or_else(|| Error::custom("no modem AT device configured"))?;
+        // A modem that does not answer blocks the read, so only one read is
+        // allowed to be outstanding at a time
+        if self.at_busy.swap(true, Ordering::SeqCst) {
+            return Err(Error::custom("previous AT read still pending"));
+        }
+        let busy = self.at_busy.clone();
+        let read = task::spawn_blocking(move || {
+            let result = at_command(&device, "AT+CSQ");
+            busy.store(false, Ordering::SeqCst);
+            result
+        });
+        let response = time::timeout(READ_TIMEOUT, read)
+            .await
+            .map_err(|_| Error::custom("AT read timeout"))?
+            .map_err(|_| Error::custom("AT read task failed"))??;
+        Ok(ModemStatus {
+            rssi: parse_csq(&response),
+            ..Default::default()
+        })
+    }
+}
+
+fn at_command(device: &Path, command: &str) -> Result<String> {
+    let mut port = fs::OpenOptions::new().read(true).write(true).open(device)?;
+    port.write_all(format!("{command}\r").as_bytes())?;
+    let mut response = String::new();
+    let mut buf = [0u8; 256];
+    while !(response.contains("OK") || response.contains("ERROR")) {
+        let n = port.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        response.push_str(&String::from_utf8_lossy(&buf[..n]));
+    }
+    Ok(response)
+}
+
+fn read_interface_stat(interface: &str, stat: &str) -> Option<u64> {
+    fs::read_to_string(format!("/sys/class/net/{interface}/statistics/{stat}"))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Parses the output of `mmcli --signal-get --output-keyvalue`. The first
+/// access technology (lte, umts, gsm) reporting a value wins.
+fn parse_mmcli_signal(output: &str) -> ModemStatus {
+    let value = |key: &str| {
+        ["lte", "5g", "umts", "gsm"].iter().find_map(|tech| {
+            let full_key = format!("modem.signal.{tech}.{key}");
+            output.lines().find_map(|line| {
+                let (k, v) = line.split_once(':')?;
+                (k.trim() == full_key)
+                    .then(|| v.trim().parse::<f64>().ok())
+                    .flatten()
+            })
+        })
+    };
+    ModemStatus {
+        rssi: value("rssi"),
+        rsrp: value("rsrp"),
+        rsrq: value("rsrq"),
+        snr: value("snr"),
+        ..Default::default()
+    }
+}
+
+/// Parses a `+CSQ: <rssi>,<ber>` response into dBm. An rssi index of 99 means
+/// the signal is unknown.
+fn parse_csq(response: &str) -> Option<f64> {
+    let (_, values) = response.split_once("+CSQ:")?;
+    let index: u32 = values.split(',').next()?.trim().parse().ok()?;
+    (index <= 31).then_some(-113.0 + 2.0 * index as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mmcli_signal() {
+        let output = "modem.signal.refresh.rate                  : 60\n\
+                      modem.signal.lte.rssi                      : -71.00\n\
+                      modem.signal.lte.rsrq                      : -11.00\n\
+                      modem.signal.lte.rsrp                      : -99.00\n\
+                      modem.signal.lte.snr                       : 8.20\n\
+                      modem.signal.umts.rssi                     : --\n";
+        let status = parse_mmcli_signal(output);
+        assert_eq!(Some(-71.0), status.rssi);
+        assert_eq!(Some(-99.0), status.rsrp);
+        assert_eq!(Some(-11.0), status.rsrq);
+        assert_eq!(Some(8.2), status.snr);
+    }
+
+    #[test]
+    fn csq() {
+        assert_eq!(
+            Some(-83.0),
+            parse_csq("AT+CSQ\r\r\n+CSQ: 15,99\r\n\r\nOK\r\n")
+        );
+        assert_eq!(None, parse_csq("+CSQ: 99,99\r\nOK"));
+        assert_eq!(None, parse_csq("ERROR"));
+    }
+}
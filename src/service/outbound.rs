@@ -0,0 +1,151 @@
+//! Binding of outbound connections to a network interface or source address.
+//!
+//! On multi-homed gateways, like ones with both LTE and Ethernet, the default
+//! route can send service traffic out the wrong link. With an `[outbound]`
+//! interface or address configured, the connections of the gRPC service
+//! channels and the HTTP clients are bound to it before connecting. The
+//! packet forwarder and cluster UDP sockets are bound through their own
+//! listen addresses.
+
+use crate::{settings::OutboundSettings, Error, Result};
+use http::Uri;
+#[cfg(feature = "https")]
+use hyper_rustls::HttpsConnector;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tower_service::Service;
+
+static BINDING: Mutex<Option<Binding>> = Mutex::new(None);
+
+/// Where outbound connections are bound to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Binding {
+    /// The network interface, like "wwan0"
+    pub interface: Option<String>,
+    /// The source address
+    pub address: Option<IpAddr>,
+}
+
+/// Configures the binding of connections made after this call. Binding to an
+/// interface is only supported on Linux.
+pub fn configure(settings: &OutboundSettings) -> Result {
+    if settings.interface.is_some()
+        && !cfg!(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "linux"
+        ))
+    {
+        return Err(Error::custom(
+            "outbound.interface is not supported on this platform",
+        ));
+    }
+    *BINDING.lock().expect("outbound binding lock") = Some(Binding {
+        interface: settings.interface.clone(),
+        address: settings.address,
+    });
+    Ok(())
+}
+
+/// The configured binding
+pub fn binding() -> Binding {
+    BINDING
+        .lock()
+        .expect("outbound binding lock")
+        .clone()
+        .unwrap_or_default()
+}
+
+/// A connector for gRPC channels and HTTP clients that binds its TCP
+/// connections to the configured interface and source address
+#[derive(Debug, Clone, Default)]
+pub struct Connector(Binding);
+
+/// A connector using the configured binding
+pub fn connector() -> Connector {
+    Connector(binding())
+}
+
+/// An HTTP and HTTPS connector using the configured binding
+#[cfg(feature = "https")]
+pub fn https_connector() -> HttpsConnector<Connector> {
+    hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(connector())
+}
+
+impl Connector {
+    async fn connect(self, uri: Uri) -> io::Result<TcpStream> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "uri without host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        let mut last_err = None;
+        for addr in lookup_host((host, port)).await? {
+            // A source address can only reach destinations of its own family
+            if let Some(address) = self.0.address {
+                if address.is_ipv4() != addr.is_ipv4() {
+                    continue;
+                }
+            }
+            match self.0.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no address of {host} matches the outbound address"),
+            )
+        }))
+    }
+}
+
+impl Binding {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(address) = self.address {
+            socket.bind(SocketAddr::new(address, 0))?;
+        }
+        let stream = socket.connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
@@ -1,30 +1,44 @@
 use std::{
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    error::DecodeError,
-    impl_msg_sign,
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
+    error::{DecodeError, ServiceError},
+    impl_msg_sign, metrics,
+    queues::{ConduitPolicy, QueueSizes, QueuesHandle},
+    server::Handles,
+    service::{
+        channel, data_usage::DataUsageHandle, faults, stats::ServiceStatsHandle, CONNECT_TIMEOUT,
+    },
     Error, Keypair, MsgSign, Result,
 };
 
-use helium_proto::services::{
-    router::{
-        envelope_down_v1, envelope_up_v1, EnvelopeDownV1, EnvelopeUpV1, PacketRouterClient,
-        PacketRouterPacketDownV1, PacketRouterPacketUpV1, PacketRouterRegisterV1,
+use helium_proto::{
+    services::{
+        router::{
+            envelope_down_v1, envelope_up_v1, EnvelopeDownV1, EnvelopeUpV1, PacketRouterClient,
+            PacketRouterPacketDownV1, PacketRouterPacketUpV1, PacketRouterRegisterV1,
+        },
+        Channel,
     },
-    Channel, Endpoint,
+    Message,
 };
 
+use exponential_backoff::Backoff;
+use futures::{
+    stream::{self, PollNext},
+    StreamExt,
+};
 use http::Uri;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
 use tokio_stream::wrappers::ReceiverStream;
 
 type PacketClient = PacketRouterClient<Channel>;
 
-type PacketSender = mpsc::Sender<EnvelopeUpV1>;
 type PacketReceiver = tonic::Streaming<EnvelopeDownV1>;
 
 impl_msg_sign!(PacketRouterRegisterV1, signature);
@@ -34,11 +48,55 @@ impl_msg_sign!(PacketRouterPacketUpV1, signature);
 // router. The service will connect when (re)connect or a packet send is
 // attempted. It will ensure that the register rpc is called on the constructed
 // connection before a packet is sent.
+//
+// With fallback endpoints the service fails over to the next endpoint in
+// priority order when a connect or the stream fails, and tries the primary
+// endpoint again once it was on a fallback for the failback interval.
+//
+// While disconnected a receive reconnects on its own with an exponential
+// backoff, so downlinks are received again without waiting for an uplink to
+// be sent. With a heartbeat interval the service registers again when no
+// uplink was sent for that long, so a broken stream fails while idle.
+//
+// Uplinks are signed with the gateway keypair. Signing them with a session
+// key negotiated with the router needs the packet router session messages,
+// which the locked helium-proto revision does not have.
 #[derive(Debug)]
 pub struct PacketRouterService {
-    pub uri: Uri,
+    /// The router endpoints, the primary first
+    uris: Vec<Uri>,
+    /// The index of the endpoint connected to, or tried first on the next
+    /// connect
+    active: usize,
+    failback_interval: Duration,
+    /// The time to try the primary endpoint again while connected to a
+    /// fallback
+    failback_at: Option<Instant>,
+    heartbeat_interval: Duration,
+    /// The time of the next heartbeat while connected
+    heartbeat_at: Option<Instant>,
+    /// The time of the next connect attempt of a receive while disconnected
+    recv_reconnect_at: Option<Instant>,
+    /// The number of failed connect attempts of receives since the last
+    /// connect
+    recv_reconnect_retry: u32,
     conduit: Option<PacketRouterConduit>,
     keypair: Arc<Keypair>,
+    /// The gauge set while the conduit is connected
+    connected_metric: &'static str,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
+    /// The conduit size and policy of the next connect
+    queues: QueuesHandle,
+}
+
+/// What a receive on the router service got
+#[derive(Debug)]
+pub enum Received {
+    /// The receive connected the disconnected service. Uplinks that waited
+    /// for the router can be sent now.
+    Connected,
+    Downlink(PacketRouterPacketDownV1),
 }
 
 /// A router conduit is the tx/rx stream pair for the `route` rpc on the
@@ -48,41 +106,169 @@ pub struct PacketRouterService {
 struct PacketRouterConduit {
     tx: PacketSender,
     rx: PacketReceiver,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
 }
 
-pub const CONDUIT_CAPACITY: usize = 50;
+const DATA_USAGE_SERVICE: &str = "packet_router";
+
+const RECV_RECONNECT_RETRIES: u32 = 10;
+const RECV_RECONNECT_MIN_WAIT: Duration = Duration::from_secs(5);
+const RECV_RECONNECT_MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Gauge which is 1 while a router conduit is connected and 0 otherwise
+pub const CONNECTED_METRIC: &str = "router_connected";
+/// Gauge which is 1 while connected to a router endpoint, labeled with the
+/// endpoint `uri`. Only set with fallback endpoints.
+pub const ENDPOINT_CONNECTED_METRIC: &str = "router_endpoint_connected";
+/// Counter of failed connects to a router endpoint, labeled with the
+/// endpoint `uri`. Only set with fallback endpoints.
+pub const ENDPOINT_FAILURES_METRIC: &str = "router_endpoint_connect_failures_total";
+/// Counter of switches to another router endpoint after an error
+pub const FAILOVERS_METRIC: &str = "router_failovers_total";
+/// Counter of heartbeats sent on an idle router stream
+pub const HEARTBEATS_METRIC: &str = "router_heartbeats_total";
+/// Counter of sends which waited for room in a full conduit
+pub const CONDUIT_BLOCKED_METRIC: &str = "router_conduit_blocked_total";
+/// Counter of uplinks dropped from a full conduit
+pub const CONDUIT_DROPPED_METRIC: &str = "router_conduit_dropped_total";
+
+/// The number of register messages that can wait to be sent with the drop
+/// oldest policy
+const CONTROL_CAPACITY: usize = 4;
+
+/// The sending half of the conduit stream. With the drop oldest policy
+/// uplinks are fed by a broadcast channel, which overwrites the oldest message
+/// when full. Register messages are never dropped, they go on a separate
+/// channel which the stream takes from first.
+#[derive(Debug)]
+enum PacketSender {
+    Block(mpsc::Sender<EnvelopeUpV1>),
+    DropOldest {
+        control: mpsc::Sender<EnvelopeUpV1>,
+        uplinks: broadcast::Sender<EnvelopeUpV1>,
+    },
+}
+
+impl PacketSender {
+    /// Returns the sender and the stream to pass to the `route` rpc
+    fn new(
+        capacity: usize,
+        policy: ConduitPolicy,
+    ) -> (Self, stream::BoxStream<'static, EnvelopeUpV1>) {
+        match policy {
+            ConduitPolicy::Block => {
+                let (tx, rx) = mpsc::channel(capacity);
+                (Self::Block(tx), ReceiverStream::new(rx).boxed())
+            }
+            ConduitPolicy::DropOldest => {
+                let (control, control_rx) = mpsc::channel(CONTROL_CAPACITY);
+                let (uplinks, rx) = broadcast::channel(capacity);
+                let rx = stream::unfold(rx, |mut rx| async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok(msg) => return Some((msg, rx)),
+                            Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                                metrics::increment_by(CONDUIT_DROPPED_METRIC, dropped)
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                });
+                let rx = stream::select_with_strategy(
+                    ReceiverStream::new(control_rx),
+                    rx,
+                    |_: &mut ()| PollNext::Left,
+                );
+                (Self::DropOldest { control, uplinks }, rx.boxed())
+            }
+        }
+    }
+
+    /// Sends an uplink, which is dropped with the drop oldest policy when the
+    /// conduit is full
+    async fn send(&self, msg: EnvelopeUpV1) -> Result {
+        match self {
+            Self::Block(tx) => match tx.try_send(msg) {
+                Ok(()) => Ok(()),
+                Err(mpsc::error::TrySendError::Full(msg)) => {
+                    metrics::increment(CONDUIT_BLOCKED_METRIC);
+                    Ok(tx.send(msg).await?)
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    Err(Error::Service(ServiceError::Stream))
+                }
+            },
+            // Only fails when the stream was dropped
+            Self::DropOldest { uplinks, .. } => match uplinks.send(msg) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(Error::Service(ServiceError::Stream)),
+            },
+        }
+    }
+
+    /// Sends a register message, which waits for room rather than being
+    /// dropped and is sent ahead of waiting uplinks
+    async fn send_control(&self, msg: EnvelopeUpV1) -> Result {
+        match self {
+            Self::Block(_) => self.send(msg).await,
+            Self::DropOldest { control, .. } => control
+                .send(msg)
+                .await
+                .map_err(|_| Error::Service(ServiceError::Stream)),
+        }
+    }
+}
 
 impl PacketRouterConduit {
-    async fn new(uri: Uri) -> Result<Self> {
-        let endpoint = Endpoint::from(uri)
-            .timeout(RPC_TIMEOUT)
-            .connect_timeout(CONNECT_TIMEOUT)
-            .connect_lazy();
-        let mut client = PacketClient::new(endpoint);
-        let (tx, client_rx) = mpsc::channel(CONDUIT_CAPACITY);
-        let rx = client
-            .route(ReceiverStream::new(client_rx))
+    async fn new(
+        uri: Uri,
+        sizes: QueueSizes,
+        stats: ServiceStatsHandle,
+        usage: DataUsageHandle,
+    ) -> Result<Self> {
+        let mut client = PacketClient::new(channel::channel(&uri));
+        let (tx, client_rx) = PacketSender::new(sizes.conduit, sizes.conduit_policy);
+        let rx = stats
+            .timed(DATA_USAGE_SERVICE, "connect", client.route(client_rx))
             .await?
             .into_inner();
-        Ok(Self { tx, rx })
+        Ok(Self {
+            tx,
+            rx,
+            stats,
+            usage,
+        })
     }
 
     async fn recv(&mut self) -> Result<Option<PacketRouterPacketDownV1>> {
         match self.rx.message().await {
-            Ok(Some(msg)) => match msg.data {
-                Some(envelope_down_v1::Data::Packet(packet)) => Ok(Some(packet)),
-                None => Err(DecodeError::invalid_envelope()),
-            },
+            Ok(Some(msg)) => {
+                self.usage.record(DATA_USAGE_SERVICE, 0, msg.encoded_len());
+                match msg.data {
+                    Some(envelope_down_v1::Data::Packet(packet)) => Ok(Some(packet)),
+                    None => Err(DecodeError::invalid_envelope()),
+                }
+            }
             Ok(None) => Ok(None),
             Err(err) => Err(err.into()),
         }
     }
 
+    /// Sends a single uplink in its own envelope. The router protocol has no
+    /// batched uplink envelope and the register message does not negotiate a
+    /// protocol version, so uplinks can not be batched until the
+    /// packet_router protocol adds both.
     async fn send(&mut self, msg: PacketRouterPacketUpV1) -> Result {
         let msg = EnvelopeUpV1 {
             data: Some(envelope_up_v1::Data::Packet(msg)),
         };
-        Ok(self.tx.send(msg).await?)
+        self.usage.record(DATA_USAGE_SERVICE, msg.encoded_len(), 0);
+        // Sends only wait when the stream to the router is backed up and the
+        // conduit blocks
+        self.stats
+            .timed(DATA_USAGE_SERVICE, "send", self.tx.send(msg))
+            .await
     }
 
     async fn register(&mut self, keypair: Arc<Keypair>) -> Result {
@@ -98,16 +284,97 @@ impl PacketRouterConduit {
         let msg = EnvelopeUpV1 {
             data: Some(envelope_up_v1::Data::Register(msg)),
         };
-        Ok(self.tx.send(msg).await?)
+        self.usage.record(DATA_USAGE_SERVICE, msg.encoded_len(), 0);
+        self.tx.send_control(msg).await
     }
 }
 
 impl PacketRouterService {
-    pub fn new(uri: Uri, keypair: Arc<Keypair>) -> Self {
+    pub fn new(uri: Uri, keypair: Arc<Keypair>, handles: &Handles) -> Self {
         Self {
-            uri,
+            uris: vec![uri],
+            active: 0,
+            failback_interval: Duration::ZERO,
+            failback_at: None,
+            heartbeat_interval: Duration::ZERO,
+            heartbeat_at: None,
+            recv_reconnect_at: None,
+            recv_reconnect_retry: 0,
             conduit: None,
             keypair,
+            connected_metric: CONNECTED_METRIC,
+            stats: handles.service_stats.clone(),
+            usage: handles.data_usage.clone(),
+            queues: handles.queues.clone(),
+        }
+    }
+
+    /// Uses the given gauge rather than `CONNECTED_METRIC` for the connection
+    /// state, for a service next to the packet router
+    pub fn with_connected_metric(mut self, connected_metric: &'static str) -> Self {
+        self.connected_metric = connected_metric;
+        self
+    }
+
+    /// Adds endpoints to fail over to, in priority order, and the time a
+    /// fallback is used before the primary endpoint is tried again
+    pub fn with_fallbacks(mut self, fallbacks: Vec<Uri>, failback_interval: Duration) -> Self {
+        self.uris.extend(fallbacks);
+        self.failback_interval = failback_interval;
+        self
+    }
+
+    /// Registers again when no uplink was sent for the given interval while
+    /// connected
+    pub fn with_heartbeat(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// The endpoint connected to, or tried first on the next connect
+    pub fn uri(&self) -> &Uri {
+        &self.uris[self.active]
+    }
+
+    /// The time to try the primary endpoint again, when connected to a
+    /// fallback
+    pub fn failback_at(&self) -> Option<Instant> {
+        self.failback_at
+    }
+
+    /// The time of the next heartbeat, when connected with heartbeats
+    pub fn heartbeat_at(&self) -> Option<Instant> {
+        self.heartbeat_at
+    }
+
+    fn schedule_heartbeat(&mut self) {
+        self.heartbeat_at = (!self.heartbeat_interval.is_zero() && self.conduit.is_some())
+            .then(|| Instant::now() + self.heartbeat_interval);
+    }
+
+    fn has_fallbacks(&self) -> bool {
+        self.uris.len() > 1
+    }
+
+    fn set_endpoint_metric(&self, index: usize, connected: bool) {
+        if self.has_fallbacks() {
+            metrics::set(
+                &metrics::labeled(
+                    ENDPOINT_CONNECTED_METRIC,
+                    &[("uri", &self.uris[index].to_string())],
+                ),
+                if connected { 1.0 } else { 0.0 },
+            );
+        }
+    }
+
+    /// Disconnects after a stream error, the next connect tries the next
+    /// endpoint first
+    fn fail(&mut self) {
+        self.disconnect();
+        if self.has_fallbacks() {
+            self.active = (self.active + 1) % self.uris.len();
+            metrics::increment(FAILOVERS_METRIC);
         }
     }
 
@@ -115,44 +382,178 @@ impl PacketRouterService {
         if self.conduit.is_none() {
             self.connect().await?;
         }
+        if let Err(status) = faults::inject(DATA_USAGE_SERVICE).await {
+            self.fail();
+            return Err(status.into());
+        }
         // Unwrap since the above connect early exits if no conduit is created
         match self.conduit.as_mut().unwrap().send(msg).await {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                self.schedule_heartbeat();
+                Ok(())
+            }
             other => {
-                self.disconnect();
+                self.fail();
                 other
             }
         }
     }
 
-    pub async fn recv(&mut self) -> Result<Option<PacketRouterPacketDownV1>> {
-        // Since recv is usually called from a select loop we don't try a
-        // connect every time it is called since the rate for attempted
-        // connections in failure setups would be as high as the loop rate of
-        // the caller. This relies on either a reconnect attempt or a packet
-        // send at a later time to reconnect the conduit.
+    /// Registers again on an idle stream. A stream that fails is
+    /// disconnected like on a failed send.
+    pub async fn heartbeat(&mut self) -> Result {
+        let Some(conduit) = self.conduit.as_mut() else {
+            self.heartbeat_at = None;
+            return Ok(());
+        };
+        match conduit.register(self.keypair.clone()).await {
+            Ok(()) => {
+                metrics::increment(HEARTBEATS_METRIC);
+                self.schedule_heartbeat();
+                Ok(())
+            }
+            Err(err) => {
+                self.fail();
+                Err(err)
+            }
+        }
+    }
+
+    /// Receives the next downlink. While disconnected this waits for the
+    /// reconnect backoff and connects, returning [`Received::Connected`] or
+    /// the connect error if that fails.
+    pub async fn recv(&mut self) -> Result<Option<Received>> {
+        // Since recv is usually called from a select loop the connect attempt
+        // is held to a deadline which survives the receive future being
+        // dropped. Otherwise the rate of attempted connections in failure
+        // setups would be as high as the loop rate of the caller.
         if self.conduit.is_none() {
-            futures::future::pending::<()>().await;
-            return Ok(None);
+            let wait = self.recv_reconnect_wait();
+            let reconnect_at = *self
+                .recv_reconnect_at
+                .get_or_insert_with(|| Instant::now() + wait);
+            tokio::time::sleep_until(reconnect_at).await;
+            let result = self.connect().await;
+            self.recv_reconnect_at = None;
+            return match result {
+                Ok(()) => Ok(Some(Received::Connected)),
+                Err(err) => {
+                    self.recv_reconnect_retry =
+                        (self.recv_reconnect_retry + 1).min(RECV_RECONNECT_RETRIES);
+                    Err(err)
+                }
+            };
         }
-        match self.conduit.as_mut().unwrap().recv().await {
-            Ok(msg) if msg.is_some() => Ok(msg),
-            other => {
-                self.disconnect();
-                other
-            }
+        let result = match self.conduit.as_mut().unwrap().recv().await {
+            Ok(Some(packet)) => faults::corrupt(DATA_USAGE_SERVICE, packet)
+                .map(|packet| Some(Received::Downlink(packet))),
+            other => other.map(|_| None),
+        };
+        if !matches!(result, Ok(Some(_))) {
+            self.fail();
         }
+        result
+    }
+
+    fn recv_reconnect_wait(&self) -> Duration {
+        Backoff::new(
+            RECV_RECONNECT_RETRIES,
+            RECV_RECONNECT_MIN_WAIT,
+            RECV_RECONNECT_MAX_WAIT,
+        )
+        .next(self.recv_reconnect_retry)
+        .unwrap_or(RECV_RECONNECT_MAX_WAIT)
     }
 
     pub fn disconnect(&mut self) {
-        self.conduit = None;
+        if self.conduit.take().is_some() {
+            self.set_endpoint_metric(self.active, false);
+        }
+        self.failback_at = None;
+        self.heartbeat_at = None;
+        metrics::set(self.connected_metric, 0.0);
     }
 
+    /// Connects to the first endpoint that can be reached, starting at the
+    /// active one
     pub async fn connect(&mut self) -> Result {
-        let mut conduit = PacketRouterConduit::new(self.uri.clone()).await?;
-        conduit.register(self.keypair.clone()).await?;
+        let mut result = Ok(());
+        for offset in 0..self.uris.len() {
+            let index = (self.active + offset) % self.uris.len();
+            match self.connect_endpoint(index).await {
+                Ok(conduit) => {
+                    if offset > 0 {
+                        metrics::increment(FAILOVERS_METRIC);
+                    }
+                    self.connected(index, conduit);
+                    return Ok(());
+                }
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    async fn connect_endpoint(&self, index: usize) -> Result<PacketRouterConduit> {
+        // Bound the whole connect attempt, including name resolution and
+        // registration, by a single deadline
+        let uri = self.uris[index].clone();
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, async {
+            faults::inject(DATA_USAGE_SERVICE).await?;
+            let mut conduit = PacketRouterConduit::new(
+                uri.clone(),
+                self.queues.snapshot(),
+                self.stats.clone(),
+                self.usage.clone(),
+            )
+            .await?;
+            conduit.register(self.keypair.clone()).await?;
+            Ok::<_, Error>(conduit)
+        })
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(Error::timeout()),
+        };
+        if result.is_err() && self.has_fallbacks() {
+            metrics::increment(&metrics::labeled(
+                ENDPOINT_FAILURES_METRIC,
+                &[("uri", &uri.to_string())],
+            ));
+        }
+        result
+    }
+
+    fn connected(&mut self, index: usize, conduit: PacketRouterConduit) {
+        if self.conduit.is_some() {
+            self.set_endpoint_metric(self.active, false);
+        }
+        self.active = index;
         self.conduit = Some(conduit);
-        Ok(())
+        self.failback_at = (index > 0).then(|| Instant::now() + self.failback_interval);
+        self.recv_reconnect_at = None;
+        self.recv_reconnect_retry = 0;
+        self.schedule_heartbeat();
+        self.set_endpoint_metric(index, true);
+        metrics::set(self.connected_metric, 1.0);
+    }
+
+    /// Switches back to the primary endpoint when connected to a fallback
+    /// past the failback time. Returns whether the service switched.
+    pub async fn failback(&mut self) -> Result<bool> {
+        if self.conduit.is_none() || !self.failback_at.is_some_and(|at| at <= Instant::now()) {
+            return Ok(false);
+        }
+        match self.connect_endpoint(0).await {
+            Ok(conduit) => {
+                self.connected(0, conduit);
+                Ok(true)
+            }
+            Err(err) => {
+                self.failback_at = Some(Instant::now() + self.failback_interval);
+                Err(err)
+            }
+        }
     }
 
     pub async fn reconnect(&mut self) -> Result {
@@ -160,3 +561,42 @@ impl PacketRouterService {
         self.connect().await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn envelope(data: envelope_up_v1::Data) -> EnvelopeUpV1 {
+        EnvelopeUpV1 { data: Some(data) }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_register() {
+        let (tx, mut rx) = PacketSender::new(2, ConduitPolicy::DropOldest);
+        for _ in 0..4 {
+            let uplink = PacketRouterPacketUpV1::default();
+            tx.send(envelope(envelope_up_v1::Data::Packet(uplink)))
+                .await
+                .expect("uplink sent");
+        }
+        let register = PacketRouterRegisterV1::default();
+        tx.send_control(envelope(envelope_up_v1::Data::Register(register)))
+            .await
+            .expect("register sent");
+        drop(tx);
+
+        // The register goes ahead of the uplinks left in the full conduit
+        let mut received = vec![];
+        while let Some(msg) = rx.next().await {
+            received.push(msg.data);
+        }
+        assert!(matches!(
+            received.as_slice(),
+            [
+                Some(envelope_up_v1::Data::Register(_)),
+                Some(envelope_up_v1::Data::Packet(_)),
+                Some(envelope_up_v1::Data::Packet(_)),
+            ]
+        ));
+    }
+}
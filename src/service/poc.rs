@@ -1,36 +1,82 @@
 use crate::{
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
+    server::Handles,
+    service::{
+        channel, data_usage::DataUsageHandle, faults, retry_transient, stats::ServiceStatsHandle,
+    },
     Result,
 };
 use helium_proto::services::{
     self,
     poc_lora::{LoraBeaconReportReqV1, LoraWitnessReportReqV1},
-    Channel, Endpoint,
+    Channel,
 };
+use helium_proto::Message;
 use http::Uri;
 
+const DATA_USAGE_SERVICE: &str = "poc";
+
 type PocIotClient = helium_proto::services::poc_lora::Client<Channel>;
 
 #[derive(Debug)]
-pub struct PocIotService(PocIotClient);
+pub struct PocIotService {
+    client: PocIotClient,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
+}
 
 impl PocIotService {
-    pub fn new(uri: Uri) -> Self {
-        let channel = Endpoint::from(uri)
-            .connect_timeout(CONNECT_TIMEOUT)
-            .timeout(RPC_TIMEOUT)
-            .connect_lazy();
-        let client = services::poc_lora::Client::new(channel);
-        Self(client)
+    pub fn new(uri: Uri, handles: &Handles) -> Self {
+        let client = services::poc_lora::Client::new(channel::channel(&uri));
+        Self {
+            client,
+            stats: handles.service_stats.clone(),
+            usage: handles.data_usage.clone(),
+        }
     }
 
-    pub async fn submit_beacon(&mut self, req: LoraBeaconReportReqV1) -> Result {
-        _ = self.0.submit_lora_beacon(req).await?;
-        Ok(())
+    /// Submits a beacon report, returns the id the ingest service accepted it
+    /// with, empty when it returned none
+    pub async fn submit_beacon(&mut self, req: LoraBeaconReportReqV1) -> Result<String> {
+        let sent = req.encoded_len();
+        let resp = retry_transient(|| {
+            let mut client = self.client.clone();
+            let stats = self.stats.clone();
+            let req = req.clone();
+            async move {
+                stats
+                    .timed(DATA_USAGE_SERVICE, "submit_beacon", async {
+                        faults::inject(DATA_USAGE_SERVICE).await?;
+                        client.submit_lora_beacon(req).await
+                    })
+                    .await
+            }
+        })
+        .await?;
+        self.usage
+            .record(DATA_USAGE_SERVICE, sent, resp.get_ref().encoded_len());
+        Ok(resp.into_inner().id)
     }
 
-    pub async fn submit_witness(&mut self, req: LoraWitnessReportReqV1) -> Result {
-        _ = self.0.submit_lora_witness(req).await?;
-        Ok(())
+    /// Submits a witness report, returns the id the ingest service accepted
+    /// it with, empty when it returned none
+    pub async fn submit_witness(&mut self, req: LoraWitnessReportReqV1) -> Result<String> {
+        let sent = req.encoded_len();
+        let resp = retry_transient(|| {
+            let mut client = self.client.clone();
+            let stats = self.stats.clone();
+            let req = req.clone();
+            async move {
+                stats
+                    .timed(DATA_USAGE_SERVICE, "submit_witness", async {
+                        faults::inject(DATA_USAGE_SERVICE).await?;
+                        client.submit_lora_witness(req).await
+                    })
+                    .await
+            }
+        })
+        .await?;
+        self.usage
+            .record(DATA_USAGE_SERVICE, sent, resp.get_ref().encoded_len());
+        Ok(resp.into_inner().id)
     }
 }
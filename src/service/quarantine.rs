@@ -0,0 +1,390 @@
+//! Quarantine of received messages with unknown or defaulted critical fields.
+//!
+//! The router and config services can roll out protobuf changes before
+//! gateways are updated, like a new datarate that is not in our enum yet.
+//! Instead of erroring out on such a message, its critical fields are checked
+//! first. An optional field that can not be used, like the rx2 window of a
+//! downlink, is stripped and the rest of the message is used. A message that
+//! can not be used at all is dropped without affecting the stream it arrived
+//! on. Either way a warning is logged, a labeled counter is bumped and the
+//! most recent quarantined messages are kept for the local API.
+
+use crate::{clock::unix_now, logging, metrics};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::{
+    services::router::PacketRouterPacketDownV1, BlockchainRegionParamsV1,
+    DataRate as ProtoDataRate, Message, Region as ProtoRegion, RegionSpreading,
+};
+use semtech_udp::DataRate;
+use serde::Serialize;
+use slog::{warn, Level, Logger};
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Counter of quarantined fields labeled with `source`, `field`, `issue` and
+/// `action`
+pub const QUARANTINED_METRIC: &str = "quarantined_fields_total";
+
+/// The number of quarantined messages that are kept
+const HISTORY_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Issue {
+    /// The value is not known to this version of the gateway
+    Unknown,
+    /// The field is missing or left at its default value
+    Defaulted,
+}
+
+impl Issue {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Defaulted => "defaulted",
+        }
+    }
+}
+
+/// What was done about a quarantined field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The field was removed and the rest of the message used
+    Stripped,
+    /// The whole message was dropped
+    Dropped,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stripped => "stripped",
+            Self::Dropped => "dropped",
+        }
+    }
+}
+
+/// A critical field of a received message that could not be used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub field: &'static str,
+    pub issue: Issue,
+    /// The offending value, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    pub action: Action,
+}
+
+impl Finding {
+    fn unknown(field: &'static str, value: impl ToString, action: Action) -> Self {
+        Self {
+            field,
+            issue: Issue::Unknown,
+            value: Some(value.to_string()),
+            action,
+        }
+    }
+
+    fn defaulted(field: &'static str, action: Action) -> Self {
+        Self {
+            field,
+            issue: Issue::Defaulted,
+            value: None,
+            action,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.issue.as_str())?;
+        if let Some(value) = &self.value {
+            write!(f, " ({value})")?;
+        }
+        write!(f, ", {}", self.action.as_str())
+    }
+}
+
+/// Checks a packet router downlink. Unusable rx2 windows are stripped, a
+/// downlink without a usable rx1 window or payload is dropped.
+pub fn check_downlink(downlink: &mut PacketRouterPacketDownV1) -> Vec<Finding> {
+    let mut findings = vec![];
+    if downlink.payload.is_empty() {
+        findings.push(Finding::defaulted("payload", Action::Dropped));
+    }
+    match &downlink.rx1 {
+        None => findings.push(Finding::defaulted("rx1", Action::Dropped)),
+        Some(rx1) => {
+            if ProtoDataRate::from_i32(rx1.datarate).is_none() {
+                findings.push(Finding::unknown(
+                    "rx1.datarate",
+                    rx1.datarate,
+                    Action::Dropped,
+                ));
+            }
+            if rx1.frequency == 0 {
+                findings.push(Finding::defaulted("rx1.frequency", Action::Dropped));
+            }
+        }
+    }
+    if let Some(rx2) = &downlink.rx2 {
+        let strip = findings.len();
+        if ProtoDataRate::from_i32(rx2.datarate).is_none() {
+            findings.push(Finding::unknown(
+                "rx2.datarate",
+                rx2.datarate,
+                Action::Stripped,
+            ));
+        }
+        if rx2.frequency == 0 {
+            findings.push(Finding::defaulted("rx2.frequency", Action::Stripped));
+        }
+        if findings.len() > strip {
+            downlink.rx2 = None;
+        }
+    }
+    findings
+}
+
+/// Checks a state channel (validator router) downlink packet the same way as
+/// [`check_downlink`]
+pub fn check_packet(packet: &mut helium_proto::Packet) -> Vec<Finding> {
+    let mut findings = vec![];
+    if packet.payload.is_empty() {
+        findings.push(Finding::defaulted("payload", Action::Dropped));
+    }
+    if DataRate::from_str(&packet.datarate).is_err() {
+        findings.push(Finding::unknown(
+            "datarate",
+            &packet.datarate,
+            Action::Dropped,
+        ));
+    }
+    if packet.frequency == 0.0 {
+        findings.push(Finding::defaulted("frequency", Action::Dropped));
+    }
+    if let Some(rx2) = &packet.rx2_window {
+        let strip = findings.len();
+        if DataRate::from_str(&rx2.datarate).is_err() {
+            findings.push(Finding::unknown(
+                "rx2_window.datarate",
+                &rx2.datarate,
+                Action::Stripped,
+            ));
+        }
+        if rx2.frequency == 0.0 {
+            findings.push(Finding::defaulted("rx2_window.frequency", Action::Stripped));
+        }
+        if findings.len() > strip {
+            packet.rx2_window = None;
+        }
+    }
+    findings
+}
+
+/// Checks the region and channel parameters of a config service response.
+/// Tagged spreadings with an unknown spreading are stripped, params for an
+/// unknown region or without channel parameters are dropped.
+pub fn check_region_params(
+    region: i32,
+    params: &mut Option<BlockchainRegionParamsV1>,
+) -> Vec<Finding> {
+    let mut findings = vec![];
+    if ProtoRegion::from_i32(region).is_none() {
+        findings.push(Finding::unknown("region", region, Action::Dropped));
+    }
+    let Some(params) = params else {
+        findings.push(Finding::defaulted("params", Action::Dropped));
+        return findings;
+    };
+    if params.region_params.is_empty() {
+        findings.push(Finding::defaulted("params.region_params", Action::Dropped));
+    }
+    for channel in &mut params.region_params {
+        let Some(spreading) = &mut channel.spreading else {
+            continue;
+        };
+        spreading.tagged_spreading.retain(|tagged_spreading| {
+            let known = RegionSpreading::from_i32(tagged_spreading.region_spreading).is_some();
+            if !known {
+                findings.push(Finding::unknown(
+                    "tagged_spreading.region_spreading",
+                    tagged_spreading.region_spreading,
+                    Action::Stripped,
+                ));
+            }
+            known
+        });
+    }
+    findings
+}
+
+/// A message with quarantined fields
+#[derive(Debug, Clone, Serialize)]
+pub struct Quarantined {
+    /// Unix time (seconds) the message was received
+    pub time: u64,
+    /// The service the message was received from, like "router" or "config"
+    pub source: &'static str,
+    pub findings: Vec<Finding>,
+    /// Whether the message was dropped
+    pub dropped: bool,
+    /// The protobuf encoded message after stripping, base64 encoded
+    pub message: String,
+}
+
+/// Keeps the most recent quarantined messages. Clones share the same history.
+#[derive(Debug, Clone, Default)]
+pub struct QuarantineHandle(Arc<Mutex<VecDeque<Quarantined>>>);
+
+impl QuarantineHandle {
+    /// Quarantines a checked message when there are findings. Returns whether
+    /// the message has to be dropped.
+    pub fn quarantine<M: Message>(
+        &self,
+        logger: &Logger,
+        source: &'static str,
+        message: &M,
+        findings: Vec<Finding>,
+    ) -> bool {
+        if findings.is_empty() {
+            return false;
+        }
+        let dropped = findings
+            .iter()
+            .any(|finding| finding.action == Action::Dropped);
+        if let Some(suppressed) = logging::sampling::sample(Level::Warning, "quarantine") {
+            let summary = findings
+                .iter()
+                .map(Finding::to_string)
+                .collect::<Vec<_>>()
+                .join("; ");
+            warn!(logger, "quarantined {source} message: {summary}";
+                "dropped" => dropped,
+                "suppressed" => suppressed);
+        }
+        for finding in &findings {
+            metrics::increment(&metrics::labeled(
+                QUARANTINED_METRIC,
+                &[
+                    ("source", source),
+                    ("field", finding.field),
+                    ("issue", finding.issue.as_str()),
+                    ("action", finding.action.as_str()),
+                ],
+            ));
+        }
+
+        let mut history = self.0.lock().expect("quarantine lock");
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(Quarantined {
+            time: unix_now(),
+            source,
+            findings,
+            dropped,
+            message: STANDARD.encode(message.encode_to_vec()),
+        });
+        dropped
+    }
+
+    /// The most recent quarantined messages, oldest first
+    pub fn snapshot(&self) -> Vec<Quarantined> {
+        self.0
+            .lock()
+            .expect("quarantine lock")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_proto::{
+        services::router::WindowV1, BlockchainRegionParamV1, BlockchainRegionSpreadingV1,
+        TaggedSpreading,
+    };
+
+    #[test]
+    fn downlinks() {
+        let window = |datarate, frequency| WindowV1 {
+            timestamp: 1000,
+            frequency,
+            datarate,
+            ..Default::default()
+        };
+        let mut downlink = PacketRouterPacketDownV1 {
+            payload: vec![1, 2, 3],
+            rx1: Some(window(ProtoDataRate::Sf9bw125 as i32, 868_100_000)),
+            rx2: Some(window(ProtoDataRate::Sf12bw125 as i32, 869_525_000)),
+        };
+        assert!(check_downlink(&mut downlink).is_empty());
+
+        // An unknown rx2 datarate only strips the rx2 window
+        downlink.rx2 = Some(window(1000, 869_525_000));
+        let findings = check_downlink(&mut downlink);
+        assert_eq!(
+            vec![Finding::unknown("rx2.datarate", 1000, Action::Stripped)],
+            findings
+        );
+        assert!(downlink.rx2.is_none());
+        assert!(downlink.rx1.is_some());
+
+        downlink.rx1 = Some(window(1000, 0));
+        let findings = check_downlink(&mut downlink);
+        assert_eq!(2, findings.len());
+        assert!(findings
+            .iter()
+            .all(|finding| finding.action == Action::Dropped));
+    }
+
+    #[test]
+    fn region_params() {
+        let tagged = |region_spreading, max_packet_size| TaggedSpreading {
+            region_spreading,
+            max_packet_size,
+        };
+        let mut params = Some(BlockchainRegionParamsV1 {
+            region_params: vec![BlockchainRegionParamV1 {
+                channel_frequency: 868_100_000,
+                bandwidth: 125_000,
+                max_eirp: 160,
+                spreading: Some(BlockchainRegionSpreadingV1 {
+                    tagged_spreading: vec![
+                        tagged(RegionSpreading::Sf12 as i32, 51),
+                        tagged(1000, 242),
+                    ],
+                }),
+            }],
+        });
+        let findings = check_region_params(ProtoRegion::Eu868 as i32, &mut params);
+        assert_eq!(
+            vec![Finding::unknown(
+                "tagged_spreading.region_spreading",
+                1000,
+                Action::Stripped
+            )],
+            findings
+        );
+        let spreading = params.as_ref().unwrap().region_params[0]
+            .spreading
+            .as_ref()
+            .unwrap();
+        assert_eq!(1, spreading.tagged_spreading.len());
+
+        let findings = check_region_params(1000, &mut params);
+        assert_eq!(Action::Dropped, findings[0].action);
+        assert_eq!(
+            Action::Dropped,
+            check_region_params(ProtoRegion::Eu868 as i32, &mut None)[0].action
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Differences between successive region params.
+//!
+//! When the region watcher replaces the region params, what changed is logged
+//! and published as a [`RegionDiff`] event, and the most recent differences
+//! are kept for audits through the local API.
+
+use crate::{clock::unix_now, RegionParams};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// The number of region param changes that are kept
+const HISTORY_SIZE: usize = 20;
+
+/// A change of a channel setting, from the old to the new value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelChange<T> {
+    /// Channel frequency in Hz
+    pub frequency: u64,
+    pub from: T,
+    pub to: T,
+}
+
+/// A change of a value, from the old to the new value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change<T> {
+    pub from: T,
+    pub to: T,
+}
+
+/// What changed between two region params
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct RegionDiff {
+    /// Unix time (seconds) the new params were applied
+    pub time: u64,
+    pub region: String,
+    /// The previous region when it changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_region: Option<String>,
+    /// Frequencies in Hz of the channels that were added
+    pub added_channels: Vec<u64>,
+    /// Frequencies in Hz of the channels that were removed
+    pub removed_channels: Vec<u64>,
+    /// Max EIRP changes in dBm
+    pub max_eirp: Vec<ChannelChange<String>>,
+    /// Bandwidth changes in Hz
+    pub bandwidth: Vec<ChannelChange<u32>>,
+    /// Frequencies in Hz of the channels with changed spreading
+    pub spreading: Vec<u64>,
+    /// The antenna gain change in dBi, when it changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gain: Option<Change<String>>,
+}
+
+impl RegionDiff {
+    pub fn new(old: &RegionParams, new: &RegionParams, time: u64) -> Self {
+        let channels = |params: &RegionParams| {
+            params
+                .params
+                .iter()
+                .map(|channel| (channel.channel_frequency, channel.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let (old_channels, new_channels) = (channels(old), channels(new));
+        let mut diff = Self {
+            time,
+            region: new.region.to_string(),
+            previous_region: (old.region != new.region).then(|| old.region.to_string()),
+            added_channels: new_channels
+                .keys()
+                .filter(|frequency| !old_channels.contains_key(frequency))
+                .copied()
+                .collect(),
+            removed_channels: old_channels
+                .keys()
+                .filter(|frequency| !new_channels.contains_key(frequency))
+                .copied()
+                .collect(),
+            gain: (old.gain != new.gain).then(|| Change {
+                from: old.gain.to_string(),
+                to: new.gain.to_string(),
+            }),
+            ..Default::default()
+        };
+        for (frequency, new_channel) in &new_channels {
+            let Some(old_channel) = old_channels.get(frequency) else {
+                continue;
+            };
+            if old_channel.max_eirp != new_channel.max_eirp {
+                diff.max_eirp.push(ChannelChange {
+                    frequency: *frequency,
+                    from: eirp(old_channel.max_eirp),
+                    to: eirp(new_channel.max_eirp),
+                });
+            }
+            if old_channel.bandwidth != new_channel.bandwidth {
+                diff.bandwidth.push(ChannelChange {
+                    frequency: *frequency,
+                    from: old_channel.bandwidth,
+                    to: new_channel.bandwidth,
+                });
+            }
+            if old_channel.spreading != new_channel.spreading {
+                diff.spreading.push(*frequency);
+            }
+        }
+        diff
+    }
+
+    /// Whether nothing but the time differs
+    pub fn is_empty(&self) -> bool {
+        Self {
+            time: self.time,
+            region: self.region.clone(),
+            ..Default::default()
+        } == *self
+    }
+}
+
+/// The max EIRP of region params is in tenths of a dBm
+fn eirp(max_eirp: u32) -> String {
+    format!("{:.1}", max_eirp as f64 / 10.0)
+}
+
+/// A one line summary for the log, like
+/// `added [868500000], max_eirp 868100000: 16.0 -> 14.0`
+impl fmt::Display for RegionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(previous) = &self.previous_region {
+            parts.push(format!("region {previous} -> {}", self.region));
+        }
+        if !self.added_channels.is_empty() {
+            parts.push(format!("added {:?}", self.added_channels));
+        }
+        if !self.removed_channels.is_empty() {
+            parts.push(format!("removed {:?}", self.removed_channels));
+        }
+        for change in &self.max_eirp {
+            parts.push(format!(
+                "max_eirp {}: {} -> {}",
+                change.frequency, change.from, change.to
+            ));
+        }
+        for change in &self.bandwidth {
+            parts.push(format!(
+                "bandwidth {}: {} -> {}",
+                change.frequency, change.from, change.to
+            ));
+        }
+        if !self.spreading.is_empty() {
+            parts.push(format!("spreading {:?}", self.spreading));
+        }
+        if let Some(gain) = &self.gain {
+            parts.push(format!("gain {} -> {}", gain.from, gain.to));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Keeps the most recent region params changes. Clones share the same
+/// history.
+#[derive(Debug, Clone, Default)]
+pub struct RegionHistoryHandle(Arc<Mutex<VecDeque<RegionDiff>>>);
+
+impl RegionHistoryHandle {
+    /// Records a region params change and returns what changed
+    pub fn record(&self, old: &RegionParams, new: &RegionParams) -> RegionDiff {
+        let diff = RegionDiff::new(old, new, unix_now());
+        let mut history = self.0.lock().expect("region history lock");
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(diff.clone());
+        diff
+    }
+
+    /// The most recent region params changes, oldest first
+    pub fn snapshot(&self) -> Vec<RegionDiff> {
+        self.0
+            .lock()
+            .expect("region history lock")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Region;
+    use helium_proto::{BlockchainRegionParamV1, Region as ProtoRegion};
+
+    #[test]
+    fn diff() {
+        let channel = |channel_frequency, max_eirp| BlockchainRegionParamV1 {
+            channel_frequency,
+            bandwidth: 125_000,
+            max_eirp,
+            spreading: None,
+        };
+        let params = |region: ProtoRegion, channels| RegionParams {
+            params: channels,
+            ..RegionParams::from(Region::from(region))
+        };
+        let old = params(
+            ProtoRegion::Eu868,
+            vec![channel(868_100_000, 160), channel(868_300_000, 160)],
+        );
+        let new = params(
+            ProtoRegion::Eu868,
+            vec![channel(868_100_000, 140), channel(868_500_000, 160)],
+        );
+
+        let diff = RegionDiff::new(&old, &new, 10);
+        assert_eq!(vec![868_500_000], diff.added_channels);
+        assert_eq!(vec![868_300_000], diff.removed_channels);
+        assert_eq!(
+            vec![ChannelChange {
+                frequency: 868_100_000,
+                from: "16.0".to_string(),
+                to: "14.0".to_string()
+            }],
+            diff.max_eirp
+        );
+        assert!(diff.bandwidth.is_empty());
+        assert_eq!(None, diff.previous_region);
+        assert!(!diff.is_empty());
+        assert_eq!(
+            "added [868500000], removed [868300000], max_eirp 868100000: 16.0 -> 14.0",
+            diff.to_string()
+        );
+
+        assert!(RegionDiff::new(&new, &new, 20).is_empty());
+        let moved = params(ProtoRegion::Us915, vec![]);
+        let diff = RegionDiff::new(&new, &moved, 30);
+        assert!(diff.previous_region.is_some());
+        assert_eq!(2, diff.removed_channels.len());
+    }
+}
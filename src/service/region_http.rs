@@ -0,0 +1,315 @@
+//! Region parameters from a static JSON document served over http(s), for
+//! private networks that do not run the iot-config service.
+//!
+//! The document lists the channels of a region with their allowed spreading
+//! factors:
+//!
+//! ```json
+//! {
+//!   "region": "EU868",
+//!   "gain": 12,
+//!   "params": [{
+//!     "channel_frequency": 868100000,
+//!     "bandwidth": 125000,
+//!     "max_eirp": 160,
+//!     "spreading": [{ "spreading": "SF12", "max_packet_size": 51 }]
+//!   }]
+//! }
+//! ```
+//!
+//! Frequencies and bandwidths are in Hz, gain and max_eirp in tenths of a
+//! dBi/dBm like in config service responses. An optional `name` makes the
+//! channels a custom region: a private channel plan that is reported as
+//! `region` to routers. The same document can be given inline in the settings
+//! as a custom region (`region_params.custom`). When a public key is configured
+//! the document must be signed: a base64 signature over the document bytes is
+//! fetched from the document url with `.sig` appended. Unsigned documents are
+//! only fetched over https. Fetching documents requires the `region-http`
+//! feature, inline custom regions do not.
+
+#[cfg(feature = "region-http")]
+use crate::service::{outbound, RPC_TIMEOUT};
+use crate::{
+    server::Handles, service::stats::ServiceStatsHandle, Error, PublicKey, Region, RegionParams,
+    Result,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use config::ConfigError;
+use helium_crypto::Verify;
+use helium_proto::{
+    BlockchainRegionParamV1, BlockchainRegionSpreadingV1, RegionSpreading, TaggedSpreading,
+};
+use http::{uri::Scheme, Uri};
+#[cfg(feature = "region-http")]
+use hyper::{Body, Client};
+#[cfg(feature = "region-http")]
+use hyper_rustls::HttpsConnector;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::sync::Arc;
+#[cfg(feature = "region-http")]
+use tokio::time;
+
+/// Allowed channel bandwidths in Hz, sub-GHz and 2.4 GHz
+const BANDWIDTHS: [u32; 7] = [
+    125_000, 250_000, 500_000, 203_125, 406_250, 812_500, 1_625_000,
+];
+/// The largest LoRaWAN PHY payload
+const MAX_PACKET_SIZE: u32 = 255;
+
+/// The channel plan of a region
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionDocument {
+    /// The name of a custom region
+    #[serde(default)]
+    name: Option<String>,
+    region: Region,
+    #[serde(default)]
+    gain: u64,
+    params: Vec<ChannelDocument>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelDocument {
+    channel_frequency: u64,
+    bandwidth: u32,
+    max_eirp: u32,
+    spreading: Vec<SpreadingDocument>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpreadingDocument {
+    spreading: String,
+    max_packet_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRegionSource {
+    pub uri: Uri,
+    pubkey: Option<Arc<PublicKey>>,
+    stats: ServiceStatsHandle,
+    #[cfg(feature = "region-http")]
+    client: Client<HttpsConnector<outbound::Connector>, Body>,
+}
+
+impl HttpRegionSource {
+    /// Creates a source for the document at the given url. Without a key to
+    /// verify the document with the url must be https, so the document can
+    /// not be altered on the way.
+    pub fn new(url: &str, pubkey: Option<Arc<PublicKey>>, handles: &Handles) -> Result<Self> {
+        if cfg!(not(feature = "region-http")) {
+            return Err(Error::from(ConfigError::Message(
+                "region_params.source \"http\" requires the region-http feature".to_string(),
+            )));
+        }
+        let uri: Uri = url.parse()?;
+        if pubkey.is_none() && uri.scheme() != Some(&Scheme::HTTPS) {
+            return Err(Error::from(ConfigError::Message(format!(
+                "region_params.url \"{url}\" must be https when region_params.pubkey is not set"
+            ))));
+        }
+        Ok(Self {
+            uri,
+            pubkey,
+            stats: handles.service_stats.clone(),
+            #[cfg(feature = "region-http")]
+            client: Client::builder().build(outbound::https_connector()),
+        })
+    }
+
+    /// Fetches, verifies and validates the region params document
+    pub async fn region_params(&self) -> Result<RegionParams> {
+        let document = self
+            .stats
+            .timed("region_http", "fetch", self.get(self.uri.clone()))
+            .await?;
+        if let Some(pubkey) = &self.pubkey {
+            let signature_uri: Uri = format!("{}.sig", self.uri).parse()?;
+            let signature = self.get(signature_uri).await?;
+            let signature = STANDARD.decode(String::from_utf8_lossy(&signature).trim())?;
+            pubkey.verify(&document, &signature)?;
+        }
+        parse_document(&document)
+    }
+
+    #[cfg(feature = "region-http")]
+    async fn get(&self, uri: Uri) -> Result<Vec<u8>> {
+        let request = async {
+            let resp = self.client.get(uri.clone()).await?;
+            if !resp.status().is_success() {
+                return Err(Error::custom(format!("GET {uri}: {}", resp.status())));
+            }
+            Ok(hyper::body::to_bytes(resp.into_body()).await?.to_vec())
+        };
+        time::timeout(RPC_TIMEOUT, request)
+            .await
+            .map_err(|_| Error::timeout())?
+    }
+
+    /// Sources are not created without the region-http feature
+    #[cfg(not(feature = "region-http"))]
+    async fn get(&self, _uri: Uri) -> Result<Vec<u8>> {
+        unreachable!("http region source without the region-http feature")
+    }
+}
+
+/// Parses and validates a region params document
+fn parse_document(data: &[u8]) -> Result<RegionParams> {
+    serde_json::from_slice::<RegionDocument>(data)
+        .map_err(Error::invalid_region_params)?
+        .region_params()
+}
+
+impl RegionDocument {
+    /// Validates the document and converts it to region params
+    pub fn region_params(self) -> Result<RegionParams> {
+        let name = match self.name {
+            Some(name) if name.trim().is_empty() => {
+                return Err(Error::invalid_region_params("empty region name"))
+            }
+            name => name,
+        };
+        if self.params.is_empty() {
+            return Err(Error::invalid_region_params("no channels"));
+        }
+        let params = self
+            .params
+            .into_iter()
+            .map(|channel| {
+                if channel.channel_frequency == 0 {
+                    return Err(Error::invalid_region_params("zero channel frequency"));
+                }
+                if !BANDWIDTHS.contains(&channel.bandwidth) {
+                    return Err(Error::invalid_region_params(format!(
+                        "unsupported bandwidth {}",
+                        channel.bandwidth
+                    )));
+                }
+                if channel.spreading.is_empty() {
+                    return Err(Error::invalid_region_params(format!(
+                        "no spreading for channel {}",
+                        channel.channel_frequency
+                    )));
+                }
+                let tagged_spreading = channel
+                    .spreading
+                    .into_iter()
+                    .map(|spreading| {
+                        let region_spreading = RegionSpreading::from_str_name(
+                            &spreading.spreading.to_ascii_uppercase(),
+                        )
+                        .filter(|region_spreading| *region_spreading != RegionSpreading::SfInvalid)
+                        .ok_or_else(|| {
+                            Error::invalid_region_params(format!(
+                                "unsupported spreading {}",
+                                spreading.spreading
+                            ))
+                        })?;
+                        if !(1..=MAX_PACKET_SIZE).contains(&spreading.max_packet_size) {
+                            return Err(Error::invalid_region_params(format!(
+                                "invalid max packet size {}",
+                                spreading.max_packet_size
+                            )));
+                        }
+                        Ok(TaggedSpreading {
+                            region_spreading: region_spreading.into(),
+                            max_packet_size: spreading.max_packet_size,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(BlockchainRegionParamV1 {
+                    channel_frequency: channel.channel_frequency,
+                    bandwidth: channel.bandwidth,
+                    max_eirp: channel.max_eirp,
+                    spreading: Some(BlockchainRegionSpreadingV1 { tagged_spreading }),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(RegionParams {
+            gain: Decimal::new(self.gain as i64, 1),
+            region: self.region,
+            params,
+            name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DOCUMENT: &str = r#"{
+        "region": "EU868",
+        "gain": 12,
+        "params": [{
+            "channel_frequency": 868100000,
+            "bandwidth": 125000,
+            "max_eirp": 160,
+            "spreading": [
+                { "spreading": "SF12", "max_packet_size": 51 },
+                { "spreading": "sf7", "max_packet_size": 222 }
+            ]
+        }]
+    }"#;
+
+    #[test]
+    fn parse() {
+        let params = parse_document(DOCUMENT.as_bytes()).expect("region params");
+        assert_eq!("EU868", params.region.to_string());
+        assert_eq!(Decimal::new(12, 1), params.gain);
+        assert_eq!(1, params.params.len());
+        assert_eq!(14, params.max_conducted_power().expect("power"));
+        let spreading = &params.params[0]
+            .spreading
+            .as_ref()
+            .unwrap()
+            .tagged_spreading;
+        assert_eq!(
+            i32::from(RegionSpreading::Sf7),
+            spreading[1].region_spreading
+        );
+        assert_eq!(None, params.name);
+
+        let custom = DOCUMENT.replace(
+            "\"region\": \"EU868\"",
+            "\"name\": \"PRIVATE868\", \"region\": \"EU868\"",
+        );
+        let params = parse_document(custom.as_bytes()).expect("custom region params");
+        assert_eq!(Some("PRIVATE868"), params.name.as_deref());
+        assert_eq!("PRIVATE868 (EU868)", params.to_string());
+
+        for (from, to) in [
+            ("\"bandwidth\": 125000", "\"bandwidth\": 100000"),
+            ("\"SF12\"", "\"SF13\""),
+            ("\"max_packet_size\": 51", "\"max_packet_size\": 300"),
+            ("\"max_eirp\": 160", "\"max_eirp\": 160, \"dwell\": 400"),
+            ("\"region\": \"EU868\"", "\"region\": \"XX000\""),
+            (
+                "\"region\": \"EU868\"",
+                "\"name\": \" \", \"region\": \"EU868\"",
+            ),
+        ] {
+            let invalid = DOCUMENT.replace(from, to);
+            assert!(parse_document(invalid.as_bytes()).is_err(), "{to}");
+        }
+        assert!(parse_document(br#"{"region": "EU868", "params": []}"#).is_err());
+    }
+
+    #[cfg(feature = "region-http")]
+    #[test]
+    fn unsigned_needs_https() {
+        let pubkey: Arc<PublicKey> = Arc::new(
+            "115PmCR6fpFihdjw626JXYdUEdzwjh66yoWzWkMvB9CRGEx1U6G"
+                .parse()
+                .expect("pubkey"),
+        );
+        let handles = Handles::default();
+        let url = "http://example.com/region_params/EU868.json";
+        assert!(HttpRegionSource::new(url, None, &handles).is_err());
+        assert!(HttpRegionSource::new(url, Some(pubkey), &handles).is_ok());
+        assert!(HttpRegionSource::new("https://example.com/EU868.json", None, &handles).is_ok());
+    }
+}
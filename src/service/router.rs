@@ -1,10 +1,11 @@
 use crate::{
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
+    server::Handles,
+    service::{channel, data_usage::DataUsageHandle, stats::ServiceStatsHandle},
     KeyedUri, Result,
 };
 use helium_proto::{
-    services::{self, Channel, Endpoint},
-    BlockchainStateChannelMessageV1,
+    services::{self, Channel},
+    BlockchainStateChannelMessageV1, Message,
 };
 
 type RouterClient = services::router::RouterClient<Channel>;
@@ -13,17 +14,18 @@ type RouterClient = services::router::RouterClient<Channel>;
 pub struct RouterService {
     pub uri: KeyedUri,
     router_client: RouterClient,
+    stats: ServiceStatsHandle,
+    usage: DataUsageHandle,
 }
 
 impl RouterService {
-    pub fn new(keyed_uri: KeyedUri) -> Result<Self> {
-        let router_channel = Endpoint::from(keyed_uri.uri.clone())
-            .timeout(RPC_TIMEOUT)
-            .connect_timeout(CONNECT_TIMEOUT)
-            .connect_lazy();
+    pub fn new(keyed_uri: KeyedUri, handles: &Handles) -> Result<Self> {
+        let router_channel = channel::channel(&keyed_uri.uri);
         Ok(Self {
             uri: keyed_uri,
             router_client: RouterClient::new(router_channel),
+            stats: handles.service_stats.clone(),
+            usage: handles.data_usage.clone(),
         })
     }
 
@@ -31,6 +33,13 @@ impl RouterService {
         &mut self,
         msg: BlockchainStateChannelMessageV1,
     ) -> Result<BlockchainStateChannelMessageV1> {
-        Ok(self.router_client.route(msg).await?.into_inner())
+        let sent = msg.encoded_len();
+        let resp = self
+            .stats
+            .timed("router", "route", self.router_client.route(msg))
+            .await?
+            .into_inner();
+        self.usage.record("router", sent, resp.encoded_len());
+        Ok(resp)
     }
 }
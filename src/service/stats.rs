@@ -0,0 +1,125 @@
+//! Request latency statistics per upstream service.
+//!
+//! Services time every request to an upstream (config fetches, router sends
+//! and report submissions) so slowness can be attributed to a specific
+//! upstream. Latencies are recorded in the
+//! `service_request_duration_seconds{service,rpc}` histogram and summarized
+//! per service and rpc for the stats API (`GET /v1/services/stats`) and the
+//! `service-stats` command.
+
+use crate::metrics::{self, HISTOGRAM_BUCKETS};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Histogram of request latencies labeled with `service` and `rpc`
+pub const LATENCY_METRIC: &str = "service_request_duration_seconds";
+
+/// Latency statistics of one rpc of an upstream service
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RpcStats {
+    /// Number of requests made
+    pub requests: u64,
+    /// Number of requests that failed
+    pub errors: u64,
+    /// Mean request latency in milliseconds
+    pub mean_ms: f64,
+    /// Largest request latency in milliseconds
+    pub max_ms: f64,
+    /// Latency of the last request in milliseconds
+    pub last_ms: f64,
+    /// Cumulative request counts keyed by upper latency bound in milliseconds
+    pub histogram: BTreeMap<u64, u64>,
+}
+
+/// Stats per rpc keyed by service name
+pub type ServiceStats = BTreeMap<String, BTreeMap<String, RpcStats>>;
+
+/// Records the request latencies of the upstream services. Clones share the
+/// same statistics.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceStatsHandle(Arc<Mutex<ServiceStats>>);
+
+impl ServiceStatsHandle {
+    /// Records the latency and outcome of a request to the given service rpc
+    pub fn record(&self, service: &str, rpc: &str, elapsed: Duration, ok: bool) {
+        metrics::observe(
+            &metrics::labeled(LATENCY_METRIC, &[("service", service), ("rpc", rpc)]),
+            elapsed.as_secs_f64(),
+        );
+
+        let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+        let mut stats = self.0.lock().expect("service stats lock");
+        let rpc_stats = stats
+            .entry(service.to_string())
+            .or_default()
+            .entry(rpc.to_string())
+            .or_default();
+        rpc_stats.mean_ms = (rpc_stats.mean_ms * rpc_stats.requests as f64 + elapsed_ms)
+            / (rpc_stats.requests + 1) as f64;
+        rpc_stats.requests += 1;
+        if !ok {
+            rpc_stats.errors += 1;
+        }
+        rpc_stats.max_ms = rpc_stats.max_ms.max(elapsed_ms);
+        rpc_stats.last_ms = elapsed_ms;
+        for bound in HISTOGRAM_BUCKETS {
+            let count = rpc_stats
+                .histogram
+                .entry((bound * 1000.0) as u64)
+                .or_default();
+            if elapsed.as_secs_f64() <= bound {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Times the given request future and records its latency and outcome
+    pub async fn timed<T, E, F>(&self, service: &str, rpc: &str, request: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let started = Instant::now();
+        let result = request.await;
+        self.record(service, rpc, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// Returns the latency statistics recorded since startup
+    pub fn snapshot(&self) -> ServiceStats {
+        self.0.lock().expect("service stats lock").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latencies() {
+        let stats = ServiceStatsHandle::default();
+        stats.record("test", "fetch", Duration::from_millis(20), true);
+        stats.record("test", "fetch", Duration::from_millis(400), false);
+
+        let stats = stats.snapshot();
+        let fetch = &stats["test"]["fetch"];
+        assert_eq!(2, fetch.requests);
+        assert_eq!(1, fetch.errors);
+        assert_eq!(210.0, fetch.mean_ms.round());
+        assert_eq!(400.0, fetch.max_ms.round());
+        assert_eq!(Some(&0), fetch.histogram.get(&10));
+        assert_eq!(Some(&1), fetch.histogram.get(&25));
+        assert_eq!(Some(&2), fetch.histogram.get(&500));
+        assert_eq!(
+            Some(2.0),
+            metrics::get(&metrics::labeled(
+                LATENCY_METRIC,
+                &[("service", "test"), ("rpc", "fetch")]
+            ))
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Self-update from signed release manifests.
+//!
+//! Meant for installs that are not updated by a package manager. When
+//! `update.manifest` is set the gateway periodically fetches the release
+//! manifest, a JSON document with the latest version and a binary per CPU
+//! architecture (as in `std::env::consts::ARCH`):
+//!
+//! ```json
+//! {
+//!   "version": "1.0.1",
+//!   "binaries": {
+//!     "aarch64": {
+//!       "url": "https://releases.example.com/1.0.1/aarch64/helium_gateway",
+//!       "sha256": "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! The manifest must be signed with the Ed25519 `update.pubkey`: a base64
+//! signature over the manifest bytes is fetched from the manifest url with
+//! `.sig` appended. When the manifest has a newer version than the running
+//! gateway, the binary for this architecture is downloaded, checked against
+//! its sha256 digest and staged as an executable. Depending on
+//! `update.action` the gateway then execs into the staged binary with the same
+//! arguments, hands over to the staged binary (see [`crate::server::handoff`]), or runs
+//! `update.command` with the staged path and version so the supervisor can
+//! install the binary and restart the gateway.
+//!
+//! The exec happens only after the gateway shut down. The updater stops the
+//! server tasks and the server execs into the staged binary once they
+//! finished, so deferred reports, spooled uplinks and status files are
+//! written out first. Downloads are capped in size.
+
+use crate::{
+    error::UpdateError,
+    region_watcher,
+    server::handoff,
+    service::{outbound, RPC_TIMEOUT},
+    settings::{self, Settings, UpdateAction},
+    Error, PublicKey, Result,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use config::ConfigError;
+use helium_crypto::{KeyType, Verify};
+use http::Uri;
+use hyper::{body::HttpBody, Body, Client};
+use hyper_rustls::HttpsConnector;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use slog::{info, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{process::Command, time};
+
+/// The staged binary in the data directory when no staging path is set
+const STAGING_FILE: &str = "helium_gateway.update";
+/// Time allowed to download a binary
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+/// Time allowed for the update command to finish
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// The largest manifest or signature accepted
+const MAX_MANIFEST_SIZE: usize = 64 * 1024;
+/// The largest binary accepted
+const MAX_BINARY_SIZE: usize = 128 * 1024 * 1024;
+
+/// The staged binary to exec into once the server tasks have shut down.
+/// Clones share the same binary.
+#[derive(Debug, Clone, Default)]
+pub struct StagedExec(Arc<Mutex<Option<PathBuf>>>);
+
+impl StagedExec {
+    /// Execs into the binary staged by the updater, if any, with the arguments
+    /// of this process. Only returns when there is no staged binary or the
+    /// exec failed.
+    pub fn exec(&self, logger: &Logger) -> Result {
+        let Some(binary) = self.0.lock().expect("update exec lock").take() else {
+            return Ok(());
+        };
+        info!(logger, "executing update"; "path" => binary.display().to_string());
+        let err = std::process::Command::new(&binary)
+            .args(std::env::args_os().skip(1))
+            .exec();
+        Err(UpdateError::exec(err))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    version: Version,
+    #[serde(default)]
+    binaries: HashMap<String, Binary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Binary {
+    url: String,
+    /// Hex sha256 digest of the binary
+    sha256: String,
+}
+
+impl Manifest {
+    /// The binary to update to when the manifest has a newer version than
+    /// the given one for the given architecture
+    fn update(&self, current: &Version, arch: &str) -> Option<&Binary> {
+        if self.version <= *current {
+            return None;
+        }
+        self.binaries.get(arch)
+    }
+}
+
+pub struct Updater {
+    manifest: Option<Uri>,
+    pubkey: Option<PublicKey>,
+    interval: Duration,
+    staging: PathBuf,
+    action: UpdateAction,
+    command: Option<String>,
+    data_dir: PathBuf,
+    client: Client<HttpsConnector<outbound::Connector>, Body>,
+    region_watch: region_watcher::MessageReceiver,
+    /// Stops the server tasks to exec into or hand over to an update
+    stop: triggered::Trigger,
+    exec: StagedExec,
+}
+
+impl Updater {
+    /// Creates the updater. `stop` stops the server tasks when the gateway
+    /// restarts into an update.
+    pub fn new(
+        settings: &Settings,
+        region_watch: region_watcher::MessageReceiver,
+        stop: triggered::Trigger,
+    ) -> Result<Self> {
+        let update = &settings.update;
+        let manifest = update
+            .manifest
+            .as_deref()
+            .map(str::parse::<Uri>)
+            .transpose()?;
+        if manifest.is_some() {
+            match &update.pubkey {
+                Some(pubkey) if pubkey.key_type() == KeyType::Ed25519 => (),
+                Some(_) => {
+                    return Err(Error::from(ConfigError::Message(
+                        "update.pubkey must be an Ed25519 key".to_string(),
+                    )))
+                }
+                None => {
+                    return Err(Error::from(ConfigError::Message(
+                        "update.manifest requires update.pubkey".to_string(),
+                    )))
+                }
+            }
+            if update.action == UpdateAction::Command && update.command.is_none() {
+                return Err(Error::from(ConfigError::Message(
+                    "update action \"command\" requires update.command".to_string(),
+                )));
+            }
+            if update.action == UpdateAction::Handoff && cfg!(not(feature = "handoff")) {
+                return Err(Error::from(ConfigError::Message(
+                    "update action \"handoff\" requires the handoff feature".to_string(),
+                )));
+            }
+        }
+        let connector = outbound::https_connector();
+        Ok(Self {
+            manifest,
+            pubkey: update.pubkey.clone(),
+            interval: Duration::from_secs(update.interval),
+            staging: update
+                .staging
+                .clone()
+                .unwrap_or_else(|| settings.data_dir.join(STAGING_FILE)),
+            action: update.action,
+            command: update.command.clone(),
+            data_dir: settings.data_dir.clone(),
+            client: Client::builder().build(connector),
+            region_watch,
+            stop,
+            exec: StagedExec::default(),
+        })
+    }
+
+    /// The binary to exec into after an update stopped the server tasks
+    pub fn staged(&self) -> StagedExec {
+        self.exec.clone()
+    }
+
+    /// Whether a release manifest is configured
+    pub fn is_enabled(&self) -> bool {
+        self.manifest.is_some()
+    }
+
+    pub async fn run(self, shutdown: &triggered::Listener, logger: &Logger) -> Result {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest.clone(),
+            None => return Ok(()),
+        };
+        let logger = logger.new(o!("module" => "updater"));
+        info!(logger, "starting";
+            "manifest" => manifest.to_string(),
+            "interval" => self.interval.as_secs());
+
+        // The version that was staged and handed to the supervisor, to not
+        // download it again on every check
+        let mut staged: Option<Version> = None;
+        let mut timer = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!(logger, "shutting down");
+                    return Ok(())
+                },
+                _ = timer.tick() => match self.check(&manifest, staged.as_ref(), &logger).await {
+                    Ok(Some(version)) => staged = Some(version),
+                    Ok(None) => (),
+                    Err(err) => warn!(logger, "update check failed: {err:?}"; "code" => err.code()),
+                }
+            }
+        }
+    }
+
+    /// Checks for and applies an update. Returns the version handed to the
+    /// supervisor, if any.
+    async fn check(
+        &self,
+        manifest_uri: &Uri,
+        staged: Option<&Version>,
+        logger: &Logger,
+    ) -> Result<Option<Version>> {
+        let manifest = self.fetch_manifest(manifest_uri).await?;
+        let current = settings::version();
+        let binary = match manifest.update(&current, std::env::consts::ARCH) {
+            Some(_) if staged == Some(&manifest.version) => return Ok(None),
+            Some(binary) => binary,
+            None => return Ok(None),
+        };
+        info!(logger, "downloading update";
+            "version" => manifest.version.to_string(),
+            "url" => &binary.url);
+        let uri: Uri = binary.url.parse()?;
+        let data = time::timeout(DOWNLOAD_TIMEOUT, self.get(uri, MAX_BINARY_SIZE))
+            .await
+            .map_err(|_| Error::timeout())??;
+        verify_digest(&data, &binary.sha256)?;
+        self.stage(&data)?;
+        info!(logger, "update staged";
+            "version" => manifest.version.to_string(),
+            "path" => self.staging.display().to_string());
+
+        match self.action {
+            UpdateAction::Exec => {
+                info!(logger, "stopping to execute update");
+                *self.exec.0.lock().expect("update exec lock") = Some(self.staging.clone());
+                self.stop.trigger();
+                Ok(Some(manifest.version))
+            }
+            UpdateAction::Handoff => {
+                let state = handoff::State::new(&self.region_watch.borrow());
+                handoff::hand_over(&self.staging, &self.data_dir, state, logger).await?;
+                info!(logger, "stopping after handoff");
+                self.stop.trigger();
+                Ok(Some(manifest.version))
+            }
+            UpdateAction::Command => {
+                self.run_command(&manifest.version).await?;
+                info!(logger, "update handed to supervisor";
+                    "version" => manifest.version.to_string());
+                Ok(Some(manifest.version))
+            }
+        }
+    }
+
+    async fn fetch_manifest(&self, uri: &Uri) -> Result<Manifest> {
+        let document = time::timeout(RPC_TIMEOUT, self.get(uri.clone(), MAX_MANIFEST_SIZE))
+            .await
+            .map_err(|_| Error::timeout())??;
+        if let Some(pubkey) = &self.pubkey {
+            let signature_uri: Uri = format!("{uri}.sig").parse()?;
+            let signature = time::timeout(RPC_TIMEOUT, self.get(signature_uri, MAX_MANIFEST_SIZE))
+                .await
+                .map_err(|_| Error::timeout())??;
+            let signature = STANDARD.decode(String::from_utf8_lossy(&signature).trim())?;
+            pubkey.verify(&document, &signature)?;
+        }
+        Ok(serde_json::from_slice(&document)?)
+    }
+
+    /// Fetches the given uri, failing when the body is larger than
+    /// `max_size` bytes
+    async fn get(&self, uri: Uri, max_size: usize) -> Result<Vec<u8>> {
+        let resp = self.client.get(uri.clone()).await?;
+        if !resp.status().is_success() {
+            return Err(UpdateError::http_status(uri, resp.status()));
+        }
+        let too_large = || UpdateError::too_large(uri.clone(), max_size);
+        if resp
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|size| size > max_size as u64)
+        {
+            return Err(too_large());
+        }
+        let mut body = resp.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > max_size {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// Writes the binary next to the staging path and moves it in place, so
+    /// the staging path only ever holds a complete binary
+    fn stage(&self, data: &[u8]) -> Result {
+        if let Some(parent) = self.staging.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = self.staging.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o755))?;
+        fs::rename(&partial, &self.staging)?;
+        Ok(())
+    }
+
+    async fn run_command(&self, version: &Version) -> Result {
+        let command = self.command.as_deref().ok_or_else(|| {
+            Error::from(ConfigError::Message(
+                "no update command configured".to_string(),
+            ))
+        })?;
+        let output = time::timeout(
+            COMMAND_TIMEOUT,
+            Command::new(command)
+                .arg(&self.staging)
+                .arg(version.to_string())
+                .output(),
+        )
+        .await
+        .map_err(|_| Error::timeout())??;
+        if !output.status.success() {
+            return Err(UpdateError::command_failed(
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that data has the given hex sha256 digest
+fn verify_digest(data: &[u8], expected: &str) -> Result {
+    let digest: String = Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    if !digest.eq_ignore_ascii_case(expected.trim()) {
+        return Err(UpdateError::digest_mismatch(expected.to_string(), digest));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "version": "1.0.1",
+                "binaries": {
+                    "aarch64": { "url": "https://example.com/aarch64", "sha256": "00" }
+                }
+            }"#,
+        )
+        .expect("manifest");
+        let current = Version::new(1, 0, 0);
+        assert_eq!(
+            Some("https://example.com/aarch64"),
+            manifest
+                .update(&current, "aarch64")
+                .map(|binary| binary.url.as_str())
+        );
+        assert!(manifest.update(&current, "mips").is_none());
+        assert!(manifest.update(&Version::new(1, 0, 1), "aarch64").is_none());
+        let prerelease = Version::parse("1.0.1-alpha.1").expect("version");
+        assert!(manifest.update(&prerelease, "aarch64").is_some());
+
+        let digest = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+        assert!(verify_digest(b"foo", digest).is_ok());
+        assert!(verify_digest(b"foo", &digest.to_uppercase()).is_ok());
+        assert!(verify_digest(b"bar", digest).is_err());
+    }
+}
@@ -1,9 +1,21 @@
-use crate::{api::GatewayStakingMode, KeyedUri, Keypair, PublicKey, Region, Result};
-use config::{Config, Environment, File};
+use crate::{
+    api::GatewayStakingMode, beaconer::cluster::ClusterPolicy, gateway::tx_power::TxPowerTable,
+    keypair, queues::ConduitPolicy, service::region_http::RegionDocument, Error, KeyedUri, Keypair,
+    PublicKey, Region, Result,
+};
+use config::{Config, ConfigError, Environment, File, FileFormat, FileSourceFile};
 use http::uri::Uri;
 pub use log_method::LogMethod;
-use serde::Deserialize;
-use std::{fmt, path::Path, str::FromStr, sync::Arc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
 pub fn version() -> semver::Version {
     semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("unable to parse version")
@@ -16,12 +28,28 @@ pub struct Settings {
     /// Default "127.0.0.1:1680"
     #[serde(default = "default_listen")]
     pub listen: String,
+    /// Additional addresses to listen on for semtech UDP packet forwarders,
+    /// like "[::]:1680" next to an IPv4 `listen` address. Default none
+    #[serde(default)]
+    pub extra_listen: Vec<String>,
+    /// The network interface to listen on for semtech UDP packet forwarders.
+    /// When set the ports of the listen addresses are bound on all addresses
+    /// of the interface instead.
+    pub listen_interface: Option<String>,
     /// The listening network port for the grpc / jsonrpc API.
     /// Default 4467
     #[serde(default = "default_api")]
     pub api: u16,
+    /// Public keys, like the key of a maker's mobile app, allowed to call the
+    /// mutating local API endpoints (signing, add gateway and transmitting
+    /// beacons). When set these calls must be signed by the gateway key or
+    /// one of these keys. Default none, which leaves the calls unchecked.
+    #[serde(default)]
+    pub authorized_keys: Vec<PublicKey>,
     /// The location of the keypair binary file for the gateway. If the keyfile
     /// is not found there a new one is generated and saved in that location.
+    /// Defaults to the one of the hardware profile, or
+    /// "/etc/helium_gateway/gateway_key.bin" without a profile.
     pub keypair: Arc<Keypair>,
     /// The location of the onboarding keypair binary file for the gateway. If
     /// the keyfile is not found there a new one is generated and saved in that
@@ -34,6 +62,9 @@ pub struct Settings {
     pub log: LogSettings,
     /// The config service to use for region and other config settings
     pub config: KeyedUri,
+    /// Where region parameters are fetched from
+    #[serde(default)]
+    pub region_params: RegionParamsSettings,
     /// The packet router to deliver all packets when packet router is active.
     pub router: RouterSettings,
     /// Proof-of-coverage (PoC) settings.
@@ -44,6 +75,108 @@ pub struct Settings {
     /// The validator(s) to query for chain related state. Defaults to a Helium
     /// validator.
     pub gateways: Vec<KeyedUri>,
+    /// The directory to store persistent gateway state in. Default
+    /// "/etc/helium_gateway"
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    /// Metrics settings
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    /// REST API settings
+    #[serde(default)]
+    pub rest: RestSettings,
+    /// Status LED settings
+    #[serde(default)]
+    pub led: LedSettings,
+    /// Installed antenna metadata
+    #[serde(default)]
+    pub antenna: AntennaSettings,
+    /// RSSI and SNR calibration offsets
+    #[serde(default)]
+    pub calibration: CalibrationSettings,
+    /// Cellular modem settings
+    #[serde(default)]
+    pub modem: ModemSettings,
+    /// Periodic keypair sign/verify checks
+    #[serde(default)]
+    pub key_health: KeyHealthSettings,
+    /// Witness deduplication cluster settings
+    #[serde(default)]
+    pub cluster: ClusterSettings,
+    /// Upstream data usage accounting settings
+    #[serde(default)]
+    pub data_usage: DataUsageSettings,
+    /// Structural filtering of received uplinks
+    #[serde(default)]
+    pub uplink_filter: UplinkFilterSettings,
+    /// JoinEUI and DevEUI filtering of join requests
+    #[serde(default)]
+    pub join_filter: JoinFilterSettings,
+    /// NetID and DevAddr filtering of forwarded uplinks
+    #[serde(default)]
+    pub forward_filter: ForwardFilterSettings,
+    /// Uplink radio metadata completeness settings
+    #[serde(default)]
+    pub uplink_metadata: UplinkMetadataSettings,
+    /// Downlink scheduling settings
+    #[serde(default)]
+    pub downlink: DownlinkSettings,
+    /// RF sanity alarm settings
+    #[serde(default)]
+    pub rf_health: RfHealthSettings,
+    /// Outbound fleet control channel settings
+    #[serde(default)]
+    pub fleet: FleetSettings,
+    /// Self-update settings
+    #[serde(default)]
+    pub update: UpdateSettings,
+    /// Adaptive beacon channel mask settings
+    #[serde(default)]
+    pub channel_mask: ChannelMaskSettings,
+    /// Maximum conducted TX power in dBm by datarate, like `SF12BW125 = 14`,
+    /// for datarate specific derating. Applied to beacons and downlinks on
+    /// top of the maximum power of the region params.
+    #[serde(default)]
+    pub tx_power: BTreeMap<String, u32>,
+    /// Error budget settings for the routers in multi-router mode
+    #[serde(default)]
+    pub router_health: RouterHealthSettings,
+    /// Network interface or source address binding of outbound connections
+    #[serde(default)]
+    pub outbound: OutboundSettings,
+    /// Semtech Basics Station listener settings
+    #[serde(default)]
+    pub basics_station: BasicsStationSettings,
+    /// The packet forwarder backend uplinks are taken from
+    #[serde(default)]
+    pub forwarder: ForwarderSettings,
+    /// MQTT bridge settings
+    #[serde(default)]
+    pub mqtt: MqttSettings,
+    /// Settings for refusing to transmit outside the region territory
+    #[serde(default)]
+    pub geofence: GeofenceSettings,
+    /// Settings for keeping CRC-failed uplinks for diagnostics
+    #[serde(default)]
+    pub crc_failed: CrcFailedSettings,
+    /// Internal queue sizes
+    #[serde(default)]
+    pub queues: QueueSettings,
+    /// The named hardware profile the defaults were taken from, if any. See
+    /// [`profile::PROFILES`] for the supported profiles.
+    pub profile: Option<String>,
+    /// Additional settings files applied after the settings file, so later
+    /// files override earlier ones. Paths are relative to the directory of the
+    /// settings file and a `*` in the file name matches any characters, like
+    /// "conf.d/*.toml". Matching files are applied in name order. Includes are
+    /// only read from the settings file itself. A missing file, or directory
+    /// of a pattern, fails loading the settings; a pattern matching no files
+    /// includes nothing.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// The settings file these settings were loaded from
+    #[serde(skip)]
+    pub config_path: PathBuf,
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -52,16 +185,772 @@ pub struct LogSettings {
     /// Log level to show (default info)
     pub level: log_level::Level,
 
-    ///  Which log method to use (stdio or syslog, default stdio)
+    ///  Which log method to use (stdio, syslog or file, default stdio)
     pub method: log_method::LogMethod,
 
-    /// Whehter to show timestamps in the stdio output stream (default false)
+    /// The log file of the file log method (default
+    /// /var/log/helium_gateway/helium_gateway.log)
+    #[serde(default = "default_log_file")]
+    pub file: PathBuf,
+
+    /// Size in KiB at which the log file is rotated, 0 to never rotate
+    /// (default 1024)
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+
+    /// The number of rotated log files kept (default 5)
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+
+    /// Whether rotated log files are gzipped (default true)
+    #[serde(default = "default_true")]
+    pub compress: bool,
+
+    /// Whehter to show timestamps in the stdio or file output (default false)
     pub timestamp: bool,
+
+    /// Log sample rates per high-rate event type ("uplink", "downlink",
+    /// "witness" or "send"). A rate of N logs one in every N events.
+    #[serde(default)]
+    pub sample: BTreeMap<String, u64>,
+
+    /// Interval in seconds between summaries of suppressed log events
+    /// (default 60)
+    #[serde(default = "default_log_sample_summary")]
+    pub sample_summary: u64,
+}
+
+/// Settings for the metrics endpoint
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsSettings {
+    /// The listen address for the Prometheus metrics endpoint. The endpoint
+    /// is disabled when not set.
+    pub listen: Option<String>,
+    /// Seconds between metrics snapshots stored in the data directory, 0 to
+    /// disable snapshots. Default 300
+    #[serde(default = "default_metrics_snapshot_interval")]
+    pub snapshot_interval: u64,
+    /// Seconds metrics snapshots are kept. Default 86400
+    #[serde(default = "default_metrics_snapshot_retention")]
+    pub snapshot_retention: u64,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            snapshot_interval: default_metrics_snapshot_interval(),
+            snapshot_retention: default_metrics_snapshot_retention(),
+        }
+    }
+}
+
+/// Settings for the REST/JSON front of the local API
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RestSettings {
+    /// The listen address for the REST API. The REST API is disabled when not
+    /// set. Do NOT expose this outside of the host network.
+    pub listen: Option<String>,
+}
+
+/// Settings for binding outbound service connections on multi-homed
+/// gateways.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct OutboundSettings {
+    /// The network interface to bind outbound connections to, like "wwan0"
+    /// (Linux only). Not bound to an interface when not set.
+    pub interface: Option<String>,
+    /// The source address of outbound connections. Connections to services
+    /// of the other address family are not possible when set.
+    pub address: Option<IpAddr>,
+}
+
+/// Settings for concentrators that run a Semtech Basics Station forwarder
+/// instead of a UDP packet forwarder.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BasicsStationSettings {
+    /// The listen address for Basics Station (LNS protocol) connections, like
+    /// "127.0.0.1:3001". Disabled when not set.
+    pub listen: Option<String>,
+}
+
+/// Settings for the packet forwarder backend of the gateway.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForwarderSettings {
+    /// How packets are exchanged with the concentrator. Default "semtech_udp"
+    #[serde(default)]
+    pub backend: ForwarderBackend,
+    /// The ZeroMQ event socket chirpstack-concentratord publishes uplinks on.
+    /// Default "ipc:///tmp/concentratord_event"
+    #[serde(default = "default_concentratord_event_url")]
+    pub event_url: String,
+    /// The ZeroMQ command socket chirpstack-concentratord takes downlinks on.
+    /// Default "ipc:///tmp/concentratord_command"
+    #[serde(default = "default_concentratord_command_url")]
+    pub command_url: String,
+    /// The gateway EUIs of the Semtech UDP packet forwarders allowed to send
+    /// to the gateway, as hex like "AA555A0000000000". Messages of other
+    /// forwarders are dropped. All forwarders are allowed when empty.
+    #[serde(default)]
+    pub allowed_euis: Vec<String>,
+    /// Seconds without a connect, uplink or stat of a Semtech UDP packet
+    /// forwarder after which the forwarder is considered lost. 0 disables the
+    /// watchdog. Default 120
+    #[serde(default = "default_forwarder_watchdog_timeout")]
+    pub watchdog_timeout: u64,
+    /// A command run, without arguments, when the packet forwarder is lost,
+    /// like a script restarting the forwarder or resetting the concentrator
+    #[serde(default)]
+    pub recovery_command: Option<String>,
+    /// Secondary GWMP servers, as "host:port", the Semtech UDP traffic of the
+    /// packet forwarders is mirrored to, like a private ChirpStack. Their
+    /// downlinks are transmitted as well.
+    #[serde(default)]
+    pub mirror: Vec<String>,
+}
+
+impl Default for ForwarderSettings {
+    fn default() -> Self {
+        Self {
+            backend: ForwarderBackend::default(),
+            event_url: default_concentratord_event_url(),
+            command_url: default_concentratord_command_url(),
+            allowed_euis: vec![],
+            watchdog_timeout: default_forwarder_watchdog_timeout(),
+            recovery_command: None,
+            mirror: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwarderBackend {
+    /// A Semtech UDP packet forwarder sending to the `listen` address
+    #[default]
+    SemtechUdp,
+    /// chirpstack-concentratord, over its ZeroMQ event and command sockets.
+    /// The Semtech UDP port is not bound.
+    Concentratord,
+}
+
+/// Settings for bridging uplinks and downlinks to an MQTT broker.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttSettings {
+    /// The broker address as "host:port", the port defaults to 1883. The
+    /// bridge is disabled when not set.
+    pub broker: Option<String>,
+    /// The client id to connect with. Default "helium_gateway"
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The topic received uplinks are published to. Default
+    /// "helium_gateway/uplink"
+    #[serde(default = "default_mqtt_uplink_topic")]
+    pub uplink_topic: String,
+    /// The topic downlinks to transmit are taken from. Default
+    /// "helium_gateway/downlink"
+    #[serde(default = "default_mqtt_downlink_topic")]
+    pub downlink_topic: String,
+    /// The topic downlink transmit results are published to as JSON. Default
+    /// "helium_gateway/downlink_result"
+    #[serde(default = "default_mqtt_result_topic")]
+    pub result_topic: String,
+    /// The encoding of uplinks and downlinks. Default "json"
+    #[serde(default)]
+    pub format: MqttFormat,
+    /// An age X25519 recipient, like "age1...". When set, uplinks and
+    /// downlink results are encrypted to it before they are published, so
+    /// they are not readable on the broker.
+    #[serde(default)]
+    pub recipient: Option<String>,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            broker: None,
+            client_id: default_mqtt_client_id(),
+            username: None,
+            password: None,
+            uplink_topic: default_mqtt_uplink_topic(),
+            downlink_topic: default_mqtt_downlink_topic(),
+            result_topic: default_mqtt_result_topic(),
+            format: MqttFormat::default(),
+            recipient: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttFormat {
+    #[default]
+    Json,
+    /// A protobuf encoded `helium_proto::Packet`
+    Protobuf,
+}
+
+/// Settings for refusing to transmit when the gateway is outside the
+/// territory of its region, like after a move abroad with stale settings.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GeofenceSettings {
+    /// Whether beacons and downlinks are refused outside the region
+    /// territory. Default false
+    #[serde(default)]
+    pub enabled: bool,
+    /// The operator entered latitude of the gateway in degrees, used until a
+    /// packet forwarder reports a GPS position
+    pub latitude: Option<f64>,
+    /// The operator entered longitude of the gateway in degrees
+    pub longitude: Option<f64>,
+}
+
+/// Settings for keeping uplinks that failed the CRC check, to diagnose
+/// antenna and noise problems.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CrcFailedSettings {
+    /// Whether recent CRC-failed uplinks are kept and counted per channel.
+    /// Default false
+    #[serde(default)]
+    pub keep: bool,
+    /// Whether kept CRC-failed uplinks are streamed by the local API as they
+    /// arrive. Default false
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// Internal queue sizes, each in 1..=4096. See [`crate::queues`].
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueueSettings {
+    /// Uplinks queued on the router conduit stream. Default 50
+    #[serde(default = "default_queue_conduit")]
+    pub conduit: usize,
+    /// Messages queued for the router task. Default 20
+    #[serde(default = "default_queue_router")]
+    pub router: usize,
+    /// Messages queued for the gateway task. Default 10
+    #[serde(default = "default_queue_gateway")]
+    pub gateway: usize,
+    /// Messages queued for the beaconer task. Default 10
+    #[serde(default = "default_queue_beacon")]
+    pub beacon: usize,
+    /// Events a slow event bus subscriber can fall behind. Default 256
+    #[serde(default = "default_queue_event_bus")]
+    pub event_bus: usize,
+    /// What a send to a full conduit does, "block" to wait for the router or
+    /// "drop_oldest" to drop the oldest queued uplink. Default "block"
+    #[serde(default)]
+    pub conduit_policy: ConduitPolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            conduit: default_queue_conduit(),
+            router: default_queue_router(),
+            gateway: default_queue_gateway(),
+            beacon: default_queue_beacon(),
+            event_bus: default_queue_event_bus(),
+            conduit_policy: ConduitPolicy::default(),
+        }
+    }
+}
+
+/// Settings for a status LED driven through sysfs.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LedSettings {
+    /// The sysfs file to write the LED state to, for example
+    /// "/sys/class/leds/status/brightness". Takes precedence over `gpio`.
+    pub path: Option<PathBuf>,
+    /// The GPIO number to export and drive when no `path` is given. The LED
+    /// driver is disabled when neither `path` nor `gpio` is set.
+    pub gpio: Option<u32>,
+    /// Whether the LED is lit by driving the output low. Default false
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// Metadata about the installed antenna. The values are informational: they
+/// are logged with beacon reports and exposed as metrics for audits, while the
+/// gain used for transmit power calculations remains the asserted gain from the
+/// config service.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct AntennaSettings {
+    /// Antenna gain in dBi
+    pub gain: Option<Decimal>,
+    /// Antenna elevation above ground in meters
+    pub elevation: Option<i32>,
+}
+
+/// Offsets (in dB) added to the RSSI and SNR of received packets, for
+/// concentrators that systematically misreport them.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CalibrationSettings {
+    /// RSSI offset. Default 0
+    #[serde(default)]
+    pub rssi: f32,
+    /// SNR offset. Default 0
+    #[serde(default)]
+    pub snr: f32,
+    /// A JSON calibration file (`{"rssi": -3.5, "snr": 1.0}`) whose offsets
+    /// take precedence over the ones above when it exists.
+    pub file: Option<PathBuf>,
+}
+
+/// Settings for reading cellular modem signal quality and data usage.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModemSettings {
+    /// Where to read modem signal quality from ("mmcli" or "at"). Modem
+    /// readings are disabled when not set.
+    pub source: Option<ModemSource>,
+    /// The ModemManager modem index to use with "mmcli". Default 0
+    #[serde(default)]
+    pub modem: u32,
+    /// The AT command port to use with "at", for example "/dev/ttyUSB2"
+    pub device: Option<PathBuf>,
+    /// The network interface of the modem to report data usage for, for
+    /// example "wwan0"
+    pub interface: Option<String>,
+    /// Interval in seconds between modem readings, at least 1. Default 60
+    #[serde(default = "default_modem_interval")]
+    pub interval: u64,
+}
+
+impl Default for ModemSettings {
+    fn default() -> Self {
+        Self {
+            source: None,
+            modem: 0,
+            device: None,
+            interface: None,
+            interval: default_modem_interval(),
+        }
+    }
+}
+
+/// Settings for the source of region parameters.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RegionParamsSettings {
+    /// Where to fetch region parameters from ("config" or "http"), or
+    /// "custom" for the custom region below. Default "config"
+    #[serde(default)]
+    pub source: RegionSource,
+    /// The url of the region params JSON document for the "http" source. Must
+    /// be https when no `pubkey` is set.
+    pub url: Option<String>,
+    /// The key the JSON document is signed with. The signature is fetched from
+    /// the document url with ".sig" appended. Documents are not verified when
+    /// not set.
+    pub pubkey: Option<Arc<PublicKey>>,
+    /// The channel plan of a custom region for the "custom" source, in the
+    /// format of the JSON document. See [`crate::service::region_http`].
+    pub custom: Option<RegionDocument>,
+    /// Seconds a fetched change of the region parameters is held back before
+    /// it is applied. Further changes within that window are coalesced into
+    /// one, and a change that reverts within it is not applied at all. 0
+    /// applies changes right away. Default 30
+    #[serde(default = "default_region_params_debounce")]
+    pub debounce: u64,
+}
+
+impl Default for RegionParamsSettings {
+    fn default() -> Self {
+        Self {
+            source: RegionSource::default(),
+            url: None,
+            pubkey: None,
+            custom: None,
+            debounce: default_region_params_debounce(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionSource {
+    /// The iot-config service (or validators with the validator feature)
+    #[default]
+    Config,
+    /// A static JSON document served over http(s)
+    Http,
+    /// A custom region defined in the settings, for private networks with
+    /// their own channel plan
+    Custom,
+}
+
+/// Settings for periodically checking that the keypair (for example on an
+/// ECC608 secure element) can still sign.
+#[derive(Debug, Deserialize, Clone)]
+pub struct KeyHealthSettings {
+    /// Interval in seconds between sign/verify checks, 0 disables the checks.
+    /// Default 600
+    #[serde(default = "default_key_health_interval")]
+    pub interval: u64,
+}
+
+impl Default for KeyHealthSettings {
+    fn default() -> Self {
+        Self {
+            interval: default_key_health_interval(),
+        }
+    }
+}
+
+/// Settings for the outbound fleet control channel. The gateway dials the
+/// fleet manager, so gateways behind NAT can be managed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct FleetSettings {
+    /// The gRPC uri of the fleet manager. Fleet control is disabled when not
+    /// set.
+    pub uri: Option<String>,
+    /// Seconds to wait before dialing the fleet manager again after the
+    /// control channel closed or failed to connect. Default 60
+    #[serde(default = "default_fleet_reconnect_interval")]
+    pub reconnect_interval: u64,
+}
+
+impl Default for FleetSettings {
+    fn default() -> Self {
+        Self {
+            uri: None,
+            reconnect_interval: default_fleet_reconnect_interval(),
+        }
+    }
+}
+
+/// Settings for updating the gateway binary from a signed release manifest,
+/// for installs that are not updated by a package manager.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpdateSettings {
+    /// The url of the release manifest. Self-updates are disabled when not
+    /// set.
+    pub manifest: Option<String>,
+    /// The Ed25519 key the release manifest is signed with. Required when a
+    /// manifest is set.
+    pub pubkey: Option<PublicKey>,
+    /// Seconds between update checks. Default 21600 (6 hours)
+    #[serde(default = "default_update_interval")]
+    pub interval: u64,
+    /// Where to stage a downloaded binary. Default "helium_gateway.update" in
+    /// the data directory
+    pub staging: Option<PathBuf>,
+    /// What to do with a staged binary. Default "exec"
+    #[serde(default)]
+    pub action: UpdateAction,
+    /// The command the "command" action runs with the staged binary path and
+    /// the new version as arguments
+    pub command: Option<String>,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            manifest: None,
+            pubkey: None,
+            interval: default_update_interval(),
+            staging: None,
+            action: UpdateAction::default(),
+            command: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateAction {
+    /// Replace the running gateway with the staged binary, keeping the
+    /// command line arguments
+    #[default]
+    Exec,
+    /// Run the update command so a supervisor can install the staged binary
+    /// and restart the gateway
+    Command,
+    /// Start the staged binary and hand the API sockets and region params
+    /// over to it, see [`crate::server::handoff`]
+    Handoff,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModemSource {
+    /// Read signal quality through ModemManager
+    Mmcli,
+    /// Read signal quality with AT commands on a modem AT port
+    At,
+}
+
+/// Settings for upstream data usage accounting.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DataUsageSettings {
+    /// Whether to store the data usage across restarts and apply the monthly
+    /// cap. Default true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Monthly data cap in megabytes (10^6 bytes) for all upstream services
+    pub monthly_cap_mb: Option<u64>,
+    /// Whether to stop forwarding non-join uplinks when the monthly cap is
+    /// exceeded. Beacons and witnesses are still sent. Default false
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+impl Default for DataUsageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            monthly_cap_mb: None,
+            enforce: false,
+        }
+    }
+}
+
+/// Settings for dropping received frames that can not be valid LoRaWAN uplinks,
+/// like RF noise decoded as a packet, before they are forwarded.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct UplinkFilterSettings {
+    /// Whether to drop structurally invalid uplinks. Default false
+    #[serde(default)]
+    pub enabled: bool,
+    /// Hex join EUIs (AppEUIs) of the devices served by this gateway. When
+    /// not empty, join requests for other join EUIs are dropped as well.
+    #[serde(default)]
+    pub join_euis: Vec<String>,
+}
+
+/// Settings for dropping join requests of foreign devices before they are
+/// signed and forwarded. EUIs are hex. Deny lists are applied first; when an
+/// allow list is not empty, join requests matching none of its EUIs are
+/// dropped. The filter can be replaced at runtime through the local API.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct JoinFilterSettings {
+    /// Whether to filter join requests. Default false
+    #[serde(default)]
+    pub enabled: bool,
+    /// JoinEUIs (AppEUIs) of the join requests that are forwarded
+    #[serde(default)]
+    pub allow_join_euis: Vec<String>,
+    /// JoinEUIs (AppEUIs) of the join requests that are dropped
+    #[serde(default)]
+    pub deny_join_euis: Vec<String>,
+    /// DevEUIs of the join requests that are forwarded
+    #[serde(default)]
+    pub allow_dev_euis: Vec<String>,
+    /// DevEUIs of the join requests that are dropped
+    #[serde(default)]
+    pub deny_dev_euis: Vec<String>,
+}
+
+/// Settings for dropping uplinks of other LoRaWAN networks, like a private
+/// network sharing the gateway, rather than forwarding them to the router.
+/// NetIDs are hex, like `00003C`, and DevAddr ranges are inclusive hex
+/// `start-end` ranges, like `26000000-27FFFFFF`. Deny rules are applied first.
+/// When any allow rule is set only uplinks matching an allow rule are
+/// forwarded. Join requests are never filtered.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ForwardFilterSettings {
+    /// NetIDs of the devices whose uplinks are forwarded
+    #[serde(default)]
+    pub allow_net_ids: Vec<String>,
+    /// NetIDs of the devices whose uplinks are dropped
+    #[serde(default)]
+    pub deny_net_ids: Vec<String>,
+    /// DevAddr ranges of the devices whose uplinks are forwarded
+    #[serde(default)]
+    pub allow_devaddrs: Vec<String>,
+    /// DevAddr ranges of the devices whose uplinks are dropped
+    #[serde(default)]
+    pub deny_devaddrs: Vec<String>,
+}
+
+/// Settings for uplinks with missing radio metadata, like RSSI or SNR, which
+/// skews adaptive data rate in the LNS.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct UplinkMetadataSettings {
+    /// Whether uplinks with missing metadata are dropped rather than
+    /// forwarded with the missing values as 0. Default false
+    #[serde(default)]
+    pub drop_incomplete: bool,
+}
+
+/// Settings for raising an alarm on RF symptoms of a disconnected antenna or a
+/// failed LNA, like a previously active gateway not receiving any uplinks.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RfHealthSettings {
+    /// Hours without uplinks after which an alarm is raised, 0 disables the
+    /// alarms. Default 6
+    #[serde(default = "default_rf_silence_hours")]
+    pub silence_hours: u64,
+    /// The number of uplinks a gateway must have received before it is
+    /// considered active and a silence alarm can be raised. Default 100
+    #[serde(default = "default_rf_min_uplinks")]
+    pub min_uplinks: u64,
+    /// Drop in dB of the median RSSI of recent uplinks below the long term
+    /// median that raises an alarm. Default 20
+    #[serde(default = "default_rf_rssi_drop")]
+    pub rssi_drop: u64,
+    /// Url a JSON description of an alarm is POSTed to when it is raised or
+    /// cleared. Not used when not set.
+    pub webhook: Option<String>,
+}
+
+impl Default for RfHealthSettings {
+    fn default() -> Self {
+        Self {
+            silence_hours: default_rf_silence_hours(),
+            min_uplinks: default_rf_min_uplinks(),
+            rssi_drop: default_rf_rssi_drop(),
+            webhook: None,
+        }
+    }
+}
+
+/// Settings for temporarily excluding channels with interference from beacon
+/// frequency selection.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChannelMaskSettings {
+    /// Whether beacons avoid masked channels. Default false
+    #[serde(default)]
+    pub enabled: bool,
+    /// Percentage of received frames with a CRC error above which a channel
+    /// is masked. Default 50
+    #[serde(default = "default_channel_mask_error_percent")]
+    pub error_percent: u64,
+    /// dB the noise floor of a channel has to be above the median noise floor
+    /// of the channel plan for the channel to be masked. Default 10
+    #[serde(default = "default_channel_mask_noise_margin")]
+    pub noise_margin: u64,
+    /// Maximum number of masked channels. Never more than half of the
+    /// channel plan is masked. Default 2
+    #[serde(default = "default_channel_mask_max_masked")]
+    pub max_masked: usize,
+    /// Seconds a channel stays masked. Default 3600
+    #[serde(default = "default_channel_mask_hold")]
+    pub hold: u64,
+}
+
+impl Default for ChannelMaskSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            error_percent: default_channel_mask_error_percent(),
+            noise_margin: default_channel_mask_noise_margin(),
+            max_masked: default_channel_mask_max_masked(),
+            hold: default_channel_mask_hold(),
+        }
+    }
+}
+
+/// Settings for deciding whether an RX1 downlink can still be scheduled.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DownlinkSettings {
+    /// Milliseconds an RX1 downlink has to reach the packet forwarder before
+    /// its transmit time, covering backhaul and forwarder latency. Downlinks
+    /// that would arrive later go to RX2 directly. Default 40
+    #[serde(default = "default_rx1_margin")]
+    pub rx1_margin: u64,
+    /// Whether to tune the margin from the rate of TOO_LATE acks. Default true
+    #[serde(default = "default_true")]
+    pub auto_tune: bool,
+    /// The lower bound in milliseconds for the tuned margin. Default 10
+    #[serde(default = "default_rx1_margin_min")]
+    pub rx1_margin_min: u64,
+    /// The upper bound in milliseconds for the tuned margin. Default 500
+    #[serde(default = "default_rx1_margin_max")]
+    pub rx1_margin_max: u64,
+    /// Whether to add the measured round trip time to the packet forwarder
+    /// to the margin, for forwarders on another host. Default true
+    #[serde(default = "default_true")]
+    pub rtt_compensation: bool,
+}
+
+impl Default for DownlinkSettings {
+    fn default() -> Self {
+        Self {
+            rx1_margin: default_rx1_margin(),
+            auto_tune: true,
+            rx1_margin_min: default_rx1_margin_min(),
+            rx1_margin_max: default_rx1_margin_max(),
+            rtt_compensation: true,
+        }
+    }
+}
+
+/// Settings for taking routers that keep failing out of rotation in
+/// multi-router mode.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouterHealthSettings {
+    /// Whether to blacklist routers that exceed the error budget. Default true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// The percentage of failed sends in the window at which a router is
+    /// blacklisted. Default 50
+    #[serde(default = "default_router_health_error_percent")]
+    pub error_percent: u64,
+    /// The number of most recent sends per router the error rate is taken
+    /// over. Default 20
+    #[serde(default = "default_router_health_window")]
+    pub window: usize,
+    /// Seconds a blacklisted router is out of rotation. Default 300
+    #[serde(default = "default_router_health_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for RouterHealthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            error_percent: default_router_health_error_percent(),
+            window: default_router_health_window(),
+            cooldown: default_router_health_cooldown(),
+        }
+    }
+}
+
+/// Settings for sharing witnessed beacons with co-located gateways.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClusterSettings {
+    /// The UDP address to listen for peer announcements on. Clustering is
+    /// disabled when not set.
+    pub listen: Option<String>,
+    /// The UDP addresses of the peer gateways
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// The public keys of the peer gateways. Announcements not signed by one
+    /// of these keys are ignored.
+    #[serde(default)]
+    pub peer_keys: Vec<String>,
+    /// Which gateway of the cluster reports a beacon ("first" or "strongest").
+    /// Default "first"
+    #[serde(default)]
+    pub policy: ClusterPolicy,
+    /// Milliseconds to wait for peer announcements before reporting a
+    /// witness. Default 500
+    #[serde(default = "default_cluster_holdoff")]
+    pub holdoff: u64,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            peers: vec![],
+            peer_keys: vec![],
+            policy: ClusterPolicy::default(),
+            holdoff: default_cluster_holdoff(),
+        }
+    }
 }
 
 /// Settings for proof-of-coverage (PoC).
 #[derive(Debug, Deserialize, Clone)]
 pub struct PocSettings {
+    /// Whether beacons are transmitted and received beacons witnessed, for
+    /// uplink only gateways. Only applies to builds with the "poc" feature.
+    /// Defaults to true.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
     /// Entropy URL.
     #[serde(with = "http_serde::uri")]
     pub entropy_uri: Uri,
@@ -73,6 +962,116 @@ pub struct PocSettings {
     /// increase rewards
     #[serde(default = "default_poc_interval")]
     pub interval: u64,
+    /// Whether to align beacon transmissions to GPS second boundaries when the
+    /// packet forwarder reports a GPS lock (requires a PPS capable
+    /// concentrator). Defaults to false.
+    #[serde(default)]
+    pub gps_align: bool,
+    /// Source of the local entropy mixed into beacons. Defaults to the
+    /// operating system RNG.
+    #[serde(default)]
+    pub entropy_source: LocalEntropySource,
+    /// The hardware RNG device used by the "hwrng" entropy source. Defaults
+    /// to /dev/hwrng.
+    #[serde(default = "default_entropy_device")]
+    pub entropy_device: PathBuf,
+    /// Whether beacon and witness reports are signed when made but stored in
+    /// the data directory and submitted in bulk when the ingest service is
+    /// reachable, for gateways with intermittent connectivity. Defaults to
+    /// false.
+    #[serde(default)]
+    pub deferred: bool,
+    /// Maximum age in seconds of a deferred report. Older reports would be
+    /// rejected as stale and are discarded instead of submitted. Defaults to
+    /// 24 hours.
+    #[serde(default = "default_deferred_max_age")]
+    pub deferred_max_age: u64,
+    /// Maximum number of stored deferred reports. The oldest reports are
+    /// discarded when more are stored. Defaults to 1000.
+    #[serde(default = "default_deferred_max_reports")]
+    pub deferred_max_reports: usize,
+    /// Seconds between attempts to submit deferred reports, at least 1.
+    /// Defaults to 5 minutes.
+    #[serde(default = "default_deferred_interval")]
+    pub deferred_interval: u64,
+    /// Ingest URLs for reports made in specific regions. Reports made in any
+    /// other region go to `ingest_uri`. The URL follows the region as it is
+    /// fetched, so a gateway moved to another region submits its reports to
+    /// that region's ingestor.
+    #[serde(default)]
+    pub regional_ingest: Vec<RegionIngestSettings>,
+    /// Maximum milliseconds from receiving a beacon to submitting its witness
+    /// report. The oracle rejects stale witnesses, so slower reports are
+    /// dropped or flagged, see `witness_late_drop`. Not applied to deferred
+    /// reports. 0 disables the budget. Defaults to 10 seconds.
+    #[serde(default = "default_witness_max_latency")]
+    pub witness_max_latency: u64,
+    /// Whether witness reports over `witness_max_latency` are dropped. When
+    /// false they are only logged and counted. Defaults to true.
+    #[serde(default = "default_true")]
+    pub witness_late_drop: bool,
+}
+
+/// A PoC ingest URL for the reports made in a region
+#[derive(Debug, Deserialize, Clone)]
+pub struct RegionIngestSettings {
+    pub region: Region,
+    #[serde(with = "http_serde::uri")]
+    pub uri: Uri,
+}
+
+impl PocSettings {
+    pub fn ingest_uris(&self) -> IngestUris {
+        IngestUris {
+            default: self.ingest_uri.clone(),
+            regional: self
+                .regional_ingest
+                .iter()
+                .map(|ingest| (ingest.region, ingest.uri.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// PoC ingest URLs by region
+#[derive(Debug, Clone)]
+pub struct IngestUris {
+    default: Uri,
+    regional: Vec<(Region, Uri)>,
+}
+
+impl From<Uri> for IngestUris {
+    fn from(default: Uri) -> Self {
+        Self {
+            default,
+            regional: vec![],
+        }
+    }
+}
+
+impl IngestUris {
+    /// The ingest URL for reports made in the given region. The first
+    /// matching regional entry is used, or the default URL if there is none.
+    pub fn for_region(&self, region: &Region) -> &Uri {
+        self.regional
+            .iter()
+            .find(|(ingest_region, _)| ingest_region == region)
+            .map(|(_, uri)| uri)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Local beacon entropy sources
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalEntropySource {
+    /// The operating system random number generator
+    #[default]
+    Os,
+    /// A hardware random number generator device
+    Hwrng,
+    /// RF noise sampled from received packets
+    Rf,
 }
 
 /// Settings for packet routing
@@ -82,6 +1081,46 @@ pub struct RouterSettings {
     pub uri: Uri,
     // Maximum number of packets to queue up for the packet router
     pub queue: u16,
+    /// The uri of a shadow packet router that is sent a copy of all uplinks.
+    /// Its downlinks are not transmitted but compared to those of the router
+    /// in metrics. Disabled when not set.
+    #[serde(default)]
+    pub shadow: Option<String>,
+    /// Router uris to fail over to, in priority order, when `uri` can not be
+    /// connected to or its stream fails. Defaults to none.
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Seconds a fallback router is used before the primary router is tried
+    /// again. Defaults to 5 minutes.
+    #[serde(default = "default_failback_interval")]
+    pub failback_interval: u64,
+    /// Whether uplinks that fail to send are stored in the data directory and
+    /// sent when the router is reachable again, for gateways with a flaky
+    /// backhaul. Defaults to false.
+    #[serde(default)]
+    pub spool: bool,
+    /// Maximum number of spooled uplinks. The oldest uplinks are discarded
+    /// when more are spooled. Defaults to 10000.
+    #[serde(default = "default_spool_max_packets")]
+    pub spool_max_packets: usize,
+    /// Maximum age in seconds of a spooled uplink. Older uplinks are
+    /// discarded instead of sent. Defaults to 24 hours.
+    #[serde(default = "default_spool_max_age")]
+    pub spool_max_age: u64,
+    /// Seconds between HTTP/2 pings on connections with an open stream, like
+    /// the router stream, so a dead connection is noticed. 0 disables pings.
+    /// Defaults to 30.
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    /// Seconds to wait for a ping to be answered before the connection is
+    /// closed. Defaults to 10.
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: u64,
+    /// Seconds without an uplink after which the gateway registers again on
+    /// the router stream as a heartbeat, so a broken stream fails without
+    /// waiting for the next uplink. 0 disables heartbeats. Defaults to 60.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
 }
 
 impl Settings {
@@ -90,16 +1129,149 @@ impl Settings {
     /// Environemnt overrides have the same name as the entries in the settings
     /// file in uppercase and prefixed with "GW_". For example "GW_KEY" will
     /// override the key file location.
+    ///
+    /// When a `profile` is named in the settings file (or environment) the
+    /// defaults of that hardware profile are applied first, so any value in the
+    /// settings file or environment overrides the profile.
+    ///
+    /// Files listed in `include` are applied after the settings file, in
+    /// order, and before the environment.
     pub fn new(path: &Path) -> Result<Self> {
-        Config::builder()
-            // Source settings file
-            .add_source(File::with_name(path.to_str().expect("file name")).required(false))
-            // Add in settings from the environment (with a prefix of APP)
-            // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
-            .add_source(Environment::with_prefix("gw").separator("_"))
-            .build()
-            .and_then(|config| config.try_deserialize())
-            .map_err(|e| e.into())
+        Self::load(path, false)
+    }
+
+    /// Loads the settings like [`Settings::new`] for the server, which probes
+    /// a secure element key with retries and saves the probe results in the
+    /// data dir. See [`keypair::enable_probing`].
+    pub fn for_server(path: &Path) -> Result<Self> {
+        Self::load(path, true)
+    }
+
+    fn load(path: &Path, probe_keys: bool) -> Result<Self> {
+        let includes = include_paths(path)?;
+        let mut builder = Config::builder().set_default("keypair", DEFAULT_KEYPAIR)?;
+        if let Some(defaults) = Self::profile_defaults(path, &includes)? {
+            builder = builder.add_source(File::from_str(defaults, FileFormat::Toml));
+        }
+        // Source settings file
+        builder = builder.add_source(file_source(path));
+        for include in &includes {
+            builder = builder.add_source(include_source(include));
+        }
+        let config = builder.add_source(env_source()).build()?;
+        if probe_keys {
+            let data_dir = config
+                .get::<PathBuf>("data_dir")
+                .unwrap_or_else(|_| default_data_dir());
+            keypair::enable_probing(&data_dir);
+        }
+        let settings = config.try_deserialize::<Self>().map(|settings| Self {
+            config_path: path.to_path_buf(),
+            ..settings
+        })?;
+        // Validate tables that are only used once the gateway runs
+        TxPowerTable::new(&settings.tx_power)?;
+        settings.validate_intervals()?;
+        Ok(settings)
+    }
+
+    /// Rejects timer intervals of 0 seconds, which can not be scheduled
+    fn validate_intervals(&self) -> Result {
+        let intervals = [
+            ("poc.deferred_interval", self.poc.deferred_interval),
+            ("modem.interval", self.modem.interval),
+            ("update.interval", self.update.interval),
+        ];
+        for (key, interval) in intervals {
+            if interval == 0 {
+                return Err(Error::from(ConfigError::Message(format!(
+                    "{key} must be at least 1 second"
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the defaults of the hardware profile named in the settings
+    /// file, its includes or environment, if any.
+    fn profile_defaults(path: &Path, includes: &[PathBuf]) -> Result<Option<&'static str>> {
+        let mut builder = Config::builder().add_source(file_source(path));
+        for include in includes {
+            builder = builder.add_source(include_source(include));
+        }
+        let profile = builder
+            .add_source(env_source())
+            .build()?
+            .get_string("profile")
+            .ok();
+        profile
+            .map(|name| {
+                profile::find(&name).ok_or_else(|| {
+                    Error::from(ConfigError::Message(format!(
+                        "unsupported hardware profile: \"{name}\""
+                    )))
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the effective configuration loaded from the given settings file
+    /// path, with the source each value came from. Later sources override
+    /// earlier ones: defaults, hardware profile, settings file, included
+    /// files, environment.
+    pub fn provenance(path: &Path) -> Result<Vec<ConfigEntry>> {
+        let includes = include_paths(path)?;
+        let mut entries: BTreeMap<String, ConfigEntry> = BTreeMap::new();
+        for (key, value) in defaults() {
+            entries.insert(
+                key.to_string(),
+                ConfigEntry {
+                    key: key.to_string(),
+                    value: value.clone(),
+                    source: ConfigSource::Default,
+                    file: None,
+                    default: Some(value),
+                },
+            );
+        }
+        let mut apply = |source: ConfigSource,
+                         file: Option<&Path>,
+                         values: Vec<(String, serde_json::Value)>| {
+            for (key, value) in values {
+                let default = entries.get(&key).and_then(|entry| entry.default.clone());
+                entries.insert(
+                    key.clone(),
+                    ConfigEntry {
+                        key,
+                        value,
+                        source,
+                        file: file.map(Path::to_path_buf),
+                        default,
+                    },
+                );
+            }
+        };
+        if let Some(defaults) = Self::profile_defaults(path, &includes)? {
+            apply(
+                ConfigSource::Profile,
+                None,
+                flatten_source(File::from_str(defaults, FileFormat::Toml))?,
+            );
+        }
+        apply(
+            ConfigSource::File,
+            Some(path),
+            flatten_source(file_source(path))?,
+        );
+        for include in &includes {
+            apply(
+                ConfigSource::Include,
+                Some(include),
+                flatten_source(include_source(include))?,
+            );
+        }
+        apply(ConfigSource::Env, None, flatten_source(env_source())?);
+        Ok(entries.into_values().collect())
     }
 
     /// Returns the onboarding key for this gateway. The onboarding key is
@@ -118,19 +1290,563 @@ impl Settings {
     }
 }
 
+fn file_source(path: &Path) -> File<FileSourceFile, FileFormat> {
+    File::with_name(path.to_str().expect("file name")).required(false)
+}
+
+fn include_source(path: &Path) -> File<FileSourceFile, FileFormat> {
+    File::from(path).required(true)
+}
+
+/// Returns the files included by the settings file in the order they are
+/// applied, expanding `*` patterns in file names. A missing include is an
+/// error, whether it is a file or the directory of a pattern. A pattern
+/// matching no files in an existing directory includes nothing.
+fn include_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let patterns: Vec<String> = Config::builder()
+        .add_source(file_source(path))
+        .build()?
+        .get("include")
+        .unwrap_or_default();
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut paths = vec![];
+    for pattern in patterns {
+        let pattern = base.join(pattern);
+        let name = match pattern.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains('*') => name,
+            _ => {
+                paths.push(pattern);
+                continue;
+            }
+        };
+        let dir = pattern.parent().unwrap_or(base);
+        let entries = fs::read_dir(dir).map_err(|err| {
+            ConfigError::Message(format!("include directory {}: {err}", dir.display()))
+        })?;
+        let mut matches: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .file_name()
+                        .and_then(|file_name| file_name.to_str())
+                        .is_some_and(|file_name| wildcard_match(name, file_name))
+            })
+            .collect();
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Matches a name against a pattern where `*` matches any (possibly empty)
+/// sequence of characters
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No wildcard
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn env_source() -> Environment {
+    // Add in settings from the environment (with a prefix of APP)
+    // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
+    Environment::with_prefix("gw").separator("_")
+}
+
+/// Where the effective value of a configuration key came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSource {
+    /// The built-in default
+    Default,
+    /// The defaults of the selected hardware profile
+    Profile,
+    /// The settings file
+    File,
+    /// A file included by the settings file
+    Include,
+    /// A `GW_` environment variable
+    Env,
+    /// Updated at runtime, like the region received from the config service
+    HotReload,
+}
+
+/// An effective configuration value and where it came from
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigEntry {
+    /// The dotted key of the value, for example "poc.interval"
+    pub key: String,
+    pub value: serde_json::Value,
+    pub source: ConfigSource,
+    /// The file the value was read from, for file and include sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// The built-in default, for keys that have one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+/// Keys holding passwords or trusted keys. Their values are not shown by
+/// `config show` or the local API.
+const SECRET_KEYS: &[&str] = &[
+    "mqtt.password",
+    "authorized_keys",
+    "update.pubkey",
+    "region_params.pubkey",
+    "cluster.peer_keys",
+];
+
+impl ConfigEntry {
+    /// Whether the effective value is the built-in default
+    pub fn is_default(&self) -> bool {
+        self.default.as_ref() == Some(&self.value)
+    }
+
+    /// Whether the value is a password or a trusted key
+    pub fn is_secret(&self) -> bool {
+        SECRET_KEYS.contains(&self.key.as_str())
+    }
+
+    /// Returns the entry for display, with a configured secret value
+    /// replaced by "<redacted>"
+    pub fn redacted(mut self) -> Self {
+        if self.is_secret() && !self.is_default() {
+            self.value = serde_json::Value::from("<redacted>");
+        }
+        self
+    }
+}
+
+/// Collects the values of a single configuration source as dotted keys
+fn flatten_source<S>(source: S) -> Result<Vec<(String, serde_json::Value)>>
+where
+    S: config::Source + Send + Sync + 'static,
+{
+    let value: serde_json::Value = Config::builder()
+        .add_source(source)
+        .build()?
+        .try_deserialize()?;
+    let mut values = vec![];
+    flatten_value(String::new(), value, &mut values);
+    Ok(values)
+}
+
+fn flatten_value(
+    prefix: String,
+    value: serde_json::Value,
+    values: &mut Vec<(String, serde_json::Value)>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten_value(key, value, values);
+            }
+        }
+        value => values.push((prefix, value)),
+    }
+}
+
+/// The keypair used when neither a profile nor a settings file sets one
+const DEFAULT_KEYPAIR: &str = "/etc/helium_gateway/gateway_key.bin";
+
+/// The built-in defaults of settings that have one
+fn defaults() -> Vec<(&'static str, serde_json::Value)> {
+    use serde_json::json;
+    vec![
+        ("keypair", json!(DEFAULT_KEYPAIR)),
+        ("listen", json!(default_listen())),
+        ("extra_listen", json!([])),
+        ("api", json!(default_api())),
+        ("authorized_keys", json!([])),
+        ("data_dir", json!(default_data_dir())),
+        ("log.sample_summary", json!(default_log_sample_summary())),
+        ("log.file", json!(default_log_file())),
+        ("log.max_size", json!(default_log_max_size())),
+        ("log.keep", json!(default_log_keep())),
+        ("log.compress", json!(true)),
+        ("region_params.source", json!("config")),
+        (
+            "region_params.debounce",
+            json!(default_region_params_debounce()),
+        ),
+        ("poc.enabled", json!(true)),
+        ("poc.interval", json!(default_poc_interval())),
+        ("poc.gps_align", json!(false)),
+        ("poc.entropy_source", json!("os")),
+        ("poc.entropy_device", json!(default_entropy_device())),
+        ("poc.deferred", json!(false)),
+        ("poc.deferred_max_age", json!(default_deferred_max_age())),
+        (
+            "poc.deferred_max_reports",
+            json!(default_deferred_max_reports()),
+        ),
+        ("poc.deferred_interval", json!(default_deferred_interval())),
+        ("poc.regional_ingest", json!([])),
+        ("router.fallbacks", json!([])),
+        (
+            "router.failback_interval",
+            json!(default_failback_interval()),
+        ),
+        ("router.spool", json!(false)),
+        (
+            "router.spool_max_packets",
+            json!(default_spool_max_packets()),
+        ),
+        ("router.spool_max_age", json!(default_spool_max_age())),
+        (
+            "router.keepalive_interval",
+            json!(default_keepalive_interval()),
+        ),
+        (
+            "router.keepalive_timeout",
+            json!(default_keepalive_timeout()),
+        ),
+        (
+            "router.heartbeat_interval",
+            json!(default_heartbeat_interval()),
+        ),
+        (
+            "poc.witness_max_latency",
+            json!(default_witness_max_latency()),
+        ),
+        ("poc.witness_late_drop", json!(true)),
+        ("calibration.rssi", json!(0.0)),
+        ("calibration.snr", json!(0.0)),
+        ("led.active_low", json!(false)),
+        ("modem.modem", json!(0)),
+        ("modem.interval", json!(default_modem_interval())),
+        ("key_health.interval", json!(default_key_health_interval())),
+        ("data_usage.enabled", json!(true)),
+        ("data_usage.enforce", json!(false)),
+        ("uplink_filter.enabled", json!(false)),
+        ("join_filter.enabled", json!(false)),
+        ("join_filter.allow_join_euis", json!([])),
+        ("join_filter.deny_join_euis", json!([])),
+        ("join_filter.allow_dev_euis", json!([])),
+        ("join_filter.deny_dev_euis", json!([])),
+        ("forward_filter.allow_net_ids", json!([])),
+        ("forward_filter.deny_net_ids", json!([])),
+        ("forward_filter.allow_devaddrs", json!([])),
+        ("forward_filter.deny_devaddrs", json!([])),
+        ("uplink_metadata.drop_incomplete", json!(false)),
+        ("downlink.rx1_margin", json!(default_rx1_margin())),
+        ("downlink.auto_tune", json!(true)),
+        ("downlink.rx1_margin_min", json!(default_rx1_margin_min())),
+        ("downlink.rx1_margin_max", json!(default_rx1_margin_max())),
+        ("downlink.rtt_compensation", json!(true)),
+        ("rf_health.silence_hours", json!(default_rf_silence_hours())),
+        ("rf_health.min_uplinks", json!(default_rf_min_uplinks())),
+        ("rf_health.rssi_drop", json!(default_rf_rssi_drop())),
+        ("cluster.policy", json!("first")),
+        ("cluster.holdoff", json!(default_cluster_holdoff())),
+        (
+            "fleet.reconnect_interval",
+            json!(default_fleet_reconnect_interval()),
+        ),
+        ("forwarder.backend", json!("semtech_udp")),
+        ("forwarder.allowed_euis", json!([])),
+        ("forwarder.mirror", json!([])),
+        (
+            "forwarder.watchdog_timeout",
+            json!(default_forwarder_watchdog_timeout()),
+        ),
+        (
+            "forwarder.event_url",
+            json!(default_concentratord_event_url()),
+        ),
+        (
+            "forwarder.command_url",
+            json!(default_concentratord_command_url()),
+        ),
+        ("mqtt.client_id", json!(default_mqtt_client_id())),
+        ("mqtt.uplink_topic", json!(default_mqtt_uplink_topic())),
+        ("mqtt.downlink_topic", json!(default_mqtt_downlink_topic())),
+        ("mqtt.result_topic", json!(default_mqtt_result_topic())),
+        ("mqtt.format", json!("json")),
+        ("geofence.enabled", json!(false)),
+        ("crc_failed.keep", json!(false)),
+        ("crc_failed.stream", json!(false)),
+        ("queues.conduit", json!(default_queue_conduit())),
+        ("queues.router", json!(default_queue_router())),
+        ("queues.gateway", json!(default_queue_gateway())),
+        ("queues.beacon", json!(default_queue_beacon())),
+        ("queues.event_bus", json!(default_queue_event_bus())),
+        ("queues.conduit_policy", json!("block")),
+        ("update.interval", json!(default_update_interval())),
+        ("update.action", json!("exec")),
+        ("channel_mask.enabled", json!(false)),
+        ("tx_power", json!({})),
+        (
+            "channel_mask.error_percent",
+            json!(default_channel_mask_error_percent()),
+        ),
+        (
+            "channel_mask.noise_margin",
+            json!(default_channel_mask_noise_margin()),
+        ),
+        (
+            "channel_mask.max_masked",
+            json!(default_channel_mask_max_masked()),
+        ),
+        ("channel_mask.hold", json!(default_channel_mask_hold())),
+        (
+            "metrics.snapshot_interval",
+            json!(default_metrics_snapshot_interval()),
+        ),
+        (
+            "metrics.snapshot_retention",
+            json!(default_metrics_snapshot_retention()),
+        ),
+        ("router_health.enabled", json!(true)),
+        (
+            "router_health.error_percent",
+            json!(default_router_health_error_percent()),
+        ),
+        (
+            "router_health.window",
+            json!(default_router_health_window()),
+        ),
+        (
+            "router_health.cooldown",
+            json!(default_router_health_cooldown()),
+        ),
+    ]
+}
+
 fn default_listen() -> String {
     "127.0.0.1:1680".to_string()
 }
 
+fn default_concentratord_event_url() -> String {
+    "ipc:///tmp/concentratord_event".to_string()
+}
+
+fn default_region_params_debounce() -> u64 {
+    30
+}
+
+fn default_queue_conduit() -> usize {
+    50
+}
+
+fn default_queue_router() -> usize {
+    20
+}
+
+fn default_queue_gateway() -> usize {
+    10
+}
+
+fn default_queue_beacon() -> usize {
+    10
+}
+
+fn default_queue_event_bus() -> usize {
+    256
+}
+
+fn default_forwarder_watchdog_timeout() -> u64 {
+    120
+}
+
+fn default_concentratord_command_url() -> String {
+    "ipc:///tmp/concentratord_command".to_string()
+}
+
+fn default_mqtt_client_id() -> String {
+    "helium_gateway".to_string()
+}
+
+fn default_mqtt_uplink_topic() -> String {
+    "helium_gateway/uplink".to_string()
+}
+
+fn default_mqtt_downlink_topic() -> String {
+    "helium_gateway/downlink".to_string()
+}
+
+fn default_mqtt_result_topic() -> String {
+    "helium_gateway/downlink_result".to_string()
+}
+
 fn default_api() -> u16 {
     4467
 }
 
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("/etc/helium_gateway")
+}
+
+fn default_cluster_holdoff() -> u64 {
+    500
+}
+
+fn default_log_sample_summary() -> u64 {
+    60
+}
+
+fn default_log_file() -> PathBuf {
+    PathBuf::from("/var/log/helium_gateway/helium_gateway.log")
+}
+
+fn default_log_max_size() -> u64 {
+    1024
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+fn default_modem_interval() -> u64 {
+    60
+}
+
+fn default_channel_mask_error_percent() -> u64 {
+    50
+}
+
+fn default_channel_mask_noise_margin() -> u64 {
+    10
+}
+
+fn default_channel_mask_max_masked() -> usize {
+    2
+}
+
+fn default_channel_mask_hold() -> u64 {
+    3600
+}
+
+fn default_fleet_reconnect_interval() -> u64 {
+    60
+}
+
+fn default_update_interval() -> u64 {
+    21600
+}
+
+fn default_metrics_snapshot_interval() -> u64 {
+    300
+}
+
+fn default_metrics_snapshot_retention() -> u64 {
+    86400
+}
+
+fn default_router_health_error_percent() -> u64 {
+    50
+}
+
+fn default_router_health_window() -> usize {
+    20
+}
+
+fn default_router_health_cooldown() -> u64 {
+    300
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_rx1_margin() -> u64 {
+    40
+}
+
+fn default_rx1_margin_min() -> u64 {
+    10
+}
+
+fn default_rx1_margin_max() -> u64 {
+    500
+}
+
+fn default_key_health_interval() -> u64 {
+    600
+}
+
+fn default_rf_silence_hours() -> u64 {
+    6
+}
+
+fn default_rf_min_uplinks() -> u64 {
+    100
+}
+
+fn default_rf_rssi_drop() -> u64 {
+    20
+}
+
 fn default_poc_interval() -> u64 {
     // every 6 hours
     6 * 3600
 }
 
+fn default_entropy_device() -> PathBuf {
+    PathBuf::from("/dev/hwrng")
+}
+
+fn default_witness_max_latency() -> u64 {
+    10_000
+}
+
+fn default_deferred_max_age() -> u64 {
+    // a day
+    24 * 3600
+}
+
+fn default_deferred_max_reports() -> usize {
+    1000
+}
+
+fn default_deferred_interval() -> u64 {
+    300
+}
+
+fn default_failback_interval() -> u64 {
+    300
+}
+
+fn default_keepalive_interval() -> u64 {
+    30
+}
+
+fn default_keepalive_timeout() -> u64 {
+    10
+}
+
+fn default_heartbeat_interval() -> u64 {
+    60
+}
+
+fn default_spool_max_packets() -> usize {
+    10_000
+}
+
+fn default_spool_max_age() -> u64 {
+    // a day
+    24 * 3600
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Copy, clap::ValueEnum)]
 #[clap(rename_all = "lower")]
 #[repr(u8)]
@@ -228,6 +1944,8 @@ pub mod log_method {
         Stdio,
         /// Send logging information to syslog
         Syslog,
+        /// Write logging information to a size capped log file
+        File,
     }
 
     impl<'de> Deserialize<'de> for LogMethod {
@@ -249,6 +1967,7 @@ pub mod log_method {
                     let method = match value.to_lowercase().as_str() {
                         "stdio" => LogMethod::Stdio,
                         "syslog" => LogMethod::Syslog,
+                        "file" => LogMethod::File,
                         unsupported => {
                             return Err(de::Error::custom(format!(
                                 "unsupported log method: \"{unsupported}\""
@@ -263,3 +1982,249 @@ pub mod log_method {
         }
     }
 }
+
+pub mod profile {
+    //! Named hardware profiles which provide defaults for common gateway
+    //! hardware. A profile is selected with `profile = "<name>"` in the
+    //! settings file.
+
+    /// The supported profiles as (name, toml defaults) pairs. A profile sets
+    /// the packet forwarder listen address, the keypair location, the gain of
+    /// the antenna shipped with the hardware and, for hardware with a GPS
+    /// receiver, GPS aligned beacons.
+    pub const PROFILES: &[(&str, &str)] = &[
+        (
+            "rak2287-raspi",
+            r#"
+            listen = "127.0.0.1:1680"
+            keypair = "ecc://i2c-1:96?slot=0"
+            onboarding = "ecc://i2c-1:96?slot=15"
+            antenna = { gain = 2.8 }
+            poc = { gps_align = true }
+            "#,
+        ),
+        (
+            "linxdot",
+            r#"
+            listen = "127.0.0.1:1680"
+            keypair = "ecc://i2c-2:96?slot=0"
+            onboarding = "ecc://i2c-2:96?slot=15"
+            antenna = { gain = 3.0 }
+            poc = { gps_align = true }
+            "#,
+        ),
+        (
+            "sensecap-m1",
+            r#"
+            listen = "127.0.0.1:1680"
+            keypair = "ecc://i2c-1:96?slot=0"
+            onboarding = "ecc://i2c-1:96?slot=15"
+            antenna = { gain = 2.6 }
+            "#,
+        ),
+        (
+            "file",
+            r#"
+            listen = "127.0.0.1:1680"
+            keypair = "/etc/helium_gateway/gateway_key.bin"
+            "#,
+        ),
+    ];
+
+    /// Returns the toml defaults for the profile with the given name
+    pub fn find(name: &str) -> Option<&'static str> {
+        PROFILES
+            .iter()
+            .find(|(profile, _)| profile.eq_ignore_ascii_case(name))
+            .map(|(_, defaults)| *defaults)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Each entry of the defaults table, set explicitly, must deserialize to
+    /// the same settings as leaving the key to its serde default
+    #[test]
+    fn defaults_match_serde() {
+        const PUBKEY: &str = "115PmCR6fpFihdjw626JXYdUEdzwjh66yoWzWkMvB9CRGEx1U6G";
+        let dir = std::env::temp_dir().join(format!("settings_{}", std::process::id()));
+        let required = format!(
+            r#"
+            keypair = "file://{}"
+            region = "US915"
+            config = {{ pubkey = "{PUBKEY}", uri = "http://config.example.com:6080" }}
+            gateways = [{{ pubkey = "{PUBKEY}", uri = "http://gw.example.com:8080" }}]
+            log = {{ level = "info", method = "stdio", timestamp = false }}
+            router = {{ uri = "http://router.example.com:8080", queue = 20 }}
+            poc = {{ entropy_uri = "http://entropy.example.com:7080", ingest_uri = "http://ingest.example.com:9080" }}
+            "#,
+            dir.join("gateway_key.bin").display()
+        );
+        let load = |value: Option<(&str, &serde_json::Value)>| -> String {
+            let mut builder =
+                Config::builder().add_source(File::from_str(&required, FileFormat::Toml));
+            if let Some((key, value)) = value {
+                let mut override_value = value.clone();
+                for part in key.rsplit('.') {
+                    let mut map = serde_json::Map::new();
+                    map.insert(part.to_string(), override_value);
+                    override_value = map.into();
+                }
+                builder = builder.add_source(File::from_str(
+                    &override_value.to_string(),
+                    FileFormat::Json,
+                ));
+            }
+            let settings: Settings = builder
+                .build()
+                .and_then(Config::try_deserialize)
+                .unwrap_or_else(|err| panic!("settings with {value:?}: {err}"));
+            format!("{settings:?}")
+        };
+        let expected = load(None);
+        // The keypair default is set on the config builder rather than by
+        // serde, and loading it would create a key in /etc
+        for (key, value) in defaults().into_iter().filter(|(key, _)| *key != "keypair") {
+            assert_eq!(expected, load(Some((key, &value))), "default of {key}");
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn missing_includes() {
+        let dir = std::env::temp_dir().join(format!("includes_{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).expect("settings dir");
+        let path = dir.join("settings.toml");
+        let includes = |include: &str| {
+            fs::write(&path, format!("include = [\"{include}\"]")).expect("settings file");
+            include_paths(&path)
+        };
+        assert!(includes("conf.d/*.toml").expect("includes").is_empty());
+        assert!(includes("missing.d/*.toml").is_err());
+        assert_eq!(
+            vec![dir.join("missing.toml")],
+            includes("missing.toml").expect("includes")
+        );
+        assert!(Settings::provenance(&path).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn profile_layers() {
+        let dir = std::env::temp_dir().join(format!("profile_{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).expect("settings dir");
+        let path = dir.join("settings.toml");
+        fs::write(
+            &path,
+            r#"
+            profile = "rak2287-raspi"
+            include = ["conf.d/*.toml"]
+            [antenna]
+            gain = 5.8
+            "#,
+        )
+        .expect("settings file");
+        fs::write(
+            dir.join("conf.d/10-local.toml"),
+            r#"listen = "0.0.0.0:1680""#,
+        )
+        .expect("include file");
+        let entries = Settings::provenance(&path).expect("provenance");
+        let _ = fs::remove_dir_all(dir);
+        let entry = |key: &str| {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.key == key)
+                .unwrap_or_else(|| panic!("no {key} entry"));
+            (entry.source, entry.value.clone())
+        };
+        // The profile overrides the built-in defaults
+        assert_eq!(
+            (
+                ConfigSource::Profile,
+                serde_json::json!("ecc://i2c-1:96?slot=0")
+            ),
+            entry("keypair")
+        );
+        assert_eq!(
+            (ConfigSource::Profile, serde_json::json!(true)),
+            entry("poc.gps_align")
+        );
+        // The settings file and its includes override the profile
+        assert_eq!(
+            (ConfigSource::File, serde_json::json!(5.8)),
+            entry("antenna.gain")
+        );
+        assert_eq!(
+            (ConfigSource::Include, serde_json::json!("0.0.0.0:1680")),
+            entry("listen")
+        );
+        // Values the profile does not set keep their built-in default
+        assert_eq!(
+            (
+                ConfigSource::Default,
+                serde_json::json!(default_poc_interval())
+            ),
+            entry("poc.interval")
+        );
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let entry = |key: &str, value: serde_json::Value| ConfigEntry {
+            key: key.to_string(),
+            value,
+            source: ConfigSource::File,
+            file: None,
+            default: None,
+        };
+        assert_eq!(
+            serde_json::json!("<redacted>"),
+            entry("mqtt.password", serde_json::json!("hunter2"))
+                .redacted()
+                .value
+        );
+        assert_eq!(
+            serde_json::json!("broker"),
+            entry("mqtt.username", serde_json::json!("broker"))
+                .redacted()
+                .value
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.toml", "10-region.toml"));
+        assert!(wildcard_match("*.toml", ".toml"));
+        assert!(!wildcard_match("*.toml", "region.toml.bak"));
+        assert!(wildcard_match("10-*.toml", "10-region.toml"));
+        assert!(!wildcard_match("10-*.toml", "20-region.toml"));
+        assert!(wildcard_match("*-*.toml", "10-region.toml"));
+        assert!(!wildcard_match("*a*a.toml", "a.toml"));
+        assert!(wildcard_match("settings.toml", "settings.toml"));
+        assert!(!wildcard_match("settings.toml", "settings.toml2"));
+    }
+
+    #[test]
+    fn regional_ingest() {
+        use helium_proto::Region as ProtoRegion;
+
+        let eu868 = Region::from(ProtoRegion::Eu868);
+        let ingest_uris = IngestUris {
+            default: Uri::from_static("http://ingest.example.com:9080"),
+            regional: vec![(eu868, Uri::from_static("http://eu.ingest.example.com:9080"))],
+        };
+        assert_eq!(
+            "http://eu.ingest.example.com:9080/",
+            ingest_uris.for_region(&eu868).to_string()
+        );
+        assert_eq!(
+            "http://ingest.example.com:9080/",
+            ingest_uris
+                .for_region(&Region::from(ProtoRegion::Us915))
+                .to_string()
+        );
+    }
+}
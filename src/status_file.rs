@@ -0,0 +1,47 @@
+//! JSON status files in the data directory.
+//!
+//! The server keeps the state of its tasks in small JSON files in the data
+//! directory, where the CLI reads them without a connection to the running
+//! server and where they outlive a restart.
+
+use crate::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fs, io, path::Path};
+
+/// Loads the status file with the given name. Returns None when the file does
+/// not exist.
+pub fn load<T: DeserializeOwned>(data_dir: &Path, name: &str) -> Result<Option<T>> {
+    match fs::read(data_dir.join(name)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Stores the status file with the given name, creating the data directory
+/// when needed
+pub fn save<T: Serialize + ?Sized>(data_dir: &Path, name: &str, value: &T) -> Result {
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join(name), serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load_save() {
+        let dir = std::env::temp_dir().join(format!("status_file_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(None, load::<Vec<u32>>(&dir, "status.json").expect("load"));
+        save(&dir, "status.json", &[1, 2, 3]).expect("save");
+        assert_eq!(
+            Some(vec![1, 2, 3]),
+            load::<Vec<u32>>(&dir, "status.json").expect("load")
+        );
+        fs::write(dir.join("status.json"), b"not json").expect("write");
+        assert!(load::<Vec<u32>>(&dir, "status.json").is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
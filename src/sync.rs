@@ -57,6 +57,12 @@ impl<T> MessageSender<T> {
         _ = self.0.send(msg).await
     }
 
+    /// Sends a message without waiting for queue capacity. Fails when the
+    /// queue is full or closed.
+    pub fn try_send(&self, msg: T) -> Result {
+        self.0.try_send(msg).map_err(|_| Error::channel())
+    }
+
     pub async fn request<R, F>(&self, req: F) -> Result<R>
     where
         F: FnOnce(ResponseSender<R>) -> T,